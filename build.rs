@@ -1,8 +1,62 @@
 extern crate lalrpop;
 
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::process::Command;
+
 fn main() {
     lalrpop::Configuration::new()
         .set_in_dir("src/parsing")
         .process()
         .unwrap();
+    println!("cargo:rustc-env=FRAWK_BUILD_ID={:016x}", build_id());
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
+
+/// A hash of the sources, dependencies, compiler and configuration frawk is built from. The JIT
+/// cache includes it in its keys, because code generated by one build of frawk may depend on
+/// struct layouts and calling conventions that differ in another build of the same version.
+fn build_id() -> u64 {
+    let mut h = DefaultHasher::new();
+    hash_dir(Path::new("src"), &mut h);
+    fs::read("Cargo.lock").unwrap_or_default().hash(&mut h);
+    if let Ok(rustc) = env::var("RUSTC") {
+        if let Ok(out) = Command::new(rustc).arg("-vV").output() {
+            out.stdout.hash(&mut h);
+        }
+    }
+    let mut vars: Vec<_> = env::vars()
+        .filter(|(k, _)| {
+            k.starts_with("CARGO_FEATURE_")
+                || k.starts_with("CARGO_CFG_")
+                || k == "PROFILE"
+                || k == "TARGET"
+                || k == "OPT_LEVEL"
+                || k == "DEBUG"
+        })
+        .collect();
+    vars.sort();
+    vars.hash(&mut h);
+    h.finish()
+}
+
+fn hash_dir(dir: &Path, h: &mut DefaultHasher) {
+    let mut entries: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => return,
+    };
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            hash_dir(&path, h);
+        } else if let Ok(contents) = fs::read(&path) {
+            path.hash(h);
+            contents.hash(h);
+        }
+    }
 }
@@ -13,17 +13,41 @@
 ///    actions to execute when the input matches that pattern is desugared in this module. We do
 ///    not handle it specially.
 ///
-///    TODO It is not clear that this is the right move long-term: lots of regex implementations
-///    (like HyperScan, or BurntSushi's engine in use here) achieve higher throughput by matching a
-///    string against several patterns at once. There is probably a transormation we could do here
-///    to take advantage of that, but it would probably involve building out def-use chains (which
-///    we currently don't do), and we'd want to verify that performance didn't degrade when the
-///    patterns are _not sparse_ in the input.
+///    The one exception is programs with many `/re/` rules. Regex engines (like HyperScan, or
+///    BurntSushi's engine in use here) achieve higher throughput by matching a string against
+///    several patterns at once, so when nothing can change `$0` partway through a record we match
+///    all of those patterns in one pass at the start of the record; see `regex_dispatch`.
+///    Similarly, when every rule is guarded by a regex (or prefix test) on `$0`, records matching
+///    none of them are skipped by the reader before they are split into fields; see `prefilter`.
 use crate::arena::Arena;
-use crate::builtins::Function;
+use crate::builtins::{Bitwise, Function, IsSprintf, Variable};
 use crate::common::{Either, FileSpec, Stage};
+use crate::lexer::Span;
+use crate::runtime::matcher::{self, PatternSet};
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+use hashbrown::HashSet;
+
+use std::convert::TryFrom;
+use std::hash::Hash;
+
+/// An illegal frawk identifier holding the patterns matched by the current record; see
+/// `Prog::regex_dispatch`.
+const MATCHED_VAR: &'static str = "--matched";
+
+/// Illegal frawk identifiers holding the key of the current group of each `GROUP` pattern, and the
+/// key of the record being considered.
+const GROUP_VAR: &'static str = "--group";
+const GROUP_KEY_VAR: &'static str = "--group-key";
+
+/// Illegal frawk identifiers holding the names of the functions registered with `atexit`, their
+/// number, and the name of the function being called at exit, along with the name of the function
+/// that calls them; see `Prog::atexit_runner`.
+pub(crate) const ATEXIT_VAR: &'static str = "--atexit";
+pub(crate) const ATEXIT_COUNT_VAR: &'static str = "--atexit-n";
+const ATEXIT_FN_VAR: &'static str = "--atexit-f";
+pub(crate) const ATEXIT_RUN_FN: &'static str = "--atexit-run";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Unop {
     Column,
     Not,
@@ -43,18 +67,32 @@ pub struct FunDec<'a, 'b, I> {
     pub name: I,
     pub args: Vec<I>,
     pub body: &'a Stmt<'a, 'b, I>,
+    // The location of the function's signature.
+    pub span: Span,
 }
 
 pub enum Pattern<'a, 'b, I> {
     Null,
     Bool(&'a Expr<'a, 'b, I>),
     Comma(&'a Expr<'a, 'b, I>, &'a Expr<'a, 'b, I>),
+    /// `GROUP key { ... }`: run the action whenever `key` changes from one record to the next,
+    /// and once more at the end of the input; see `Prog::desugar_stage`.
+    Group(&'a Expr<'a, 'b, I>),
 }
 
 pub struct Prog<'a, 'b, I> {
     // FS
     pub field_sep: Option<&'b [u8]>,
     pub prelude_vardecs: Vec<(I, &'a Expr<'a, 'b, I>)>,
+    // `var=value` operands, along with the number of input files that precede them.
+    pub operand_vardecs: Vec<(usize, I, &'a Expr<'a, 'b, I>)>,
+    // The input files named on the command line, used to tell when `operand_vardecs` apply.
+    pub input_files: Vec<&'b str>,
+    // Set when the main input takes its files from ARGV as the program runs (see
+    // `runtime::splitter::ArgvReader`), to the variables a `var=value` entry in ARGV may assign.
+    // Such entries, including the ones given on the command line, are then assigned as the reader
+    // reaches them, rather than through `operand_vardecs`. See `Prog::argv_vars`.
+    pub argv_vars: Option<Vec<&'b str>>,
     // OFS
     pub output_sep: Option<&'b [u8]>,
     // ORS
@@ -63,10 +101,30 @@ pub struct Prog<'a, 'b, I> {
     pub begin: Option<&'a Stmt<'a, 'b, I>>,
     pub prepare: Option<&'a Stmt<'a, 'b, I>>,
     pub end: Option<&'a Stmt<'a, 'b, I>>,
-    pub pats: Vec<(Pattern<'a, 'b, I>, Option<&'a Stmt<'a, 'b, I>>)>,
+    // Runs before END if a signal stopped the main loop; see `runtime::signal`.
+    pub signal: Option<&'a Stmt<'a, 'b, I>>,
+    pub pats: Vec<(Span, Pattern<'a, 'b, I>, Option<&'a Stmt<'a, 'b, I>>)>,
     pub stage: Stage<()>,
-    pub argv: Vec<&'b str>,
     pub parse_header: bool,
+    // Write records the program did not modify exactly as they were read; see `--round-trip`.
+    pub round_trip: bool,
+    // Record that a rule's action ran, for `--match-status`.
+    pub match_status: bool,
+    // Keep every global variable in a single register, even the ones only referenced from the
+    // main function, so that `--trace` can print their current values.
+    pub trace: bool,
+    // Split a serial program into the BEGIN, main loop and END stages of a parallel one, so that
+    // the interpreter can hand it to compiled code partway through the main loop; see
+    // `runtime::tiered`.
+    pub tiered: bool,
+    // Replace calls to small functions with their bodies; see `FunDec::inline_body`. This is off
+    // when profiling, so that the time spent in each function is attributed to it.
+    pub inline_functions: bool,
+    // Arrays named with --persist, which are loaded from the state file before BEGIN and saved
+    // back after END.
+    pub persist: Vec<&'b str>,
+    // Arrays whose sizes are printed to standard error after END, for --stats.
+    pub stat_arrays: Vec<&'b str>,
 }
 
 fn parse_header<'a, 'b, I: From<&'b str> + Clone>(
@@ -124,28 +182,87 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
         Prog {
             field_sep: None,
             prelude_vardecs: Default::default(),
+            operand_vardecs: Default::default(),
+            input_files: Default::default(),
+            argv_vars: None,
             output_sep: None,
             output_record_sep: None,
             decs: Default::default(),
             begin: None,
             prepare: None,
             end: None,
+            signal: None,
             pats: Default::default(),
-            argv: Default::default(),
             parse_header: false,
+            round_trip: false,
+            match_status: false,
+            trace: false,
+            tiered: false,
+            inline_functions: false,
+            persist: Default::default(),
+            stat_arrays: Default::default(),
             stage,
         }
     }
+    /// Whether the program has a main loop, i.e. whether it reads its input record-by-record.
+    pub(crate) fn has_main_loop(&self) -> bool {
+        self.end.is_some() || self.prepare.is_some() || self.pats.len() > 0
+    }
+
+    /// The distinct regex literals (`/re/`) appearing in the program.
+    pub(crate) fn regex_literals(&self) -> Vec<&'b [u8]> {
+        let mut res = Vec::new();
+        let f = &mut |n: Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>| {
+            if let Either::Right(Expr::PatLit(re)) = n {
+                if !res.contains(re) {
+                    res.push(*re);
+                }
+            }
+            false
+        };
+        for (_, e) in self.prelude_vardecs.iter() {
+            e.any(f);
+        }
+        for s in self
+            .begin
+            .iter()
+            .chain(self.prepare.iter())
+            .chain(self.end.iter())
+            .chain(self.signal.iter())
+        {
+            s.any(f);
+        }
+        for d in self.decs.iter() {
+            d.body.any(f);
+        }
+        for (_, pat, body) in self.pats.iter() {
+            match pat {
+                Pattern::Null => false,
+                Pattern::Bool(e) => e.any(f),
+                Pattern::Comma(l, r) => l.any(f) || r.any(f),
+                Pattern::Group(e) => e.any(f),
+            };
+            body.iter().any(|s| s.any(f));
+        }
+        res
+    }
     pub(crate) fn desugar_stage<'outer>(
         &self,
         arena: &'a Arena<'outer>,
-    ) -> Stage<&'a Stmt<'a, 'b, I>> {
+    ) -> Stage<&'a Stmt<'a, 'b, I>>
+    where
+        Variable: TryFrom<I>,
+        Function: TryFrom<I>,
+        I: PartialEq + IsSprintf,
+    {
         use {self::Binop::*, self::Expr::*, Stmt::*};
         let mut conds = 0;
 
         let mut begin = vec![];
         let mut main_loop = None;
         let mut end = None;
+        let mut post_loop = vec![];
+        let unwinds = self.unwinds();
 
         // Desugar -F flag
         if let Some(sep) = self.field_sep {
@@ -186,20 +303,19 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
             )));
         }
 
-        // Set argc, argv
-        if self.argv.len() > 0 {
-            begin.push(arena.alloc_v(Expr(arena.alloc_v(Assign(
-                arena.alloc_v(Var("ARGC".into())),
-                arena.alloc_v(ILit(self.argv.len() as i64)),
-            )))));
-            let argv = arena.alloc_v(Var("ARGV".into()));
-            for (ix, arg) in self.argv.iter().enumerate() {
-                let arg = arena.alloc_v(StrLit(arg.as_bytes()));
-                let ix = arena.alloc_v(ILit(ix as i64));
-                let arr_exp = arena.alloc_v(Index(argv, ix));
-                begin.push(arena.alloc_v(Expr(arena.alloc_v(Assign(arr_exp, arg)))));
-            }
-        }
+        // Desugar --persist
+        let persist = |f: Function| {
+            self.persist.iter().map(move |name| {
+                arena.alloc_v(Expr(arena.alloc_v(Call(
+                    Either::Right(f),
+                    vec![
+                        arena.alloc_v(Var((*name).into())),
+                        arena.alloc_v(StrLit(name.as_bytes())),
+                    ],
+                ))))
+            })
+        };
+        begin.extend(persist(Function::LoadState));
 
         if let Some(begin_block) = self.begin {
             begin.push(begin_block);
@@ -219,15 +335,51 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
             }))),
         ];
         let init_len = inner.len();
-        for (pat, body) in self.pats.iter() {
-            let body = if let Some(body) = body {
+        let dispatch = self.regex_dispatch();
+        if dispatch.len() > 0 {
+            // --matched = match-set($0, /re_1/, ..., /re_n/)
+            let mut args = vec![arena.alloc_v(Unop(self::Unop::Column, arena.alloc_v(ILit(0))))];
+            args.extend(dispatch.iter().map(|i| match &self.pats[*i].1 {
+                Pattern::Bool(pat) => *pat,
+                Pattern::Null | Pattern::Comma(..) | Pattern::Group(..) => unreachable!(),
+            }));
+            inner.push(arena.alloc_v(Expr(arena.alloc_v(Assign(
+                arena.alloc_v(Var(MATCHED_VAR.into())),
+                arena.alloc_v(Call(Either::Right(Function::MatchSet), args)),
+            )))));
+        }
+        let mut group_flushes = Vec::new();
+        for (i, (span, pat, body)) in self.pats.iter().enumerate() {
+            // Skip rules that can never run; `--lint` reports these.
+            if pat.never_matches() {
+                continue;
+            }
+            let mut body = if let Some(body) = body {
                 body
             } else {
                 arena.alloc_v(Print(vec![], None))
             };
+            if self.match_status {
+                // { ... } => { set-matched(); ... }
+                let set = arena.alloc_v(Call(Either::Right(Function::SetMatched), vec![]));
+                body = arena.alloc_v(Block(vec![arena.alloc_v(Expr(set)), body]));
+            }
             match pat {
                 Pattern::Null => inner.push(body),
-                Pattern::Bool(pat) => inner.push(arena.alloc_v(If(pat, body, None))),
+                Pattern::Bool(pat) => {
+                    // /re_j/ { ... } => if (and(--matched, 2^j)) { ... }
+                    let pat = match dispatch.iter().position(|d| *d == i) {
+                        Some(bit) => arena.alloc_v(Call(
+                            Either::Right(Function::IntFunc(Bitwise::And)),
+                            vec![
+                                arena.alloc_v(Var(MATCHED_VAR.into())),
+                                arena.alloc_v(ILit(1i64.wrapping_shl(bit as u32))),
+                            ],
+                        )),
+                        None => pat,
+                    };
+                    inner.push(arena.alloc_v(Located(*span, arena.alloc_v(If(pat, body, None)))))
+                }
                 Pattern::Comma(l, r) => {
                     // Comma patterns run the corresponding action between pairs of lines matching
                     // patterns `l` and `r`, inclusive. One common example is the patterh
@@ -268,8 +420,10 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
                     //      if (Cond(0) == 2) EndCond(0); # _cond_0 = 0;
                     //      next;
                     //  }
-                    inner.push(arena.alloc_v(If(l, arena.alloc_v(StartCond(conds)), None)));
-                    inner.push(arena.alloc_v(If(r, arena.alloc_v(LastCond(conds)), None)));
+                    let start = arena.alloc_v(If(l, arena.alloc_v(StartCond(conds)), None));
+                    let last = arena.alloc_v(If(r, arena.alloc_v(LastCond(conds)), None));
+                    inner.push(arena.alloc_v(Located(*span, start)));
+                    inner.push(arena.alloc_v(Located(*span, last)));
                     let block = vec![
                         arena.alloc_v(If(
                             arena.alloc_v(Binop(
@@ -289,44 +443,948 @@ impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I> {
                     )));
                     conds += 1;
                 }
+                Pattern::Group(key) => {
+                    // GROUP patterns run their action when a group of records sharing the same
+                    // key ends, which is either when a record with a different key comes along,
+                    // or at the end of the input. The action runs before any later rule sees the
+                    // first record of the next group, so variables set by those rules still
+                    // describe the group that just ended. The key of the current group is kept
+                    // in an array indexed by the position of the pattern:
+                    //   --group-key = "" key
+                    //   if ((i in --group) && !(--group[i] == --group-key)) { ... }
+                    //   --group[i] = --group-key
+                    // and after the main loop:
+                    //   if (i in --group) { ... }
+                    let group = arena.alloc_v(Var(GROUP_VAR.into()));
+                    let key_var = arena.alloc_v(Var(GROUP_KEY_VAR.into()));
+                    let ix = arena.alloc_v(ILit(i as i64));
+                    let started =
+                        arena.alloc_v(Call(Either::Right(Function::Contains), vec![group, ix]));
+                    let cur = arena.alloc_v(Index(group, ix));
+                    let changed = arena.alloc_v(Unop(
+                        self::Unop::Not,
+                        arena.alloc_v(Binop(EQ, cur, key_var)),
+                    ));
+                    let block = vec![
+                        arena.alloc_v(Expr(arena.alloc_v(Assign(
+                            key_var,
+                            arena.alloc_v(Binop(Concat, arena.alloc_v(StrLit(b"")), key)),
+                        )))),
+                        arena.alloc_v(If(arena.alloc_v(And(started, changed)), body, None)),
+                        arena.alloc_v(Expr(arena.alloc_v(Assign(cur, key_var)))),
+                    ];
+                    inner.push(arena.alloc_v(Located(*span, arena.alloc_v(Block(block)))));
+                    group_flushes.push(arena.alloc_v(If(started, body, None)));
+                }
             }
         }
 
         if self.end.is_some() || self.prepare.is_some() || inner.len() > init_len {
+            let (before, at_new_file, after) = self.desugar_operand_vardecs(arena);
+            if let Some(at_new_file) = at_new_file {
+                inner.insert(init_len, at_new_file);
+            }
             // Wrap the whole thing in a while((getline) > 0) { } statement.
-            let main_portion = arena.alloc_v(While(
+            let mut cond =
+                arena.alloc(|| Binop(GT, arena.alloc(|| ReadStdin), arena.alloc(|| ILit(0))));
+            if let Some(limit) = self.nr_limit() {
+                // No record past the limit can match a pattern: stop reading once we reach it.
+                // while (NR < limit && (getline) > 0) { }
+                let nr = arena.alloc_v(Var("NR".into()));
+                let below = arena.alloc_v(Binop(LT, nr, arena.alloc_v(ILit(limit))));
+                cond = arena.alloc_v(And(below, cond));
+            }
+            if self.signal.is_some() {
+                // Stop at the first record boundary after a signal arrives:
+                // while (!signal() && ...) { }
+                let caught = arena.alloc_v(Call(Either::Right(Function::Signal), vec![]));
+                cond = arena.alloc_v(And(arena.alloc_v(Unop(self::Unop::Not, caught)), cond));
+            }
+            if unwinds && (matches!(self.stage, Stage::Par { .. }) || self.tiered) {
+                // `exit` in one worker stops the others at their next record, as does `exit` in
+                // BEGIN before any worker starts (or, in a tiered program, before the main loop):
+                // while (!exiting() && ...) { }
+                let exiting = arena.alloc_v(Call(Either::Right(Function::Exiting), vec![]));
+                cond = arena.alloc_v(And(arena.alloc_v(Unop(self::Unop::Not, exiting)), cond));
+            }
+            if self.tiered {
+                // Stop at the first record boundary once compiled code is ready to take over:
+                // while (!tier-up() && ...) { }
+                let tier_up = arena.alloc_v(Call(Either::Right(Function::TierUp), vec![]));
+                cond = arena.alloc_v(And(arena.alloc_v(Unop(self::Unop::Not, tier_up)), cond));
+            }
+            let mut main_portion = arena.alloc_v(While(
                 /*is_toplevel=*/ true,
-                arena.alloc(|| Binop(GT, arena.alloc(|| ReadStdin), arena.alloc(|| ILit(0)))),
+                cond,
                 arena.alloc(move || Block(inner)),
             ));
-            main_loop = Some(if let Some(prepare_block) = self.prepare {
-                arena.alloc_v(Stmt::Block(vec![main_portion, prepare_block]))
+            if self.tiered {
+                // The main loop stage holds nothing but the loop itself, so that compiled code can
+                // run the rest of it. What comes before the loop ends BEGIN, and what comes after
+                // it starts END.
+                begin.extend(before);
+                post_loop.extend(group_flushes);
+                post_loop.extend(after);
+                post_loop.extend(self.prepare);
+                main_loop = Some(main_portion);
             } else {
-                main_portion
-            });
+                if before.len() > 0 || after.len() > 0 || group_flushes.len() > 0 {
+                    let mut block = before;
+                    block.push(main_portion);
+                    block.extend(group_flushes);
+                    block.extend(after);
+                    main_portion = arena.alloc_v(Block(block));
+                }
+                main_loop = Some(if let Some(prepare_block) = self.prepare {
+                    arena.alloc_v(Stmt::Block(vec![main_portion, prepare_block]))
+                } else {
+                    main_portion
+                });
+            }
         }
         if let Some(end_block) = self.end {
             end = Some(end_block);
         }
+        // SIGNAL { ... } END { ... } => END { if (signal()) { ... } ... }
+        if let Some(signal_block) = self.signal {
+            let caught = arena.alloc_v(Call(Either::Right(Function::Signal), vec![]));
+            let mut block = vec![arena.alloc_v(If(caught, signal_block, None))];
+            block.extend(end);
+            end = Some(arena.alloc_v(Block(block)));
+        }
+        // `exit` in BEGIN or in a rule skips to END, and `exit` in END skips the rest of END. Each
+        // of these is a Phase; `exit` jumps to the end of the innermost one. If `exit` may have to
+        // unwind to get there, END stops the unwinding first (see `runtime::exit`), and is skipped
+        // if a second signal stopped the program:
+        //   END { ... } => END { if (!set-exiting(0)) { ... } }
+        let phased = self.has_exit() || self.stops_on_signal();
+        if phased {
+            end = end.map(|mut end| {
+                if unwinds {
+                    let stop = arena.alloc_v(Call(
+                        Either::Right(Function::SetExiting),
+                        vec![arena.alloc_v(ILit(0))],
+                    ));
+                    let run = arena.alloc_v(Unop(self::Unop::Not, stop));
+                    end = arena.alloc_v(If(run, end, None));
+                }
+                arena.alloc_v(Phase(end))
+            });
+        }
+        // In a tiered program, the statements after the main loop come before END, in a phase of
+        // their own. `exit` in BEGIN or in a rule skips them as well:
+        //   if (!exiting()) { ... }
+        if post_loop.len() > 0 {
+            let mut post = arena.alloc_v(Block(post_loop));
+            if unwinds {
+                let exiting = arena.alloc_v(Call(Either::Right(Function::Exiting), vec![]));
+                post = arena.alloc_v(If(
+                    arena.alloc_v(Unop(self::Unop::Not, exiting)),
+                    post,
+                    None,
+                ));
+            }
+            if phased {
+                post = arena.alloc_v(Phase(post));
+            }
+            let mut block = vec![post];
+            block.extend(end);
+            end = Some(arena.alloc_v(Block(block)));
+        }
+        if self.persist.len() > 0 {
+            let mut block: Vec<_> = end.into_iter().collect();
+            block.extend(persist(Function::SaveState));
+            end = Some(arena.alloc_v(Block(block)));
+        }
+        // Desugar the array sizes reported by --stats into
+        //   printf "%s %s: %d %s\n", "frawk: array", "a", length(a), "entries" > "/dev/stderr"
+        // Each literal is short enough to be stored inline, so it need not be aligned; see the
+        // `From<&[u8]>` impl for Str.
+        if self.stat_arrays.len() > 0 {
+            let mut block: Vec<_> = end.into_iter().collect();
+            for name in self.stat_arrays.iter() {
+                block.push(arena.alloc_v(Printf(
+                    arena.alloc_v(StrLit(b"%s %s: %d %s\n")),
+                    vec![
+                        arena.alloc_v(StrLit(b"frawk: array")),
+                        arena.alloc_v(StrLit(name.as_bytes())),
+                        arena.alloc_v(Call(
+                            Either::Right(Function::Length),
+                            vec![arena.alloc_v(Var((*name).into()))],
+                        )),
+                        arena.alloc_v(StrLit(b"entries")),
+                    ],
+                    Some((arena.alloc_v(StrLit(b"/dev/stderr")), FileSpec::Append)),
+                )));
+            }
+            end = Some(arena.alloc_v(Block(block)));
+        }
+        // The functions registered with `atexit` run last; see `Prog::atexit_runner`.
+        if self.atexit_functions().len() > 0 {
+            let mut block: Vec<_> = end.into_iter().collect();
+            block.push(arena.alloc_v(Expr(
+                arena.alloc_v(Call(Either::Left(ATEXIT_RUN_FN.into()), vec![])),
+            )));
+            end = Some(arena.alloc_v(Block(block)));
+        }
+        let phase = |s: &'a Stmt<'a, 'b, I>| {
+            if phased {
+                arena.alloc_v(Phase(s))
+            } else {
+                s
+            }
+        };
         match self.stage {
-            Stage::Main(_) => {
-                begin.extend(main_loop.into_iter().chain(end));
-                Stage::Main(arena.alloc_v(Stmt::Block(begin)))
+            Stage::Main(_) if !self.tiered => {
+                begin.extend(main_loop);
+                let mut block = vec![phase(arena.alloc_v(Stmt::Block(begin)))];
+                block.extend(end);
+                Stage::Main(arena.alloc_v(Stmt::Block(block)))
             }
-            Stage::Par { .. } => Stage::Par {
+            Stage::Main(_) | Stage::Par { .. } => Stage::Par {
                 begin: if begin.len() > 0 {
-                    Some(arena.alloc_v(Stmt::Block(begin)))
+                    Some(phase(arena.alloc_v(Stmt::Block(begin))))
                 } else {
                     None
                 },
-                main_loop,
+                main_loop: main_loop.map(phase),
                 end,
             },
         }
     }
+
+    /// Whether `f` holds for any statement or expression in the program.
+    fn any(&self, f: &mut impl FnMut(Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>) -> bool) -> bool {
+        self.any_toplevel(f) || self.decs.iter().any(|d| d.body.any(f))
+    }
+
+    /// Like `any`, but skipping the bodies of functions.
+    fn any_toplevel(
+        &self,
+        f: &mut impl FnMut(Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>) -> bool,
+    ) -> bool {
+        self.prelude_vardecs.iter().any(|(_, e)| e.any(f))
+            || self.operand_vardecs.iter().any(|(_, _, e)| e.any(f))
+            || self
+                .begin
+                .iter()
+                .chain(self.prepare.iter())
+                .chain(self.end.iter())
+                .chain(self.signal.iter())
+                .any(|s| s.any(f))
+            || self.pats.iter().any(|(_, pat, body)| {
+                let pat = match pat {
+                    Pattern::Null => false,
+                    Pattern::Bool(e) | Pattern::Group(e) => e.any(f),
+                    Pattern::Comma(l, r) => l.any(f) || r.any(f),
+                };
+                pat || body.iter().any(|s| s.any(f))
+            })
+    }
+
+    fn has_exit(&self) -> bool {
+        self.any(&mut |n| matches!(n, Either::Left(Stmt::Exit(_))))
+    }
+
+    /// Whether a second signal stops the program so that its `atexit` functions can run, rather
+    /// than killing frawk; see `runtime::signal`.
+    pub(crate) fn stops_on_signal(&self) -> bool
+    where
+        I: IsSprintf,
+    {
+        self.signal.is_some() && self.atexit_functions().len() > 0
+    }
+
+    /// Whether `exit` may have to unwind through function calls to reach the end of its phase: it
+    /// does when it is called from a function or in a parallel or tiered program, and when a
+    /// second signal stops the program. See `runtime::exit`.
+    pub(crate) fn unwinds(&self) -> bool
+    where
+        I: IsSprintf,
+    {
+        let is_exit = &mut |n: Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>| {
+            matches!(n, Either::Left(Stmt::Exit(_)))
+        };
+        let parallel = (matches!(self.stage, Stage::Par { .. }) || self.tiered) && self.has_exit();
+        parallel || self.decs.iter().any(|d| d.body.any(is_exit)) || self.stops_on_signal()
+    }
+
+    /// Whether the program asks where its records came from, which the reader only keeps track of
+    /// when it has to.
+    pub(crate) fn tracks_records(&self) -> bool
+    where
+        Function: TryFrom<I>,
+    {
+        self.any(&mut |n| match n {
+            Either::Right(Expr::Call(Either::Left(name), _)) => is_record_source(name),
+            Either::Left(_) | Either::Right(_) => false,
+        })
+    }
+
+    /// The functions registered with `atexit("name")`, in the order they first appear. The
+    /// argument to `atexit` must be a string literal, which `cfg` enforces. Like `tee`, `atexit` is
+    /// not a reserved word, so a user-defined function of the same name disables it.
+    pub(crate) fn atexit_functions(&self) -> Vec<&'b str>
+    where
+        I: IsSprintf,
+    {
+        let mut res = Vec::new();
+        if self.decs.iter().any(|d| d.name.is_atexit()) {
+            return res;
+        }
+        self.any(&mut |n| {
+            if let Either::Right(Expr::Call(Either::Left(name), args)) = n {
+                if let (true, [Expr::StrLit(s)]) = (name.is_atexit(), &args[..]) {
+                    if let Ok(s) = std::str::from_utf8(s) {
+                        if !res.contains(&s) {
+                            res.push(s);
+                        }
+                    }
+                }
+            }
+            false
+        });
+        res
+    }
+
+    /// The body of the function that calls the functions registered with `atexit`, most recent
+    /// registration first:
+    ///   set-exiting(-1)
+    ///   while (--atexit-n > 0) {
+    ///       --atexit-f = --atexit[--atexit-n--]
+    ///       if (--atexit-f == "f") f(); else if (--atexit-f == "g") g(); ...
+    ///   }
+    /// The program calls it once it has finished, and the runtime calls it if the program stops
+    /// early: when stdout is closed or when it fails. As each function is taken off the list
+    /// before it is called, calling it again only runs the functions that have not run yet. The
+    /// first statement stops any unwinding `exit` (see `runtime::exit`), and is only there if
+    /// `exit` unwinds.
+    pub(crate) fn atexit_runner<'outer>(
+        &self,
+        arena: &'a Arena<'outer>,
+    ) -> Option<&'a Stmt<'a, 'b, I>>
+    where
+        I: IsSprintf,
+    {
+        use {self::Binop::*, self::Expr::*, Stmt::*};
+        let funcs = self.atexit_functions();
+        if funcs.len() == 0 {
+            return None;
+        }
+        let count = arena.alloc_v(Var(ATEXIT_COUNT_VAR.into()));
+        let cur = arena.alloc_v(Var(ATEXIT_FN_VAR.into()));
+        let mut dispatch = None;
+        for name in funcs.iter().rev() {
+            let call = arena.alloc_v(Expr(
+                arena.alloc_v(Call(Either::Left((*name).into()), vec![])),
+            ));
+            let is_name = arena.alloc_v(Binop(EQ, cur, arena.alloc_v(StrLit(name.as_bytes()))));
+            dispatch = Some(arena.alloc_v(If(is_name, call, dispatch)));
+        }
+        let next = arena.alloc_v(Index(
+            arena.alloc_v(Var(ATEXIT_VAR.into())),
+            arena.alloc_v(Inc {
+                is_inc: false,
+                is_post: true,
+                x: count,
+            }),
+        ));
+        let mut body = vec![arena.alloc_v(Expr(arena.alloc_v(Assign(cur, next))))];
+        body.extend(dispatch);
+        let mut block = Vec::new();
+        if self.unwinds() {
+            block.push(arena.alloc_v(Expr(arena.alloc_v(Call(
+                Either::Right(Function::SetExiting),
+                vec![arena.alloc_v(ILit(-1))],
+            )))));
+        }
+        block.push(arena.alloc_v(While(
+            /*is_toplevel=*/ false,
+            arena.alloc_v(Binop(GT, count, arena.alloc_v(ILit(0)))),
+            arena.alloc_v(Block(body)),
+        )));
+        Some(arena.alloc_v(Block(block)))
+    }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+impl<'a, 'b, I: From<&'b str> + Clone> Prog<'a, 'b, I>
+where
+    Variable: TryFrom<I>,
+{
+    /// The rules whose pattern is a bare regex literal (`/re/ { ... }`), if there are enough of them
+    /// that matching them all against each record in one pass is worthwhile, and as long as no
+    /// rule or function can modify the current record: the patterns are matched before any rule
+    /// runs.
+    fn regex_dispatch(&self) -> Vec<usize>
+    where
+        Function: TryFrom<I>,
+    {
+        const MIN_RULES: usize = 4;
+        let res: Vec<usize> = self
+            .pats
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, pat, _))| match pat {
+                Pattern::Bool(Expr::PatLit(re)) => matcher::default_engine_supports(re),
+                _ => false,
+            })
+            .map(|(i, _)| i)
+            .take(PatternSet::MAX_PATTERNS)
+            .collect();
+        if res.len() < MIN_RULES {
+            return vec![];
+        }
+        let is_record = |e: &Expr<'a, 'b, I>| match e {
+            Expr::Unop(Unop::Column, _) => true,
+            Expr::Var(v) => matches!(Variable::try_from(v.clone()), Ok(Variable::NF)),
+            _ => false,
+        };
+        let writes_record = &mut |n: Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>| match n {
+            Either::Right(Expr::Assign(x, _))
+            | Either::Right(Expr::AssignOp(x, _, _))
+            | Either::Right(Expr::Inc { x, .. }) => is_record(x),
+            Either::Right(Expr::Getline { into, .. }) => into.map_or(true, |x| is_record(x)),
+            Either::Right(Expr::ReadStdin) => true,
+            Either::Right(Expr::Call(f, args)) => {
+                let f = match f {
+                    Either::Left(name) => Function::try_from(name.clone()).ok(),
+                    Either::Right(f) => Some(*f),
+                };
+                matches!(f, Some(Function::Sub) | Some(Function::GSub))
+                    && (args.len() < 3 || is_record(args[2]))
+            }
+            Either::Left(_) | Either::Right(_) => false,
+        };
+        if self.decs.iter().any(|d| d.body.any(writes_record))
+            || self.pats.iter().any(|(_, pat, body)| {
+                let pat_writes = match pat {
+                    Pattern::Null => false,
+                    Pattern::Bool(e) => e.any(writes_record),
+                    Pattern::Comma(l, r) => l.any(writes_record) || r.any(writes_record),
+                    Pattern::Group(e) => e.any(writes_record),
+                };
+                pat_writes || body.iter().any(|s| s.any(writes_record))
+            })
+        {
+            return vec![];
+        }
+        res
+    }
+
+    /// If every pattern in the main loop can only match records with NR at or below some limit,
+    /// and nothing after the main loop can tell how much of the input was read, return that limit.
+    /// The main loop can stop reading input once NR reaches it: `NR <= 10` runs in constant time.
+    ///
+    /// This only applies to serial programs: in parallel mode NR counts the records of each worker.
+    /// Nor does it apply when ARGV is read as the program runs, as the `var=value` entries after
+    /// the limit must still be assigned.
+    fn nr_limit(&self) -> Option<i64> {
+        if !matches!(self.stage, Stage::Main(_))
+            || self.prepare.is_some()
+            || self.pats.len() == 0
+            || self.argv_vars.is_some()
+        {
+            return None;
+        }
+        let mut limit = 0;
+        for (_, pat, _) in self.pats.iter() {
+            match pat {
+                Pattern::Bool(e) => limit = limit.max(nr_bound(e)?),
+                Pattern::Null | Pattern::Comma(..) | Pattern::Group(..) => return None,
+            }
+        }
+
+        // NR must only ever increase.
+        let is_nr = |e: &Expr<'a, 'b, I>| match e {
+            Expr::Var(v) => matches!(Variable::try_from(v.clone()), Ok(Variable::NR)),
+            _ => false,
+        };
+        let writes_nr = &mut |n: Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>| match n {
+            Either::Right(Expr::Assign(x, _))
+            | Either::Right(Expr::AssignOp(x, _, _))
+            | Either::Right(Expr::Inc { x, .. })
+            | Either::Right(Expr::Getline { into: Some(x), .. }) => is_nr(x),
+            // sub and gsub assign to their last argument.
+            Either::Right(Expr::Call(_, args)) => args.iter().any(|a| is_nr(a)),
+            Either::Left(_) | Either::Right(_) => false,
+        };
+        if self
+            .operand_vardecs
+            .iter()
+            .any(|(_, v, _)| matches!(Variable::try_from(v.clone()), Ok(Variable::NR)))
+            || self.prelude_vardecs.iter().any(|(_, e)| e.any(writes_nr))
+            || self.decs.iter().any(|d| d.body.any(writes_nr))
+            || self
+                .begin
+                .iter()
+                .chain(self.end.iter())
+                .chain(self.signal.iter())
+                .any(|s| s.any(writes_nr))
+            || self.pats.iter().any(|(_, pat, body)| {
+                matches!(pat, Pattern::Bool(e) if e.any(writes_nr))
+                    || body.iter().any(|s| s.any(writes_nr))
+            })
+        {
+            return None;
+        }
+
+        // END and SIGNAL (and any function they might call) must not look at the last record read,
+        // the number of records, or the rest of the input.
+        if self.end.is_some() || self.signal.is_some() {
+            let observes_input = &mut |n: Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>| match n {
+                Either::Left(Stmt::Print(args, _)) => args.len() == 0,
+                Either::Right(Expr::Unop(Unop::Column, _))
+                | Either::Right(Expr::ReadStdin)
+                | Either::Right(Expr::Getline { from: None, .. }) => true,
+                Either::Right(Expr::Var(v)) => matches!(
+                    Variable::try_from(v.clone()),
+                    Ok(Variable::NR)
+                        | Ok(Variable::FNR)
+                        | Ok(Variable::NF)
+                        | Ok(Variable::FILENAME)
+                ),
+                Either::Left(_) | Either::Right(_) => false,
+            };
+            if self
+                .end
+                .iter()
+                .chain(self.signal.iter())
+                .any(|s| s.any(observes_input))
+                || self.decs.iter().any(|d| d.body.any(observes_input))
+            {
+                return None;
+            }
+        }
+        Some(limit)
+    }
+
+    /// If every rule is guarded by a regex matched against the whole record (`/re/` or
+    /// `$0 ~ /re/`) or by a test that the record starts with a given string (`index($0, s) == 1`
+    /// or `substr($0, 1, n) == s`), return the patterns of those guards. A record matching none of
+    /// them cannot run any rule, so the reader skips it before it is split into fields or counted,
+    /// provided nothing else could tell that it was read: NR and FNR are never used, getline never
+    /// reads from the main input, and PREPARE, END and SIGNAL do not look at the last record.
+    pub(crate) fn prefilter(&self) -> Option<Vec<String>>
+    where
+        I: PartialEq,
+        Function: TryFrom<I>,
+    {
+        if self.parse_header || self.operand_vardecs.len() > 0 || self.argv_vars.is_some() {
+            return None;
+        }
+        let mut res = Vec::new();
+        for (_, pat, _) in self.pats.iter() {
+            match pat {
+                _ if pat.never_matches() => {}
+                Pattern::Bool(e) => res.push(record_guard(e)?),
+                Pattern::Null | Pattern::Comma(..) | Pattern::Group(..) => return None,
+            }
+        }
+        if res.len() == 0 {
+            return None;
+        }
+        let sees_skipped = &mut |n: Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>| match n {
+            Either::Right(Expr::ReadStdin) | Either::Right(Expr::Getline { from: None, .. }) => {
+                true
+            }
+            Either::Right(Expr::Var(v)) => matches!(
+                Variable::try_from(v.clone()),
+                Ok(Variable::NR) | Ok(Variable::FNR)
+            ),
+            Either::Left(_) | Either::Right(_) => false,
+        };
+        if self.any(sees_skipped) {
+            return None;
+        }
+        let reads_record = &mut |n: Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>| match n {
+            Either::Left(Stmt::Print(args, _)) => args.len() == 0,
+            Either::Right(Expr::Unop(Unop::Column, _)) => true,
+            Either::Right(Expr::Var(v)) => {
+                matches!(Variable::try_from(v.clone()), Ok(Variable::NF))
+            }
+            Either::Right(Expr::Call(Either::Right(f), args)) => match f {
+                Function::Length => args.len() == 0,
+                Function::Sub | Function::GSub => args.len() < 3,
+                Function::JoinCols
+                | Function::JoinCSV
+                | Function::JoinTSV
+                | Function::FSum
+                | Function::FMean => true,
+                _ => false,
+            },
+            Either::Right(Expr::Call(Either::Left(name), _)) => is_record_source(name),
+            Either::Left(_) | Either::Right(_) => false,
+        };
+        if self
+            .prepare
+            .iter()
+            .chain(self.end.iter())
+            .chain(self.signal.iter())
+            .any(|s| s.any(reads_record))
+            || self.decs.iter().any(|d| d.body.any(reads_record))
+        {
+            return None;
+        }
+        Some(res)
+    }
+
+    /// Whether the program refers to ARGV or ARGC. Programs that do not cannot change which files
+    /// they read, so their input files can be fixed up front.
+    pub(crate) fn uses_argv(&self) -> bool {
+        self.any(&mut |n| match n {
+            Either::Right(Expr::Var(v)) => matches!(
+                Variable::try_from(v.clone()),
+                Ok(Variable::ARGV) | Ok(Variable::ARGC)
+            ),
+            Either::Left(_) | Either::Right(_) => false,
+        })
+    }
+
+    /// Desugar `var=value` operands. POSIX awk performs these assignments as it reaches them in
+    /// ARGV: the ones before the first input file run before any input is read, the ones between
+    /// two files run before the second file is read, and the rest run before END.
+    ///
+    /// We track this in the main loop. Whenever a new file starts (FNR == 1) we look FILENAME up in
+    /// `input_files` and run every assignment preceding it that has not run yet. Empty files never
+    /// reach FNR == 1, so the assignments before an empty file run along with those for the file
+    /// after it. Assignments to RS only take effect after the first record of the next file has
+    /// been read.
+    ///
+    /// When the main input reads ARGV as the program runs, the reader itself tracks which
+    /// assignments it has reached instead; see `desugar_argv_vars`.
+    ///
+    /// Returns the statements to run before the main loop, at the start of each record, and after
+    /// the main loop.
+    fn desugar_operand_vardecs<'outer>(
+        &self,
+        arena: &'a Arena<'outer>,
+    ) -> (
+        Vec<&'a Stmt<'a, 'b, I>>,
+        Option<&'a Stmt<'a, 'b, I>>,
+        Vec<&'a Stmt<'a, 'b, I>>,
+    ) {
+        use {self::Binop::*, self::Expr::*, Stmt::*};
+        // Illegal frawk identifiers holding the number of input files started so far, and the
+        // index of the current one.
+        const STARTED_VAR: &'static str = "--started";
+        const CUR_VAR: &'static str = "--cur";
+
+        if let Some(vars) = &self.argv_vars {
+            let assign = self.desugar_argv_vars(arena, vars);
+            let at_new_file = arena.alloc_v(If(
+                arena.alloc_v(Binop(
+                    EQ,
+                    arena.alloc_v(Var("FNR".into())),
+                    arena.alloc_v(ILit(1)),
+                )),
+                assign,
+                None,
+            ));
+            return (vec![], Some(at_new_file), vec![assign]);
+        }
+
+        let n = self.input_files.len();
+        // groups[i] holds the assignments preceding input file i.
+        let mut groups: Vec<Vec<&'a Stmt<'a, 'b, I>>> = vec![vec![]; n + 1];
+        for (files_before, ident, exp) in self.operand_vardecs.iter() {
+            groups[*files_before].push(arena.alloc_v(Expr(
+                arena.alloc_v(Assign(arena.alloc_v(Var(ident.clone())), exp)),
+            )));
+        }
+        let before = std::mem::replace(&mut groups[0], vec![]);
+        if groups.iter().all(|g| g.len() == 0) {
+            return (before, None, vec![]);
+        }
+
+        let started = arena.alloc_v(Var(STARTED_VAR.into()));
+        let cur = arena.alloc_v(Var(CUR_VAR.into()));
+        let ilit = |i: usize| arena.alloc_v(ILit(i as i64));
+        let not_run = |i: usize| arena.alloc_v(Binop(LTE, started, ilit(i)));
+
+        // cur = started;
+        // if (started <= n-1 && FILENAME == "file n-1") cur = n-1;
+        // ...
+        // if (started <= 0 && FILENAME == "file 0") cur = 0;
+        let mut block = vec![arena.alloc_v(Expr(arena.alloc_v(Assign(cur, started))))];
+        for (i, file) in self.input_files.iter().enumerate().rev() {
+            let is_file = arena.alloc_v(Binop(
+                EQ,
+                arena.alloc_v(Var("FILENAME".into())),
+                arena.alloc_v(StrLit(file.as_bytes())),
+            ));
+            block.push(arena.alloc_v(If(
+                arena.alloc_v(And(not_run(i), is_file)),
+                arena.alloc_v(Expr(arena.alloc_v(Assign(cur, ilit(i))))),
+                None,
+            )));
+        }
+        // if (started <= i && i <= cur) { <assignments before file i> }
+        for (i, group) in groups.iter().enumerate().take(n).skip(1) {
+            if group.len() == 0 {
+                continue;
+            }
+            block.push(arena.alloc_v(If(
+                arena.alloc_v(And(not_run(i), arena.alloc_v(Binop(LTE, ilit(i), cur)))),
+                arena.alloc_v(Block(group.clone())),
+                None,
+            )));
+        }
+        // started = cur + 1;
+        block.push(arena.alloc_v(Expr(
+            arena.alloc_v(Assign(started, arena.alloc_v(Binop(Plus, cur, ilit(1))))),
+        )));
+        let at_new_file = arena.alloc_v(If(
+            arena.alloc_v(Binop(EQ, arena.alloc_v(Var("FNR".into())), ilit(1))),
+            arena.alloc_v(Block(block)),
+            None,
+        ));
+
+        // Once the input is exhausted, run whatever is left.
+        let mut after = vec![];
+        for (i, group) in groups.into_iter().enumerate().skip(1) {
+            if group.len() > 0 {
+                after.push(arena.alloc_v(If(not_run(i), arena.alloc_v(Block(group)), None)));
+            }
+        }
+        (before, Some(at_new_file), after)
+    }
+
+    /// Perform the `var=value` entries in ARGV that the main input has passed over. These are
+    /// queued up as the reader looks for the next file to open, so we run this at the start of each
+    /// file and once the input is exhausted. Entries naming a variable outside of `vars` are
+    /// ignored.
+    fn desugar_argv_vars<'outer>(
+        &self,
+        arena: &'a Arena<'outer>,
+        vars: &[&'b str],
+    ) -> &'a Stmt<'a, 'b, I> {
+        use {self::Binop::*, self::Expr::*, Stmt::*};
+        // Illegal frawk identifiers holding the current entry, the position of its `=`, and its
+        // value.
+        const ARG_VAR: &'static str = "--argv";
+        const EQ_VAR: &'static str = "--argv-eq";
+        const VALUE_VAR: &'static str = "--argv-value";
+
+        let arg = arena.alloc_v(Var(ARG_VAR.into()));
+        let eq = arena.alloc_v(Var(EQ_VAR.into()));
+        let value = arena.alloc_v(Var(VALUE_VAR.into()));
+        let call = |f: Function, args: Vec<&'a self::Expr<'a, 'b, I>>| {
+            arena.alloc_v(Call(Either::Right(f), args))
+        };
+        let assign = |to: &'a self::Expr<'a, 'b, I>, from: &'a self::Expr<'a, 'b, I>| {
+            arena.alloc_v(Expr(arena.alloc_v(Assign(to, from))))
+        };
+
+        // if (--argv == "x") x = --argv-value; else if (--argv == "y") ...
+        let mut dispatch = None;
+        for v in vars.iter().rev() {
+            let is_var = arena.alloc_v(Binop(EQ, arg, arena.alloc_v(StrLit(v.as_bytes()))));
+            let set = assign(arena.alloc_v(Var((*v).into())), value);
+            dispatch = Some(&*arena.alloc_v(If(is_var, set, dispatch)));
+        }
+
+        // while ((--argv = argv_assign()) != "") {
+        //   --argv-eq = index(--argv, "=");
+        //   --argv-value = substr(--argv, --argv-eq + 1);
+        //   --argv = substr(--argv, 1, --argv-eq - 1);
+        //   <dispatch>
+        // }
+        let next = arena.alloc_v(Assign(arg, call(Function::ArgvAssign, vec![])));
+        let cond = arena.alloc_v(Unop(
+            self::Unop::Not,
+            arena.alloc_v(Binop(EQ, next, arena.alloc_v(StrLit(b"")))),
+        ));
+        let ilit = |i: i64| arena.alloc_v(ILit(i));
+        let mut body = vec![
+            assign(
+                eq,
+                call(
+                    Function::SubstrIndex,
+                    vec![arg, arena.alloc_v(StrLit(b"="))],
+                ),
+            ),
+            assign(
+                value,
+                call(
+                    Function::Substr,
+                    vec![arg, arena.alloc_v(Binop(Plus, eq, ilit(1)))],
+                ),
+            ),
+            assign(
+                arg,
+                call(
+                    Function::Substr,
+                    vec![arg, ilit(1), arena.alloc_v(Binop(Minus, eq, ilit(1)))],
+                ),
+            ),
+        ];
+        body.extend(dispatch);
+        arena.alloc_v(While(
+            /*is_toplevel=*/ false,
+            cond,
+            arena.alloc_v(Block(body)),
+        ))
+    }
+}
+
+impl<'a, 'b> Prog<'a, 'b, &'b str> {
+    /// The variables that a `var=value` entry in ARGV may assign while the program runs: the
+    /// global variables the program uses as scalars, the variables named by `var=value` operands,
+    /// and the string-valued special variables. A variable only ever passed to functions is left
+    /// out, as it may be an array.
+    pub(crate) fn argv_vars(&self) -> Vec<&'b str> {
+        let mut res = vec!["FS", "OFS", "ORS", "RS"];
+        res.extend(self.operand_vardecs.iter().map(|(_, v, _)| *v));
+        let mut add = |v: &&'b str, locals: &[&'b str]| {
+            if !locals.contains(v)
+                && !res.contains(v)
+                && !matches!(
+                    Variable::try_from(*v),
+                    Ok(Variable::ARGV) | Ok(Variable::FI)
+                )
+            {
+                res.push(*v)
+            }
+        };
+        self.any_toplevel(&mut |n| {
+            scalar_uses(n, &mut |v| add(v, &[]));
+            false
+        });
+        for d in self.decs.iter() {
+            d.body.any(&mut |n| {
+                scalar_uses(n, &mut |v| add(v, &d.args[..]));
+                false
+            });
+        }
+        res
+    }
+}
+
+/// Call `f` on each variable that `n` uses directly as a scalar: as an operand, as the target of
+/// an assignment, and so on. The arguments of function calls are skipped, as they may be arrays.
+fn scalar_uses<'a, 'b, I>(n: Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>, f: &mut impl FnMut(&I)) {
+    use Expr::*;
+    let mut scalar = |e: &Expr<'a, 'b, I>| {
+        if let Var(v) = e {
+            f(v)
+        }
+    };
+    match n {
+        Either::Right(e) => match e {
+            Unop(_, x) | Index(_, x) | Inc { x, .. } => scalar(x),
+            Binop(_, l, r) | Assign(l, r) | AssignOp(l, _, r) | And(l, r) | Or(l, r) => {
+                scalar(l);
+                scalar(r);
+            }
+            ITE(c, t, e) => {
+                scalar(c);
+                scalar(t);
+                scalar(e);
+            }
+            Getline { into, from, .. } => into.iter().chain(from.iter()).for_each(|e| scalar(e)),
+            _ => {}
+        },
+        Either::Left(s) => match s {
+            Stmt::Expr(e) | Stmt::If(e, _, _) | Stmt::DoWhile(e, _) | Stmt::While(_, e, _) => {
+                scalar(e)
+            }
+            Stmt::For(_, cond, _, _) => cond.iter().for_each(|e| scalar(e)),
+            Stmt::Return(e) | Stmt::Exit(e) => e.iter().for_each(|e| scalar(e)),
+            Stmt::ForEach(v, _, _) => f(v),
+            Stmt::Print(args, out) => args
+                .iter()
+                .chain(out.iter().map(|(o, _)| o))
+                .for_each(|e| scalar(e)),
+            Stmt::Printf(fmt, args, out) => std::iter::once(fmt)
+                .chain(args.iter())
+                .chain(out.iter().map(|(o, _)| o))
+                .for_each(|e| scalar(e)),
+            _ => {}
+        },
+    }
+}
+
+/// The largest value of NR for which `e` can hold, if `e` bounds NR from above.
+fn nr_bound<'a, 'b, I: Clone>(e: &Expr<'a, 'b, I>) -> Option<i64>
+where
+    Variable: TryFrom<I>,
+{
+    use {self::Binop::*, self::Expr::*};
+    let is_nr = |e: &Expr<'a, 'b, I>| match e {
+        Var(v) => matches!(Variable::try_from(v.clone()), Ok(Variable::NR)),
+        _ => false,
+    };
+    let bound = match e {
+        Binop(op, l, ILit(n)) if is_nr(l) => match op {
+            LT => n.saturating_sub(1),
+            LTE | EQ => *n,
+            _ => return None,
+        },
+        Binop(op, ILit(n), r) if is_nr(r) => match op {
+            GT => n.saturating_sub(1),
+            GTE | EQ => *n,
+            _ => return None,
+        },
+        And(l, r) => match (nr_bound(l), nr_bound(r)) {
+            (Some(l), Some(r)) => l.min(r),
+            (Some(b), None) | (None, Some(b)) => b,
+            (None, None) => return None,
+        },
+        Or(l, r) => nr_bound(l)?.max(nr_bound(r)?),
+        _ => return None,
+    };
+    Some(bound.max(0))
+}
+
+/// Whether `name` is one of the builtins describing where the last record came from; see
+/// `Prog::tracks_records`.
+fn is_record_source<I: Clone>(name: &I) -> bool
+where
+    Function: TryFrom<I>,
+{
+    matches!(
+        Function::try_from(name.clone()),
+        Ok(Function::RecordFile) | Ok(Function::RecordOffset) | Ok(Function::RecordText)
+    )
+}
+
+/// A regex matching exactly the records for which `e` holds, if `e` only looks at the whole
+/// record in one of the ways recognized by `Prog::prefilter`.
+fn record_guard<'a, 'b, I>(e: &Expr<'a, 'b, I>) -> Option<String> {
+    use {self::Binop::*, self::Expr::*};
+    let is_record = |e: &Expr<'a, 'b, I>| matches!(e, Unop(self::Unop::Column, ILit(0)));
+    let starts_with = |s: &[u8]| {
+        std::str::from_utf8(s)
+            .ok()
+            .map(|s| format!("^{}", regex::escape(s)))
+    };
+    match e {
+        PatLit(re) => std::str::from_utf8(re).ok().map(String::from),
+        Binop(IsMatch, x, PatLit(re)) if is_record(x) => {
+            std::str::from_utf8(re).ok().map(String::from)
+        }
+        Binop(EQ, l, r) => match (l, r) {
+            // index($0, s) == 1
+            (Call(Either::Right(Function::SubstrIndex), args), ILit(1))
+            | (ILit(1), Call(Either::Right(Function::SubstrIndex), args)) => match &args[..] {
+                [x, StrLit(s)] if is_record(x) => starts_with(s),
+                _ => None,
+            },
+            // substr($0, 1, n) == s, where s is n ASCII characters long.
+            (Call(Either::Right(Function::Substr), args), StrLit(s))
+            | (StrLit(s), Call(Either::Right(Function::Substr), args)) => match &args[..] {
+                [x, ILit(1), ILit(n)] if is_record(x) && s.is_ascii() && *n == s.len() as i64 => {
+                    starts_with(s)
+                }
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Binop {
     Plus,
     Minus,
@@ -400,6 +1458,8 @@ pub enum Expr<'a, 'b, I> {
 
 #[derive(Debug, Clone)]
 pub enum Stmt<'a, 'b, I> {
+    // A statement annotated with its location in the program text.
+    Located(Span, &'a Stmt<'a, 'b, I>),
     StartCond(usize),
     EndCond(usize),
     LastCond(usize),
@@ -440,4 +1500,413 @@ pub enum Stmt<'a, 'b, I> {
     Next,
     NextFile,
     Return(Option<&'a Expr<'a, 'b, I>>),
+    // `exit [status]`: record the exit status, then jump to the end of the enclosing `Phase`.
+    Exit(Option<&'a Expr<'a, 'b, I>>),
+    // A part of the program that `exit` leaves early: BEGIN along with the main loop, or END. See
+    // `Prog::desugar_stage`.
+    Phase(&'a Stmt<'a, 'b, I>),
+}
+
+impl<'a, 'b, I> Expr<'a, 'b, I> {
+    /// Whether `f` holds for this expression or any of its subexpressions.
+    pub(crate) fn any(
+        &self,
+        f: &mut impl FnMut(Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>) -> bool,
+    ) -> bool {
+        use Expr::*;
+        if f(Either::Right(self)) {
+            return true;
+        }
+        match self {
+            ILit(_) | FLit(_) | StrLit(_) | PatLit(_) | Var(_) | ReadStdin | Cond(_) => false,
+            Unop(_, e) | Inc { x: e, .. } => e.any(f),
+            Binop(_, l, r)
+            | Index(l, r)
+            | Assign(l, r)
+            | AssignOp(l, _, r)
+            | And(l, r)
+            | Or(l, r) => l.any(f) || r.any(f),
+            ITE(c, t, e) => c.any(f) || t.any(f) || e.any(f),
+            Call(_, args) => args.iter().any(|a| a.any(f)),
+            Getline { into, from, .. } => into.iter().chain(from.iter()).any(|e| e.any(f)),
+        }
+    }
+}
+
+impl<'a, 'b, I> Stmt<'a, 'b, I> {
+    /// Whether `f` holds for this statement, or any statement or expression within it.
+    pub(crate) fn any(
+        &self,
+        f: &mut impl FnMut(Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>) -> bool,
+    ) -> bool {
+        use Stmt::*;
+        if f(Either::Left(self)) {
+            return true;
+        }
+        match self {
+            Located(_, s) | Phase(s) => s.any(f),
+            StartCond(_) | EndCond(_) | LastCond(_) | Break | Continue | Next | NextFile => false,
+            Expr(e) => e.any(f),
+            DoWhile(e, s) | While(_, e, s) | ForEach(_, e, s) => e.any(f) || s.any(f),
+            Block(ss) => ss.iter().any(|s| s.any(f)),
+            Print(args, out) => args.iter().any(|a| a.any(f)) || out.iter().any(|(o, _)| o.any(f)),
+            Printf(fmt, args, out) => {
+                fmt.any(f) || args.iter().any(|a| a.any(f)) || out.iter().any(|(o, _)| o.any(f))
+            }
+            If(c, t, e) => c.any(f) || t.any(f) || e.iter().any(|s| s.any(f)),
+            For(init, cond, update, body) => {
+                init.iter().any(|s| s.any(f))
+                    || cond.iter().any(|e| e.any(f))
+                    || update.iter().any(|s| s.any(f))
+                    || body.any(f)
+            }
+            Return(e) | Exit(e) => e.iter().any(|e| e.any(f)),
+        }
+    }
+}
+
+impl<'a, 'b, I: Clone + Eq + Hash> FunDec<'a, 'b, I> {
+    /// The expression this function returns, if calls to it can be replaced by that expression.
+    ///
+    /// This holds for functions whose body is a single `return` of a small expression with no side
+    /// effects and no calls to other functions. Parameters must not be used as arrays, as an
+    /// inlined parameter holds a copy of its argument rather than a reference to it. We also skip
+    /// operations that can fail at runtime, so that the stack trace reported for an error still
+    /// includes the function. Alongside the expression, we return the other variables it reads:
+    /// these refer to globals in the body of the function, so the body cannot be inlined into a
+    /// function with parameters of the same name. `declared` says whether a name belongs to a
+    /// function declared in the program, rather than a builtin.
+    pub(crate) fn inline_body(
+        &self,
+        declared: impl Fn(&I) -> bool,
+    ) -> Option<(&'a Expr<'a, 'b, I>, Vec<I>)>
+    where
+        Function: TryFrom<I>,
+    {
+        const MAX_SIZE: usize = 32;
+        let mut stmt = self.body;
+        let body = loop {
+            match stmt {
+                Stmt::Located(_, s) => stmt = s,
+                Stmt::Block(ss) if ss.len() == 1 => stmt = ss[0],
+                Stmt::Return(Some(e)) => break *e,
+                _ => return None,
+            }
+        };
+        let is_param = |e: &Expr<'a, 'b, I>| matches!(e, Expr::Var(v) if self.args.contains(v));
+        let mut size = 0;
+        let mut free = Vec::new();
+        let disqualified = body.any(&mut |n| {
+            use Expr::*;
+            size += 1;
+            if size > MAX_SIZE {
+                return true;
+            }
+            let e = match n {
+                Either::Right(e) => e,
+                Either::Left(_) => return true,
+            };
+            match e {
+                Var(v) => {
+                    if !self.args.contains(v) && !free.contains(v) {
+                        free.push(v.clone());
+                    }
+                    false
+                }
+                Index(m, _) => is_param(m),
+                Binop(self::Binop::Mod, _, _) | Binop(self::Binop::Div, _, _) => true,
+                Unop(self::Unop::Column, e) => !matches!(e, ILit(_)),
+                Binop(self::Binop::IsMatch, _, pat) => !matches!(pat, PatLit(_)),
+                Call(f, args) => {
+                    let f = match f {
+                        Either::Left(name) if declared(name) => return true,
+                        Either::Left(name) => match Function::try_from(name.clone()) {
+                            Ok(f) => f,
+                            Err(_) => return true,
+                        },
+                        Either::Right(f) => *f,
+                    };
+                    !crate::cse::is_side_effect_free(f)
+                        || matches!(f, Function::IntFunc(_) | Function::Unop(self::Unop::Column))
+                        || (matches!(f, Function::Length | Function::Contains)
+                            && args.iter().any(|a| is_param(a)))
+                }
+                Assign(..) | AssignOp(..) | Inc { .. } | Getline { .. } | ReadStdin | Cond(_) => {
+                    true
+                }
+                _ => false,
+            }
+        });
+        if disqualified {
+            None
+        } else {
+            Some((body, free))
+        }
+    }
+}
+
+impl<'a, 'b, I: Clone + PartialEq> Pattern<'a, 'b, I> {
+    /// Whether this pattern can be shown to never match a record, in which case its rule is
+    /// skipped. A range pattern never matches if its first pattern never matches.
+    pub(crate) fn never_matches(&self) -> bool
+    where
+        Variable: TryFrom<I>,
+    {
+        match self {
+            Pattern::Null | Pattern::Group(..) => false,
+            Pattern::Bool(e) | Pattern::Comma(e, _) => e.never_true(),
+        }
+    }
+}
+
+// The key and bounds of a comparison against a numeric constant, as used by `never_true`.
+enum CmpKey<'c, I> {
+    Var(&'c I),
+    Column(i64),
+}
+
+#[derive(Default)]
+struct Bounds {
+    // (value, inclusive)
+    lo: Option<(f64, bool)>,
+    hi: Option<(f64, bool)>,
+}
+
+impl Bounds {
+    fn add(&mut self, op: Binop, v: f64) {
+        fn tighten(cur: &mut Option<(f64, bool)>, new: (f64, bool), is_lo: bool) {
+            *cur = match *cur {
+                Some(old) if old.0 == new.0 => Some((old.0, old.1 && new.1)),
+                Some(old) if (old.0 > new.0) == is_lo => Some(old),
+                _ => Some(new),
+            }
+        }
+        match op {
+            Binop::GT => tighten(&mut self.lo, (v, false), true),
+            Binop::GTE => tighten(&mut self.lo, (v, true), true),
+            Binop::LT => tighten(&mut self.hi, (v, false), false),
+            Binop::LTE => tighten(&mut self.hi, (v, true), false),
+            Binop::EQ => {
+                tighten(&mut self.lo, (v, true), true);
+                tighten(&mut self.hi, (v, true), false);
+            }
+            _ => {}
+        }
+    }
+    fn is_empty(&self) -> bool {
+        match (self.lo, self.hi) {
+            (Some((lo, lo_inc)), Some((hi, hi_inc))) => {
+                lo > hi || (lo == hi && !(lo_inc && hi_inc))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<'a, 'b, I: Clone + PartialEq> Expr<'a, 'b, I> {
+    /// Whether this expression is false no matter the input: it has no side effects, and it is
+    /// either a constant or a conjunction of contradictory comparisons against numbers, like
+    /// `NR < 5 && NR > 10`.
+    pub(crate) fn never_true(&self) -> bool
+    where
+        Variable: TryFrom<I>,
+    {
+        use Expr::*;
+        let impure = self.any(&mut |n| {
+            matches!(
+                n,
+                Either::Right(Assign(..))
+                    | Either::Right(AssignOp(..))
+                    | Either::Right(Inc { .. })
+                    | Either::Right(Call(..))
+                    | Either::Right(Getline { .. })
+                    | Either::Right(ReadStdin)
+            )
+        });
+        !impure && (self.const_truth() == Some(false) || self.contradictory())
+    }
+
+    fn num_lit(&self) -> Option<f64> {
+        match self {
+            Expr::ILit(i) => Some(*i as f64),
+            Expr::FLit(f) => Some(*f),
+            Expr::Unop(Unop::Neg, e) => e.num_lit().map(|f| -f),
+            _ => None,
+        }
+    }
+
+    // The value of a constant condition. Floating-point values are truncated to integers, as they
+    // are when a condition is evaluated at runtime.
+    fn const_truth(&self) -> Option<bool> {
+        use Expr::*;
+        match self {
+            ILit(i) => Some(*i != 0),
+            FLit(f) => Some(*f as i64 != 0),
+            StrLit(s) => Some(!s.is_empty()),
+            Unop(self::Unop::Not, e) => e.const_truth().map(|b| !b),
+            And(l, r) => match (l.const_truth(), r.const_truth()) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Or(l, r) => match (l.const_truth(), r.const_truth()) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Binop(op, l, r) => {
+                let (l, r) = (l.num_lit()?, r.num_lit()?);
+                match op {
+                    self::Binop::LT => Some(l < r),
+                    self::Binop::GT => Some(l > r),
+                    self::Binop::LTE => Some(l <= r),
+                    self::Binop::GTE => Some(l >= r),
+                    self::Binop::EQ => Some(l == r),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // A variable or column that is compared against a number as a number. Strings are compared
+    // with numbers as numbers unless gawk compatibility is enabled, in which case we stick to the
+    // numeric builtin variables.
+    fn cmp_key(&self) -> Option<CmpKey<'_, I>>
+    where
+        Variable: TryFrom<I>,
+    {
+        let gawk = crate::runtime::compat::gawk();
+        match self {
+            Expr::Var(v) => match Variable::try_from(v.clone()) {
+                Ok(Variable::NR) | Ok(Variable::FNR) | Ok(Variable::NF) => Some(CmpKey::Var(v)),
+                _ if gawk => None,
+                _ => Some(CmpKey::Var(v)),
+            },
+            Expr::Unop(Unop::Column, c) if !gawk => match c {
+                Expr::ILit(i) => Some(CmpKey::Column(*i)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn contradictory(&self) -> bool
+    where
+        Variable: TryFrom<I>,
+    {
+        fn conjuncts<'c, 'a, 'b, I>(e: &'c Expr<'a, 'b, I>, res: &mut Vec<&'c Expr<'a, 'b, I>>) {
+            match e {
+                Expr::And(l, r) => {
+                    conjuncts(l, res);
+                    conjuncts(r, res);
+                }
+                e => res.push(e),
+            }
+        }
+        let mut cs = Vec::new();
+        conjuncts(self, &mut cs);
+        let mut bounds: Vec<(CmpKey<I>, Bounds)> = Vec::new();
+        for c in cs {
+            use self::Binop::*;
+            let (op, key, v) = match c {
+                Expr::Binop(op, l, r) if matches!(op, LT | GT | LTE | GTE | EQ) => {
+                    match (l.cmp_key(), r.num_lit(), r.cmp_key(), l.num_lit()) {
+                        (Some(key), Some(v), _, _) => (*op, key, v),
+                        // Flip `5 < x` to `x > 5`.
+                        (_, _, Some(key), Some(v)) => {
+                            let op = match op {
+                                LT => GT,
+                                GT => LT,
+                                LTE => GTE,
+                                GTE => LTE,
+                                op => *op,
+                            };
+                            (op, key, v)
+                        }
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let same = |k: &CmpKey<I>| match (k, &key) {
+                (CmpKey::Var(v1), CmpKey::Var(v2)) => v1 == v2,
+                (CmpKey::Column(c1), CmpKey::Column(c2)) => c1 == c2,
+                _ => false,
+            };
+            let b = match bounds.iter().position(|(k, _)| same(k)) {
+                Some(i) => &mut bounds[i].1,
+                None => {
+                    bounds.push((key, Bounds::default()));
+                    &mut bounds.last_mut().unwrap().1
+                }
+            };
+            b.add(op, v);
+            if b.is_empty() {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl<'a, 'b, I: Clone + Eq + Hash> Prog<'a, 'b, I> {
+    /// The functions that may be called when the program runs: those called from BEGIN, END,
+    /// SIGNAL, the rules that are not skipped (see `Pattern::never_matches`), the functions
+    /// registered with `atexit`, and the functions they call in turn. Code is only generated for these functions.
+    pub(crate) fn called_functions(&self) -> HashSet<I>
+    where
+        Variable: TryFrom<I>,
+        I: From<&'b str> + IsSprintf,
+    {
+        fn push_call<'a, 'b, I: Clone>(
+            n: Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>,
+            wl: &mut Vec<I>,
+        ) -> bool {
+            if let Either::Right(Expr::Call(Either::Left(name), _)) = n {
+                wl.push(name.clone());
+            }
+            false
+        }
+        let mut wl = Vec::new();
+        let f = &mut |n: Either<&Stmt<'a, 'b, I>, &Expr<'a, 'b, I>>| push_call(n, &mut wl);
+        for e in self
+            .prelude_vardecs
+            .iter()
+            .map(|(_, e)| e)
+            .chain(self.operand_vardecs.iter().map(|(_, _, e)| e))
+        {
+            e.any(f);
+        }
+        for s in self
+            .begin
+            .iter()
+            .chain(self.prepare.iter())
+            .chain(self.end.iter())
+            .chain(self.signal.iter())
+        {
+            s.any(f);
+        }
+        for (_, pat, body) in self.pats.iter() {
+            match pat {
+                _ if pat.never_matches() => continue,
+                Pattern::Null => false,
+                Pattern::Bool(e) => e.any(f),
+                Pattern::Comma(l, r) => l.any(f) || r.any(f),
+                Pattern::Group(e) => e.any(f),
+            };
+            body.iter().any(|s| s.any(f));
+        }
+        wl.extend(self.atexit_functions().into_iter().map(I::from));
+        let mut called = HashSet::default();
+        while let Some(name) = wl.pop() {
+            if !called.insert(name.clone()) {
+                continue;
+            }
+            if let Some(dec) = self.decs.iter().find(|d| d.name == name) {
+                dec.body.any(&mut |n| push_call(n, &mut wl));
+            }
+        }
+        called
+    }
 }
@@ -4,13 +4,13 @@ use crate::ast;
 use crate::common::Either;
 use crate::common::{NodeIx, Result};
 use crate::compile;
-use crate::runtime::{Int, IntMap, Str, StrMap};
+use crate::runtime::{self, Int, IntMap, Str, StrMap};
 use crate::types::{self, SmallVec};
 use smallvec::smallvec;
 
 use std::convert::TryFrom;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Function {
     Unop(ast::Unop),
     Binop(ast::Binop),
@@ -30,6 +30,9 @@ pub enum Function {
     Length,
     Contains,
     Delete,
+    Bound,
+    LoadState,
+    SaveState,
     Match,
     SubstrIndex,
     Sub,
@@ -40,8 +43,72 @@ pub enum Function {
     JoinCSV,
     JoinTSV,
     Substr,
+    Graphemes,
+    GraphemeLength,
+    GraphemeSubstr,
+    Levenshtein,
+    JaroWinkler,
+    CmpNatural,
+    CmpLocale,
+    Trim,
+    LTrim,
+    RTrim,
+    Squeeze,
+    Color,
+    Bold,
+    LPad,
+    RPad,
+    ZFill,
     ToInt,
     HexToInt,
+    IsNA,
+    NumOr,
+    IntDiv,
+    DivMod,
+    HllAdd,
+    HllCount,
+    BloomAdd,
+    BloomHas,
+    Reservoir,
+    WinPush,
+    WinSum,
+    WinAvg,
+    WinMax,
+    HistAdd,
+    HistFormat,
+    VecDot,
+    VecNorm,
+    VecAdd,
+    VecSub,
+    VecMul,
+    VecScale,
+    FSum,
+    FMean,
+    Readable,
+    SetTimeout,
+    Spawn,
+    ReadLine,
+    WriteLine,
+    Wait,
+    ReadCmd,
+    Getenv,
+    Setenv,
+    Stat,
+    Glob,
+    Mkdirp,
+    Rename,
+    RmFile,
+    Sleep,
+    Monotime,
+    Signal,
+    RecordFile,
+    RecordOffset,
+    RecordText,
+    // The separator `print` writes after $0 in round-trip mode; see `FileRead::round_trip_sep`.
+    RoundTripSep,
+    // ArgvAssign() returns the next `var=value` entry in ARGV that the main input has passed over,
+    // or "" if there is none; see `FileRead::argv_assign`.
+    ArgvAssign,
     Rand,
     Srand,
     ReseedRng,
@@ -49,9 +116,28 @@ pub enum Function {
     // For header-parsing logic
     UpdateUsedFields,
     SetFI,
+    // MatchSet(s, pat_1, ..., pat_n) sets bit i-1 of its result if pat_i matches s. The patterns
+    // must be string constants. Used to dispatch on many `/re/` rules at once.
+    MatchSet,
+    // SetExit(n) records n as the exit status of frawk; used to implement `exit n`.
+    SetExit,
+    // Exiting() returns whether an `exit` is unwinding to the end of the current phase, and
+    // SetExiting(n) starts (n = 1) or stops (n = 0, or n = -1 to stop even when a second signal
+    // has asked frawk to stop) unwinding, returning Exiting(). See `runtime::exit`.
+    Exiting,
+    SetExiting,
+    // For `--tiered`: TierUp() returns 1 once the main loop should stop so that compiled code can
+    // take over from the interpreter. See `runtime::tiered`.
+    TierUp,
+    // For `--match-status`: SetMatched() records that a rule's action ran, and returns 1.
+    SetMatched,
+    // For `--strict`: MarkInit(i) records that the i'th checked variable has been assigned, and
+    // CheckInit(i, name) fails if it has not. Both return 1. See `strict::instrument`.
+    MarkInit,
+    CheckInit,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Bitwise {
     Complement,
     And,
@@ -60,6 +146,10 @@ pub enum Bitwise {
     ArithmeticRightShift,
     LeftShift,
     Xor,
+    RotateLeft,
+    RotateRight,
+    PopCount,
+    LeadingZeros,
 }
 
 impl Bitwise {
@@ -73,15 +163,20 @@ impl Bitwise {
             ArithmeticRightShift => "rshift",
             LeftShift => "lshift",
             Xor => "xor",
+            RotateLeft => "rotl",
+            RotateRight => "rotr",
+            PopCount => "popcount",
+            LeadingZeros => "clz",
         }
     }
     pub fn eval1(&self, op: i64) -> i64 {
         use Bitwise::*;
         match self {
             Complement => !op,
-            And | Or | LogicalRightShift | ArithmeticRightShift | LeftShift | Xor => {
-                panic!("bitwise: mismatched arity!")
-            }
+            PopCount => op.count_ones() as i64,
+            LeadingZeros => op.leading_zeros() as i64,
+            And | Or | LogicalRightShift | ArithmeticRightShift | LeftShift | Xor | RotateLeft
+            | RotateRight => panic!("bitwise: mismatched arity!"),
         }
     }
     pub fn eval2(&self, lhs: i64, rhs: i64) -> i64 {
@@ -93,14 +188,17 @@ impl Bitwise {
             ArithmeticRightShift => lhs.wrapping_shr(rhs as u32),
             LeftShift => lhs.wrapping_shl(rhs as u32),
             Xor => lhs ^ rhs,
-            Complement => panic!("bitwise: mismatched arity!"),
+            RotateLeft => lhs.rotate_left(rhs as u32),
+            RotateRight => lhs.rotate_right(rhs as u32),
+            Complement | PopCount | LeadingZeros => panic!("bitwise: mismatched arity!"),
         }
     }
     pub fn arity(&self) -> usize {
         use Bitwise::*;
         match self {
-            Complement => 1,
-            And | Or | LogicalRightShift | ArithmeticRightShift | LeftShift | Xor => 2,
+            Complement | PopCount | LeadingZeros => 1,
+            And | Or | LogicalRightShift | ArithmeticRightShift | LeftShift | Xor | RotateLeft
+            | RotateRight => 2,
         }
     }
     fn sig(&self) -> (SmallVec<compile::Ty>, compile::Ty) {
@@ -114,7 +212,7 @@ impl Bitwise {
 
 // TODO: move the llvm-level code back into the LLVM module.
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum FloatFunc {
     Cos,
     Sin,
@@ -194,13 +292,75 @@ static_map!(
     FUNCTIONS<&'static str, Function>,
     ["close", Function::Close],
     ["split", Function::Split],
+    ["bound", Function::Bound],
+    ["load_state", Function::LoadState],
+    ["save_state", Function::SaveState],
     ["length", Function::Length],
     ["match", Function::Match],
     ["sub", Function::Sub],
     ["gsub", Function::GSub],
     ["substr", Function::Substr],
+    ["graphemes", Function::Graphemes],
+    ["grapheme_length", Function::GraphemeLength],
+    ["grapheme_substr", Function::GraphemeSubstr],
+    ["levenshtein", Function::Levenshtein],
+    ["jaro_winkler", Function::JaroWinkler],
+    ["cmp_natural", Function::CmpNatural],
+    ["cmp_locale", Function::CmpLocale],
+    ["trim", Function::Trim],
+    ["ltrim", Function::LTrim],
+    ["rtrim", Function::RTrim],
+    ["squeeze", Function::Squeeze],
+    ["color", Function::Color],
+    ["bold", Function::Bold],
+    ["lpad", Function::LPad],
+    ["rpad", Function::RPad],
+    ["zfill", Function::ZFill],
     ["int", Function::ToInt],
     ["hex", Function::HexToInt],
+    ["isna", Function::IsNA],
+    ["numor", Function::NumOr],
+    ["intdiv", Function::IntDiv],
+    ["divmod", Function::DivMod],
+    ["hll_add", Function::HllAdd],
+    ["hll_count", Function::HllCount],
+    ["bloom_add", Function::BloomAdd],
+    ["bloom_has", Function::BloomHas],
+    ["reservoir", Function::Reservoir],
+    ["win_push", Function::WinPush],
+    ["win_sum", Function::WinSum],
+    ["win_avg", Function::WinAvg],
+    ["win_max", Function::WinMax],
+    ["hist_add", Function::HistAdd],
+    ["hist_format", Function::HistFormat],
+    ["vec_dot", Function::VecDot],
+    ["vec_norm", Function::VecNorm],
+    ["vec_add", Function::VecAdd],
+    ["vec_sub", Function::VecSub],
+    ["vec_mul", Function::VecMul],
+    ["vec_scale", Function::VecScale],
+    ["fsum", Function::FSum],
+    ["fmean", Function::FMean],
+    ["readable", Function::Readable],
+    ["settimeout", Function::SetTimeout],
+    ["spawn", Function::Spawn],
+    ["readline", Function::ReadLine],
+    ["writeline", Function::WriteLine],
+    ["wait", Function::Wait],
+    ["readcmd", Function::ReadCmd],
+    ["getenv", Function::Getenv],
+    ["setenv", Function::Setenv],
+    ["stat", Function::Stat],
+    ["glob", Function::Glob],
+    ["mkdirp", Function::Mkdirp],
+    ["rename", Function::Rename],
+    ["rmfile", Function::RmFile],
+    ["sleep", Function::Sleep],
+    ["monotime", Function::Monotime],
+    ["signal", Function::Signal],
+    ["record_file", Function::RecordFile],
+    ["record_offset", Function::RecordOffset],
+    ["record_text", Function::RecordText],
     ["exp", Function::FloatFunc(FloatFunc::Exp)],
     ["cos", Function::FloatFunc(FloatFunc::Cos)],
     ["sin", Function::FloatFunc(FloatFunc::Sin)],
@@ -217,6 +377,10 @@ static_map!(
     ["rshift", Function::IntFunc(Bitwise::ArithmeticRightShift)],
     ["rshiftl", Function::IntFunc(Bitwise::LogicalRightShift)],
     ["xor", Function::IntFunc(Bitwise::Xor)],
+    ["rotl", Function::IntFunc(Bitwise::RotateLeft)],
+    ["rotr", Function::IntFunc(Bitwise::RotateRight)],
+    ["popcount", Function::IntFunc(Bitwise::PopCount)],
+    ["clz", Function::IntFunc(Bitwise::LeadingZeros)],
     ["join_fields", Function::JoinCols],
     ["join_csv", Function::JoinCSV],
     ["join_tsv", Function::JoinTSV],
@@ -240,19 +404,154 @@ impl<'a> TryFrom<&'a str> for Function {
 
 pub(crate) trait IsSprintf {
     fn is_sprintf(&self) -> bool;
+    fn is_tee(&self) -> bool;
+    fn is_sample_bernoulli(&self) -> bool;
+    fn is_hist_print(&self) -> bool;
+    fn is_atexit(&self) -> bool;
 }
 impl<'a> IsSprintf for &'a str {
     fn is_sprintf(&self) -> bool {
         *self == "sprintf"
     }
+    fn is_tee(&self) -> bool {
+        *self == "tee"
+    }
+    fn is_sample_bernoulli(&self) -> bool {
+        *self == "sample_bernoulli"
+    }
+    fn is_hist_print(&self) -> bool {
+        *self == "hist_print"
+    }
+    fn is_atexit(&self) -> bool {
+        *self == "atexit"
+    }
 }
 
 impl Function {
+    /// Whether a function declared in the program may not have this builtin's name. This holds for
+    /// the functions of POSIX Awk and frawk's original extensions. A function declared with the
+    /// name of any other builtin takes precedence over it, so that programs written before that
+    /// builtin was added keep working.
+    pub(crate) fn is_reserved(&self) -> bool {
+        use Function::*;
+        match self {
+            IntFunc(bw) => !matches!(
+                bw,
+                Bitwise::RotateLeft
+                    | Bitwise::RotateRight
+                    | Bitwise::PopCount
+                    | Bitwise::LeadingZeros
+            ),
+            FloatFunc(_) | Close | Split | Length | Match | Sub | GSub | Substr | SubstrIndex
+            | ToInt | HexToInt | Rand | Srand | System | EscapeCSV | EscapeTSV | JoinCSV
+            | JoinTSV | JoinCols => true,
+            _ => false,
+        }
+    }
+
     // feedback allows for certain functions to propagate type information back to their arguments.
     pub(crate) fn feedback(&self, args: &[NodeIx], ctx: &mut types::TypeContext) {
         use types::{BaseTy, Constraint, TVar::*};
         match self {
-            Function::Split => {
+            Function::Split | Function::Graphemes => {
+                let arg1 = ctx.constant(
+                    Map {
+                        key: BaseTy::Int,
+                        val: BaseTy::Str,
+                    }
+                    .abs(),
+                );
+                ctx.nw.add_dep(arg1, args[1], Constraint::Flows(()));
+            }
+            Function::IntDiv | Function::DivMod => {
+                let key = if let Function::IntDiv = self {
+                    BaseTy::Str
+                } else {
+                    BaseTy::Int
+                };
+                let arg2 = ctx.constant(
+                    Map {
+                        key,
+                        val: BaseTy::Int,
+                    }
+                    .abs(),
+                );
+                ctx.nw.add_dep(arg2, args[2], Constraint::Flows(()));
+            }
+            Function::HllAdd | Function::HllCount | Function::BloomAdd | Function::BloomHas => {
+                let arg0 = ctx.constant(
+                    Map {
+                        key: BaseTy::Int,
+                        val: BaseTy::Int,
+                    }
+                    .abs(),
+                );
+                ctx.nw.add_dep(arg0, args[0], Constraint::Flows(()));
+            }
+            Function::Reservoir => {
+                let arg0 = ctx.constant(
+                    Map {
+                        key: BaseTy::Int,
+                        val: BaseTy::Str,
+                    }
+                    .abs(),
+                );
+                ctx.nw.add_dep(arg0, args[0], Constraint::Flows(()));
+            }
+            Function::WinPush | Function::WinSum | Function::WinAvg | Function::WinMax => {
+                let arg0 = ctx.constant(
+                    Map {
+                        key: BaseTy::Int,
+                        val: BaseTy::Float,
+                    }
+                    .abs(),
+                );
+                ctx.nw.add_dep(arg0, args[0], Constraint::Flows(()));
+            }
+            Function::VecDot
+            | Function::VecNorm
+            | Function::VecAdd
+            | Function::VecSub
+            | Function::VecMul
+            | Function::VecScale => {
+                // Every argument but the scale factor is a vector.
+                let vecs = match self {
+                    Function::VecNorm => 1,
+                    Function::VecDot | Function::VecScale => 2,
+                    _ => 3,
+                };
+                for arg in &args[..std::cmp::min(vecs, args.len())] {
+                    let v = ctx.constant(
+                        Map {
+                            key: BaseTy::Int,
+                            val: BaseTy::Float,
+                        }
+                        .abs(),
+                    );
+                    ctx.nw.add_dep(v, *arg, Constraint::Flows(()));
+                }
+            }
+            Function::HistAdd | Function::HistFormat => {
+                let arg0 = ctx.constant(
+                    Map {
+                        key: BaseTy::Int,
+                        val: BaseTy::Int,
+                    }
+                    .abs(),
+                );
+                ctx.nw.add_dep(arg0, args[0], Constraint::Flows(()));
+            }
+            Function::Stat => {
+                let arg1 = ctx.constant(
+                    Map {
+                        key: BaseTy::Str,
+                        val: BaseTy::Str,
+                    }
+                    .abs(),
+                );
+                ctx.nw.add_dep(arg1, args[1], Constraint::Flows(()));
+            }
+            Function::Glob => {
                 let arg1 = ctx.constant(
                     Map {
                         key: BaseTy::Int,
@@ -262,6 +561,16 @@ impl Function {
                 );
                 ctx.nw.add_dep(arg1, args[1], Constraint::Flows(()));
             }
+            Function::Readable => {
+                let arg0 = ctx.constant(
+                    Map {
+                        key: BaseTy::Str,
+                        val: BaseTy::Int,
+                    }
+                    .abs(),
+                );
+                ctx.nw.add_dep(arg0, args[0], Constraint::Flows(()));
+            }
             Function::Contains => {
                 let arr = args[0];
                 let query = args[1];
@@ -272,6 +581,8 @@ impl Function {
                 let query = args[1];
                 ctx.nw.add_dep(query, arr, Constraint::KeyIn(()));
             }
+            Function::LoadState => ctx.constrain_as_loaded(args[0]),
+            Function::SaveState => ctx.constrain_as_map(args[0]),
             Function::Sub | Function::GSub => {
                 let out_str = args[2];
                 let str_const = ctx.constant(Scalar(BaseTy::Str).abs());
@@ -303,13 +614,17 @@ impl Function {
         Ok(match self {
             FloatFunc(ff) => ff.sig(),
             IntFunc(bw) => bw.sig(),
+            // See runtime::arith for when integer arithmetic produces floating-point numbers.
+            Unop(Neg) if runtime::arith::int_promotion() => (smallvec![Float], Float),
             Unop(Neg) | Unop(Pos) => match &incoming[0] {
                 Str | Float => (smallvec![Float], Float),
                 _ => (smallvec![Int], Int),
             },
             Unop(Column) => (smallvec![Int], Str),
-            Binop(Concat) => (smallvec![Str; 2], Str),
-            SubstrIndex | Binop(IsMatch) => (smallvec![Str; 2], Int),
+            Binop(Concat) | Trim | LTrim | RTrim | Color => (smallvec![Str; 2], Str),
+            SubstrIndex | Levenshtein | Binop(IsMatch) => (smallvec![Str; 2], Int),
+            JaroWinkler => (smallvec![Str; 2], Float),
+            CmpNatural | CmpLocale => (smallvec![Str; 2], Int),
             // Not doesn't unconditionally convert to integers before negating it. Nonempty strings
             // are considered "truthy". Floating point numbers are converted beforehand:
             //    !5 == !1 == 0
@@ -324,12 +639,18 @@ impl Function {
             Binop(LT) | Binop(GT) | Binop(LTE) | Binop(GTE) | Binop(EQ) => (
                 match (incoming[0], incoming[1]) {
                     (Str, Str) => smallvec![Str; 2],
+                    // gawk compares strings with numbers as strings, unless the string looks
+                    // numeric; see runtime::compat.
+                    (_, Str) | (Str, _) if runtime::compat::gawk() => smallvec![Str; 2],
                     (Int, Int) | (Null, Int) | (Int, Null) | (Null, Null) => smallvec![Int; 2],
                     (_, Str) | (Str, _) | (Float, _) | (_, Float) => smallvec![Float; 2],
                     _ => return err!("invalid input spec for comparison op: {:?}", &incoming[..]),
                 },
                 Int,
             ),
+            Binop(Plus) | Binop(Minus) | Binop(Mult) if runtime::arith::int_promotion() => {
+                (smallvec![Float; 2], Float)
+            }
             Binop(Plus) | Binop(Minus) | Binop(Mod) | Binop(Mult) => {
                 match (incoming[0], incoming[1]) {
                     (Str, _) | (_, Str) | (Float, _) | (_, Float) => (smallvec![Float; 2], Float),
@@ -347,8 +668,21 @@ impl Function {
                 MapStrInt | MapStrStr | MapStrFloat => (smallvec![incoming[0], Str], Int),
                 _ => return err!("invalid input spec fo Delete: {:?}", &incoming[..]),
             },
+            Bound => match incoming[0] {
+                MapIntInt | MapIntStr | MapIntFloat | MapStrInt | MapStrStr | MapStrFloat => {
+                    (smallvec![incoming[0], Int, Str], Int)
+                }
+                _ => return err!("invalid input spec for bound: {:?}", &incoming[..]),
+            },
+            LoadState | SaveState => match incoming[0] {
+                MapIntInt | MapIntStr | MapIntFloat | MapStrInt | MapStrStr | MapStrFloat => {
+                    (smallvec![incoming[0], Str], Int)
+                }
+                _ => return err!("invalid input spec for {}: {:?}", self, &incoming[..]),
+            },
             Srand => (smallvec![Int], Int),
-            System | HexToInt => (smallvec![Str], Int),
+            System | HexToInt | IsNA => (smallvec![Str], Int),
+            NumOr => (smallvec![Str, Float], Float),
             ReseedRng => (smallvec![], Int),
             Rand => (smallvec![], Float),
             ToInt => {
@@ -365,16 +699,108 @@ impl Function {
             }
             NextlineCmd | Nextline => (smallvec![Str], Str),
             ReadErrCmd | ReadErr => (smallvec![Str], Int),
-            UpdateUsedFields | NextFile | ReadLineStdinFused => (smallvec![], Int),
+            UpdateUsedFields | NextFile => (smallvec![], Int),
             NextlineStdin => (smallvec![], Str),
-            ReadErrStdin => (smallvec![], Int),
+            ReadErrStdin | ReadLineStdinFused => (smallvec![], Int),
             // irrelevant return type
             Setcol => (smallvec![Int, Str], Int),
             Length => (smallvec![incoming[0]], Int),
             Close => (smallvec![Str], Str),
             Sub | GSub => (smallvec![Str, Str, Str], Int),
-            EscapeCSV | EscapeTSV => (smallvec![Str], Str),
-            Substr => (smallvec![Str, Int, Int], Str),
+            EscapeCSV | EscapeTSV | Squeeze | Bold => (smallvec![Str], Str),
+            Substr | GraphemeSubstr => (smallvec![Str, Int, Int], Str),
+            LPad | RPad => (smallvec![Str, Int, Str], Str),
+            ZFill => (smallvec![Str, Int], Str),
+            GraphemeLength => (smallvec![Str], Int),
+            Graphemes => {
+                if let MapIntStr = incoming[1] {
+                    (smallvec![Str, incoming[1]], Int)
+                } else {
+                    return err!("invalid input spec for graphemes: {:?}", &incoming[..]);
+                }
+            }
+            IntDiv | DivMod => match (self, incoming[2]) {
+                (IntDiv, MapStrInt) | (DivMod, MapIntInt) => {
+                    (smallvec![Int, Int, incoming[2]], Int)
+                }
+                _ => return err!("invalid input spec for {}: {:?}", self, &incoming[..]),
+            },
+            HllAdd | HllCount => match incoming[0] {
+                MapIntInt if *self == HllAdd => (smallvec![MapIntInt, Str], Int),
+                MapIntInt => (smallvec![MapIntInt], Int),
+                _ => return err!("invalid input spec for {}: {:?}", self, &incoming[..]),
+            },
+            BloomAdd | BloomHas => match incoming[0] {
+                MapIntInt if *self == BloomAdd => (smallvec![MapIntInt, Str, Int, Float], Int),
+                MapIntInt => (smallvec![MapIntInt, Str], Int),
+                _ => return err!("invalid input spec for {}: {:?}", self, &incoming[..]),
+            },
+            Reservoir => match incoming[0] {
+                MapIntStr => (smallvec![MapIntStr, Int, Str], Int),
+                _ => return err!("invalid input spec for reservoir: {:?}", &incoming[..]),
+            },
+            WinPush => match incoming[0] {
+                MapIntFloat => (smallvec![MapIntFloat, Float, Int], Int),
+                _ => return err!("invalid input spec for win_push: {:?}", &incoming[..]),
+            },
+            WinSum | WinAvg | WinMax => match incoming[0] {
+                MapIntFloat => (smallvec![MapIntFloat], Float),
+                _ => return err!("invalid input spec for {}: {:?}", self, &incoming[..]),
+            },
+            HistAdd => match incoming[0] {
+                MapIntInt => (smallvec![MapIntInt, Float, Float, Int], Int),
+                _ => return err!("invalid input spec for hist_add: {:?}", &incoming[..]),
+            },
+            VecDot => match (incoming[0], incoming[1]) {
+                (MapIntFloat, MapIntFloat) => (smallvec![MapIntFloat, MapIntFloat], Float),
+                _ => return err!("invalid input spec for vec_dot: {:?}", &incoming[..]),
+            },
+            VecNorm => match incoming[0] {
+                MapIntFloat => (smallvec![MapIntFloat], Float),
+                _ => return err!("invalid input spec for vec_norm: {:?}", &incoming[..]),
+            },
+            VecAdd | VecSub | VecMul => match (incoming[0], incoming[1], incoming[2]) {
+                (MapIntFloat, MapIntFloat, MapIntFloat) => {
+                    (smallvec![MapIntFloat, MapIntFloat, MapIntFloat], Int)
+                }
+                _ => return err!("invalid input spec for {}: {:?}", self, &incoming[..]),
+            },
+            VecScale => match (incoming[0], incoming[1]) {
+                (MapIntFloat, MapIntFloat) => (smallvec![MapIntFloat, MapIntFloat, Float], Int),
+                _ => return err!("invalid input spec for vec_scale: {:?}", &incoming[..]),
+            },
+            HistFormat => match incoming[0] {
+                MapIntInt => (smallvec![MapIntInt], Str),
+                _ => return err!("invalid input spec for hist_format: {:?}", &incoming[..]),
+            },
+            Readable => match incoming[0] {
+                MapStrInt => (smallvec![MapStrInt, Int], Int),
+                _ => return err!("invalid input spec for readable: {:?}", &incoming[..]),
+            },
+            SetTimeout => (smallvec![Str, Int], Int),
+            Spawn => (smallvec![Str], Int),
+            ReadLine => (smallvec![Int, Int], Str),
+            WriteLine => (smallvec![Int, Str], Int),
+            Wait => (smallvec![Int], Int),
+            ReadCmd => (smallvec![Str], Str),
+            Getenv => (smallvec![Str, Str], Str),
+            Setenv => (smallvec![Str, Str], Int),
+            Mkdirp | RmFile => (smallvec![Str], Int),
+            Rename => (smallvec![Str, Str], Int),
+            Sleep => (smallvec![Float], Int),
+            Monotime => (smallvec![], Float),
+            Signal => (smallvec![], Int),
+            RecordFile | RecordText | ArgvAssign => (smallvec![], Str),
+            RecordOffset => (smallvec![], Int),
+            RoundTripSep => (smallvec![Str, Str], Str),
+            Stat => match incoming[1] {
+                MapStrStr => (smallvec![Str, MapStrStr], Int),
+                _ => return err!("invalid input spec for stat: {:?}", &incoming[..]),
+            },
+            Glob => match incoming[1] {
+                MapIntStr => (smallvec![Str, MapIntStr], Int),
+                _ => return err!("invalid input spec for glob: {:?}", &incoming[..]),
+            },
             Match => (smallvec![Str, Str], Int),
             // Split's second input can be a map of either type
             Split => {
@@ -386,22 +812,38 @@ impl Function {
             }
             JoinCols => (smallvec![Int, Int, Str], Str),
             JoinCSV | JoinTSV => (smallvec![Int, Int], Str),
+            FSum | FMean => (smallvec![Int, Int], Float),
             SetFI => (smallvec![Int, Int], Int),
+            MatchSet => (smallvec![Str; incoming.len()], Int),
+            MarkInit | SetExit | SetExiting => (smallvec![Int], Int),
+            CheckInit => (smallvec![Int, Str], Int),
+            SetMatched | Exiting | TierUp => (smallvec![], Int),
         })
     }
 
     pub(crate) fn arity(&self) -> Option<usize> {
         use Function::*;
         Some(match self {
+            MatchSet => return None,
             FloatFunc(ff) => ff.arity(),
             IntFunc(bw) => bw.arity(),
             UpdateUsedFields | Rand | ReseedRng | ReadErrStdin | NextlineStdin | NextFile
-            | ReadLineStdinFused => 0,
-            Srand | System | HexToInt | ToInt | EscapeCSV | EscapeTSV | Close | Length
-            | ReadErr | ReadErrCmd | Nextline | NextlineCmd | Unop(_) => 1,
-            SetFI | SubstrIndex | Match | Setcol | Binop(_) => 2,
-            JoinCSV | JoinTSV | Delete | Contains => 2,
-            JoinCols | Substr | Sub | GSub | Split => 3,
+            | ReadLineStdinFused | Monotime | Signal | TierUp => 0,
+            RecordFile | RecordOffset | RecordText | ArgvAssign | SetMatched | Exiting => 0,
+            Srand | System | HexToInt | IsNA | ToInt | EscapeCSV | EscapeTSV | Squeeze | Close
+            | Length | GraphemeLength | ReadErr | ReadErrCmd | Nextline | NextlineCmd | Unop(_)
+            | MarkInit | SetExit | SetExiting | HllCount | WinSum | WinAvg | WinMax
+            | HistFormat | VecNorm => 1,
+            SetFI | SubstrIndex | Match | Setcol | Levenshtein | JaroWinkler | Binop(_) => 2,
+            CmpNatural | CmpLocale | RoundTripSep | Color => 2,
+            JoinCSV | JoinTSV | Delete | Contains | Graphemes | Trim | LTrim | RTrim | ZFill => 2,
+            FSum | FMean => 2,
+            LoadState | SaveState | CheckInit | Readable | NumOr | HllAdd | BloomHas | VecDot => 2,
+            SetTimeout | ReadLine | WriteLine | Getenv | Setenv | Stat | Glob | Rename => 2,
+            Spawn | Wait | ReadCmd | Mkdirp | RmFile | Sleep | Bold => 1,
+            JoinCols | Substr | GraphemeSubstr | LPad | RPad | Sub | GSub | Split | Bound => 3,
+            IntDiv | DivMod | Reservoir | WinPush | VecAdd | VecSub | VecMul | VecScale => 3,
+            BloomAdd | HistAdd => 4,
         })
     }
 
@@ -414,6 +856,10 @@ impl Function {
         match self {
             IntFunc(bw) => Ok(bw.ret_state()),
             FloatFunc(ff) => Ok(ff.ret_state()),
+            Unop(Neg) if runtime::arith::int_promotion() => Ok(Scalar(BaseTy::Float).abs()),
+            Binop(Plus) | Binop(Minus) | Binop(Mult) if runtime::arith::int_promotion() => {
+                Ok(Scalar(BaseTy::Float).abs())
+            }
             Unop(Neg) | Unop(Pos) => match &args[0] {
                 Some(Scalar(Some(BaseTy::Str))) | Some(Scalar(Some(BaseTy::Float))) => {
                     Ok(Scalar(BaseTy::Float).abs())
@@ -430,17 +876,25 @@ impl Function {
                     (_, _) => Ok(Scalar(Int).abs()),
                 }
             }
-            Rand | Binop(Div) | Binop(Pow) => Ok(Scalar(BaseTy::Float).abs()),
+            Rand | JaroWinkler | NumOr | WinSum | WinAvg | WinMax | VecDot | VecNorm | FSum
+            | FMean | Monotime | Binop(Div) | Binop(Pow) => Ok(Scalar(BaseTy::Float).abs()),
             Setcol => Ok(Scalar(BaseTy::Null).abs()),
             SubstrIndex | Srand | ReseedRng | Unop(Not) | Binop(IsMatch) | Binop(LT)
             | Binop(GT) | Binop(LTE) | Binop(GTE) | Binop(EQ) | Length | Split | ReadErr
-            | ReadErrCmd | ReadErrStdin | Contains | Delete | Match | Sub | GSub | ToInt
-            | System | HexToInt => Ok(Scalar(BaseTy::Int).abs()),
-            JoinCSV | JoinTSV | JoinCols | EscapeCSV | EscapeTSV | Substr | Unop(Column)
-            | Binop(Concat) | Nextline | NextlineCmd | NextlineStdin => {
-                Ok(Scalar(BaseTy::Str).abs())
-            }
-            SetFI | UpdateUsedFields | NextFile | ReadLineStdinFused | Close => Ok(None),
+            | ReadErrCmd | ReadErrStdin | ReadLineStdinFused | Contains | Delete | Bound
+            | LoadState | SaveState | Match | Sub | GSub | ToInt | System | HexToInt | IsNA
+            | MatchSet | Graphemes | GraphemeLength | Levenshtein | CmpNatural | CmpLocale
+            | IntDiv | DivMod | Readable | SetTimeout | Spawn | WriteLine | Wait | Setenv
+            | Stat | Glob | Mkdirp | Rename | RmFile | Sleep | Signal | RecordOffset | SetExit
+            | SetMatched | Exiting | SetExiting | TierUp | MarkInit | CheckInit | HllAdd
+            | HllCount | BloomAdd | BloomHas | Reservoir | WinPush | HistAdd | VecAdd | VecSub
+            | VecMul | VecScale => Ok(Scalar(BaseTy::Int).abs()),
+            JoinCSV | JoinTSV | JoinCols | EscapeCSV | EscapeTSV | Substr | GraphemeSubstr
+            | Trim | LTrim | RTrim | Squeeze | Color | Bold | LPad | RPad | ZFill
+            | Unop(Column) | Binop(Concat) | Nextline | NextlineCmd | NextlineStdin
+            | HistFormat | ReadLine | ReadCmd | Getenv | RecordFile | RecordText | RoundTripSep
+            | ArgvAssign => Ok(Scalar(BaseTy::Str).abs()),
+            SetFI | UpdateUsedFields | NextFile | Close => Ok(None),
         }
     }
 }
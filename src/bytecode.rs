@@ -1,14 +1,17 @@
+use hashbrown::HashSet;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 use std::sync::Arc;
 
 use crate::builtins::{Bitwise, FloatFunc, Variable};
-use crate::common::{FileSpec, NumTy};
-use crate::compile::{self, Ty};
+use crate::common::{FileSpec, NumTy, Stage};
+use crate::compile::{self, Ty, NUM_TYPES};
 use crate::interp::{index, index_mut, Storage};
+use crate::lexer::Span;
+use crate::pushdown::FieldSet;
 use crate::runtime::{self, Float, Int, Str, UniqueStr};
 
-use regex::bytes::Regex;
+use crate::runtime::matcher::{Matcher, PatternSet};
 
 pub(crate) use crate::interp::Interp;
 
@@ -74,6 +77,8 @@ pub(crate) enum Instr<'a> {
     FloatToStr(Reg<Str<'a>>, Reg<Float>),
     StrToInt(Reg<Int>, Reg<Str<'a>>),
     HexStrToInt(Reg<Int>, Reg<Str<'a>>),
+    IsNA(Reg<Int>, Reg<Str<'a>>),
+    NumOr(Reg<Float>, Reg<Str<'a>>, Reg<Float>),
     FloatToInt(Reg<Int>, Reg<Float>),
     IntToFloat(Reg<Float>, Reg<Int>),
     StrToFloat(Reg<Float>, Reg<Str<'a>>),
@@ -82,6 +87,9 @@ pub(crate) enum Instr<'a> {
     // Note, for now we do not support iterator moves. Iterators own their own copy of an array,
     // and there is no reason we should be emitting movs for them.
     Mov(Ty, NumTy, NumTy),
+    // Move a string into dst, leaving an empty string in src. This is used in place of `Mov` when
+    // src is not read again; see the `ownership` module.
+    TakeStr(Reg<Str<'a>>, Reg<Str<'a>>),
 
     AllocMap(Ty, NumTy),
 
@@ -114,9 +122,11 @@ pub(crate) enum Instr<'a> {
     // String processing
     Concat(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Str<'a>>),
     IsMatch(Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
-    IsMatchConst(Reg<Int>, Reg<Str<'a>>, Arc<Regex>),
+    IsMatchConst(Reg<Int>, Reg<Str<'a>>, Arc<Matcher>),
     Match(Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
-    MatchConst(Reg<Int>, Reg<Str<'a>>, Arc<Regex>),
+    MatchConst(Reg<Int>, Reg<Str<'a>>, Arc<Matcher>),
+    // Bitmask of the patterns in the set matching the string.
+    MatchSet(Reg<Int>, Reg<Str<'a>>, Arc<PatternSet>),
     // index(s, t) returns index of substring t in s, 0 if it does not appear.
     SubstrIndex(Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
     LenStr(Reg<Int>, Reg<Str<'a>>),
@@ -135,6 +145,169 @@ pub(crate) enum Instr<'a> {
     EscapeCSV(Reg<Str<'a>>, Reg<Str<'a>>),
     EscapeTSV(Reg<Str<'a>>, Reg<Str<'a>>),
     Substr(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Int>, Reg<Int>),
+    // Like LenStr, Substr and SplitInt, but counting extended grapheme clusters.
+    GraphemeLen(Reg<Int>, Reg<Str<'a>>),
+    GraphemeSubstr(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Int>, Reg<Int>),
+    Graphemes(Reg<Int>, Reg<Str<'a>>, Reg<runtime::IntMap<Str<'a>>>),
+    Levenshtein(Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
+    IntDiv(
+        Reg<Int>,
+        /* numerator */ Reg<Int>,
+        /* denominator */ Reg<Int>,
+        Reg<runtime::StrMap<'a, Int>>,
+    ),
+    DivMod(
+        Reg<Int>,
+        /* numerator */ Reg<Int>,
+        /* denominator */ Reg<Int>,
+        Reg<runtime::IntMap<Int>>,
+    ),
+    HllAdd(
+        Reg<Int>,
+        /* sketch */ Reg<runtime::IntMap<Int>>,
+        Reg<Str<'a>>,
+    ),
+    HllCount(Reg<Int>, /* sketch */ Reg<runtime::IntMap<Int>>),
+    BloomAdd(
+        Reg<Int>,
+        /* filter */ Reg<runtime::IntMap<Int>>,
+        Reg<Str<'a>>,
+        /* values */ Reg<Int>,
+        /* false-positive rate */ Reg<Float>,
+    ),
+    BloomHas(
+        Reg<Int>,
+        /* filter */ Reg<runtime::IntMap<Int>>,
+        Reg<Str<'a>>,
+    ),
+    Reservoir(
+        Reg<Int>,
+        /* sample */ Reg<runtime::IntMap<Str<'a>>>,
+        /* size */ Reg<Int>,
+        Reg<Str<'a>>,
+    ),
+    WinPush(
+        Reg<Int>,
+        /* window */ Reg<runtime::IntMap<Float>>,
+        Reg<Float>,
+        /* size */ Reg<Int>,
+    ),
+    WinSum(Reg<Float>, Reg<runtime::IntMap<Float>>),
+    WinAvg(Reg<Float>, Reg<runtime::IntMap<Float>>),
+    WinMax(Reg<Float>, Reg<runtime::IntMap<Float>>),
+    HistAdd(
+        Reg<Int>,
+        /* histogram */ Reg<runtime::IntMap<Int>>,
+        Reg<Float>,
+        /* bucket width */ Reg<Float>,
+        /* log */ Reg<Int>,
+    ),
+    HistFormat(Reg<Str<'a>>, Reg<runtime::IntMap<Int>>),
+    VecDot(
+        Reg<Float>,
+        Reg<runtime::IntMap<Float>>,
+        Reg<runtime::IntMap<Float>>,
+    ),
+    VecNorm(Reg<Float>, Reg<runtime::IntMap<Float>>),
+    VecAdd(
+        Reg<Int>,
+        /* dst */ Reg<runtime::IntMap<Float>>,
+        Reg<runtime::IntMap<Float>>,
+        Reg<runtime::IntMap<Float>>,
+    ),
+    VecSub(
+        Reg<Int>,
+        /* dst */ Reg<runtime::IntMap<Float>>,
+        Reg<runtime::IntMap<Float>>,
+        Reg<runtime::IntMap<Float>>,
+    ),
+    VecMul(
+        Reg<Int>,
+        /* dst */ Reg<runtime::IntMap<Float>>,
+        Reg<runtime::IntMap<Float>>,
+        Reg<runtime::IntMap<Float>>,
+    ),
+    VecScale(
+        Reg<Int>,
+        /* dst */ Reg<runtime::IntMap<Float>>,
+        Reg<runtime::IntMap<Float>>,
+        /* factor */ Reg<Float>,
+    ),
+    Readable(
+        Reg<Int>,
+        /* inputs */ Reg<runtime::StrMap<'a, Int>>,
+        /* timeout */ Reg<Int>,
+    ),
+    SetTimeout(
+        Reg<Int>,
+        /* input */ Reg<Str<'a>>,
+        /* ms */ Reg<Int>,
+    ),
+    Spawn(Reg<Int>, /* cmd */ Reg<Str<'a>>),
+    ReadLine(
+        Reg<Str<'a>>,
+        /* handle */ Reg<Int>,
+        /* stream */ Reg<Int>,
+    ),
+    WriteLine(Reg<Int>, /* handle */ Reg<Int>, Reg<Str<'a>>),
+    Wait(Reg<Int>, /* handle */ Reg<Int>),
+    ReadCmd(Reg<Str<'a>>, /* cmd */ Reg<Str<'a>>),
+    Getenv(
+        Reg<Str<'a>>,
+        /* name */ Reg<Str<'a>>,
+        /* default */ Reg<Str<'a>>,
+    ),
+    Setenv(
+        Reg<Int>,
+        /* name */ Reg<Str<'a>>,
+        /* value */ Reg<Str<'a>>,
+    ),
+    Stat(
+        Reg<Int>,
+        /* path */ Reg<Str<'a>>,
+        Reg<runtime::StrMap<'a, Str<'a>>>,
+    ),
+    Glob(
+        Reg<Int>,
+        /* pattern */ Reg<Str<'a>>,
+        Reg<runtime::IntMap<Str<'a>>>,
+    ),
+    Mkdirp(Reg<Int>, /* path */ Reg<Str<'a>>),
+    Rename(
+        Reg<Int>,
+        /* from */ Reg<Str<'a>>,
+        /* to */ Reg<Str<'a>>,
+    ),
+    RmFile(Reg<Int>, /* path */ Reg<Str<'a>>),
+    Sleep(Reg<Int>, /* seconds */ Reg<Float>),
+    Monotime(Reg<Float>),
+    Signal(Reg<Int>),
+    RecordFile(Reg<Str<'a>>),
+    RecordOffset(Reg<Int>),
+    RecordText(Reg<Str<'a>>),
+    RoundTripSep(
+        Reg<Str<'a>>,
+        /* record */ Reg<Str<'a>>,
+        /* ors */ Reg<Str<'a>>,
+    ),
+    ArgvAssign(Reg<Str<'a>>),
+    SetExit(Reg<Int>, /* status */ Reg<Int>),
+    SetMatched(Reg<Int>),
+    Exiting(Reg<Int>),
+    SetExiting(Reg<Int>, Reg<Int>),
+    TierUp(Reg<Int>),
+    JaroWinkler(Reg<Float>, Reg<Str<'a>>, Reg<Str<'a>>),
+    CmpNatural(Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
+    CmpLocale(Reg<Int>, Reg<Str<'a>>, Reg<Str<'a>>),
+    Trim(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Str<'a>>),
+    LTrim(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Str<'a>>),
+    RTrim(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Str<'a>>),
+    Squeeze(Reg<Str<'a>>, Reg<Str<'a>>),
+    Color(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Str<'a>>),
+    Bold(Reg<Str<'a>>, Reg<Str<'a>>),
+    LPad(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Int>, Reg<Str<'a>>),
+    RPad(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Int>, Reg<Str<'a>>),
+    ZFill(Reg<Str<'a>>, Reg<Str<'a>>, Reg<Int>),
 
     // Comparison
     LTFloat(Reg<Int>, Reg<Float>, Reg<Float>),
@@ -156,6 +329,10 @@ pub(crate) enum Instr<'a> {
     // Columns
     SetColumn(Reg<Int> /* dst column */, Reg<Str<'a>>),
     GetColumn(Reg<Str<'a>>, Reg<Int>),
+    // Superinstructions for `GetColumn(s, col)` followed by a conversion of `s` to a number. They
+    // are only introduced by `Program::fuse`; see there for details.
+    GetColumnInt(Reg<Int>, Reg<Str<'a>>, Reg<Int> /* col */),
+    GetColumnFloat(Reg<Float>, Reg<Str<'a>>, Reg<Int> /* col */),
     JoinCSV(
         Reg<Str<'a>>, /* dst */
         Reg<Int>,     /* start col */
@@ -166,6 +343,16 @@ pub(crate) enum Instr<'a> {
         Reg<Int>,     /* start col */
         Reg<Int>,     /* end col */
     ),
+    SumColumns(
+        Reg<Float>, /* dst */
+        Reg<Int>,   /* start col */
+        Reg<Int>,   /* end col */
+    ),
+    MeanColumns(
+        Reg<Float>, /* dst */
+        Reg<Int>,   /* start col */
+        Reg<Int>,   /* end col */
+    ),
     JoinColumns(
         Reg<Str<'a>>, /* dst */
         Reg<Int>,     /* start col */
@@ -178,14 +365,22 @@ pub(crate) enum Instr<'a> {
     NextLine(Reg<Str<'a>>, Reg<Str<'a>>, /*is_file=*/ bool),
     ReadErrStdin(Reg<Int>),
     NextLineStdin(Reg<Str<'a>>),
-    // Fetches line directly into $0.
-    NextLineStdinFused(),
+    // Fetches line directly into $0, storing the same result as ReadErrStdin.
+    NextLineStdinFused(Reg<Int>),
     // Advances early to the next file in our sequence
     NextFile(),
     UpdateUsedFields(),
     // Set the corresponding index in the FI variable. This is equivalent of loading FI, but we
     // keep this as a separate instruction to make static analysis easier.
     SetFI(Reg<Int>, Reg<Int>),
+    // Record that a variable checked by `--strict` has been assigned, or fail if it has not been.
+    // The variable is identified by its index, and by its name for the error message.
+    MarkInit(Reg<Int>, /* var */ Reg<Int>),
+    CheckInit(
+        Reg<Int>,
+        /* var */ Reg<Int>,
+        /* name */ Reg<Str<'a>>,
+    ),
 
     // Split
     SplitInt(
@@ -240,6 +435,25 @@ pub(crate) enum Instr<'a> {
         dst: NumTy,
         map: NumTy,
     },
+    Bound {
+        map_ty: Ty,
+        dst: NumTy,
+        map: NumTy,
+        size: NumTy,
+        policy: NumTy,
+    },
+    LoadState {
+        map_ty: Ty,
+        dst: NumTy,
+        map: NumTy,
+        name: NumTy,
+    },
+    SaveState {
+        map_ty: Ty,
+        dst: NumTy,
+        map: NumTy,
+        name: NumTy,
+    },
     Store {
         map_ty: Ty,
         map: NumTy,
@@ -295,6 +509,122 @@ pub(crate) enum Instr<'a> {
     Ret,
 }
 
+/// A program lowered to bytecode, along with everything needed to build an interpreter for it.
+pub(crate) struct Program<'a> {
+    pub instrs: Vec<Vec<Instr<'a>>>,
+    pub stage: Stage<usize>,
+    /// The function that calls the functions registered with `atexit`, which the interpreter calls
+    /// if the program stops early.
+    pub atexit: Option<usize>,
+    /// The number of registers of each type, indexed by `Ty as usize`.
+    pub regs: [usize; NUM_TYPES],
+    pub used_fields: FieldSet,
+    pub named_columns: Option<Vec<&'a [u8]>>,
+    /// Source-level information for each function in `instrs`.
+    pub debug: Vec<DebugInfo>,
+    /// The name, register and type of each scalar or map global variable. This is only used by
+    /// `--trace`, and is not written to bytecode files.
+    pub globals: Vec<(String, NumTy, compile::Ty)>,
+    /// For each string register, whether it is assigned newly-allocated strings that do not
+    /// escape the current record; see the `escape` module. This is only used by the interpreter,
+    /// and is not written to bytecode files.
+    pub record_local_strs: Vec<bool>,
+}
+
+/// The name of a function and the locations in the source program its instructions came from.
+/// This is used to print a stack trace when a runtime error occurs.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DebugInfo {
+    pub name: String,
+    /// Pairs of an instruction index and the span of the statement that the instructions starting
+    /// at that index were generated from, sorted by index. The span is None for instructions that
+    /// frawk added to the program, which do not come from any one statement.
+    pub lines: Vec<(usize, Option<Span>)>,
+}
+
+impl DebugInfo {
+    /// The span of the statement that generated the instruction at index `instr`, if any.
+    pub(crate) fn span_of(&self, instr: usize) -> Option<Span> {
+        match self.lines.binary_search_by_key(&instr, |(start, _)| *start) {
+            Ok(i) => self.lines[i].1,
+            Err(0) => None,
+            Err(i) => self.lines[i - 1].1,
+        }
+    }
+}
+
+impl<'a> Program<'a> {
+    /// Whether the program refers to ARGV or ARGC, or performs the `var=value` entries in ARGV as
+    /// it reads its input; see `ast::Prog::argv_vars`.
+    pub(crate) fn uses_argv(&self) -> bool {
+        use Variable::{ARGC, ARGV};
+        self.instrs.iter().flat_map(|f| f.iter()).any(|i| match i {
+            Instr::ArgvAssign(_) => true,
+            Instr::LoadVarInt(_, v) | Instr::StoreVarInt(v, _) => *v == ARGC,
+            Instr::LoadVarIntMap(_, v) | Instr::StoreVarIntMap(v, _) => *v == ARGV,
+            _ => false,
+        })
+    }
+
+    /// Replace common pairs of instructions with a single superinstruction, saving the interpreter
+    /// a trip around its dispatch loop.
+    ///
+    /// A superinstruction takes the place of the first instruction in the pair and does the work of
+    /// both, then continues after the second. The second instruction is left where it was, so
+    /// jumps to it, and the instruction indices in `debug`, stay valid. Pairs whose second
+    /// instruction starts a statement are not fused, so that `--trace` and `--profile` still see
+    /// every statement.
+    pub(crate) fn fuse(&mut self) {
+        use Instr::*;
+        for (f, instrs) in self.instrs.iter_mut().enumerate() {
+            let starts: HashSet<usize> = match self.debug.get(f) {
+                Some(info) => info.lines.iter().map(|(start, _)| *start).collect(),
+                None => Default::default(),
+            };
+            for i in 1..instrs.len() {
+                if starts.contains(&i) {
+                    continue;
+                }
+                let fused = match (&instrs[i - 1], &instrs[i]) {
+                    (GetColumn(s1, col), StrToInt(dst, s2)) if s1 == s2 => {
+                        GetColumnInt(*dst, *s1, *col)
+                    }
+                    (GetColumn(s1, col), StrToFloat(dst, s2)) if s1 == s2 => {
+                        GetColumnFloat(*dst, *s1, *col)
+                    }
+                    _ => continue,
+                };
+                instrs[i - 1] = fused;
+            }
+        }
+    }
+
+    pub(crate) fn into_interp<LR: runtime::LineReader>(
+        mut self,
+        reader: LR,
+        ff: impl runtime::writers::FileFactory,
+        num_workers: usize,
+    ) -> Interp<'a, LR> {
+        self.fuse();
+        let regs = self.regs;
+        let mut interp = Interp::new(
+            self.instrs,
+            self.stage,
+            num_workers,
+            |ty| regs[ty as usize],
+            reader,
+            ff,
+            &self.used_fields,
+            self.named_columns,
+        );
+        interp.atexit = self.atexit;
+        interp.debug = Arc::new(self.debug);
+        interp.globals = self.globals;
+        interp.record_local_strs = Arc::new(self.record_local_strs);
+        interp
+    }
+}
+
 impl<T> Reg<T> {
     pub(crate) fn index(&self) -> usize {
         self.0 as usize
@@ -398,7 +728,7 @@ impl<'a> Instr<'a> {
                 sr.accum(&mut f);
                 fr.accum(&mut f);
             }
-            StrToInt(ir, sr) | HexStrToInt(ir, sr) => {
+            StrToInt(ir, sr) | HexStrToInt(ir, sr) | IsNA(ir, sr) => {
                 ir.accum(&mut f);
                 sr.accum(&mut f);
             }
@@ -514,35 +844,228 @@ impl<'a> Instr<'a> {
                 l.accum(&mut f);
                 r.accum(&mut f);
             }
-            MatchConst(res, src, _) | IsMatchConst(res, src, _) => {
+            MatchConst(res, src, _) | IsMatchConst(res, src, _) | MatchSet(res, src, _) => {
                 res.accum(&mut f);
                 src.accum(&mut f);
             }
-            SubstrIndex(res, s, t) => {
+            SubstrIndex(res, s, t)
+            | Levenshtein(res, s, t)
+            | CmpNatural(res, s, t)
+            | CmpLocale(res, s, t) => {
+                res.accum(&mut f);
+                s.accum(&mut f);
+                t.accum(&mut f);
+            }
+            Trim(res, s, t) | LTrim(res, s, t) | RTrim(res, s, t) | Color(res, s, t) => {
+                res.accum(&mut f);
+                s.accum(&mut f);
+                t.accum(&mut f);
+            }
+            JaroWinkler(res, s, t) => {
                 res.accum(&mut f);
                 s.accum(&mut f);
                 t.accum(&mut f);
             }
-            LenStr(res, s) => {
+            NumOr(res, s, default) => {
+                res.accum(&mut f);
+                s.accum(&mut f);
+                default.accum(&mut f);
+            }
+            LenStr(res, s) | GraphemeLen(res, s) => {
                 res.accum(&mut f);
                 s.accum(&mut f)
             }
+            Graphemes(res, s, arr) => {
+                res.accum(&mut f);
+                s.accum(&mut f);
+                arr.accum(&mut f);
+            }
+            IntDiv(res, num, den, arr) => {
+                res.accum(&mut f);
+                num.accum(&mut f);
+                den.accum(&mut f);
+                arr.accum(&mut f);
+            }
+            DivMod(res, num, den, arr) => {
+                res.accum(&mut f);
+                num.accum(&mut f);
+                den.accum(&mut f);
+                arr.accum(&mut f);
+            }
+            HllAdd(res, h, x) => {
+                res.accum(&mut f);
+                h.accum(&mut f);
+                x.accum(&mut f);
+            }
+            HllCount(res, h) => {
+                res.accum(&mut f);
+                h.accum(&mut f);
+            }
+            BloomAdd(res, b, x, n, p) => {
+                res.accum(&mut f);
+                b.accum(&mut f);
+                x.accum(&mut f);
+                n.accum(&mut f);
+                p.accum(&mut f);
+            }
+            BloomHas(res, b, x) => {
+                res.accum(&mut f);
+                b.accum(&mut f);
+                x.accum(&mut f);
+            }
+            Reservoir(res, arr, k, x) => {
+                res.accum(&mut f);
+                arr.accum(&mut f);
+                k.accum(&mut f);
+                x.accum(&mut f);
+            }
+            WinPush(res, w, x, n) => {
+                res.accum(&mut f);
+                w.accum(&mut f);
+                x.accum(&mut f);
+                n.accum(&mut f);
+            }
+            WinSum(res, w) | WinAvg(res, w) | WinMax(res, w) => {
+                res.accum(&mut f);
+                w.accum(&mut f);
+            }
+            HistAdd(res, h, x, w, log) => {
+                res.accum(&mut f);
+                h.accum(&mut f);
+                x.accum(&mut f);
+                w.accum(&mut f);
+                log.accum(&mut f);
+            }
+            HistFormat(res, h) => {
+                res.accum(&mut f);
+                h.accum(&mut f);
+            }
+            VecDot(res, a, b) => {
+                res.accum(&mut f);
+                a.accum(&mut f);
+                b.accum(&mut f);
+            }
+            VecNorm(res, a) => {
+                res.accum(&mut f);
+                a.accum(&mut f);
+            }
+            VecAdd(res, dst, a, b) | VecSub(res, dst, a, b) | VecMul(res, dst, a, b) => {
+                res.accum(&mut f);
+                dst.accum(&mut f);
+                a.accum(&mut f);
+                b.accum(&mut f);
+            }
+            VecScale(res, dst, a, k) => {
+                res.accum(&mut f);
+                dst.accum(&mut f);
+                a.accum(&mut f);
+                k.accum(&mut f);
+            }
+            Readable(res, inputs, timeout) => {
+                res.accum(&mut f);
+                inputs.accum(&mut f);
+                timeout.accum(&mut f);
+            }
+            SetTimeout(res, input, ms) => {
+                res.accum(&mut f);
+                input.accum(&mut f);
+                ms.accum(&mut f);
+            }
+            Spawn(res, cmd) => {
+                res.accum(&mut f);
+                cmd.accum(&mut f);
+            }
+            ReadLine(res, h, stream) => {
+                res.accum(&mut f);
+                h.accum(&mut f);
+                stream.accum(&mut f);
+            }
+            WriteLine(res, h, s) => {
+                res.accum(&mut f);
+                h.accum(&mut f);
+                s.accum(&mut f);
+            }
+            Wait(res, h) => {
+                res.accum(&mut f);
+                h.accum(&mut f);
+            }
+            ReadCmd(res, cmd) => {
+                res.accum(&mut f);
+                cmd.accum(&mut f);
+            }
+            Getenv(res, name, default) => {
+                res.accum(&mut f);
+                name.accum(&mut f);
+                default.accum(&mut f);
+            }
+            Setenv(res, name, val) => {
+                res.accum(&mut f);
+                name.accum(&mut f);
+                val.accum(&mut f);
+            }
+            Stat(res, path, arr) => {
+                res.accum(&mut f);
+                path.accum(&mut f);
+                arr.accum(&mut f);
+            }
+            Glob(res, pattern, arr) => {
+                res.accum(&mut f);
+                pattern.accum(&mut f);
+                arr.accum(&mut f);
+            }
+            Mkdirp(res, path) | RmFile(res, path) => {
+                res.accum(&mut f);
+                path.accum(&mut f);
+            }
+            Rename(res, from, to) => {
+                res.accum(&mut f);
+                from.accum(&mut f);
+                to.accum(&mut f);
+            }
+            Sleep(res, secs) => {
+                res.accum(&mut f);
+                secs.accum(&mut f);
+            }
+            Monotime(res) => res.accum(&mut f),
+            Signal(res) => res.accum(&mut f),
+            RecordFile(res) | RecordText(res) | ArgvAssign(res) => res.accum(&mut f),
+            RecordOffset(res) | SetMatched(res) | Exiting(res) | TierUp(res) => res.accum(&mut f),
+            RoundTripSep(res, record, ors) => {
+                res.accum(&mut f);
+                record.accum(&mut f);
+                ors.accum(&mut f);
+            }
+            SetExit(res, status) | SetExiting(res, status) => {
+                res.accum(&mut f);
+                status.accum(&mut f);
+            }
             GSub(res, pat, s, in_s) | Sub(res, pat, s, in_s) => {
                 res.accum(&mut f);
                 pat.accum(&mut f);
                 s.accum(&mut f);
                 in_s.accum(&mut f);
             }
-            EscapeCSV(res, s) | EscapeTSV(res, s) => {
+            EscapeCSV(res, s) | EscapeTSV(res, s) | Squeeze(res, s) | Bold(res, s) => {
                 res.accum(&mut f);
                 s.accum(&mut f);
             }
-            Substr(res, base, l, r) => {
+            Substr(res, base, l, r) | GraphemeSubstr(res, base, l, r) => {
                 res.accum(&mut f);
                 base.accum(&mut f);
                 l.accum(&mut f);
                 r.accum(&mut f);
             }
+            LPad(res, s, width, with) | RPad(res, s, width, with) => {
+                res.accum(&mut f);
+                s.accum(&mut f);
+                width.accum(&mut f);
+                with.accum(&mut f);
+            }
+            ZFill(res, s, width) => {
+                res.accum(&mut f);
+                s.accum(&mut f);
+                width.accum(&mut f);
+            }
             LTFloat(res, l, r) => {
                 res.accum(&mut f);
                 l.accum(&mut f);
@@ -626,11 +1149,26 @@ impl<'a> Instr<'a> {
                 dst.accum(&mut f);
                 src.accum(&mut f)
             }
+            GetColumnInt(dst, s, col) => {
+                dst.accum(&mut f);
+                s.accum(&mut f);
+                col.accum(&mut f)
+            }
+            GetColumnFloat(dst, s, col) => {
+                dst.accum(&mut f);
+                s.accum(&mut f);
+                col.accum(&mut f)
+            }
             JoinCSV(dst, start, end) | JoinTSV(dst, start, end) => {
                 dst.accum(&mut f);
                 start.accum(&mut f);
                 end.accum(&mut f);
             }
+            SumColumns(dst, start, end) | MeanColumns(dst, start, end) => {
+                dst.accum(&mut f);
+                start.accum(&mut f);
+                end.accum(&mut f);
+            }
             JoinColumns(dst, start, end, sep) => {
                 dst.accum(&mut f);
                 start.accum(&mut f);
@@ -709,6 +1247,34 @@ impl<'a> Instr<'a> {
                 f(*dst, Ty::Int);
                 f(*map, *map_ty);
             }
+            Bound {
+                map_ty,
+                dst,
+                map,
+                size,
+                policy,
+            } => {
+                f(*dst, Ty::Int);
+                f(*map, *map_ty);
+                f(*size, Ty::Int);
+                f(*policy, Ty::Str);
+            }
+            LoadState {
+                map_ty,
+                dst,
+                map,
+                name,
+            }
+            | SaveState {
+                map_ty,
+                dst,
+                map,
+                name,
+            } => {
+                f(*dst, Ty::Int);
+                f(*map, *map_ty);
+                f(*name, Ty::Str);
+            }
             IterBegin { map_ty, map, dst } => {
                 f(*dst, map_ty.key_iter().unwrap());
                 f(*map, *map_ty);
@@ -747,6 +1313,10 @@ impl<'a> Instr<'a> {
                 f(*dst, *ty);
                 f(*src, *ty);
             }
+            TakeStr(dst, src) => {
+                dst.accum(&mut f);
+                src.accum(&mut f)
+            }
             AllocMap(ty, reg) => f(*reg, *ty),
             ReadErr(dst, file, _) => {
                 dst.accum(&mut f);
@@ -758,6 +1328,7 @@ impl<'a> Instr<'a> {
             }
             ReadErrStdin(dst) => dst.accum(&mut f),
             NextLineStdin(dst) => dst.accum(&mut f),
+            NextLineStdinFused(dst) => dst.accum(&mut f),
             JmpIf(cond, _lbl) => cond.accum(&mut f),
             Push(ty, reg) => f(*reg, *ty),
             Pop(ty, reg) => f(*reg, *ty),
@@ -765,8 +1336,16 @@ impl<'a> Instr<'a> {
                 key.accum(&mut f);
                 val.accum(&mut f);
             }
-            UpdateUsedFields() | NextFile() | NextLineStdinFused() | Call(_) | Jmp(_) | Ret
-            | Halt => {}
+            MarkInit(res, var) => {
+                res.accum(&mut f);
+                var.accum(&mut f);
+            }
+            CheckInit(res, var, name) => {
+                res.accum(&mut f);
+                var.accum(&mut f);
+                name.accum(&mut f);
+            }
+            UpdateUsedFields() | NextFile() | Call(_) | Jmp(_) | Ret | Halt => {}
         }
     }
 }
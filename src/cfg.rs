@@ -1,8 +1,12 @@
 use crate::arena;
 use crate::ast::{self, Expr, Stmt, Unop};
 use crate::builtins::{self, IsSprintf};
-use crate::common::{Either, FileSpec, Graph, NodeIx, NumTy, Result, Stage};
+use crate::common::{Either, FileSpec, Graph, NodeIx, NumTy, Result, Source, Stage};
+use crate::const_fold;
+use crate::cse;
 use crate::dom;
+use crate::lexer::Span;
+use crate::licm;
 
 use hashbrown::{HashMap, HashSet};
 use petgraph::Direction;
@@ -46,6 +50,8 @@ impl<I> FunctionName<I> {
 #[derive(Debug, Default)]
 pub(crate) struct BasicBlock<'a> {
     pub q: VecDeque<PrimStmt<'a>>,
+    // The span of the statement that each element of `q` was generated from.
+    pub spans: VecDeque<Option<Span>>,
     pub sealed: bool,
 }
 
@@ -287,6 +293,9 @@ pub(crate) struct ProgramContext<'a, I> {
     // table at construction time (in the func_table passed to View).
     pub funcs: Vec<Function<'a, I>>,
     main_offset: Stage<usize>,
+    // The function that calls the functions registered with `atexit`, which the runtime calls if
+    // the program stops early; see `ast::Prog::atexit_runner`.
+    pub atexit: Option<usize>,
     // Permit arbitrary strings to be passed to a subshell, skips any taint analysis of the script.
     pub allow_arbitrary_commands: bool,
     // Lower certain regular expression instructions to direct invocations of a given pattern,
@@ -294,15 +303,85 @@ pub(crate) struct ProgramContext<'a, I> {
     pub fold_regex_constants: bool,
     // Thread through information regarding header columns used.
     pub parse_header: bool,
+    // Whether the program reads records from its input in a main loop.
+    pub has_main_loop: bool,
+    // Whether the program is staged to be handed from the interpreter to compiled code as it runs;
+    // see `ast::Prog::tiered`.
+    pub tiered: bool,
+    // The program text, used to render errors.
+    pub source: Source<'a>,
+    // The initial contents of ARGV. These are set when the program starts running, rather than
+    // being compiled into it.
+    pub argv: Vec<&'a str>,
+    // The regex literals in the program, compiled before it starts running.
+    pub regex_literals: Vec<String>,
+    // How the main input should be read for this program.
+    pub input: crate::runtime::InputConfig,
+    // A specialized implementation of the whole program, if it has one; see the `kernels` module.
+    pub kernel: Option<crate::kernels::Kernel>,
+    // Whether the main input takes its files from ARGV as the program runs; see
+    // `ast::Prog::argv_vars`.
+    pub reads_argv: bool,
 }
 
 impl<'a, I> ProgramContext<'a, I> {
     pub fn main_stage(&self) -> &Stage<usize> {
         &self.main_offset
     }
+    /// The span of the statement in which the identifier with the given `low` field first
+    /// appeared, if any.
+    pub(crate) fn ident_span(&self, low: NumTy) -> Option<Span> {
+        self.shared.spans.get(&low).cloned()
+    }
+    /// The named variables in the program, along with their identifiers.
+    pub(crate) fn named_idents(&self) -> impl Iterator<Item = (&I, Ident)> + '_ {
+        self.shared.hm.iter().map(|(name, id)| (name, *id))
+    }
     pub fn main_offsets(&self) -> impl Iterator<Item = usize> + '_ {
         self.main_offset.iter().cloned()
     }
+    /// The total number of primitive statements in the program; a rough measure of its size.
+    pub fn num_stmts(&self) -> usize {
+        self.funcs
+            .iter()
+            .flat_map(|f| f.cfg.raw_nodes().iter())
+            .map(|n| n.weight.q.len())
+            .sum()
+    }
+    /// The number of explicit loops in the program. The main loop is not included.
+    pub fn num_loops(&self) -> usize {
+        self.funcs.iter().map(|f| f.num_loops).sum()
+    }
+    /// Fold constant expressions and branches in each function; see the `const_fold` module. This
+    /// must be called before `analyze_sep_assignments` for it to see the folded constants.
+    pub(crate) fn fold_constants<'outer>(&mut self, arena: &'a arena::Arena<'outer>) {
+        for f in self.funcs.iter_mut() {
+            let reachable = const_fold::fold(
+                &mut f.cfg,
+                f.entry,
+                f.exit,
+                &self.shared.local_globals,
+                arena,
+            );
+            f.update_vars(&reachable);
+        }
+    }
+
+    /// Replace repeated computations in each function with the first result; see the `cse`
+    /// module.
+    pub(crate) fn eliminate_common_subexprs(&mut self) {
+        for f in self.funcs.iter_mut() {
+            cse::eliminate(&mut f.cfg, f.entry, &self.shared.local_globals);
+        }
+    }
+
+    /// Move computations that do not change within a loop to before the loop; see the `licm`
+    /// module.
+    pub(crate) fn hoist_loop_invariants(&mut self) {
+        for f in self.funcs.iter_mut() {
+            licm::hoist(&mut f.cfg, f.entry, &self.shared.local_globals);
+        }
+    }
 }
 
 impl<'a> ProgramContext<'a, &'a str> {
@@ -445,10 +524,18 @@ where
         let mut shared: GlobalContext<I> = GlobalContext {
             hm: Default::default(),
             local_globals: Default::default(),
+            keep_globals: p.trace || p.tiered,
+            tiered: p.tiered,
             may_rename: Default::default(),
             max: 1, // 0 reserved for assigning to "unused" var for side-effecting operations
             conds: Default::default(),
             esc,
+            cur_span: None,
+            spans: Default::default(),
+            atexit: (ast::ATEXIT_VAR.into(), ast::ATEXIT_COUNT_VAR.into()),
+            unwind: p.unwinds(),
+            unwind_loops: p.stops_on_signal(),
+            round_trip: p.round_trip,
         };
         let mut func_table: HashMap<FunctionName<I>, NumTy> = Default::default();
         let mut funcs: Vec<Function<'a, I>> = Default::default();
@@ -459,9 +546,14 @@ where
             ) {
                 return err!("duplicate function found for name {}", fundec.name);
             }
-            if let Ok(bi) = builtins::Function::try_from(fundec.name.clone()) {
-                return err!("attempted redefinition of builtin function {}", bi);
+            match builtins::Function::try_from(fundec.name.clone()) {
+                Ok(bi) if bi.is_reserved() => {
+                    return err!("attempted redefinition of builtin function {}", bi);
+                }
+                _ => {}
             }
+            // Attribute errors involving the parameters and return value to the signature.
+            shared.cur_span = Some(fundec.span);
             // All exit blocks simply return the designated return node. Return statements in the
             // AST will becode assignments to this variable followed by an unconditional jump to
             // this block.
@@ -491,6 +583,31 @@ where
             f.ret = ret;
             funcs.push(f);
         }
+        shared.cur_span = None;
+        // The function that calls those registered with `atexit`; see `ast::Prog::atexit_runner`.
+        let atexit_runner = p.atexit_runner(arena).map(|body| {
+            let name = || FunctionName::Named(ast::ATEXIT_RUN_FN.into());
+            let offset = funcs.len();
+            let mut f = Function::new(name(), offset as NumTy);
+            f.ret = shared.fresh_local();
+            shared.may_rename.push(f.ret);
+            func_table.insert(name(), offset as NumTy);
+            funcs.push(f);
+            (offset, body)
+        });
+        // Calls to small functions are replaced with their bodies as they are converted.
+        let inline: HashMap<I, Inline<I>> = p
+            .decs
+            .iter()
+            .filter(|_| p.inline_functions)
+            .filter_map(|fundec| {
+                let declared =
+                    |name: &I| func_table.contains_key(&FunctionName::Named(name.clone()));
+                let (body, free) = fundec.inline_body(declared)?;
+                let params = &fundec.args[..];
+                Some((fundec.name.clone(), Inline { params, body, free }))
+            })
+            .collect();
         // Now that we have all the functions in place, it's time to fill them up and convert them
         // to SSA.
         macro_rules! fill {
@@ -502,6 +619,8 @@ where
                         ctx: &mut shared,
                         f: &mut func,
                         func_table: &func_table,
+                        inline: &inline,
+                        subst: Default::default(),
                         parse_header: p.parse_header,
                     }
                     .fill(s)?;
@@ -514,7 +633,12 @@ where
             };
         }
 
+        // Functions that are never called are left empty.
+        let called = p.called_functions();
         for fundec in p.decs.iter() {
+            if !called.contains(&fundec.name) {
+                continue;
+            }
             let f = *func_table
                 .get_mut(&FunctionName::Named(fundec.name.clone()))
                 .unwrap();
@@ -522,10 +646,23 @@ where
                 ctx: &mut shared,
                 f: funcs.get_mut(f as usize).unwrap(),
                 func_table: &func_table,
+                inline: &inline,
+                subst: Default::default(),
                 parse_header: p.parse_header,
             }
             .fill(fundec.body)?;
         }
+        if let Some((offset, body)) = atexit_runner {
+            View {
+                ctx: &mut shared,
+                f: &mut funcs[offset],
+                func_table: &func_table,
+                inline: &inline,
+                subst: Default::default(),
+                parse_header: p.parse_header,
+            }
+            .fill(body)?;
+        }
 
         // Bind the main function
         let main_offset = match p.desugar_stage(arena) {
@@ -559,9 +696,26 @@ where
             shared,
             funcs,
             main_offset,
+            atexit: atexit_runner.map(|(offset, _)| offset),
             allow_arbitrary_commands: false,
             fold_regex_constants: false,
             parse_header: p.parse_header,
+            has_main_loop: p.has_main_loop(),
+            tiered: p.tiered,
+            source: Default::default(),
+            argv: Default::default(),
+            regex_literals: p
+                .regex_literals()
+                .into_iter()
+                .filter_map(|re| std::str::from_utf8(re).ok().map(String::from))
+                .collect(),
+            input: crate::runtime::InputConfig {
+                prefilter: p.prefilter().unwrap_or_default(),
+                track_records: p.tracks_records(),
+                round_trip: p.round_trip,
+            },
+            kernel: None,
+            reads_argv: p.argv_vars.is_some(),
         })
     }
 }
@@ -570,9 +724,21 @@ struct View<'a, 'b, I> {
     ctx: &'a mut GlobalContext<I>,
     f: &'a mut Function<'b, I>,
     func_table: &'a HashMap<FunctionName<I>, NumTy>,
+    inline: &'a HashMap<I, Inline<'a, 'b, I>>,
+    // The variables holding the arguments of the function currently being inlined, if any.
+    subst: HashMap<I, Ident>,
     parse_header: bool,
 }
 
+// A function whose calls are replaced with the expression it returns. See
+// `ast::FunDec::inline_body`.
+struct Inline<'a, 'b, I> {
+    params: &'a [I],
+    body: &'a Expr<'a, 'b, I>,
+    // Variables other than the parameters that are read by `body`.
+    free: Vec<I>,
+}
+
 #[derive(Debug)]
 struct GlobalContext<I> {
     // Map the identifiers from the AST to this IR's Idents.
@@ -580,6 +746,11 @@ struct GlobalContext<I> {
     // Global identifiers to rewrite global => local. We only store the `low` field of the
     // identifier.
     local_globals: HashSet<NumTy>,
+    // If set, never convert globals to locals.
+    keep_globals: bool,
+    // Whether the state of comma patterns is kept in globals, so that it carries over when a tiered
+    // program moves to compiled code.
+    tiered: bool,
 
     // Many identifiers are generated and assigned to only once by construction, so we do not add
     // them to the work list for renaming. All named identifiers are added, as well as the ones
@@ -591,19 +762,37 @@ struct GlobalContext<I> {
     max: NumTy,
     conds: HashMap<usize, Ident>,
     esc: Escaper,
+    // The span of the statement currently being converted, and the span in which each identifier
+    // (keyed by its `low` field) was created. These are used to attach locations to errors.
+    cur_span: Option<Span>,
+    spans: HashMap<NumTy, Span>,
+    // The hidden array holding the functions registered with `atexit`, and its length.
+    atexit: (I, I),
+    // Whether `exit` may have to unwind through function calls, and whether loops check for it as
+    // well; see `ast::Prog::unwinds` and `runtime::signal`.
+    unwind: bool,
+    unwind_loops: bool,
+    // Whether `print` of an unmodified record ends it with the separator it was read with, rather
+    // than ORS.
+    round_trip: bool,
 }
 
 impl<I> GlobalContext<I> {
-    fn fresh(&mut self) -> Ident {
+    fn next_low(&mut self) -> NumTy {
         let res = self.max;
         self.max += 1;
-        Ident::new_global(res)
+        if let Some(span) = self.cur_span {
+            self.spans.insert(res, span);
+        }
+        res
+    }
+
+    fn fresh(&mut self) -> Ident {
+        Ident::new_global(self.next_low())
     }
 
     fn fresh_local(&mut self) -> Ident {
-        let res = self.max;
-        self.max += 1;
-        Ident::new_local(res)
+        Ident::new_local(self.next_low())
     }
 }
 
@@ -637,6 +826,11 @@ pub(crate) struct Function<'a, I> {
     //
     // NB: We only support doing this from main.
     toplevel_header: Option<NodeIx>,
+    // The number of loops in the function, not counting the toplevel loop.
+    pub num_loops: usize,
+    // Stack of the footer nodes for the phases (see `ast::Stmt::Phase`) within which the current
+    // statement is nested. `exit` jumps to the innermost one.
+    phases: SmallVec<NodeIx>,
 
     // Variable assignments, used to extract fast paths for splitting.
     // None indicates a call to `getline`.
@@ -665,11 +859,51 @@ impl<'a, I> Function<'a, I> {
             exit,
             loop_ctx: Default::default(),
             toplevel_header: None,
+            num_loops: 0,
+            phases: Default::default(),
             vars: Default::default(),
             dt: Default::default(),
             df: Default::default(),
         }
     }
+
+    // Recompute the assignments to builtin variables after the CFG has been simplified: some
+    // assignments may now be string literals, and others may no longer be reachable.
+    fn update_vars(&mut self, reachable: &[bool]) {
+        let mut vars: HashMap<_, Vec<_>> = Default::default();
+        if let Some(calls) = self.vars.remove(&None) {
+            vars.insert(
+                None,
+                calls.into_iter().filter(|(bb, _)| reachable[*bb]).collect(),
+            );
+        }
+        for (bb, n) in self.cfg.raw_nodes().iter().enumerate() {
+            // Literals assigned to builtins earlier in this block. These let us see through
+            // chained assignments like `FS = OFS = "\t"`, which copy one builtin into another.
+            let mut known: HashMap<builtins::Variable, &'a [u8]> = Default::default();
+            for stmt in n.weight.q.iter() {
+                match stmt {
+                    PrimStmt::SetBuiltin(b, e) => {
+                        let lit = match e {
+                            PrimExpr::Val(PrimVal::StrLit(s)) => Some(*s),
+                            PrimExpr::LoadBuiltin(src) => known.get(src).cloned(),
+                            _ => None,
+                        };
+                        match lit {
+                            Some(s) => known.insert(*b, s),
+                            None => known.remove(b),
+                        };
+                        vars.entry(Some(*b)).or_default().push((bb, lit));
+                    }
+                    // Functions can assign to any builtin.
+                    PrimStmt::AsgnVar(_, PrimExpr::CallUDF(..))
+                    | PrimStmt::AsgnIndex(_, _, PrimExpr::CallUDF(..)) => known.clear(),
+                    _ => {}
+                }
+            }
+        }
+        self.vars = vars;
+    }
 }
 
 pub(crate) fn is_unused(i: Ident) -> bool {
@@ -758,7 +992,11 @@ where
         if let Some(i) = self.ctx.conds.get(&cond) {
             return *i;
         }
-        let i = self.fresh_local();
+        let i = if self.ctx.tiered {
+            self.fresh()
+        } else {
+            self.fresh_local()
+        };
         self.ctx.conds.insert(cond, i);
         self.ctx.may_rename.push(i);
         i
@@ -771,6 +1009,12 @@ where
     ) -> Result<NodeIx> /*next open */ {
         use Stmt::*;
         Ok(match stmt {
+            Located(span, s) => {
+                let prev = mem::replace(&mut self.ctx.cur_span, Some(*span));
+                let res = self.convert_stmt(s, current_open);
+                self.ctx.cur_span = prev;
+                res.map_err(|e| e.with_span(*span))?
+            }
             StartCond(cond) => {
                 self.set_cond(current_open, *cond, 1)?;
                 current_open
@@ -837,18 +1081,39 @@ where
                 // Why a macro? breaking this out into methods too easily runs afoul of aliasing
                 // rules, a previous version here had to split out several local variables into
                 // parameters of outer functions; it was a lot more code.
-                if vs.len() == 0 {
+                let prints_record = match &vs[..] {
+                    [] => true,
+                    [ast::Expr::Unop(Unop::Column, ast::Expr::ILit(0))] => self.ctx.round_trip,
+                    _ => false,
+                };
+                if prints_record {
                     let tmp = self.fresh_local();
                     self.add_stmt(
                         current_open,
                         PrimStmt::AsgnVar(
-                            tmp,
+                            tmp.clone(),
                             PrimExpr::CallBuiltin(
                                 builtins::Function::Unop(Unop::Column),
                                 smallvec![PrimVal::ILit(0)],
                             ),
                         ),
                     )?;
+                    let ors = if self.ctx.round_trip {
+                        let sep = self.fresh_local();
+                        self.add_stmt(
+                            current_open,
+                            PrimStmt::AsgnVar(
+                                sep.clone(),
+                                PrimExpr::CallBuiltin(
+                                    builtins::Function::RoundTripSep,
+                                    smallvec![PrimVal::Var(tmp.clone()), ors],
+                                ),
+                            ),
+                        )?;
+                        PrimVal::Var(sep)
+                    } else {
+                        ors
+                    };
                     self.add_stmt(
                         current_open,
                         PrimStmt::PrintAll(smallvec![PrimVal::Var(tmp), ors], out.clone()),
@@ -905,6 +1170,7 @@ where
                     /*is_do*/ false,
                     /*is_toplevel*/ false,
                 )?;
+                let h = self.loop_unwind_check(h)?;
                 let (h_end, cond_val) = if let Some(c) = cond {
                     self.convert_val(c, h)?
                 } else {
@@ -919,6 +1185,7 @@ where
             While(is_toplevel, cond, body) => {
                 let (h, b_start, _b_end, f) =
                     self.make_loop(body, None, current_open, /*is_do*/ false, *is_toplevel)?;
+                let h = self.loop_unwind_check(h)?;
                 let (h_end, cond_val) = self.convert_val(cond, h)?;
                 self.f
                     .cfg
@@ -934,6 +1201,7 @@ where
                     /*is_do*/ true,
                     /*is_toplevel*/ false,
                 )?;
+                let h = self.loop_unwind_check(h)?;
                 let (h_end, cond_val) = self.convert_val(cond, h)?;
                 self.f
                     .cfg
@@ -952,7 +1220,8 @@ where
                 // in the array.
                 let cond = PrimExpr::HasNext(array_iter.clone());
                 let cond_block = self.f.cfg.add_node(Default::default());
-                let cond_v = self.to_val(cond, cond_block)?;
+                let cond_end = self.loop_unwind_check(cond_block)?;
+                let cond_v = self.to_val(cond, cond_end)?;
                 self.f
                     .cfg
                     .add_edge(current_open, cond_block, Transition::null());
@@ -963,6 +1232,7 @@ where
                 self.add_stmt(footer, PrimStmt::IterDrop(array_iter.clone()))?;
 
                 self.f.loop_ctx.push((cond_block, footer));
+                self.f.num_loops += 1;
 
                 // Create the body, but start by getting the next element from the iterator and
                 // assigning it to `v`
@@ -972,8 +1242,8 @@ where
                 let body_end = self.convert_stmt(body, body_start)?;
                 self.f
                     .cfg
-                    .add_edge(cond_block, body_start, Transition::new(cond_v));
-                self.f.cfg.add_edge(cond_block, footer, Transition::null());
+                    .add_edge(cond_end, body_start, Transition::new(cond_v));
+                self.f.cfg.add_edge(cond_end, footer, Transition::null());
                 self.f
                     .cfg
                     .add_edge(body_end, cond_block, Transition::null());
@@ -1011,6 +1281,49 @@ where
                 self.seal(current_open);
                 current_open
             }
+            Exit(status) => {
+                if let Some(status) = status {
+                    let (next, v) = self.convert_val(status, current_open)?;
+                    current_open = next;
+                    self.add_stmt(
+                        current_open,
+                        PrimStmt::AsgnVar(
+                            Ident::unused(),
+                            PrimExpr::CallBuiltin(builtins::Function::SetExit, smallvec![v]),
+                        ),
+                    )?;
+                }
+                if self.ctx.unwind {
+                    self.add_stmt(
+                        current_open,
+                        PrimStmt::AsgnVar(
+                            Ident::unused(),
+                            PrimExpr::CallBuiltin(
+                                builtins::Function::SetExiting,
+                                smallvec![PrimVal::ILit(1)],
+                            ),
+                        ),
+                    )?;
+                }
+                let footer = self.exit_target()?;
+                // Statements after `exit` never run, but they still need a block with a
+                // predecessor, so the jump to the footer is written as an always-taken branch.
+                let next = self.f.cfg.add_node(Default::default());
+                self.f
+                    .cfg
+                    .add_edge(current_open, footer, Transition::new(PrimVal::ILit(1)));
+                self.f.cfg.add_edge(current_open, next, Transition::null());
+                self.seal(current_open);
+                next
+            }
+            Phase(s) => {
+                let footer = self.f.cfg.add_node(Default::default());
+                self.f.phases.push(footer);
+                let end = self.convert_stmt(s, current_open);
+                self.f.phases.pop().unwrap();
+                self.f.cfg.add_edge(end?, footer, Transition::null());
+                footer
+            }
         })
     }
 
@@ -1185,18 +1498,19 @@ where
                 return Ok((next, if *is_post { pre.unwrap() } else { post }));
             }
             ReadStdin => {
-                use builtins::Function::{ReadErrStdin, ReadLineStdinFused};
-                self.add_stmt(
+                // Reads the next record into $0 and returns the same result as ReadErrStdin, so
+                // that the main loop makes one call into the runtime per record. The result is
+                // also written to `unused`, which doubles as main's return variable and so needs
+                // a definition in the loop header.
+                let res = self.to_val(
+                    PrimExpr::CallBuiltin(builtins::Function::ReadLineStdinFused, smallvec![]),
                     current_open,
-                    PrimStmt::AsgnVar(
-                        Ident::unused(),
-                        PrimExpr::CallBuiltin(ReadLineStdinFused, smallvec![]),
-                    ),
                 )?;
-                return self.convert_expr(
-                    &ast::Expr::Call(Either::Right(ReadErrStdin), vec![]),
+                self.add_stmt(
                     current_open,
-                );
+                    PrimStmt::AsgnVar(Ident::unused(), PrimExpr::Val(res.clone())),
+                )?;
+                PrimExpr::Val(res)
             }
             Getline {
                 from,
@@ -1213,64 +1527,104 @@ where
                 // Another use of non-structural recursion for desugaring. Here we desugar:
                 //   getline var < file
                 // to
-                //   var = nextline(file)
-                //   readerr(file)
-                // And we fill in various other pieces of sugar as well. Informally:
+                //   tmp = nextline(file)
+                //   res = readerr(file)
+                //   if (res > 0) var = tmp
+                // so that `var` is left alone at the end of the input or on an error, as POSIX
+                // requires. And we fill in various other pieces of sugar as well. Informally:
                 //  getline < file => getline $0 < file
                 //  getline var => getline var < stdin
                 //  getline => getline $0
+                // Except that an unadorned `getline` uses the "fused" stdin construct, which reads
+                // directly into $0 and in turn enables some optimizations.
                 use builtins::Function::{
                     Nextline, NextlineCmd, NextlineStdin, ReadErr, ReadErrCmd, ReadErrStdin,
                 };
-                let next_line = if *is_file { Nextline } else { NextlineCmd };
-                let read_err = if *is_file { ReadErr } else { ReadErrCmd };
-                match (from, into) {
-                    // an unadorned `getline` is uses the "fused" stdin construct, which in turn
-                    // enables some optimizations.
-                    (None /* stdin */, None /* $0 */) => {
-                        return self.convert_expr_inner(
-                            &ast::Expr::ReadStdin,
-                            current_open,
-                            in_cond,
-                        )
-                    }
-                    (from, None /* $0 */) => {
-                        return self.convert_expr(
-                            &ast::Expr::Getline {
-                                from: from.clone(),
-                                into: Some(&Unop(ast::Unop::Column, &ast::Expr::ILit(0))),
-                                is_file: *is_file,
-                            },
-                            current_open,
-                        )
-                    }
-                    (Some(from), Some(into)) => {
-                        let (next, _) = self.convert_expr(
-                            &ast::Expr::Assign(
-                                into,
-                                &ast::Expr::Call(Either::Right(next_line), vec![from]),
-                            ),
-                            current_open,
-                        )?;
-                        return self.convert_expr(
-                            &ast::Expr::Call(Either::Right(read_err), vec![from]),
-                            next,
-                        );
-                    }
-                    (None /*stdin*/, Some(into)) => {
-                        let (next, _) = self.convert_expr(
-                            &ast::Expr::Assign(
-                                into,
-                                &ast::Expr::Call(Either::Right(NextlineStdin), vec![]),
-                            ),
-                            current_open,
-                        )?;
-                        return self.convert_expr(
-                            &ast::Expr::Call(Either::Right(ReadErrStdin), vec![]),
-                            next,
-                        );
-                    }
+                let gt_zero = |res: &PrimVal<'b>| {
+                    PrimExpr::CallBuiltin(
+                        builtins::Function::Binop(ast::Binop::GT),
+                        smallvec![res.clone(), PrimVal::ILit(0)],
+                    )
+                };
+                let col0 = &Unop(ast::Unop::Column, &ast::Expr::ILit(0));
+                let (done, res, read) = if let (None, None) = (from, into) {
+                    // The fused read clears $0 at the end of the input, so put it back afterwards.
+                    let (next, saved) = self.convert_val(col0, current_open)?;
+                    let (next, res) =
+                        self.convert_expr_inner(&ast::Expr::ReadStdin, next, in_cond)?;
+                    let res = self.to_val(res, next)?;
+                    let read = self.to_val(gt_zero(&res), next)?;
+                    let f_start = self.f.cfg.add_node(Default::default());
+                    let (f_end, _) = self.do_assign(col0, |_| PrimExpr::Val(saved), f_start)?;
+                    let done = self.f.cfg.add_node(Default::default());
+                    self.f
+                        .cfg
+                        .add_edge(next, done, Transition::new(read.clone()));
+                    self.f.cfg.add_edge(next, f_start, Transition::null());
+                    self.f.cfg.add_edge(f_end, done, Transition::null());
+                    (done, res, read)
+                } else {
+                    let into = into.unwrap_or(col0);
+                    let (next, line, res) = match from {
+                        Some(from) => {
+                            let (next_line, read_err) = if *is_file {
+                                (Nextline, ReadErr)
+                            } else {
+                                (NextlineCmd, ReadErrCmd)
+                            };
+                            let (next, from_v) = self.convert_val(from, current_open)?;
+                            let line = self.to_val(
+                                PrimExpr::CallBuiltin(next_line, smallvec![from_v.clone()]),
+                                next,
+                            )?;
+                            let res = self
+                                .to_val(PrimExpr::CallBuiltin(read_err, smallvec![from_v]), next)?;
+                            (next, line, res)
+                        }
+                        None => {
+                            let line = self.to_val(
+                                PrimExpr::CallBuiltin(NextlineStdin, smallvec![]),
+                                current_open,
+                            )?;
+                            let res = self.to_val(
+                                PrimExpr::CallBuiltin(ReadErrStdin, smallvec![]),
+                                current_open,
+                            )?;
+                            (current_open, line, res)
+                        }
+                    };
+                    let read = self.to_val(gt_zero(&res), next)?;
+                    let t_start = self.f.cfg.add_node(Default::default());
+                    let (t_end, _) = self.do_assign(into, |_| PrimExpr::Val(line), t_start)?;
+                    let done = self.f.cfg.add_node(Default::default());
+                    self.f
+                        .cfg
+                        .add_edge(next, t_start, Transition::new(read.clone()));
+                    self.f.cfg.add_edge(t_end, done, Transition::null());
+                    self.f.cfg.add_edge(next, done, Transition::null());
+                    (done, res, read)
                 };
+                // Records read from standard input count towards NR and FNR, and records read
+                // from a command count towards NR.
+                let counters: &[builtins::Variable] = match (from, *is_file) {
+                    (None, _) => &[builtins::Variable::NR, builtins::Variable::FNR],
+                    (Some(_), false) => &[builtins::Variable::NR],
+                    (Some(_), true) => &[],
+                };
+                for var in counters.iter().cloned() {
+                    let count = self.to_val(PrimExpr::LoadBuiltin(var), done)?;
+                    self.add_stmt(
+                        done,
+                        PrimStmt::SetBuiltin(
+                            var,
+                            PrimExpr::CallBuiltin(
+                                builtins::Function::Binop(ast::Binop::Plus),
+                                smallvec![count, read.clone()],
+                            ),
+                        ),
+                    )?;
+                }
+                return Ok((done, PrimExpr::Val(res)));
             }
         };
         Ok((current_open, res_expr))
@@ -1302,6 +1656,117 @@ where
         }
         Ok((current_open, PrimExpr::Sprintf(fmt, res)))
     }
+
+    // tee(s, f1, f2, ...) => print s > f1; print s > f2; ...; and returns the number of files.
+    fn do_tee<'c>(
+        &mut self,
+        args: &Vec<&'c Expr<'c, 'b, I>>,
+        mut current_open: NodeIx,
+    ) -> Result<(NodeIx, PrimExpr<'b>)> {
+        if args.len() < 2 {
+            return err!("tee must be passed a string and at least one file");
+        }
+        let (next, s) = self.convert_val(args[0], current_open)?;
+        let s = self.escape(s, current_open)?;
+        current_open = next;
+        let ors = self.fresh_local();
+        self.add_stmt(
+            current_open,
+            PrimStmt::AsgnVar(ors.clone(), PrimExpr::LoadBuiltin(builtins::Variable::ORS)),
+        )?;
+        for f in &args[1..] {
+            let (next, out) = self.convert_val(f, current_open)?;
+            current_open = next;
+            self.add_stmt(
+                current_open,
+                PrimStmt::PrintAll(
+                    smallvec![s.clone(), PrimVal::Var(ors.clone())],
+                    Some((out, FileSpec::Trunc)),
+                ),
+            )?;
+        }
+        Ok((
+            current_open,
+            PrimExpr::Val(PrimVal::ILit(args.len() as i64 - 1)),
+        ))
+    }
+    fn do_sample_bernoulli<'c>(
+        &mut self,
+        args: &Vec<&'c Expr<'c, 'b, I>>,
+        current_open: NodeIx,
+    ) -> Result<(NodeIx, PrimExpr<'b>)> {
+        // sample_bernoulli(p) => rand() < p, so that srand makes samples reproducible.
+        if args.len() != 1 {
+            return err!("sample_bernoulli must be passed a single probability");
+        }
+        let (next, p) = self.convert_val(args[0], current_open)?;
+        let r = self.fresh_local();
+        self.add_stmt(
+            next,
+            PrimStmt::AsgnVar(
+                r.clone(),
+                PrimExpr::CallBuiltin(builtins::Function::Rand, smallvec![]),
+            ),
+        )?;
+        Ok((
+            next,
+            PrimExpr::CallBuiltin(
+                builtins::Function::Binop(ast::Binop::LT),
+                smallvec![PrimVal::Var(r), p],
+            ),
+        ))
+    }
+    // hist_print(h) => print hist_format(h), without an ORS; and returns 0.
+    fn do_hist_print<'c>(
+        &mut self,
+        args: &Vec<&'c Expr<'c, 'b, I>>,
+        current_open: NodeIx,
+    ) -> Result<(NodeIx, PrimExpr<'b>)> {
+        if args.len() != 1 {
+            return err!("hist_print must be passed a single histogram");
+        }
+        let (next, h) = self.convert_val(args[0], current_open)?;
+        let s = self.fresh_local();
+        self.add_stmt(
+            next,
+            PrimStmt::AsgnVar(
+                s.clone(),
+                PrimExpr::CallBuiltin(builtins::Function::HistFormat, smallvec![h]),
+            ),
+        )?;
+        self.add_stmt(next, PrimStmt::PrintAll(smallvec![PrimVal::Var(s)], None))?;
+        Ok((next, PrimExpr::Val(PrimVal::ILit(0))))
+    }
+    // atexit("f") => --atexit[++--atexit-n] = "f"; see `ast::Prog::atexit_runner`.
+    fn do_atexit<'c>(
+        &mut self,
+        args: &Vec<&'c Expr<'c, 'b, I>>,
+        current_open: NodeIx,
+    ) -> Result<(NodeIx, PrimExpr<'b>)> {
+        use ast::Expr::*;
+        let name = match &args[..] {
+            [StrLit(name)] => *name,
+            _ => return err!("atexit must be passed the name of a function as a string literal"),
+        };
+        let defined = self.func_table.keys().any(|f| match f {
+            FunctionName::Named(f) => f.to_string().as_bytes() == name,
+            _ => false,
+        });
+        if !defined {
+            return err!(
+                "atexit: no function named \"{}\"",
+                String::from_utf8_lossy(name)
+            );
+        }
+        let (arr, count) = self.ctx.atexit.clone();
+        let (arr, count) = (Var(arr), Var(count));
+        let ix = Inc {
+            is_inc: true,
+            is_post: false,
+            x: &count,
+        };
+        self.convert_expr(&Assign(&Index(&arr, &ix), &StrLit(name)), current_open)
+    }
     fn do_assign<'c>(
         &mut self,
         v: &'c Expr<'c, 'b, I>,
@@ -1503,6 +1968,55 @@ where
         }
     }
 
+    // The node that `exit` jumps to: the end of the innermost phase, or else the end of the
+    // function. Functions return their default value.
+    fn exit_target(&mut self) -> Result<NodeIx> {
+        if let Some(footer) = self.f.phases.last() {
+            return Ok(*footer);
+        }
+        if let FunctionName::Named(_) = self.f.name {
+            let ret = self.f.cfg.add_node(Default::default());
+            self.add_stmt(
+                ret,
+                PrimStmt::AsgnVar(self.f.ret, PrimExpr::Val(PrimVal::Var(Ident::unused()))),
+            )?;
+            self.f.cfg.add_edge(ret, self.f.exit, Transition::null());
+            return Ok(ret);
+        }
+        Ok(self.f.exit)
+    }
+
+    // Jump to `exit_target` if an `exit` is unwinding, returning the node to carry on from
+    // otherwise.
+    fn unwind_check(&mut self, current_open: NodeIx) -> Result<NodeIx> {
+        let exiting = self.fresh_local();
+        self.add_stmt(
+            current_open,
+            PrimStmt::AsgnVar(
+                exiting,
+                PrimExpr::CallBuiltin(builtins::Function::Exiting, smallvec![]),
+            ),
+        )?;
+        let target = self.exit_target()?;
+        let next = self.f.cfg.add_node(Default::default());
+        self.f
+            .cfg
+            .add_edge(current_open, target, Transition::new(PrimVal::Var(exiting)));
+        self.f.cfg.add_edge(current_open, next, Transition::null());
+        self.seal(current_open);
+        Ok(next)
+    }
+
+    // Loops only check for an unwinding `exit` when a second signal can set it; see
+    // `runtime::signal`.
+    fn loop_unwind_check(&mut self, header: NodeIx) -> Result<NodeIx> {
+        if self.ctx.unwind_loops {
+            self.unwind_check(header)
+        } else {
+            Ok(header)
+        }
+    }
+
     fn make_loop<'c>(
         &mut self,
         body: &'c Stmt<'c, 'b, I>,
@@ -1522,6 +2036,8 @@ where
         self.f.loop_ctx.push((h, f));
         if is_toplevel {
             self.f.toplevel_header = Some(h);
+        } else {
+            self.f.num_loops += 1;
         }
 
         // The body is a standalone graph.
@@ -1553,6 +2069,35 @@ where
         Ok((h, b_start, b_end, f))
     }
 
+    // Evaluate the body of `inline` in place of a call to it with (already evaluated) arguments
+    // `args`. Returns `None` if the call cannot be inlined, in which case nothing is added to the
+    // CFG.
+    fn inline_call(
+        &mut self,
+        inline: &Inline<'a, 'b, I>,
+        args: SmallVec<PrimVal<'b>>,
+        current_open: NodeIx,
+    ) -> Result<Option<(NodeIx, PrimExpr<'b>)>> {
+        // Missing arguments are treated as uninitialized variables, which is only correct for
+        // scalars; leave those calls alone. Likewise if a variable read by the body would refer
+        // to a parameter of the caller, rather than to a global.
+        if args.len() != inline.params.len()
+            || inline.free.iter().any(|v| self.f.args_map.contains_key(v))
+        {
+            return Ok(None);
+        }
+        let mut subst = HashMap::default();
+        for (param, arg) in inline.params.iter().zip(args.into_iter()) {
+            let id = self.fresh_local();
+            self.add_stmt(current_open, PrimStmt::AsgnVar(id, PrimExpr::Val(arg)))?;
+            subst.insert(param.clone(), id);
+        }
+        let outer = mem::replace(&mut self.subst, subst);
+        let res = self.convert_expr(inline.body, current_open);
+        self.subst = outer;
+        res.map(Some)
+    }
+
     fn to_val(&mut self, exp: PrimExpr<'b>, current_open: NodeIx) -> Result<PrimVal<'b>> {
         Ok(if let PrimExpr::Val(v) = exp {
             v
@@ -1577,9 +2122,50 @@ where
                 // function that occurs in expression position.
                 return self.do_sprintf(args, current_open);
             }
+            // tee is not a reserved word in other Awks, so a function of the same name takes
+            // precedence.
+            Either::Left(fname)
+                if fname.is_tee()
+                    && !self
+                        .func_table
+                        .contains_key(&FunctionName::Named(fname.clone())) =>
+            {
+                return self.do_tee(args, current_open);
+            }
+            // Likewise for sample_bernoulli.
+            Either::Left(fname)
+                if fname.is_sample_bernoulli()
+                    && !self
+                        .func_table
+                        .contains_key(&FunctionName::Named(fname.clone())) =>
+            {
+                return self.do_sample_bernoulli(args, current_open);
+            }
+            // And for hist_print.
+            Either::Left(fname)
+                if fname.is_hist_print()
+                    && !self
+                        .func_table
+                        .contains_key(&FunctionName::Named(fname.clone())) =>
+            {
+                return self.do_hist_print(args, current_open);
+            }
+            // And for atexit.
+            Either::Left(fname)
+                if fname.is_atexit()
+                    && !self
+                        .func_table
+                        .contains_key(&FunctionName::Named(fname.clone())) =>
+            {
+                return self.do_atexit(args, current_open);
+            }
             Either::Left(fname) => {
-                if let Ok(bi) = builtins::Function::try_from(fname.clone()) {
-                    // Okay, there's a builtin in here.
+                let declared = self
+                    .func_table
+                    .contains_key(&FunctionName::Named(fname.clone()));
+                if let (false, Ok(bi)) = (declared, builtins::Function::try_from(fname.clone())) {
+                    // Okay, there's a builtin in here. A function declared in the program with the
+                    // same name takes precedence; see `builtins::Function::is_reserved`.
                     Either::Right(bi)
                 } else {
                     // We'll keep this as a raw identifier. Below, we'll check if it's a UDF, or if
@@ -1600,6 +2186,11 @@ where
         }
         match bi {
             Either::Left(fname) => {
+                if let Some(inline) = self.inline.get(&fname) {
+                    if let Some(res) = self.inline_call(inline, prim_args.clone(), open)? {
+                        return Ok(res);
+                    }
+                }
                 return if let Some(i) = self.func_table.get(&FunctionName::Named(fname.clone())) {
                     // For field separator optimizations, any UDF calls in the BEGIN block of main
                     // causes fallback to the generic regex-based splitter.
@@ -1610,7 +2201,17 @@ where
                         .entry(None)
                         .or_insert_with(Vec::new)
                         .push((current_open.index(), None));
-                    Ok((open, PrimExpr::CallUDF(*i, prim_args)))
+                    if !self.ctx.unwind {
+                        return Ok((open, PrimExpr::CallUDF(*i, prim_args)));
+                    }
+                    // Unwind if the function called `exit`; see `runtime::exit`.
+                    let res = self.fresh_local();
+                    self.add_stmt(
+                        open,
+                        PrimStmt::AsgnVar(res, PrimExpr::CallUDF(*i, prim_args)),
+                    )?;
+                    let open = self.unwind_check(open)?;
+                    Ok((open, PrimExpr::Val(PrimVal::Var(res))))
                 } else {
                     err!("Call to unknown function \"{}\"", fname)
                 };
@@ -1652,12 +2253,70 @@ where
 
                 // substr(s, a) => substr(s, a, INT_MAX); as we always clamp the second value to
                 // the length of s.
-                if bi == builtins::Function::Substr && args.len() == 2 {
+                if (bi == builtins::Function::Substr || bi == builtins::Function::GraphemeSubstr)
+                    && args.len() == 2
+                {
                     // We clamp indexes anyways, we'll just put a big number in as the
                     // rightmost index.
                     prim_args.push(PrimVal::ILit(i64::max_value()));
                 }
 
+                // trim(s) => trim(s, <whitespace>), and likewise for ltrim and rtrim.
+                if (bi == builtins::Function::Trim
+                    || bi == builtins::Function::LTrim
+                    || bi == builtins::Function::RTrim)
+                    && args.len() == 1
+                {
+                    prim_args.push(PrimVal::StrLit(crate::runtime::trim::DEFAULT_SET));
+                }
+
+                // lpad(s, n) => lpad(s, n, " "), and likewise for rpad.
+                if (bi == builtins::Function::LPad || bi == builtins::Function::RPad)
+                    && args.len() == 2
+                {
+                    prim_args.push(PrimVal::StrLit(crate::runtime::pad::DEFAULT_PAD));
+                }
+
+                // bound(m, n) => bound(m, n, "lru")
+                if bi == builtins::Function::Bound && args.len() == 2 {
+                    prim_args.push(PrimVal::StrLit(b"lru"));
+                }
+
+                // bloom_add(b, x) => bloom_add(b, x, 1000000, 0.01), and likewise with only `n`.
+                if bi == builtins::Function::BloomAdd {
+                    if args.len() == 2 {
+                        prim_args.push(PrimVal::ILit(crate::runtime::sketch::BLOOM_VALUES));
+                    }
+                    if args.len() <= 3 {
+                        prim_args.push(PrimVal::FLit(crate::runtime::sketch::BLOOM_ERROR));
+                    }
+                }
+
+                // hist_add(h, x) => hist_add(h, x, 1, 0), and likewise with only `w`.
+                if bi == builtins::Function::HistAdd {
+                    if args.len() == 2 {
+                        prim_args.push(PrimVal::FLit(1.0));
+                    }
+                    if args.len() <= 3 {
+                        prim_args.push(PrimVal::ILit(0));
+                    }
+                }
+
+                // readable(m) => readable(m, -1), which waits indefinitely.
+                if bi == builtins::Function::Readable && args.len() == 1 {
+                    prim_args.push(PrimVal::ILit(-1));
+                }
+
+                // getenv(name) => getenv(name, "")
+                if bi == builtins::Function::Getenv && args.len() == 1 {
+                    prim_args.push(PrimVal::StrLit(b""));
+                }
+
+                // readline(h) => readline(h, 1), which reads from the standard output.
+                if bi == builtins::Function::ReadLine && args.len() == 1 {
+                    prim_args.push(PrimVal::ILit(crate::runtime::process::STDOUT));
+                }
+
                 // srand() => the special "reseed rng" function
                 if bi == builtins::Function::Srand && args.len() == 0 {
                     bi = builtins::Function::ReseedRng;
@@ -1752,8 +2411,11 @@ where
 
     fn get_identifier(&mut self, i: &I) -> Ident {
         // Look for any local variables with this name first, then search the global scope, then
-        // create a fresh global variable.
-        if let Some(ix) = self.f.args_map.get(i) {
+        // create a fresh global variable. Within the body of an inlined function, its parameters
+        // shadow everything else.
+        if let Some(id) = self.subst.get(i) {
+            *id
+        } else if let Some(ix) = self.f.args_map.get(i) {
             self.f.args[*ix as usize].id
         } else if let Some(id) = self.ctx.hm.get(i) {
             // We have found a global identifier that is not in main. Make sure it is not marked as
@@ -1766,7 +2428,7 @@ where
             let next = self.fresh();
             self.ctx.hm.insert(i.clone(), next);
             self.ctx.may_rename.push(next);
-            if self.f.name.is_main() {
+            if self.f.name.is_main() && !self.ctx.keep_globals {
                 self.ctx.local_globals.insert(next.low);
             }
             next
@@ -1786,6 +2448,7 @@ where
             );
         }
         bb.q.push_back(stmt);
+        bb.spans.push_back(self.ctx.cur_span);
         Ok(())
     }
 
@@ -1828,12 +2491,13 @@ where
                                 .collect(),
                         );
                         let stmt = PrimStmt::AsgnVar(ident, phi);
-                        self.f
+                        let bb = self
+                            .f
                             .cfg
                             .node_weight_mut(d_ix)
-                            .expect("node in dominance frontier must be valid")
-                            .q
-                            .push_front(stmt);
+                            .expect("node in dominance frontier must be valid");
+                        bb.q.push_front(stmt);
+                        bb.spans.push_front(None);
                         phis.entry(ident).or_insert(HashSet::default()).insert(d_ix);
                         if !defsites.contains(&d_ix) {
                             worklist.insert(d_ix);
@@ -1972,7 +2636,8 @@ where
             };
             self.ctx.max as usize
         ];
-        for id in self.ctx.hm.values() {
+        // The state of comma patterns is global in tiered programs; see `get_cond`.
+        for id in self.ctx.hm.values().chain(self.ctx.conds.values()) {
             if id.global && self.ctx.local_globals.get(&id.low).is_none() {
                 state[id.low as usize].global = true;
             }
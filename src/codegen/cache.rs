@@ -0,0 +1,329 @@
+//! An on-disk cache for JIT-compiled frawk programs.
+//!
+//! Compiling a large script with Cranelift can take far longer than running it over a small
+//! input. When a [`JitCache`] is supplied, the Cranelift backend records the machine code it emits
+//! for each function (along with any relocations) and writes it to a file keyed by a hash of the
+//! program text, the command-line configuration, the version and build of frawk, and the host
+//! ISA. Subsequent runs with the same key load those bytes directly and skip code generation.
+//!
+//! Generated code usually embeds the addresses of string constants and compiled regexes as
+//! immediates. Those addresses are only meaningful in the process that produced them, so code
+//! compiled for the cache instead loads them from a data object that is filled in at load time.
+//! The cache file stores enough information to reconstruct each of these [`Constant`]s.
+use cranelift_codegen::binemit::Reloc;
+use cranelift_codegen::ir::{ExternalName, LibCall};
+use cranelift_module::RelocRecord;
+
+use crate::serialize::{Reader, Writer};
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const MAGIC: &[u8; 8] = b"FRAWKJC1";
+
+/// The relocation kinds we know how to serialize; a relocation is encoded as its index here.
+const RELOCS: &[Reloc] = &[
+    Reloc::Abs4,
+    Reloc::Abs8,
+    Reloc::X86PCRel4,
+    Reloc::X86PCRelRodata4,
+    Reloc::X86CallPCRel4,
+    Reloc::X86CallPLTRel4,
+    Reloc::X86GOTPCRel4,
+    Reloc::Arm32Call,
+    Reloc::Arm64Call,
+    Reloc::RiscvCall,
+    Reloc::ElfX86_64TlsGd,
+    Reloc::MachOX86_64Tlv,
+];
+
+/// A handle on a directory of cached, compiled programs, along with the key for the current one.
+pub struct JitCache {
+    dir: PathBuf,
+    key: String,
+}
+
+/// A value referenced by generated code that has to be recreated when that code is loaded.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Constant {
+    /// A string constant, stored as its contents.
+    Str(Vec<u8>),
+    /// A pointer to a compiled regex, stored as its source.
+    Regex(String),
+    /// A pointer to a compiled pattern set, stored as the source of each pattern.
+    RegexSet(Vec<String>),
+}
+
+/// The machine code for a single function, ready to be passed to `define_function_bytes`.
+pub(crate) struct CachedFunction {
+    pub id: u32,
+    pub bytes: Vec<u8>,
+    pub relocs: Vec<RelocRecord>,
+}
+
+/// The contents of a cache entry.
+#[derive(Default)]
+pub(crate) struct CachedModule {
+    pub consts: Vec<Constant>,
+    pub funcs: Vec<CachedFunction>,
+}
+
+impl JitCache {
+    /// Create a cache handle storing entries in `dir`. `key` should contain everything the
+    /// generated code depends on apart from the target ISA, which the backend appends itself.
+    pub fn new(dir: impl Into<PathBuf>, key: String) -> JitCache {
+        JitCache {
+            dir: dir.into(),
+            key,
+        }
+    }
+
+    fn full_key(&self, isa: &str) -> String {
+        format!(
+            "{}\0{}\0{}\0{}",
+            env!("CARGO_PKG_VERSION"),
+            env!("FRAWK_BUILD_ID"),
+            isa,
+            self.key
+        )
+    }
+
+    fn path(&self, full_key: &str) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        full_key.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.fjc", hasher.finish()))
+    }
+
+    /// Load the entry for the current program, if one is present. Entries that fail to parse or
+    /// whose stored key does not match (i.e. hash collisions) are treated as misses.
+    pub(crate) fn load(&self, isa: &str) -> Option<CachedModule> {
+        let key = self.full_key(isa);
+        let contents = fs::read(self.path(&key)).ok()?;
+        let mut r = Reader(&contents[..]);
+        if r.bytes(MAGIC.len())? != MAGIC || r.blob()? != key.as_bytes() {
+            return None;
+        }
+        let module = read_module(&mut r)?;
+        if !r.is_empty() {
+            return None;
+        }
+        Some(module)
+    }
+
+    /// Write `module` as the entry for the current program.
+    ///
+    /// The entry is written to a temporary file and then renamed into place, so concurrent
+    /// invocations of the same script never observe a partially-written entry.
+    pub(crate) fn store(&self, isa: &str, module: &CachedModule) -> io::Result<()> {
+        let key = self.full_key(isa);
+        let mut w = Writer::default();
+        w.0.extend_from_slice(&MAGIC[..]);
+        w.blob(key.as_bytes());
+        write_module(&mut w, module)?;
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(&key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, &w.0)?;
+        fs::rename(&tmp, &path).map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            e
+        })
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_module(w: &mut Writer, m: &CachedModule) -> io::Result<()> {
+    w.u64(m.consts.len() as u64);
+    for c in m.consts.iter() {
+        match c {
+            Constant::Str(s) => {
+                w.u8(0);
+                w.blob(&s[..]);
+            }
+            Constant::Regex(re) => {
+                w.u8(1);
+                w.blob(re.as_bytes());
+            }
+            Constant::RegexSet(pats) => {
+                w.u8(2);
+                w.u64(pats.len() as u64);
+                for p in pats.iter() {
+                    w.blob(p.as_bytes());
+                }
+            }
+        }
+    }
+    w.u64(m.funcs.len() as u64);
+    for f in m.funcs.iter() {
+        w.u32(f.id);
+        w.blob(&f.bytes[..]);
+        w.u64(f.relocs.len() as u64);
+        for r in f.relocs.iter() {
+            write_reloc(w, r)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_reloc(w: &mut Writer, r: &RelocRecord) -> io::Result<()> {
+    let kind = match RELOCS.iter().position(|k| k == &r.reloc) {
+        Some(k) => k,
+        None => return Err(invalid(format!("unsupported relocation {:?}", r.reloc))),
+    };
+    w.u32(r.offset);
+    w.u8(kind as u8);
+    match &r.name {
+        ExternalName::User { namespace, index } => {
+            w.u8(0);
+            w.u32(*namespace);
+            w.u32(*index);
+        }
+        ExternalName::LibCall(lc) => {
+            w.u8(1);
+            w.blob(lc.to_string().as_bytes());
+        }
+        name @ ExternalName::TestCase { .. } => {
+            return Err(invalid(format!("unsupported relocation target {}", name)))
+        }
+    }
+    w.u64(r.addend as u64);
+    Ok(())
+}
+
+fn read_module(r: &mut Reader) -> Option<CachedModule> {
+    let mut res = CachedModule::default();
+    for _ in 0..r.len()? {
+        res.consts.push(match r.u8()? {
+            0 => Constant::Str(r.blob()?.to_vec()),
+            1 => Constant::Regex(r.string()?),
+            2 => {
+                let mut pats = Vec::new();
+                for _ in 0..r.len()? {
+                    pats.push(r.string()?);
+                }
+                Constant::RegexSet(pats)
+            }
+            _ => return None,
+        });
+    }
+    for _ in 0..r.len()? {
+        let id = r.u32()?;
+        let bytes = r.blob()?.to_vec();
+        let mut relocs = Vec::new();
+        for _ in 0..r.len()? {
+            relocs.push(read_reloc(r)?);
+        }
+        res.funcs.push(CachedFunction { id, bytes, relocs });
+    }
+    Some(res)
+}
+
+fn read_reloc(r: &mut Reader) -> Option<RelocRecord> {
+    let offset = r.u32()?;
+    let reloc = *RELOCS.get(r.u8()? as usize)?;
+    let name = match r.u8()? {
+        0 => ExternalName::User {
+            namespace: r.u32()?,
+            index: r.u32()?,
+        },
+        1 => ExternalName::LibCall(LibCall::from_str(r.string()?.as_str()).ok()?),
+        _ => return None,
+    };
+    let addend = r.u64()? as i64;
+    Some(RelocRecord {
+        offset,
+        reloc,
+        name,
+        addend,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> CachedModule {
+        CachedModule {
+            consts: vec![
+                Constant::Str(b"hello\0world".to_vec()),
+                Constant::Regex("^[a-z]+$".into()),
+                Constant::RegexSet(vec!["a+".into(), "b".into()]),
+            ],
+            funcs: vec![
+                CachedFunction {
+                    id: 3,
+                    bytes: vec![0x55, 0x48, 0x89, 0xe5, 0xc3],
+                    relocs: vec![
+                        RelocRecord {
+                            offset: 1,
+                            reloc: Reloc::Abs8,
+                            name: ExternalName::User {
+                                namespace: 1,
+                                index: 0,
+                            },
+                            addend: -4,
+                        },
+                        RelocRecord {
+                            offset: 2,
+                            reloc: Reloc::X86CallPLTRel4,
+                            name: ExternalName::LibCall(LibCall::FloorF64),
+                            addend: 0,
+                        },
+                    ],
+                },
+                CachedFunction {
+                    id: 0,
+                    bytes: vec![],
+                    relocs: vec![],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = JitCache::new(dir.path(), "BEGIN { print 1; }".into());
+        assert!(cache.load("x86_64").is_none());
+        let module = sample();
+        cache.store("x86_64", &module).unwrap();
+        let loaded = cache.load("x86_64").expect("entry should be present");
+        assert_eq!(loaded.consts, module.consts);
+        assert_eq!(loaded.funcs.len(), module.funcs.len());
+        for (l, r) in loaded.funcs.iter().zip(module.funcs.iter()) {
+            assert_eq!(l.id, r.id);
+            assert_eq!(l.bytes, r.bytes);
+            assert_eq!(l.relocs.len(), r.relocs.len());
+            for (lr, rr) in l.relocs.iter().zip(r.relocs.iter()) {
+                assert_eq!(lr.offset, rr.offset);
+                assert_eq!(lr.reloc, rr.reloc);
+                assert_eq!(lr.name, rr.name);
+                assert_eq!(lr.addend, rr.addend);
+            }
+        }
+        // Different ISAs and different programs get different entries.
+        assert!(cache.load("aarch64").is_none());
+        assert!(JitCache::new(dir.path(), "BEGIN { print 2; }".into())
+            .load("x86_64")
+            .is_none());
+    }
+
+    #[test]
+    fn corrupt_entries_are_misses() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = JitCache::new(dir.path(), "{ print $1 }".into());
+        cache.store("x86_64", &sample()).unwrap();
+        let path = cache.path(&cache.full_key("x86_64"));
+        let mut contents = fs::read(&path).unwrap();
+        contents.truncate(contents.len() - 3);
+        fs::write(&path, &contents).unwrap();
+        assert!(cache.load("x86_64").is_none());
+    }
+}
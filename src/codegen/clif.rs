@@ -4,7 +4,9 @@
 //! code):
 //! * Integers are I64s
 //! * Floats are F64s
-//! * Strings are I128s
+//! * Strings are pairs of I64s. Runtime functions and UDFs return them as two I64 values;
+//! otherwise they are kept in 16-byte stack slots and referred to by pointer. Cranelift does not
+//! support I128 values on every platform, so we never create any.
 //! * Maps are I64s (pointers, in actuality, but we have no need for cranelift's special handling
 //! of reference types)
 //! * Iterators are separate variables for the base pointer, the current offset, and the length of
@@ -15,24 +17,44 @@
 //! parameters to the main function and UDFs. We include metadata in [`VarRef`] to ensure we can
 //! emit separate code for assignments into global and local variables, as necessary.
 //!
+//! Integer and float globals are "promoted" within each function that uses them: they are loaded
+//! into an ordinary variable on entry, which cranelift can keep in a register, and are written
+//! back through their pointer before calls to other frawk functions (which may read or assign
+//! them) and before returning. See [`Frame::promoted`]. Globals used by the functions registered
+//! with `atexit` are not promoted, as the runtime may call those functions from any intrinsic.
+//!
 //! Strings are passed "by reference" to functions, so we explicitly allocate string variables on
 //! the stack and then pass pointers to them.
+//!
+//! When compiling with a [`JitCache`], string and regex constants are loaded from a data object
+//! rather than embedded in the code, and the emitted machine code is recorded so that it can be
+//! written to (or later read back from) disk. See the [`cache`] module for more details.
+use crate::runtime::matcher::{Matcher, PatternSet};
 use cranelift::prelude::*;
-use cranelift_codegen::ir::StackSlot;
+use cranelift_codegen::binemit::{
+    Addend, CodeOffset, NullStackMapSink, NullTrapSink, Reloc, RelocSink,
+};
+use cranelift_codegen::ir::{ConstantOffset, GlobalValue, JumpTable, SourceLoc, StackSlot};
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
-use hashbrown::HashMap;
+use cranelift_module::{
+    default_libcall_names, DataContext, DataId, FuncId, Linkage, Module, RelocRecord,
+};
+use hashbrown::{HashMap, HashSet};
 use smallvec::{smallvec, SmallVec};
 
 use crate::builtins;
 use crate::bytecode::Accum;
+use crate::codegen::cache::{CachedFunction, CachedModule, Constant, JitCache};
 use crate::codegen::{intrinsics, Backend, CodeGenerator, Config, Jit, Op, Ref, Sig, StrReg};
-use crate::common::{traverse, CompileError, Either, FileSpec, NodeIx, NumTy, Result, Stage};
+use crate::common::{
+    traverse, CompileError, Either, FileSpec, IntOverflow, NodeIx, NumTy, Result, Stage,
+};
 use crate::compile::{self, Typer};
 use crate::runtime::{self, UniqueStr};
 
 use std::convert::TryFrom;
 use std::mem;
+use std::sync::Arc;
 
 /// Information about a user-defined function needed by callers.
 #[derive(Clone)]
@@ -83,6 +105,9 @@ struct IterState {
 /// Function-level state
 struct Frame {
     vars: HashMap<Ref, VarRef>,
+    // Integer and float globals held in a local variable (the entry for the global in `vars`) for
+    // the duration of the function, mapped to the variable holding the global's address.
+    promoted: HashMap<Ref, Variable>,
     iters: HashMap<Ref, IterState>,
     header_actions: Vec<EntryDeclaration>,
     runtime: Variable,
@@ -99,6 +124,9 @@ struct Frame {
     header_block: Block,
     n_params: usize,
     n_vars: usize,
+    // The constant pool, as a global value in the current function. Only set if we are caching
+    // compiled code and the pool has been referenced.
+    consts: Option<GlobalValue>,
 }
 
 /// Function-independent data used in compilation
@@ -109,6 +137,197 @@ struct Shared {
     // We need cranelift Signatures for declaring external functions. We put them here to reuse
     // them across calls to `register_external_fn`.
     sig: Signature,
+    // Set if compiled code is being written to, or read from, a `JitCache`.
+    cache: Option<CacheState>,
+    int_overflow: IntOverflow,
+    // Globals that are never promoted: those used by the functions registered with `atexit`, which
+    // the runtime may call from inside an intrinsic, when promoted values have not been written
+    // back.
+    unpromoted: HashSet<Ref>,
+}
+
+/// State needed to compile code that can be stored in a [`JitCache`].
+struct CacheState {
+    /// A description of the current target, included in the cache key.
+    isa: String,
+    /// The data object containing the values of the constants referenced by generated code.
+    data: DataId,
+    /// Descriptions of each constant in the pool, in order.
+    consts: Vec<Constant>,
+    /// The contents of the pool: one word for each regex or pattern set, two for
+    /// each string.
+    words: Vec<u64>,
+    /// Regexes compiled while loading the pool from the cache. Generated code holds pointers to
+    /// these, so they must outlive it.
+    regexes: Vec<Arc<Matcher>>,
+    /// Likewise for pattern sets.
+    sets: Vec<Arc<PatternSet>>,
+    /// Likewise for the contents of string constants too long to store inline. These are words
+    /// rather than bytes so that the strings are aligned well enough to be literals.
+    strs: Vec<Box<[u64]>>,
+    /// Functions emitted so far, to be written back to the cache.
+    emitted: Vec<CachedFunction>,
+    /// Function bodies read from the cache, if we found an entry for this program.
+    replay: Option<HashMap<u32, CachedFunction>>,
+}
+
+impl CacheState {
+    fn new(isa: String, data: DataId, cache: &JitCache) -> CacheState {
+        let mut res = CacheState {
+            isa,
+            data,
+            consts: Default::default(),
+            words: Default::default(),
+            regexes: Default::default(),
+            sets: Default::default(),
+            strs: Default::default(),
+            emitted: Default::default(),
+            replay: None,
+        };
+        if let Some(CachedModule { consts, funcs }) = cache.load(&res.isa) {
+            for c in consts.into_iter() {
+                if res.push_const(c).is_err() {
+                    // This entry is unusable (e.g. it was produced with an incompatible regex
+                    // engine); start over and compile from scratch.
+                    res.consts.clear();
+                    res.words.clear();
+                    res.regexes.clear();
+                    res.sets.clear();
+                    res.strs.clear();
+                    return res;
+                }
+            }
+            res.replay = Some(funcs.into_iter().map(|f| (f.id, f)).collect());
+        }
+        res
+    }
+
+    /// Recreate the value of `c`, append it to the pool and return its word offset.
+    fn push_const(&mut self, c: Constant) -> Result<usize> {
+        match c {
+            Constant::Str(s) => Ok(self.push_literal(s)),
+            Constant::Regex(ref src) => {
+                let re = Arc::new(Matcher::new(src.as_str()).map_err(|e| {
+                    CompileError::new(format!("failed to compile cached regex: {}", e))
+                })?);
+                let res = self.push_re(c, &*re);
+                self.regexes.push(re);
+                Ok(res)
+            }
+            Constant::RegexSet(ref pats) => {
+                let set = Arc::new(PatternSet::new(pats.clone()).map_err(|e| {
+                    CompileError::new(format!("failed to compile cached regex: {}", e))
+                })?);
+                let res = self.push_ptr(c, &*set as *const PatternSet as *const u8);
+                self.sets.push(set);
+                Ok(res)
+            }
+        }
+    }
+
+    /// Append a string constant with contents `s` to the pool and return its word offset.
+    ///
+    /// Generated code treats string constants as literals, which are never freed. The string is
+    /// stored inline or as a literal pointing into `strs`, the same way whether the code is being
+    /// compiled or replayed.
+    fn push_literal(&mut self, s: Vec<u8>) -> usize {
+        let mut words = vec![0u64; (s.len() + 7) / 8].into_boxed_slice();
+        let bytes =
+            unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, s.len()) };
+        bytes.copy_from_slice(&s[..]);
+        let bits = runtime::Str::from(&bytes[..]).into_bits();
+        self.strs.push(words);
+        let res = self.words.len();
+        self.consts.push(Constant::Str(s));
+        self.words.push(bits as u64);
+        self.words.push((bits >> 64) as u64);
+        res
+    }
+
+    fn push_re(&mut self, c: Constant, re: *const Matcher) -> usize {
+        self.push_ptr(c, re as *const u8)
+    }
+
+    fn push_ptr(&mut self, c: Constant, p: *const u8) -> usize {
+        let res = self.words.len();
+        self.consts.push(c);
+        self.words.push(p as u64);
+        res
+    }
+
+    fn pool_bytes(&self) -> Box<[u8]> {
+        let mut res = Vec::with_capacity(std::cmp::max(self.words.len(), 1) * 8);
+        for w in self.words.iter() {
+            res.extend_from_slice(&w.to_le_bytes());
+        }
+        // Cranelift does not permit empty data objects.
+        if res.len() == 0 {
+            res.extend_from_slice(&[0u8; 8]);
+        }
+        res.into_boxed_slice()
+    }
+}
+
+/// A [`RelocSink`] recording the relocations in a function so they can be passed to
+/// `define_function_bytes`; this mirrors the sink used internally by `cranelift_jit`.
+#[derive(Default)]
+struct RelocCollector(Vec<RelocRecord>);
+
+impl RelocSink for RelocCollector {
+    fn reloc_external(
+        &mut self,
+        offset: CodeOffset,
+        _srcloc: SourceLoc,
+        reloc: Reloc,
+        name: &ExternalName,
+        addend: Addend,
+    ) {
+        self.0.push(RelocRecord {
+            offset,
+            reloc,
+            name: name.clone(),
+            addend,
+        });
+    }
+    // Jump tables and constants are emitted alongside the function body and are referenced
+    // relative to it, so they do not need to be recorded.
+    fn reloc_constant(&mut self, _: CodeOffset, _: Reloc, _: ConstantOffset) {}
+    fn reloc_jt(&mut self, _: CodeOffset, _: Reloc, _: JumpTable) {}
+}
+
+/// Whether cranelift can compile frawk programs for the host.
+pub(crate) const SUPPORTED: bool = cfg!(any(target_arch = "x86_64", target_arch = "aarch64"));
+
+/// A description of the host CPU features cranelift may take advantage of. Code compiled for one
+/// set of features may not run on a machine lacking them, so these are part of the cache key.
+fn host_features() -> String {
+    #[allow(unused_mut)]
+    let mut res = String::new();
+    #[cfg(target_arch = "x86_64")]
+    {
+        macro_rules! features {
+            ($($f:tt),*) => {
+                $( if is_x86_feature_detected!($f) {
+                    res.push_str(concat!(" ", $f));
+                } )*
+            };
+        }
+        features!(
+            "sse3", "ssse3", "sse4.1", "sse4.2", "popcnt", "avx", "avx2", "bmi1", "bmi2", "lzcnt"
+        );
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        macro_rules! features {
+            ($($f:tt),*) => {
+                $( if std::arch::is_aarch64_feature_detected!($f) {
+                    res.push_str(concat!(" ", $f));
+                } )*
+            };
+        }
+        features!("neon", "lse", "crc", "aes", "sha2", "fp16", "sve");
+    }
+    res
 }
 
 /// Toplevel information
@@ -118,6 +337,14 @@ pub(crate) struct Generator {
     cctx: codegen::Context,
     funcs: Vec<Option<Prelude>>,
     mains: Stage<FuncId>,
+    /// The function that calls the functions registered with `atexit`, if there is one, and the
+    /// main function through which the runtime calls it; see `define_atexit_function`.
+    atexit: Option<usize>,
+    atexit_main: Option<FuncId>,
+    /// The name and size of each function we define, if we are writing a perf map.
+    symbols: Option<Vec<(FuncId, u32, String)>>,
+    /// The CLIF for each function we define, if it was requested.
+    clif: Option<String>,
 }
 
 /// The state required for generating code for the function at `f`.
@@ -162,6 +389,8 @@ fn ty_to_clifty(ty: compile::Ty, ptr_ty: Type) -> Result<Type> {
     match ty {
         Null | Int => Ok(types::I64),
         Float => Ok(types::F64),
+        // No I128 values appear in generated code (see the module documentation), but the type has
+        // the size of a string, and marks string return values in signatures.
         Str => Ok(types::I128),
         MapIntInt | MapIntFloat | MapIntStr => Ok(ptr_ty),
         MapStrInt | MapStrFloat | MapStrStr => Ok(ptr_ty),
@@ -175,20 +404,88 @@ impl Jit for Generator {
             .mains
             .map_ref(|id| self.shared.module.get_finalized_function(*id)))
     }
+    fn atexit_pointer(&mut self) -> Option<*const u8> {
+        let module = &self.shared.module;
+        self.atexit_main.map(|id| module.get_finalized_function(id))
+    }
 }
 
 impl Generator {
-    pub(crate) fn init(typer: &mut Typer, _config: Config) -> Result<Generator> {
-        let builder = JITBuilder::new(default_libcall_names());
+    pub(crate) fn init(
+        typer: &mut Typer,
+        config: Config,
+        cache: Option<&JitCache>,
+    ) -> Result<Generator> {
+        Generator::init_with(typer, config, cache, None)
+    }
+
+    /// Like `init`, but if `clif` is set, the CLIF for each function is appended to it before the
+    /// function is compiled.
+    pub(crate) fn init_with(
+        typer: &mut Typer,
+        config: Config,
+        cache: Option<&JitCache>,
+        clif: Option<&mut String>,
+    ) -> Result<Generator> {
+        if !SUPPORTED {
+            return Err(CompileError::new(format!(
+                "the cranelift backend is not supported on {}; use the llvm or interp backend",
+                std::env::consts::ARCH
+            )));
+        }
+        let mut flags = settings::builder();
+        // These two match the defaults used by JITBuilder::new.
+        flags.set("use_colocated_libcalls", "false").unwrap();
+        flags.set("is_pic", "true").unwrap();
+        // Functions with large frames (e.g. with many string temporaries) would otherwise call
+        // __cranelift_probestack, which the JIT has no definition for.
+        flags.set("enable_probestack", "false").unwrap();
+        flags
+            .set("opt_level", config.jit_opt.cranelift_setting())
+            .unwrap();
+        // The verifier is expensive; only run it in debug builds.
+        flags
+            .set(
+                "enable_verifier",
+                if cfg!(debug_assertions) {
+                    "true"
+                } else {
+                    "false"
+                },
+            )
+            .unwrap();
+        let isa = cranelift_native::builder()
+            .map_err(|msg| CompileError::new(format!("host machine is not supported: {}", msg)))?
+            .finish(settings::Flags::new(flags));
+        let builder = JITBuilder::with_isa(isa, default_libcall_names());
         let mut regstate = RegistrationState { builder };
         intrinsics::register_all(&mut regstate)?;
-        let module = JITModule::new(regstate.builder);
+        let mut module = JITModule::new(regstate.builder);
         let cctx = module.make_context();
+        let cache_state = match cache {
+            Some(cache) => {
+                let isa = format!(
+                    "{} {}{}\n{}",
+                    module.isa().name(),
+                    module.isa().triple(),
+                    host_features(),
+                    module.isa().flags()
+                );
+                let data = module
+                    .declare_data("__frawk_consts", Linkage::Local, false, false)
+                    .map_err(|e| CompileError::new(format!("cranelift module error: {}", e)))?;
+                Some(CacheState::new(isa, data, cache))
+            }
+            None => None,
+        };
         let shared = Shared {
             module,
             func_ids: Default::default(),
             external_funcs: Default::default(),
             sig: cctx.func.signature.clone(),
+            cache: cache_state,
+            int_overflow: config.int_overflow,
+            unpromoted: Default::default(),
         };
         let mut global = Generator {
             shared,
@@ -197,6 +494,14 @@ impl Generator {
             funcs: Default::default(),
             // placeholder
             mains: Stage::Main(FuncId::from_u32(0)),
+            atexit: typer.atexit,
+            atexit_main: None,
+            symbols: if config.perf_map {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            clif: clif.as_ref().map(|_| String::new()),
         };
         global.define_functions(typer)?;
         let stage = match typer.stage() {
@@ -216,10 +521,47 @@ impl Generator {
             },
         };
         global.mains = stage;
+        global.atexit_main = match global.atexit {
+            Some(udf) => Some(global.define_atexit_function("__frawk_atexit", udf)?),
+            None => None,
+        };
+        if let Some(state) = &global.shared.cache {
+            let mut data = DataContext::new();
+            data.define(state.pool_bytes());
+            global
+                .shared
+                .module
+                .define_data(state.data, &data)
+                .map_err(|e| CompileError::new(format!("cranelift module error: {}", e)))?;
+        }
+        if let (Some(out), Some(text)) = (clif, global.clif.take()) {
+            out.push_str(text.as_str());
+        }
         global.shared.module.finalize_definitions();
+        if let Err(e) = global.write_perf_map() {
+            eprintln_ignore!("warning: failed to write perf map: {}", e);
+        }
+        if let (Some(state), Some(cache)) = (&mut global.shared.cache, cache) {
+            if state.replay.is_none() {
+                let module = CachedModule {
+                    consts: mem::replace(&mut state.consts, Default::default()),
+                    funcs: mem::replace(&mut state.emitted, Default::default()),
+                };
+                if let Err(e) = cache.store(&state.isa, &module) {
+                    eprintln_ignore!("warning: failed to write to JIT cache: {}", e);
+                }
+            }
+        }
         Ok(global)
     }
 
+    fn replaying(&self) -> bool {
+        match &self.shared.cache {
+            Some(state) => state.replay.is_some(),
+            None => false,
+        }
+    }
+
     /// We get a set of UDFs that are idenfitied as "toplevel" or "main", but these will probably
     /// reference global variables, which we have compiled to take as function parameters. This
     /// method allocates those globals on the stack (while still taking a pointer to the runtime as
@@ -234,11 +576,15 @@ impl Generator {
             .module
             .declare_function(name, Linkage::Export, &sig)
             .map_err(|e| {
-                CompileError(format!(
+                CompileError::new(format!(
                     "failed to declare main function: {}",
                     e.to_string()
                 ))
             })?;
+        if self.replaying() {
+            self.define_cur_function(res, name)?;
+            return Ok(res);
+        }
         let prelude = Prelude {
             sig,
             refs: smallvec![PLACEHOLDER],
@@ -246,12 +592,19 @@ impl Generator {
         };
 
         // And now we allocate global variables. First, grab the globals we need from the FuncInfo
-        // stored for `udf`.
-        let globals = self.shared.func_ids[udf as usize]
+        // stored for `udf`, along with those of the function that calls the functions registered
+        // with `atexit`.
+        let mut globals = self.shared.func_ids[udf as usize]
             .as_ref()
             .unwrap()
             .globals
             .clone();
+        let hooks = self.atexit_globals();
+        for g in hooks.iter() {
+            if !globals.contains(g) {
+                globals.push(*g);
+            }
+        }
 
         // We'll keep track of these variables and their types so we can drop them at the end.
         let mut vars = Vec::with_capacity(globals.len());
@@ -271,15 +624,15 @@ impl Generator {
             let ptr_ty = view.ptr_to(cl_ty);
             view.builder.declare_var(var, ptr_ty);
 
-            let slot = view.stack_slot_bytes(cl_ty.lane_bits() as u32 / 8);
             let default = view.default_value(ty)?;
-            if let compile::Ty::Str = ty {
-                view.store_string(slot, default);
+            let addr = if let compile::Ty::Str = ty {
+                // Default strings already have a stack slot of their own.
+                default
             } else {
+                let slot = view.stack_slot_bytes(cl_ty.lane_bits() as u32 / 8);
                 view.builder.ins().stack_store(default, slot, 0);
-            }
-
-            let addr = view.builder.ins().stack_addr(ptr_ty, slot, 0);
+                view.builder.ins().stack_addr(ptr_ty, slot, 0)
+            };
             view.builder.def_var(var, addr);
             view.f.vars.insert(
                 (reg, ty),
@@ -291,7 +644,17 @@ impl Generator {
             );
         }
 
+        // Tell the runtime where the globals used by the `atexit` functions are while `udf` runs,
+        // so that it can call those functions if the program stops early.
+        for (i, (reg, ty)) in hooks.iter().cloned().enumerate() {
+            let addr = view.builder.use_var(view.f.vars[&(reg, ty)].var);
+            view.set_atexit_global(i, addr)?;
+        }
         view.call_udf(NumTy::try_from(udf).expect("function Id too large"), &[])?;
+        for i in 0..hooks.len() {
+            let null = view.const_int(0);
+            view.set_atexit_global(i, null)?;
+        }
         for (var, ty) in vars {
             let val = view.builder.use_var(var);
             view.drop_val(ty, val);
@@ -299,31 +662,182 @@ impl Generator {
         view.builder.ins().return_(&[]);
         view.builder.finalize();
         mem::drop(view);
-        self.define_cur_function(res)?;
+        self.define_cur_function(res, name)?;
         Ok(res)
     }
 
-    fn define_cur_function(&mut self, id: FuncId) -> Result<()> {
-        self.shared
+    /// The global variables used by the function that calls the functions registered with
+    /// `atexit`, in the order in which main functions register them with the runtime.
+    fn atexit_globals(&self) -> SmallVec<[Ref; 2]> {
+        match self.atexit {
+            Some(udf) => self.shared.func_ids[udf].as_ref().unwrap().globals.clone(),
+            None => Default::default(),
+        }
+    }
+
+    /// Define a main function that calls `udf`, the function that calls the functions registered
+    /// with `atexit`. Unlike other main functions, it does not allocate the global variables it
+    /// uses: it uses those registered by the main function that is running when the runtime calls
+    /// it.
+    fn define_atexit_function(&mut self, name: &str, udf: usize) -> Result<FuncId> {
+        let mut sig = Signature::new(isa::CallConv::SystemV);
+        let ptr_ty = self.shared.module.target_config().pointer_type();
+        sig.params.push(AbiParam::new(ptr_ty));
+        let res = self
+            .shared
             .module
-            .define_function(id, &mut self.cctx, &mut codegen::binemit::NullTrapSink {})
-            .map_err(|e| CompileError(e.to_string()))?;
+            .declare_function(name, Linkage::Export, &sig)
+            .map_err(|e| {
+                CompileError::new(format!(
+                    "failed to declare main function: {}",
+                    e.to_string()
+                ))
+            })?;
+        if self.replaying() {
+            self.define_cur_function(res, name)?;
+            return Ok(res);
+        }
+        let prelude = Prelude {
+            sig,
+            refs: smallvec![PLACEHOLDER],
+            n_args: 0,
+        };
+        let globals = self.atexit_globals();
+        let mut view = self.create_view(prelude);
+        view.builder.switch_to_block(view.f.header_block);
+        view.builder.seal_block(view.f.header_block);
+        for (i, (reg, ty)) in globals.into_iter().enumerate() {
+            let var = Variable::new(view.f.n_vars);
+            view.f.n_vars += 1;
+            view.builder.declare_var(var, ptr_ty);
+            let rt = view.runtime_val();
+            let ix = view.const_int(i as i64);
+            let addr = view.call_external(
+                crate::codegen::intrinsics::atexit_global as *const u8,
+                &[rt, ix],
+            );
+            view.builder.def_var(var, addr);
+            view.f.vars.insert(
+                (reg, ty),
+                VarRef {
+                    var,
+                    is_global: true,
+                    skip_drop: true,
+                },
+            );
+        }
+        view.call_udf(NumTy::try_from(udf).expect("function Id too large"), &[])?;
+        view.builder.ins().return_(&[]);
+        view.builder.finalize();
+        mem::drop(view);
+        self.define_cur_function(res, name)?;
+        Ok(res)
+    }
+
+    /// Write a map from addresses to function names to `/tmp/perf-<pid>.map`, where `perf` looks
+    /// for symbols for JIT-compiled code.
+    fn write_perf_map(&self) -> std::io::Result<()> {
+        use std::io::Write;
+        let symbols = match &self.symbols {
+            Some(symbols) => symbols,
+            None => return Ok(()),
+        };
+        let mut buf = Vec::new();
+        for (id, size, name) in symbols.iter() {
+            let addr = self.shared.module.get_finalized_function(*id);
+            writeln!(&mut buf, "{:x} {:x} {}", addr as usize, size, name)?;
+        }
+        let path = format!("/tmp/perf-{}.map", std::process::id());
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&buf[..])
+    }
+
+    fn define_cur_function(&mut self, id: FuncId, name: &str) -> Result<()> {
+        if let Some(clif) = &mut self.clif {
+            use std::fmt::Write;
+            let isa = self.shared.module.isa();
+            let _ = writeln!(clif, "; {}\n{}", name, self.cctx.func.display(isa));
+        }
+        let module = &mut self.shared.module;
+        let size = match &mut self.shared.cache {
+            None => {
+                module
+                    .define_function(id, &mut self.cctx, &mut NullTrapSink {})
+                    .map_err(|e| CompileError::new(e.to_string()))?
+                    .size
+            }
+            Some(CacheState {
+                replay: Some(funcs),
+                ..
+            }) => {
+                let func = match funcs.get(&id.as_u32()) {
+                    Some(func) => func,
+                    None => return err!("JIT cache entry is missing function {}", id),
+                };
+                module
+                    .define_function_bytes(id, &func.bytes[..], &func.relocs[..])
+                    .map_err(|e| CompileError::new(e.to_string()))?
+                    .size
+            }
+            Some(state) => {
+                // Compile the function ourselves, rather than using `define_function`, so that we
+                // can hold onto the emitted bytes and relocations.
+                let info = self
+                    .cctx
+                    .compile(module.isa())
+                    .map_err(|e| CompileError::new(e.to_string()))?;
+                let mut bytes = vec![0u8; info.total_size as usize];
+                let mut relocs = RelocCollector::default();
+                unsafe {
+                    self.cctx.emit_to_memory(
+                        module.isa(),
+                        bytes.as_mut_ptr(),
+                        &mut relocs,
+                        &mut NullTrapSink {},
+                        &mut NullStackMapSink {},
+                    )
+                };
+                let size = module
+                    .define_function_bytes(id, &bytes[..], &relocs.0[..])
+                    .map_err(|e| CompileError::new(e.to_string()))?
+                    .size;
+                state.emitted.push(CachedFunction {
+                    id: id.as_u32(),
+                    bytes,
+                    relocs: relocs.0,
+                });
+                size
+            }
+        };
+        if let Some(symbols) = &mut self.symbols {
+            symbols.push((id, size, format!("frawk::{}", name)));
+        }
         self.shared.module.clear_context(&mut self.cctx);
         Ok(())
     }
 
     fn define_functions(&mut self, typer: &mut Typer) -> Result<()> {
         self.declare_local_funcs(typer)?;
+        self.shared.unpromoted = self.atexit_globals().into_iter().collect();
+        let replaying = self.replaying();
         for (i, frame) in typer.frames.iter().enumerate() {
             if let Some(prelude) = self.funcs[i].take() {
                 let mut view = self.create_view(prelude);
                 if i == 0 {
                     intrinsics::register_all(&mut view)?;
                 }
-                view.gen_function_body(frame)?;
+                // When replaying, we still declare everything in the same order as when the
+                // cache entry was written, so FuncIds in the cached relocations line up.
+                if !replaying {
+                    view.gen_function_body(frame)?;
+                }
                 // func_id and prelude entries should be initialized in lockstep.
                 let id = self.shared.func_ids[i].as_ref().unwrap().func_id;
-                self.define_cur_function(id)?;
+                // Functions are monomorphized, so one AWK function may map to several frames.
+                self.define_cur_function(id, &format!("{}#{}", frame.name, i))?;
             }
         }
         Ok(())
@@ -352,8 +866,10 @@ impl Generator {
                 runtime: Variable::new(0),
                 n_vars: 0,
                 vars: Default::default(),
+                promoted: Default::default(),
                 iters: Default::default(),
                 header_actions: Default::default(),
+                consts: None,
             },
             builder,
             shared: &mut self.shared,
@@ -414,15 +930,23 @@ impl Generator {
             arg_refs.push(PLACEHOLDER);
 
             sig.params.push(AbiParam::new(ptr_ty)); // runtime
-            sig.returns
-                .push(AbiParam::new(ty_to_clifty(info.ret_ty, ptr_ty)?));
+            if let compile::Ty::Str = info.ret_ty {
+                // Strings are returned in two halves, like those returned by runtime functions.
+                let half = AbiParam::new(types::I64);
+                sig.returns.extend_from_slice(&[half, half]);
+            } else {
+                sig.returns
+                    .push(AbiParam::new(ty_to_clifty(info.ret_ty, ptr_ty)?));
+            }
 
             // Now, to create a function and prelude
             let func_id = self
                 .shared
                 .module
                 .declare_function(name.as_str(), Linkage::Local, &sig)
-                .map_err(|e| CompileError(format!("cranelift module error: {}", e.to_string())))?;
+                .map_err(|e| {
+                    CompileError::new(format!("cranelift module error: {}", e.to_string()))
+                })?;
 
             self.funcs.push(Some(Prelude {
                 sig,
@@ -444,6 +968,29 @@ macro_rules! external {
 }
 
 impl<'a> View<'a> {
+    /// Load a value of type `ty` from the word at offset `off` in the constant pool.
+    ///
+    /// Panics if the pool is not enabled.
+    fn load_const(&mut self, ty: Type, off: usize) -> Value {
+        let gv = match self.f.consts {
+            Some(gv) => gv,
+            None => {
+                let data = self.shared.cache.as_ref().unwrap().data;
+                let gv = self
+                    .shared
+                    .module
+                    .declare_data_in_func(data, self.builder.func);
+                self.f.consts = Some(gv);
+                gv
+            }
+        };
+        let ptr_ty = self.void_ptr_ty();
+        let base = self.builder.ins().symbol_value(ptr_ty, gv);
+        self.builder
+            .ins()
+            .load(ty, MemFlags::trusted(), base, (off * 8) as i32)
+    }
+
     fn stack_slot_bytes(&mut self, bytes: u32) -> StackSlot {
         debug_assert!(bytes > 0); // This signals a bug; all frawk types have positive size.
         let data = StackSlotData::new(StackSlotKind::ExplicitSlot, bytes);
@@ -504,7 +1051,17 @@ impl<'a> View<'a> {
                                     None
                                 }
                             }) {
-                                self.mov_inner(*ty, *dst_reg, src_reg, /*skip_drop=*/ true)?;
+                                if *ty == compile::Ty::Str
+                                    && insts.owned_phi_srcs.contains(&(NodeIx::new(i), *dst_reg))
+                                {
+                                    self.take_str_inner(
+                                        *dst_reg, src_reg, /*skip_drop=*/ true,
+                                    )?;
+                                } else {
+                                    self.mov_inner(
+                                        *ty, *dst_reg, src_reg, /*skip_drop=*/ true,
+                                    )?;
+                                }
                             }
                         } else {
                             // We can bail out once we see the first non-phi instruction. Those all go
@@ -574,6 +1131,25 @@ impl<'a> View<'a> {
                 self.f.runtime = var;
             } else if i >= n_args {
                 // global
+                let promote = matches!(rf.1, compile::Ty::Int | compile::Ty::Float);
+                if promote && !self.shared.unpromoted.contains(&rf) {
+                    let local = Variable::new(self.f.n_vars);
+                    self.f.n_vars += 1;
+                    let cl_ty = self.get_ty(rf.1);
+                    self.builder.declare_var(local, cl_ty);
+                    let loaded = self.builder.ins().load(cl_ty, MemFlags::trusted(), val, 0);
+                    self.builder.def_var(local, loaded);
+                    self.f.promoted.insert(rf, var);
+                    self.f.vars.insert(
+                        rf,
+                        VarRef {
+                            var: local,
+                            is_global: false,
+                            skip_drop: true,
+                        },
+                    );
+                    continue;
+                }
                 self.f.vars.insert(
                     rf,
                     VarRef {
@@ -635,6 +1211,14 @@ impl<'a> View<'a> {
         }
     }
 
+    /// Tell the runtime that the `i`th global used by the functions registered with `atexit` is at
+    /// `addr`; see `Generator::define_main_function`.
+    fn set_atexit_global(&mut self, i: usize, addr: Value) -> Result<()> {
+        let rt = self.runtime_val();
+        let ix = self.const_int(i as i64);
+        self.call_void(external!(set_atexit_global), &mut [rt, ix, addr])
+    }
+
     /// Call a frawk-level (as opposed to builtin/external) function.
     fn call_udf(&mut self, id: NumTy, args: &[Ref]) -> Result<Value> {
         let mut to_pass = SmallVec::<[Value; 6]>::with_capacity(args.len() + 1);
@@ -644,8 +1228,18 @@ impl<'a> View<'a> {
         }
         let FuncInfo { globals, func_id } = self.shared.func_ids[id as usize]
             .as_ref()
-            .expect("all referenced functions must be declared");
-        for global in globals {
+            .expect("all referenced functions must be declared")
+            .clone();
+        let mut reload = SmallVec::<[(Ref, Variable); 4]>::new();
+        for global in globals.iter() {
+            // The callee may read or assign promoted globals through their pointers, so we write
+            // them back first and load them again once it returns.
+            if let Some(ptr) = self.f.promoted.get(global).cloned() {
+                self.spill(*global, ptr);
+                reload.push((*global, ptr));
+                to_pass.push(self.builder.use_var(ptr));
+                continue;
+            }
             // We don't use get_val here because we want to pass the pointer to the global, and
             // get_val will issue a load.
             match self.f.vars.get(global) {
@@ -659,15 +1253,31 @@ impl<'a> View<'a> {
         let fref = self
             .shared
             .module
-            .declare_func_in_func(*func_id, self.builder.func);
+            .declare_func_in_func(func_id, self.builder.func);
+        if cfg!(target_arch = "aarch64") {
+            // Calls to functions in the same module are emitted as `bl` on aarch64, with a
+            // relocation cranelift_jit cannot apply. Call through the function's address instead,
+            // as we do for intrinsics.
+            self.builder.func.dfg.ext_funcs[fref].colocated = false;
+        }
         let call_inst = self.builder.ins().call(fref, &to_pass[..]);
-        Ok(self
-            .builder
-            .inst_results(call_inst)
-            .iter()
-            .cloned()
-            .next()
-            .expect("all UDFs must return a value"))
+        let res = self.call_result(call_inst);
+        for (global, ptr) in reload {
+            let cl_ty = self.get_ty(global.1);
+            let p = self.builder.use_var(ptr);
+            let v = self.builder.ins().load(cl_ty, MemFlags::trusted(), p, 0);
+            let VarRef { var, .. } = self.f.vars[&global].clone();
+            self.builder.def_var(var, v);
+        }
+        Ok(res)
+    }
+
+    /// Write the current value of the promoted global `global` to its address, `ptr`.
+    fn spill(&mut self, global: Ref, ptr: Variable) {
+        let VarRef { var, .. } = self.f.vars[&global].clone();
+        let v = self.builder.use_var(var);
+        let p = self.builder.use_var(ptr);
+        self.builder.ins().store(MemFlags::trusted(), v, p, 0);
     }
 
     /// Translate a high-level instruction. If the instruction is a `Ret`, we return the returned
@@ -688,14 +1298,21 @@ impl<'a> View<'a> {
                 Ok(())
             }
             Ret(reg, ty) => {
-                let mut v = self.get_val((*reg, *ty))?;
+                let v = self.get_val((*reg, *ty))?;
                 self.do_not_drop((*reg, *ty));
-                if let compile::Ty::Str = ty {
-                    let str_ty = self.get_ty(*ty);
-                    v = self.builder.ins().load(str_ty, MemFlags::trusted(), v, 0);
-                }
+                let rets: SmallVec<[Value; 2]> = if let compile::Ty::Str = ty {
+                    let [low, high] = self.load_str(v);
+                    smallvec![low, high]
+                } else {
+                    smallvec![v]
+                };
                 self.drop_all();
-                self.builder.ins().return_(&[v]);
+                let promoted: SmallVec<[(Ref, Variable); 4]> =
+                    self.f.promoted.iter().map(|(r, p)| (*r, *p)).collect();
+                for (global, ptr) in promoted {
+                    self.spill(global, ptr);
+                }
+                self.builder.ins().return_(&rets[..]);
                 Ok(())
             }
             DropIter(reg, ty) => {
@@ -715,6 +1332,8 @@ impl<'a> View<'a> {
             }
             // Phis are handled in predecessor blocks
             Phi(..) => Ok(()),
+            // Source locations are only used by the interpreter.
+            Loc(_) => Ok(()),
         }
     }
 
@@ -724,9 +1343,8 @@ impl<'a> View<'a> {
             Null | Int => Ok(self.const_int(0)),
             Float => Ok(self.builder.ins().f64const(0.0)),
             Str => {
-                // cranelift does not currently support iconst for I128
-                let zero64 = self.builder.ins().iconst(types::I64, 0);
-                Ok(self.builder.ins().iconcat(zero64, zero64))
+                let zero = self.builder.ins().iconst(types::I64, 0);
+                Ok(self.str_slot([zero, zero]))
             }
             MapIntInt | MapIntFloat | MapIntStr | MapStrInt | MapStrFloat | MapStrStr => {
                 let alloc_fn = match ty {
@@ -744,32 +1362,52 @@ impl<'a> View<'a> {
         }
     }
 
-    fn store_string(&mut self, ss: StackSlot, v: Value) {
-        let str_ty = self.get_ty(compile::Ty::Str);
-        let ptr_ty = self.ptr_to(str_ty);
-        // We get an error if we do a direct stack_store here
-        let addr = self.builder.ins().stack_addr(ptr_ty, ss, 0);
-        self.builder.ins().store(MemFlags::trusted(), v, addr, 0);
+    /// Load the two halves of the string that `ptr` points to.
+    fn load_str(&mut self, ptr: Value) -> [Value; 2] {
+        let half = mem::size_of::<u64>() as i32;
+        let low = self
+            .builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), ptr, 0);
+        let high = self
+            .builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), ptr, half);
+        [low, high]
+    }
+
+    /// Store the two halves of a string to `ptr`.
+    fn store_str(&mut self, [low, high]: [Value; 2], ptr: Value) {
+        let half = mem::size_of::<u64>() as i32;
+        self.builder.ins().store(MemFlags::trusted(), low, ptr, 0);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), high, ptr, half);
+    }
+
+    /// Store the two halves of a string to a new stack slot, and return its address. This is how
+    /// we represent string values that are not (yet) held in a variable.
+    fn str_slot(&mut self, halves: [Value; 2]) -> Value {
+        let slot = self.stack_slot_bytes(mem::size_of::<runtime::Str>() as u32);
+        let ptr_ty = self.void_ptr_ty();
+        let addr = self.builder.ins().stack_addr(ptr_ty, slot, 0);
+        self.store_str(halves, addr);
+        addr
     }
 
     fn execute_actions(&mut self) -> Result<()> {
         let header_actions = mem::replace(&mut self.f.header_actions, Default::default());
         for EntryDeclaration { var, ty } in header_actions {
             use compile::Ty::*;
-            let cl_ty = self.get_ty(ty);
             let default_v = self.default_value(ty)?;
             match ty {
                 Null | Int | Float => {
                     self.builder.def_var(var, default_v);
                 }
                 Str => {
-                    // allocate a stack slot for the string, then assign var to point to that
-                    // slot.
-                    let ptr_ty = self.ptr_to(cl_ty);
-                    let slot = self.stack_slot_bytes(mem::size_of::<runtime::Str>() as u32);
-                    let addr = self.builder.ins().stack_addr(ptr_ty, slot, 0);
-                    self.builder.def_var(var, addr);
-                    self.store_string(slot, default_v);
+                    // The default string is in a stack slot of its own; assign var to point to
+                    // that slot.
+                    self.builder.def_var(var, default_v);
                 }
                 MapIntInt | MapIntFloat | MapIntStr | MapStrInt | MapStrFloat | MapStrStr => {
                     self.builder.def_var(var, default_v);
@@ -867,11 +1505,20 @@ impl<'a> View<'a> {
     /// registered as returning a single value.
     fn call_external(&mut self, func: *const u8, args: &[Value]) -> Value {
         let inst = self.call_inst(func, args);
-        let mut iter = self.builder.inst_results(inst).iter().cloned();
-        let ret = iter.next().expect("expected return value");
-        // For now, we expect all functions to have a single return value.
-        debug_assert!(iter.next().is_none());
-        ret
+        self.call_result(inst)
+    }
+
+    /// The value returned by the call instruction `inst`.
+    ///
+    /// Functions return a single value, other than strings, which come back in two halves. Those
+    /// are stored to a new stack slot, whose address is returned.
+    fn call_result(&mut self, inst: cranelift_codegen::ir::Inst) -> Value {
+        let rets: SmallVec<[Value; 2]> = self.builder.inst_results(inst).iter().cloned().collect();
+        match rets[..] {
+            [ret] => ret,
+            [low, high] => self.str_slot([low, high]),
+            _ => panic!("expected return value"),
+        }
     }
 
     /// Call and external function that does not return a value.
@@ -973,6 +1620,10 @@ impl<'a> View<'a> {
             ArithmeticRightShift => self.builder.ins().sshr(args[0], args[1]),
             LeftShift => self.builder.ins().ishl(args[0], args[1]),
             Xor => self.builder.ins().bxor(args[0], args[1]),
+            RotateLeft => self.builder.ins().rotl(args[0], args[1]),
+            RotateRight => self.builder.ins().rotr(args[0], args[1]),
+            PopCount => self.builder.ins().popcnt(args[0]),
+            LeadingZeros => self.builder.ins().clz(args[0]),
         }
     }
 
@@ -1081,12 +1732,14 @@ impl<'a> View<'a> {
                 }
             }
             Str => {
-                // NB: we assume that `v` is a string, not a pointer to a string.
+                // NB: `v` points to a string, which we move into the variable. We read it before
+                // dropping the current value, in case the two are the same.
+                let halves = self.load_str(v);
 
                 // first, drop the value currently in the pointer
                 let p = self.builder.use_var(var);
                 self.drop_val(Str, p);
-                self.builder.ins().store(MemFlags::trusted(), v, p, 0);
+                self.store_str(halves, p);
             }
             MapIntInt | MapIntFloat | MapIntStr | MapStrInt | MapStrFloat | MapStrStr => {
                 // first, ref the new value
@@ -1128,9 +1781,7 @@ impl<'a> View<'a> {
             Int | Float => self.bind_val_inner((dst, ty), src, skip_drop)?,
             Str => {
                 self.call_external_void(external!(ref_str), &[src]);
-                let str_ty = self.get_ty(Str);
-                let loaded = self.builder.ins().load(str_ty, MemFlags::trusted(), src, 0);
-                self.bind_val_inner((dst, Str), loaded, skip_drop)?;
+                self.bind_val_inner((dst, Str), src, skip_drop)?;
             }
             MapIntInt | MapIntFloat | MapIntStr | MapStrInt | MapStrFloat | MapStrStr => {
                 self.call_external_void(external!(ref_map), &[src]);
@@ -1145,6 +1796,16 @@ impl<'a> View<'a> {
         Ok(())
     }
 
+    fn take_str_inner(&mut self, dst: NumTy, src: NumTy, skip_drop: bool) -> Result<()> {
+        use compile::Ty::Str;
+        let src = self.get_val((src, Str))?;
+        self.bind_val_inner((dst, Str), src, skip_drop)?;
+        // Leave an empty string behind, so that dropping `src` later on is a noop.
+        let zero = self.const_int(0);
+        self.store_str([zero, zero], src);
+        Ok(())
+    }
+
     /// For a type whose size is a power of two, divide the multiply the integer Value v by that
     /// size
     fn mul_by_type_size(&mut self, ty: Type, v: Value) -> Result<Value> {
@@ -1241,15 +1902,22 @@ impl<'a> Backend for View<'a> {
         cl_sig
             .params
             .extend(sig.args.iter().cloned().map(AbiParam::new));
-        cl_sig
-            .returns
-            .extend(sig.ret.as_ref().into_iter().cloned().map(AbiParam::new));
+        match sig.ret {
+            // Strings are returned as two I64s, which is how the platform ABI returns a struct of
+            // two u64s (see `intrinsics::U128`). `call_external` stores them to a stack slot.
+            Some(types::I128) => {
+                let half = AbiParam::new(types::I64);
+                cl_sig.returns.extend_from_slice(&[half, half]);
+            }
+            Some(ty) => cl_sig.returns.push(AbiParam::new(ty)),
+            None => {}
+        }
         let id = self
             .shared
             .module
             .declare_function(name, Linkage::Import, cl_sig)
             .map_err(|e| {
-                CompileError(format!(
+                CompileError::new(format!(
                     "error declaring {} in module: {}",
                     name,
                     e.to_string()
@@ -1295,6 +1963,9 @@ impl<'a> CodeGenerator for View<'a> {
     }
 
     // backend-specific handling of constants and low-level operations.
+    fn int_overflow(&self) -> IntOverflow {
+        self.shared.int_overflow
+    }
     fn runtime_val(&mut self) -> Self::Val {
         self.builder.use_var(self.f.runtime)
     }
@@ -1305,16 +1976,44 @@ impl<'a> CodeGenerator for View<'a> {
         self.builder.ins().f64const(f)
     }
     fn const_str<'b>(&mut self, s: &UniqueStr<'b>) -> Self::Val {
-        // iconst does not support I128, so we concatenate two I64 constants.
-        let bits: u128 = s.clone_str().into_bits();
-        let low = bits as i64;
-        let high = (bits >> 64) as i64;
-        let low_v = self.builder.ins().iconst(types::I64, low);
-        let high_v = self.builder.ins().iconst(types::I64, high);
-        self.builder.ins().iconcat(low_v, high_v)
+        let (low_v, high_v) = if let Some(state) = &mut self.shared.cache {
+            let contents = s.clone_str().with_bytes(|bs| bs.to_vec());
+            let off = state.push_literal(contents);
+            (
+                self.load_const(types::I64, off),
+                self.load_const(types::I64, off + 1),
+            )
+        } else {
+            // Strings are two I64s; see the module documentation.
+            let bits: u128 = s.clone_str().into_bits();
+            let low = bits as i64;
+            let high = (bits >> 64) as i64;
+            (
+                self.builder.ins().iconst(types::I64, low),
+                self.builder.ins().iconst(types::I64, high),
+            )
+        };
+        self.str_slot([low_v, high_v])
     }
-    fn const_ptr<'b, T>(&'b mut self, c: &'b T) -> Self::Val {
-        self.const_int(c as *const _ as i64)
+    fn const_re<'b>(&'b mut self, pat: &'b Matcher) -> Self::Val {
+        if let Some(state) = &mut self.shared.cache {
+            let off = state.push_re(Constant::Regex(pat.as_str().into()), pat);
+            let ptr_ty = self.void_ptr_ty();
+            self.load_const(ptr_ty, off)
+        } else {
+            self.const_int(pat as *const _ as i64)
+        }
+    }
+
+    fn const_set<'b>(&'b mut self, set: &'b PatternSet) -> Self::Val {
+        if let Some(state) = &mut self.shared.cache {
+            let c = Constant::RegexSet(set.patterns().to_vec());
+            let off = state.push_ptr(c, set as *const PatternSet as *const u8);
+            let ptr_ty = self.void_ptr_ty();
+            self.load_const(ptr_ty, off)
+        } else {
+            self.const_int(set as *const _ as i64)
+        }
     }
 
     fn call_void(&mut self, func: *const u8, args: &mut [Self::Val]) -> Result<()> {
@@ -1443,6 +2142,10 @@ impl<'a> CodeGenerator for View<'a> {
         self.mov_inner(ty, dst, src, /*skip_drop=*/ false)
     }
 
+    fn take_str(&mut self, dst: NumTy, src: NumTy) -> Result<()> {
+        self.take_str_inner(dst, src, /*skip_drop=*/ false)
+    }
+
     fn iter_begin(&mut self, dst: Ref, map: Ref) -> Result<()> {
         use compile::Ty::*;
         let (len_fn, begin_fn) = match map.1 {
@@ -1488,8 +2191,13 @@ impl<'a> CodeGenerator for View<'a> {
         let base = self.builder.use_var(base);
         let cur_val = self.builder.use_var(cur);
         let ptr = self.builder.ins().iadd(base, cur_val);
-        let ty = self.get_ty(dst.1);
-        let contents = self.builder.ins().load(ty, MemFlags::trusted(), ptr, 0);
+        let contents = if let compile::Ty::Str = dst.1 {
+            // Strings are bound by pointer.
+            ptr
+        } else {
+            let ty = self.get_ty(dst.1);
+            self.builder.ins().load(ty, MemFlags::trusted(), ptr, 0)
+        };
 
         // Increment cur
         let type_size = self.get_ty(dst.1).lane_bits() / 8;
@@ -1509,4 +2217,55 @@ impl<'a> CodeGenerator for View<'a> {
         // They don't appear to be needed for cranelift
         Ok(())
     }
+
+    fn next_record(&mut self, dst: Ref) -> Result<()> {
+        use runtime::splitter::{ReaderState, RecordCursor};
+        let avail_off = mem::offset_of!(RecordCursor, avail) as i32;
+        let taken_off = mem::offset_of!(RecordCursor, taken) as i32;
+        let rt = self.runtime_val();
+        let ptr_ty = self.void_ptr_ty();
+        let cursor = self.builder.ins().load(
+            ptr_ty,
+            MemFlags::trusted(),
+            rt,
+            intrinsics::Runtime::cursor_offset(),
+        );
+        let avail = self
+            .builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), cursor, avail_off);
+        let take = self.builder.create_block();
+        let refill = self.builder.create_block();
+        let next = self.builder.create_block();
+        self.builder.append_block_param(next, types::I64);
+        self.builder.ins().brz(avail, refill, &[]);
+        self.builder.ins().jump(take, &[]);
+
+        // Take the next record of the batch.
+        self.builder.switch_to_block(take);
+        let one = self.const_int(1);
+        let avail = self.builder.ins().isub(avail, one);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), avail, cursor, avail_off);
+        let taken = self
+            .builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), cursor, taken_off);
+        let taken = self.builder.ins().iadd(taken, one);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), taken, cursor, taken_off);
+        let ok = self.const_int(ReaderState::OK as i64);
+        self.builder.ins().jump(next, &[ok]);
+
+        // The batch is empty: read the next record, refilling the batch.
+        self.builder.switch_to_block(refill);
+        let res = self.call_external(external!(next_line_stdin_fused), &[rt]);
+        self.builder.ins().jump(next, &[res]);
+
+        self.builder.switch_to_block(next);
+        let res = self.builder.block_params(next)[0];
+        self.bind_val(dst, res)
+    }
 }
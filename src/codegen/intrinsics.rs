@@ -11,8 +11,10 @@ use crate::runtime::{
         batch::{ByteReader, CSVReader, WhitespaceOffsets},
         chunk::{ChunkProducer, OffsetChunk},
         regex::RegexSplitter,
+        RecordCursor,
     },
-    ChainedReader, FileRead, Float, Int, IntMap, Line, LineReader, RegexCache, Str, StrMap,
+    ArgvReader, ChainedReader, FileRead, Float, Int, IntMap, Line, LineReader, RegexCache, Str,
+    StrMap,
 };
 use crate::{
     builtins::Variable,
@@ -21,10 +23,10 @@ use crate::{
     pushdown::FieldSet,
 };
 
+use crate::runtime::matcher::{Matcher, PatternSet};
 use libc::c_void;
 use paste::paste;
 use rand::{self, Rng};
-use regex::bytes::Regex;
 use smallvec;
 
 use std::convert::TryFrom;
@@ -101,22 +103,93 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly] float_to_str(float_ty) -> str_ty;
         [ReadOnly] str_to_int(str_ref_ty) -> int_ty;
         [ReadOnly] hex_str_to_int(str_ref_ty) -> int_ty;
+        [ReadOnly] is_na(str_ref_ty) -> int_ty;
+        [ReadOnly] num_or(str_ref_ty, float_ty) -> float_ty;
         [ReadOnly] str_to_float(str_ref_ty) -> float_ty;
         [ReadOnly] str_len(str_ref_ty) -> int_ty;
+        [ReadOnly] str_char_len(str_ref_ty) -> int_ty;
         concat(str_ref_ty, str_ref_ty) -> str_ty;
         [ReadOnly] match_pat(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
         [ReadOnly] match_const_pat(str_ref_ty, rt_ty) -> int_ty;
+        [ReadOnly] match_set(str_ref_ty, rt_ty) -> int_ty;
         [ReadOnly] match_pat_loc(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
         [ReadOnly] match_const_pat_loc(rt_ty, str_ref_ty, rt_ty) -> int_ty;
         [ReadOnly] substr_index(str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] levenshtein(str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] jaro_winkler(str_ref_ty, str_ref_ty) -> float_ty;
+        [ReadOnly] cmp_natural(str_ref_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] cmp_locale(str_ref_ty, str_ref_ty) -> int_ty;
         subst_first(rt_ty, str_ref_ty, str_ref_ty, str_ref_ty) -> int_ty;
         subst_all(rt_ty, str_ref_ty, str_ref_ty, str_ref_ty) -> int_ty;
         escape_csv(str_ref_ty) -> str_ty;
         escape_tsv(str_ref_ty) -> str_ty;
+        trim(str_ref_ty, str_ref_ty) -> str_ty;
+        ltrim(str_ref_ty, str_ref_ty) -> str_ty;
+        rtrim(str_ref_ty, str_ref_ty) -> str_ty;
+        squeeze(str_ref_ty) -> str_ty;
+        color(rt_ty, str_ref_ty, str_ref_ty) -> str_ty;
+        bold(str_ref_ty) -> str_ty;
+        lpad(str_ref_ty, int_ty, str_ref_ty) -> str_ty;
+        rpad(str_ref_ty, int_ty, str_ref_ty) -> str_ty;
+        zfill(str_ref_ty, int_ty) -> str_ty;
         substr(str_ref_ty, int_ty, int_ty) -> str_ty;
+        [ReadOnly] grapheme_len(str_ref_ty) -> int_ty;
+        grapheme_substr(str_ref_ty, int_ty, int_ty) -> str_ty;
+        graphemes(str_ref_ty, map_ty) -> int_ty;
+        intdiv(rt_ty, int_ty, int_ty, map_ty) -> int_ty;
+        divmod(rt_ty, int_ty, int_ty, map_ty) -> int_ty;
+        hll_add(map_ty, str_ref_ty) -> int_ty;
+        [ReadOnly] hll_count(map_ty) -> int_ty;
+        bloom_add(rt_ty, map_ty, str_ref_ty, int_ty, float_ty) -> int_ty;
+        bloom_has(rt_ty, map_ty, str_ref_ty) -> int_ty;
+        reservoir(rt_ty, map_ty, int_ty, str_ref_ty) -> int_ty;
+        win_push(rt_ty, map_ty, float_ty, int_ty) -> int_ty;
+        [ReadOnly] win_sum(map_ty) -> float_ty;
+        [ReadOnly] win_avg(map_ty) -> float_ty;
+        [ReadOnly] win_max(map_ty) -> float_ty;
+        hist_add(rt_ty, map_ty, float_ty, float_ty, int_ty) -> int_ty;
+        [ReadOnly] hist_format(map_ty) -> str_ty;
+        [ReadOnly] vec_dot(map_ty, map_ty) -> float_ty;
+        [ReadOnly] vec_norm(map_ty) -> float_ty;
+        vec_add(map_ty, map_ty, map_ty) -> int_ty;
+        vec_sub(map_ty, map_ty, map_ty) -> int_ty;
+        vec_mul(map_ty, map_ty, map_ty) -> int_ty;
+        vec_scale(map_ty, map_ty, float_ty) -> int_ty;
+        readable(rt_ty, map_ty, int_ty) -> int_ty;
+        set_timeout(rt_ty, str_ref_ty, int_ty) -> int_ty;
+        spawn(rt_ty, str_ref_ty) -> int_ty;
+        read_line(rt_ty, int_ty, int_ty) -> str_ty;
+        write_line(rt_ty, int_ty, str_ref_ty) -> int_ty;
+        wait(rt_ty, int_ty) -> int_ty;
+        read_cmd(str_ref_ty) -> str_ty;
+        [ReadOnly] getenv(str_ref_ty, str_ref_ty) -> str_ty;
+        setenv(rt_ty, str_ref_ty, str_ref_ty) -> int_ty;
+        stat(str_ref_ty, map_ty) -> int_ty;
+        glob(str_ref_ty, map_ty) -> int_ty;
+        mkdirp(str_ref_ty) -> int_ty;
+        rename(str_ref_ty, str_ref_ty) -> int_ty;
+        rmfile(str_ref_ty) -> int_ty;
+        sleep(float_ty) -> int_ty;
+        monotime() -> float_ty;
+        caught_signal() -> int_ty;
+        set_exit(int_ty) -> int_ty;
+        set_matched() -> int_ty;
+        exiting() -> int_ty;
+        set_exiting(int_ty) -> int_ty;
+        set_atexit_global(rt_ty, int_ty, rt_ty);
+        [ReadOnly] atexit_global(rt_ty, int_ty) -> rt_ty;
+        [ReadOnly] mod_int(rt_ty, int_ty, int_ty) -> int_ty;
+        [ReadOnly] add_int_checked(rt_ty, int_ty, int_ty) -> int_ty;
+        [ReadOnly] minus_int_checked(rt_ty, int_ty, int_ty) -> int_ty;
+        [ReadOnly] mul_int_checked(rt_ty, int_ty, int_ty) -> int_ty;
+        [ReadOnly] neg_int_checked(rt_ty, int_ty) -> int_ty;
+        [ReadOnly] mod_float(rt_ty, float_ty, float_ty) -> float_ty;
+        [ReadOnly] div_float(rt_ty, float_ty, float_ty) -> float_ty;
         [ReadOnly] get_col(rt_ty, int_ty) -> str_ty;
         [ReadOnly] join_csv(rt_ty, int_ty, int_ty) -> str_ty;
         [ReadOnly] join_tsv(rt_ty, int_ty, int_ty) -> str_ty;
+        [ReadOnly] sum_cols(rt_ty, int_ty, int_ty) -> float_ty;
+        [ReadOnly] mean_cols(rt_ty, int_ty, int_ty) -> float_ty;
         [ReadOnly] join_cols(rt_ty, int_ty, int_ty, str_ref_ty) -> str_ty;
         set_col(rt_ty, int_ty, str_ref_ty);
         split_int(rt_ty, str_ref_ty, map_ty, str_ref_ty) -> int_ty;
@@ -136,7 +209,12 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         read_err_stdin(rt_ty) -> int_ty;
         next_line(rt_ty, str_ref_ty, int_ty) -> str_ty;
         next_line_stdin(rt_ty) -> str_ty;
-        next_line_stdin_fused(rt_ty);
+        next_line_stdin_fused(rt_ty) -> int_ty;
+        record_file(rt_ty) -> str_ty;
+        record_offset(rt_ty) -> int_ty;
+        record_text(rt_ty) -> str_ty;
+        argv_assign(rt_ty) -> str_ty;
+        round_trip_sep(rt_ty, str_ref_ty, str_ref_ty) -> str_ty;
         next_file(rt_ty);
         update_used_fields(rt_ty);
         set_fi_entry(rt_ty, int_ty, int_ty);
@@ -158,6 +236,7 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly, ArgmemOnly] _frawk_exp(float_ty) -> float_ty;
         [ReadOnly, ArgmemOnly] _frawk_atan2(float_ty, float_ty) -> float_ty;
 
+
         load_var_str(rt_ty, int_ty) -> str_ty;
         store_var_str(rt_ty, int_ty, str_ref_ty);
         [ReadOnly] load_var_int(rt_ty, int_ty) -> int_ty;
@@ -183,6 +262,9 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly] contains_intint(map_ty, int_ty) -> int_ty;
         insert_intint(map_ty, int_ty, int_ty);
         delete_intint(map_ty, int_ty);
+        bound_intint(rt_ty, map_ty, int_ty, str_ref_ty) -> int_ty;
+        load_state_intint(rt_ty, map_ty, str_ref_ty) -> int_ty;
+        save_state_intint(rt_ty, map_ty, str_ref_ty) -> int_ty;
         drop_intint(map_ty);
 
         alloc_intfloat() -> map_ty;
@@ -192,6 +274,9 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly] contains_intfloat(map_ty, int_ty) -> int_ty;
         insert_intfloat(map_ty, int_ty, float_ty);
         delete_intfloat(map_ty, int_ty);
+        bound_intfloat(rt_ty, map_ty, int_ty, str_ref_ty) -> int_ty;
+        load_state_intfloat(rt_ty, map_ty, str_ref_ty) -> int_ty;
+        save_state_intfloat(rt_ty, map_ty, str_ref_ty) -> int_ty;
         drop_intfloat(map_ty);
 
         alloc_intstr() -> map_ty;
@@ -201,6 +286,9 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly] contains_intstr(map_ty, int_ty) -> int_ty;
         insert_intstr(map_ty, int_ty, str_ref_ty);
         delete_intstr(map_ty, int_ty);
+        bound_intstr(rt_ty, map_ty, int_ty, str_ref_ty) -> int_ty;
+        load_state_intstr(rt_ty, map_ty, str_ref_ty) -> int_ty;
+        save_state_intstr(rt_ty, map_ty, str_ref_ty) -> int_ty;
         drop_intstr(map_ty);
 
         alloc_strint() -> map_ty;
@@ -210,6 +298,9 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly] contains_strint(map_ty, str_ref_ty) -> int_ty;
         insert_strint(map_ty, str_ref_ty, int_ty);
         delete_strint(map_ty, str_ref_ty);
+        bound_strint(rt_ty, map_ty, int_ty, str_ref_ty) -> int_ty;
+        load_state_strint(rt_ty, map_ty, str_ref_ty) -> int_ty;
+        save_state_strint(rt_ty, map_ty, str_ref_ty) -> int_ty;
         drop_strint(map_ty);
 
         alloc_strfloat() -> map_ty;
@@ -219,6 +310,9 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly] contains_strfloat(map_ty, str_ref_ty) -> int_ty;
         insert_strfloat(map_ty, str_ref_ty, float_ty);
         delete_strfloat(map_ty, str_ref_ty);
+        bound_strfloat(rt_ty, map_ty, int_ty, str_ref_ty) -> int_ty;
+        load_state_strfloat(rt_ty, map_ty, str_ref_ty) -> int_ty;
+        save_state_strfloat(rt_ty, map_ty, str_ref_ty) -> int_ty;
         drop_strfloat(map_ty);
 
         alloc_strstr() -> map_ty;
@@ -228,6 +322,9 @@ pub(crate) fn register_all(cg: &mut impl Backend) -> Result<()> {
         [ReadOnly] contains_strstr(map_ty, str_ref_ty) -> int_ty;
         insert_strstr(map_ty, str_ref_ty, str_ref_ty);
         delete_strstr(map_ty, str_ref_ty);
+        bound_strstr(rt_ty, map_ty, int_ty, str_ref_ty) -> int_ty;
+        load_state_strstr(rt_ty, map_ty, str_ref_ty) -> int_ty;
+        save_state_strstr(rt_ty, map_ty, str_ref_ty) -> int_ty;
         drop_strstr(map_ty);
 
         load_slot_int(rt_ty, int_ty) -> int_ty;
@@ -257,11 +354,13 @@ macro_rules! fail {
     ($rt:expr, $($es:expr),+) => {{
         #[cfg(test)]
         {
+            let _ = &$rt;
             panic!("failure in runtime {}. Halting execution", format!($($es),*))
         }
         #[cfg(not(test))]
         {
             eprintln_ignore!("failure in runtime {}. Halting execution", format!($($es),*));
+            runtime::stats::set_error(format!($($es),*));
             exit!($rt, 1, format!($($es),*))
         }
     }}
@@ -279,6 +378,23 @@ macro_rules! try_abort {
     };
 }
 
+/// Like try_abort, but for writes to stdout: if they failed because stdout was closed (e.g. frawk
+/// was piped into `head`), exit quietly. Worker threads instead carry on until they notice that
+/// there is no more input, leaving the main thread to exit.
+macro_rules! try_write_stdout {
+    ($rt:expr, $e:expr) => {
+        match $e {
+            Ok(res) => res,
+            Err(_) if crate::runtime::writers::stdout_closed() => {
+                if !(*($rt as *mut Runtime)).concurrent {
+                    exit!($rt)
+                }
+            }
+            Err(e) => fail!($rt, "{}", e),
+        }
+    };
+}
+
 macro_rules! exit {
     ($runtime:expr) => {
         exit!($runtime, 0, "")
@@ -290,21 +406,33 @@ macro_rules! exit {
             // Use panic to allow 'graceful' shutdown of other worker threads.
             panic!($msg)
         } else {
+            (*rt).run_atexit();
             std::ptr::drop_in_place(rt);
+            runtime::progress::finish();
+            runtime::stats::report();
             std::process::exit($code)
         }
     }};
 }
 
+// Before running `body`, this catches the input up with any records compiled code has taken from
+// the current batch; see `RecordCursor`.
 macro_rules! with_input {
     ($inp:expr, |$p:pat| $body:expr) => {
         match $inp {
-            $crate::codegen::intrinsics::InputData::V1($p) => $body,
-            $crate::codegen::intrinsics::InputData::V2($p) => $body,
-            $crate::codegen::intrinsics::InputData::V3($p) => $body,
-            $crate::codegen::intrinsics::InputData::V4($p) => $body,
+            $crate::codegen::intrinsics::InputData::V1(inp) => with_input!(@synced inp, $p, $body),
+            $crate::codegen::intrinsics::InputData::V2(inp) => with_input!(@synced inp, $p, $body),
+            $crate::codegen::intrinsics::InputData::V3(inp) => with_input!(@synced inp, $p, $body),
+            $crate::codegen::intrinsics::InputData::V4(inp) => with_input!(@synced inp, $p, $body),
+            $crate::codegen::intrinsics::InputData::V5(inp) => with_input!(@synced inp, $p, $body),
         }
     };
+    (@synced $inp:ident, $p:pat, $body:expr) => {{
+        let (line, read_files) = $inp;
+        read_files.take_records(line);
+        let $p = (line, read_files);
+        $body
+    }};
 }
 
 pub(crate) type InputTuple<LR> = (<LR as LineReader>::Line, FileRead<LR>);
@@ -313,6 +441,7 @@ pub(crate) enum InputData {
     V2(InputTuple<ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk<WhitespaceOffsets>>>>>),
     V3(InputTuple<ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>>>),
     V4(InputTuple<ChainedReader<RegexSplitter<Box<dyn io::Read + Send>>>>),
+    V5(InputTuple<ArgvReader>),
 }
 
 pub(crate) trait IntoRuntime {
@@ -333,14 +462,13 @@ macro_rules! impl_into_runtime {
                 used_fields: &FieldSet,
                 named_columns: Option<Vec<&[u8]>>,
             ) -> Runtime<'a> {
-                Runtime {
-                    concurrent: false,
-                    input_data: InputData::$var((
+                Runtime::new(
+                    crate::interp::Core::new(ff),
+                    InputData::$var((
                         Default::default(),
                         FileRead::new(self, used_fields.clone(), named_columns),
                     )),
-                    core: crate::interp::Core::new(ff),
-                }
+                )
             }
         }
 
@@ -349,6 +477,12 @@ macro_rules! impl_into_runtime {
                 InputData::$var((Default::default(), v))
             }
         }
+
+        impl From<InputTuple<$ty>> for InputData {
+            fn from(v: InputTuple<$ty>) -> InputData {
+                InputData::$var(v)
+            }
+        }
     };
 }
 
@@ -359,21 +493,68 @@ impl_into_runtime!(
 );
 impl_into_runtime!(ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>>, V3);
 impl_into_runtime!(ChainedReader<RegexSplitter<Box<dyn io::Read + Send>>>, V4);
+impl_into_runtime!(ArgvReader, V5);
 
 pub(crate) struct Runtime<'a> {
     pub(crate) core: crate::interp::Core<'a>,
     pub(crate) input_data: InputData,
+    // The cursor of the main input's record batch, which the main loop reads and updates without
+    // calling into the runtime. See `RecordCursor`.
+    cursor: *mut RecordCursor,
     #[allow(unused)]
     pub(crate) concurrent: bool,
+    // The main function that calls the functions registered with `atexit`, or null, and the
+    // addresses of the global variables it uses, which the main function running on this runtime
+    // registers while it runs. See `run_atexit`.
+    pub(crate) atexit: *const u8,
+    atexit_globals: Vec<*mut c_void>,
 }
 
 impl<'a> Runtime<'a> {
+    pub(crate) fn new(core: crate::interp::Core<'a>, mut input_data: InputData) -> Runtime<'a> {
+        let cursor = with_input!(&mut input_data, |(_, read_files)| read_files
+            .record_cursor());
+        Runtime {
+            core,
+            input_data,
+            cursor,
+            concurrent: false,
+            atexit: std::ptr::null(),
+            atexit_globals: Vec::new(),
+        }
+    }
+
+    /// Call the functions registered with `atexit`, for when the program stops early because it
+    /// failed or because stdout was closed. This does nothing if no main function is running with
+    /// the global variables they use, and it only does anything the first time it is called.
+    unsafe fn run_atexit(&mut self) {
+        let atexit = mem::replace(&mut self.atexit, std::ptr::null());
+        if atexit.is_null() || self.atexit_globals.iter().any(|g| g.is_null()) {
+            return;
+        }
+        mem::transmute::<*const u8, unsafe extern "C" fn(*mut Runtime<'a>)>(atexit)(self)
+    }
+
+    /// The offset of the pointer to the record cursor in a `Runtime`, for compiled code to load it
+    /// from.
+    pub(crate) fn cursor_offset() -> i32 {
+        std::mem::offset_of!(Runtime<'static>, cursor) as i32
+    }
+
     fn reset_file_vars(&mut self) {
+        runtime::stats::file_done(&self.core.vars.filename, self.core.vars.fnr);
         self.core.vars.fnr = 0;
         self.core.vars.filename = with_input!(&mut self.input_data, |(_, read_files)| {
             read_files.stdin_filename().upcast()
         });
     }
+
+    fn edit_in_place(&mut self) -> Result<()> {
+        let input = with_input!(&mut self.input_data, |(_, read_files)| {
+            read_files.main_input_file()
+        });
+        self.core.write_files.edit_in_place(input)
+    }
 }
 
 pub(crate) unsafe extern "C" fn run_system(cmd: *mut U128) -> Int {
@@ -419,11 +600,17 @@ pub(crate) unsafe extern "C" fn read_err(
 
 pub(crate) unsafe extern "C" fn read_err_stdin(runtime: *mut c_void) -> Int {
     let runtime = &mut *(runtime as *mut Runtime);
+    if crate::runtime::writers::skip_end() && !runtime.concurrent {
+        // Stdout has been closed: stop without running END.
+        exit!(runtime)
+    }
     with_input!(&mut runtime.input_data, |(_, read_files)| read_files
         .read_err_stdin())
 }
 
-pub(crate) unsafe extern "C" fn next_line_stdin_fused(runtime: *mut c_void) {
+// Reads the next record of the main input into $0, and returns what read_err_stdin would return
+// afterwards. Compiled code only calls this once it has taken every record of the current batch.
+pub(crate) unsafe extern "C" fn next_line_stdin_fused(runtime: *mut c_void) -> Int {
     let runtime = &mut *(runtime as *mut Runtime);
     let changed = try_abort!(
         runtime,
@@ -431,13 +618,21 @@ pub(crate) unsafe extern "C" fn next_line_stdin_fused(runtime: *mut c_void) {
             runtime
                 .core
                 .regexes
-                .get_line_stdin_reuse(&runtime.core.vars.rs, read_files, line)
+                .get_line_stdin_reuse(&runtime.core.vars, read_files, line)
         }),
         "unexpected error when reading line from stdin:"
     );
     if changed {
         runtime.reset_file_vars();
     }
+    if runtime::inplace::enabled() {
+        try_abort!(
+            runtime,
+            runtime.edit_in_place(),
+            "failed to edit input file in place:"
+        );
+    }
+    read_err_stdin(runtime as *mut Runtime as *mut c_void)
 }
 
 pub(crate) unsafe extern "C" fn next_file(runtime: *mut c_void) {
@@ -458,13 +653,60 @@ pub(crate) unsafe extern "C" fn next_line_stdin(runtime: *mut c_void) -> U128 {
             runtime
                 .core
                 .regexes
-                .get_line_stdin(&runtime.core.vars.rs, read_files)
+                .get_line_stdin(&runtime.core.vars, read_files)
         }),
         "unexpected error when reading line from stdin:"
     );
     if changed {
         runtime.reset_file_vars();
     }
+    if runtime::inplace::enabled() {
+        try_abort!(
+            runtime,
+            runtime.edit_in_place(),
+            "failed to edit input file in place:"
+        );
+    }
+    mem::transmute::<Str, U128>(res)
+}
+
+pub(crate) unsafe extern "C" fn record_file(runtime: *mut c_void) -> U128 {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let res = with_input!(&mut runtime.input_data, |(_, read_files)| read_files
+        .record_file());
+    mem::transmute::<Str, U128>(res)
+}
+
+pub(crate) unsafe extern "C" fn record_offset(runtime: *mut c_void) -> Int {
+    let runtime = &mut *(runtime as *mut Runtime);
+    with_input!(&mut runtime.input_data, |(_, read_files)| read_files
+        .record_offset())
+}
+
+pub(crate) unsafe extern "C" fn record_text(runtime: *mut c_void) -> U128 {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let res = with_input!(&mut runtime.input_data, |(_, read_files)| read_files
+        .record_text());
+    mem::transmute::<Str, U128>(res)
+}
+
+pub(crate) unsafe extern "C" fn argv_assign(runtime: *mut c_void) -> U128 {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let res = with_input!(&mut runtime.input_data, |(_, read_files)| read_files
+        .argv_assign());
+    mem::transmute::<Str, U128>(res)
+}
+
+pub(crate) unsafe extern "C" fn round_trip_sep(
+    runtime: *mut c_void,
+    record: *mut U128,
+    ors: *mut U128,
+) -> U128 {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let record = &*(record as *mut Str);
+    let ors = &*(ors as *mut Str);
+    let res = with_input!(&mut runtime.input_data, |(_, read_files)| read_files
+        .round_trip_sep(record, ors));
     mem::transmute::<Str, U128>(res)
 }
 
@@ -475,6 +717,9 @@ pub(crate) unsafe extern "C" fn next_line(
 ) -> U128 {
     let runtime = &mut *(runtime as *mut Runtime);
     let file = &*(file as *mut Str);
+    if is_file != 0 && runtime::may_prompt(file) {
+        try_abort!(runtime, runtime.core.write_files.flush_stdout());
+    }
     let res = with_input!(&mut runtime.input_data, |(_, read_files)| {
         runtime
             .core
@@ -487,6 +732,158 @@ pub(crate) unsafe extern "C" fn next_line(
     }
 }
 
+pub(crate) unsafe extern "C" fn readable(
+    runtime: *mut c_void,
+    inputs: *mut c_void,
+    timeout: Int,
+) -> Int {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let inputs = mem::transmute::<*mut c_void, StrMap<Int>>(inputs);
+    let res = with_input!(&mut runtime.input_data, |(_, read_files)| {
+        runtime
+            .core
+            .regexes
+            .readable(&inputs, timeout, &runtime.core.vars.rs, read_files)
+    });
+    mem::forget(inputs);
+    try_abort!(runtime, res)
+}
+
+pub(crate) unsafe extern "C" fn set_timeout(
+    runtime: *mut c_void,
+    input: *mut U128,
+    ms: Int,
+) -> Int {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let input = &*(input as *mut Str);
+    with_input!(&mut runtime.input_data, |(_, read_files)| {
+        read_files.inputs.set_timeout(input, ms)
+    })
+}
+
+pub(crate) unsafe extern "C" fn spawn(runtime: *mut c_void, cmd: *mut U128) -> Int {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let cmd = &*(cmd as *mut Str);
+    runtime.core.procs.spawn(cmd)
+}
+
+pub(crate) unsafe extern "C" fn read_line(runtime: *mut c_void, h: Int, stream: Int) -> U128 {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let res = runtime.core.procs.read_line(h, stream);
+    mem::transmute::<Str, U128>(try_abort!(runtime, res))
+}
+
+pub(crate) unsafe extern "C" fn write_line(runtime: *mut c_void, h: Int, s: *mut U128) -> Int {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let s = &*(s as *mut Str);
+    let res = runtime.core.procs.write_line(h, s);
+    try_abort!(runtime, res)
+}
+
+pub(crate) unsafe extern "C" fn wait(runtime: *mut c_void, h: Int) -> Int {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let res = runtime.core.procs.wait(h);
+    try_abort!(runtime, res)
+}
+
+pub(crate) unsafe extern "C" fn getenv(name: *mut U128, default: *mut U128) -> U128 {
+    let name = &*(name as *mut Str);
+    let default = &*(default as *mut Str);
+    mem::transmute::<Str, U128>(runtime::os::getenv(name, default))
+}
+
+pub(crate) unsafe extern "C" fn setenv(
+    runtime: *mut c_void,
+    name: *mut U128,
+    val: *mut U128,
+) -> Int {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let name = &*(name as *mut Str);
+    let val = &*(val as *mut Str);
+    try_abort!(runtime, runtime::os::setenv(name, val))
+}
+
+pub(crate) unsafe extern "C" fn stat(path: *mut U128, arr: *mut c_void) -> Int {
+    let path = &*(path as *mut Str);
+    let arr = mem::transmute::<*mut c_void, StrMap<Str>>(arr);
+    let res = runtime::os::stat(path, &arr);
+    mem::forget(arr);
+    res
+}
+
+pub(crate) unsafe extern "C" fn glob(pattern: *mut U128, arr: *mut c_void) -> Int {
+    let pattern = &*(pattern as *mut Str);
+    let arr = mem::transmute::<*mut c_void, IntMap<Str>>(arr);
+    let res = runtime::os::glob(pattern, &arr);
+    mem::forget(arr);
+    res
+}
+
+pub(crate) unsafe extern "C" fn mkdirp(path: *mut U128) -> Int {
+    runtime::os::mkdirp(&*(path as *mut Str))
+}
+
+pub(crate) unsafe extern "C" fn rename(from: *mut U128, to: *mut U128) -> Int {
+    runtime::os::rename(&*(from as *mut Str), &*(to as *mut Str))
+}
+
+pub(crate) unsafe extern "C" fn rmfile(path: *mut U128) -> Int {
+    runtime::os::rmfile(&*(path as *mut Str))
+}
+
+pub(crate) extern "C" fn sleep(secs: Float) -> Int {
+    runtime::os::sleep(secs)
+}
+
+pub(crate) extern "C" fn monotime() -> Float {
+    runtime::os::monotime()
+}
+
+pub(crate) extern "C" fn caught_signal() -> Int {
+    runtime::signal::caught()
+}
+
+pub(crate) extern "C" fn set_exit(status: Int) -> Int {
+    runtime::exit::set_status(status)
+}
+
+pub(crate) extern "C" fn set_matched() -> Int {
+    runtime::exit::set_matched();
+    1
+}
+
+pub(crate) extern "C" fn exiting() -> Int {
+    runtime::exit::exiting()
+}
+
+pub(crate) extern "C" fn set_exiting(n: Int) -> Int {
+    runtime::exit::set_exiting(n)
+}
+
+pub(crate) unsafe extern "C" fn set_atexit_global(
+    runtime: *mut c_void,
+    ix: Int,
+    addr: *mut c_void,
+) {
+    let runtime = &mut *(runtime as *mut Runtime);
+    let ix = ix as usize;
+    if runtime.atexit_globals.len() <= ix {
+        runtime.atexit_globals.resize(ix + 1, std::ptr::null_mut());
+    }
+    runtime.atexit_globals[ix] = addr;
+}
+
+pub(crate) unsafe extern "C" fn atexit_global(runtime: *mut c_void, ix: Int) -> *mut c_void {
+    let runtime = &*(runtime as *mut Runtime);
+    runtime.atexit_globals[ix as usize]
+}
+
+pub(crate) unsafe extern "C" fn read_cmd(cmd: *mut U128) -> U128 {
+    let cmd = &*(cmd as *mut Str);
+    let out = cmd.with_bytes(runtime::command::read_command);
+    mem::transmute::<Str, U128>(Str::from(&out[..]).unmoor())
+}
+
 pub(crate) unsafe extern "C" fn update_used_fields(runtime: *mut c_void) {
     let runtime = &mut *(runtime as *mut Runtime);
     let fi = &runtime.core.vars.fi;
@@ -599,6 +996,26 @@ pub(crate) unsafe extern "C" fn join_tsv(runtime: *mut c_void, start: Int, end:
     mem::transmute::<Str, U128>(res)
 }
 
+pub(crate) unsafe extern "C" fn sum_cols(runtime: *mut c_void, start: Int, end: Int) -> Float {
+    let runtime = &mut *(runtime as *mut Runtime);
+    try_abort!(
+        runtime,
+        with_input!(&mut runtime.input_data, |(line, _)| {
+            let nf = try_abort!(
+                runtime,
+                line.nf(&runtime.core.vars.fs, &mut runtime.core.regexes),
+                "nf:"
+            );
+            line.sum_cols(start, end, nf)
+        }),
+        "fsum:"
+    )
+}
+
+pub(crate) unsafe extern "C" fn mean_cols(runtime: *mut c_void, start: Int, end: Int) -> Float {
+    runtime::splitter::mean_cols(sum_cols(runtime, start, end), start, end)
+}
+
 pub(crate) unsafe extern "C" fn join_cols(
     runtime: *mut c_void,
     start: Int,
@@ -641,6 +1058,11 @@ pub(crate) unsafe extern "C" fn str_len(s: *mut c_void) -> usize {
     res
 }
 
+pub(crate) unsafe extern "C" fn str_char_len(s: *mut c_void) -> Int {
+    let s = &*(s as *mut Str);
+    runtime::chars::len(s)
+}
+
 pub(crate) unsafe extern "C" fn concat(s1: *mut c_void, s2: *mut c_void) -> U128 {
     let s1 = &*(s1 as *mut Str);
     let s2 = &*(s2 as *mut Str);
@@ -667,10 +1089,16 @@ pub(crate) unsafe extern "C" fn match_pat(
 
 pub(crate) unsafe extern "C" fn match_const_pat(s: *mut c_void, pat: *mut c_void) -> Int {
     let s = &*(s as *mut Str);
-    let pat = &*(pat as *const Regex);
+    let pat = &*(pat as *const Matcher);
     RegexCache::regex_const_match(pat, s) as Int
 }
 
+pub(crate) unsafe extern "C" fn match_set(s: *mut c_void, set: *mut c_void) -> Int {
+    let s = &*(s as *mut Str);
+    let set = &*(set as *const PatternSet);
+    s.with_bytes(|bs| set.matches(bs))
+}
+
 pub(crate) unsafe extern "C" fn match_pat_loc(
     runtime: *mut c_void,
     s: *mut c_void,
@@ -695,7 +1123,7 @@ pub(crate) unsafe extern "C" fn match_const_pat_loc(
 ) -> Int {
     let runtime = runtime as *mut Runtime;
     let s = &*(s as *mut Str);
-    let pat = &*(pat as *const Regex);
+    let pat = &*(pat as *const Matcher);
     try_abort!(
         runtime,
         (*runtime).core.match_const_regex(s, pat),
@@ -706,7 +1134,31 @@ pub(crate) unsafe extern "C" fn match_const_pat_loc(
 pub(crate) unsafe extern "C" fn substr_index(s: *mut U128, t: *mut U128) -> Int {
     let s = &*(s as *mut Str);
     let t = &*(t as *mut Str);
-    runtime::string_search::index_substr(/*needle*/ t, /*haystack*/ s)
+    runtime::chars::index(/*haystack*/ s, /*needle*/ t)
+}
+
+pub(crate) unsafe extern "C" fn levenshtein(a: *mut U128, b: *mut U128) -> Int {
+    let a = &*(a as *mut Str);
+    let b = &*(b as *mut Str);
+    runtime::similarity::levenshtein(a, b)
+}
+
+pub(crate) unsafe extern "C" fn jaro_winkler(a: *mut U128, b: *mut U128) -> Float {
+    let a = &*(a as *mut Str);
+    let b = &*(b as *mut Str);
+    runtime::similarity::jaro_winkler(a, b)
+}
+
+pub(crate) unsafe extern "C" fn cmp_natural(a: *mut U128, b: *mut U128) -> Int {
+    let a = &*(a as *mut Str);
+    let b = &*(b as *mut Str);
+    runtime::collate::cmp_natural(a, b)
+}
+
+pub(crate) unsafe extern "C" fn cmp_locale(a: *mut U128, b: *mut U128) -> Int {
+    let a = &*(a as *mut Str);
+    let b = &*(b as *mut Str);
+    runtime::collate::cmp_locale(a, b)
 }
 
 pub(crate) unsafe extern "C" fn subst_first(
@@ -724,7 +1176,7 @@ pub(crate) unsafe extern "C" fn subst_first(
         runtime
             .core
             .regexes
-            .with_regex(pat, |re| in_s.subst_first(re, s))
+            .with_matcher(pat, |re| in_s.subst_first(re, s))
     );
     *in_s = subbed;
     new as Int
@@ -745,7 +1197,7 @@ pub(crate) unsafe extern "C" fn subst_all(
         runtime
             .core
             .regexes
-            .with_regex(pat, |re| in_s.subst_all(re, s))
+            .with_matcher(pat, |re| in_s.subst_all(re, s))
     );
     *in_s = subbed;
     nsubs
@@ -759,13 +1211,276 @@ pub(crate) unsafe extern "C" fn escape_tsv(s: *mut U128) -> U128 {
     mem::transmute::<Str, U128>(runtime::escape_tsv(&*(s as *mut Str)))
 }
 
+pub(crate) unsafe extern "C" fn trim(s: *mut U128, set: *mut U128) -> U128 {
+    let s = &*(s as *mut Str);
+    let set = &*(set as *mut Str);
+    mem::transmute::<Str, U128>(runtime::trim::trim(s, set, true, true))
+}
+
+pub(crate) unsafe extern "C" fn ltrim(s: *mut U128, set: *mut U128) -> U128 {
+    let s = &*(s as *mut Str);
+    let set = &*(set as *mut Str);
+    mem::transmute::<Str, U128>(runtime::trim::trim(s, set, true, false))
+}
+
+pub(crate) unsafe extern "C" fn rtrim(s: *mut U128, set: *mut U128) -> U128 {
+    let s = &*(s as *mut Str);
+    let set = &*(set as *mut Str);
+    mem::transmute::<Str, U128>(runtime::trim::trim(s, set, false, true))
+}
+
+pub(crate) unsafe extern "C" fn squeeze(s: *mut U128) -> U128 {
+    mem::transmute::<Str, U128>(runtime::trim::squeeze(&*(s as *mut Str)))
+}
+
+pub(crate) unsafe extern "C" fn color(runtime: *mut c_void, s: *mut U128, name: *mut U128) -> U128 {
+    let s = &*(s as *mut Str);
+    let name = &*(name as *mut Str);
+    let res = try_abort!(runtime, runtime::color::color(s, name));
+    mem::transmute::<Str, U128>(res)
+}
+
+pub(crate) unsafe extern "C" fn bold(s: *mut U128) -> U128 {
+    mem::transmute::<Str, U128>(runtime::color::bold(&*(s as *mut Str)))
+}
+
+pub(crate) unsafe extern "C" fn lpad(s: *mut U128, width: Int, with: *mut U128) -> U128 {
+    let s = &*(s as *mut Str);
+    let with = &*(with as *mut Str);
+    mem::transmute::<Str, U128>(runtime::pad::lpad(s, width, with))
+}
+
+pub(crate) unsafe extern "C" fn rpad(s: *mut U128, width: Int, with: *mut U128) -> U128 {
+    let s = &*(s as *mut Str);
+    let with = &*(with as *mut Str);
+    mem::transmute::<Str, U128>(runtime::pad::rpad(s, width, with))
+}
+
+pub(crate) unsafe extern "C" fn zfill(s: *mut U128, width: Int) -> U128 {
+    mem::transmute::<Str, U128>(runtime::pad::zfill(&*(s as *mut Str), width))
+}
+
 pub(crate) unsafe extern "C" fn substr(base: *mut U128, l: Int, r: Int) -> U128 {
-    use std::cmp::{max, min};
     let base = &*(base as *mut Str);
-    let len = base.len();
-    let l = max(0, l - 1);
-    let r = min(len as Int, l.saturating_add(r)) as usize;
-    mem::transmute::<Str, U128>(base.slice(l as usize, r))
+    mem::transmute::<Str, U128>(runtime::chars::substr(base, l, r))
+}
+
+pub(crate) unsafe extern "C" fn grapheme_len(s: *mut c_void) -> Int {
+    let s = &*(s as *mut Str);
+    runtime::chars::grapheme_len(s)
+}
+
+pub(crate) unsafe extern "C" fn grapheme_substr(base: *mut U128, l: Int, r: Int) -> U128 {
+    let base = &*(base as *mut Str);
+    mem::transmute::<Str, U128>(runtime::chars::grapheme_substr(base, l, r))
+}
+
+pub(crate) unsafe extern "C" fn graphemes(s: *mut c_void, into_arr: *mut c_void) -> Int {
+    let into_arr = mem::transmute::<*mut c_void, IntMap<Str>>(into_arr);
+    let s = &*(s as *mut Str);
+    let res = runtime::chars::graphemes(s, &into_arr);
+    mem::forget(into_arr);
+    res
+}
+
+pub(crate) unsafe extern "C" fn intdiv(
+    runtime: *mut c_void,
+    num: Int,
+    den: Int,
+    into_arr: *mut c_void,
+) -> Int {
+    let into_arr = mem::transmute::<*mut c_void, StrMap<Int>>(into_arr);
+    let res = runtime::arith::intdiv(num, den, &into_arr);
+    mem::forget(into_arr);
+    try_abort!(runtime, res)
+}
+
+pub(crate) unsafe extern "C" fn divmod(
+    runtime: *mut c_void,
+    num: Int,
+    den: Int,
+    into_arr: *mut c_void,
+) -> Int {
+    let into_arr = mem::transmute::<*mut c_void, IntMap<Int>>(into_arr);
+    let res = runtime::arith::divmod(num, den, &into_arr);
+    mem::forget(into_arr);
+    try_abort!(runtime, res)
+}
+
+pub(crate) unsafe extern "C" fn hll_add(h: *mut c_void, x: *mut c_void) -> Int {
+    let h = mem::transmute::<*mut c_void, IntMap<Int>>(h);
+    let res = runtime::sketch::hll_add(&h, &*(x as *mut Str));
+    mem::forget(h);
+    res
+}
+
+pub(crate) unsafe extern "C" fn hll_count(h: *mut c_void) -> Int {
+    let h = mem::transmute::<*mut c_void, IntMap<Int>>(h);
+    let res = runtime::sketch::hll_count(&h);
+    mem::forget(h);
+    res
+}
+
+pub(crate) unsafe extern "C" fn bloom_add(
+    runtime: *mut c_void,
+    b: *mut c_void,
+    x: *mut c_void,
+    n: Int,
+    p: Float,
+) -> Int {
+    let b = mem::transmute::<*mut c_void, IntMap<Int>>(b);
+    let res = runtime::sketch::bloom_add(&b, &*(x as *mut Str), n, p);
+    mem::forget(b);
+    try_abort!(runtime, res)
+}
+
+pub(crate) unsafe extern "C" fn bloom_has(
+    runtime: *mut c_void,
+    b: *mut c_void,
+    x: *mut c_void,
+) -> Int {
+    let b = mem::transmute::<*mut c_void, IntMap<Int>>(b);
+    let res = runtime::sketch::bloom_has(&b, &*(x as *mut Str));
+    mem::forget(b);
+    try_abort!(runtime, res)
+}
+
+pub(crate) unsafe extern "C" fn reservoir(
+    runtime: *mut c_void,
+    arr: *mut c_void,
+    k: Int,
+    x: *mut c_void,
+) -> Int {
+    let arr = mem::transmute::<*mut c_void, IntMap<Str>>(arr);
+    let rng = &mut (*(runtime as *mut Runtime)).core.rng;
+    let res = runtime::sketch::reservoir(&arr, k, &*(x as *mut Str), rng);
+    mem::forget(arr);
+    try_abort!(runtime, res)
+}
+
+pub(crate) unsafe extern "C" fn win_push(
+    runtime: *mut c_void,
+    w: *mut c_void,
+    x: Float,
+    n: Int,
+) -> Int {
+    let w = mem::transmute::<*mut c_void, IntMap<Float>>(w);
+    let res = runtime::window::push(&w, x, n);
+    mem::forget(w);
+    try_abort!(runtime, res)
+}
+
+pub(crate) unsafe extern "C" fn win_sum(w: *mut c_void) -> Float {
+    let w = mem::transmute::<*mut c_void, IntMap<Float>>(w);
+    let res = runtime::window::sum(&w);
+    mem::forget(w);
+    res
+}
+
+pub(crate) unsafe extern "C" fn win_avg(w: *mut c_void) -> Float {
+    let w = mem::transmute::<*mut c_void, IntMap<Float>>(w);
+    let res = runtime::window::avg(&w);
+    mem::forget(w);
+    res
+}
+
+pub(crate) unsafe extern "C" fn win_max(w: *mut c_void) -> Float {
+    let w = mem::transmute::<*mut c_void, IntMap<Float>>(w);
+    let res = runtime::window::max(&w);
+    mem::forget(w);
+    res
+}
+
+pub(crate) unsafe extern "C" fn hist_add(
+    runtime: *mut c_void,
+    h: *mut c_void,
+    x: Float,
+    w: Float,
+    log: Int,
+) -> Int {
+    let h = mem::transmute::<*mut c_void, IntMap<Int>>(h);
+    let res = runtime::hist::add(&h, x, w, log);
+    mem::forget(h);
+    try_abort!(runtime, res)
+}
+
+pub(crate) unsafe extern "C" fn hist_format(h: *mut c_void) -> U128 {
+    let h = mem::transmute::<*mut c_void, IntMap<Int>>(h);
+    let res = runtime::hist::format(&h);
+    mem::forget(h);
+    mem::transmute::<Str, U128>(res)
+}
+
+pub(crate) unsafe extern "C" fn vec_dot(a: *mut c_void, b: *mut c_void) -> Float {
+    let a = mem::transmute::<*mut c_void, IntMap<Float>>(a);
+    let b = mem::transmute::<*mut c_void, IntMap<Float>>(b);
+    let res = runtime::vector::dot(&a, &b);
+    mem::forget((a, b));
+    res
+}
+
+pub(crate) unsafe extern "C" fn vec_norm(a: *mut c_void) -> Float {
+    let a = mem::transmute::<*mut c_void, IntMap<Float>>(a);
+    let res = runtime::vector::norm(&a);
+    mem::forget(a);
+    res
+}
+
+macro_rules! vec_elementwise {
+    ($name:ident, $op:path) => {
+        pub(crate) unsafe extern "C" fn $name(
+            dst: *mut c_void,
+            a: *mut c_void,
+            b: *mut c_void,
+        ) -> Int {
+            let dst = mem::transmute::<*mut c_void, IntMap<Float>>(dst);
+            let a = mem::transmute::<*mut c_void, IntMap<Float>>(a);
+            let b = mem::transmute::<*mut c_void, IntMap<Float>>(b);
+            let res = $op(&dst, &a, &b);
+            mem::forget((dst, a, b));
+            res
+        }
+    };
+}
+
+vec_elementwise!(vec_add, runtime::vector::add);
+vec_elementwise!(vec_sub, runtime::vector::sub);
+vec_elementwise!(vec_mul, runtime::vector::mul);
+
+pub(crate) unsafe extern "C" fn vec_scale(dst: *mut c_void, a: *mut c_void, k: Float) -> Int {
+    let dst = mem::transmute::<*mut c_void, IntMap<Float>>(dst);
+    let a = mem::transmute::<*mut c_void, IntMap<Float>>(a);
+    let res = runtime::vector::scale(&dst, &a, k);
+    mem::forget((dst, a));
+    res
+}
+
+pub(crate) unsafe extern "C" fn mod_int(runtime: *mut c_void, x: Int, y: Int) -> Int {
+    try_abort!(runtime, runtime::arith::imod(x, y))
+}
+
+pub(crate) unsafe extern "C" fn add_int_checked(runtime: *mut c_void, x: Int, y: Int) -> Int {
+    try_abort!(runtime, runtime::arith::checked_add(x, y))
+}
+
+pub(crate) unsafe extern "C" fn minus_int_checked(runtime: *mut c_void, x: Int, y: Int) -> Int {
+    try_abort!(runtime, runtime::arith::checked_sub(x, y))
+}
+
+pub(crate) unsafe extern "C" fn mul_int_checked(runtime: *mut c_void, x: Int, y: Int) -> Int {
+    try_abort!(runtime, runtime::arith::checked_mul(x, y))
+}
+
+pub(crate) unsafe extern "C" fn neg_int_checked(runtime: *mut c_void, x: Int) -> Int {
+    try_abort!(runtime, runtime::arith::checked_neg(x))
+}
+
+pub(crate) unsafe extern "C" fn mod_float(runtime: *mut c_void, x: Float, y: Float) -> Float {
+    try_abort!(runtime, runtime::arith::fmod(x, y))
+}
+
+pub(crate) unsafe extern "C" fn div_float(runtime: *mut c_void, x: Float, y: Float) -> Float {
+    try_abort!(runtime, runtime::arith::div(x, y))
 }
 
 pub(crate) unsafe extern "C" fn ref_str(s: *mut c_void) {
@@ -816,6 +1531,16 @@ pub(crate) unsafe extern "C" fn hex_str_to_int(s: *mut c_void) -> Int {
     res
 }
 
+pub(crate) unsafe extern "C" fn is_na(s: *mut c_void) -> Int {
+    let s = &*(s as *mut Str);
+    runtime::is_na(s) as Int
+}
+
+pub(crate) unsafe extern "C" fn num_or(s: *mut c_void, default: Float) -> Float {
+    let s = &*(s as *mut Str);
+    runtime::num_or(s, default)
+}
+
 pub(crate) unsafe extern "C" fn str_to_float(s: *mut c_void) -> Float {
     let s = &*(s as *mut Str);
     let res = runtime::convert::<&Str, Float>(&s);
@@ -916,7 +1641,7 @@ macro_rules! str_compare_inner {
         pub(crate) unsafe extern "C" fn $name(s1: *mut c_void, s2: *mut c_void) -> Int {
             let s1 = &*(s1 as *mut Str);
             let s2 = &*(s2 as *mut Str);
-            let res = s1.with_bytes(|bs1| s2.with_bytes(|bs2| bs1 $op bs2)) as Int;
+            let res = (runtime::compat::compare_strs(s1, s2) $op std::cmp::Ordering::Equal) as Int;
             mem::forget((s1, s2));
             res
         }
@@ -977,7 +1702,8 @@ pub(crate) unsafe extern "C" fn print_all_stdout(rt: *mut c_void, args: *mut usi
     let args_wrapped: &[&Str] =
         slice::from_raw_parts(args as *const usize as *const &Str, num_args as usize);
     let rt = rt as *mut Runtime;
-    try_abort!(rt, (*rt).core.write_files.write_all(args_wrapped, None))
+    let res = (*rt).core.write_files.write_all(args_wrapped, None);
+    try_write_stdout!(rt, res)
 }
 
 pub(crate) unsafe extern "C" fn print_all_file(
@@ -1059,9 +1785,7 @@ pub(crate) unsafe extern "C" fn printf_impl_stdout(
         &*(spec as *mut Str),
         &format_args[..],
     );
-    if res.is_err() {
-        exit!(rt);
-    }
+    try_write_stdout!(rt, res)
 }
 
 pub(crate) unsafe extern "C" fn close_file(rt: *mut c_void, file: *mut U128) {
@@ -1253,6 +1977,49 @@ macro_rules! map_impl {
                 mem::forget(map);
             }
 
+            pub(crate) unsafe extern "C" fn [<bound_ $ty>](
+                runtime: *mut c_void,
+                map: *mut c_void,
+                size: Int,
+                policy: *mut U128,
+            ) -> Int {
+                debug_assert!(!map.is_null());
+                let runtime = &mut *(runtime as *mut Runtime);
+                let map = mem::transmute::<*mut c_void, runtime::SharedMap<$k, $v>>(map);
+                let policy = &*(policy as *mut Str);
+                let res = map.set_bound(size, policy);
+                mem::forget(map);
+                try_abort!(runtime, res)
+            }
+
+            pub(crate) unsafe extern "C" fn [<load_state_ $ty>](
+                runtime: *mut c_void,
+                map: *mut c_void,
+                name: *mut U128,
+            ) -> Int {
+                debug_assert!(!map.is_null());
+                let runtime = &mut *(runtime as *mut Runtime);
+                let map = mem::transmute::<*mut c_void, runtime::SharedMap<$k, $v>>(map);
+                let name = &*(name as *mut Str);
+                let res = runtime::state::load(&map, name);
+                mem::forget(map);
+                try_abort!(runtime, res)
+            }
+
+            pub(crate) unsafe extern "C" fn [<save_state_ $ty>](
+                runtime: *mut c_void,
+                map: *mut c_void,
+                name: *mut U128,
+            ) -> Int {
+                debug_assert!(!map.is_null());
+                let runtime = &mut *(runtime as *mut Runtime);
+                let map = mem::transmute::<*mut c_void, runtime::SharedMap<$k, $v>>(map);
+                let name = &*(name as *mut Str);
+                let res = runtime::state::save(&map, name);
+                mem::forget(map);
+                try_abort!(runtime, res)
+            }
+
             pub(crate) unsafe extern "C" fn [<drop_ $ty>](map: *mut c_void) {
                 debug_assert!(!map.is_null());
                 drop_map_generic::<$k, $v>(map)
@@ -21,6 +21,10 @@ pub enum Function {
     Log2,
     Log10,
     Exp,
+    Fshl,
+    Fshr,
+    Ctpop,
+    Ctlz,
 }
 
 macro_rules! intrinsic_id {
@@ -41,6 +45,10 @@ lazy_static! {
     static ref LOG2_ID: c_uint = intrinsic_id!("llvm.log2");
     static ref LOG10_ID: c_uint = intrinsic_id!("llvm.log10");
     static ref EXP_ID: c_uint = intrinsic_id!("llvm.exp");
+    static ref FSHL_ID: c_uint = intrinsic_id!("llvm.fshl");
+    static ref FSHR_ID: c_uint = intrinsic_id!("llvm.fshr");
+    static ref CTPOP_ID: c_uint = intrinsic_id!("llvm.ctpop");
+    static ref CTLZ_ID: c_uint = intrinsic_id!("llvm.ctlz");
 }
 
 /// Dropping a string is one of the more common operations performed by a frawk program. Strings
@@ -133,6 +141,18 @@ impl Function {
             Function::Exp => {
                 LLVMGetIntrinsicDeclaration(module, *EXP_ID, &mut tmap.get_ty(Ty::Float), 1)
             }
+            Function::Fshl => {
+                LLVMGetIntrinsicDeclaration(module, *FSHL_ID, &mut tmap.get_ty(Ty::Int), 1)
+            }
+            Function::Fshr => {
+                LLVMGetIntrinsicDeclaration(module, *FSHR_ID, &mut tmap.get_ty(Ty::Int), 1)
+            }
+            Function::Ctpop => {
+                LLVMGetIntrinsicDeclaration(module, *CTPOP_ID, &mut tmap.get_ty(Ty::Int), 1)
+            }
+            Function::Ctlz => {
+                LLVMGetIntrinsicDeclaration(module, *CTLZ_ID, &mut tmap.get_ty(Ty::Int), 1)
+            }
         }
     }
 }
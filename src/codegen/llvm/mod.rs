@@ -8,10 +8,13 @@ use crate::bytecode::Accum;
 use crate::codegen::{
     self, intrinsics::register_all, Backend, CodeGenerator, Jit, Ref, Sig, StrReg,
 };
-use crate::common::{Either, FileSpec, NodeIx, NumTy, Result, Stage};
+use crate::common::{Either, FileSpec, IntOverflow, NodeIx, NumTy, Result, Stage};
 use crate::compile::{self, Ty, Typer};
 use crate::libc::c_char;
-use crate::runtime;
+use crate::runtime::{
+    self,
+    matcher::{Matcher, PatternSet},
+};
 
 use crate::smallvec::{self, smallvec};
 use hashbrown::{HashMap, HashSet};
@@ -78,6 +81,7 @@ struct View<'a> {
     printfs: &'a mut HashMap<(SmallVec<Ty>, PrintfKind), LLVMValueRef>,
     prints: &'a mut HashMap<(usize, /*stdout*/ bool), LLVMValueRef>,
     drop_str: LLVMValueRef,
+    int_overflow: IntOverflow,
     // We keep an extra builder always pointed at the start of the function. This is because
     // binding new string values requires an `alloca`; and we do not want to call `alloca` where a
     // string variable is referenced: for example, we do not want to call alloca in a loop.
@@ -218,6 +222,10 @@ impl<'a> CodeGenerator for View<'a> {
         }
     }
 
+    fn int_overflow(&self) -> IntOverflow {
+        self.int_overflow
+    }
+
     fn runtime_val(&mut self) -> Self::Val {
         unsafe {
             LLVMGetParam(
@@ -255,11 +263,28 @@ impl<'a> CodeGenerator for View<'a> {
         }
     }
 
-    fn const_ptr<'b, T>(&'b mut self, c: &'b T) -> Self::Val {
+    fn const_re<'b>(&'b mut self, pat: &'b Matcher) -> Self::Val {
         let voidp = self.tmap.runtime_ty;
         let int_ty = self.tmap.get_ty(Ty::Int);
         unsafe {
-            let bits = LLVMConstInt(int_ty, c as *const T as u64, /*sign_extend=*/ 0);
+            let bits = LLVMConstInt(
+                int_ty,
+                pat as *const Matcher as u64,
+                /*sign_extend=*/ 0,
+            );
+            LLVMBuildIntToPtr(self.f.builder, bits, voidp, c_str!(""))
+        }
+    }
+
+    fn const_set<'b>(&'b mut self, set: &'b PatternSet) -> Self::Val {
+        let voidp = self.tmap.runtime_ty;
+        let int_ty = self.tmap.get_ty(Ty::Int);
+        unsafe {
+            let bits = LLVMConstInt(
+                int_ty,
+                set as *const PatternSet as u64,
+                /*sign_extend=*/ 0,
+            );
             LLVMBuildIntToPtr(self.f.builder, bits, voidp, c_str!(""))
         }
     }
@@ -334,6 +359,17 @@ impl<'a> CodeGenerator for View<'a> {
                 }
                 Bitwise(bw) => {
                     use builtins::Bitwise::*;
+                    // LLVM shifts by 64 bits or more are undefined; mask the shift amount to
+                    // match the interpreter and cranelift, which shift modulo 64.
+                    let int_ty = self.get_ty(Ty::Int);
+                    if let LogicalRightShift | ArithmeticRightShift | LeftShift = bw {
+                        args[1] = LLVMBuildAnd(
+                            self.f.builder,
+                            args[1],
+                            LLVMConstInt(int_ty, 63, /*sign_extend=*/ 0),
+                            c_str!(""),
+                        );
+                    }
                     Ok(match bw {
                         Complement => LLVMBuildXor(
                             self.f.builder,
@@ -351,6 +387,20 @@ impl<'a> CodeGenerator for View<'a> {
                         }
                         LeftShift => LLVMBuildShl(self.f.builder, args[0], args[1], c_str!("")),
                         Xor => LLVMBuildXor(self.f.builder, args[0], args[1], c_str!("")),
+                        // A funnel shift of a value with itself is a rotate.
+                        RotateLeft => {
+                            self.call_builtin(BuiltinFunc::Fshl, &mut [args[0], args[0], args[1]])
+                        }
+                        RotateRight => {
+                            self.call_builtin(BuiltinFunc::Fshr, &mut [args[0], args[0], args[1]])
+                        }
+                        PopCount => self.call_builtin(BuiltinFunc::Ctpop, &mut [args[0]]),
+                        LeadingZeros => {
+                            // clz(0) is 64, so zero is not poison.
+                            let is_zero_poison =
+                                LLVMConstInt(LLVMInt1TypeInContext(self.ctx), 0, 0);
+                            self.call_builtin(BuiltinFunc::Ctlz, &mut [args[0], is_zero_poison])
+                        }
                     })
                 }
                 Math(ff) => Ok(match translate_float_func(ff) {
@@ -554,6 +604,66 @@ impl<'a> CodeGenerator for View<'a> {
         }
         Ok(())
     }
+    fn next_record(&mut self, dst: Ref) -> Result<()> {
+        use runtime::splitter::{ReaderState, RecordCursor};
+        let word = mem::size_of::<runtime::Int>();
+        let res = unsafe {
+            let builder = self.f.builder;
+            let int_ty = self.tmap.get_ty(Ty::Int);
+            let int_ptr_ty = LLVMPointerType(int_ty, 0);
+            let index =
+                |off: usize| LLVMConstInt(int_ty, (off / word) as u64, /*sign_extend=*/ 0);
+            let field = |base: LLVMValueRef, off: usize| {
+                let mut indices = [index(off)];
+                LLVMBuildGEP(builder, base, indices.as_mut_ptr(), 1, c_str!(""))
+            };
+
+            // Load the cursor's address out of the runtime.
+            let rt = self.runtime_val();
+            let rt_words =
+                LLVMBuildBitCast(builder, rt, LLVMPointerType(int_ptr_ty, 0), c_str!(""));
+            let cursor_loc = field(
+                rt_words,
+                codegen::intrinsics::Runtime::cursor_offset() as usize,
+            );
+            let cursor = LLVMBuildLoad(builder, cursor_loc, c_str!(""));
+            let avail_loc = field(cursor, mem::offset_of!(RecordCursor, avail));
+            let taken_loc = field(cursor, mem::offset_of!(RecordCursor, taken));
+            let avail = LLVMBuildLoad(builder, avail_loc, c_str!(""));
+
+            let take_bb = LLVMAppendBasicBlockInContext(self.ctx, self.f.val, c_str!(""));
+            let refill_bb = LLVMAppendBasicBlockInContext(self.ctx, self.f.val, c_str!(""));
+            let next_bb = LLVMAppendBasicBlockInContext(self.ctx, self.f.val, c_str!(""));
+            let zero = LLVMConstInt(int_ty, 0, /*sign_extend=*/ 0);
+            let one = LLVMConstInt(int_ty, 1, /*sign_extend=*/ 0);
+            let is_empty = LLVMBuildICmp(builder, Pred::LLVMIntEQ, avail, zero, c_str!(""));
+            LLVMBuildCondBr(builder, is_empty, refill_bb, take_bb);
+
+            // Take the next record of the batch.
+            LLVMPositionBuilderAtEnd(builder, take_bb);
+            let avail = LLVMBuildSub(builder, avail, one, c_str!(""));
+            LLVMBuildStore(builder, avail, avail_loc);
+            let taken = LLVMBuildLoad(builder, taken_loc, c_str!(""));
+            let taken = LLVMBuildAdd(builder, taken, one, c_str!(""));
+            LLVMBuildStore(builder, taken, taken_loc);
+            let ok = LLVMConstInt(int_ty, ReaderState::OK as u64, /*sign_extend=*/ 0);
+            LLVMBuildBr(builder, next_bb);
+
+            // The batch is empty: read the next record, refilling the batch.
+            LLVMPositionBuilderAtEnd(builder, refill_bb);
+            let read = self.call(intrinsic!(next_line_stdin_fused), &mut [rt]);
+            let refill_end = LLVMGetInsertBlock(builder);
+            LLVMBuildBr(builder, next_bb);
+
+            LLVMPositionBuilderAtEnd(builder, next_bb);
+            let res = LLVMBuildPhi(builder, int_ty, c_str!(""));
+            let mut vals = [ok, read];
+            let mut blocks = [take_bb, refill_end];
+            LLVMAddIncoming(res, vals.as_mut_ptr(), blocks.as_mut_ptr(), 2);
+            res
+        };
+        self.bind_val(dst, res)
+    }
 }
 
 impl Drop for Function {
@@ -633,6 +743,10 @@ pub(crate) struct Generator<'a, 'b> {
 
     // Specialized implementation of string destruction.
     drop_str: LLVMValueRef,
+
+    // The address of the main function that calls the functions registered with `atexit`, once it
+    // has been compiled.
+    atexit_main: Option<*const u8>,
 }
 
 impl<'a, 'b> Drop for Generator<'a, 'b> {
@@ -702,6 +816,7 @@ macro_rules! view_at {
             ctx: $slf.ctx,
             module: $slf.module,
             drop_str: $slf.drop_str,
+            int_overflow: $slf.cfg.int_overflow,
             entry_builder: $entry_builder,
         }
     };
@@ -711,11 +826,17 @@ impl<'a, 'b> Jit for Generator<'a, 'b> {
     fn main_pointers(&mut self) -> Result<Stage<*const u8>> {
         unsafe {
             let main = self.gen_main()?;
+            let atexit = self.gen_atexit()?;
             self.verify()?;
-            self.optimize(main.iter().map(|(_, x)| x).cloned())?;
+            self.optimize(main.iter().chain(atexit.iter()).map(|(_, x)| x).cloned())?;
+            self.atexit_main =
+                atexit.map(|(name, _)| LLVMGetFunctionAddress(self.engine, name) as *const u8);
             Ok(main.map(|(name, _)| LLVMGetFunctionAddress(self.engine, name) as *const u8))
         }
     }
+    fn atexit_pointer(&mut self) -> Option<*const u8> {
+        self.atexit_main
+    }
 }
 
 impl<'a, 'b> Generator<'a, 'b> {
@@ -797,6 +918,7 @@ impl<'a, 'b> Generator<'a, 'b> {
             prints: Default::default(),
             cfg,
             drop_str: ptr::null_mut(),
+            atexit_main: None,
         };
         res.build_map();
         res.build_decls();
@@ -972,22 +1094,54 @@ impl<'a, 'b> Generator<'a, 'b> {
         // For now, iterate over each element of the stage and call each component in sequence.
         // We need to allocate all of the global variables that our main function uses, and then
         // pass them as arguments, along with the runtime.
+        // We also allocate the globals used by the function that calls the functions registered
+        // with `atexit`.
+        let hooks = self.atexit_globals();
         let main_info = &self.decls[main_offset];
         let mut args: SmallVec<_> = smallvec![ptr::null_mut(); main_info.num_args];
-        for ((_reg, ty), arg_ix) in main_info.globals.iter() {
-            let local = self.alloc_local(builder, *ty)?;
-            let param = if ty.is_array() || matches!(ty, Ty::Str) {
-                // Already a pointer; we're good to go!
-                local
-            } else {
-                let loc = LLVMBuildAlloca(builder, self.llvm_ty(*ty), c_str!(""));
-                LLVMBuildStore(builder, local, loc);
-                loc
+        let mut hook_args: SmallVec<[LLVMValueRef; 2]> = smallvec![ptr::null_mut(); hooks.len()];
+        let mut allocated = HashMap::new();
+        for g in main_info
+            .globals
+            .keys()
+            .chain(hooks.iter())
+            .cloned()
+            .collect::<SmallVec<[_; 8]>>()
+        {
+            let ty = g.1;
+            let param = match allocated.get(&g) {
+                Some(param) => *param,
+                None => {
+                    let local = self.alloc_local(builder, ty)?;
+                    let param = if ty.is_array() || matches!(ty, Ty::Str) {
+                        // Already a pointer; we're good to go!
+                        local
+                    } else {
+                        let loc = LLVMBuildAlloca(builder, self.llvm_ty(ty), c_str!(""));
+                        LLVMBuildStore(builder, local, loc);
+                        loc
+                    };
+                    allocated.insert(g, param);
+                    param
+                }
             };
-            args[*arg_ix] = param;
+            if let Some(arg_ix) = main_info.globals.get(&g) {
+                args[*arg_ix] = param;
+            }
+            if let Some(i) = hooks.iter().position(|h| *h == g) {
+                hook_args[i] = param;
+            }
         }
         // Pass the runtime last.
-        args[main_info.num_args - 1] = LLVMGetParam(decl, 0);
+        let rt = LLVMGetParam(decl, 0);
+        args[main_info.num_args - 1] = rt;
+
+        // Tell the runtime where the globals used by the `atexit` functions are while the main
+        // function runs, so that it can call those functions if the program stops early.
+        for (i, param) in hook_args.iter().cloned().enumerate() {
+            self.set_atexit_global(builder, rt, i, param);
+        }
+        let main_info = &self.decls[main_offset];
         LLVMBuildCall(
             builder,
             main_info.val,
@@ -995,12 +1149,113 @@ impl<'a, 'b> Generator<'a, 'b> {
             args.len() as libc::c_uint,
             c_str!(""),
         );
+        for i in 0..hook_args.len() {
+            let null = LLVMConstNull(self.type_map.runtime_ty);
+            self.set_atexit_global(builder, rt, i, null);
+        }
 
         LLVMBuildRetVoid(builder);
         LLVMDisposeBuilder(builder);
         Ok((name, decl))
     }
 
+    /// The global variables used by the function that calls the functions registered with
+    /// `atexit`, in the order in which main functions register them with the runtime.
+    fn atexit_globals(&self) -> SmallVec<[(NumTy, Ty); 2]> {
+        let udf = match self.types.atexit {
+            Some(udf) => udf,
+            None => return Default::default(),
+        };
+        let mut globals: SmallVec<[_; 2]> = self.decls[udf]
+            .globals
+            .iter()
+            .map(|(g, ix)| (*ix, *g))
+            .collect();
+        globals.sort_by_key(|(ix, _)| *ix);
+        globals.into_iter().map(|(_, g)| g).collect()
+    }
+
+    unsafe fn set_atexit_global(
+        &mut self,
+        builder: LLVMBuilderRef,
+        rt: LLVMValueRef,
+        i: usize,
+        addr: LLVMValueRef,
+    ) {
+        let voidp = self.type_map.runtime_ty;
+        let mut args = [
+            rt,
+            LLVMConstInt(
+                self.type_map.get_ty(Ty::Int),
+                i as u64,
+                /*sign_extend=*/ 0,
+            ),
+            LLVMBuildPointerCast(builder, addr, voidp, c_str!("")),
+        ];
+        LLVMBuildCall(
+            builder,
+            self.intrinsics.get(intrinsic!(set_atexit_global)),
+            args.as_mut_ptr(),
+            args.len() as libc::c_uint,
+            c_str!(""),
+        );
+    }
+
+    /// Generate a main function that calls the function that calls the functions registered with
+    /// `atexit`. Unlike other main functions, it does not allocate the global variables it uses:
+    /// it uses those registered by the main function that is running when the runtime calls it.
+    unsafe fn gen_atexit(&mut self) -> Result<Option<(*const libc::c_char, LLVMValueRef)>> {
+        let udf = match self.types.atexit {
+            Some(udf) => udf,
+            None => return Ok(None),
+        };
+        let name = c_str!("__frawk_atexit");
+        let ty = LLVMFunctionType(
+            LLVMVoidTypeInContext(self.ctx),
+            &mut self.type_map.runtime_ty,
+            1,
+            /*IsVarArg=*/ 0,
+        );
+        let decl = LLVMAddFunction(self.module, name, ty);
+        let builder = LLVMCreateBuilderInContext(self.ctx);
+        let bb = LLVMAppendBasicBlockInContext(self.ctx, decl, c_str!(""));
+        LLVMPositionBuilderAtEnd(builder, bb);
+        let rt = LLVMGetParam(decl, 0);
+        let hooks = self.atexit_globals();
+        let mut args: SmallVec<_> = smallvec![ptr::null_mut(); self.decls[udf].num_args];
+        for (i, g) in hooks.iter().enumerate() {
+            let mut get_args = [
+                rt,
+                LLVMConstInt(
+                    self.type_map.get_ty(Ty::Int),
+                    i as u64,
+                    /*sign_extend=*/ 0,
+                ),
+            ];
+            let addr = LLVMBuildCall(
+                builder,
+                self.intrinsics.get(intrinsic!(atexit_global)),
+                get_args.as_mut_ptr(),
+                get_args.len() as libc::c_uint,
+                c_str!(""),
+            );
+            args[self.decls[udf].globals[g]] =
+                LLVMBuildPointerCast(builder, addr, self.llvm_ptr_ty(g.1), c_str!(""));
+        }
+        let n = args.len();
+        args[n - 1] = rt;
+        LLVMBuildCall(
+            builder,
+            self.decls[udf].val,
+            args.as_mut_ptr(),
+            args.len() as libc::c_uint,
+            c_str!(""),
+        );
+        LLVMBuildRetVoid(builder);
+        LLVMDisposeBuilder(builder);
+        Ok(Some((name, decl)))
+    }
+
     unsafe fn gen_main(&mut self) -> Result<Stage<(*const libc::c_char, LLVMValueRef)>> {
         use crate::common::traverse;
         match self.types.stage() {
@@ -1107,6 +1362,9 @@ impl<'a, 'b> Generator<'a, 'b> {
         // * Locals: these are in SSA form, so "definition dominates use." In other words, any path
         //   through the CFG starting at the entry node will pass through a definition for a node
         //   before it is referenced.
+        // Some instructions (see `next_record`) add blocks of their own, so the LLVM block a node
+        // ends in is not always the one it starts in.
+        let mut tails = bbs.clone();
         let mut dfs_walker = Dfs::new(&frame.cfg, NodeIx::new(0));
         while let Some(n) = dfs_walker.next(&frame.cfg) {
             let i = n.index();
@@ -1123,11 +1381,12 @@ impl<'a, 'b> Generator<'a, 'b> {
                         match hl {
                             Ret(_, _) => exits.push((i, j)),
                             Phi(_, ty, _) if ty != &Ty::Null => phis.push((i, j)),
-                            Phi(_, _, _) | DropIter(_, _) | Call { .. } => {}
+                            Phi(_, _, _) | DropIter(_, _) | Call { .. } | Loc(_) => {}
                         }
                     }
                 }
             }
+            tails[i] = LLVMGetInsertBlock(view.f.builder);
             let mut walker = frame.cfg.neighbors(NodeIx::new(i)).detach();
             let mut tcase = None;
             let mut ecase = None;
@@ -1155,7 +1414,7 @@ impl<'a, 'b> Generator<'a, 'b> {
         let node_weight = |bb, inst| &frame.cfg.node_weight(NodeIx::new(bb)).unwrap().insts[inst];
         let mut placeholder_intrinsics = IntrinsicMap::new(view.module, view.ctx);
         for (exit_bb, return_inst) in exits.into_iter() {
-            LLVMPositionBuilderAtEnd(view.f.builder, bbs[exit_bb]);
+            LLVMPositionBuilderAtEnd(view.f.builder, tails[exit_bb]);
             let var = if let Either::Right(Ret(reg, ty)) = node_weight(exit_bb, return_inst) {
                 (*reg, *ty)
             } else {
@@ -1189,7 +1448,7 @@ impl<'a, 'b> Generator<'a, 'b> {
                 let phi_node = view.get_local_raw((*reg, *ty))?;
                 for (pred_bb, pred_reg) in ps.iter() {
                     preds.push(view.get_local_raw((*pred_reg, *ty))?);
-                    blocks.push(bbs[pred_bb.index()]);
+                    blocks.push(tails[pred_bb.index()]);
                 }
                 LLVMAddIncoming(
                     phi_node,
@@ -1513,6 +1772,8 @@ impl<'a> View<'a> {
                 let IterState { iter_ptr, len, .. } = self.get_iter((*reg, *ty))?.clone();
                 self.call(drop_fn, &mut [iter_ptr, len]);
             }
+            // Source locations are only used by the interpreter.
+            Loc(_) => {}
         };
         Ok(())
     }
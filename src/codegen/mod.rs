@@ -5,24 +5,56 @@
 use crate::{
     builtins,
     bytecode::{self, Accum},
-    common::{FileSpec, NumTy, Result, Stage},
+    common::{FileSpec, IntOverflow, NumTy, Result, Stage},
     compile,
     pushdown::FieldSet,
     runtime::{self, UniqueStr},
 };
 
+use crate::runtime::matcher::{Matcher, PatternSet};
+
 use std::marker::PhantomData;
 use std::mem;
 
 /// Options used to configure a code-generating backend.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Config {
     pub opt_level: usize,
     pub num_workers: usize,
+    /// The optimization setting used by the cranelift backend; LLVM uses `opt_level`.
+    pub jit_opt: JitOpt,
+    /// Write a perf map file describing generated code, so that profilers can symbolize it.
+    /// Currently only supported by the cranelift backend.
+    pub perf_map: bool,
+    /// The behavior of integer arithmetic that overflows.
+    pub int_overflow: IntOverflow,
+}
+
+/// How hard Cranelift should work to optimize generated code.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JitOpt {
+    /// Skip all optimization passes, minimizing compile time.
+    None,
+    /// Run Cranelift's optimizations, including loop-invariant code motion and GVN.
+    Speed,
+    /// Like `Speed`, but also shrink instruction encodings where possible.
+    SpeedAndSize,
+}
+
+impl JitOpt {
+    /// The value of cranelift's `opt_level` setting corresponding to this option.
+    pub(crate) fn cranelift_setting(&self) -> &'static str {
+        match self {
+            JitOpt::None => "none",
+            JitOpt::Speed => "speed",
+            JitOpt::SpeedAndSize => "speed_and_size",
+        }
+    }
 }
 
 #[macro_use]
 pub(crate) mod intrinsics;
+pub(crate) mod cache;
 pub(crate) mod clif;
 #[cfg(feature = "llvm_backend")]
 pub(crate) mod llvm;
@@ -127,8 +159,14 @@ impl<'a> MainFunction<'a> {
 
 pub(crate) trait Jit: Sized {
     fn main_pointers(&mut self) -> Result<Stage<*const u8>>;
-    fn main_functions<'a>(&'a mut self) -> Result<Stage<MainFunction<'a>>> {
-        Ok(self.main_pointers()?.map(MainFunction::from_ptr))
+    /// The main function that calls the functions registered with `atexit`, if there is one; see
+    /// `ast::Prog::atexit_runner`. This is only available once `main_pointers` has been called.
+    fn atexit_pointer(&mut self) -> Option<*const u8>;
+    fn main_functions<'a>(
+        &'a mut self,
+    ) -> Result<(Stage<MainFunction<'a>>, Option<MainFunction<'a>>)> {
+        let main = self.main_pointers()?.map(MainFunction::from_ptr);
+        Ok((main, self.atexit_pointer().map(MainFunction::from_ptr)))
     }
 }
 
@@ -140,6 +178,9 @@ pub(crate) unsafe fn run_main<R, FF, J>(
     ff: FF,
     used_fields: &FieldSet,
     named_columns: Option<Vec<&[u8]>>,
+    argv: &[&str],
+    regex_literals: &[String],
+    input: &runtime::InputConfig,
     num_workers: usize,
 ) -> Result<()>
 where
@@ -148,7 +189,19 @@ where
     J: Jit,
 {
     let mut rt = stdin.into_runtime(ff, used_fields, named_columns);
-    let main = jit.main_functions()?;
+    rt.core.set_argv(argv);
+    rt.core
+        .regexes
+        .precompile(regex_literals.iter().map(String::as_str));
+    with_input!(&mut rt.input_data, |(_, read_files)| read_files
+        .configure(input));
+    let (main, atexit) = jit.main_functions()?;
+    if let Some(atexit) = atexit {
+        rt.atexit = atexit.fn_ptr;
+    }
+    runtime::stats::start_running();
+    // An `exit` in END leaves the flag set; clear it in case this process ran another program.
+    runtime::exit::set_exiting(-1);
     match main {
         Stage::Main(m) => Ok(m.invoke(&mut rt)),
         Stage::Par {
@@ -166,6 +219,7 @@ where
                     // execute serially.
                     for main in begin.into_iter().chain(main_loop).chain(end) {
                         main.invoke(&mut rt);
+                        runtime::watchdog::end_record();
                     }
                     return Ok(());
                 }
@@ -203,17 +257,16 @@ where
                     let scope_res = crossbeam::scope(|s| {
                         for (reader, sender, shuttle) in launch_data.into_iter() {
                             s.spawn(move |_| {
-                                let mut runtime = Runtime {
-                                    concurrent: true,
-                                    core: shuttle(),
-                                    input_data: reader().into(),
-                                };
+                                let mut runtime = Runtime::new(shuttle(), reader().into());
+                                runtime.concurrent = true;
                                 main_loop_fn.invoke(&mut runtime);
+                                runtime::watchdog::end_record();
                                 sender.send(runtime.core.extract_result()).unwrap();
                             });
                         }
                         rt.core.vars.pid = 1;
                         main_loop_fn.invoke(&mut rt);
+                        runtime::watchdog::end_record();
                         rt.core.vars.pid = 0;
                         mem::drop(sender);
                         with_input!(&mut rt.input_data, |(_, read_files)| {
@@ -221,7 +274,7 @@ where
                                 rt.core.combine(res);
                             }
                             rt.concurrent = false;
-                            if let Some(end) = end {
+                            if let Some(end) = end.filter(|_| !runtime::writers::skip_end()) {
                                 read_files.inputs = old_read_files;
                                 end.invoke(&mut rt);
                             }
@@ -237,6 +290,29 @@ where
     }
 }
 
+/// Run a tiered program from its main loop on, given the state the interpreter left behind when
+/// it stopped (see `runtime::tiered`) and the compiled main functions and `atexit` runner.
+pub(crate) unsafe fn resume_main(
+    core: crate::interp::Core,
+    input: intrinsics::InputData,
+    main: Stage<*const u8>,
+    atexit: Option<*const u8>,
+) -> Result<()> {
+    let (main_loop, end) = match main {
+        Stage::Par { main_loop, end, .. } => (main_loop, end),
+        Stage::Main(_) => return err!("tiered programs must be compiled in stages"),
+    };
+    let mut rt = Runtime::new(core, input);
+    if let Some(atexit) = atexit {
+        rt.atexit = atexit;
+    }
+    for main in main_loop.into_iter().chain(end) {
+        MainFunction::from_ptr(main).invoke(&mut rt);
+        runtime::watchdog::end_record();
+    }
+    Ok(())
+}
+
 pub(crate) trait Backend {
     type Ty: Clone;
     // mappings from compile::Ty to Self::Ty
@@ -269,12 +345,16 @@ pub(crate) trait CodeGenerator: Backend {
     fn bind_val(&mut self, r: Ref, v: Self::Val) -> Result<()>;
     fn get_val(&mut self, r: Ref) -> Result<Self::Val>;
 
+    /// The behavior of integer arithmetic that overflows, from the backend's `Config`.
+    fn int_overflow(&self) -> IntOverflow;
+
     // backend-specific handling of constants and low-level operations.
     fn runtime_val(&mut self) -> Self::Val;
     fn const_int(&mut self, i: i64) -> Self::Val;
     fn const_float(&mut self, f: f64) -> Self::Val;
     fn const_str<'a>(&mut self, s: &UniqueStr<'a>) -> Self::Val;
-    fn const_ptr<'a, T>(&'a mut self, c: &'a T) -> Self::Val;
+    fn const_re<'a>(&'a mut self, pat: &'a Matcher) -> Self::Val;
+    fn const_set<'a>(&'a mut self, set: &'a PatternSet) -> Self::Val;
 
     // NB: why &mut [..] everywhere instead of &[..] or impl Iterator<..>? The LLVM C API takes a
     // sequence of arguments by a mutable pointer to the first element along with a length. We
@@ -316,6 +396,12 @@ pub(crate) trait CodeGenerator: Backend {
     /// Moves the contents of `src` into `dst`, taking refcounts into consideration if necessary.
     fn mov(&mut self, ty: compile::Ty, dst: NumTy, src: NumTy) -> Result<()>;
 
+    /// Moves the string in `src` into `dst`, leaving `src` empty. Backends that do not implement
+    /// this fall back on a `mov`, which is correct but does not save the refcount update.
+    fn take_str(&mut self, dst: NumTy, src: NumTy) -> Result<()> {
+        self.mov(compile::Ty::Str, dst, src)
+    }
+
     /// Constructs an iterator over the keys of `map` and stores it in `dst`.
     fn iter_begin(&mut self, dst: Ref, map: Ref) -> Result<()>;
 
@@ -326,6 +412,14 @@ pub(crate) trait CodeGenerator: Backend {
     /// Advances the iterator in `iter` to the next element and stores the current element in `dst`
     fn iter_getnext(&mut self, dst: Ref, iter: Ref) -> Result<()>;
 
+    /// Moves the main loop on to the next record of the main input and stores the same result as
+    /// `ReadErrStdin` in `dst`.
+    ///
+    /// While the current batch of records has any left (see `RecordCursor`), this takes the next
+    /// one by updating the batch's cursor. Otherwise it calls `next_line_stdin_fused`, which reads
+    /// the next record and refills the batch.
+    fn next_record(&mut self, dst: Ref) -> Result<()>;
+
     // The plumbing for builtin variable manipulation is mostly pretty wrote ... anything we can do
     // here?
 
@@ -467,6 +561,53 @@ pub(crate) trait CodeGenerator: Backend {
         Ok(())
     }
 
+    /// Loads `map` from, or saves it to, the state file (see `runtime::state`), storing the number
+    /// of entries loaded or saved in `dst`.
+    fn state_map(&mut self, load: bool, map: Ref, name: Ref, dst: Ref) -> Result<()> {
+        use compile::Ty::*;
+        let func = match (load, map.1) {
+            (true, MapIntInt) => intrinsic!(load_state_intint),
+            (true, MapIntFloat) => intrinsic!(load_state_intfloat),
+            (true, MapIntStr) => intrinsic!(load_state_intstr),
+            (true, MapStrInt) => intrinsic!(load_state_strint),
+            (true, MapStrFloat) => intrinsic!(load_state_strfloat),
+            (true, MapStrStr) => intrinsic!(load_state_strstr),
+            (false, MapIntInt) => intrinsic!(save_state_intint),
+            (false, MapIntFloat) => intrinsic!(save_state_intfloat),
+            (false, MapIntStr) => intrinsic!(save_state_intstr),
+            (false, MapStrInt) => intrinsic!(save_state_strint),
+            (false, MapStrFloat) => intrinsic!(save_state_strfloat),
+            (false, MapStrStr) => intrinsic!(save_state_strstr),
+            (_, ty) => return err!("non-map type: {:?}", ty),
+        };
+        let rt = self.runtime_val();
+        let mapv = self.get_val(map)?;
+        let namev = self.get_val(name)?;
+        let resv = self.call_intrinsic(func, &mut [rt, mapv, namev])?;
+        self.bind_val(dst, resv)
+    }
+
+    /// Limits `map` to `size` entries (see `runtime::bounded`), storing the number of entries
+    /// evicted in `dst`.
+    fn bound_map(&mut self, map: Ref, size: Ref, policy: Ref, dst: Ref) -> Result<()> {
+        use compile::Ty::*;
+        let func = match map.1 {
+            MapIntInt => intrinsic!(bound_intint),
+            MapIntFloat => intrinsic!(bound_intfloat),
+            MapIntStr => intrinsic!(bound_intstr),
+            MapStrInt => intrinsic!(bound_strint),
+            MapStrFloat => intrinsic!(bound_strfloat),
+            MapStrStr => intrinsic!(bound_strstr),
+            ty => return err!("non-map type: {:?}", ty),
+        };
+        let rt = self.runtime_val();
+        let mapv = self.get_val(map)?;
+        let sizev = self.get_val(size)?;
+        let policyv = self.get_val(policy)?;
+        let resv = self.call_intrinsic(func, &mut [rt, mapv, sizev, policyv])?;
+        self.bind_val(dst, resv)
+    }
+
     /// Stores `val` into `map` at key `key`.
     ///
     /// Assumes that the types of the input registers match up.
@@ -489,6 +630,37 @@ pub(crate) trait CodeGenerator: Backend {
         Ok(())
     }
 
+    /// Integer `+`, `-` or `*`, taking the program's `IntOverflow` setting into account.
+    fn int_binop(
+        &mut self,
+        arith: Arith,
+        dst: &impl Accum,
+        l: &impl Accum,
+        r: &impl Accum,
+    ) -> Result<()> {
+        if self.int_overflow() != IntOverflow::Checked {
+            return self.binop(op(arith, false), dst, l, r);
+        }
+        let f = match arith {
+            Arith::Add => external!(add_int_checked),
+            Arith::Minus => external!(minus_int_checked),
+            Arith::Mul => external!(mul_int_checked),
+            Arith::Mod | Arith::Neg => return err!("unexpected checked integer operation"),
+        };
+        self.checked_binop(f, dst, l, r)
+    }
+
+    /// Integer negation, taking the program's `IntOverflow` setting into account.
+    fn int_neg(&mut self, dst: &impl Accum, x: &impl Accum) -> Result<()> {
+        if self.int_overflow() != IntOverflow::Checked {
+            return self.unop(op(Arith::Neg, false), dst, x);
+        }
+        let rt = self.runtime_val();
+        let xv = self.get_val(x.reflect())?;
+        let res = self.call_intrinsic(intrinsic!(neg_int_checked), &mut [rt, xv])?;
+        self.bind_val(dst.reflect(), res)
+    }
+
     /// Wraps `call_intrinsic` for [`Op`]s that have two arguments and return a value.
     fn binop(&mut self, op: Op, dst: &impl Accum, l: &impl Accum, r: &impl Accum) -> Result<()> {
         let lv = self.get_val(l.reflect())?;
@@ -497,6 +669,22 @@ pub(crate) trait CodeGenerator: Backend {
         self.bind_val(dst.reflect(), res)
     }
 
+    /// Like `binop`, but for runtime functions that take the runtime as an extra first argument,
+    /// so that they can fail.
+    fn checked_binop(
+        &mut self,
+        f: *const u8,
+        dst: &impl Accum,
+        l: &impl Accum,
+        r: &impl Accum,
+    ) -> Result<()> {
+        let rt = self.runtime_val();
+        let lv = self.get_val(l.reflect())?;
+        let rv = self.get_val(r.reflect())?;
+        let res = self.call_intrinsic(Op::Intrinsic(f), &mut [rt, lv, rv])?;
+        self.bind_val(dst.reflect(), res)
+    }
+
     /// Wraps `call_intrinsic` for [`Op`]s that have one argument and return a value.
     fn unop(&mut self, op: Op, dst: &impl Accum, x: &impl Accum) -> Result<()> {
         let xv = self.get_val(x.reflect())?;
@@ -523,17 +711,26 @@ pub(crate) trait CodeGenerator: Backend {
             FloatToStr(sr, fr) => self.unop(intrinsic!(float_to_str), sr, fr),
             StrToInt(ir, sr) => self.unop(intrinsic!(str_to_int), ir, sr),
             HexStrToInt(ir, sr) => self.unop(intrinsic!(hex_str_to_int), ir, sr),
+            IsNA(ir, sr) => self.unop(intrinsic!(is_na), ir, sr),
+            NumOr(dst, s, default) => self.binop(intrinsic!(num_or), dst, s, default),
             StrToFloat(fr, sr) => self.unop(intrinsic!(str_to_float), fr, sr),
             FloatToInt(ir, fr) => self.unop(Op::FloatToInt, ir, fr),
             IntToFloat(fr, ir) => self.unop(Op::IntToFloat, fr, ir),
-            AddInt(res, l, r) => self.binop(op(Arith::Add, false), res, l, r),
+            AddInt(res, l, r) => self.int_binop(Arith::Add, res, l, r),
             AddFloat(res, l, r) => self.binop(op(Arith::Add, true), res, l, r),
-            MinusInt(res, l, r) => self.binop(op(Arith::Minus, false), res, l, r),
+            MinusInt(res, l, r) => self.int_binop(Arith::Minus, res, l, r),
             MinusFloat(res, l, r) => self.binop(op(Arith::Minus, true), res, l, r),
-            MulInt(res, l, r) => self.binop(op(Arith::Mul, false), res, l, r),
+            MulInt(res, l, r) => self.int_binop(Arith::Mul, res, l, r),
             MulFloat(res, l, r) => self.binop(op(Arith::Mul, true), res, l, r),
-            ModInt(res, l, r) => self.binop(op(Arith::Mod, false), res, l, r),
+            // Integer division by zero traps, so we check for it in the runtime.
+            ModInt(res, l, r) => self.checked_binop(external!(mod_int), res, l, r),
+            ModFloat(res, l, r) if runtime::arith::div_by_zero_errors() => {
+                self.checked_binop(external!(mod_float), res, l, r)
+            }
             ModFloat(res, l, r) => self.binop(op(Arith::Mod, true), res, l, r),
+            Div(res, l, r) if runtime::arith::div_by_zero_errors() => {
+                self.checked_binop(external!(div_float), res, l, r)
+            }
             Div(res, l, r) => self.binop(Op::Div, res, l, r),
             Pow(res, l, r) => self.binop(Op::Pow, res, l, r),
             Not(res, ir) => {
@@ -561,7 +758,7 @@ pub(crate) trait CodeGenerator: Backend {
                 )?;
                 self.bind_val(res.reflect(), cmp)
             }
-            NegInt(res, ir) => self.unop(op(Arith::Neg, false), res, ir),
+            NegInt(res, ir) => self.int_neg(res, ir),
             NegFloat(res, fr) => self.unop(op(Arith::Neg, true), res, fr),
             Float1(ff, dst, src) => self.unop(Op::Math(*ff), dst, src),
             Float2(ff, dst, l, r) => self.binop(Op::Math(*ff), dst, l, r),
@@ -601,19 +798,32 @@ pub(crate) trait CodeGenerator: Backend {
             MatchConst(res, src, pat) => {
                 let rt = self.runtime_val();
                 let srcv = self.get_val(src.reflect())?;
-                let patv = self.const_ptr(&**pat);
+                let patv = self.const_re(&**pat);
                 let resv =
                     self.call_intrinsic(intrinsic!(match_const_pat_loc), &mut [rt, srcv, patv])?;
                 self.bind_val(res.reflect(), resv)
             }
             IsMatchConst(res, src, pat) => {
                 let srcv = self.get_val(src.reflect())?;
-                let patv = self.const_ptr(&**pat);
+                let patv = self.const_re(&**pat);
                 let resv = self.call_intrinsic(intrinsic!(match_const_pat), &mut [srcv, patv])?;
                 self.bind_val(res.reflect(), resv)
             }
+            MatchSet(res, src, set) => {
+                let srcv = self.get_val(src.reflect())?;
+                let setv = self.const_set(&**set);
+                let resv = self.call_intrinsic(intrinsic!(match_set), &mut [srcv, setv])?;
+                self.bind_val(res.reflect(), resv)
+            }
             SubstrIndex(dst, s, t) => self.binop(intrinsic!(substr_index), dst, s, t),
-            LenStr(dst, x) => self.unop(intrinsic!(str_len), dst, x),
+            Levenshtein(dst, a, b) => self.binop(intrinsic!(levenshtein), dst, a, b),
+            JaroWinkler(dst, a, b) => self.binop(intrinsic!(jaro_winkler), dst, a, b),
+            CmpNatural(dst, a, b) => self.binop(intrinsic!(cmp_natural), dst, a, b),
+            CmpLocale(dst, a, b) => self.binop(intrinsic!(cmp_locale), dst, a, b),
+            Trim(dst, s, set) => self.binop(intrinsic!(trim), dst, s, set),
+            LTrim(dst, s, set) => self.binop(intrinsic!(ltrim), dst, s, set),
+            RTrim(dst, s, set) => self.binop(intrinsic!(rtrim), dst, s, set),
+            LenStr(dst, x) => self.unop(intrinsic!(str_char_len), dst, x),
             Sub(res, pat, s, in_s) => {
                 let rt = self.runtime_val();
                 let patv = self.get_val(pat.reflect())?;
@@ -633,6 +843,9 @@ pub(crate) trait CodeGenerator: Backend {
                 self.bind_val(res.reflect(), resv)
             }
             EscapeCSV(dst, s) => self.unop(intrinsic!(escape_csv), dst, s),
+            Squeeze(dst, s) => self.unop(intrinsic!(squeeze), dst, s),
+            Color(dst, s, name) => self.checked_binop(external!(color), dst, s, name),
+            Bold(dst, s) => self.unop(intrinsic!(bold), dst, s),
             EscapeTSV(dst, s) => self.unop(intrinsic!(escape_tsv), dst, s),
             Substr(res, base, l, r) => {
                 let basev = self.get_val(base.reflect())?;
@@ -641,6 +854,206 @@ pub(crate) trait CodeGenerator: Backend {
                 let resv = self.call_intrinsic(intrinsic!(substr), &mut [basev, lv, rv])?;
                 self.bind_val(res.reflect(), resv)
             }
+            LPad(res, s, width, with) => {
+                let sv = self.get_val(s.reflect())?;
+                let widthv = self.get_val(width.reflect())?;
+                let withv = self.get_val(with.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(lpad), &mut [sv, widthv, withv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            RPad(res, s, width, with) => {
+                let sv = self.get_val(s.reflect())?;
+                let widthv = self.get_val(width.reflect())?;
+                let withv = self.get_val(with.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(rpad), &mut [sv, widthv, withv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            ZFill(dst, s, width) => self.binop(intrinsic!(zfill), dst, s, width),
+            GraphemeLen(dst, x) => self.unop(intrinsic!(grapheme_len), dst, x),
+            GraphemeSubstr(res, base, l, r) => {
+                let basev = self.get_val(base.reflect())?;
+                let lv = self.get_val(l.reflect())?;
+                let rv = self.get_val(r.reflect())?;
+                let resv =
+                    self.call_intrinsic(intrinsic!(grapheme_substr), &mut [basev, lv, rv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            Graphemes(res, s, arr) => {
+                let sv = self.get_val(s.reflect())?;
+                let arrv = self.get_val(arr.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(graphemes), &mut [sv, arrv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            IntDiv(res, num, den, arr) => {
+                let rt = self.runtime_val();
+                let numv = self.get_val(num.reflect())?;
+                let denv = self.get_val(den.reflect())?;
+                let arrv = self.get_val(arr.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(intdiv), &mut [rt, numv, denv, arrv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            DivMod(res, num, den, arr) => {
+                let rt = self.runtime_val();
+                let numv = self.get_val(num.reflect())?;
+                let denv = self.get_val(den.reflect())?;
+                let arrv = self.get_val(arr.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(divmod), &mut [rt, numv, denv, arrv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            HllAdd(res, h, x) => {
+                let hv = self.get_val(h.reflect())?;
+                let xv = self.get_val(x.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(hll_add), &mut [hv, xv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            HllCount(res, h) => self.unop(intrinsic!(hll_count), res, h),
+            BloomAdd(res, b, x, n, p) => {
+                let rt = self.runtime_val();
+                let bv = self.get_val(b.reflect())?;
+                let xv = self.get_val(x.reflect())?;
+                let nv = self.get_val(n.reflect())?;
+                let pv = self.get_val(p.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(bloom_add), &mut [rt, bv, xv, nv, pv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            BloomHas(res, b, x) => {
+                let rt = self.runtime_val();
+                let bv = self.get_val(b.reflect())?;
+                let xv = self.get_val(x.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(bloom_has), &mut [rt, bv, xv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            Reservoir(res, arr, k, x) => {
+                let rt = self.runtime_val();
+                let arrv = self.get_val(arr.reflect())?;
+                let kv = self.get_val(k.reflect())?;
+                let xv = self.get_val(x.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(reservoir), &mut [rt, arrv, kv, xv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            WinPush(res, w, x, n) => {
+                let rt = self.runtime_val();
+                let wv = self.get_val(w.reflect())?;
+                let xv = self.get_val(x.reflect())?;
+                let nv = self.get_val(n.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(win_push), &mut [rt, wv, xv, nv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            WinSum(res, w) => self.unop(intrinsic!(win_sum), res, w),
+            WinAvg(res, w) => self.unop(intrinsic!(win_avg), res, w),
+            WinMax(res, w) => self.unop(intrinsic!(win_max), res, w),
+            HistAdd(res, h, x, w, log) => {
+                let rt = self.runtime_val();
+                let hv = self.get_val(h.reflect())?;
+                let xv = self.get_val(x.reflect())?;
+                let wv = self.get_val(w.reflect())?;
+                let logv = self.get_val(log.reflect())?;
+                let resv =
+                    self.call_intrinsic(intrinsic!(hist_add), &mut [rt, hv, xv, wv, logv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            HistFormat(res, h) => self.unop(intrinsic!(hist_format), res, h),
+            VecDot(res, a, b) => {
+                let av = self.get_val(a.reflect())?;
+                let bv = self.get_val(b.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(vec_dot), &mut [av, bv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            VecNorm(res, a) => self.unop(intrinsic!(vec_norm), res, a),
+            VecAdd(res, dst, a, b) | VecSub(res, dst, a, b) | VecMul(res, dst, a, b) => {
+                let op = match inst {
+                    VecAdd(..) => intrinsic!(vec_add),
+                    VecSub(..) => intrinsic!(vec_sub),
+                    _ => intrinsic!(vec_mul),
+                };
+                let dstv = self.get_val(dst.reflect())?;
+                let av = self.get_val(a.reflect())?;
+                let bv = self.get_val(b.reflect())?;
+                let resv = self.call_intrinsic(op, &mut [dstv, av, bv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            VecScale(res, dst, a, k) => {
+                let dstv = self.get_val(dst.reflect())?;
+                let av = self.get_val(a.reflect())?;
+                let kv = self.get_val(k.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(vec_scale), &mut [dstv, av, kv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            Readable(res, inputs, timeout) => {
+                let rt = self.runtime_val();
+                let inputsv = self.get_val(inputs.reflect())?;
+                let timeoutv = self.get_val(timeout.reflect())?;
+                let resv =
+                    self.call_intrinsic(intrinsic!(readable), &mut [rt, inputsv, timeoutv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            SetTimeout(res, input, ms) => {
+                let rt = self.runtime_val();
+                let inputv = self.get_val(input.reflect())?;
+                let msv = self.get_val(ms.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(set_timeout), &mut [rt, inputv, msv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            Spawn(res, cmd) => {
+                let rt = self.runtime_val();
+                let cmdv = self.get_val(cmd.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(spawn), &mut [rt, cmdv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            ReadLine(res, h, stream) => {
+                let rt = self.runtime_val();
+                let hv = self.get_val(h.reflect())?;
+                let streamv = self.get_val(stream.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(read_line), &mut [rt, hv, streamv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            WriteLine(res, h, s) => {
+                let rt = self.runtime_val();
+                let hv = self.get_val(h.reflect())?;
+                let sv = self.get_val(s.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(write_line), &mut [rt, hv, sv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            Wait(res, h) => {
+                let rt = self.runtime_val();
+                let hv = self.get_val(h.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(wait), &mut [rt, hv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            ReadCmd(res, cmd) => self.unop(intrinsic!(read_cmd), res, cmd),
+            Getenv(res, name, default) => self.binop(intrinsic!(getenv), res, name, default),
+            Setenv(res, name, val) => {
+                let rt = self.runtime_val();
+                let namev = self.get_val(name.reflect())?;
+                let valv = self.get_val(val.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(setenv), &mut [rt, namev, valv])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            Stat(res, path, arr) => self.binop(intrinsic!(stat), res, path, arr),
+            Glob(res, pattern, arr) => self.binop(intrinsic!(glob), res, pattern, arr),
+            Mkdirp(res, path) => self.unop(intrinsic!(mkdirp), res, path),
+            Rename(res, from, to) => self.binop(intrinsic!(rename), res, from, to),
+            RmFile(res, path) => self.unop(intrinsic!(rmfile), res, path),
+            Sleep(res, secs) => self.unop(intrinsic!(sleep), res, secs),
+            Monotime(res) => {
+                let resv = self.call_intrinsic(intrinsic!(monotime), &mut [])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            Signal(res) => {
+                let resv = self.call_intrinsic(intrinsic!(caught_signal), &mut [])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            SetExit(res, status) => self.unop(intrinsic!(set_exit), res, status),
+            Exiting(res) => {
+                let resv = self.call_intrinsic(intrinsic!(exiting), &mut [])?;
+                self.bind_val(res.reflect(), resv)
+            }
+            SetExiting(res, n) => self.unop(intrinsic!(set_exiting), res, n),
+            // Compiled code never hands the program off to anything else.
+            TierUp(res) => {
+                let zero = self.const_int(0);
+                self.bind_val(res.reflect(), zero)
+            }
             LTInt(res, l, r) => self.binop(cmp(Cmp::LT, false), res, l, r),
             GTInt(res, l, r) => self.binop(cmp(Cmp::GT, false), res, l, r),
             LTEInt(res, l, r) => self.binop(cmp(Cmp::LTE, false), res, l, r),
@@ -669,6 +1082,14 @@ pub(crate) trait CodeGenerator: Backend {
                 let dstv = self.call_intrinsic(intrinsic!(get_col), &mut [rt, srcv])?;
                 self.bind_val(dst.reflect(), dstv)
             }
+            GetColumnInt(dst, s, col) => {
+                self.gen_ll_inst(&GetColumn(*s, *col))?;
+                self.unop(intrinsic!(str_to_int), dst, s)
+            }
+            GetColumnFloat(dst, s, col) => {
+                self.gen_ll_inst(&GetColumn(*s, *col))?;
+                self.unop(intrinsic!(str_to_float), dst, s)
+            }
             JoinCSV(dst, start, end) => {
                 let rt = self.runtime_val();
                 let startv = self.get_val(start.reflect())?;
@@ -683,6 +1104,20 @@ pub(crate) trait CodeGenerator: Backend {
                 let resv = self.call_intrinsic(intrinsic!(join_tsv), &mut [rt, startv, endv])?;
                 self.bind_val(dst.reflect(), resv)
             }
+            SumColumns(dst, start, end) => {
+                let rt = self.runtime_val();
+                let startv = self.get_val(start.reflect())?;
+                let endv = self.get_val(end.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(sum_cols), &mut [rt, startv, endv])?;
+                self.bind_val(dst.reflect(), resv)
+            }
+            MeanColumns(dst, start, end) => {
+                let rt = self.runtime_val();
+                let startv = self.get_val(start.reflect())?;
+                let endv = self.get_val(end.reflect())?;
+                let resv = self.call_intrinsic(intrinsic!(mean_cols), &mut [rt, startv, endv])?;
+                self.bind_val(dst.reflect(), resv)
+            }
             JoinColumns(dst, start, end, sep) => {
                 let rt = self.runtime_val();
                 let startv = self.get_val(start.reflect())?;
@@ -745,11 +1180,34 @@ pub(crate) trait CodeGenerator: Backend {
                 let resv = self.call_intrinsic(intrinsic!(next_line_stdin), &mut [rt])?;
                 self.bind_val(dst.reflect(), resv)
             }
-            NextLineStdinFused() => {
+            RecordFile(dst) => {
                 let rt = self.runtime_val();
-                self.call_void(external!(next_line_stdin_fused), &mut [rt])?;
-                Ok(())
+                let resv = self.call_intrinsic(intrinsic!(record_file), &mut [rt])?;
+                self.bind_val(dst.reflect(), resv)
+            }
+            SetMatched(dst) => {
+                let resv = self.call_intrinsic(intrinsic!(set_matched), &mut [])?;
+                self.bind_val(dst.reflect(), resv)
+            }
+            RecordOffset(dst) => {
+                let rt = self.runtime_val();
+                let resv = self.call_intrinsic(intrinsic!(record_offset), &mut [rt])?;
+                self.bind_val(dst.reflect(), resv)
+            }
+            RecordText(dst) => {
+                let rt = self.runtime_val();
+                let resv = self.call_intrinsic(intrinsic!(record_text), &mut [rt])?;
+                self.bind_val(dst.reflect(), resv)
             }
+            ArgvAssign(dst) => {
+                let rt = self.runtime_val();
+                let resv = self.call_intrinsic(intrinsic!(argv_assign), &mut [rt])?;
+                self.bind_val(dst.reflect(), resv)
+            }
+            RoundTripSep(dst, record, ors) => {
+                self.checked_binop(external!(round_trip_sep), dst, record, ors)
+            }
+            NextLineStdinFused(dst) => self.next_record(dst.reflect()),
             NextFile() => {
                 let rt = self.runtime_val();
                 self.call_void(external!(next_file), &mut [rt])?;
@@ -760,6 +1218,9 @@ pub(crate) trait CodeGenerator: Backend {
                 self.call_void(external!(update_used_fields), &mut [rt])?;
                 Ok(())
             }
+            MarkInit(..) | CheckInit(..) => {
+                err!("checks for uninitialized variables are only supported by the interpreter")
+            }
             SetFI(key, val) => {
                 // We could probably get away without an extra intrinsic here, but this way we can
                 // avoid repeated refs and drops of the FI variable outside of the existing
@@ -792,6 +1253,40 @@ pub(crate) trait CodeGenerator: Backend {
             ),
             Delete { map_ty, map, key } => self.delete_map((*map, *map_ty), (*key, map_ty.key()?)),
             Len { map_ty, map, dst } => self.len_map((*map, *map_ty), (*dst, compile::Ty::Int)),
+            Bound {
+                map_ty,
+                dst,
+                map,
+                size,
+                policy,
+            } => self.bound_map(
+                (*map, *map_ty),
+                (*size, compile::Ty::Int),
+                (*policy, compile::Ty::Str),
+                (*dst, compile::Ty::Int),
+            ),
+            LoadState {
+                map_ty,
+                dst,
+                map,
+                name,
+            } => self.state_map(
+                /*load=*/ true,
+                (*map, *map_ty),
+                (*name, compile::Ty::Str),
+                (*dst, compile::Ty::Int),
+            ),
+            SaveState {
+                map_ty,
+                dst,
+                map,
+                name,
+            } => self.state_map(
+                /*load=*/ false,
+                (*map, *map_ty),
+                (*name, compile::Ty::Str),
+                (*dst, compile::Ty::Int),
+            ),
             Store {
                 map_ty,
                 map,
@@ -865,6 +1360,7 @@ pub(crate) trait CodeGenerator: Backend {
             LoadSlot { ty, dst, slot } => self.load_slot((*dst, *ty), *slot),
             StoreSlot { ty, src, slot } => self.store_slot((*src, *ty), *slot),
             Mov(ty, dst, src) => self.mov(*ty, *dst, *src),
+            TakeStr(dst, src) => self.take_str(dst.reflect().0, src.reflect().0),
             IterBegin { map_ty, map, dst } => {
                 self.iter_begin((*dst, map_ty.key_iter()?), (*map, *map_ty))
             }
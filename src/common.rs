@@ -1,4 +1,5 @@
 //! This file contains common type definitions and utilities used in other parts of the project.
+use crate::lexer::{Loc, Span};
 use hashbrown::HashSet;
 use std::collections::VecDeque;
 use std::fmt;
@@ -13,6 +14,25 @@ pub(crate) type NodeIx = petgraph::graph::NodeIndex<NumTy>;
 pub(crate) type Graph<V, E> = petgraph::Graph<V, E, petgraph::Directed, NumTy>;
 pub(crate) type Result<T> = std::result::Result<T, CompileError>;
 
+/// The behavior of integer arithmetic that overflows.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IntOverflow {
+    /// Compute `+`, `-`, `*` and unary `-` in floating point, as gawk does. This is the default.
+    /// Results never overflow, but are only exact up to 2^53.
+    Promote,
+    /// Keep integers as integers, wrapping around on overflow as with two's complement arithmetic
+    /// (`--wrap`).
+    Wrap,
+    /// Keep integers as integers, and stop with a runtime error on overflow (`--checked`).
+    Checked,
+}
+
+impl Default for IntOverflow {
+    fn default() -> IntOverflow {
+        IntOverflow::Promote
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum ExecutionStrategy {
     /// Execute the script in a single thread. This is the default.
@@ -177,22 +197,140 @@ where
 }
 
 #[derive(Debug, Clone)]
-pub struct CompileError(pub String);
+pub struct CompileError {
+    pub msg: String,
+    /// The region of the program text responsible for the error, if it is known.
+    pub span: Option<Span>,
+    /// For errors raised while running a program, the AWK-level call stack at the point of the
+    /// error, innermost frame first.
+    pub trace: Vec<StackFrame>,
+}
+
+/// A function on the call stack when a runtime error occurred, along with the location of the
+/// statement it was executing.
+#[derive(Debug, Clone)]
+pub struct StackFrame {
+    pub func: String,
+    pub span: Option<Span>,
+}
+
+impl CompileError {
+    pub fn new(msg: impl Into<String>) -> CompileError {
+        CompileError {
+            msg: msg.into(),
+            span: None,
+            trace: Vec::new(),
+        }
+    }
+
+    /// Attribute the error to `span`, unless a more precise location has already been attached.
+    pub fn with_span(mut self, span: Span) -> CompileError {
+        if self.span.is_none() {
+            self.span = Some(span);
+        }
+        self
+    }
+}
 
 impl std::fmt::Display for CompileError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        match &self.span {
+            Some((start, _)) => write!(f, "{}: {}", start, self.msg),
+            None => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+/// The text of a program, along with the file it was read from (for programs passed with -f).
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Source<'a> {
+    pub text: &'a str,
+    pub file: Option<&'a str>,
+}
+
+impl<'a> Source<'a> {
+    /// Render `e` along with the line of the program it refers to, with the offending region
+    /// underlined:
+    ///
+    /// ```text
+    /// unexpected token `;`
+    ///   --> prog.awk:1:13
+    ///   |
+    /// 1 | BEGIN { x = ; }
+    ///   |             ^
+    /// ```
+    ///
+    /// Errors without a span, or with a span that does not point into the text, are rendered as
+    /// their message alone. Runtime errors are followed by the call stack at the point of the
+    /// error.
+    pub fn render(&self, e: &CompileError) -> String {
+        let mut res = self.snippet(e);
+        if e.trace.len() > 0 {
+            res.push_str("\nstack trace:");
+            for frame in e.trace.iter() {
+                res.push_str("\n    in ");
+                res.push_str(frame.func.as_str());
+                if let Some((start, _)) = &frame.span {
+                    res.push_str(" at ");
+                    res.push_str(self.loc(start).as_str());
+                }
+            }
+        }
+        res
+    }
+
+    fn loc(&self, l: &Loc) -> String {
+        match self.file {
+            Some(file) => format!("{}:{}:{}", file, l.line + 1, l.col + 1),
+            None => format!("{}", l),
+        }
+    }
+
+    fn snippet(&self, e: &CompileError) -> String {
+        let (start, end) = match &e.span {
+            Some(span) => span,
+            None => return e.msg.clone(),
+        };
+        let line = match self.text.lines().nth(start.line) {
+            Some(line) if line.is_char_boundary(start.col.min(line.len())) => line,
+            _ => return e.to_string(),
+        };
+        let col = start.col.min(line.len());
+        let indent: String = line[..col]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = if end.line == start.line
+            && end.col > col
+            && line.is_char_boundary(end.col.min(line.len()))
+        {
+            line[col..end.col.min(line.len())].chars().count().max(1)
+        } else {
+            1
+        };
+        let line_no = (start.line + 1).to_string();
+        let gutter: String = line_no.chars().map(|_| ' ').collect();
+        format!(
+            "{msg}\n{g} --> {loc}\n{g} |\n{n} | {line}\n{g} | {indent}{carets}",
+            msg = e.msg,
+            g = gutter,
+            loc = self.loc(start),
+            n = line_no,
+            line = line,
+            indent = indent,
+            carets = "^".repeat(width),
+        )
     }
 }
 
 macro_rules! err {
     ($head:expr) => {
-        Err($crate::common::CompileError(
+        Err($crate::common::CompileError::new(
                 format!(concat!("[", file!(), ":", line!(), ":", column!(), "] ", $head))
         ))
     };
     ($head:expr, $($t:expr),+) => {
-        Err($crate::common::CompileError(
+        Err($crate::common::CompileError::new(
                 format!(concat!("[", file!(), ":", line!(), ":", column!(), "] ", $head), $($t),*)
         ))
     };
@@ -356,6 +494,56 @@ pub(crate) fn traverse<T>(o: Option<Result<T>>) -> Result<Option<T>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::arena::Arena;
+    use crate::parsing::{parse_error, syntax::ProgParser};
+    use crate::{ast, cfg, lexer, types};
+
+    #[test]
+    fn error_locations() {
+        fn parse<'a>(
+            a: &'a Arena,
+            text: &'a str,
+        ) -> std::result::Result<&'a ast::Prog<'a, 'a, &'a str>, CompileError> {
+            let mut buf = Vec::new();
+            let mut prog = ast::Prog::from_stage(Stage::Main(()));
+            ProgParser::new()
+                .parse(a, &mut buf, &mut prog, lexer::Tokenizer::new(text))
+                .map_err(parse_error)?;
+            Ok(a.alloc_v(prog))
+        }
+        let a = Arena::default();
+
+        // Syntax errors point at the offending token.
+        let text = "BEGIN {\n\tx = ;\n}";
+        let e = parse(&a, text).err().expect("program should not parse");
+        let rendered = Source {
+            text,
+            file: Some("prog.awk"),
+        }
+        .render(&e);
+        assert!(rendered.starts_with("unexpected token `;`"), "{}", rendered);
+        assert!(
+            rendered.ends_with(" --> prog.awk:2:6\n  |\n2 | \tx = ;\n  | \t    ^"),
+            "{}",
+            rendered
+        );
+
+        // Type errors point at the statement or function signature that introduced one of the
+        // conflicting variables.
+        let text = "function f(a) { return a[1]; }\nBEGIN { x = 1;\n f(x); }";
+        let ctx =
+            cfg::ProgramContext::from_prog(&a, parse(&a, text).unwrap(), cfg::Escaper::Identity)
+                .unwrap();
+        let e = types::get_types(&ctx)
+            .err()
+            .expect("program should not type-check");
+        let (start, _) = e.span.expect("type error should have a location");
+        assert!(start.line < 2, "{:?}", start);
+
+        // Errors without a location are rendered as-is.
+        let e = CompileError::new("no location");
+        assert_eq!(Source { text, file: None }.render(&e), "no location");
+    }
 
     fn get_elems<T: Clone + Hash + Eq>(wl: &mut WorkList<T>) -> HashSet<T> {
         let mut res = HashSet::default();
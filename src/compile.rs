@@ -6,17 +6,21 @@ use crate::codegen;
 use crate::codegen::llvm;
 use crate::common::{CompileError, Either, Graph, NodeIx, NumTy, Result, Stage, WorkList};
 use crate::cross_stage;
+use crate::escape::EscapeAnalysis;
 use crate::input_taint::TaintedStringAnalysis;
+use crate::lexer::Span;
+use crate::ownership;
 use crate::pushdown::{FieldSet, UsedFieldAnalysis};
 use crate::runtime::{self, Str};
 use crate::smallvec::{self, smallvec};
 use crate::string_constants::{self, StringConstantAnalysis};
 use crate::types;
 
+use crate::runtime::matcher::{Matcher, PatternSet};
 use hashbrown::{hash_map::Entry, HashMap, HashSet};
-use regex::bytes::Regex;
 
 use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::mem;
 use std::sync::Arc;
 
@@ -140,6 +144,13 @@ fn visit_used_fields<'a>(stmt: &Instr<'a>, cur_func_id: NumTy, ufa: &mut UsedFie
     }
 }
 
+fn visit_escape_analysis<'a>(stmt: &Instr<'a>, func_id: NumTy, ea: &mut EscapeAnalysis) {
+    match stmt {
+        Either::Left(ll) => ea.visit_ll(ll),
+        Either::Right(hl) => ea.visit_hl(func_id, hl),
+    }
+}
+
 fn visit_taint_analysis<'a>(stmt: &Instr<'a>, func_id: NumTy, tsa: &mut TaintedStringAnalysis) {
     match stmt {
         Either::Left(ll) => tsa.visit_ll(ll),
@@ -164,7 +175,207 @@ pub(crate) fn bytecode<'a, LR: runtime::LineReader>(
     ff: impl runtime::writers::FileFactory,
     num_workers: usize,
 ) -> Result<bytecode::Interp<'a, LR>> {
-    Typer::init_from_ctx(ctx)?.to_interp(reader, ff, num_workers)
+    let mut interp = bytecode_program(ctx)?.into_interp(reader, ff, num_workers);
+    interp.set_argv(&ctx.argv[..]);
+    interp.precompile_regexes(&ctx.regex_literals[..]);
+    interp.configure_input(&ctx.input);
+    Ok(interp)
+}
+
+pub(crate) fn bytecode_program<'a>(
+    ctx: &mut cfg::ProgramContext<'a, &'a str>,
+) -> Result<bytecode::Program<'a>> {
+    let mut typer = Typer::init_from_ctx(ctx)?;
+    let mut prog = typer.to_program()?;
+    let mut by_low = HashMap::<NumTy, (NumTy, Ty)>::new();
+    for (id, (reg, ty)) in typer.regs.globals.iter() {
+        if id.sub == 0 && !ty.is_iter() && *ty != Ty::Null {
+            by_low.insert(id.low, (*reg, *ty));
+        }
+    }
+    prog.globals = ctx
+        .named_idents()
+        .filter_map(|(name, id)| {
+            let (reg, ty) = by_low.get(&id.low)?;
+            Some((name.to_string(), *reg, *ty))
+        })
+        .collect();
+    prog.globals.sort_by(|(x, _, _), (y, _, _)| x.cmp(y));
+    Ok(prog)
+}
+
+/// Describe the types inferred for each named variable and each instantiation of a user-defined
+/// function.
+pub(crate) fn dump_types<'a>(ctx: &mut cfg::ProgramContext<'a, &'a str>) -> Result<String> {
+    use std::fmt::Write;
+    let typer = Typer::init_from_ctx(ctx)?;
+    let mut by_low = HashMap::<NumTy, Vec<Ty>>::new();
+    let locals = typer.frames.iter().flat_map(|f| f.locals.iter());
+    for (id, (_, ty)) in typer.regs.globals.iter().chain(locals) {
+        by_low.entry(id.low).or_insert_with(Vec::new).push(*ty);
+    }
+    let mut vars: Vec<_> = ctx
+        .named_idents()
+        .filter_map(|(name, id)| Some((*name, by_low.get(&id.low)?.clone())))
+        .collect();
+    vars.sort_by_key(|(name, _)| *name);
+    let mut res = String::from("variables:\n");
+    for (name, mut tys) in vars {
+        tys.sort_by_key(|ty| *ty as u32);
+        tys.dedup();
+        // Variables are null until they are first assigned.
+        if tys.len() > 1 {
+            tys.retain(|ty| *ty != Ty::Null);
+        }
+        let tys: Vec<_> = tys.iter().map(|ty| format!("{:?}", ty)).collect();
+        let _ = writeln!(&mut res, "    {}: {}", name, tys.join(" | "));
+    }
+    res.push_str("functions:\n");
+    for (frame, info) in typer.frames.iter().zip(typer.func_info.iter()) {
+        let func = &ctx.funcs[frame.src_function as usize];
+        match func.name {
+            cfg::FunctionName::Named(_) if frame.is_called => {}
+            _ => continue,
+        }
+        let args: Vec<_> = func
+            .args
+            .iter()
+            .zip(info.arg_tys.iter())
+            .map(|(arg, ty)| format!("{}: {:?}", arg.name, ty))
+            .collect();
+        let _ = writeln!(
+            &mut res,
+            "    {}({}) -> {:?}",
+            frame.name,
+            args.join(", "),
+            info.ret_ty
+        );
+    }
+    Ok(res)
+}
+
+/// The type of each named global variable, omitting variables that are never assigned a value.
+pub(crate) fn global_types<'a>(
+    ctx: &mut cfg::ProgramContext<'a, &'a str>,
+) -> Result<Vec<(&'a str, Ty)>> {
+    let typer = Typer::init_from_ctx(ctx)?;
+    let mut by_low = HashMap::<NumTy, Ty>::new();
+    // Globals only referenced from the main function are stored as locals of that function.
+    let locals = typer.frames.iter().flat_map(|f| f.locals.iter());
+    for (id, (_, ty)) in typer.regs.globals.iter().chain(locals) {
+        if *ty != Ty::Null {
+            by_low.insert(id.low, *ty);
+        }
+    }
+    let mut vars: Vec<_> = ctx
+        .named_idents()
+        .filter_map(|(name, id)| Some((*name, *by_low.get(&id.low)?)))
+        .collect();
+    vars.sort_by_key(|(name, _)| *name);
+    Ok(vars)
+}
+
+/// The comparisons in the program that are performed on strings once types have been inferred,
+/// along with the span of the statement each appears in. Each comparison is listed once, even if
+/// its function is compiled for several argument types.
+pub(crate) fn string_comparisons<'a>(
+    ctx: &mut cfg::ProgramContext<'a, &'a str>,
+) -> Result<Vec<(Option<Span>, crate::ast::Binop, PrimVal<'a>, PrimVal<'a>)>> {
+    use crate::ast::Binop::*;
+    let typer = Typer::init_from_ctx(ctx)?;
+    let mut seen = HashSet::new();
+    let mut res = Vec::new();
+    for frame in typer.frames.iter().filter(|f| f.is_called) {
+        let ty_of = |v: &PrimVal| match v {
+            PrimVal::ILit(_) => Ty::Int,
+            PrimVal::FLit(_) => Ty::Float,
+            PrimVal::StrLit(_) => Ty::Str,
+            PrimVal::Var(id) if id.is_global(&typer.local_globals) => {
+                typer.regs.globals.get(id).map_or(Ty::Null, |(_, ty)| *ty)
+            }
+            PrimVal::Var(id) => frame.locals.get(id).map_or(Ty::Null, |(_, ty)| *ty),
+        };
+        let func = &ctx.funcs[frame.src_function as usize];
+        for (i, n) in func.cfg.raw_nodes().iter().enumerate() {
+            for (j, (stmt, span)) in n.weight.q.iter().zip(n.weight.spans.iter()).enumerate() {
+                let exp = match stmt {
+                    PrimStmt::AsgnVar(_, exp)
+                    | PrimStmt::AsgnIndex(_, _, exp)
+                    | PrimStmt::SetBuiltin(_, exp) => exp,
+                    _ => continue,
+                };
+                let (op, l, r) = match exp {
+                    PrimExpr::CallBuiltin(builtins::Function::Binop(op), args)
+                        if matches!(op, LT | GT | LTE | GTE | EQ) && args.len() == 2 =>
+                    {
+                        (*op, &args[0], &args[1])
+                    }
+                    _ => continue,
+                };
+                let f = builtins::Function::Binop(op);
+                let (conv_tys, _) = f.type_sig(&[ty_of(l), ty_of(r)])?;
+                if conv_tys[0] == Ty::Str && seen.insert((frame.src_function, i, j)) {
+                    res.push((*span, op, l.clone(), r.clone()));
+                }
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Print the typed IR for each function: the form the program takes just before it is lowered to
+/// bytecode or handed to one of the JIT backends.
+pub(crate) fn dump_ir<'a>(ctx: &mut cfg::ProgramContext<'a, &'a str>) -> Result<String> {
+    use std::fmt::Write;
+    let typer = Typer::init_from_ctx(ctx)?;
+    let mut res = String::new();
+    for (i, frame) in typer.frames.iter().enumerate() {
+        if !frame.is_called {
+            continue;
+        }
+        let info = &typer.func_info[i];
+        let args: Vec<_> = frame
+            .arg_regs
+            .iter()
+            .zip(info.arg_tys.iter())
+            .map(|(reg, ty)| format!("{}:{:?}", reg, ty))
+            .collect();
+        let _ = writeln!(
+            &mut res,
+            "function {} ({}) ({}) -> {:?} {{",
+            i,
+            frame.name,
+            args.join(", "),
+            info.ret_ty
+        );
+        for (j, n) in frame.cfg.raw_nodes().iter().enumerate() {
+            let _ = writeln!(&mut res, "  bb{}:", j);
+            for inst in n.weight.insts.iter() {
+                let _ = match inst {
+                    Either::Left(ll) => writeln!(&mut res, "\t{:?}", ll),
+                    Either::Right(HighLevel::Loc(Some((start, _)))) => {
+                        writeln!(&mut res, "\t; {}", start)
+                    }
+                    Either::Right(hl) => writeln!(&mut res, "\t{:?}", hl),
+                };
+            }
+            let mut walker = frame.cfg.neighbors(NodeIx::new(j)).detach();
+            let mut edges = SmallVec::new();
+            while let Some(eix) = walker.next_edge(&frame.cfg) {
+                edges.push(eix)
+            }
+            edges.reverse();
+            for eix in edges.iter().cloned() {
+                let dst = frame.cfg.edge_endpoints(eix).unwrap().1.index();
+                let _ = match frame.cfg.edge_weight(eix).unwrap() {
+                    Some(reg) => writeln!(&mut res, "\tbr bb{} if {}:Int", dst, reg),
+                    None => writeln!(&mut res, "\tbr bb{}", dst),
+                };
+            }
+        }
+        res.push_str("}\n");
+    }
+    Ok(res)
 }
 
 #[cfg(test)]
@@ -178,6 +389,13 @@ pub(crate) fn used_fields<'a>(ctx: &mut cfg::ProgramContext<'a, &'a str>) -> Res
     Ok(Typer::init_from_ctx(ctx)?.used_fields)
 }
 
+#[cfg(test)]
+pub(crate) fn record_local_strs<'a>(
+    ctx: &mut cfg::ProgramContext<'a, &'a str>,
+) -> Result<Vec<bool>> {
+    Ok(Typer::init_from_ctx(ctx)?.record_local_strs)
+}
+
 #[cfg(feature = "llvm_backend")]
 pub(crate) fn dump_llvm<'a>(
     ctx: &mut cfg::ProgramContext<'a, &'a str>,
@@ -212,31 +430,103 @@ pub(crate) fn run_llvm<'a>(
     cfg: llvm::Config,
 ) -> Result<()> {
     use llvm::Generator;
+    let argv = ctx.argv.clone();
+    let regex_literals = ctx.regex_literals.clone();
+    let input = ctx.input.clone();
     let mut typer = Typer::init_from_ctx(ctx)?;
     let used_fields = typer.used_fields.clone();
     let named_cols = typer.named_columns.take();
     unsafe {
         let gen = Generator::init(&mut typer, cfg)?;
-        codegen::run_main(gen, reader, ff, &used_fields, named_cols, cfg.num_workers)
+        codegen::run_main(
+            gen,
+            reader,
+            ff,
+            &used_fields,
+            named_cols,
+            &argv[..],
+            &regex_literals[..],
+            &input,
+            cfg.num_workers,
+        )
+    }
+}
+
+/// Compile the program with LLVM and pass the addresses of its main functions and its `atexit`
+/// runner to `f`. The code is freed once `f` returns.
+#[cfg(feature = "llvm_backend")]
+pub(crate) fn with_llvm<'a, R>(
+    ctx: &mut cfg::ProgramContext<'a, &'a str>,
+    cfg: llvm::Config,
+    f: impl FnOnce(Stage<*const u8>, Option<*const u8>) -> R,
+) -> Result<R> {
+    use codegen::Jit;
+    let mut typer = Typer::init_from_ctx(ctx)?;
+    unsafe {
+        let mut gen = llvm::Generator::init(&mut typer, cfg)?;
+        let main = gen.main_pointers()?;
+        let atexit = gen.atexit_pointer();
+        Ok(f(main, atexit))
     }
 }
 
+/// Compile the program with cranelift and return the generated CLIF for each function.
+pub(crate) fn dump_clif<'a>(
+    ctx: &mut cfg::ProgramContext<'a, &'a str>,
+    cfg: codegen::Config,
+) -> Result<String> {
+    use codegen::clif::Generator;
+    let mut typer = Typer::init_from_ctx(ctx)?;
+    let mut res = String::new();
+    Generator::init_with(&mut typer, cfg, None, Some(&mut res))?;
+    Ok(res)
+}
+
 pub(crate) fn run_cranelift<'a>(
     ctx: &mut cfg::ProgramContext<'a, &'a str>,
     reader: impl codegen::intrinsics::IntoRuntime,
     ff: impl runtime::writers::FileFactory,
     cfg: codegen::Config,
+    cache: Option<&codegen::cache::JitCache>,
 ) -> Result<()> {
     use codegen::clif::Generator;
+    let argv = ctx.argv.clone();
+    let regex_literals = ctx.regex_literals.clone();
+    let input = ctx.input.clone();
     let mut typer = Typer::init_from_ctx(ctx)?;
     let used_fields = typer.used_fields.clone();
     let named_cols = typer.named_columns.take();
     unsafe {
-        let gen = Generator::init(&mut typer, cfg)?;
-        codegen::run_main(gen, reader, ff, &used_fields, named_cols, cfg.num_workers)
+        let gen = Generator::init(&mut typer, cfg, cache)?;
+        codegen::run_main(
+            gen,
+            reader,
+            ff,
+            &used_fields,
+            named_cols,
+            &argv[..],
+            &regex_literals[..],
+            &input,
+            cfg.num_workers,
+        )
     }
 }
 
+/// Like `with_llvm`, but compiling with cranelift.
+pub(crate) fn with_cranelift<'a, R>(
+    ctx: &mut cfg::ProgramContext<'a, &'a str>,
+    cfg: codegen::Config,
+    cache: Option<&codegen::cache::JitCache>,
+    f: impl FnOnce(Stage<*const u8>, Option<*const u8>) -> R,
+) -> Result<R> {
+    use codegen::{clif::Generator, Jit};
+    let mut typer = Typer::init_from_ctx(ctx)?;
+    let mut gen = Generator::init(&mut typer, cfg, cache)?;
+    let main = gen.main_pointers()?;
+    let atexit = gen.atexit_pointer();
+    Ok(f(main, atexit))
+}
+
 type SmallVec<T> = smallvec::SmallVec<[T; 2]>;
 
 #[derive(Debug)]
@@ -252,6 +542,9 @@ pub(crate) enum HighLevel {
     Ret(NumTy, Ty),
     Phi(NumTy, Ty, SmallVec<(NodeIx /*pred*/, NumTy /*register*/)>),
     DropIter(NumTy, Ty),
+    // Marks the following instructions as coming from the given statement in the source program,
+    // or, for None, as code frawk added to the program that has no location of its own.
+    Loc(Option<Span>),
 }
 
 #[derive(Default)]
@@ -335,11 +628,18 @@ pub(crate) struct Typer<'a> {
     pub func_info: Vec<FuncInfo>,
     pub frames: Vec<Frame<'a>>,
     pub main_offset: Stage<usize>,
+    // The function that calls the functions registered with `atexit`; see `ast::Prog::atexit_runner`.
+    pub atexit: Option<usize>,
+    // Whether the main loop may start in the interpreter and finish in compiled code; see
+    // `runtime::tiered`.
+    tiered: bool,
 
     // For projection pushdown
     used_fields: FieldSet,
     // The fields referenced by name via the FI builtin variable
     named_columns: Option<Vec<&'a [u8]>>,
+    // For allocating temporary strings from the record arena.
+    record_local_strs: Vec<bool>,
     // For rejecting suspcicious programs with commands.
     taint_analysis: Option<TaintedStringAnalysis>,
     // For analysis passes that introspect into the set of constant string values that will
@@ -383,6 +683,8 @@ pub(crate) struct FuncInfo {
 
 #[derive(Default)]
 pub(crate) struct Frame<'a> {
+    // The name of the function this frame was generated from, for use in debugging output.
+    pub name: String,
     src_function: NumTy,
     cur_ident: NumTy,
     entry: NodeIx,
@@ -391,6 +693,10 @@ pub(crate) struct Frame<'a> {
     pub arg_regs: SmallVec<NumTy>,
     pub cfg: CFG<'a>,
     pub is_called: bool,
+    // Copies into a phi node at the end of a predecessor block, keyed by the predecessor and the
+    // phi node's register, that can take their string source rather than copy it; see the
+    // `ownership` module.
+    pub owned_phi_srcs: HashSet<(NodeIx, NumTy)>,
 }
 
 impl<'a> Frame<'a> {
@@ -435,6 +741,9 @@ struct View<'a, 'b> {
     // The current basic block being filled; It'll be swaped into `frame.cfg` as we translate a
     // given function cfg.
     stream: &'b mut Node<'a>,
+    // Registers holding conversions of local variables to another type, computed so far in the
+    // current basic block. Keyed by (source register, source type, target type).
+    convs: HashMap<(u32, Ty, Ty), u32>,
 }
 
 fn pop_var<'a>(instrs: &mut Vec<LL<'a>>, reg: NumTy, ty: Ty) -> Result<()> {
@@ -486,7 +795,7 @@ fn mov<'a>(dst_reg: u32, src_reg: u32, ty: Ty) -> Result<Option<LL<'a>>> {
     Ok(Some(res))
 }
 
-fn accum<'a>(inst: &Instr<'a>, mut f: impl FnMut(NumTy, Ty)) {
+pub(crate) fn accum<'a>(inst: &Instr<'a>, mut f: impl FnMut(NumTy, Ty)) {
     use {Either::*, HighLevel::*};
     match inst {
         Left(ll) => ll.accum(f),
@@ -502,6 +811,7 @@ fn accum<'a>(inst: &Instr<'a>, mut f: impl FnMut(NumTy, Ty)) {
             }
         }
         Right(Ret(reg, ty)) | Right(Phi(reg, ty, _)) | Right(DropIter(reg, ty)) => f(*reg, *ty),
+        Right(Loc(_)) => {}
     }
 }
 
@@ -510,29 +820,37 @@ impl<'a> Typer<'a> {
         self.main_offset.clone()
     }
 
-    fn to_interp<LR: runtime::LineReader>(
-        &mut self,
-        reader: LR,
-        ff: impl runtime::writers::FileFactory,
-        num_workers: usize,
-    ) -> Result<bytecode::Interp<'a, LR>> {
-        let instrs = self.to_bytecode()?;
-        let cols = self.named_columns.take();
-        Ok(bytecode::Interp::new(
+    fn to_program(&mut self) -> Result<bytecode::Program<'a>> {
+        let (instrs, debug) = self.to_bytecode()?;
+        let mut regs = [0usize; NUM_TYPES];
+        for (i, count) in regs.iter_mut().enumerate() {
+            let ty = Ty::try_from(i as u32).expect("all type indexes should be valid");
+            *count = self.regs.stats.count(ty) as usize;
+        }
+        Ok(bytecode::Program {
             instrs,
-            self.stage(),
-            num_workers,
-            |ty| self.regs.stats.count(ty) as usize,
-            reader,
-            ff,
-            &self.used_fields,
-            cols,
-        ))
+            stage: self.stage(),
+            atexit: self.atexit,
+            regs,
+            used_fields: self.used_fields.clone(),
+            named_columns: self.named_columns.take(),
+            debug,
+            globals: Vec::new(),
+            record_local_strs: self.record_local_strs.clone(),
+        })
     }
 
     // At initialization time, we generate Either<LL, HL>, this function lowers the HL into LL.
-    fn to_bytecode(&mut self) -> Result<Vec<Vec<LL<'a>>>> {
+    fn to_bytecode(&mut self) -> Result<(Vec<Vec<LL<'a>>>, Vec<bytecode::DebugInfo>)> {
         let mut res = vec![vec![]; self.frames.len()];
+        let mut debug: Vec<bytecode::DebugInfo> = self
+            .frames
+            .iter()
+            .map(|frame| bytecode::DebugInfo {
+                name: frame.name.clone(),
+                lines: Vec::new(),
+            })
+            .collect();
         let ret_regs: Vec<_> = (0..self.frames.len())
             .map(|i| {
                 let ret_ty = self.func_info[i].ret_ty;
@@ -550,6 +868,7 @@ impl<'a> Typer<'a> {
                 continue;
             }
             let instrs = &mut res[i];
+            let lines = &mut debug[i].lines;
             bb_map.clear();
             bb_map.reserve(frame.cfg.node_count());
             jmps.clear();
@@ -646,6 +965,10 @@ impl<'a> Typer<'a> {
                         Either::Right(Phi(_, _, _)) => {}
                         // we do not explicitly drop iterators in the bytecode interpreter.
                         Either::Right(DropIter(_, _)) => {}
+                        Either::Right(Loc(span)) => match lines.last_mut() {
+                            Some((start, last)) if *start == instrs.len() => *last = *span,
+                            _ => lines.push((instrs.len(), *span)),
+                        },
                     }
                 }
 
@@ -656,7 +979,10 @@ impl<'a> Typer<'a> {
                         if let Either::Right(Phi(reg, ty, preds)) = stmt {
                             for (pred, src_reg) in preds.iter() {
                                 if pred == &ix {
-                                    if let Some(inst) = mov(*reg, *src_reg, *ty)? {
+                                    if *ty == Ty::Str && frame.owned_phi_srcs.contains(&(ix, *reg))
+                                    {
+                                        instrs.push(LL::TakeStr((*reg).into(), (*src_reg).into()));
+                                    } else if let Some(inst) = mov(*reg, *src_reg, *ty)? {
                                         instrs.push(inst);
                                     }
                                     break;
@@ -694,7 +1020,7 @@ impl<'a> Typer<'a> {
                 }
             }
         }
-        Ok(res)
+        Ok((res, debug))
     }
 
     fn init_from_ctx(pc: &mut ProgramContext<'a, &'a str>) -> Result<Typer<'a>> {
@@ -722,6 +1048,7 @@ impl<'a> Typer<'a> {
                     let res = gen.frames.len() as NumTy;
                     $v.insert(res);
                     let mut f = Frame::default();
+                    f.name = pc.funcs[$func_id as usize].name.to_string();
                     f.src_function = $func_id;
                     f.cur_ident = res;
                     gen.frames.push(f);
@@ -772,7 +1099,12 @@ impl<'a> Typer<'a> {
         gen.main_offset = pc
             .main_stage()
             .map_ref(|o| gen.id_map[&(*o as NumTy, Default::default())] as usize);
+        gen.atexit = pc
+            .atexit
+            .and_then(|o| gen.id_map.get(&(o as NumTy, Default::default())))
+            .map(|o| *o as usize);
         gen.local_globals = local_globals;
+        gen.tiered = pc.tiered;
         for frame in gen.frames.iter_mut() {
             let src_func = frame.src_function as usize;
             let mut stream = Default::default();
@@ -785,6 +1117,7 @@ impl<'a> Typer<'a> {
                 local_globals: &gen.local_globals,
                 func_info: &gen.func_info,
                 stream: &mut stream,
+                convs: Default::default(),
             }
             .process_function(&pc.funcs[src_func])?;
         }
@@ -792,17 +1125,20 @@ impl<'a> Typer<'a> {
         gen.run_analyses()?;
         gen.mark_used_frames();
         gen.add_slots()?;
+        gen.elide_copies();
         Ok(gen)
     }
 
     fn run_analyses(&mut self) -> Result<()> {
         let mut ufa = UsedFieldAnalysis::default();
+        let mut ea = EscapeAnalysis::default();
         let mut refs = SmallVec::new();
         for (fix, frame) in self.frames.iter().enumerate() {
             for (bbix, bb) in frame.cfg.raw_nodes().iter().enumerate() {
                 for (stmtix, stmt) in bb.weight.insts.iter().enumerate() {
                     // not tracking function calls
                     visit_used_fields(stmt, frame.cur_ident, &mut ufa);
+                    visit_escape_analysis(stmt, frame.cur_ident, &mut ea);
                     if let Some(tsa) = &mut self.taint_analysis {
                         visit_taint_analysis(stmt, frame.cur_ident, tsa)
                     }
@@ -820,6 +1156,17 @@ impl<'a> Typer<'a> {
             }
         }
         self.used_fields = ufa.solve();
+        if runtime::watchdog::enabled() {
+            // --record-timeout reports the whole record.
+            self.used_fields.set(0);
+        }
+        let n_strs = self.regs.stats.count(Ty::Str);
+        for reg in 0..n_strs {
+            if let RegStatus::Global = self.regs.stats.get_status(reg, Ty::Str) {
+                ea.escapes(reg);
+            }
+        }
+        self.record_local_strs = ea.solve(n_strs as usize);
         if let Some(tsa) = &mut self.taint_analysis {
             if !tsa.ok() {
                 return err!("command potentially containing interpolated user input detected.\nIf this is a false positive, you can pass the -A flag to bypass this check.");
@@ -836,10 +1183,10 @@ impl<'a> Typer<'a> {
                         continue;
                     }
                     let text = std::str::from_utf8(&strs[0]).map_err(|e| {
-                        CompileError(format!("regex patterns must be valid UTF-8: {}", e))
+                        CompileError::new(format!("regex patterns must be valid UTF-8: {}", e))
                     })?;
-                    let re = Arc::new(Regex::new(text).map_err(|err| {
-                        CompileError(format!("regex parse error during compilation: {}", err))
+                    let re = Arc::new(Matcher::new(text).map_err(|err| {
+                        CompileError::new(format!("regex parse error during compilation: {}", err))
                     })?);
                     let inst = self.frames[frame]
                         .cfg
@@ -875,9 +1222,18 @@ impl<'a> Typer<'a> {
         Ok(())
     }
 
+    fn elide_copies(&mut self) {
+        let stats = &self.regs.stats;
+        for frame in self.frames.iter_mut() {
+            ownership::elide_copies(frame, |reg| {
+                matches!(stats.get_status(reg, Ty::Str), RegStatus::Local)
+            });
+        }
+    }
+
     fn mark_used_frames(&mut self) {
         use petgraph::visit::Dfs;
-        for offset in self.main_offset.iter() {
+        for offset in self.main_offset.iter().chain(self.atexit.iter()) {
             let mut dfs = Dfs::new(&self.callgraph, NodeIx::new(*offset));
             while let Some(ix) = dfs.next(&self.callgraph) {
                 self.frames[ix.index()].is_called = true;
@@ -896,19 +1252,38 @@ impl<'a> Typer<'a> {
             } => (begin, main_loop, end),
         };
         let global_refs = self.get_global_refs();
-        let slots = compute_slots(&begin, &main_loop, &end, global_refs);
+        let slots = compute_slots(&begin, &main_loop, &end, global_refs, self.tiered);
         let mut ctr = SlotCounter::default();
+        if self.tiered {
+            // The bytecode and the compiled code for a tiered program are generated separately,
+            // but they share their slots. Number the slots in an order that only depends on the
+            // program, rather than on the order in which the sets above are iterated.
+            let idents: HashMap<(NumTy, Ty), Ident> = self
+                .regs
+                .globals
+                .iter()
+                .map(|(id, reg)| (*reg, *id))
+                .collect();
+            let mut regs: Vec<_> = slots.loop_loads.iter().cloned().collect();
+            regs.sort_by_key(|reg| {
+                let id = idents.get(reg);
+                (id.map(|id| (id.low, id.sub)), reg.1 as u32)
+            });
+            for reg in regs {
+                ctr.get_slot(reg);
+            }
+        }
 
         // Begin stores the context of begin_stores
         if let Some(off) = begin {
             self.frames[off].store_slots(slots.begin_stores.iter().cloned(), &mut ctr)?;
         }
         if let Some(off) = main_loop {
-            self.frames[off].load_slots(slots.begin_stores.iter().cloned(), &mut ctr)?;
+            self.frames[off].load_slots(slots.loop_loads.iter().cloned(), &mut ctr)?;
             self.frames[off].store_slots(slots.loop_stores.iter().cloned(), &mut ctr)?;
         }
         if let Some(off) = end {
-            self.frames[off].load_slots(slots.loop_stores.iter().cloned(), &mut ctr)?;
+            self.frames[off].load_slots(slots.end_loads.iter().cloned(), &mut ctr)?;
         }
 
         Ok(())
@@ -1006,7 +1381,17 @@ impl<'a, 'b> View<'a, 'b> {
         }
         // Fill them in.
         for (i, n) in func.cfg.raw_nodes().iter().enumerate() {
-            for stmt in n.weight.q.iter() {
+            self.convs.clear();
+            // Statements without a span (such as the read at the top of the main loop) are marked
+            // too, so that errors they raise are not blamed on whatever statement came before.
+            // Phis must stay at the top of the block, and have no location of their own.
+            let mut last_span = None;
+            for (stmt, span) in n.weight.q.iter().zip(n.weight.spans.iter()) {
+                let is_phi = matches!(stmt, PrimStmt::AsgnVar(_, PrimExpr::Phi(_)));
+                if !is_phi && last_span != Some(*span) {
+                    self.pushr(HighLevel::Loc(*span));
+                    last_span = Some(*span);
+                }
                 self.stmt(stmt)?;
             }
             let ix = NodeIx::new(i);
@@ -1089,6 +1474,8 @@ impl<'a, 'b> View<'a, 'b> {
 
     fn pushr(&mut self, i: HighLevel) {
         if let HighLevel::Call { func_id, .. } = i {
+            // Temporary registers are not saved across calls.
+            self.convs.clear();
             // We do not annotate the edges in the callgraph with call sites or anything, so we
             // only need one edge between each node.
             // NB we can only do this here because calls to pushr always have `stream` matching
@@ -1132,6 +1519,40 @@ impl<'a, 'b> View<'a, 'b> {
         }
     }
 
+    // Store a constant into a new register of type `ty`. Conversions that do not depend on the
+    // state of the program (like `CONVFMT`) are performed ahead of time.
+    fn const_reg(&mut self, v: &PrimVal<'a>, ty: Ty) -> Result<u32> {
+        use PrimVal::*;
+        // Strings that do not look like numbers are converted at runtime, where `--strict` can
+        // report them.
+        let numeric =
+            |s: &[u8]| s.iter().all(u8::is_ascii_whitespace) || runtime::compat::looks_numeric(s);
+        let runtime_conv = match (v, ty) {
+            (Var(_), _) | (FLit(_), Ty::Str) | (_, Ty::Null) => true,
+            (StrLit(s), Ty::Int) | (StrLit(s), Ty::Float) => !numeric(s),
+            _ => false,
+        };
+        if runtime_conv {
+            let (reg, from_ty) = self.get_reg(v)?;
+            return self.ensure_ty(reg, from_ty, ty);
+        }
+        let reg = self.regs.stats.new_reg(ty, RegStatus::Local);
+        self.pushl(match (v, ty) {
+            (ILit(i), Ty::Float) => LL::StoreConstFloat(reg.into(), *i as f64),
+            (ILit(i), Ty::Str) => LL::StoreConstStr(reg.into(), Str::from(*i).into()),
+            (FLit(f), Ty::Int) => LL::StoreConstInt(reg.into(), runtime::convert(*f)),
+            (FLit(f), _) => LL::StoreConstFloat(reg.into(), *f),
+            (StrLit(s), Ty::Int) => LL::StoreConstInt(reg.into(), runtime::convert(&Str::from(*s))),
+            (StrLit(s), Ty::Float) => {
+                LL::StoreConstFloat(reg.into(), runtime::convert(&Str::from(*s)))
+            }
+            (StrLit(s), _) => LL::StoreConstStr(reg.into(), Str::from(*s).into()),
+            (ILit(i), _) => LL::StoreConstInt(reg.into(), *i),
+            (Var(_), _) => unreachable!(),
+        });
+        Ok(reg)
+    }
+
     fn ensure_ty(&mut self, reg: u32, from_ty: Ty, to_ty: Ty) -> Result<u32> {
         if from_ty == to_ty {
             return Ok(reg);
@@ -1188,6 +1609,9 @@ impl<'a, 'b> View<'a, 'b> {
 
     // Store values into a register at a given type, converting if necessary.
     fn store(&mut self, dst_reg: u32, dst_ty: Ty, src: &PrimVal<'a>) -> Result<()> {
+        if dst_reg == UNUSED {
+            return Ok(());
+        }
         match src {
             PrimVal::Var(id) => {
                 let (src_reg, src_ty) = self.reg_of_ident(id);
@@ -1271,6 +1695,41 @@ impl<'a, 'b> View<'a, 'b> {
         self.convert(dst_reg, dst_ty, load_reg, arr_val_ty)
     }
 
+    // MatchSet's patterns are compiled into the instruction itself, rather than being loaded into
+    // registers.
+    fn match_set(
+        &mut self,
+        dst_reg: u32,
+        dst_ty: Ty,
+        args: &cfg::SmallVec<PrimVal<'a>>,
+    ) -> Result<()> {
+        if dst_reg == UNUSED {
+            return Ok(());
+        }
+        let (s_reg, s_ty) = self.get_reg(&args[0])?;
+        let s_reg = self.ensure_ty(s_reg, s_ty, Ty::Str)?;
+        let mut pats = Vec::with_capacity(args.len() - 1);
+        for arg in &args[1..] {
+            let pat = match arg {
+                PrimVal::StrLit(s) => std::str::from_utf8(s).map_err(|e| {
+                    CompileError::new(format!("regex patterns must be valid UTF-8: {}", e))
+                })?,
+                _ => return err!("non-constant pattern passed to match-set: {:?}", arg),
+            };
+            pats.push(pat.to_string());
+        }
+        let set = Arc::new(PatternSet::new(pats).map_err(|err| {
+            CompileError::new(format!("regex parse error during compilation: {}", err))
+        })?);
+        let res_reg = if dst_ty == Ty::Int {
+            dst_reg
+        } else {
+            self.regs.stats.reg_of_ty(Ty::Int)
+        };
+        self.pushl(LL::MatchSet(res_reg.into(), s_reg.into(), set));
+        self.convert(dst_reg, dst_ty, res_reg, Ty::Int)
+    }
+
     fn builtin(
         &mut self,
         dst_reg: u32,
@@ -1281,34 +1740,62 @@ impl<'a, 'b> View<'a, 'b> {
         use crate::ast::{Binop::*, Unop::*};
         use builtins::Function::*;
 
+        if let MatchSet = bf {
+            return self.match_set(dst_reg, dst_ty, args);
+        }
+
         // Compile the argument values
         let mut args_regs = cfg::SmallVec::with_capacity(args.len());
         let mut args_tys = cfg::SmallVec::with_capacity(args.len());
+        let mut args_local = cfg::SmallVec::with_capacity(args.len());
         for arg in args.iter() {
-            let (reg, ty) = self.get_reg(arg)?;
+            // Constants are stored below, once we know which type they are needed at.
+            let (reg, ty, status) = match arg {
+                PrimVal::ILit(_) => (UNUSED, Ty::Int, RegStatus::Local),
+                PrimVal::FLit(_) => (UNUSED, Ty::Float, RegStatus::Local),
+                PrimVal::StrLit(_) => (UNUSED, Ty::Str, RegStatus::Local),
+                PrimVal::Var(_) => self.get_reg_status(arg)?,
+            };
             args_regs.push(reg);
             args_tys.push(ty);
+            args_local.push(matches!((arg, status), (PrimVal::Var(_), RegStatus::Local)));
         }
 
         // Now, perform any necessary conversions if input types do not match the argument types.
         let mut conv_regs: cfg::SmallVec<_> = smallvec![UNUSED; args.len()];
         let (conv_tys, res_ty) = bf.type_sig(&args_tys[..])?;
 
-        for (areg, (aty, (creg, cty))) in args_regs.iter().cloned().zip(
-            args_tys
-                .iter()
-                .cloned()
-                .zip(conv_regs.iter_mut().zip(conv_tys.iter().cloned())),
-        ) {
-            if aty == cty {
+        for (i, (areg, (aty, (creg, cty)))) in args_regs
+            .iter()
+            .cloned()
+            .zip(
+                args_tys
+                    .iter()
+                    .cloned()
+                    .zip(conv_regs.iter_mut().zip(conv_tys.iter().cloned())),
+            )
+            .enumerate()
+        {
+            if !matches!(args[i], PrimVal::Var(_)) {
+                *creg = self.const_reg(&args[i], cty)?;
+            } else if aty == cty {
                 *creg = areg;
+            } else if let Some(reg) = self.convs.get(&(areg, aty, cty)) {
+                *creg = *reg;
             } else {
                 let reg = self.regs.stats.reg_of_ty(cty);
 
                 self.convert(reg, cty, areg, aty)?;
+                if args_local[i] {
+                    self.convs.insert((areg, aty, cty), reg);
+                }
                 *creg = reg;
             }
         }
+        if let Sub | GSub = bf {
+            // The last argument is modified in place.
+            self.convs.clear();
+        }
 
         let mut res_reg = if dst_ty == res_ty {
             dst_reg
@@ -1389,6 +1876,14 @@ impl<'a, 'b> View<'a, 'b> {
             }
             Match => gen_op!(Match, [Str, Match]),
             SubstrIndex => gen_op!(SubstrIndex, [Str, SubstrIndex]),
+            Levenshtein => gen_op!(Levenshtein, [Str, Levenshtein]),
+            JaroWinkler => gen_op!(JaroWinkler, [Str, JaroWinkler]),
+            CmpNatural => gen_op!(CmpNatural, [Str, CmpNatural]),
+            CmpLocale => gen_op!(CmpLocale, [Str, CmpLocale]),
+            Trim => gen_op!(Trim, [Str, Trim]),
+            LTrim => gen_op!(LTrim, [Str, LTrim]),
+            RTrim => gen_op!(RTrim, [Str, RTrim]),
+            Color => gen_op!(Color, [Str, Color]),
             Contains => {
                 if res_reg != UNUSED {
                     match conv_tys[0] {
@@ -1411,6 +1906,17 @@ impl<'a, 'b> View<'a, 'b> {
             }
             UpdateUsedFields => self.pushl(LL::UpdateUsedFields()),
             SetFI => self.pushl(LL::SetFI(conv_regs[0].into(), conv_regs[1].into())),
+            MarkInit | CheckInit => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(if let MarkInit = bf {
+                    LL::MarkInit(res_reg.into(), conv_regs[0].into())
+                } else {
+                    LL::CheckInit(res_reg.into(), conv_regs[0].into(), conv_regs[1].into())
+                })
+            }
+            MatchSet => return err!("match-set is handled before its arguments are compiled"),
             System => {
                 if res_reg == UNUSED {
                     res_reg = self.regs.stats.reg_of_ty(res_ty);
@@ -1451,7 +1957,43 @@ impl<'a, 'b> View<'a, 'b> {
                 }
             }
             NextlineStdin => self.pushl(LL::NextLineStdin(res_reg.into())),
-            ReadLineStdinFused => self.pushl(LL::NextLineStdinFused()),
+            RecordFile => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::RecordFile(res_reg.into()))
+                }
+            }
+            RecordOffset => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::RecordOffset(res_reg.into()))
+                }
+            }
+            RecordText => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::RecordText(res_reg.into()))
+                }
+            }
+            RoundTripSep => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::RoundTripSep(
+                    res_reg.into(),
+                    conv_regs[0].into(),
+                    conv_regs[1].into(),
+                ))
+            }
+            ArgvAssign => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::ArgvAssign(res_reg.into()))
+            }
+            ReadLineStdinFused => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::NextLineStdinFused(res_reg.into()))
+            }
             NextFile => self.pushl(LL::NextFile()),
             Setcol => self.pushl(LL::SetColumn(conv_regs[0].into(), conv_regs[1].into())),
             Sub => {
@@ -1486,6 +2028,16 @@ impl<'a, 'b> View<'a, 'b> {
                     self.pushl(LL::EscapeTSV(res_reg.into(), conv_regs[0].into()))
                 }
             }
+            Squeeze => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::Squeeze(res_reg.into(), conv_regs[0].into()))
+                }
+            }
+            Bold => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::Bold(res_reg.into(), conv_regs[0].into()))
+                }
+            }
             Substr => {
                 if res_reg != UNUSED {
                     self.pushl(LL::Substr(
@@ -1496,12 +2048,75 @@ impl<'a, 'b> View<'a, 'b> {
                     ))
                 }
             }
+            LPad | RPad => {
+                if res_reg != UNUSED {
+                    let (res, s, width, with) = (
+                        res_reg.into(),
+                        conv_regs[0].into(),
+                        conv_regs[1].into(),
+                        conv_regs[2].into(),
+                    );
+                    self.pushl(if let LPad = bf {
+                        LL::LPad(res, s, width, with)
+                    } else {
+                        LL::RPad(res, s, width, with)
+                    })
+                }
+            }
+            ZFill => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::ZFill(
+                        res_reg.into(),
+                        conv_regs[0].into(),
+                        conv_regs[1].into(),
+                    ))
+                }
+            }
+            GraphemeSubstr => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::GraphemeSubstr(
+                        res_reg.into(),
+                        conv_regs[0].into(),
+                        conv_regs[1].into(),
+                        conv_regs[2].into(),
+                    ))
+                }
+            }
+            GraphemeLength => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::GraphemeLen(res_reg.into(), conv_regs[0].into()))
+                }
+            }
+            Graphemes => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::Graphemes(
+                    res_reg.into(),
+                    conv_regs[0].into(),
+                    conv_regs[1].into(),
+                ))
+            }
             ToInt => self.convert(res_reg, Ty::Int, conv_regs[0], conv_tys[0])?,
             HexToInt => {
                 if res_reg != UNUSED {
                     self.pushl(LL::HexStrToInt(res_reg.into(), conv_regs[0].into()))
                 }
             }
+            IsNA => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::IsNA(res_reg.into(), conv_regs[0].into()))
+                }
+            }
+            NumOr => {
+                if res_reg != UNUSED {
+                    self.pushl(LL::NumOr(
+                        res_reg.into(),
+                        conv_regs[0].into(),
+                        conv_regs[1].into(),
+                    ))
+                }
+            }
             Rand => {
                 if res_reg == UNUSED {
                     res_reg = self.regs.stats.reg_of_ty(res_ty);
@@ -1542,6 +2157,221 @@ impl<'a, 'b> View<'a, 'b> {
                     return err!("invalid input types to split: {:?}", &conv_tys[..]);
                 })
             }
+            IntDiv | DivMod => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                let (num, den, arr) = (conv_regs[0].into(), conv_regs[1].into(), conv_regs[2]);
+                self.pushl(if let IntDiv = bf {
+                    LL::IntDiv(res_reg.into(), num, den, arr.into())
+                } else {
+                    LL::DivMod(res_reg.into(), num, den, arr.into())
+                })
+            }
+            HllAdd | HllCount => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(if let HllAdd = bf {
+                    LL::HllAdd(res_reg.into(), conv_regs[0].into(), conv_regs[1].into())
+                } else {
+                    LL::HllCount(res_reg.into(), conv_regs[0].into())
+                })
+            }
+            BloomAdd | BloomHas => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                let (b, x) = (conv_regs[0].into(), conv_regs[1].into());
+                self.pushl(if let BloomAdd = bf {
+                    LL::BloomAdd(
+                        res_reg.into(),
+                        b,
+                        x,
+                        conv_regs[2].into(),
+                        conv_regs[3].into(),
+                    )
+                } else {
+                    LL::BloomHas(res_reg.into(), b, x)
+                })
+            }
+            Reservoir => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::Reservoir(
+                    res_reg.into(),
+                    conv_regs[0].into(),
+                    conv_regs[1].into(),
+                    conv_regs[2].into(),
+                ))
+            }
+            WinPush => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::WinPush(
+                    res_reg.into(),
+                    conv_regs[0].into(),
+                    conv_regs[1].into(),
+                    conv_regs[2].into(),
+                ))
+            }
+            WinSum | WinAvg | WinMax => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                let (res, w) = (res_reg.into(), conv_regs[0].into());
+                self.pushl(match bf {
+                    WinSum => LL::WinSum(res, w),
+                    WinAvg => LL::WinAvg(res, w),
+                    _ => LL::WinMax(res, w),
+                })
+            }
+            HistAdd | HistFormat => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(if let HistAdd = bf {
+                    LL::HistAdd(
+                        res_reg.into(),
+                        conv_regs[0].into(),
+                        conv_regs[1].into(),
+                        conv_regs[2].into(),
+                        conv_regs[3].into(),
+                    )
+                } else {
+                    LL::HistFormat(res_reg.into(), conv_regs[0].into())
+                })
+            }
+            VecDot | VecNorm => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(if let VecDot = bf {
+                    LL::VecDot(res_reg.into(), conv_regs[0].into(), conv_regs[1].into())
+                } else {
+                    LL::VecNorm(res_reg.into(), conv_regs[0].into())
+                })
+            }
+            VecAdd | VecSub | VecMul | VecScale => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                let (res, dst, a) = (res_reg.into(), conv_regs[0].into(), conv_regs[1].into());
+                self.pushl(match bf {
+                    VecAdd => LL::VecAdd(res, dst, a, conv_regs[2].into()),
+                    VecSub => LL::VecSub(res, dst, a, conv_regs[2].into()),
+                    VecMul => LL::VecMul(res, dst, a, conv_regs[2].into()),
+                    _ => LL::VecScale(res, dst, a, conv_regs[2].into()),
+                })
+            }
+            Readable => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::Readable(
+                    res_reg.into(),
+                    conv_regs[0].into(),
+                    conv_regs[1].into(),
+                ))
+            }
+            SetTimeout => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::SetTimeout(
+                    res_reg.into(),
+                    conv_regs[0].into(),
+                    conv_regs[1].into(),
+                ))
+            }
+            Getenv | Setenv | Stat | Glob => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                let (a, b) = (conv_regs[0], conv_regs[1]);
+                self.pushl(match bf {
+                    Getenv => LL::Getenv(res_reg.into(), a.into(), b.into()),
+                    Setenv => LL::Setenv(res_reg.into(), a.into(), b.into()),
+                    Stat => LL::Stat(res_reg.into(), a.into(), b.into()),
+                    _ => LL::Glob(res_reg.into(), a.into(), b.into()),
+                })
+            }
+            Mkdirp | Rename | RmFile => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(match bf {
+                    Mkdirp => LL::Mkdirp(res_reg.into(), conv_regs[0].into()),
+                    Rename => LL::Rename(res_reg.into(), conv_regs[0].into(), conv_regs[1].into()),
+                    _ => LL::RmFile(res_reg.into(), conv_regs[0].into()),
+                })
+            }
+            Sleep => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::Sleep(res_reg.into(), conv_regs[0].into()))
+            }
+            Monotime => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::Monotime(res_reg.into()))
+            }
+            Signal => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::Signal(res_reg.into()))
+            }
+            SetExit => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::SetExit(res_reg.into(), conv_regs[0].into()))
+            }
+            SetMatched => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::SetMatched(res_reg.into()))
+            }
+            Exiting => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::Exiting(res_reg.into()))
+            }
+            TierUp => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::TierUp(res_reg.into()))
+            }
+            SetExiting => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(LL::SetExiting(res_reg.into(), conv_regs[0].into()))
+            }
+            Spawn | ReadLine | WriteLine | Wait | ReadCmd => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                self.pushl(match bf {
+                    Spawn => LL::Spawn(res_reg.into(), conv_regs[0].into()),
+                    ReadLine => {
+                        LL::ReadLine(res_reg.into(), conv_regs[0].into(), conv_regs[1].into())
+                    }
+                    WriteLine => {
+                        LL::WriteLine(res_reg.into(), conv_regs[0].into(), conv_regs[1].into())
+                    }
+                    Wait => LL::Wait(res_reg.into(), conv_regs[0].into()),
+                    _ => LL::ReadCmd(res_reg.into(), conv_regs[0].into()),
+                })
+            }
             Length => {
                 if res_reg != UNUSED {
                     self.pushl(match conv_tys[0] {
@@ -1574,6 +2404,55 @@ impl<'a, 'b> View<'a, 'b> {
                 }),
                 _ => return err!("incorrect parameter types for Delete: {:?}", &conv_tys[..]),
             },
+            Bound => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                match &conv_tys[0] {
+                    Ty::MapIntInt
+                    | Ty::MapIntStr
+                    | Ty::MapIntFloat
+                    | Ty::MapStrInt
+                    | Ty::MapStrStr
+                    | Ty::MapStrFloat => self.pushl(LL::Bound {
+                        map_ty: conv_tys[0],
+                        dst: res_reg,
+                        map: conv_regs[0],
+                        size: conv_regs[1],
+                        policy: conv_regs[2],
+                    }),
+                    _ => return err!("incorrect parameter types for bound: {:?}", &conv_tys[..]),
+                }
+            }
+            LoadState | SaveState => {
+                if res_reg == UNUSED {
+                    res_reg = self.regs.stats.reg_of_ty(res_ty);
+                }
+                let (map_ty, dst, map, name) = (conv_tys[0], res_reg, conv_regs[0], conv_regs[1]);
+                match map_ty {
+                    Ty::MapIntInt
+                    | Ty::MapIntStr
+                    | Ty::MapIntFloat
+                    | Ty::MapStrInt
+                    | Ty::MapStrStr
+                    | Ty::MapStrFloat => self.pushl(if *bf == LoadState {
+                        LL::LoadState {
+                            map_ty,
+                            dst,
+                            map,
+                            name,
+                        }
+                    } else {
+                        LL::SaveState {
+                            map_ty,
+                            dst,
+                            map,
+                            name,
+                        }
+                    }),
+                    _ => return err!("incorrect parameter types for {}: {:?}", bf, &conv_tys[..]),
+                }
+            }
             Close => {
                 self.pushl(LL::Close(conv_regs[0].into()));
                 assert_eq!(res_ty, Ty::Str);
@@ -1590,6 +2469,17 @@ impl<'a, 'b> View<'a, 'b> {
                     ))
                 }
             }
+            FSum | FMean => {
+                if res_reg != UNUSED {
+                    let (dst, start, end) =
+                        (res_reg.into(), conv_regs[0].into(), conv_regs[1].into());
+                    self.pushl(if let FSum = bf {
+                        LL::SumColumns(dst, start, end)
+                    } else {
+                        LL::MeanColumns(dst, start, end)
+                    })
+                }
+            }
             JoinTSV => {
                 if res_reg != UNUSED {
                     self.pushl(LL::JoinTSV(
@@ -1832,6 +2722,7 @@ impl<'a, 'b> View<'a, 'b> {
             PrimStmt::AsgnVar(id, pe) => {
                 let (dst_reg, dst_ty) = self.reg_of_ident(id);
                 self.expr(dst_reg, dst_ty, pe)?;
+                self.convs.retain(|(src_reg, _, _), _| *src_reg != dst_reg);
             }
             PrimStmt::SetBuiltin(v, pe) => {
                 let ty = Ty::from(*v);
@@ -0,0 +1,537 @@
+//! Constant folding and propagation over the SSA form of a function.
+//!
+//! This pass runs on each function once it has been converted to SSA form. It:
+//!
+//! * Replaces uses of local variables that are assigned a single constant with that constant.
+//!   This includes globals that are only referenced from the main loop, which are renamed like
+//!   locals.
+//! * Forwards string constants assigned to the remaining globals to later uses in the same basic
+//!   block, so long as no function call could have modified them in between. This covers scripts
+//!   like `BEGIN { sep = ","; FS = sep }`, which can then use the fast field-splitting paths.
+//! * Evaluates arithmetic, comparisons and concatenations whose operands are all constants.
+//! * Removes branches with constant conditions, along with any code that is no longer reachable
+//!   as a result.
+//!
+//! Replacements preserve types: a variable holding an integer constant is replaced with an
+//! integer literal, and so on. The exception is integer `+`, `-`, `*` and unary `-`, which produce
+//! floating-point numbers by default (see `runtime::arith`): constant operands still fold to an
+//! integer when that gives the same number, so that `-1` stays an integer.
+//! Operations whose result could differ from what the program would
+//! compute at runtime (integer overflow, division by zero, parsing numbers out of strings, or
+//! anything depending on `CONVFMT`) are left alone.
+use crate::arena::Arena;
+use crate::ast::{Binop, Unop};
+use crate::builtins::Function;
+use crate::cfg::{Ident, PrimExpr, PrimStmt, PrimVal, Transition, CFG};
+use crate::common::{NodeIx, NumTy};
+use crate::runtime;
+
+use hashbrown::{HashMap, HashSet};
+use petgraph::graph::EdgeIndex;
+use petgraph::Direction;
+
+/// Fold constants in `cfg`, returning which of its basic blocks are still reachable from `entry`.
+pub(crate) fn fold<'a, 'outer>(
+    cfg: &mut CFG<'a>,
+    entry: NodeIx,
+    exit: NodeIx,
+    local_globals: &HashSet<NumTy>,
+    arena: &'a Arena<'outer>,
+) -> Vec<bool> {
+    let mut folder = Folder {
+        consts: Default::default(),
+        clobbered: clobbered(cfg),
+        local_globals,
+        arena,
+    };
+    loop {
+        let mut changed = folder.propagate(cfg);
+        changed |= prune_branches(cfg);
+        let reachable = reachable(cfg, entry);
+        changed |= remove_unreachable(cfg, exit, &reachable);
+        prune_phis(cfg);
+        if !changed {
+            return reachable;
+        }
+    }
+}
+
+struct Folder<'a, 'b, 'outer> {
+    // Local variables known to hold a constant.
+    consts: HashMap<Ident, PrimVal<'a>>,
+    // Local variables that are not safe to replace: those with more than one definition, and
+    // those modified in place by sub or gsub.
+    clobbered: HashSet<Ident>,
+    local_globals: &'b HashSet<NumTy>,
+    arena: &'a Arena<'outer>,
+}
+
+// The variable modified in place by a call to sub or gsub.
+pub(crate) fn sub_target(e: &PrimExpr) -> Option<Ident> {
+    match e {
+        PrimExpr::CallBuiltin(Function::Sub, args)
+        | PrimExpr::CallBuiltin(Function::GSub, args) => match args.get(2) {
+            Some(PrimVal::Var(id)) => Some(*id),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub(crate) fn stmt_expr<'c, 'a>(stmt: &'c PrimStmt<'a>) -> Option<&'c PrimExpr<'a>> {
+    match stmt {
+        PrimStmt::AsgnVar(_, e) | PrimStmt::SetBuiltin(_, e) | PrimStmt::AsgnIndex(_, _, e) => {
+            Some(e)
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn stmt_expr_mut<'c, 'a>(stmt: &'c mut PrimStmt<'a>) -> Option<&'c mut PrimExpr<'a>> {
+    match stmt {
+        PrimStmt::AsgnVar(_, e) | PrimStmt::SetBuiltin(_, e) | PrimStmt::AsgnIndex(_, _, e) => {
+            Some(e)
+        }
+        _ => None,
+    }
+}
+
+// Apply `f` to every value read by `e`. The in-place argument to sub and gsub is skipped, as it
+// must remain a variable.
+fn expr_vals<'a>(e: &mut PrimExpr<'a>, f: &mut impl FnMut(&mut PrimVal<'a>)) {
+    use PrimExpr::*;
+    match e {
+        Val(v) | IterBegin(v) | HasNext(v) | Next(v) => f(v),
+        CallBuiltin(Function::Sub, args) | CallBuiltin(Function::GSub, args) => {
+            for a in args.iter_mut().take(2) {
+                f(a)
+            }
+        }
+        CallBuiltin(_, args) | CallUDF(_, args) => {
+            for a in args.iter_mut() {
+                f(a)
+            }
+        }
+        Sprintf(fmt, args) => {
+            f(fmt);
+            for a in args.iter_mut() {
+                f(a)
+            }
+        }
+        Index(m, k) => {
+            f(m);
+            f(k)
+        }
+        Phi(_) | LoadBuiltin(_) => {}
+    }
+}
+
+fn stmt_vals<'a>(stmt: &mut PrimStmt<'a>, f: &mut impl FnMut(&mut PrimVal<'a>)) {
+    use PrimStmt::*;
+    match stmt {
+        AsgnIndex(_, k, e) => {
+            f(k);
+            expr_vals(e, f)
+        }
+        AsgnVar(_, e) | SetBuiltin(_, e) => expr_vals(e, f),
+        Return(v) | IterDrop(v) => f(v),
+        Printf(spec, args, out) => {
+            f(spec);
+            for a in args.iter_mut() {
+                f(a)
+            }
+            if let Some((o, _)) = out {
+                f(o)
+            }
+        }
+        PrintAll(args, out) => {
+            for a in args.iter_mut() {
+                f(a)
+            }
+            if let Some((o, _)) = out {
+                f(o)
+            }
+        }
+    }
+}
+
+/// The local variables of `cfg` that may be assigned more than once: those with more than one
+/// definition, and those modified in place by sub or gsub.
+pub(crate) fn clobbered(cfg: &CFG) -> HashSet<Ident> {
+    let mut res = HashSet::default();
+    let mut defined = HashSet::<Ident>::default();
+    for n in cfg.raw_nodes() {
+        for stmt in n.weight.q.iter() {
+            if let PrimStmt::AsgnVar(id, _) = stmt {
+                if !defined.insert(*id) {
+                    res.insert(*id);
+                }
+            }
+            if let Some(id) = stmt_expr(stmt).and_then(sub_target) {
+                res.insert(id);
+            }
+        }
+    }
+    res
+}
+
+fn same(v1: &PrimVal, v2: &PrimVal) -> bool {
+    use PrimVal::*;
+    match (v1, v2) {
+        (ILit(i1), ILit(i2)) => i1 == i2,
+        (FLit(f1), FLit(f2)) => f1.to_bits() == f2.to_bits(),
+        (StrLit(s1), StrLit(s2)) => s1 == s2,
+        _ => false,
+    }
+}
+
+// Whether a constant is "true" when used as a condition. Floating point values are converted to
+// integers first, as they are when the program runs.
+fn truthy(v: &PrimVal) -> Option<bool> {
+    match v {
+        PrimVal::ILit(i) => Some(*i != 0),
+        PrimVal::FLit(f) => Some(*f as i64 != 0),
+        PrimVal::StrLit(s) => Some(!s.is_empty()),
+        PrimVal::Var(_) => None,
+    }
+}
+
+// The result `res` of integer `+`, `-`, `*` or unary `-`, if it can be folded. With integer
+// promotion the runtime computes these in floating point, getting `float`; the integer result can
+// only stand in for it if it is the same number, down to the sign of zero, and prints the same way.
+fn int_arith(res: Option<i64>, float: f64) -> Option<i64> {
+    const EXACT: u64 = 1 << 53;
+    let res = res?;
+    let same = res.unsigned_abs() <= EXACT
+        && res as f64 == float
+        && (res != 0 || float.is_sign_positive());
+    if !same && runtime::arith::int_promotion() {
+        return None;
+    }
+    Some(res)
+}
+
+fn as_float(v: &PrimVal) -> Option<f64> {
+    match v {
+        PrimVal::ILit(i) => Some(*i as f64),
+        PrimVal::FLit(f) => Some(*f),
+        _ => None,
+    }
+}
+
+impl<'a, 'b, 'outer> Folder<'a, 'b, 'outer> {
+    fn is_global(&self, id: &Ident) -> bool {
+        id.is_global(self.local_globals)
+    }
+
+    fn const_of(&self, e: &PrimExpr<'a>) -> Option<PrimVal<'a>> {
+        match e {
+            PrimExpr::Val(PrimVal::Var(_)) => None,
+            PrimExpr::Val(v) => Some(v.clone()),
+            PrimExpr::Phi(preds) => {
+                let mut res: Option<&PrimVal<'a>> = None;
+                for (_, id) in preds.iter() {
+                    let v = self.consts.get(id)?;
+                    match res {
+                        Some(prev) if !same(prev, v) => return None,
+                        _ => res = Some(v),
+                    }
+                }
+                res.cloned()
+            }
+            _ => None,
+        }
+    }
+
+    fn fold_builtin(&self, f: Function, args: &[PrimVal<'a>]) -> Option<PrimVal<'a>> {
+        use PrimVal::*;
+        Some(match (f, args) {
+            (Function::Unop(Unop::Neg), [ILit(i)]) => {
+                ILit(int_arith(i.checked_neg(), -(*i as f64))?)
+            }
+            (Function::Unop(Unop::Neg), [FLit(f)]) => FLit(-f),
+            (Function::Unop(Unop::Pos), [v @ ILit(_)])
+            | (Function::Unop(Unop::Pos), [v @ FLit(_)]) => v.clone(),
+            (Function::Unop(Unop::Not), [v]) => ILit(!truthy(v)? as i64),
+            (Function::Binop(op), [l, r]) => match (op, l, r) {
+                (Binop::Concat, _, _) => {
+                    let mut buf = Vec::new();
+                    for v in [l, r].iter() {
+                        match v {
+                            StrLit(s) => buf.extend_from_slice(s),
+                            ILit(i) => buf.extend_from_slice(i.to_string().as_bytes()),
+                            _ => return None,
+                        }
+                    }
+                    StrLit(self.arena.alloc_bytes(&buf[..]))
+                }
+                (Binop::EQ, StrLit(s1), StrLit(s2)) => ILit((s1 == s2) as i64),
+                (Binop::Plus, ILit(i1), ILit(i2)) => {
+                    ILit(int_arith(i1.checked_add(*i2), *i1 as f64 + *i2 as f64)?)
+                }
+                (Binop::Minus, ILit(i1), ILit(i2)) => {
+                    ILit(int_arith(i1.checked_sub(*i2), *i1 as f64 - *i2 as f64)?)
+                }
+                (Binop::Mult, ILit(i1), ILit(i2)) => {
+                    ILit(int_arith(i1.checked_mul(*i2), *i1 as f64 * *i2 as f64)?)
+                }
+                (Binop::Mod, ILit(i1), ILit(i2)) => ILit(i1.checked_rem(*i2)?),
+                (Binop::LT, ILit(i1), ILit(i2)) => ILit((i1 < i2) as i64),
+                (Binop::GT, ILit(i1), ILit(i2)) => ILit((i1 > i2) as i64),
+                (Binop::LTE, ILit(i1), ILit(i2)) => ILit((i1 <= i2) as i64),
+                (Binop::GTE, ILit(i1), ILit(i2)) => ILit((i1 >= i2) as i64),
+                (Binop::EQ, ILit(i1), ILit(i2)) => ILit((i1 == i2) as i64),
+                (op, l, r) => {
+                    let (f1, f2) = (as_float(l)?, as_float(r)?);
+                    match op {
+                        Binop::Plus => FLit(f1 + f2),
+                        Binop::Minus => FLit(f1 - f2),
+                        Binop::Mult => FLit(f1 * f2),
+                        Binop::Mod if f2 != 0.0 => FLit(f1 % f2),
+                        Binop::Div if f2 != 0.0 => FLit(f1 / f2),
+                        Binop::Pow => FLit(f1.powf(f2)),
+                        Binop::LT => ILit((f1 < f2) as i64),
+                        Binop::GT => ILit((f1 > f2) as i64),
+                        Binop::LTE => ILit((f1 <= f2) as i64),
+                        Binop::GTE => ILit((f1 >= f2) as i64),
+                        Binop::EQ => ILit((f1 == f2) as i64),
+                        _ => return None,
+                    }
+                }
+            },
+            _ => return None,
+        })
+    }
+
+    // Substitute and fold constants in every basic block, returning whether anything changed.
+    fn propagate(&mut self, cfg: &mut CFG<'a>) -> bool {
+        let mut changed = false;
+        for n in cfg.node_indices() {
+            // String constants assigned to globals earlier in this block.
+            let mut globals = HashMap::<NumTy, &'a [u8]>::default();
+            let bb = cfg.node_weight_mut(n).unwrap();
+            for stmt in bb.q.iter_mut() {
+                stmt_vals(stmt, &mut |v| {
+                    if let Some(c) = self.lookup(v, &globals) {
+                        *v = c;
+                        changed = true;
+                    }
+                });
+                if let Some(PrimExpr::CallBuiltin(f, args)) = stmt_expr_mut(stmt) {
+                    if let Some(v) = self.fold_builtin(*f, &args[..]) {
+                        *stmt_expr_mut(stmt).unwrap() = PrimExpr::Val(v);
+                        changed = true;
+                    }
+                }
+                if let Some(PrimExpr::CallUDF(..)) = stmt_expr(stmt) {
+                    globals.clear();
+                }
+                if let Some(id) = stmt_expr(stmt).and_then(sub_target) {
+                    globals.remove(&id.low);
+                }
+                if let PrimStmt::AsgnVar(id, e) = stmt {
+                    if self.is_global(id) {
+                        match e {
+                            PrimExpr::Val(PrimVal::StrLit(s)) => {
+                                globals.insert(id.low, s);
+                            }
+                            _ => {
+                                globals.remove(&id.low);
+                            }
+                        }
+                    } else if !self.clobbered.contains(id) && !self.consts.contains_key(id) {
+                        if let Some(c) = self.const_of(e) {
+                            self.consts.insert(*id, c);
+                            changed = true;
+                        }
+                    }
+                }
+            }
+            let mut walker = cfg.neighbors(n).detach();
+            while let Some(e) = walker.next_edge(cfg) {
+                if let Transition(Some(v)) = cfg.edge_weight_mut(e).unwrap() {
+                    if let Some(c) = self.lookup(v, &globals) {
+                        *v = c;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    fn lookup(&self, v: &PrimVal<'a>, globals: &HashMap<NumTy, &'a [u8]>) -> Option<PrimVal<'a>> {
+        let id = match v {
+            PrimVal::Var(id) => id,
+            _ => return None,
+        };
+        if self.is_global(id) {
+            globals.get(&id.low).map(|s| PrimVal::StrLit(s))
+        } else {
+            self.consts.get(id).cloned()
+        }
+    }
+}
+
+// The outgoing edges of `n`, in the order in which they are tested.
+fn out_edges(cfg: &CFG, n: NodeIx) -> Vec<EdgeIndex<NumTy>> {
+    let mut res = Vec::new();
+    let mut walker = cfg.neighbors(n).detach();
+    while let Some(e) = walker.next_edge(cfg) {
+        res.push(e);
+    }
+    res.reverse();
+    res
+}
+
+// Remove branches that can never be taken, and make branches that are always taken
+// unconditional.
+fn prune_branches(cfg: &mut CFG) -> bool {
+    let mut changed = false;
+    for n in cfg.node_indices() {
+        loop {
+            let edges = out_edges(cfg, n);
+            let mut remove = None;
+            for (i, e) in edges.iter().cloned().enumerate() {
+                let cond = match &cfg.edge_weight(e).unwrap().0 {
+                    Some(v) => truthy(v),
+                    None => break,
+                };
+                match cond {
+                    Some(false) => remove = Some(e),
+                    // Remove any later branches before making this one unconditional.
+                    Some(true) if i + 1 < edges.len() => remove = edges.last().cloned(),
+                    Some(true) => *cfg.edge_weight_mut(e).unwrap() = Transition(None),
+                    None => continue,
+                }
+                changed = true;
+                break;
+            }
+            match remove {
+                Some(e) => {
+                    cfg.remove_edge(e);
+                }
+                None => break,
+            }
+        }
+    }
+    changed
+}
+
+fn reachable(cfg: &CFG, entry: NodeIx) -> Vec<bool> {
+    let mut res = vec![false; cfg.node_count()];
+    let mut stack = vec![entry];
+    res[entry.index()] = true;
+    while let Some(n) = stack.pop() {
+        for neigh in cfg.neighbors(n) {
+            if !res[neigh.index()] {
+                res[neigh.index()] = true;
+                stack.push(neigh);
+            }
+        }
+    }
+    res
+}
+
+// Clear out unreachable blocks. We leave the exit block alone, as it holds the function's return
+// statement.
+fn remove_unreachable(cfg: &mut CFG, exit: NodeIx, reachable: &[bool]) -> bool {
+    let mut changed = false;
+    for n in cfg.node_indices() {
+        if reachable[n.index()] {
+            continue;
+        }
+        for e in out_edges(cfg, n).into_iter().rev() {
+            cfg.remove_edge(e);
+            changed = true;
+        }
+        let bb = cfg.node_weight_mut(n).unwrap();
+        if n != exit && !bb.q.is_empty() {
+            bb.q.clear();
+            bb.spans.clear();
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Drop the arguments of phi nodes corresponding to edges that have been removed.
+fn prune_phis(cfg: &mut CFG) {
+    for n in cfg.node_indices() {
+        let preds: HashSet<NodeIx> = cfg.neighbors_directed(n, Direction::Incoming).collect();
+        for stmt in cfg.node_weight_mut(n).unwrap().q.iter_mut() {
+            if let PrimStmt::AsgnVar(_, PrimExpr::Phi(ps)) = stmt {
+                ps.retain(|(pred, _)| preds.contains(pred));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::harness::fold_constants;
+
+    fn folded(prog: &str) -> String {
+        fold_constants(prog).unwrap().0
+    }
+
+    #[test]
+    fn folds_arithmetic() {
+        let prog = folded(r#"BEGIN { x = 1 + 2 * 3; y = x - 1; print y "!"; }"#);
+        assert!(prog.contains(r#"print("6!""#), "{}", prog);
+        // Overflowing integer arithmetic is left to the runtime.
+        let prog = folded(r#"BEGIN { x = 9223372036854775807; print x + 1; }"#);
+        assert!(
+            prog.contains("(9223372036854775807@int, 1@int)"),
+            "{}",
+            prog
+        );
+        // So is integer arithmetic whose floating-point result is not an integer: here, -0.
+        let prog = folded(r#"BEGIN { print -1, 0 * -1; }"#);
+        assert!(prog.contains("print(-1@int, "), "{}", prog);
+        assert!(prog.contains("(0@int, -1@int)"), "{}", prog);
+    }
+
+    #[test]
+    fn folds_branches() {
+        let prog = folded(r#"BEGIN { debug = 0; if (debug) print "debugging"; print "done"; }"#);
+        assert!(
+            !prog.contains("debugging") && prog.contains("done"),
+            "{}",
+            prog
+        );
+        let prog = folded(r#"{ if (NR > 1) print "many"; else print "one"; }"#);
+        assert!(prog.contains("many") && prog.contains("one"), "{}", prog);
+    }
+
+    #[test]
+    fn respects_sub() {
+        let prog = folded(r#"BEGIN { x = "aaa"; sub(/a/, "b", x); print x; }"#);
+        assert!(!prog.contains(r#"print("aaa""#), "{}", prog);
+    }
+
+    #[test]
+    fn propagates_separators() {
+        let sep = |prog| fold_constants(prog).unwrap().1;
+        assert_eq!(
+            sep(r#"BEGIN { sep = ","; FS = sep; } { print $1; }"#),
+            Some(b",".to_vec())
+        );
+        assert_eq!(
+            sep(r#"BEGIN { FS = "|" "|"; } { print $1; }"#),
+            Some(b"||".to_vec())
+        );
+        assert_eq!(
+            sep(r#"BEGIN { FS = OFS = "\t"; } { print $1; }"#),
+            Some(b"\t".to_vec())
+        );
+        assert_eq!(
+            sep(r#"function f() { OFS = ";" } BEGIN { OFS = ","; f(); FS = OFS; } { print $1; }"#),
+            None
+        );
+        assert_eq!(
+            sep(r#"function f() { sep = ";" } BEGIN { sep = ","; f(); FS = sep; } { print $1; }"#),
+            None
+        );
+    }
+}
@@ -1,7 +1,9 @@
 //! This module contains helper routines for computing "slot" instructions for parallel scripts.
 //!
 //! If a frawk program executes its main loop in parallel, we need some mechanism for computing
-//! which variables need to be propagated between stages.
+//! which variables need to be propagated between stages. Tiered programs (see `runtime::tiered`)
+//! are split into the same stages, so that compiled code can pick up the main loop where the
+//! interpreter left off.
 use crate::common::{NumTy, Result};
 use crate::compile::{Ty, LL};
 use hashbrown::HashSet;
@@ -10,10 +12,14 @@ type SlotSet = HashSet<(NumTy, Ty)>;
 
 #[derive(Default)]
 pub(crate) struct SlotOps {
-    // The values stored in the BEGIN stage and loaded in the main loop stage.
+    // The values stored in the BEGIN stage.
     pub(crate) begin_stores: SlotSet,
-    // The values stored in the main loop stage and loaded in END stage.
+    // The values loaded in the main loop stage.
+    pub(crate) loop_loads: SlotSet,
+    // The values stored in the main loop stage.
     pub(crate) loop_stores: SlotSet,
+    // The values loaded in the END stage.
+    pub(crate) end_loads: SlotSet,
 }
 
 pub(crate) fn load_slot_instr<'a>(reg: NumTy, ty: Ty, slot: usize) -> Result<Option<LL<'a>>> {
@@ -52,9 +58,35 @@ fn compute_par(
     let begin_loop = begin_refs.intersection(loop_refs);
     let loop_end = loop_refs.intersection(end_refs);
     let begin_end = || begin_refs.intersection(end_refs);
+    let begin_stores: SlotSet = begin_loop.chain(begin_end()).cloned().collect();
+    let loop_stores: SlotSet = loop_end.chain(begin_end()).cloned().collect();
     SlotOps {
-        begin_stores: begin_loop.chain(begin_end()).cloned().collect(),
-        loop_stores: loop_end.chain(begin_end()).cloned().collect(),
+        loop_loads: begin_stores.clone(),
+        begin_stores,
+        end_loads: loop_stores.clone(),
+        loop_stores,
+    }
+}
+
+// A tiered main loop may stop partway through its input, with the rest of it run by another copy
+// of the main loop. So the main loop loads everything it refers to, and stores it all again
+// along with what BEGIN passes on to END.
+fn compute_tiered(
+    begin_refs: &HashSet<(NumTy, Ty)>,
+    loop_refs: &HashSet<(NumTy, Ty)>,
+    end_refs: &HashSet<(NumTy, Ty)>,
+) -> SlotOps {
+    let par = compute_par(begin_refs, loop_refs, end_refs);
+    let loop_state: SlotSet = loop_refs
+        .iter()
+        .chain(begin_refs.intersection(end_refs))
+        .cloned()
+        .collect();
+    SlotOps {
+        begin_stores: par.begin_stores,
+        loop_loads: loop_state.clone(),
+        loop_stores: loop_state,
+        end_loads: par.end_loads,
     }
 }
 
@@ -64,8 +96,13 @@ pub(crate) fn compute_slots(
     main_loop: &Option<usize>,
     end: &Option<usize>,
     global_refs: Vec<HashSet<(NumTy, Ty)>>,
+    tiered: bool,
 ) -> SlotOps {
     let empty: HashSet<(NumTy, Ty)> = Default::default();
     let get_ref = |x: &Option<usize>| x.as_ref().map(|i| &global_refs[*i]).unwrap_or(&empty);
-    compute_par(get_ref(begin), get_ref(main_loop), get_ref(end))
+    if tiered {
+        compute_tiered(get_ref(begin), get_ref(main_loop), get_ref(end))
+    } else {
+        compute_par(get_ref(begin), get_ref(main_loop), get_ref(end))
+    }
 }
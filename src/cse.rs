@@ -0,0 +1,302 @@
+//! Common subexpression elimination over the SSA form of a function.
+//!
+//! Scripts often reference the same field, or the same computation over it, several times while
+//! processing a record, as in `$3 > 0 { total += $3; print substr($1, 1, 10), $3 }`. This pass
+//! replaces a repeated expression with a copy of the variable holding its first result, so long
+//! as the first computation is guaranteed to have run, and the value cannot have changed, by the
+//! time of the second.
+//!
+//! The pass works on _extended basic blocks_: trees of basic blocks in which every block other
+//! than the root has a single predecessor. An expression computed in a block is available in the
+//! rest of that block, and in any of its successors whose only predecessor it is. This covers the
+//! bodies of `if` statements and of `&&` and `||`, which is where most repeated field references
+//! live.
+//!
+//! Only builtins that have no side effects are eligible. Their results fall into two groups:
+//!
+//! * Pure functions of their arguments, like arithmetic, `substr` or `sprintf`. These remain
+//!   available until the end of the extended block.
+//! * Functions that also depend on the state of the current record or of a map: reading a
+//!   column or a special variable like `NR`, `length`, the `in` operator, and joining columns.
+//!   These are forgotten after any statement that could modify that state: assigning to a column,
+//!   a special variable or a map element, reading a new line, calling a function and so on.
+//!
+//! Expressions that read global variables, or local variables that are assigned more than once,
+//! are never replaced. The conversion of a variable from a string to a number is cached
+//! separately, during code generation.
+use crate::builtins::{Function, Variable};
+use crate::cfg::{is_unused, Ident, PrimExpr, PrimStmt, PrimVal, SmallVec, CFG};
+use crate::common::{NodeIx, NumTy};
+use crate::const_fold::{clobbered, stmt_expr_mut};
+
+use hashbrown::{HashMap, HashSet};
+use petgraph::Direction;
+
+/// Eliminate common subexpressions in `cfg`, returning whether any were found.
+pub(crate) fn eliminate<'a>(
+    cfg: &mut CFG<'a>,
+    entry: NodeIx,
+    local_globals: &HashSet<NumTy>,
+) -> bool {
+    let mut elim = Eliminator {
+        clobbered: clobbered(cfg),
+        copies: Default::default(),
+        local_globals,
+    };
+    let mut changed = false;
+    let mut stack: Vec<(NodeIx, Available)> = cfg
+        .node_indices()
+        .filter(|n| *n == entry || single_pred(cfg, *n).is_none())
+        .map(|n| (n, Available::default()))
+        .collect();
+    while let Some((n, mut avail)) = stack.pop() {
+        changed |= elim.block(cfg, n, &mut avail);
+        let succs: HashSet<NodeIx> = cfg.neighbors(n).collect();
+        for succ in succs {
+            if succ != entry && single_pred(cfg, succ) == Some(n) {
+                stack.push((succ, avail.clone()));
+            }
+        }
+    }
+    changed
+}
+
+// The only predecessor of `n`, if it has exactly one (other than itself).
+fn single_pred(cfg: &CFG, n: NodeIx) -> Option<NodeIx> {
+    let mut preds = cfg.neighbors_directed(n, Direction::Incoming);
+    let pred = preds.next()?;
+    if pred == n || preds.any(|p| p != pred) {
+        return None;
+    }
+    Some(pred)
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Operand<'a> {
+    Var(Ident),
+    Int(i64),
+    Float(u64),
+    Str(&'a [u8]),
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Op {
+    Builtin(Function),
+    Sprintf,
+    Load(Variable),
+}
+
+type Key<'a> = (Op, SmallVec<Operand<'a>>);
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Pure,
+    ReadsState,
+}
+
+/// Whether `f` is a function of its arguments alone, with no side effects.
+pub(crate) fn is_pure(f: Function) -> bool {
+    kind_of(f) == Some(Kind::Pure)
+}
+
+/// Whether `f` has no side effects, though its result may depend on the current record.
+pub(crate) fn is_side_effect_free(f: Function) -> bool {
+    kind_of(f).is_some()
+}
+
+fn kind_of(f: Function) -> Option<Kind> {
+    use crate::ast::Unop::*;
+    use Function::*;
+    match f {
+        Unop(Neg) | Unop(Pos) | Unop(Not) | Binop(_) | FloatFunc(_) | IntFunc(_) | Substr
+        | SubstrIndex | ToInt | HexToInt | IsNA | NumOr | Trim | LTrim | RTrim | Squeeze
+        | Color | Bold | LPad | RPad | ZFill | EscapeCSV | EscapeTSV | GraphemeLength
+        | GraphemeSubstr | Levenshtein | JaroWinkler | CmpNatural | CmpLocale => Some(Kind::Pure),
+        Unop(Column) | Length | Contains | JoinCols | JoinCSV | JoinTSV | FSum | FMean => {
+            Some(Kind::ReadsState)
+        }
+        _ => None,
+    }
+}
+
+// Expressions computed so far in the current extended block, and the variables holding them.
+#[derive(Clone, Default)]
+struct Available<'a> {
+    pure: HashMap<Key<'a>, Ident>,
+    reads_state: HashMap<Key<'a>, Ident>,
+}
+
+impl<'a> Available<'a> {
+    fn get(&self, kind: Kind, key: &Key<'a>) -> Option<Ident> {
+        match kind {
+            Kind::Pure => self.pure.get(key).cloned(),
+            Kind::ReadsState => self.reads_state.get(key).cloned(),
+        }
+    }
+    fn insert(&mut self, kind: Kind, key: Key<'a>, id: Ident) {
+        match kind {
+            Kind::Pure => self.pure.insert(key, id),
+            Kind::ReadsState => self.reads_state.insert(key, id),
+        };
+    }
+}
+
+struct Eliminator<'b> {
+    clobbered: HashSet<Ident>,
+    // Variables that are assigned a copy of another variable, mapped to the original.
+    copies: HashMap<Ident, Ident>,
+    local_globals: &'b HashSet<NumTy>,
+}
+
+impl<'b> Eliminator<'b> {
+    // Whether `id` holds the same value everywhere it is in scope.
+    fn stable(&self, id: &Ident) -> bool {
+        !id.is_global(self.local_globals) && !self.clobbered.contains(id) && !is_unused(*id)
+    }
+
+    fn original(&self, mut id: Ident) -> Ident {
+        while let Some(src) = self.copies.get(&id) {
+            id = *src;
+        }
+        id
+    }
+
+    fn operand<'a>(&self, v: &PrimVal<'a>) -> Option<Operand<'a>> {
+        Some(match v {
+            PrimVal::Var(id) if self.stable(id) => Operand::Var(self.original(*id)),
+            PrimVal::Var(_) => return None,
+            PrimVal::ILit(i) => Operand::Int(*i),
+            PrimVal::FLit(f) => Operand::Float(f.to_bits()),
+            PrimVal::StrLit(s) => Operand::Str(s),
+        })
+    }
+
+    fn key<'a>(&self, e: &PrimExpr<'a>) -> Option<(Kind, Key<'a>)> {
+        let (kind, op, args) = match e {
+            PrimExpr::CallBuiltin(f, args) => (kind_of(*f)?, Op::Builtin(*f), &args[..]),
+            PrimExpr::Sprintf(fmt, args) => {
+                let mut ops = SmallVec::with_capacity(args.len() + 1);
+                ops.push(self.operand(fmt)?);
+                for a in args.iter() {
+                    ops.push(self.operand(a)?);
+                }
+                return Some((Kind::Pure, (Op::Sprintf, ops)));
+            }
+            PrimExpr::LoadBuiltin(v) => {
+                return Some((Kind::ReadsState, (Op::Load(*v), SmallVec::new())))
+            }
+            _ => return None,
+        };
+        let mut ops = SmallVec::with_capacity(args.len());
+        for a in args.iter() {
+            ops.push(self.operand(a)?);
+        }
+        Some((kind, (op, ops)))
+    }
+
+    fn block<'a>(&mut self, cfg: &mut CFG<'a>, n: NodeIx, avail: &mut Available<'a>) -> bool {
+        let mut changed = false;
+        for stmt in cfg.node_weight_mut(n).unwrap().q.iter_mut() {
+            if let Some(e) = stmt_expr_mut(stmt) {
+                if let Some((kind, key)) = self.key(e) {
+                    if let Some(id) = avail.get(kind, &key) {
+                        *e = PrimExpr::Val(PrimVal::Var(id));
+                        changed = true;
+                    }
+                }
+            }
+            let modifies_state = match stmt {
+                PrimStmt::AsgnVar(id, PrimExpr::Val(PrimVal::Var(src))) => {
+                    if self.stable(id) && self.stable(src) {
+                        self.copies.insert(*id, *src);
+                    }
+                    false
+                }
+                PrimStmt::AsgnVar(id, e) => {
+                    if let Some((kind, key)) = self.key(e) {
+                        if self.stable(id) {
+                            avail.insert(kind, key, *id);
+                        }
+                    }
+                    match e {
+                        PrimExpr::CallBuiltin(f, _) => kind_of(*f).is_none(),
+                        PrimExpr::CallUDF(..) | PrimExpr::Index(..) => true,
+                        PrimExpr::Val(_)
+                        | PrimExpr::Phi(_)
+                        | PrimExpr::Sprintf(..)
+                        | PrimExpr::IterBegin(_)
+                        | PrimExpr::HasNext(_)
+                        | PrimExpr::Next(_)
+                        | PrimExpr::LoadBuiltin(_) => false,
+                    }
+                }
+                PrimStmt::AsgnIndex(..) | PrimStmt::SetBuiltin(..) => true,
+                PrimStmt::Return(_)
+                | PrimStmt::IterDrop(_)
+                | PrimStmt::Printf(..)
+                | PrimStmt::PrintAll(..) => false,
+            };
+            if modifies_state {
+                avail.reads_state.clear();
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::harness::eliminate_common_subexprs;
+
+    fn count(prog: &str, pat: &str) -> usize {
+        eliminate_common_subexprs(prog)
+            .unwrap()
+            .matches(pat)
+            .count()
+    }
+
+    #[test]
+    fn reuses_columns() {
+        assert_eq!(count(r#"{ x = $3 + 1; y = $3 * 2; print x, y; }"#, "$("), 1);
+        assert_eq!(
+            count(r#"$3 > 0 { total += $3; } END { print total; }"#, "$("),
+            1
+        );
+        assert_eq!(
+            count(
+                r#"{ print substr($1, 1, 10); print substr($1, 1, 10) "!"; }"#,
+                "substr("
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn respects_modifications() {
+        assert_eq!(count(r#"{ x = $3; $3 = "y"; print x, $3; }"#, "$("), 3);
+        assert_eq!(count(r#"{ x = $1; getline; print x, $1; }"#, "$(1@int)"), 2);
+        assert_eq!(
+            count(
+                r#"{ n = length(m); m[$1]; print n, length(m); }"#,
+                "length("
+            ),
+            2
+        );
+        // Pure expressions are not affected.
+        assert_eq!(
+            count(
+                r#"function f(a,    x) { x = a * 2; $1 = "y"; return x + a * 2; } { print f(NR); }"#,
+                "*"
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn respects_control_flow() {
+        assert_eq!(
+            count(r#"{ if (NR > 1) x = $2; else x = 0; print $2, x; }"#, "$("),
+            2
+        );
+    }
+}
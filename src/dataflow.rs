@@ -177,7 +177,7 @@ pub(crate) mod boilerplate {
                     f(Key::Reg(*reg, *ty), Some(Key::Reg(*pred_reg, *ty)));
                 }
             }
-            DropIter(..) => {}
+            DropIter(..) | Loc(_) => {}
         }
     }
 
@@ -193,8 +193,9 @@ pub(crate) mod boilerplate {
             FloatToStr(dst, src) => f(dst.into(), Some(src.into())),
             FloatToInt(dst, src) => f(dst.into(), Some(src.into())),
             StrToFloat(dst, src) => f(dst.into(), Some(src.into())),
-            LenStr(dst, src) | StrToInt(dst, src) | HexStrToInt(dst, src) => f(dst.into(), Some(src.into())),
+            LenStr(dst, src) | GraphemeLen(dst, src) | StrToInt(dst, src) | HexStrToInt(dst, src) | IsNA(dst, src) => f(dst.into(), Some(src.into())),
 
+            TakeStr(dst, src) => f(dst.into(), Some(src.into())),
             Mov(ty, dst, src) => if !ty.is_array() {
                 f(Key::Reg(*dst, *ty), Some(Key::Reg(*src, *ty)))
             } else {
@@ -238,8 +239,18 @@ pub(crate) mod boilerplate {
             // NB: this assumes that regexes that have been constant-folded are not tainted by
             // user-input. That is certainly true today, but any kind of dynamic simplification or
             // inlining could change that.
-            MatchConst(dst, x, _) | IsMatchConst(dst, x, _) => f(dst.into(), Some(x.into())),
-            IsMatch(dst, x, y) | Match(dst, x, y) | SubstrIndex(dst, x, y) => {
+            MatchConst(dst, x, _) | IsMatchConst(dst, x, _) | MatchSet(dst, x, _) => {
+                f(dst.into(), Some(x.into()))
+            }
+            IsMatch(dst, x, y) | Match(dst, x, y) | SubstrIndex(dst, x, y) | Levenshtein(dst, x, y) | CmpNatural(dst, x, y) | CmpLocale(dst, x, y) => {
+                f(dst.into(), Some(x.into()));
+                f(dst.into(), Some(y.into()));
+            }
+            JaroWinkler(dst, x, y) => {
+                f(dst.into(), Some(x.into()));
+                f(dst.into(), Some(y.into()));
+            }
+            NumOr(dst, x, y) => {
                 f(dst.into(), Some(x.into()));
                 f(dst.into(), Some(y.into()));
             }
@@ -249,12 +260,27 @@ pub(crate) mod boilerplate {
                 f(dstin.into(), Some(x.into()));
                 f(dstin.into(), Some(y.into()));
             }
-            EscapeTSV(dst, src) | EscapeCSV(dst, src) => f(dst.into(), Some(src.into())),
-            Substr(dst, x, y, z) => {
+            EscapeTSV(dst, src) | EscapeCSV(dst, src) | Squeeze(dst, src) | Bold(dst, src) => {
+                f(dst.into(), Some(src.into()))
+            }
+            Trim(dst, x, y) | LTrim(dst, x, y) | RTrim(dst, x, y) | Color(dst, x, y) => {
+                f(dst.into(), Some(x.into()));
+                f(dst.into(), Some(y.into()));
+            }
+            Substr(dst, x, y, z) | GraphemeSubstr(dst, x, y, z) => {
                 f(dst.into(), Some(x.into()));
                 f(dst.into(), Some(y.into()));
                 f(dst.into(), Some(z.into()));
             }
+            LPad(dst, x, y, z) | RPad(dst, x, y, z) => {
+                f(dst.into(), Some(x.into()));
+                f(dst.into(), Some(y.into()));
+                f(dst.into(), Some(z.into()));
+            }
+            ZFill(dst, x, y) => {
+                f(dst.into(), Some(x.into()));
+                f(dst.into(), Some(y.into()));
+            }
             LTFloat(dst, x, y)
             | GTFloat(dst, x, y)
             | LTEFloat(dst, x, y)
@@ -280,10 +306,22 @@ pub(crate) mod boilerplate {
                 f(dst.into(), Some(y.into()));
             }
             GetColumn(dst, _) => f(dst.into(), None),
+            GetColumnInt(dst, s, _) => {
+                f(s.into(), None);
+                f(dst.into(), Some(s.into()));
+            }
+            GetColumnFloat(dst, s, _) => {
+                f(s.into(), None);
+                f(dst.into(), Some(s.into()));
+            }
             JoinTSV(dst, start, end) | JoinCSV(dst, start, end) => {
                 f(dst.into(), Some(start.into()));
                 f(dst.into(), Some(end.into()));
             }
+            SumColumns(dst, start, end) | MeanColumns(dst, start, end) => {
+                f(dst.into(), Some(start.into()));
+                f(dst.into(), Some(end.into()));
+            }
             JoinColumns(dst, x, y, z) => {
                 f(dst.into(), Some(x.into()));
                 f(dst.into(), Some(y.into()));
@@ -293,6 +331,7 @@ pub(crate) mod boilerplate {
             NextLine(dst, _cmd, _) => f(dst.into(), None),
             ReadErrStdin(dst) => f(dst.into(), None),
             NextLineStdin(dst) => f(dst.into(), None),
+            NextLineStdinFused(dst) => f(dst.into(), None),
             SplitInt(dst1, src1, dst2, src2) => {
                 f(dst1.into(), Some(src1.into()));
                 f(dst1.into(), Some(src2.into()));
@@ -301,6 +340,103 @@ pub(crate) mod boilerplate {
                 f(Key::MapVal(dst2_reg, dst2_ty), Some(src1.into()));
                 f(Key::MapVal(dst2_reg, dst2_ty), Some(src2.into()));
             }
+            Graphemes(dst1, src, dst2) => {
+                f(dst1.into(), Some(src.into()));
+                let (dst2_reg, dst2_ty) = dst2.reflect();
+                f(Key::MapVal(dst2_reg, dst2_ty), Some(src.into()));
+            }
+            IntDiv(dst1, src1, src2, dst2) => {
+                f(dst1.into(), Some(src1.into()));
+                f(dst1.into(), Some(src2.into()));
+                let (dst2_reg, dst2_ty) = dst2.reflect();
+                f(Key::MapKey(dst2_reg, dst2_ty), None);
+                f(Key::MapVal(dst2_reg, dst2_ty), Some(src1.into()));
+                f(Key::MapVal(dst2_reg, dst2_ty), Some(src2.into()));
+            }
+            DivMod(dst1, src1, src2, dst2) => {
+                f(dst1.into(), Some(src1.into()));
+                f(dst1.into(), Some(src2.into()));
+                let (dst2_reg, dst2_ty) = dst2.reflect();
+                f(Key::MapVal(dst2_reg, dst2_ty), Some(src1.into()));
+                f(Key::MapVal(dst2_reg, dst2_ty), Some(src2.into()));
+            }
+            HllAdd(dst, h, _) | BloomAdd(dst, h, _, _, _) => {
+                f(dst.into(), None);
+                let (h_reg, h_ty) = h.reflect();
+                f(Key::MapKey(h_reg, h_ty), None);
+                f(Key::MapVal(h_reg, h_ty), None);
+            }
+            HllCount(dst, _) | BloomHas(dst, _, _) => f(dst.into(), None),
+            Reservoir(dst, arr, _, src) => {
+                f(dst.into(), None);
+                let (arr_reg, arr_ty) = arr.reflect();
+                f(Key::MapKey(arr_reg, arr_ty), None);
+                f(Key::MapVal(arr_reg, arr_ty), Some(src.into()));
+                f(Key::MapVal(arr_reg, arr_ty), None);
+            }
+            WinPush(dst, w, src, _) => {
+                f(dst.into(), None);
+                let (w_reg, w_ty) = w.reflect();
+                f(Key::MapKey(w_reg, w_ty), None);
+                f(Key::MapVal(w_reg, w_ty), Some(src.into()));
+                f(Key::MapVal(w_reg, w_ty), None);
+            }
+            WinSum(dst, _) | WinAvg(dst, _) | WinMax(dst, _) => f(dst.into(), None),
+            HistAdd(dst, h, _, _, _) => {
+                f(dst.into(), None);
+                let (h_reg, h_ty) = h.reflect();
+                f(Key::MapKey(h_reg, h_ty), None);
+                f(Key::MapVal(h_reg, h_ty), None);
+            }
+            HistFormat(dst, _) => f(dst.into(), None),
+            VecDot(dst, _, _) | VecNorm(dst, _) => f(dst.into(), None),
+            VecAdd(res, dst, _, _)
+            | VecSub(res, dst, _, _)
+            | VecMul(res, dst, _, _)
+            | VecScale(res, dst, _, _) => {
+                f(res.into(), None);
+                let (dst_reg, dst_ty) = dst.reflect();
+                f(Key::MapKey(dst_reg, dst_ty), None);
+                f(Key::MapVal(dst_reg, dst_ty), None);
+            }
+            Readable(dst, inputs, _timeout) => {
+                f(dst.into(), None);
+                let (inputs_reg, inputs_ty) = inputs.reflect();
+                f(Key::MapVal(inputs_reg, inputs_ty), None);
+            }
+            SetTimeout(dst, _input, _ms) => f(dst.into(), None),
+            Spawn(dst, _) | WriteLine(dst, _, _) | Wait(dst, _) => f(dst.into(), None),
+            ReadLine(dst, _, _) | ReadCmd(dst, _) => f(dst.into(), None),
+            Getenv(dst, _, default) => {
+                f(dst.into(), None);
+                f(dst.into(), Some(default.into()));
+            }
+            Setenv(dst, _, _) | Rename(dst, _, _) => f(dst.into(), None),
+            Mkdirp(dst, _) | RmFile(dst, _) => f(dst.into(), None),
+            Sleep(dst, _) => f(dst.into(), None),
+            Monotime(dst) => f(dst.into(), None),
+            Signal(dst) => f(dst.into(), None),
+            RecordFile(dst) | RecordText(dst) | ArgvAssign(dst) => f(dst.into(), None),
+            RecordOffset(dst) | SetMatched(dst) | Exiting(dst) | TierUp(dst) => {
+                f(dst.into(), None)
+            }
+            RoundTripSep(dst, _, ors) => {
+                f(dst.into(), None);
+                f(dst.into(), Some(ors.into()));
+            }
+            SetExit(dst, _) | SetExiting(dst, _) => f(dst.into(), None),
+            Stat(dst, _, arr) => {
+                f(dst.into(), None);
+                let (arr_reg, arr_ty) = arr.reflect();
+                f(Key::MapKey(arr_reg, arr_ty), None);
+                f(Key::MapVal(arr_reg, arr_ty), None);
+            }
+            Glob(dst, _, arr) => {
+                f(dst.into(), None);
+                let (arr_reg, arr_ty) = arr.reflect();
+                f(Key::MapKey(arr_reg, arr_ty), None);
+                f(Key::MapVal(arr_reg, arr_ty), None);
+            }
             SplitStr(dst1, src1, dst2, src2) => {
                 f(dst1.into(), Some(src1.into()));
                 f(dst1.into(), Some(src2.into()));
@@ -314,6 +450,7 @@ pub(crate) mod boilerplate {
                 }
             }
             RunCmd(dst, _) => f(dst.into(), None),
+            MarkInit(dst, _) | CheckInit(dst, _, _) => f(dst.into(), None),
             Lookup {
                 map_ty,
                 dst,
@@ -327,6 +464,14 @@ pub(crate) mod boilerplate {
                 f(Key::Reg(*dst, map_ty.val().unwrap()), Some(Key::MapVal(*map, *map_ty)))
             },
             Len { map_ty, dst, map } => f(Key::Reg(*dst, Ty::Int), Some(Key::Reg(*map, *map_ty))),
+            Bound { map_ty, dst, map, .. } => f(Key::Reg(*dst, Ty::Int), Some(Key::Reg(*map, *map_ty))),
+            LoadState { map_ty, dst, map, .. } => {
+                // keys and values come from the state file
+                f(Key::MapKey(*map, *map_ty), None);
+                f(Key::MapVal(*map, *map_ty), None);
+                f(Key::Reg(*dst, Ty::Int), Some(Key::Reg(*map, *map_ty)))
+            }
+            SaveState { map_ty, dst, map, .. } => f(Key::Reg(*dst, Ty::Int), Some(Key::Reg(*map, *map_ty))),
             Store { map_ty, map, key, val } => {
                 f(Key::MapKey(*map, *map_ty), Some(Key::Reg(*key, map_ty.key().unwrap())));
                 f(Key::MapVal(*map, *map_ty), Some(Key::Reg(*val, map_ty.val().unwrap())));
@@ -378,7 +523,6 @@ pub(crate) mod boilerplate {
             | Ret
             | Printf { .. }
             | Close(_)
-            | NextLineStdinFused()
             | NextFile()
             | SetColumn(_, _)
             | AllocMap(_, _) => {}
@@ -165,6 +165,9 @@ impl Display for Function {
             Length => write!(f, "length"),
             Contains => write!(f, "contains"),
             Delete => write!(f, "delete"),
+            Bound => write!(f, "bound"),
+            LoadState => write!(f, "load_state"),
+            SaveState => write!(f, "save_state"),
             Close => write!(f, "close"),
             Match => write!(f, "match"),
             SubstrIndex => write!(f, "index"),
@@ -176,14 +179,83 @@ impl Display for Function {
             JoinTSV => write!(f, "join_tsv"),
             JoinCols => write!(f, "join_fields"),
             Substr => write!(f, "substr"),
+            Graphemes => write!(f, "graphemes"),
+            GraphemeLength => write!(f, "grapheme_length"),
+            GraphemeSubstr => write!(f, "grapheme_substr"),
+            Levenshtein => write!(f, "levenshtein"),
+            IntDiv => write!(f, "intdiv"),
+            DivMod => write!(f, "divmod"),
+            HllAdd => write!(f, "hll_add"),
+            HllCount => write!(f, "hll_count"),
+            BloomAdd => write!(f, "bloom_add"),
+            BloomHas => write!(f, "bloom_has"),
+            Reservoir => write!(f, "reservoir"),
+            WinPush => write!(f, "win_push"),
+            WinSum => write!(f, "win_sum"),
+            WinAvg => write!(f, "win_avg"),
+            WinMax => write!(f, "win_max"),
+            HistAdd => write!(f, "hist_add"),
+            HistFormat => write!(f, "hist_format"),
+            VecDot => write!(f, "vec_dot"),
+            VecNorm => write!(f, "vec_norm"),
+            VecAdd => write!(f, "vec_add"),
+            VecSub => write!(f, "vec_sub"),
+            VecMul => write!(f, "vec_mul"),
+            VecScale => write!(f, "vec_scale"),
+            FSum => write!(f, "fsum"),
+            FMean => write!(f, "fmean"),
+            Readable => write!(f, "readable"),
+            SetTimeout => write!(f, "settimeout"),
+            Spawn => write!(f, "spawn"),
+            ReadLine => write!(f, "readline"),
+            WriteLine => write!(f, "writeline"),
+            Wait => write!(f, "wait"),
+            ReadCmd => write!(f, "readcmd"),
+            Getenv => write!(f, "getenv"),
+            Setenv => write!(f, "setenv"),
+            Stat => write!(f, "stat"),
+            Glob => write!(f, "glob"),
+            Mkdirp => write!(f, "mkdirp"),
+            Rename => write!(f, "rename"),
+            RmFile => write!(f, "rmfile"),
+            Sleep => write!(f, "sleep"),
+            Monotime => write!(f, "monotime"),
+            Signal => write!(f, "signal"),
+            RecordFile => write!(f, "record_file"),
+            RecordOffset => write!(f, "record_offset"),
+            RecordText => write!(f, "record_text"),
+            RoundTripSep => write!(f, "round_trip_sep"),
+            ArgvAssign => write!(f, "argv_assign"),
+            JaroWinkler => write!(f, "jaro_winkler"),
+            CmpNatural => write!(f, "cmp_natural"),
+            CmpLocale => write!(f, "cmp_locale"),
+            Trim => write!(f, "trim"),
+            LTrim => write!(f, "ltrim"),
+            RTrim => write!(f, "rtrim"),
+            Squeeze => write!(f, "squeeze"),
+            Color => write!(f, "color"),
+            Bold => write!(f, "bold"),
+            LPad => write!(f, "lpad"),
+            RPad => write!(f, "rpad"),
+            ZFill => write!(f, "zfill"),
             ToInt => write!(f, "int"),
             HexToInt => write!(f, "hex"),
+            IsNA => write!(f, "isna"),
+            NumOr => write!(f, "numor"),
             Rand => write!(f, "rand"),
             Srand => write!(f, "srand"),
             ReseedRng => write!(f, "srand_reseed"),
             System => write!(f, "system"),
             UpdateUsedFields => write!(f, "update_used_fields"),
             SetFI => write!(f, "set-FI"),
+            MatchSet => write!(f, "match-set"),
+            SetExit => write!(f, "set-exit"),
+            Exiting => write!(f, "exiting"),
+            SetExiting => write!(f, "set-exiting"),
+            TierUp => write!(f, "tier-up"),
+            SetMatched => write!(f, "set-matched"),
+            MarkInit => write!(f, "mark-init"),
+            CheckInit => write!(f, "check-init"),
         }
     }
 }
@@ -273,10 +345,13 @@ impl<'a> Display for lexer::Tok<'a> {
         let rep = match self {
             Begin => "BEGIN",
             Prepare => "PREPARE",
+            Group => "GROUP",
             End => "END",
+            Signal => "SIGNAL",
             Break => "break",
             Continue => "continue",
             Next => "next",
+            Exit => "exit",
             NextFile => "nextfile",
             For => "for",
             If => "if",
@@ -348,7 +423,7 @@ impl<'a> Display for lexer::Tok<'a> {
             CallStart(s) => return write!(fmt, "{}(", s),
             FunDec(s) => return write!(fmt, "function {}", s),
 
-            ILit(s) | HexLit(s) | FLit(s) => return write!(fmt, "{}", s),
+            ILit(s) | RadixLit(s) | FLit(s) => return write!(fmt, "{}", s),
         };
         write!(fmt, "{}", rep)
     }
@@ -0,0 +1,192 @@
+//! This module performs an _escape analysis_ of the strings in a (typed) frawk program.
+//!
+//! The bytecode interpreter allocates some strings (concatenations, `sprintf` output, numbers
+//! converted to strings, and so on) from a per-thread arena that is recycled from one record to
+//! the next; see `runtime::record_arena`. That only pays off for strings that are dropped
+//! shortly after they are built: a string from the arena that is stored in an array, for example,
+//! keeps an entire chunk of the arena alive. This analysis determines which string registers are
+//! assigned a newly-allocated string that may outlive the current record. A string _escapes_ if it
+//! (or a string derived from it, such as a concatenation containing it) may be:
+//!
+//! * Stored in a map, a special variable like `FS`, a global variable that is referenced from a
+//!   function, or a slot passed between stages of a parallel script.
+//! * Passed as an argument to a function. We do not track arguments into function bodies.
+//! * Used as a regular expression, file name or command, all of which are cached by the runtime.
+//!
+//! Like the other analyses in frawk, this one is flow-insensitive. It is only used as a heuristic:
+//! strings from the arena remain valid no matter how long they live. In particular, variables that
+//! are only referenced from the main body of a script are held in local registers, so a string
+//! assigned to one of them can be carried over into later records. That keeps at most one chunk
+//! alive per variable.
+use crate::bytecode::{Accum, Instr, Reg};
+use crate::common::NumTy;
+use crate::compile::{HighLevel, Ty};
+use crate::dataflow::{self, JoinSemiLattice, Key};
+use crate::runtime::Str;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Escape {
+    Escapes,
+    Local,
+}
+
+impl JoinSemiLattice for Escape {
+    type Func = ();
+    fn bottom() -> Escape {
+        Escape::Local
+    }
+    fn invoke(&mut self, other: &Self, (): &()) -> bool /* changed */ {
+        if *self == Escape::Local && *other == Escape::Escapes {
+            *self = Escape::Escapes;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct EscapeAnalysis {
+    // Edges are the reverse of those in the other analyses: a string escapes if any value derived
+    // from it does.
+    dfa: dataflow::Analysis<Escape>,
+    allocs: Vec<NumTy>,
+}
+
+fn flow(dfa: &mut dataflow::Analysis<Escape>, dst: Key, src: Option<Key>) {
+    let src = match src {
+        Some(src @ Key::Reg(_, Ty::Str)) => src,
+        _ => return,
+    };
+    match dst {
+        Key::Reg(_, Ty::Str) | Key::Func(_) => dfa.add_dep(src, dst, ()),
+        // Integers and floats computed from a string do not hold onto it.
+        Key::Reg(..) => {}
+        _ => dfa.add_src(src, Escape::Escapes),
+    }
+}
+
+impl EscapeAnalysis {
+    /// Mark a string register as escaping, e.g. because it holds a global variable.
+    pub(crate) fn escapes(&mut self, reg: NumTy) {
+        self.dfa.add_src(Key::Reg(reg, Ty::Str), Escape::Escapes);
+    }
+
+    fn escape<'a>(&mut self, reg: &Reg<Str<'a>>) {
+        self.dfa.add_src(reg, Escape::Escapes);
+    }
+
+    fn alloc<'a>(&mut self, reg: &Reg<Str<'a>>) {
+        let (reg, _) = reg.reflect();
+        self.dfa.add_query(Key::Reg(reg, Ty::Str));
+        self.allocs.push(reg);
+    }
+
+    pub(crate) fn visit_hl(&mut self, cur_fn_id: NumTy, inst: &HighLevel) {
+        use HighLevel::*;
+        match inst {
+            Call {
+                func_id,
+                dst_reg,
+                dst_ty,
+                args,
+            } => {
+                for (reg, ty) in args.iter().cloned() {
+                    if ty == Ty::Str {
+                        self.escapes(reg);
+                    }
+                }
+                if *dst_ty == Ty::Str {
+                    self.dfa
+                        .add_dep(Key::Func(*func_id), Key::Reg(*dst_reg, *dst_ty), ());
+                }
+            }
+            Ret(..) | Phi(..) => {
+                let dfa = &mut self.dfa;
+                dataflow::boilerplate::visit_hl(inst, cur_fn_id, |dst, src| flow(dfa, dst, src))
+            }
+            DropIter(..) | Loc(_) => {}
+        }
+    }
+
+    pub(crate) fn visit_ll<'a>(&mut self, inst: &Instr<'a>) {
+        use Instr::*;
+        match inst {
+            Concat(dst, _, _)
+            | Sprintf { dst, .. }
+            | IntToStr(dst, _)
+            | FloatToStr(dst, _)
+            | EscapeCSV(dst, _)
+            | EscapeTSV(dst, _)
+            | JoinCSV(dst, _, _)
+            | JoinTSV(dst, _, _)
+            | JoinColumns(dst, _, _, _)
+            | LPad(dst, _, _, _)
+            | RPad(dst, _, _, _)
+            | ZFill(dst, _, _) => self.alloc(dst),
+            IsMatch(_, _, pat) | Match(_, _, pat) | Sub(_, pat, _, _) | GSub(_, pat, _, _) => {
+                self.escape(pat)
+            }
+            PrintAll {
+                output: Some((out, _)),
+                ..
+            }
+            | Printf {
+                output: Some((out, _)),
+                ..
+            } => self.escape(out),
+            Close(file) | NextLine(_, file, _) | ReadErr(_, file, _) | RunCmd(_, file) => {
+                self.escape(file)
+            }
+            _ => {}
+        }
+        let dfa = &mut self.dfa;
+        dataflow::boilerplate::visit_ll(inst, |dst, src| flow(dfa, dst, src));
+    }
+
+    /// Solve the analysis, returning, for each string register, whether it is assigned a newly
+    /// allocated string that cannot outlive the current record.
+    pub(crate) fn solve(mut self, n_regs: usize) -> Vec<bool> {
+        let mut res = vec![false; n_regs];
+        for reg in self.allocs.iter().cloned() {
+            res[reg as usize] = *self.dfa.query(Key::Reg(reg, Ty::Str)) == Escape::Local;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::harness::record_local_strs;
+
+    fn local_strs(prog: &str) -> usize {
+        let strs = record_local_strs(prog).unwrap();
+        strs.iter().filter(|b| **b).count()
+    }
+
+    #[test]
+    fn temporaries_are_local() {
+        assert_eq!(local_strs(r#"{ print $1 "," $2; }"#), 2);
+        assert_eq!(
+            local_strs(r#"{ x = sprintf("%s-%d", $1, NR); print x; }"#),
+            1
+        );
+        assert_eq!(local_strs(r#"{ if (length($1 $2) > 3) print; }"#), 1);
+    }
+
+    #[test]
+    fn stored_strings_escape() {
+        assert_eq!(local_strs(r#"{ m[$1] = $2 "," $3; }"#), 0);
+        assert_eq!(
+            local_strs(
+                r#"function get() { return last; } { last = $1 "," $2; } END { print get(); }"#
+            ),
+            0
+        );
+        assert_eq!(local_strs(r#"{ print "x" > ($1 ".txt"); }"#), 0);
+        assert_eq!(
+            local_strs(r#"function f(x) { return x; } { y = f($1 $2); m[y]++; }"#),
+            0
+        );
+    }
+}
@@ -6,11 +6,11 @@ use crate::bytecode::Interp;
 use crate::codegen::llvm;
 use crate::{
     arena::Arena,
-    ast,
+    ast, bytecode,
     cfg::{self, Escaper},
     codegen::{self, intrinsics::IntoRuntime},
-    common::{ExecutionStrategy, Result},
-    compile, lexer,
+    common::{ExecutionStrategy, IntOverflow, Result},
+    compile, lexer, lint,
     parsing::syntax,
     pushdown::FieldSet,
     runtime::{
@@ -77,6 +77,15 @@ macro_rules! with_reader {
     };
 }
 
+// Only used by the JIT backends, which may not be available on this target.
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        feature = "llvm_backend"
+    )),
+    allow(dead_code)
+)]
 fn simulate_stdin_singlechar(
     field_sep: u8,
     record_sep: u8,
@@ -92,6 +101,14 @@ fn simulate_stdin_singlechar(
     )
 }
 
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        feature = "llvm_backend"
+    )),
+    allow(dead_code)
+)]
 fn simulate_stdin_whitespace(inp: impl Into<String>) -> impl IntoRuntime + runtime::LineReader {
     ByteReader::new_whitespace(
         split_stdin(inp.into()),
@@ -146,9 +163,20 @@ type ProgResult<'a> = Result<(
     HashMap<&'a str, compile::Ty>, /* type info */
 )>;
 
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        feature = "llvm_backend"
+    )),
+    allow(dead_code)
+)]
 const CODEGEN_CONFIG: codegen::Config = codegen::Config {
     opt_level: 0,
     num_workers: 1,
+    jit_opt: codegen::JitOpt::Speed,
+    perf_map: false,
+    int_overflow: IntOverflow::Promote,
 };
 
 pub(crate) fn run_program<'a>(
@@ -198,6 +226,9 @@ cfg_if! {
             let stmt = parse_program(prog, &a, esc, strat)?;
             let mut ctx = cfg::ProgramContext::from_prog(&a, stmt, esc)?;
             ctx.fold_regex_constants = true;
+            ctx.fold_constants(&a);
+            ctx.eliminate_common_subexprs();
+            ctx.hoist_loop_invariants();
             let sep_analysis = ctx.analyze_sep_assignments();
             if _PRINT_DEBUG_INFO {
                 let mut buf = Vec::<u8>::new();
@@ -211,8 +242,8 @@ cfg_if! {
                     simulate_stdin_csv(ifmt, stdin, strat),
                     fake_fs.clone(),
                     llvm::Config {
-                        opt_level: CODEGEN_CONFIG.opt_level,
                         num_workers: strat.num_workers(),
+                        ..CODEGEN_CONFIG
                     },
                 )?;
             } else {
@@ -229,6 +260,10 @@ cfg_if! {
     }
 }
 
+#[cfg_attr(
+    not(any(target_arch = "x86_64", target_arch = "aarch64")),
+    allow(dead_code)
+)]
 pub(crate) fn run_cranelift(
     prog: &str,
     stdin: impl Into<String>,
@@ -240,6 +275,9 @@ pub(crate) fn run_cranelift(
     let stmt = parse_program(prog, &a, esc, strat)?;
     let mut ctx = cfg::ProgramContext::from_prog(&a, stmt, esc)?;
     ctx.fold_regex_constants = true;
+    ctx.fold_constants(&a);
+    ctx.eliminate_common_subexprs();
+    ctx.hoist_loop_invariants();
     let sep_analysis = ctx.analyze_sep_assignments();
     if _PRINT_DEBUG_INFO {
         let mut buf = Vec::<u8>::new();
@@ -253,13 +291,20 @@ pub(crate) fn run_cranelift(
             simulate_stdin_csv(ifmt, stdin, strat),
             fake_fs.clone(),
             codegen::Config {
-                opt_level: CODEGEN_CONFIG.opt_level,
                 num_workers: strat.num_workers(),
+                ..CODEGEN_CONFIG
             },
+            /*cache=*/ None,
         )?;
     } else {
         with_reader!(sep_analysis, stdin, |reader| {
-            compile::run_cranelift(&mut ctx, reader, fake_fs.clone(), CODEGEN_CONFIG)?;
+            compile::run_cranelift(
+                &mut ctx,
+                reader,
+                fake_fs.clone(),
+                CODEGEN_CONFIG,
+                /*cache=*/ None,
+            )?;
         });
     }
     let v = fake_fs.stdout.read_data();
@@ -299,16 +344,108 @@ pub(crate) fn used_fields(prog: &str) -> Result<FieldSet> {
     compile::used_fields(&mut ctx)
 }
 
+pub(crate) fn record_local_strs(prog: &str) -> Result<Vec<bool>> {
+    let a = Arena::default();
+    let esc = Escaper::Identity;
+    let stmt = parse_program(prog, &a, esc, ExecutionStrategy::Serial)?;
+    let mut ctx = cfg::ProgramContext::from_prog(&a, stmt, esc)?;
+    compile::record_local_strs(&mut ctx)
+}
+
+/// Eliminate common subexpressions in `prog`, returning the resulting CFG.
+pub(crate) fn eliminate_common_subexprs(prog: &str) -> Result<String> {
+    let a = Arena::default();
+    let esc = Escaper::Identity;
+    let stmt = parse_program(prog, &a, esc, ExecutionStrategy::Serial)?;
+    let mut ctx = cfg::ProgramContext::from_prog(&a, stmt, esc)?;
+    ctx.eliminate_common_subexprs();
+    let mut buf = Vec::<u8>::new();
+    ctx.dbg_print(&mut buf).unwrap();
+    Ok(String::from_utf8(buf).unwrap())
+}
+
+/// Move loop invariants in `prog` out of their loops, returning the resulting CFG.
+pub(crate) fn hoist_loop_invariants(prog: &str) -> Result<String> {
+    let a = Arena::default();
+    let esc = Escaper::Identity;
+    let stmt = parse_program(prog, &a, esc, ExecutionStrategy::Serial)?;
+    let mut ctx = cfg::ProgramContext::from_prog(&a, stmt, esc)?;
+    ctx.hoist_loop_invariants();
+    let mut buf = Vec::<u8>::new();
+    ctx.dbg_print(&mut buf).unwrap();
+    Ok(String::from_utf8(buf).unwrap())
+}
+
+/// Count the string copies in the bytecode for `prog`, and the string moves that take their
+/// source instead.
+pub(crate) fn string_moves(prog: &str) -> Result<(usize, usize)> {
+    let a = Arena::default();
+    let esc = Escaper::Identity;
+    let stmt = parse_program(prog, &a, esc, ExecutionStrategy::Serial)?;
+    let mut ctx = cfg::ProgramContext::from_prog(&a, stmt, esc)?;
+    let prog = compile::bytecode_program(&mut ctx)?;
+    let (mut movs, mut takes) = (0, 0);
+    for inst in prog.instrs.iter().flat_map(|f| f.iter()) {
+        match inst {
+            bytecode::Instr::Mov(compile::Ty::Str, _, _) => movs += 1,
+            bytecode::Instr::TakeStr(..) => takes += 1,
+            _ => {}
+        }
+    }
+    Ok((movs, takes))
+}
+
+/// The warnings for comparisons in `prog` that are performed on strings, with the line each
+/// appears on (starting at 1).
+pub(crate) fn lint_comparisons(prog: &str) -> Result<Vec<(usize, String)>> {
+    let a = Arena::default();
+    let esc = Escaper::Identity;
+    let stmt = parse_program(prog, &a, esc, ExecutionStrategy::Serial)?;
+    let mut ctx = cfg::ProgramContext::from_prog(&a, stmt, esc)?;
+    Ok(lint::lint_comparisons(&mut ctx)?
+        .into_iter()
+        .map(|w| (w.span.map_or(0, |(start, _)| start.line + 1), w.msg))
+        .collect())
+}
+
+/// Fold constants in `prog`, returning the resulting CFG along with the field separator that the
+/// program can be specialized to, if any.
+pub(crate) fn fold_constants(prog: &str) -> Result<(String, Option<Vec<u8>>)> {
+    let a = Arena::default();
+    let esc = Escaper::Identity;
+    let stmt = parse_program(prog, &a, esc, ExecutionStrategy::Serial)?;
+    let mut ctx = cfg::ProgramContext::from_prog(&a, stmt, esc)?;
+    ctx.fold_constants(&a);
+    let mut buf = Vec::<u8>::new();
+    ctx.dbg_print(&mut buf).unwrap();
+    let field_sep = match ctx.analyze_sep_assignments() {
+        cfg::SepAssign::Potential { field_sep, .. } => field_sep.map(Vec::from),
+        cfg::SepAssign::Unsure => None,
+    };
+    Ok((String::from_utf8(buf).unwrap(), field_sep))
+}
+
 pub(crate) fn parse_program<'a, 'inp, 'outer>(
     prog: &'inp str,
     a: &'a Arena<'outer>,
     esc: Escaper,
     strat: ExecutionStrategy,
+) -> Result<Prog<'a>> {
+    parse_program_with(prog, a, esc, strat, /*inline_functions=*/ true)
+}
+
+pub(crate) fn parse_program_with<'a, 'inp, 'outer>(
+    prog: &'inp str,
+    a: &'a Arena<'outer>,
+    esc: Escaper,
+    strat: ExecutionStrategy,
+    inline_functions: bool,
 ) -> Result<Prog<'a>> {
     let prog = a.alloc_str(prog);
     let lexer = lexer::Tokenizer::new(prog);
     let mut buf = Vec::new();
     let mut program = ast::Prog::from_stage(strat.stage());
+    program.inline_functions = inline_functions;
     let parser = syntax::ProgParser::new();
     match parser.parse(a, &mut buf, &mut program, lexer) {
         Ok(()) => {
@@ -374,6 +511,9 @@ pub(crate) fn run_prog<'a>(
     strat: ExecutionStrategy,
 ) -> ProgResult<'a> {
     let mut ctx = cfg::ProgramContext::from_prog(arena, prog, esc)?;
+    ctx.fold_constants(arena);
+    ctx.eliminate_common_subexprs();
+    ctx.hoist_loop_invariants();
     // NB the invert_ident machinery only works for global identifiers. We could get it to work in
     // a limited capacity for locals, but it would require a lot more bookkeeping.
     let ident_map = ctx._invert_ident();
@@ -541,6 +681,7 @@ mod tests {
                     }
                 }
 
+                #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
                 #[test]
                 fn cranelift() {
                     match run_cranelift($e, $inp, $esc, $csv, ExecutionStrategy::Serial) {
@@ -583,6 +724,107 @@ mod tests {
 
     // TODO: extend testing macros to read non-stdout files and test more of those cases.
 
+    #[test]
+    fn runtime_stack_trace() {
+        let a = Arena::default();
+        let prog = "function check(s, pat) {\n  return s ~ pat\n}\n{ print check($0, $2 \"(\") }";
+        let e = match run_program(
+            &a,
+            prog,
+            "hi\n",
+            Escaper::Identity,
+            None,
+            ExecutionStrategy::Serial,
+        ) {
+            Ok(_) => panic!("invalid regex should fail at runtime"),
+            Err(e) => e,
+        };
+        let frames: Vec<_> = e
+            .trace
+            .iter()
+            .map(|f| (f.func.as_str(), f.span.map(|(start, _)| start.line)))
+            .collect();
+        assert_eq!(frames, vec![("check", Some(1)), ("<main>", Some(3))]);
+        assert_eq!(e.span.map(|(start, _)| start.line), Some(1));
+    }
+
+    #[test]
+    fn inlines_small_functions() {
+        let a = Arena::default();
+        let esc = Escaper::Identity;
+        let prog = r#"function max(a, b) { return a > b ? a : b }
+function set(a) { $1 = a; return 1 }
+{ print max($1, 2) set(3) }"#;
+        let stmt = parse_program(prog, &a, esc, ExecutionStrategy::Serial).unwrap();
+        let ctx = cfg::ProgramContext::from_prog(&a, stmt, esc).unwrap();
+        let mut buf = Vec::<u8>::new();
+        ctx.dbg_print(&mut buf).unwrap();
+        let cfg = String::from_utf8(buf).unwrap();
+        let main = &cfg[cfg.find("function <main>").unwrap()..];
+        // `max` (function 0) is inlined, while `set` has side effects and is still called.
+        assert!(
+            !main.contains(" 0(") && main.contains(" 1(3@int)"),
+            "{}",
+            cfg
+        );
+    }
+
+    #[test]
+    fn dump_types() {
+        let a = Arena::default();
+        let esc = Escaper::Identity;
+        let prog = r#"function add(m, k) { m[k]++; return length(m) }
+{ n = add(counts, $1); x = x + 1.5 }
+END { print n, x }"#;
+        let stmt = parse_program(prog, &a, esc, ExecutionStrategy::Serial).unwrap();
+        let mut ctx = cfg::ProgramContext::from_prog(&a, stmt, esc).unwrap();
+        let types = compile::dump_types(&mut ctx).unwrap();
+        for line in &[
+            "    counts: MapStrFloat\n",
+            "    n: Int\n",
+            "    x: Float\n",
+            "functions:\n    add(m: MapStrFloat, k: Str) -> Int\n",
+        ] {
+            assert!(types.contains(line), "missing {:?} in:\n{}", line, types);
+        }
+    }
+
+    #[test]
+    fn profile() {
+        let a = Arena::default();
+        let esc = Escaper::Identity;
+        let prog = r#"function inc(x) { return x + 1 }
+$1 > 1 { n = inc(n) }
+END { print n }"#;
+        // As in main, functions are not inlined when profiling.
+        let stmt = parse_program_with(prog, &a, esc, ExecutionStrategy::Serial, false).unwrap();
+        let mut ctx = cfg::ProgramContext::from_prog(&a, stmt, esc).unwrap();
+        let mut interp = compile::bytecode(
+            &mut ctx,
+            simulate_stdin_regex("1\n2\n3\n"),
+            FakeFs::default(),
+            1,
+        )
+        .unwrap();
+        interp.enable_profile();
+        interp.run().unwrap();
+        let profile = interp.take_profile().unwrap();
+        let listing = crate::pretty::pretty_print_annotated(stmt, prog, &profile);
+        let count = |line: &str| {
+            listing
+                .lines()
+                .find(|l| l.ends_with(line))
+                .and_then(|l| l.split_whitespace().next())
+                .unwrap_or_else(|| panic!("missing {:?} in:\n{}", line, listing))
+                .to_string()
+        };
+        assert_eq!(count("return x + 1"), "2");
+        assert_eq!(count("$1 > 1 {"), "3");
+        assert_eq!(count("n = inc(n)"), "2");
+        assert_eq!(count("print n"), "1");
+        assert!(listing.contains("# 2 calls, "), "{}", listing);
+    }
+
     #[test]
     fn basic_used_fields() {
         let p1 = r#"{ print $0; x=1; if (z) { x=3 } else { x=4 }; print $x, $5; }"#;
@@ -674,7 +916,7 @@ mod tests {
         END { print x; }"#,
         r#"1,2<<<FILE BREAK>>>3,4<<<FILE BREAK>>>5,6<<<FILE BREAK>>>7,8
 9,10"#,
-        "1 2\n24.0\n"
+        "1 2\n24\n"
     );
 
     test_program!(
@@ -788,7 +1030,7 @@ it has one more line"#
         r#"function max(x, y) { return x<y?y:x; }
         { m=max($2+0, m); }
         END { print m; }"#,
-          "3.0\n",
+          "3\n",
           @input "help,1\nsomeone,2\nout,3\n"
     );
 
@@ -827,6 +1069,27 @@ it has one more line"#
         @input "1,a 2,b 3,c 4,d 5,e"
     );
 
+    test_program!(
+        field_sums,
+        r#"{ print int(fsum(1, NF)), int(fsum(2, 10) * 2), int(fmean(1, 4) * 4), int(fmean(3, 2)), int(fsum(5, 7)) }"#,
+        "10 18 10 0 0\n12 5 12 0 0\n",
+        @input "1 2 3 4\n10 x 2.5\n"
+    );
+
+    test_program!(
+        field_sums_empty_range,
+        r#"{ print fsum(3, 2), 1 / fsum(3, 2) }"#,
+        "0 inf\n",
+        @input "1 2 3 x 5"
+    );
+
+    test_program_csv!(
+        csv_field_sums,
+        r#"{ print int(fsum(2, NF)), int(fmean(1, NF) * 3) }"#,
+        "5 6\n7 10\n",
+        @input "1,2,3\n3,\"4\",3\n"
+    );
+
     test_program!(
         raw_getline,
         r#"{ print "even", $0; getline; print "odd", $0; }"#,
@@ -834,6 +1097,284 @@ it has one more line"#
         @input "0\n1\n2\n3"
     );
 
+    test_program!(
+        getline_side_effects,
+        r#"NR == 1 {
+    r = getline; print r, NR, FNR, NF, $0
+    r = getline x; print r, NR, FNR, NF, $0, x
+    r = "echo a b c d" | getline; print r, NR, FNR, NF, $0
+    r = "echo e" | getline x; print r, NR, FNR, NF, $0, x
+    r = getline < "/nonexistent/file"; print r, NR, NF, $0
+    r = getline x < "/nonexistent/file"; print r, x
+}
+END { $0 = "last"; x = "old"; r = getline; s = getline x; print r, s, NR, $0, x }"#,
+        "1 2 2 2 1 2\n1 3 3 2 1 2 3\n1 4 3 4 a b c d\n1 5 3 4 a b c d e\n-1 5 4 a b c d\n-1 e\n0 0 5 last old\n",
+        @input "0 1\n1 2\n3"
+    );
+
+    test_program!(
+        getline_side_effects_plain,
+        r#"NR == 1 {
+    getline; print NR, FNR, NF, $0
+    getline x; print NR, FNR, NF, $0, x
+    "echo a b c d" | getline; print NR, FNR, NF, $0
+    "echo e" | getline x; print NR, FNR, NF, $0, x
+    getline < "/nonexistent/file"; print NR, NF, $0
+    getline x < "/nonexistent/file"; print x
+}"#,
+        "2 2 2 1 2\n3 3 2 1 2 3\n4 3 4 a b c d\n5 3 4 a b c d e\n5 4 a b c d\ne\n",
+        @input "0 1\n1 2\n3"
+    );
+
+    test_program!(
+        getline_stdin_path,
+        r#"NR == 1 { r = getline x < "-"; print r, NR, $0, x; getline y < "/dev/stdin"; print y; }
+    END { r = getline z < "-"; print r, NR }"#,
+        "1 1 a b\nc\n0 2\n",
+        @input "a\nb\nc\nd"
+    );
+
+    test_program!(
+        nr_limit_early_exit,
+        r#"NR <= 2 { s = s $1 } NR == 1 || 2 > NR { print "first", $0 }
+END { print s }
+function f() { return 1 }"#,
+        "first 1 x\n12\n",
+        @input "1 x\n2 y\n3 z\n4 w"
+    );
+
+    test_program!(
+        nr_limit_observed_by_end,
+        r#"NR < 3 { s = s $1 } END { print s, NR }"#,
+        "12 4\n",
+        @input "1 x\n2 y\n3 z\n4 w"
+    );
+
+    test_program!(
+        nr_limit_nr_assigned,
+        r#"NR <= 2 { print; NR = 0 }"#,
+        "1 x\n2 y\n3 z\n4 w\n",
+        @input "1 x\n2 y\n3 z\n4 w"
+    );
+
+    test_program!(
+        regex_dispatch_literals,
+        r#"/ERROR/ { e++ } /WARN/ { w++ } /INFO/ { i++ } /DEBUG/ { d++; next } /ERR/ { r++ }
+    END { print e+0, w+0, i+0, d+0, r+0 }"#,
+        "3 1 1 1 2\n",
+        @input "ERROR a\nWARN b INFO\nDEBUG ERROR\nnone\nERRORS"
+    );
+
+    test_program!(
+        regex_dispatch_regexes,
+        r#"/^a/ { print "a", $0 } /[0-9]+$/ { print "n", $0 } /x|y/ { print "xy", $0 }
+$1 == "b" { print "b", $0 }
+/^$/ { print "empty" }"#,
+        "a abc\nn x12\nxy x12\nn b 3\nb b 3\nempty\n",
+        @input "abc\nx12\nb 3\n\nzzz"
+    );
+
+    test_program!(
+        regex_dispatch_record_modified,
+        r#"/a/ { sub(/a/, "b") } /b/ { print "b", $0 } /c/ { $0 = "a" } /a/ { print "a", $0 }"#,
+        "b b\nb bc\na a\n",
+        @input "a\nac"
+    );
+
+    test_program!(
+        regex_dispatch_sub,
+        r#"/foo/ { sub(/foo/, "qux") } /qux/ { print "qux:" $0 } /a1/ { } /a2/ { }"#,
+        "qux:qux\n",
+        @input "foo"
+    );
+
+    test_program!(
+        regex_dispatch_gsub_in_function,
+        r#"function f() { gsub(/o/, "0") }
+    /foo/ { f() } /f00/ { print "f00:" $0 } /a1/ { } /a2/ { }"#,
+        "f00:f00\n",
+        @input "foo"
+    );
+
+    test_program!(
+        regex_inline_flags,
+        r#"BEGIN { re = "(?i)error"; FS = "(?i)x" }
+$0 ~ re { print "match", $1, $2 }
+{ n = split($0, a, "(?x) [0-9] + "); gsub("(?i:e)r", "_"); print n, $0 }"#,
+        "match ab ERROR\n1 abXERROR\n3 1x2\nmatch Error: \n1 _ror:xXzzz\n",
+        @input "abXERROR\n1x2\nError:xXzzz"
+    );
+
+    test_program!(
+        regex_dispatch_inline_flags,
+        r#"/(?i)^get/ { g++ } /(?i)post/ { p++ } /(?s)a.b/ { s++ } /put/ { u++ }
+    END { print g+0, p+0, s+0, u+0 }"#,
+        "2 1 1 0\n",
+        @input "GET /\nget /x\nPost a\nPUT\naxb"
+    );
+
+    test_program!(
+        prefilter_guards,
+        r#"/err/ { n++; print $2 } index($0, "W:") == 1 { print "warn", NF }
+substr($0, 1, 2) == "I:" { i++ } $0 ~ /^x/ { s = s $1 }
+END { print n, i, s }"#,
+        "b\nwarn 3\nerr\n2 1 xa\n",
+        @input "err b\nW: c d\nnothing here\nI: x\nxa err d\n W: e"
+    );
+
+    test_program!(
+        prefilter_nr_used,
+        r#"/b/ { print NR, FNR } END { print NR }"#,
+        "2 2\n4 4\n4\n",
+        @input "a\nb\nc\nb"
+    );
+
+    test_program!(
+        prefilter_record_modified,
+        r#"/a/ { $0 = "b" } /b/ { print } END { print "done" }"#,
+        "b\nb\ndone\n",
+        @input "a\nb\nc"
+    );
+
+    test_program!(
+        prefilter_record_source,
+        r#"/b/ { n++ } END { print n, record_text() }"#,
+        "1 c\n",
+        @input "a\nb\nc"
+    );
+
+    test_program!(
+        record_provenance,
+        r#"{ $1 = "x"; print record_offset(), record_text(), $0 }
+record_text() ~ /c/ { getline; print record_offset(), record_text() }
+END { print record_offset(), record_text() }"#,
+        "0 a b x b\n4  x\n5 c d x d\n9 e\n9 e\n",
+        @input "a b\n\nc d\ne"
+    );
+
+    test_program!(
+        char_indexes,
+        r#"{ print length($0), index($0, "w"), substr($0, 2, 3), match($0, /w.r/), RSTART, RLENGTH }
+END {
+    for (i = 0; i < 100; i++) s = s "añb";
+    for (i = 1; i <= length(s); i++) if (substr(s, i, 1) == "ñ") n++;
+    print length(s), n, index(s, "bañ"), substr(s, 298, 10)
+}"#,
+        "11 7 éll 7 7 3\n5 0 ñañ 0 0 -1\n300 100 3 añb\n",
+        @input "héllo wörld\nañañ!"
+    );
+
+    test_program!(
+        substr_out_of_range,
+        r#"BEGIN { s = "héllo"; print "[" substr(s, 10) "]", "[" substr(s, 0, 2) "]", "[" substr(s, 2, -1) "]", "[" substr(s, -5, 100) "]" }"#,
+        "[] [hé] [] [héllo]\n"
+    );
+
+    test_program!(
+        grapheme_builtins,
+        r#"{
+    n = graphemes($0, g)
+    for (i = 1; i <= n; i++) printf "[%s]", g[i]
+    print "", length($0), grapheme_length($0), grapheme_substr($0, 2, 2), grapheme_substr($0, 4)
+}"#,
+        "[e\u{301}][t][é][👍🏽] 6 4 té 👍🏽\n",
+        @input "e\u{301}té👍🏽"
+    );
+
+    test_program!(
+        string_similarity,
+        r#"{ s = jaro_winkler($1, $2); print $1, levenshtein($1, $2), (s > 0.9) }"#,
+        "kitten 3 0\nmartha 2 1\nnaïve 1 0\n",
+        @input "kitten sitting\nmartha marhta\nnaïve naive"
+    );
+
+    test_program!(
+        na_aware_aggregation,
+        r#"BEGIN { FS = "," }
+{ if (!isna($2)) { sum += $2; n++ }; all += numor($2, -1) }
+END {
+    printf "%.2f %d %.2f %d %d %.2f\n", sum / n, n, all, isna(" 1e3 "), isna("3 x"), numor(" 2 ", 0)
+}"#,
+        "2.00 3 4.00 0 1 2.00\n",
+        @input "a,1\nb,\nc,3\nd,n/a\ne,2"
+    );
+
+    test_program!(
+        fused_column_conversions,
+        r#"{ n += and($1, 1); s = s $1 "/" ($1 + 0.5) " " } END { printf "%d %s\n", n, s }"#,
+        "2 1/1.5 2/2.5 3/3.5 \n",
+        @input "1 x\n2 y\n3 z"
+    );
+
+    test_program!(
+        globals_assigned_in_callee,
+        r#"function bump(k) { total += k; calls++; return total }
+{ for (i = 0; i < 3; i++) { x = bump($1); total += 0.5 } }
+END { printf "%.1f %d %.1f\n", total, calls, x }"#,
+        "12.0 6 11.5\n",
+        @input "1\n2"
+    );
+
+    test_program!(
+        natural_sort,
+        r#"function isort(a, n,   i, j, t) {
+    for (i = 2; i <= n; i++) {
+        t = a[i]
+        for (j = i - 1; j > 0 && cmp_natural(a[j], t) > 0; j--) a[j + 1] = a[j]
+        a[j + 1] = t
+    }
+}
+{ a[NR] = $0 }
+END { isort(a, NR); for (i = 1; i <= NR; i++) printf "%s ", a[i]; print cmp_locale("a", "b") }"#,
+        "file1 file2 file9 file10 x2y10 -1\n",
+        @input "file10\nfile9\nx2y10\nfile1\nfile2"
+    );
+
+    test_program!(
+        trim_builtins,
+        r#"{ print "[" trim($0) "]", "[" ltrim($0) "]", "[" rtrim($0) "]", "[" squeeze($0) "]" }
+    END { print trim("--x-y--", "-"), rtrim("«a»", "»"), trim(squeeze("  a \t b  ")) }"#,
+        "[a  b] [a  b ] [  a  b] [ a b ]\n[c] [c] [c] [c]\nx-y «a a b\n",
+        @input "  a  b \nc"
+    );
+
+    test_program!(
+        pad_builtins,
+        r#"{ print "[" lpad($1, 6) "]", "[" rpad($1, 6, ".") "]", zfill($2, 5), lpad($1, 2) }"#,
+        "[   abc] [abc...] 00042 abc\n[ héllo] [héllo.] -0007 héllo\n",
+        @input "abc 42\nhéllo -7"
+    );
+
+    test_program!(
+        color_builtins_off_terminal,
+        r#"{ x = color($1, "red") bold($2); print x, length(x); printf "%s\n", color($2, "GRAY") }"#,
+        "ab 19\nb\n",
+        @input "a b"
+    );
+
+    test_program!(
+        bounded_arrays,
+        r#"BEGIN { bound(lru, 2); bound(fifo, 2, "fifo") }
+{ lru[$1]++; fifo[$1]++; all[$1]++ }
+END {
+    print length(lru), ("b" in lru), lru["a"], lru["c"];
+    print length(fifo), ("b" in fifo), fifo["a"], fifo["c"];
+    print bound(all, 1), length(all);
+}"#,
+        "2 0 3 1\n2 0 1 1\n2 1\n",
+        @input "a\nb\na\nc\na"
+    );
+
+    test_program!(
+        functions_shadow_extension_builtins,
+        r#"function g(x) { return trim(x) }
+    function trim(s) { return "<" s ">" }
+    function sleep(n) { return n * 2 }
+    function levenshtein(a, b) { a[1] = b }
+    BEGIN { print trim(" a "), g("b"), sleep(2); levenshtein(m, "c"); print m[1] }"#,
+        "< a > <b> 4\nc\n"
+    );
+
     test_program!(single_stmt, r#"BEGIN {print "hello"}"#, "hello\n");
     test_program!(
         factorial,
@@ -970,7 +1511,7 @@ print w,z;
         print log2("32");
         print exp(1);
         }"#,
-        "2.0\n2.0\n5.0\n2.718281828459045\n"
+        "2\n2\n5\n2.718281828459045\n"
     );
 
     test_program!(
@@ -981,7 +1522,7 @@ print w,z;
     }"#,
         "1 where\n2 is\n3 all\n4 of\n5 this\n6 going\n",
         @input "",
-        @types [ m1 :: MapIntStr, i :: Int]
+        @types [ m1 :: MapIntStr ]
     );
 
     test_program!(
@@ -992,7 +1533,7 @@ print w,z;
     }"#,
         "1 where\n2 is\n3 this\n4 all\n5 going\n6 \n",
         @input "",
-        @types [ m1 :: MapIntStr, i :: Int]
+        @types [ m1 :: MapIntStr ]
     );
 
     test_program!(
@@ -1069,7 +1610,7 @@ print w,z;
         }"#,
         "0 1 2.5 4\n",
         @input "",
-        @types [ r0 :: Int, r1 :: Int, r2 :: Float, r3 :: Int, m :: MapIntInt  ]
+        @types [ r0 :: Float, r1 :: Float, r2 :: Float, r3 :: Float, m :: MapIntInt  ]
     );
 
     test_program!(
@@ -1099,7 +1640,7 @@ print w,z;
             print X(m, 2);
         }
         "#,
-        "49.0\n"
+        "49\n"
     );
 
     test_program!(
@@ -1240,6 +1781,36 @@ this as well"#
         "2\n3\n1\n256\n1\n-1\n3\n"
     );
 
+    test_program!(
+        numeric_literals,
+        r#"BEGIN {
+        print 1e3, 2.5e-3, 1_000_000, 1_000.5, 1E2 + 1, 1e;
+        }"#,
+        "1000 0.0025 1000000 1000.5 101 1\n"
+    );
+
+    test_program!(
+        radix_literals,
+        r#"BEGIN {
+        print 0x1F, 0XfF, 0o17, 0b101, -0b11, 0xffffffffffffffff;
+        printf "%b %x %o|%-5b|\n", 0b1010, 0o777, 0x40, 3;
+        print "0x10" + 0, int("0b11");
+        }"#,
+        "31 255 15 5 -3 -1\n1010 1ff 100|11   |\n0 0\n"
+    );
+
+    test_program!(
+        bit_rotates_and_counts,
+        r#"BEGIN {
+        print rotl(1, 63), rotl(-2, 1), rotl(3, 65);
+        print rotr(1, 1), rotr(6, 1), rotr(rotl(12345, 17), 17);
+        print popcount(0), popcount(255), popcount(-1);
+        print clz(0), clz(1), clz(-1), clz(rshiftl(-1, 4));
+        print rshift(-8, 65), lshift(1, 64);
+        }"#,
+        "-9223372036854775808 -3 6\n-9223372036854775808 3 12345\n0 8 64\n64 63 0 4\n-4 1\n"
+    );
+
     test_program!(
         column_substitutions,
         r#"{
@@ -1270,7 +1841,7 @@ this as well"#
     test_program!(
         arithmetic,
         r#"BEGIN { print 1+1; print 1+2*7; print 1+2*7^3; print 1+2*7^3*4; }"#,
-        "2\n15\n687.0\n2745.0\n"
+        "2\n15\n687\n2745\n"
     );
 
     test_program!(
@@ -1309,6 +1880,237 @@ this as well"#
         @input "4\n3\n2\n3\n2\n3\n"
     );
 
+    test_program!(
+        repeated_append,
+        r#"BEGIN {
+            for (i = 0; i < 200000; i++) {
+                s = s "ab";
+                if (i == 1000) { t = s; u = s "!"; }
+            }
+            s = s "cd";
+            print length(s), substr(s, length(s) - 3), length(t), substr(u, length(u) - 2);
+        }"#,
+        "400002 abcd 2002 ab!\n"
+    );
+
+    test_program!(
+        dead_rules,
+        r#"function unused(x) { return x + 1 }
+        NR < 2 && NR > 3 { print "never"; }
+        $1 > 5 && $1 < 3 { print "never" }
+        0 { print unused($1) }
+        (x++ && 0) || NR == 2 { print "x=" x; }"#,
+        "x=2\n",
+        @input "1\n7\n4\n"
+    );
+
+    test_program!(
+        common_subexprs,
+        r#"function g(a,    b) { b = a + 1; sub(/1/, "2", a); return b + a; }
+        $1 > 0 { s += $1 * 2; x = $1; y = x + 1; $1 = "5"; print $1 + 1, y, x + 1, g($2) }
+        END { print s }"#,
+        "6 2 2 33\n6 3 3 4\n6\n",
+        @input "1 11\n2 1\n-3 1\n"
+    );
+
+    test_program!(
+        loop_invariants,
+        r#"function f(n, a, b,    i, s) { for (i = 0; i < n; i++) s = s (a b) i; return s; }
+        BEGIN { x = 1.5; y = x + 1; s = "3" + 2; u = 7 ""; v = "1e2x" + 0;
+            for (i = 0; i < 3; i++) { w = i * 2.5; z = x + i; }
+            print y, s, u, v, w, z, f(3, "a", "b") f(0, "c", "d");
+        }"#,
+        "2.5 5 7 100 5 3.5 ab0ab1ab2\n"
+    );
+
+    test_program!(
+        inline_functions,
+        r#"function max(a, b) { return a > b ? a : b }
+        function scale(x) { return x * k }
+        function shadow(k) { return scale(2) }
+        BEGIN { k = 5; i = 1;
+            print max(3, 7), max("x", "a"), max(i++, i++), i;
+            print scale(3), shadow(10), max(1);
+        }"#,
+        "7 x 2 3\n15 10 1\n"
+    );
+
+    test_program!(
+        integer_division,
+        r#"BEGIN { intdiv(-7, 2, r); print r["quotient"], r["remainder"], length(r);
+            print divmod(-7, 2, d), d[1], d[2];
+            print divmod(7, -2, d), d[1], d[2], -7 % 2;
+        }"#,
+        "-3 -1 2\n-4 -4 1\n-4 -4 -1 -1\n"
+    );
+
+    test_program!(
+        hll_distinct_count,
+        r#"BEGIN { for (i = 0; i < 3000; i++) { n += hll_add(h, i % 1000) }
+            est = hll_count(h);
+            print (n <= 1000), (est > 950 && est < 1050), hll_add(h, 7), hll_count(e);
+        }"#,
+        "1 1 0 0\n"
+    );
+
+    test_program!(
+        bloom_membership,
+        r#"BEGIN { for (i = 0; i < 500; i++) { n += bloom_add(b, "id" i, 500, 0.001) }
+            for (i = 0; i < 500; i++) { fp += bloom_has(b, "other" i); tp += bloom_has(b, "id" i) }
+            print (n >= 499), tp, (fp < 5), bloom_add(b, "id7"), bloom_has(e, "x"), bloom_add(d, "y");
+        }"#,
+        "1 500 1 0 0 1\n"
+    );
+
+    test_program!(
+        sampling,
+        r#"BEGIN { for (i = 1; i <= 3; i++) print reservoir(r, 5, "x" i);
+            for (i = 4; i <= 1000; i++) reservoir(r, 5, "x" i);
+            for (k in r) n++;
+            print n, r[0], r[-1];
+            for (i = 0; i < 10000; i++) s += sample_bernoulli(0.25);
+            print (s > 2300 && s < 2700), sample_bernoulli(0), sample_bernoulli(1);
+        }"#,
+        "1\n2\n3\n7 1000 5\n1 0 1\n"
+    );
+
+    test_program!(
+        sliding_windows,
+        r#"{ print win_push(w, $1, 3), int(win_sum(w)), int(win_max(w)),
+            (win_avg(w) == win_sum(w) / 3) }"#,
+        "1 4 4 0\n2 5 4 0\n3 9 4 1\n3 7 4 1\n3 12 6 1\n3 8 6 1\n",
+        @input "4\n1\n4\n2\n6\n0\n"
+    );
+
+    test_program!(
+        getline_timeouts,
+        r#"BEGIN { f = "/dev/null"; a = settimeout(f, 10); b = settimeout(f, 20);
+            c = settimeout(f, -1); d = settimeout(f, -1);
+            print a, b, c, d; settimeout(f, 10); print (getline x < f) }"#,
+        "-1 10 20 -1\n0\n"
+    );
+
+    test_program!(
+        spawned_processes,
+        r#"BEGIN { h = spawn("sort -r; echo warn >&2; exit 2");
+            writeline(h, "a"); writeline(h, "c"); writeline(h, "b"); print wait(h);
+            while ((l = readline(h)) != "") printf "%s", l; printf "%s", readline(h, 2);
+            print "[" readcmd("echo hi; echo") "]" }"#,
+        "2\nc\nb\na\nwarn\n[hi]\n"
+    );
+
+    test_program!(
+        environment_and_files,
+        r#"BEGIN { print "[" getenv("FRAWK_UNSET_VAR") "]", getenv("FRAWK_UNSET_VAR", "none");
+            setenv("FRAWK_SET_VAR", "x y"); print getenv("FRAWK_SET_VAR"), readcmd("echo $FRAWK_SET_VAR");
+            print glob("/dev/nul[l]", g), g[1], glob("/dev/no-such-*", g), length(g);
+            print stat("/dev/null", st), st["size"], st["type"], stat("/no/such/file", st), length(st) }"#,
+        "[] none\nx y x y\n1 /dev/null 0 0\n1 0 other -1 0\n"
+    );
+
+    test_program!(
+        file_management,
+        r#"BEGIN { d = readcmd("mktemp -d"); print mkdirp(d "/x/y"), mkdirp(d "/x/y");
+            system("echo data >" d "/out.tmp");
+            print rename(d "/out.tmp", d "/x/y/out"), rename(d "/out.tmp", d "/x/y/out");
+            print readcmd("cat " d "/x/y/out");
+            print rmfile(d "/x/y/out"), rmfile(d "/x/y/out"), rmfile(d "/x");
+            system("rm -r " d) }"#,
+        "1 1\n1 -1\ndata\n1 -1 -1\n"
+    );
+
+    test_program!(
+        sleep_monotime,
+        r#"BEGIN { start = monotime(); print sleep(0.01), sleep(-1);
+            elapsed = monotime() - start; print (elapsed >= 0.01), (elapsed < 5) }"#,
+        "0 0\n1 1\n"
+    );
+
+    test_program!(
+        histograms,
+        r#"{ n = hist_add(h, $1, 10); hist_add(l, $1, 10, 1) }
+        END { print n; hist_print(h); printf "%s", hist_format(l); hist_print(e) }"#,
+        "1\n\
+         [0, 10)    2 ##########################\n\
+         [10, 20)   3 ########################################\n\
+         [20, 30)   1 #############\n\
+         [250, 260) 1 #############\n\
+         [1, 10)     2 ####################\n\
+         [10, 100)   4 ########################################\n\
+         [100, 1000) 1 ##########\n",
+        @input "1\n5\n12\n15\n18\n25\n250\n"
+    );
+
+    test_program!(
+        vector_math,
+        r#"BEGIN { w[1] = 2; w[2] = -1 }
+        { x[1] = $1 + 0; x[2] = $2 + 0; print int(vec_dot(w, x)) }
+        END { n = vec_add(s, w, w); vec_scale(t, s, 0.5); vec_sub(d, t, w);
+            print n, int(s[1]), int(s[2]), int(vec_norm(d)), vec_mul(m, w, x), int(m[1] + m[2]);
+            h[1] = 3; h[2] = 4; print int(vec_norm(h)), int(vec_dot(h, e)), length(e);
+            print 1 / vec_dot(h, e), 1 / vec_norm(e)
+        }"#,
+        "1\n4\n2 4 -2 0 2 4\n5 0 0\ninf inf\n",
+        @input "1 1\n3 2\n"
+    );
+
+    test_program!(
+        group_by_key_change,
+        r#"GROUP $1 { print k, s, n; s = n = 0 }
+        GROUP substr($1, 1, 1) { print "prefix", p }
+        { k = $1; p = substr($1, 1, 1); s += int($2); n++ }
+        END { print "end", NR }"#,
+        "a1 3 2\na2 5 1\nprefix a\nb1 3 3\nprefix b\nend 6\n",
+        @input "a1 1\na1 2\na2 5\nb1 1\nb1 1\nb1 1\n"
+    );
+
+    test_program!(
+        group_by_empty_input,
+        r#"GROUP $1 { print "group" } END { print "end" }"#,
+        "end\n"
+    );
+
+    test_program!(
+        signal_block_without_signal,
+        r#"{ n++ } SIGNAL { print "stopped" } END { print n, signal() }"#,
+        "3 0\n",
+        @input "a\nb\nc\n"
+    );
+
+    test_program!(
+        exit_in_begin_runs_end,
+        r#"BEGIN { print "begin"; exit; print "unreachable" } { print } END { print "end", NR }"#,
+        "begin\nend 0\n",
+        @input "a\nb\n"
+    );
+
+    test_program!(
+        exit_in_main_loop,
+        r#"{ print } NR == 2 { exit; x = 1 } END { print "end", NR, x; exit; print "unreachable" }"#,
+        "a\nb\nend 2 \n",
+        @input "a\nb\nc\n"
+    );
+
+    test_program!(
+        atexit_order,
+        r#"function f() { print "f" } function g() { print "g", NR }
+        BEGIN { atexit("f"); atexit("g"); atexit("f") } END { print "end"; exit; print "no" }"#,
+        "end\nf\ng 2\nf\n",
+        @input "a\nb\n"
+    );
+
+    test_program!(
+        atexit_user_function,
+        r#"function atexit(f) { return "called " f } BEGIN { print atexit("x") }"#,
+        "called x\n"
+    );
+
+    test_program!(
+        tee_user_function,
+        r#"function tee(a, b) { return a "-" b } BEGIN { print tee("x", "y") }"#,
+        "x-y\n"
+    );
+
     // TODO test more operators, consider more edge cases around functions
 }
 
@@ -116,6 +116,10 @@ impl TaintedStringAnalysis {
             GetColumn(dst, _) => self.dfa.add_src(dst, Taint::Tainted),
             ReadErrStdin(dst) => self.dfa.add_src(dst, Taint::Tainted),
             NextLineStdin(dst) => self.dfa.add_src(dst, Taint::Tainted),
+            NextLineStdinFused(dst) => self.dfa.add_src(dst, Taint::Tainted),
+            RecordFile(dst) | RecordText(dst) | ArgvAssign(dst) => {
+                self.dfa.add_src(dst, Taint::Tainted)
+            }
             StoreConstStr(dst, _) => self.dfa.add_src(dst, Taint::Okay),
             StoreConstInt(dst, _) => self.dfa.add_src(dst, Taint::Okay),
             StoreConstFloat(dst, _) => self.dfa.add_src(dst, Taint::Okay),
@@ -1,18 +1,23 @@
 use crate::builtins::Variable;
-use crate::bytecode::{Get, Instr, Label, Reg};
-use crate::common::{NumTy, Result, Stage};
+use crate::bytecode::{DebugInfo, Get, Instr, Label, Reg};
+use crate::common::{CompileError, IntOverflow, NumTy, Result, StackFrame, Stage};
 use crate::compile::{self, Ty};
+use crate::profile::{Profile, Profiler};
 use crate::pushdown::FieldSet;
-use crate::runtime::{self, Float, Int, Line, LineReader, Str, UniqueStr};
+use crate::runtime::{
+    self, compat::compare_strs, record_arena, Float, Int, Line, LineReader, Str, UniqueStr,
+};
+use crate::trace::{self, Tracer};
 
+use crate::runtime::matcher::Matcher;
 use crossbeam::scope;
 use crossbeam_channel::bounded;
 use hashbrown::HashMap;
 use rand::{self, rngs::StdRng, Rng, SeedableRng};
-use regex::bytes::Regex;
 
 use std::cmp;
 use std::mem;
+use std::sync::Arc;
 
 type ClassicReader = runtime::splitter::regex::RegexSplitter<Box<dyn std::io::Read>>;
 
@@ -31,6 +36,7 @@ pub(crate) struct Core<'a> {
     pub rng: StdRng,
     pub current_seed: u64,
     pub slots: Slots,
+    pub procs: runtime::process::Processes,
 }
 
 impl<'a> Drop for Core<'a> {
@@ -38,6 +44,8 @@ impl<'a> Drop for Core<'a> {
         if let Err(e) = self.write_files.shutdown() {
             eprintln_ignore!("{}", e);
         }
+        runtime::stats::file_done(&self.vars.filename, self.vars.fnr);
+        runtime::stats::regexes_done(self.regexes.stats());
     }
 }
 
@@ -108,8 +116,7 @@ impl Slots {
         macro_rules! for_each_slot_pair {
             ($s1:ident, $s2:ident, $body:expr) => {
                 for_each_slot_pair!(
-                    $s1, $s2, $body, int, float, strs, intint, intfloat, intstr, strint, strfloat,
-                    strstr
+                    $s1, $s2, $body, int, float, strs, intfloat, strint, strfloat, strstr
                 );
             };
             ($s1:ident, $s2:ident, $body:expr, $($fld:tt),*) => {$({
@@ -126,6 +133,23 @@ impl Slots {
                 *a_elt = a_elt_v.agg(b_elt_v);
             }
         });
+
+        // Bloom filters, histograms and reservoir samples are merged on their own terms.
+        macro_rules! combine_sketches {
+            ($fld:ident, $merge:expr) => {{
+                let (a, b) = (&mut self.$fld, &mut other.$fld);
+                a.resize_with(std::cmp::max(a.len(), b.len()), Default::default);
+                for (a_elt, b_elt_v) in a.iter_mut().zip(b.drain(..)) {
+                    if let Some(b_elt_v) = ($merge)(a_elt, b_elt_v) {
+                        let a_elt_v = mem::replace(a_elt, Default::default());
+                        *a_elt = a_elt_v.agg(b_elt_v);
+                    }
+                }
+            }};
+        }
+        combine_sketches!(intint, |a: &mut _, b| runtime::sketch::merge_bloom(a, b)
+            .and_then(|b| runtime::hist::merge(a, b)));
+        combine_sketches!(intstr, runtime::sketch::merge_reservoir);
     }
 }
 
@@ -138,6 +162,13 @@ pub fn set_slot<T: Default>(vec: &mut Vec<T>, slot: usize, v: T) {
     vec.push(v)
 }
 
+/// Take the value out of `slot`, leaving the default in its place. Slots that were never stored
+/// to are read as the default as well: with `--tiered`, the main loop loads every global it refers to,
+/// whether or not BEGIN stored it; see `runtime::tiered`.
+pub fn take_slot<T: Default>(vec: &mut [T], slot: usize) -> T {
+    vec.get_mut(slot).map(mem::take).unwrap_or_default()
+}
+
 pub fn combine_slot<T: Default>(vec: &mut Vec<T>, slot: usize, f: impl FnOnce(T) -> T) {
     if slot < vec.len() {
         let res = f(std::mem::replace(&mut vec[slot], Default::default()));
@@ -159,6 +190,7 @@ impl<'a> Core<'a> {
         let rs: UniqueStr<'a> = self.vars.rs.clone().into();
         let ors: UniqueStr<'a> = self.vars.ors.clone().into();
         let filename: UniqueStr<'a> = self.vars.filename.clone().into();
+        let argc = self.vars.argc;
         let argv = self.vars.argv.shuttle();
         let fi = self.vars.fi.shuttle();
         let slots = self.slots.clone();
@@ -175,7 +207,7 @@ impl<'a> Core<'a> {
                 fnr: 0,
                 rstart: 0,
                 rlength: 0,
-                argc: 0,
+                argc,
                 argv: argv.into(),
                 fi: fi.into(),
             };
@@ -186,6 +218,7 @@ impl<'a> Core<'a> {
                 rng: rand::rngs::StdRng::seed_from_u64(seed),
                 current_seed: seed,
                 slots,
+                procs: Default::default(),
             }
         }
     }
@@ -198,9 +231,20 @@ impl<'a> Core<'a> {
             rng: rand::rngs::StdRng::seed_from_u64(seed),
             current_seed: seed,
             slots: Default::default(),
+            procs: Default::default(),
         }
     }
 
+    /// Set the initial contents of ARGV and ARGC.
+    pub(crate) fn set_argv(&mut self, argv: &[&str]) {
+        self.vars.argc = argv.len() as Int;
+        self.vars.argv = argv
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| (i as Int, Str::from(arg.to_string())))
+            .collect();
+    }
+
     pub fn extract_result(&mut self) -> StageResult {
         StageResult {
             slots: mem::replace(&mut self.slots, Default::default()),
@@ -228,7 +272,7 @@ impl<'a> Core<'a> {
         self.regexes.regex_match_loc(&mut self.vars, pat, s)
     }
 
-    pub fn match_const_regex(&mut self, s: &Str<'a>, pat: &Regex) -> Result<Int> {
+    pub fn match_const_regex(&mut self, s: &Str<'a>, pat: &Matcher) -> Result<Int> {
         runtime::RegexCache::regex_const_match_loc(&mut self.vars, pat, s)
     }
 
@@ -237,42 +281,40 @@ impl<'a> Core<'a> {
     }
 
     pub fn load_int(&mut self, slot: usize) -> Int {
-        self.slots.int[slot]
+        self.slots.int.get(slot).cloned().unwrap_or_default()
     }
     pub fn load_float(&mut self, slot: usize) -> Float {
-        self.slots.float[slot]
+        self.slots.float.get(slot).cloned().unwrap_or_default()
     }
     pub fn load_str(&mut self, slot: usize) -> Str<'a> {
-        mem::replace(&mut self.slots.strs[slot], Default::default())
-            .into_str()
-            .upcast()
+        take_slot(&mut self.slots.strs, slot).into_str().upcast()
     }
     pub fn load_intint(&mut self, slot: usize) -> runtime::IntMap<Int> {
-        mem::replace(&mut self.slots.intint[slot], Default::default()).into()
+        take_slot(&mut self.slots.intint, slot).into()
     }
     pub fn load_intfloat(&mut self, slot: usize) -> runtime::IntMap<Float> {
-        mem::replace(&mut self.slots.intfloat[slot], Default::default()).into()
+        take_slot(&mut self.slots.intfloat, slot).into()
     }
     pub fn load_intstr(&mut self, slot: usize) -> runtime::IntMap<Str<'a>> {
-        mem::replace(&mut self.slots.intstr[slot], Default::default())
+        take_slot(&mut self.slots.intstr, slot)
             .into_iter()
             .map(|(k, v)| (k, v.into_str().upcast()))
             .collect()
     }
     pub fn load_strint(&mut self, slot: usize) -> runtime::StrMap<'a, Int> {
-        mem::replace(&mut self.slots.strint[slot], Default::default())
+        take_slot(&mut self.slots.strint, slot)
             .into_iter()
             .map(|(k, v)| (k.into_str().upcast(), v))
             .collect()
     }
     pub fn load_strfloat(&mut self, slot: usize) -> runtime::StrMap<'a, Float> {
-        mem::replace(&mut self.slots.strfloat[slot], Default::default())
+        take_slot(&mut self.slots.strfloat, slot)
             .into_iter()
             .map(|(k, v)| (k.into_str().upcast(), v))
             .collect()
     }
     pub fn load_strstr(&mut self, slot: usize) -> runtime::StrMap<'a, Str<'a>> {
-        mem::replace(&mut self.slots.strstr[slot], Default::default())
+        take_slot(&mut self.slots.strstr, slot)
             .into_iter()
             .map(|(k, v)| (k.into_str().upcast(), v.into_str().upcast()))
             .collect()
@@ -291,35 +333,35 @@ impl<'a> Core<'a> {
         set_slot(
             &mut self.slots.intint,
             slot,
-            s.iter(|i| i.map(|(k, v)| (*k, *v)).collect()),
+            s.iter(|i| i.map(|(k, v)| (k, *v)).collect()),
         )
     }
     pub fn store_intfloat(&mut self, slot: usize, s: runtime::IntMap<Float>) {
         set_slot(
             &mut self.slots.intfloat,
             slot,
-            s.iter(|i| i.map(|(k, v)| (*k, *v)).collect()),
+            s.iter(|i| i.map(|(k, v)| (k, *v)).collect()),
         )
     }
     pub fn store_intstr(&mut self, slot: usize, s: runtime::IntMap<Str<'a>>) {
         set_slot(
             &mut self.slots.intstr,
             slot,
-            s.iter(|i| i.map(|(k, v)| (*k, v.clone().unmoor().into())).collect()),
+            s.iter(|i| i.map(|(k, v)| (k, v.clone().unmoor().into())).collect()),
         )
     }
     pub fn store_strint(&mut self, slot: usize, s: runtime::StrMap<'a, Int>) {
         set_slot(
             &mut self.slots.strint,
             slot,
-            s.iter(|i| i.map(|(k, v)| (k.clone().unmoor().into(), *v)).collect()),
+            s.iter(|i| i.map(|(k, v)| (k.unmoor().into(), *v)).collect()),
         )
     }
     pub fn store_strfloat(&mut self, slot: usize, s: runtime::StrMap<'a, Float>) {
         set_slot(
             &mut self.slots.strfloat,
             slot,
-            s.iter(|i| i.map(|(k, v)| (k.clone().unmoor().into(), *v)).collect()),
+            s.iter(|i| i.map(|(k, v)| (k.unmoor().into(), *v)).collect()),
         )
     }
     pub fn store_strstr(&mut self, slot: usize, s: runtime::StrMap<'a, Str<'a>>) {
@@ -327,7 +369,7 @@ impl<'a> Core<'a> {
             &mut self.slots.strstr,
             slot,
             s.iter(|i| {
-                i.map(|(k, v)| (k.clone().unmoor().into(), v.clone().unmoor().into()))
+                i.map(|(k, v)| (k.unmoor().into(), v.clone().unmoor().into()))
                     .collect()
             }),
         )
@@ -404,12 +446,48 @@ macro_rules! map_regs {
     }};
 }
 
+// Apply the integer operation `$op` to `$arg`s, using the program's `IntOverflow` setting to pick
+// the wrapping or checked version of the operation.
+macro_rules! int_arith {
+    ($slf:expr, $op:ident, $($arg:expr),+) => {
+        paste::paste! {
+            match $slf.int_overflow {
+                IntOverflow::Promote | IntOverflow::Wrap => Int::[<wrapping_ $op>]($($arg),+),
+                IntOverflow::Checked => runtime::arith::[<checked_ $op>]($($arg),+)?,
+            }
+        }
+    };
+}
+
 pub(crate) struct Interp<'a, LR: LineReader = ClassicReader> {
     // index of `instrs` that contains "main"
     main_func: Stage<usize>,
+    // The function that calls the functions registered with `atexit`; see
+    // `ast::Prog::atexit_runner`.
+    pub(crate) atexit: Option<usize>,
     num_workers: usize,
     instrs: Vec<Vec<Instr<'a>>>,
     stack: Vec<(usize /*function*/, Label /*instr*/)>,
+    // Function names and source locations for `instrs`, used to report runtime errors. This is
+    // empty if the program was not compiled with that information.
+    pub(crate) debug: Arc<Vec<DebugInfo>>,
+    // Statement counts and timings, if we are running with `--profile`.
+    profile: Option<Box<Profiler>>,
+    // The statement log, if we are running with `--trace`, and the global variables it can print.
+    trace: Option<Box<Tracer>>,
+    // Whether either of `profile` or `trace` is set, so the dispatch loop can check both at once.
+    instrumented: bool,
+    pub(crate) globals: Vec<(String, NumTy, Ty)>,
+    // The string registers whose values are allocated from the record arena.
+    pub(crate) record_local_strs: Arc<Vec<bool>>,
+    // Whether converting a non-numeric string to a number is an error (`--strict`).
+    strict: bool,
+    // Whether the runtime checks made by `--strict` print a warning instead (`--strict=warn`).
+    strict_warn: bool,
+    // The variables checked by `--strict` that have been assigned; see `strict::instrument`.
+    initialized: Vec<bool>,
+    // What to do when integer addition, subtraction, multiplication or negation overflows.
+    int_overflow: IntOverflow,
 
     line: LR::Line,
     read_files: runtime::FileRead<LR>,
@@ -457,9 +535,20 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
         use compile::Ty::*;
         Interp {
             main_func,
+            atexit: None,
             num_workers,
             instrs,
             stack: Default::default(),
+            debug: Default::default(),
+            profile: None,
+            trace: None,
+            instrumented: false,
+            globals: Default::default(),
+            record_local_strs: Default::default(),
+            strict: false,
+            strict_warn: false,
+            initialized: Default::default(),
+            int_overflow: Default::default(),
             floats: default_of(regs(Float)),
             ints: default_of(regs(Int)),
             strs: default_of(regs(Str)),
@@ -485,6 +574,109 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
         &self.instrs
     }
 
+    /// Count and time the execution of each statement and function from now on.
+    pub(crate) fn enable_profile(&mut self) {
+        let instrs = &self.instrs;
+        self.profile = Some(Box::new(Profiler::new(&self.debug[..], |f| {
+            instrs[f].len()
+        })));
+        self.instrumented = true;
+    }
+
+    /// Log statements from the program `text` to standard error as they execute.
+    pub(crate) fn enable_trace(&mut self, text: &str, config: trace::Config) {
+        let globals = mem::replace(&mut self.globals, Default::default());
+        self.trace = Some(Box::new(Tracer::new(text, config, globals)));
+        self.instrumented = true;
+    }
+
+    /// Fail when a string that does not look like a number is converted to one, or a variable is
+    /// read before it is assigned. If `warn` is set, print a warning and carry on instead.
+    pub(crate) fn enable_strict(&mut self, warn: bool) {
+        self.strict = true;
+        self.strict_warn = warn;
+    }
+
+    /// Set the behavior of integer arithmetic that overflows.
+    pub(crate) fn set_int_overflow(&mut self, int_overflow: IntOverflow) {
+        self.int_overflow = int_overflow;
+    }
+
+    pub(crate) fn set_argv(&mut self, argv: &[&str]) {
+        self.core.set_argv(argv);
+    }
+
+    pub(crate) fn precompile_regexes(&mut self, pats: &[String]) {
+        self.core
+            .regexes
+            .precompile(pats.iter().map(String::as_str));
+    }
+
+    pub(crate) fn configure_input(&mut self, cfg: &runtime::InputConfig) {
+        self.read_files.configure(cfg);
+    }
+
+    fn check_numeric(&self, s: &Str) -> Result<()> {
+        let numeric = s.with_bytes(|bs| {
+            bs.iter().all(u8::is_ascii_whitespace) || runtime::compat::looks_numeric(bs)
+        });
+        if numeric {
+            return Ok(());
+        }
+        let msg = format!("non-numeric string {:?} used as a number", s.to_string());
+        if self.strict_warn {
+            let kind = runtime::warnings::Kind::NumericConversion;
+            runtime::warnings::warn(kind, None, msg);
+            return Ok(());
+        }
+        Err(CompileError::new(msg))
+    }
+
+    fn trace_statement(&mut self, cur_fn: usize, cur: usize) {
+        let lines = &self.debug[cur_fn].lines;
+        let span = match lines.binary_search_by_key(&cur, |(start, _)| *start) {
+            Ok(i) => match lines[i].1 {
+                Some(span) => span,
+                None => return,
+            },
+            Err(_) => return,
+        };
+        let mut tracer = match self.trace.take() {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        if let Some((stmt, vars)) = tracer.statement(&span) {
+            let mut line = format!("trace: NR={} {}: {}", self.core.vars.nr, span.0, stmt);
+            for (name, reg, ty) in vars {
+                let (reg, ty) = (*reg, *ty);
+                let val = match ty {
+                    Ty::Int => {
+                        runtime::convert::<_, Str>(*self.get(Reg::<Int>::from(reg))).to_string()
+                    }
+                    Ty::Float => {
+                        runtime::convert::<_, Str>(*self.get(Reg::<Float>::from(reg))).to_string()
+                    }
+                    Ty::Str => format!("{:?}", self.get(Reg::<Str>::from(reg)).to_string()),
+                    Ty::Null | Ty::IterInt | Ty::IterStr => continue,
+                    _ => {
+                        let map = reg;
+                        let len = map_regs!(ty, map, self.get(map).len());
+                        format!("<array of {}>", len)
+                    }
+                };
+                line.push_str(format!(" {}={}", name, val).as_str());
+            }
+            eprintln_ignore!("{}", line);
+        }
+        self.trace = Some(tracer);
+    }
+
+    /// The profile gathered since `enable_profile` was called.
+    pub(crate) fn take_profile(&mut self) -> Option<Profile> {
+        let regex_stats = self.core.regexes.stats();
+        self.profile.take().map(|p| p.finish(regex_stats))
+    }
+
     fn format_arg(&self, (reg, ty): (NumTy, Ty)) -> Result<runtime::FormatArg<'a>> {
         Ok(match ty {
             Ty::Str => self.get(Reg::<Str<'a>>::from(reg)).clone().into(),
@@ -494,11 +686,24 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
         })
     }
 
+    /// Allocate the string assigned to `reg` from the record arena until the returned guard is
+    /// dropped, if that string does not escape the current record.
+    fn arena_for(&self, reg: &Reg<Str<'a>>) -> record_arena::Active {
+        let local = self.record_local_strs.get(reg.index()).cloned();
+        record_arena::enter(local.unwrap_or(false))
+    }
+
     fn reset_file_vars(&mut self) {
+        runtime::stats::file_done(&self.core.vars.filename, self.core.vars.fnr);
         self.core.vars.fnr = 0;
         self.core.vars.filename = self.read_files.stdin_filename().upcast();
     }
 
+    fn edit_in_place(&mut self) -> Result<()> {
+        let input = self.read_files.main_input_file();
+        self.core.write_files.edit_in_place(input)
+    }
+
     pub(crate) fn run_parallel(&mut self) -> Result<()> {
         if self.num_workers <= 1 {
             return self.run_serial();
@@ -556,13 +761,31 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                 let sender = sender.clone();
                 let core_shuttle = self.core.shuttle(i as Int + 2);
                 let instrs = self.instrs.clone();
+                let debug = self.debug.clone();
+                let record_local_strs = self.record_local_strs.clone();
+                let strict = self.strict;
+                let strict_warn = self.strict_warn;
+                let initialized = self.initialized.clone();
+                let int_overflow = self.int_overflow;
                 s.spawn(move |_| {
                     let inner = || {
                         let mut interp = Interp {
                             main_func: Stage::Main(main_loop),
+                            atexit: None,
                             num_workers: 1,
                             instrs,
                             stack: Default::default(),
+                            debug,
+                            // Only the main thread is profiled.
+                            profile: None,
+                            trace: None,
+                            instrumented: false,
+                            globals: Default::default(),
+                            record_local_strs,
+                            strict,
+                            strict_warn,
+                            initialized,
+                            int_overflow,
                             core: core_shuttle(),
                             line: Default::default(),
                             read_files: handle(),
@@ -580,6 +803,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             iters_str: default_of(iters_str_size),
                         };
                         interp.run_at(main_loop)?;
+                        runtime::watchdog::end_record();
                         Ok(interp.core.extract_result())
                     };
                     // Ignore errors, as it means another thread executed with an error and we are
@@ -590,6 +814,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
             mem::drop(sender);
             self.core.vars.pid = 1;
             self.run_at(main_loop)?;
+            runtime::watchdog::end_record();
             self.core.vars.pid = 0;
             while let Ok(res) = receiver.recv() {
                 self.core.combine(res?);
@@ -597,7 +822,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
             Ok(())
         });
         wrap_error(scope_res)?;
-        if let Some(end) = end {
+        if let Some(end) = end.filter(|_| !runtime::writers::skip_end()) {
             mem::swap(&mut self.read_files.inputs, &mut old_read_files);
             self.run_at(end)?;
         }
@@ -607,30 +832,100 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
     pub(crate) fn run_serial(&mut self) -> Result<()> {
         let offs: crate::smallvec::SmallVec<[usize; 3]> = self.main_func.iter().cloned().collect();
         for off in offs.into_iter() {
-            self.run_at(off)?
+            // With `--tiered`, compiled code runs the rest of the program once the main loop has
+            // stopped for it.
+            if runtime::writers::skip_end() || runtime::tiered::stopped() {
+                break;
+            }
+            self.run_at(off)?;
+            runtime::watchdog::end_record();
         }
         Ok(())
     }
 
     pub(crate) fn run(&mut self) -> Result<()> {
-        match self.main_func {
+        runtime::stats::start_running();
+        // An `exit` in END leaves the flag set; clear it in case this process ran another program.
+        runtime::exit::set_exiting(-1);
+        let res = match self.main_func {
             Stage::Main(_) => self.run_serial(),
             Stage::Par { .. } => self.run_parallel(),
+        };
+        if res.is_ok() && !runtime::writers::skip_end() {
+            return res;
+        }
+        // The program stopped early, either because it failed or because stdout was closed. It
+        // did not get to call the functions registered with `atexit`, so call them here.
+        let atexit = match self.atexit.take() {
+            Some(atexit) => atexit,
+            None => return res,
+        };
+        self.stack.clear();
+        let hooks = self.run_at(atexit);
+        res.and(hooks)
+    }
+
+    /// The state a tiered program's main loop leaves behind when it stops so that compiled code
+    /// can take over (see `runtime::tiered`): the slots and open files of `Core`, and the current
+    /// record and main input.
+    pub(crate) fn into_parts(self) -> (Core<'a>, (LR::Line, runtime::FileRead<LR>)) {
+        (self.core, (self.line, self.read_files))
+    }
+
+    pub(crate) fn run_at(&mut self, cur_fn: usize) -> Result<()> {
+        let (mut cur_fn, mut cur) = (cur_fn, 0);
+        let res = self.run_from(&mut cur_fn, &mut cur);
+        if let Some(p) = &mut self.profile {
+            p.pause();
         }
+        res.map_err(|e| self.stack_trace(e, cur_fn, cur))
     }
 
-    pub(crate) fn run_at(&mut self, mut cur_fn: usize) -> Result<()> {
+    /// Attach the AWK-level call stack to an error raised while executing instruction `cur` of
+    /// function `cur_fn`.
+    fn stack_trace(&mut self, mut e: CompileError, cur_fn: usize, cur: usize) -> CompileError {
+        let callers = mem::replace(&mut self.stack, Default::default());
+        if self.debug.len() == 0 || e.trace.len() > 0 {
+            return e;
+        }
+        // Callers' stack entries hold the return address, one past the Call instruction.
+        let frames = callers
+            .into_iter()
+            .map(|(func, Label(ret))| (func, ret - 1))
+            .rev();
+        for (func, instr) in std::iter::once((cur_fn, cur)).chain(frames) {
+            let info = &self.debug[func];
+            e.trace.push(StackFrame {
+                func: info.name.clone(),
+                span: info.span_of(instr),
+            });
+        }
+        match e.trace[0].span {
+            Some(span) => e.with_span(span),
+            None => e,
+        }
+    }
+
+    fn run_from(&mut self, cur_fn: &mut usize, cur: &mut usize) -> Result<()> {
         use Instr::*;
         let mut scratch: Vec<runtime::FormatArg> = Vec::new();
         // We are only accessing one vector at a time here, but it's hard to convince the borrow
         // checker of this fact, so we access the vectors through raw pointers.
-        let mut instrs = (&mut self.instrs[cur_fn]) as *mut Vec<Instr<'a>>;
-        let mut cur = 0;
+        let mut instrs = (&mut self.instrs[*cur_fn]) as *mut Vec<Instr<'a>>;
 
         'outer: loop {
             // must end with Halt
-            cur = loop {
+            *cur = loop {
+                let cur = *cur;
                 debug_assert!(cur < unsafe { (*instrs).len() });
+                if self.instrumented {
+                    if let Some(p) = &mut self.profile {
+                        p.step(*cur_fn, cur);
+                    }
+                    if self.trace.is_some() {
+                        self.trace_statement(*cur_fn, cur);
+                    }
+                }
                 use Variable::*;
                 match unsafe { (*instrs).get_unchecked(cur) } {
                     StoreConstStr(sr, s) => {
@@ -646,16 +941,21 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         *self.get_mut(fr) = *f
                     }
                     IntToStr(sr, ir) => {
+                        let _arena = self.arena_for(sr);
                         let s = runtime::convert::<_, Str>(*self.get(*ir));
                         let sr = *sr;
                         *self.get_mut(sr) = s;
                     }
                     FloatToStr(sr, fr) => {
+                        let _arena = self.arena_for(sr);
                         let s = runtime::convert::<_, Str>(*self.get(*fr));
                         let sr = *sr;
                         *self.get_mut(sr) = s;
                     }
                     StrToInt(ir, sr) => {
+                        if self.strict {
+                            self.check_numeric(self.get(*sr))?;
+                        }
                         let i = runtime::convert::<_, Int>(self.get(*sr));
                         let ir = *ir;
                         *self.get_mut(ir) = i;
@@ -666,6 +966,9 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         *self.get_mut(ir) = i;
                     }
                     StrToFloat(fr, sr) => {
+                        if self.strict {
+                            self.check_numeric(self.get(*sr))?;
+                        }
                         let f = runtime::convert::<_, Float>(self.get(*sr));
                         let fr = *fr;
                         *self.get_mut(fr) = f;
@@ -684,7 +987,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l + r;
+                        *self.get_mut(res) = int_arith!(self, add, l, r);
                     }
                     AddFloat(res, l, r) => {
                         let res = *res;
@@ -696,7 +999,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l * r;
+                        *self.get_mut(res) = int_arith!(self, mul, l, r);
                     }
                     MulFloat(res, l, r) => {
                         let res = *res;
@@ -708,7 +1011,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l - r;
+                        *self.get_mut(res) = int_arith!(self, sub, l, r);
                     }
                     MinusFloat(res, l, r) => {
                         let res = *res;
@@ -720,19 +1023,19 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l % r;
+                        *self.get_mut(res) = runtime::arith::imod(l, r)?;
                     }
                     ModFloat(res, l, r) => {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l % r;
+                        *self.get_mut(res) = runtime::arith::fmod(l, r)?;
                     }
                     Div(res, l, r) => {
                         let res = *res;
                         let l = *self.get(*l);
                         let r = *self.get(*r);
-                        *self.get_mut(res) = l / r;
+                        *self.get_mut(res) = runtime::arith::div(l, r)?;
                     }
                     Pow(res, l, r) => {
                         let res = *res;
@@ -754,7 +1057,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     NegInt(res, ir) => {
                         let res = *res;
                         let i = *self.get(*ir);
-                        *self.get_mut(res) = -i;
+                        *self.get_mut(res) = int_arith!(self, neg, i);
                     }
                     NegFloat(res, fr) => {
                         let res = *res;
@@ -798,6 +1101,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l).clone();
                         let r = self.get(*r).clone();
+                        let _arena = self.arena_for(&res);
                         *self.get_mut(res) = Str::concat(l, r);
                     }
                     Match(res, l, r) => {
@@ -812,27 +1116,29 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             as Int;
                     }
                     MatchConst(res, x, pat) => {
+                        *index_mut(&mut self.ints, res) =
+                            self.core.match_const_regex(index(&self.strs, x), &*pat)?;
+                    }
+                    IsMatchConst(res, x, pat) => {
                         *index_mut(&mut self.ints, res) =
                             runtime::RegexCache::regex_const_match(&*pat, index(&self.strs, x))
                                 as Int;
                     }
-                    IsMatchConst(res, x, pat) => {
+                    MatchSet(res, x, set) => {
                         *index_mut(&mut self.ints, res) =
-                            self.core.match_const_regex(index(&self.strs, x), &*pat)?;
+                            index(&self.strs, x).with_bytes(|bs| set.matches(bs));
                     }
                     SubstrIndex(res, s, t) => {
                         let res = *res;
                         let s = index(&self.strs, s);
                         let t = index(&self.strs, t);
-                        *self.get_mut(res) = runtime::string_search::index_substr(t, s);
+                        *self.get_mut(res) = runtime::chars::index(s, t);
                     }
                     LenStr(res, s) => {
                         let res = *res;
                         let s = *s;
-                        // TODO consider doing a with_str here or enforce elsewhere that strings
-                        // cannot exceed u32::max.
-                        let len = self.get(s).len();
-                        *self.get_mut(res) = len as Int;
+                        let len = runtime::chars::len(self.get(s));
+                        *self.get_mut(res) = len;
                     }
                     Sub(res, pat, s, in_s) => {
                         let (subbed, new) = {
@@ -841,7 +1147,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             let in_s = index(&self.strs, in_s);
                             self.core
                                 .regexes
-                                .with_regex(pat, |re| in_s.subst_first(re, s))?
+                                .with_matcher(pat, |re| in_s.subst_first(re, s))?
                         };
                         *index_mut(&mut self.strs, in_s) = subbed;
                         *index_mut(&mut self.ints, res) = new as Int;
@@ -853,18 +1159,56 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             let in_s = index(&self.strs, in_s);
                             self.core
                                 .regexes
-                                .with_regex(pat, |re| in_s.subst_all(re, s))?
+                                .with_matcher(pat, |re| in_s.subst_all(re, s))?
                         };
                         *index_mut(&mut self.strs, in_s) = subbed;
                         *index_mut(&mut self.ints, res) = subs_made;
                     }
+                    Trim(res, s, set) => {
+                        *index_mut(&mut self.strs, res) = runtime::trim::trim(
+                            index(&self.strs, s),
+                            index(&self.strs, set),
+                            /*left=*/ true,
+                            /*right=*/ true,
+                        );
+                    }
+                    LTrim(res, s, set) => {
+                        *index_mut(&mut self.strs, res) = runtime::trim::trim(
+                            index(&self.strs, s),
+                            index(&self.strs, set),
+                            /*left=*/ true,
+                            /*right=*/ false,
+                        );
+                    }
+                    RTrim(res, s, set) => {
+                        *index_mut(&mut self.strs, res) = runtime::trim::trim(
+                            index(&self.strs, s),
+                            index(&self.strs, set),
+                            /*left=*/ false,
+                            /*right=*/ true,
+                        );
+                    }
+                    Squeeze(res, s) => {
+                        *index_mut(&mut self.strs, res) =
+                            runtime::trim::squeeze(index(&self.strs, s));
+                    }
+                    Color(res, s, name) => {
+                        *index_mut(&mut self.strs, res) =
+                            runtime::color::color(index(&self.strs, s), index(&self.strs, name))?;
+                    }
+                    Bold(res, s) => {
+                        *index_mut(&mut self.strs, res) =
+                            runtime::color::bold(index(&self.strs, s));
+                    }
                     EscapeCSV(res, s) => {
+                        let _arena = self.arena_for(res);
                         *index_mut(&mut self.strs, res) = {
                             let s = index(&self.strs, s);
                             runtime::escape_csv(s)
                         };
                     }
                     EscapeTSV(res, s) => {
+                        let _arena = self.arena_for(res);
                         *index_mut(&mut self.strs, res) = {
                             let s = index(&self.strs, s);
                             runtime::escape_tsv(s)
@@ -872,11 +1216,179 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     }
                     Substr(res, base, l, r) => {
                         let base = index(&self.strs, base);
-                        let len = base.len();
-                        let l = cmp::max(0, -1 + *index(&self.ints, l));
-                        let r =
-                            cmp::min(len as Int, l.saturating_add(*index(&self.ints, r))) as usize;
-                        *index_mut(&mut self.strs, res) = base.slice(l as usize, r);
+                        let l = *index(&self.ints, l);
+                        let r = *index(&self.ints, r);
+                        *index_mut(&mut self.strs, res) = runtime::chars::substr(base, l, r);
+                    }
+                    Levenshtein(res, a, b) => {
+                        *index_mut(&mut self.ints, res) = runtime::similarity::levenshtein(
+                            index(&self.strs, a),
+                            index(&self.strs, b),
+                        );
+                    }
+                    JaroWinkler(res, a, b) => {
+                        *index_mut(&mut self.floats, res) = runtime::similarity::jaro_winkler(
+                            index(&self.strs, a),
+                            index(&self.strs, b),
+                        );
+                    }
+                    CmpNatural(res, a, b) => {
+                        *index_mut(&mut self.ints, res) = runtime::collate::cmp_natural(
+                            index(&self.strs, a),
+                            index(&self.strs, b),
+                        );
+                    }
+                    CmpLocale(res, a, b) => {
+                        *index_mut(&mut self.ints, res) = runtime::collate::cmp_locale(
+                            index(&self.strs, a),
+                            index(&self.strs, b),
+                        );
+                    }
+                    IsNA(res, s) => {
+                        *index_mut(&mut self.ints, res) =
+                            runtime::is_na(index(&self.strs, s)) as Int;
+                    }
+                    NumOr(res, s, default) => {
+                        *index_mut(&mut self.floats, res) =
+                            runtime::num_or(index(&self.strs, s), *index(&self.floats, default));
+                    }
+                    GraphemeLen(res, s) => {
+                        *index_mut(&mut self.ints, res) =
+                            runtime::chars::grapheme_len(index(&self.strs, s));
+                    }
+                    LPad(res, s, width, with) => {
+                        let _arena = self.arena_for(res);
+                        let s = index(&self.strs, s);
+                        let width = *index(&self.ints, width);
+                        let with = index(&self.strs, with);
+                        *index_mut(&mut self.strs, res) = runtime::pad::lpad(s, width, with);
+                    }
+                    RPad(res, s, width, with) => {
+                        let _arena = self.arena_for(res);
+                        let s = index(&self.strs, s);
+                        let width = *index(&self.ints, width);
+                        let with = index(&self.strs, with);
+                        *index_mut(&mut self.strs, res) = runtime::pad::rpad(s, width, with);
+                    }
+                    ZFill(res, s, width) => {
+                        let _arena = self.arena_for(res);
+                        let s = index(&self.strs, s);
+                        let width = *index(&self.ints, width);
+                        *index_mut(&mut self.strs, res) = runtime::pad::zfill(s, width);
+                    }
+                    GraphemeSubstr(res, base, l, r) => {
+                        let base = index(&self.strs, base);
+                        let l = *index(&self.ints, l);
+                        let r = *index(&self.ints, r);
+                        *index_mut(&mut self.strs, res) =
+                            runtime::chars::grapheme_substr(base, l, r);
+                    }
+                    Graphemes(res, s, arr) => {
+                        let s = index(&self.strs, s);
+                        let arr = index(&self.maps_int_str, arr);
+                        *index_mut(&mut self.ints, res) = runtime::chars::graphemes(s, arr);
+                    }
+                    IntDiv(res, num, den, arr) => {
+                        let num = *index(&self.ints, num);
+                        let den = *index(&self.ints, den);
+                        let arr = index(&self.maps_str_int, arr);
+                        *index_mut(&mut self.ints, res) = runtime::arith::intdiv(num, den, arr)?;
+                    }
+                    DivMod(res, num, den, arr) => {
+                        let num = *index(&self.ints, num);
+                        let den = *index(&self.ints, den);
+                        let arr = index(&self.maps_int_int, arr);
+                        *index_mut(&mut self.ints, res) = runtime::arith::divmod(num, den, arr)?;
+                    }
+                    HllAdd(res, h, x) => {
+                        let h = index(&self.maps_int_int, h);
+                        let x = index(&self.strs, x);
+                        *index_mut(&mut self.ints, res) = runtime::sketch::hll_add(h, x);
+                    }
+                    HllCount(res, h) => {
+                        let h = index(&self.maps_int_int, h);
+                        *index_mut(&mut self.ints, res) = runtime::sketch::hll_count(h);
+                    }
+                    BloomAdd(res, b, x, n, p) => {
+                        let b = index(&self.maps_int_int, b);
+                        let x = index(&self.strs, x);
+                        let n = *index(&self.ints, n);
+                        let p = *index(&self.floats, p);
+                        *index_mut(&mut self.ints, res) = runtime::sketch::bloom_add(b, x, n, p)?;
+                    }
+                    BloomHas(res, b, x) => {
+                        let b = index(&self.maps_int_int, b);
+                        let x = index(&self.strs, x);
+                        *index_mut(&mut self.ints, res) = runtime::sketch::bloom_has(b, x)?;
+                    }
+                    Reservoir(res, arr, k, x) => {
+                        let arr = index(&self.maps_int_str, arr);
+                        let k = *index(&self.ints, k);
+                        let x = index(&self.strs, x);
+                        *index_mut(&mut self.ints, res) =
+                            runtime::sketch::reservoir(arr, k, x, &mut self.core.rng)?;
+                    }
+                    WinPush(res, w, x, n) => {
+                        let w = index(&self.maps_int_float, w);
+                        let x = *index(&self.floats, x);
+                        let n = *index(&self.ints, n);
+                        *index_mut(&mut self.ints, res) = runtime::window::push(w, x, n)?;
+                    }
+                    WinSum(res, w) => {
+                        let w = index(&self.maps_int_float, w);
+                        *index_mut(&mut self.floats, res) = runtime::window::sum(w);
+                    }
+                    WinAvg(res, w) => {
+                        let w = index(&self.maps_int_float, w);
+                        *index_mut(&mut self.floats, res) = runtime::window::avg(w);
+                    }
+                    WinMax(res, w) => {
+                        let w = index(&self.maps_int_float, w);
+                        *index_mut(&mut self.floats, res) = runtime::window::max(w);
+                    }
+                    HistAdd(res, h, x, w, log) => {
+                        let h = index(&self.maps_int_int, h);
+                        let x = *index(&self.floats, x);
+                        let w = *index(&self.floats, w);
+                        let log = *index(&self.ints, log);
+                        *index_mut(&mut self.ints, res) = runtime::hist::add(h, x, w, log)?;
+                    }
+                    HistFormat(res, h) => {
+                        let h = index(&self.maps_int_int, h);
+                        *index_mut(&mut self.strs, res) = runtime::hist::format(h);
+                    }
+                    VecDot(res, a, b) => {
+                        let a = index(&self.maps_int_float, a);
+                        let b = index(&self.maps_int_float, b);
+                        *index_mut(&mut self.floats, res) = runtime::vector::dot(a, b);
+                    }
+                    VecNorm(res, a) => {
+                        let a = index(&self.maps_int_float, a);
+                        *index_mut(&mut self.floats, res) = runtime::vector::norm(a);
+                    }
+                    VecAdd(res, dst, a, b) => {
+                        let dst = index(&self.maps_int_float, dst);
+                        let a = index(&self.maps_int_float, a);
+                        let b = index(&self.maps_int_float, b);
+                        *index_mut(&mut self.ints, res) = runtime::vector::add(dst, a, b);
+                    }
+                    VecSub(res, dst, a, b) => {
+                        let dst = index(&self.maps_int_float, dst);
+                        let a = index(&self.maps_int_float, a);
+                        let b = index(&self.maps_int_float, b);
+                        *index_mut(&mut self.ints, res) = runtime::vector::sub(dst, a, b);
+                    }
+                    VecMul(res, dst, a, b) => {
+                        let dst = index(&self.maps_int_float, dst);
+                        let a = index(&self.maps_int_float, a);
+                        let b = index(&self.maps_int_float, b);
+                        *index_mut(&mut self.ints, res) = runtime::vector::mul(dst, a, b);
+                    }
+                    VecScale(res, dst, a, k) => {
+                        let dst = index(&self.maps_int_float, dst);
+                        let a = index(&self.maps_int_float, a);
+                        let k = *index(&self.floats, k);
+                        *index_mut(&mut self.ints, res) = runtime::vector::scale(dst, a, k);
                     }
                     LTFloat(res, l, r) => {
                         let res = *res;
@@ -894,7 +1406,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l);
                         let r = self.get(*r);
-                        *self.get_mut(res) = l.with_bytes(|l| r.with_bytes(|r| l < r)) as Int;
+                        *self.get_mut(res) = (compare_strs(l, r) == cmp::Ordering::Less) as Int;
                     }
                     GTFloat(res, l, r) => {
                         let res = *res;
@@ -912,7 +1424,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l);
                         let r = self.get(*r);
-                        *self.get_mut(res) = l.with_bytes(|l| r.with_bytes(|r| l > r)) as Int;
+                        *self.get_mut(res) = (compare_strs(l, r) == cmp::Ordering::Greater) as Int;
                     }
                     LTEFloat(res, l, r) => {
                         let res = *res;
@@ -930,7 +1442,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l);
                         let r = self.get(*r);
-                        *self.get_mut(res) = l.with_bytes(|l| r.with_bytes(|r| l <= r)) as Int;
+                        *self.get_mut(res) = (compare_strs(l, r) != cmp::Ordering::Greater) as Int;
                     }
                     GTEFloat(res, l, r) => {
                         let res = *res;
@@ -948,7 +1460,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l);
                         let r = self.get(*r);
-                        *self.get_mut(res) = l.with_bytes(|l| r.with_bytes(|r| l >= r)) as Int;
+                        *self.get_mut(res) = (compare_strs(l, r) != cmp::Ordering::Less) as Int;
                     }
                     EQFloat(res, l, r) => {
                         let res = *res;
@@ -966,7 +1478,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let res = *res;
                         let l = self.get(*l);
                         let r = self.get(*r);
-                        *self.get_mut(res) = (l == r) as Int;
+                        *self.get_mut(res) = (compare_strs(l, r) == cmp::Ordering::Equal) as Int;
                     }
                     SetColumn(dst, src) => {
                         let col = *self.get(*dst);
@@ -985,8 +1497,46 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         )?;
                         *self.get_mut(dst) = res;
                     }
+                    GetColumnInt(dst, s, col) => {
+                        let col = *self.get(*col);
+                        let s = *s;
+                        let res = self.line.get_col(
+                            col,
+                            &self.core.vars.fs,
+                            &self.core.vars.ofs,
+                            &mut self.core.regexes,
+                        )?;
+                        if self.strict {
+                            self.check_numeric(&res)?;
+                        }
+                        let i = runtime::convert::<_, Int>(&res);
+                        *self.get_mut(s) = res;
+                        let dst = *dst;
+                        *self.get_mut(dst) = i;
+                        // Skip the StrToInt this replaced.
+                        break cur + 2;
+                    }
+                    GetColumnFloat(dst, s, col) => {
+                        let col = *self.get(*col);
+                        let s = *s;
+                        let res = self.line.get_col(
+                            col,
+                            &self.core.vars.fs,
+                            &self.core.vars.ofs,
+                            &mut self.core.regexes,
+                        )?;
+                        if self.strict {
+                            self.check_numeric(&res)?;
+                        }
+                        let f = runtime::convert::<_, Float>(&res);
+                        *self.get_mut(s) = res;
+                        let dst = *dst;
+                        *self.get_mut(dst) = f;
+                        break cur + 2;
+                    }
                     JoinCSV(dst, start, end) => {
                         let nf = self.line.nf(&self.core.vars.fs, &mut self.core.regexes)?;
+                        let _arena = self.arena_for(dst);
                         *index_mut(&mut self.strs, dst) = {
                             let start = *index(&self.ints, start);
                             let end = *index(&self.ints, end);
@@ -997,6 +1547,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     }
                     JoinTSV(dst, start, end) => {
                         let nf = self.line.nf(&self.core.vars.fs, &mut self.core.regexes)?;
+                        let _arena = self.arena_for(dst);
                         *index_mut(&mut self.strs, dst) = {
                             let start = *index(&self.ints, start);
                             let end = *index(&self.ints, end);
@@ -1005,8 +1556,23 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             })?
                         };
                     }
+                    SumColumns(dst, start, end) => {
+                        let nf = self.line.nf(&self.core.vars.fs, &mut self.core.regexes)?;
+                        let start = *index(&self.ints, start);
+                        let end = *index(&self.ints, end);
+                        *index_mut(&mut self.floats, dst) = self.line.sum_cols(start, end, nf)?;
+                    }
+                    MeanColumns(dst, start, end) => {
+                        let nf = self.line.nf(&self.core.vars.fs, &mut self.core.regexes)?;
+                        let start = *index(&self.ints, start);
+                        let end = *index(&self.ints, end);
+                        let sum = self.line.sum_cols(start, end, nf)?;
+                        *index_mut(&mut self.floats, dst) =
+                            runtime::splitter::mean_cols(sum, start, end);
+                    }
                     JoinColumns(dst, start, end, sep) => {
                         let nf = self.line.nf(&self.core.vars.fs, &mut self.core.regexes)?;
+                        let _arena = self.arena_for(dst);
                         *index_mut(&mut self.strs, dst) = {
                             let sep = index(&self.strs, sep);
                             let start = *index(&self.ints, start);
@@ -1044,6 +1610,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             scratch.push(self.format_arg(*a)?);
                         }
                         use runtime::str_impl::DynamicBuf;
+                        let _arena = self.arena_for(dst);
                         let fmt_str = index(&self.strs, fmt);
                         let mut buf = DynamicBuf::new(0);
                         fmt_str
@@ -1119,6 +1686,25 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     } => self.contains(*map_ty, *dst, *map, *key),
                     Delete { map_ty, map, key } => self.delete(*map_ty, *map, *key),
                     Len { map_ty, map, dst } => self.len(*map_ty, *map, *dst),
+                    Bound {
+                        map_ty,
+                        dst,
+                        map,
+                        size,
+                        policy,
+                    } => self.bound(*map_ty, *dst, *map, *size, *policy)?,
+                    LoadState {
+                        map_ty,
+                        dst,
+                        map,
+                        name,
+                    } => self.load_state(*map_ty, *dst, *map, *name)?,
+                    SaveState {
+                        map_ty,
+                        dst,
+                        map,
+                        name,
+                    } => self.save_state(*map_ty, *dst, *map, *name)?,
                     Store {
                         map_ty,
                         map,
@@ -1179,6 +1765,10 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     LoadSlot { ty, dst, slot } => self.load_slot(*ty, *dst, *slot),
                     StoreSlot { ty, src, slot } => self.store_slot(*ty, *src, *slot),
                     Mov(ty, dst, src) => self.mov(*ty, *dst, *src),
+                    TakeStr(dst, src) => {
+                        let s = mem::take(index_mut(&mut self.strs, src));
+                        *index_mut(&mut self.strs, dst) = s;
+                    }
                     AllocMap(ty, reg) => self.alloc_map(*ty, *reg),
 
                     // TODO add error logging for these errors perhaps?
@@ -1195,6 +1785,9 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     NextLine(dst, file, is_file) => {
                         let dst = *dst;
                         let file = index(&self.strs, file);
+                        if *is_file && runtime::may_prompt(file) {
+                            self.core.write_files.flush_stdout()?;
+                        }
                         match self.core.regexes.get_line(
                             file,
                             &self.core.vars.rs,
@@ -1205,7 +1798,126 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                             Err(_) => *self.get_mut(dst) = "".into(),
                         };
                     }
+                    Readable(dst, inputs, timeout) => {
+                        let dst = *dst;
+                        let inputs = index(&self.maps_str_int, inputs);
+                        let timeout = *index(&self.ints, timeout);
+                        let res = self.core.regexes.readable(
+                            inputs,
+                            timeout,
+                            &self.core.vars.rs,
+                            &mut self.read_files,
+                        )?;
+                        *self.get_mut(dst) = res;
+                    }
+                    SetTimeout(dst, input, ms) => {
+                        let dst = *dst;
+                        let input = index(&self.strs, input);
+                        let ms = *index(&self.ints, ms);
+                        let res = self.read_files.inputs.set_timeout(input, ms);
+                        *self.get_mut(dst) = res;
+                    }
+                    Spawn(dst, cmd) => {
+                        let cmd = index(&self.strs, cmd);
+                        *index_mut(&mut self.ints, dst) = self.core.procs.spawn(cmd);
+                    }
+                    ReadLine(dst, h, stream) => {
+                        let h = *index(&self.ints, h);
+                        let stream = *index(&self.ints, stream);
+                        *index_mut(&mut self.strs, dst) =
+                            self.core.procs.read_line(h, stream)?.upcast();
+                    }
+                    WriteLine(dst, h, s) => {
+                        let h = *index(&self.ints, h);
+                        let s = index(&self.strs, s);
+                        *index_mut(&mut self.ints, dst) = self.core.procs.write_line(h, s)?;
+                    }
+                    Wait(dst, h) => {
+                        let h = *index(&self.ints, h);
+                        *index_mut(&mut self.ints, dst) = self.core.procs.wait(h)?;
+                    }
+                    Getenv(dst, name, default) => {
+                        let name = index(&self.strs, name);
+                        let default = index(&self.strs, default);
+                        *index_mut(&mut self.strs, dst) = runtime::os::getenv(name, default);
+                    }
+                    Setenv(dst, name, val) => {
+                        let name = index(&self.strs, name);
+                        let val = index(&self.strs, val);
+                        *index_mut(&mut self.ints, dst) = runtime::os::setenv(name, val)?;
+                    }
+                    Stat(dst, path, arr) => {
+                        let path = index(&self.strs, path);
+                        let arr = index(&self.maps_str_str, arr);
+                        *index_mut(&mut self.ints, dst) = runtime::os::stat(path, arr);
+                    }
+                    Glob(dst, pattern, arr) => {
+                        let pattern = index(&self.strs, pattern);
+                        let arr = index(&self.maps_int_str, arr);
+                        *index_mut(&mut self.ints, dst) = runtime::os::glob(pattern, arr);
+                    }
+                    Mkdirp(dst, path) => {
+                        let path = index(&self.strs, path);
+                        *index_mut(&mut self.ints, dst) = runtime::os::mkdirp(path);
+                    }
+                    Rename(dst, from, to) => {
+                        let from = index(&self.strs, from);
+                        let to = index(&self.strs, to);
+                        *index_mut(&mut self.ints, dst) = runtime::os::rename(from, to);
+                    }
+                    RmFile(dst, path) => {
+                        let path = index(&self.strs, path);
+                        *index_mut(&mut self.ints, dst) = runtime::os::rmfile(path);
+                    }
+                    Sleep(dst, secs) => {
+                        let secs = *index(&self.floats, secs);
+                        *index_mut(&mut self.ints, dst) = runtime::os::sleep(secs);
+                    }
+                    Monotime(dst) => *index_mut(&mut self.floats, dst) = runtime::os::monotime(),
+                    Signal(dst) => *index_mut(&mut self.ints, dst) = runtime::signal::caught(),
+                    RecordFile(dst) => {
+                        *index_mut(&mut self.strs, dst) = self.read_files.record_file().upcast()
+                    }
+                    RecordOffset(dst) => {
+                        *index_mut(&mut self.ints, dst) = self.read_files.record_offset()
+                    }
+                    RecordText(dst) => {
+                        *index_mut(&mut self.strs, dst) = self.read_files.record_text().upcast()
+                    }
+                    ArgvAssign(dst) => {
+                        *index_mut(&mut self.strs, dst) = self.read_files.argv_assign().upcast()
+                    }
+                    RoundTripSep(dst, record, ors) => {
+                        let res = self
+                            .read_files
+                            .round_trip_sep(index(&self.strs, record), index(&self.strs, ors));
+                        *index_mut(&mut self.strs, dst) = res;
+                    }
+                    SetExit(dst, status) => {
+                        let status = *index(&self.ints, status);
+                        *index_mut(&mut self.ints, dst) = runtime::exit::set_status(status);
+                    }
+                    SetMatched(dst) => {
+                        runtime::exit::set_matched();
+                        *index_mut(&mut self.ints, dst) = 1;
+                    }
+                    Exiting(dst) => *index_mut(&mut self.ints, dst) = runtime::exit::exiting(),
+                    SetExiting(dst, n) => {
+                        let n = *index(&self.ints, n);
+                        *index_mut(&mut self.ints, dst) = runtime::exit::set_exiting(n);
+                    }
+                    TierUp(dst) => {
+                        *index_mut(&mut self.ints, dst) = runtime::tiered::tier_up() as Int
+                    }
+                    ReadCmd(dst, cmd) => {
+                        let out = index(&self.strs, cmd).with_bytes(runtime::command::read_command);
+                        *index_mut(&mut self.strs, dst) = Str::from(&out[..]).unmoor().upcast();
+                    }
                     ReadErrStdin(dst) => {
+                        if runtime::writers::skip_end() {
+                            // Stdout has been closed: stop without running END.
+                            return Ok(());
+                        }
                         let dst = *dst;
                         let res = self.read_files.read_err_stdin();
                         *self.get_mut(dst) = res;
@@ -1215,21 +1927,35 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let (changed, res) = self
                             .core
                             .regexes
-                            .get_line_stdin(&self.core.vars.rs, &mut self.read_files)?;
+                            .get_line_stdin(&self.core.vars, &mut self.read_files)?;
                         if changed {
                             self.reset_file_vars();
                         }
+                        if runtime::inplace::enabled() {
+                            self.edit_in_place()?;
+                        }
                         *self.get_mut(dst) = res;
                     }
-                    NextLineStdinFused() => {
+                    NextLineStdinFused(dst) => {
+                        record_arena::end_record();
+                        let dst = *dst;
                         let changed = self.core.regexes.get_line_stdin_reuse(
-                            &self.core.vars.rs,
+                            &self.core.vars,
                             &mut self.read_files,
                             &mut self.line,
                         )?;
                         if changed {
                             self.reset_file_vars()
                         }
+                        if runtime::inplace::enabled() {
+                            self.edit_in_place()?;
+                        }
+                        if runtime::writers::skip_end() {
+                            // Stdout has been closed: stop without running END.
+                            return Ok(());
+                        }
+                        let res = self.read_files.read_err_stdin();
+                        *self.get_mut(dst) = res;
                     }
                     NextFile() => {
                         self.read_files.next_file()?;
@@ -1239,6 +1965,33 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                         let fi = &self.core.vars.fi;
                         self.read_files.update_named_columns(fi);
                     }
+                    MarkInit(res, var) => {
+                        let var = *index(&self.ints, var) as usize;
+                        if var >= self.initialized.len() {
+                            self.initialized.resize(var + 1, false);
+                        }
+                        self.initialized[var] = true;
+                        *index_mut(&mut self.ints, res) = 1;
+                    }
+                    CheckInit(res, var, name) => {
+                        let var = *index(&self.ints, var) as usize;
+                        if !self.initialized.get(var).cloned().unwrap_or(false) {
+                            let msg = format!(
+                                "`{}` is read before it is assigned",
+                                index(&self.strs, name).to_string()
+                            );
+                            if !self.strict_warn {
+                                return Err(CompileError::new(msg));
+                            }
+                            // Only warn about each variable once.
+                            let kind = runtime::warnings::Kind::Uninitialized;
+                            runtime::warnings::warn(kind, None, msg);
+                            if let Some(init) = self.initialized.get_mut(var) {
+                                *init = true;
+                            }
+                        }
+                        *index_mut(&mut self.ints, res) = 1;
+                    }
                     SetFI(key, val) => {
                         let key = *index(&self.ints, key);
                         let val = *index(&self.ints, val);
@@ -1262,14 +2015,20 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                     Push(ty, reg) => self.push_reg(*ty, *reg),
                     Pop(ty, reg) => self.pop_reg(*ty, *reg),
                     Call(func) => {
-                        self.stack.push((cur_fn, Label(cur + 1)));
-                        cur_fn = *func;
+                        if let Some(p) = &mut self.profile {
+                            p.call(*func);
+                        }
+                        self.stack.push((*cur_fn, Label(cur + 1)));
+                        *cur_fn = *func;
                         instrs = &mut self.instrs[*func];
                         break 0;
                     }
                     Ret => {
                         if let Some((func, Label(inst))) = self.stack.pop() {
-                            cur_fn = func;
+                            if let Some(p) = &mut self.profile {
+                                p.ret(*cur_fn);
+                            }
+                            *cur_fn = func;
                             instrs = &mut self.instrs[func];
                             break inst as usize;
                         } else {
@@ -1362,6 +2121,32 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
         let len = map_regs!(map_ty, map, self.get(map).len() as Int);
         *index_mut(&mut self.ints, &dst.into()) = len;
     }
+    fn bound(
+        &mut self,
+        map_ty: Ty,
+        dst: NumTy,
+        map: NumTy,
+        size: NumTy,
+        policy: NumTy,
+    ) -> Result<()> {
+        let size = *index(&self.ints, &size.into());
+        let policy = index(&self.strs, &policy.into());
+        let evicted = map_regs!(map_ty, map, self.get(map).set_bound(size, policy)?);
+        *index_mut(&mut self.ints, &dst.into()) = evicted;
+        Ok(())
+    }
+    fn load_state(&mut self, map_ty: Ty, dst: NumTy, map: NumTy, name: NumTy) -> Result<()> {
+        let name = index(&self.strs, &name.into());
+        let loaded = map_regs!(map_ty, map, runtime::state::load(self.get(map), name)?);
+        *index_mut(&mut self.ints, &dst.into()) = loaded;
+        Ok(())
+    }
+    fn save_state(&mut self, map_ty: Ty, dst: NumTy, map: NumTy, name: NumTy) -> Result<()> {
+        let name = index(&self.strs, &name.into());
+        let saved = map_regs!(map_ty, map, runtime::state::save(self.get(map), name)?);
+        *index_mut(&mut self.ints, &dst.into()) = saved;
+        Ok(())
+    }
     fn iter_begin(&mut self, map_ty: Ty, map: NumTy, dst: NumTy) {
         let _k = 0u32;
         let _v = 0u32;
@@ -1476,7 +2261,7 @@ impl<'a, LR: LineReader> Interp<'a, LR> {
                 *index_mut(&mut self.maps_str_float, &dst.into()) = pop(&mut self.maps_str_float)
             }
             Ty::MapStrStr => {
-                *index_mut(&mut self.maps_str_float, &dst.into()) = pop(&mut self.maps_str_float)
+                *index_mut(&mut self.maps_str_str, &dst.into()) = pop(&mut self.maps_str_str)
             }
             Ty::Null | Ty::IterInt | Ty::IterStr => {
                 panic!("unsupported register type for pop operation: {:?}", ty)
@@ -0,0 +1,224 @@
+//! Specialized implementations of whole programs with a handful of very common shapes.
+//!
+//! Scripts like `{ s += $3 } END { print s }` or `{ print $2, $1 }` make up a large share of the
+//! awk that gets run in practice. For these, all the compiled program does is pull a few columns
+//! out of each record, so we skip compilation altogether and run a loop that does exactly that.
+//! The loop asks the splitter for only the columns it needs, and it uses the same conversion and
+//! output routines as the rest of the runtime, so the results are identical to running the
+//! program normally.
+use crate::ast::{Binop, Expr, Pattern, Prog, Stmt, Unop};
+use crate::builtins::Variable;
+use crate::common::{Result, Stage};
+use crate::pushdown::FieldSet;
+use crate::runtime::{
+    self, splitter::ReaderState, writers::FileFactory, FileWrite, Float, Line, LineReader,
+    RegexCache, Str,
+};
+
+use std::convert::TryFrom;
+
+/// The shapes of program that we recognize.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Shape {
+    /// `{ s += $col } END { print s }`
+    Sum(usize),
+    /// `{ print $c1, $c2, ... }`
+    Project(Vec<usize>),
+}
+
+#[derive(Debug)]
+pub(crate) struct Kernel {
+    shape: Shape,
+    ofs: Vec<u8>,
+    ors: Vec<u8>,
+}
+
+/// Return the kernel implementing `prog`, if it has one of the shapes in `Shape`.
+///
+/// Anything that could make the program behave differently from the kernel (a BEGIN block,
+/// function definitions, `-v` assignments, header parsing, and so on) rules the kernel out.
+pub(crate) fn recognize<'a, 'b>(prog: &Prog<'a, 'b, &'b str>) -> Option<Kernel> {
+    if !matches!(prog.stage, Stage::Main(()))
+        || prog.begin.is_some()
+        || prog.prepare.is_some()
+        || prog.signal.is_some()
+    {
+        return None;
+    }
+    if prog.decs.len() > 0
+        || prog.prelude_vardecs.len() > 0
+        || prog.operand_vardecs.len() > 0
+        || prog.parse_header
+        || prog.round_trip
+        || prog.match_status
+        || prog.trace
+        || prog.persist.len() > 0
+        || prog.stat_arrays.len() > 0
+    {
+        return None;
+    }
+    let body = match &prog.pats[..] {
+        [(_, Pattern::Null, Some(body))] => single(body)?,
+        _ => return None,
+    };
+    let shape = match (body, prog.end.map(single)) {
+        (Stmt::Expr(Expr::AssignOp(Expr::Var(v), Binop::Plus, e)), Some(Some(end))) => match end {
+            Stmt::Print(args, None) if args.len() == 1 && is_var(args[0], v) => {
+                Shape::Sum(column(e)?)
+            }
+            _ => return None,
+        },
+        (Stmt::Print(args, None), None) if args.len() > 0 => {
+            let mut cols = Vec::with_capacity(args.len());
+            for a in args.iter() {
+                cols.push(column(a)?);
+            }
+            Shape::Project(cols)
+        }
+        _ => return None,
+    };
+    Some(Kernel {
+        shape,
+        ofs: prog.output_sep.unwrap_or(b" ").to_vec(),
+        ors: prog.output_record_sep.unwrap_or(b"\n").to_vec(),
+    })
+}
+
+/// Strip location annotations and singleton blocks from `stmt`.
+fn single<'a, 'b, I>(mut stmt: &'a Stmt<'a, 'b, I>) -> Option<&'a Stmt<'a, 'b, I>> {
+    loop {
+        stmt = match stmt {
+            Stmt::Located(_, s) => s,
+            Stmt::Block(ss) if ss.len() == 1 => ss[0],
+            Stmt::Block(_) => return None,
+            s => return Some(s),
+        }
+    }
+}
+
+/// Whether `e` is a reference to the user-defined variable `v`.
+fn is_var<'b>(e: &Expr<&'b str>, v: &&'b str) -> bool {
+    match e {
+        Expr::Var(w) => w == v && Variable::try_from(*v).is_err(),
+        _ => false,
+    }
+}
+
+/// The column index `n` if `e` is of the form `$n`.
+fn column<'b>(e: &Expr<&'b str>) -> Option<usize> {
+    match e {
+        Expr::Unop(Unop::Column, Expr::ILit(n)) if *n >= 0 => Some(*n as usize),
+        _ => None,
+    }
+}
+
+impl Kernel {
+    /// Run the kernel over `inp`, splitting records on `field_sep` and `record_sep`.
+    pub(crate) fn run<LR: LineReader>(
+        &self,
+        mut inp: LR,
+        ff: impl FileFactory,
+        field_sep: &[u8],
+        record_sep: &[u8],
+    ) -> Result<()> {
+        let fs = Str::from(field_sep);
+        let rs = Str::from(record_sep);
+        let ofs = Str::from(&self.ofs[..]);
+        let ors = Str::from(&self.ors[..]);
+        let mut out = FileWrite::new(ff);
+        let mut rc = RegexCache::default();
+        let mut line = LR::Line::default();
+
+        let mut used = FieldSet::empty();
+        match &self.shape {
+            Shape::Sum(col) => used.set(*col),
+            Shape::Project(cols) => cols.iter().for_each(|c| used.set(*c)),
+        }
+        inp.set_used_fields(&used);
+
+        macro_rules! records {
+            (|$line:ident| $body:expr) => {
+                loop {
+                    inp.read_line_reuse(&rs, &mut rc, &mut line)?;
+                    if inp.read_state() != ReaderState::OK as i64
+                        || runtime::writers::stdout_closed()
+                    {
+                        break;
+                    }
+                    let $line = &mut line;
+                    $body
+                }
+            };
+        }
+
+        match &self.shape {
+            Shape::Sum(col) => {
+                let col = *col as runtime::Int;
+                let mut sum: Float = 0.0;
+                records!(|line| {
+                    let s = line.get_col(col, &fs, &ofs, &mut rc)?;
+                    sum += runtime::convert::<_, Float>(&s);
+                });
+                let s: Str = runtime::convert::<_, Str>(sum);
+                out.write_all(&[&s, &ors], None)?;
+            }
+            Shape::Project(cols) => {
+                let mut strs = Vec::with_capacity(cols.len() * 2);
+                records!(|line| {
+                    strs.clear();
+                    for c in cols.iter() {
+                        strs.push(line.get_col(*c as runtime::Int, &fs, &ofs, &mut rc)?);
+                    }
+                    let mut args = smallvec::SmallVec::<[&Str; 8]>::new();
+                    for (i, s) in strs.iter().enumerate() {
+                        if i > 0 {
+                            args.push(&ofs);
+                        }
+                        args.push(s);
+                    }
+                    args.push(&ors);
+                    if out.write_all(&args[..], None).is_err() {
+                        // Mirror the interpreter, which stops quietly when it cannot print.
+                        break;
+                    }
+                });
+            }
+        }
+        out.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena::Arena;
+    use crate::harness::parse_program_with;
+
+    fn shape(prog: &str) -> Option<Shape> {
+        let a = Arena::default();
+        let stmt = parse_program_with(
+            prog,
+            &a,
+            crate::cfg::Escaper::Identity,
+            crate::common::ExecutionStrategy::Serial,
+            false,
+        )
+        .unwrap();
+        recognize(stmt).map(|k| k.shape)
+    }
+
+    #[test]
+    fn recognizes_shapes() {
+        assert_eq!(shape("{ s += $3 } END { print s }"), Some(Shape::Sum(3)));
+        assert_eq!(
+            shape("{ print $2, $1, $0 }"),
+            Some(Shape::Project(vec![2, 1, 0]))
+        );
+        assert_eq!(shape("{ s += $3 } END { print t }"), None);
+        assert_eq!(shape("{ NR += $3 } END { print NR }"), None);
+        assert_eq!(shape("BEGIN { FS = \",\" } { print $2 }"), None);
+        assert_eq!(shape("NR > 1 { print $2 }"), None);
+        assert_eq!(shape("{ print $2 > \"out\" }"), None);
+        assert_eq!(shape("{ print $(1+1) }"), None);
+    }
+}
@@ -9,23 +9,27 @@ use unicode_xid::UnicodeXID;
 
 use crate::arena::Arena;
 
-#[derive(PartialEq, Eq, Clone, Debug, Default)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
 pub struct Loc {
     pub line: usize,
     pub col: usize,
-    offset: usize,
+    pub(crate) offset: usize,
 }
 
 pub type Spanned<T> = (Loc, T, Loc);
+pub type Span = (Loc, Loc);
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Tok<'a> {
     Begin,
     Prepare,
+    Group,
     End,
+    Signal,
     Break,
     Continue,
     Next,
+    Exit,
     NextFile,
     For,
     If,
@@ -98,7 +102,7 @@ pub enum Tok<'a> {
     FunDec(&'a str),
 
     ILit(&'a str),
-    HexLit(&'a str),
+    RadixLit(&'a str),
     FLit(&'a str),
 }
 
@@ -131,16 +135,20 @@ lazy_static! {
     static ref WS_SEMI: Regex = Regex::new(r"^[\s;]").unwrap();
     static ref WS_SEMI_RPAREN: Regex = Regex::new(r"^[\s;)]").unwrap();
     static ref WS_PAREN: Regex = Regex::new(r"^[\s()]").unwrap();
+    static ref NOT_IDENT: Regex = Regex::new(r"^([^a-zA-Z0-9_]|$)").unwrap();
+    static ref BLOCK: Regex = Regex::new(r"^[ \t]*\{").unwrap();
 }
 
 keyword_map!(
     KEYWORDS<&'static [u8], (Tok<'static>, Option<Regex>)>,
     [b"PREPARE", Tok::Prepare],
+    [b"GROUP", Tok::Group, WS_PAREN.clone()],
     [b"BEGIN", Tok::Begin, WS_BRACE.clone()],
     [b"END", Tok::End, WS_BRACE.clone()],
     [b"break", Tok::Break, WS_SEMI.clone()],
     [b"continue", Tok::Continue, WS_SEMI.clone()],
     [b"next", Tok::Next],
+    [b"exit", Tok::Exit, NOT_IDENT.clone()],
     [b"nextfile", Tok::NextFile],
     [b"for", Tok::For, WS_PAREN.clone()],
     [b"if", Tok::If],
@@ -217,7 +225,11 @@ pub struct Tokenizer<'a> {
     text: &'a str,
     cur: usize,
     prev_tok: Option<Tok<'a>>,
+    // The number of braces opened and not yet closed before the current token.
+    depth: usize,
     lines: Vec<usize>,
+    // Comments skipped so far, along with their starting locations.
+    comments: Vec<(Loc, &'a str)>,
 }
 
 fn is_id_start(c: char) -> bool {
@@ -339,6 +351,17 @@ pub(crate) fn parse_string_literal<'a, 'outer>(
     arena.alloc_bytes(&buf[..])
 }
 
+/// Numeric literals can separate groups of digits with underscores, as in `1_000_000`. Returns the
+/// literal with the underscores removed, ready to be passed to `strtoi` or `strtod`.
+pub(crate) fn parse_num_literal<'a>(lit: &'a str, buf: &'a mut Vec<u8>) -> &'a [u8] {
+    if !lit.contains('_') {
+        return lit.as_bytes();
+    }
+    buf.clear();
+    buf.extend(lit.bytes().filter(|b| *b != b'_'));
+    &buf[..]
+}
+
 pub(crate) fn parse_regex_literal<'a, 'outer>(
     lit: &str,
     arena: &'a Arena<'outer>,
@@ -407,15 +430,21 @@ impl<'a> Tokenizer<'a> {
 
     fn num(&self) -> Option<(Tok<'a>, usize)> {
         lazy_static! {
-            static ref HEX_PATTERN: Regex = Regex::new(r"^[+-]?0[xX][0-9A-Fa-f]+").unwrap();
-            static ref INT_PATTERN: Regex = Regex::new(r"^[+-]?\d+").unwrap();
-            // Adapted from https://www.regular-expressions.info/floatingpoint.html
-            static ref FLOAT_PATTERN: Regex = Regex::new(r"^[-+]?\d*\.\d+([eE][-+]?\d+)?").unwrap();
+            static ref RADIX_PATTERN: Regex =
+                Regex::new(r"^[+-]?0([xX][0-9A-Fa-f]+|[oO][0-7]+|[bB][01]+)").unwrap();
+            // Groups of digits can be separated by underscores, as in 1_000_000.
+            static ref INT_PATTERN: Regex = Regex::new(r"^[+-]?\d+(_\d+)*").unwrap();
+            // Adapted from https://www.regular-expressions.info/floatingpoint.html. A float either
+            // has a fractional part, as in 2.5 or 2.5e-3, or an exponent, as in 1e9.
+            static ref FLOAT_PATTERN: Regex = Regex::new(
+                r"^[-+]?((\d+(_\d+)*)?\.\d+(_\d+)*([eE][-+]?\d+)?|\d+(_\d+)*[eE][-+]?\d+)"
+            )
+            .unwrap();
         };
         let text = &self.text[self.cur..];
-        if let Some(i) = HEX_PATTERN.captures(text).and_then(|c| c.get(0)) {
+        if let Some(i) = RADIX_PATTERN.captures(text).and_then(|c| c.get(0)) {
             let is = i.as_str();
-            return Some((Tok::HexLit(is), is.len()));
+            return Some((Tok::RadixLit(is), is.len()));
         } else if let Some(f) = FLOAT_PATTERN.captures(text).and_then(|c| c.get(0)) {
             let fs = f.as_str();
             Some((Tok::FLit(fs), fs.len()))
@@ -490,11 +519,14 @@ impl<'a> Tokenizer<'a> {
     fn consume_comment(&mut self) {
         let mut iter = self.text[self.cur..].char_indices();
         if let Some((_, '#')) = iter.next() {
+            let start = self.cur;
             if let Some((ix, _)) = iter.skip_while(|x| x.1 != '\n').next() {
                 self.cur += ix;
             } else {
                 self.cur = self.text.len();
             }
+            self.comments
+                .push((self.index_to_loc(start), &self.text[start..self.cur]));
         }
     }
 
@@ -543,6 +575,15 @@ impl<'a> Tokenizer<'a> {
             _ => true,
         }
     }
+
+    /// Whether the next token starts a new toplevel item: a function, a special block or a rule.
+    fn at_item_start(&self) -> bool {
+        self.depth == 0
+            && matches!(
+                self.prev_tok,
+                None | Some(Tok::Newline) | Some(Tok::Semi) | Some(Tok::RBrace)
+            )
+    }
 }
 
 #[derive(Debug)]
@@ -567,14 +608,25 @@ impl<'a> Tokenizer<'a> {
             text: text.trim_end_matches('\n'),
             cur: 0,
             prev_tok: None,
+            depth: 0,
             lines: text
                 .as_bytes()
                 .iter()
                 .enumerate()
                 .flat_map(|(i, b)| if *b == b'\n' { Some(i) } else { None }.into_iter())
                 .collect(),
+            comments: Vec::new(),
         }
     }
+
+    /// The comments in `text`, in order, with their locations. Comments run from a `#` to the end
+    /// of the line; the returned text includes the `#`.
+    pub(crate) fn comments(text: &'a str) -> Vec<(Loc, &'a str)> {
+        let mut tok = Tokenizer::new(text);
+        while let Some(Ok(_)) = tok.next() {}
+        tok.comments
+    }
+
     fn index_to_loc(&self, ix: usize) -> Loc {
         let offset = ix;
         match self.lines.binary_search(&ix) {
@@ -583,12 +635,7 @@ impl<'a> Tokenizer<'a> {
                 col: ix,
                 offset,
             },
-            Ok(line) => Loc {
-                line: line - 1,
-                col: ix - self.lines[line - 1] - 1,
-                offset,
-            },
-            Err(line) => Loc {
+            Ok(line) | Err(line) => Loc {
                 line,
                 col: ix - self.lines[line - 1] - 1,
                 offset,
@@ -644,6 +691,15 @@ impl<'a> Iterator for Tokenizer<'a> {
                         if new_start < bs.len() && self.text.as_bytes()[new_start] == b'(' {
                             self.cur = new_start + 1;
                             self.spanned(ix, self.cur, Tok::CallStart(s))
+                        } else if s == "SIGNAL"
+                            && self.at_item_start()
+                            && BLOCK.is_match(&self.text[new_start..])
+                        {
+                            // SIGNAL is only a keyword where a pattern could start, outside of
+                            // any braces, so that it can still be used as a variable name. A
+                            // variable named SIGNAL cannot be a pattern on its own there.
+                            self.cur = new_start;
+                            self.spanned(ix, self.cur, Tok::Signal)
                         } else {
                             self.cur = new_start;
                             self.spanned(ix, self.cur, Tok::Ident(s))
@@ -658,6 +714,11 @@ impl<'a> Iterator for Tokenizer<'a> {
         } else {
             self.spanned(self.cur, self.cur, Tok::Newline)
         };
+        match span.1 {
+            Tok::LBrace => self.depth += 1,
+            Tok::RBrace => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
         self.prev_tok = Some(span.1.clone());
         Some(Ok(span))
     }
@@ -793,10 +854,26 @@ and the third"#;
                 FLit("3.5"),
             ],
         );
+        let toks = lex_str(r#"1e9 2.5e-3 1_000_000 1_000.000_5 1_ 1e x_1 "#);
+        assert_eq!(
+            toks.into_iter().map(|x| x.1).collect::<Vec<_>>(),
+            vec![
+                FLit("1e9"),
+                FLit("2.5e-3"),
+                ILit("1_000_000"),
+                FLit("1_000.000_5"),
+                ILit("1"),
+                Ident("_"),
+                ILit("1"),
+                Ident("e"),
+                Ident("x_1"),
+            ],
+        );
         let mut buf = Vec::new();
         let a = Arena::default();
         assert_eq!(parse_string_literal(s1, &a, &mut buf), b"\"hi\tthere\n");
         assert_eq!(parse_regex_literal(s2, &a, &mut buf), b"hows it /going");
+        assert_eq!(parse_num_literal("1_000_000", &mut buf), b"1000000");
         assert_eq!(
             parse_string_literal(r#"are you there \77\x3f"#, &a, &mut buf),
             b"are you there ??"
@@ -0,0 +1,230 @@
+//! Loop-invariant code motion over the SSA form of a function.
+//!
+//! This pass moves computations whose operands do not change within a loop to just before the
+//! loop starts. For example, in
+//!
+//! ```awk
+//! { n = split($0, parts, ","); for (i = 1; i <= n; i++) if (parts[i] == prefix "-" suffix) c++ }
+//! ```
+//!
+//! the concatenation is computed once per record rather than once per field. The main loop over
+//! input records is a loop like any other, so values computed in each record from variables
+//! assigned in `BEGIN` are computed once for the whole program.
+//!
+//! Only pure builtins are moved (see `cse::is_pure`), and only if they are assigned to a local
+//! variable that is assigned nowhere else, and all of their operands are constants or such
+//! variables assigned outside of the loop. As a moved computation runs even if the loop body never
+//! does, we also skip operations that can fail at runtime: `/` and `%`, which can divide by zero,
+//! bitwise operations and regular expression matches, which report invalid patterns.
+//!
+//! A loop is only considered if its header has a single predecessor outside of the loop, which
+//! branches unconditionally to the header. This holds for the loops that frawk generates for
+//! `for`, `while` and the main loop over the input.
+//!
+//! Two related costs are handled during code generation instead. Constants are converted to the
+//! type they are used at ahead of time, as in `s += 1` for a floating-point `s`. Matches against
+//! constant regular expressions look up their pattern once, at compile time, when
+//! `fold_regex_constants` is set.
+use crate::ast::Binop;
+use crate::builtins::Function;
+use crate::cfg::{is_unused, Ident, PrimExpr, PrimStmt, PrimVal, CFG};
+use crate::common::{NodeIx, NumTy};
+use crate::const_fold::clobbered;
+use crate::cse::is_pure;
+
+use hashbrown::{HashMap, HashSet};
+use petgraph::algo::dominators::{self, Dominators};
+use petgraph::visit::DfsPostOrder;
+use petgraph::Direction;
+
+/// Move loop-invariant computations out of the loops in `cfg`, returning whether any were found.
+pub(crate) fn hoist<'a>(cfg: &mut CFG<'a>, entry: NodeIx, local_globals: &HashSet<NumTy>) -> bool {
+    let clobbered = clobbered(cfg);
+    let stable =
+        |id: &Ident| !id.is_global(local_globals) && !clobbered.contains(id) && !is_unused(*id);
+    let loops = find_loops(cfg, entry);
+    let mut changed = false;
+    for (header, body) in loops.iter() {
+        let preheader = match preheader(cfg, *header, body) {
+            Some(p) => p,
+            None => continue,
+        };
+        // Variables assigned within the loop.
+        let mut defined = HashSet::<Ident>::default();
+        for n in body.iter() {
+            for stmt in cfg.node_weight(*n).unwrap().q.iter() {
+                if let PrimStmt::AsgnVar(id, _) = stmt {
+                    defined.insert(*id);
+                }
+            }
+        }
+        for n in body.iter().cloned() {
+            let bb = cfg.node_weight_mut(n).unwrap();
+            let mut hoisted = Vec::new();
+            let mut i = 0;
+            while i < bb.q.len() {
+                let invariant = match &bb.q[i] {
+                    PrimStmt::AsgnVar(id, e) if stable(id) && hoistable(e) => {
+                        let mut invariant = true;
+                        operands(e, |v| {
+                            if let PrimVal::Var(v) = v {
+                                invariant &= stable(v) && !defined.contains(v);
+                            }
+                        });
+                        invariant
+                    }
+                    _ => false,
+                };
+                if invariant {
+                    if let PrimStmt::AsgnVar(id, _) = &bb.q[i] {
+                        defined.remove(id);
+                    }
+                    hoisted.push((bb.q.remove(i).unwrap(), bb.spans.remove(i).unwrap()));
+                } else {
+                    i += 1;
+                }
+            }
+            if hoisted.is_empty() {
+                continue;
+            }
+            changed = true;
+            let pre = cfg.node_weight_mut(preheader).unwrap();
+            for (stmt, span) in hoisted {
+                pre.q.push_back(stmt);
+                pre.spans.push_back(span);
+            }
+        }
+    }
+    changed
+}
+
+// Whether `e` can be computed ahead of time without changing what the program does.
+fn hoistable(e: &PrimExpr) -> bool {
+    match e {
+        PrimExpr::CallBuiltin(f, _) => {
+            is_pure(*f)
+                && !matches!(
+                    f,
+                    Function::Binop(Binop::Mod)
+                        | Function::Binop(Binop::Div)
+                        | Function::Binop(Binop::IsMatch)
+                        | Function::IntFunc(_)
+                )
+        }
+        _ => false,
+    }
+}
+
+fn operands<'a>(e: &PrimExpr<'a>, mut f: impl FnMut(&PrimVal<'a>)) {
+    if let PrimExpr::CallBuiltin(_, args) = e {
+        for a in args.iter() {
+            f(a)
+        }
+    }
+}
+
+// The natural loops in `cfg`, as pairs of a loop header and the blocks in the loop, ordered so
+// that inner loops come before the loops that contain them. Blocks are listed in reverse
+// postorder, so that a variable is defined before any of its uses in the loop.
+fn find_loops(cfg: &CFG, entry: NodeIx) -> Vec<(NodeIx, Vec<NodeIx>)> {
+    let doms = dominators::simple_fast(cfg, entry);
+    let mut bodies = HashMap::<NodeIx, HashSet<NodeIx>>::default();
+    for e in cfg.raw_edges() {
+        let (n, header) = (e.source(), e.target());
+        if !dominates(&doms, header, n) {
+            continue;
+        }
+        // A back edge: everything that reaches `n` without going through `header` is in the loop.
+        let body = bodies.entry(header).or_insert_with(|| {
+            let mut body = HashSet::default();
+            body.insert(header);
+            body
+        });
+        let mut stack = vec![n];
+        while let Some(n) = stack.pop() {
+            if body.insert(n) {
+                stack.extend(cfg.neighbors_directed(n, Direction::Incoming));
+            }
+        }
+    }
+    let mut rpo = Vec::new();
+    let mut dfs = DfsPostOrder::new(cfg, entry);
+    while let Some(n) = dfs.next(cfg) {
+        rpo.push(n);
+    }
+    rpo.reverse();
+    let mut loops: Vec<(NodeIx, Vec<NodeIx>)> = bodies
+        .into_iter()
+        .map(|(header, body)| {
+            let blocks = rpo.iter().cloned().filter(|n| body.contains(n)).collect();
+            (header, blocks)
+        })
+        .collect();
+    loops.sort_by_key(|(header, body)| (body.len(), header.index()));
+    loops
+}
+
+fn dominates(doms: &Dominators<NodeIx>, a: NodeIx, mut b: NodeIx) -> bool {
+    if doms.immediate_dominator(b).is_none() && b != doms.root() {
+        // `b` is unreachable.
+        return false;
+    }
+    loop {
+        if a == b {
+            return true;
+        }
+        match doms.immediate_dominator(b) {
+            Some(d) => b = d,
+            None => return false,
+        }
+    }
+}
+
+// The only block outside of the loop that branches to `header`, provided that it does not branch
+// anywhere else.
+fn preheader(cfg: &CFG, header: NodeIx, body: &[NodeIx]) -> Option<NodeIx> {
+    let mut outside = cfg
+        .neighbors_directed(header, Direction::Incoming)
+        .filter(|p| !body.contains(p));
+    let pre = outside.next()?;
+    if outside.any(|p| p != pre) || cfg.neighbors(pre).any(|s| s != header) {
+        return None;
+    }
+    Some(pre)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::harness::hoist_loop_invariants;
+
+    #[test]
+    fn hoists_invariants() {
+        let prog = r#"function f(n, a, b,    i, s, t) {
+            for (i = 0; i < n; i++) { t = a b; s = s t; }
+            return s;
+        }
+        BEGIN { print f(3, "x", "y"); }"#;
+        let cfg = hoist_loop_invariants(prog).unwrap();
+        // The concatenation is computed in the entry block of `f`, before the loop.
+        let f = &cfg[..cfg.find("function <main>").unwrap()];
+        let entry = &f[..f.find("\n1:").unwrap()];
+        assert!(entry.contains("<concat>(3-0, 4-0)"), "{}", cfg);
+    }
+
+    #[test]
+    fn leaves_variant_code() {
+        let prog = r#"function f(n, a,    i, s) {
+            for (i = 0; i < n; i++) { s = s a i; if (i > 0) x = a % i; }
+            return s;
+        }
+        BEGIN { print f(3, 2); }"#;
+        let cfg = hoist_loop_invariants(prog).unwrap();
+        let f = &cfg[..cfg.find("function <main>").unwrap()];
+        let entry = &f[..f.find("\n1:").unwrap()];
+        assert!(
+            !entry.contains("<concat>") && !entry.contains("%"),
+            "{}",
+            cfg
+        );
+    }
+}
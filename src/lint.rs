@@ -0,0 +1,527 @@
+//! Lint passes, used to implement `--lint`.
+//!
+//! Everything flagged here is legal AWK, and none of it changes how the program is compiled. The
+//! checks are heuristics for code that is likely to be a mistake. `lint` works on the AST and
+//! flags:
+//!
+//! * Variables that are read but never assigned.
+//! * Variables, parameters and functions that are never used.
+//! * Assignments used as conditions (`if (x = 1)`).
+//! * Regex constants that match every string, or that can never match.
+//! * Rules whose patterns can never match. These are not compiled.
+//!
+//! `lint_comparisons` runs after type inference, and flags comparisons that are performed on
+//! strings even though they were probably meant to be numeric: those against numeric-looking
+//! string constants (`$1 > "10"`), and ordering comparisons between two values that are both
+//! strings (`if ($1 > max) max = $1`). frawk only compares numerically when one side is a
+//! number, so these silently compare lexicographically.
+use crate::ast::{Binop, Expr, Pattern, Prog, Stmt};
+use crate::builtins::{Function, Variable};
+use crate::cfg::{self, PrimVal};
+use crate::common::{Either, Result};
+use crate::compile;
+use crate::lexer::Span;
+use crate::runtime::compat;
+
+use hashbrown::{HashMap, HashSet};
+use regex::bytes::Regex;
+
+use std::convert::TryFrom;
+
+pub(crate) struct Warning {
+    pub msg: String,
+    pub span: Option<Span>,
+}
+
+#[derive(Default)]
+struct Usage {
+    read: Option<Option<Span>>,
+    written: Option<Option<Span>>,
+}
+
+#[derive(Default)]
+struct Linter<'b> {
+    cur_span: Option<Span>,
+    globals: HashMap<&'b str, Usage>,
+    // The parameters of the function currently being linted.
+    locals: HashMap<&'b str, Usage>,
+    // The functions declared in the program, which take precedence over builtins of the same name.
+    declared: HashSet<&'b str>,
+    warnings: Vec<Warning>,
+}
+
+/// Lint `prog`. Variables assigned in `predefined` (e.g. with -v) are treated as initialized.
+pub(crate) fn lint<'a, 'b>(prog: &Prog<'a, 'b, &'b str>, predefined: &[&'b str]) -> Vec<Warning> {
+    let mut l = Linter::default();
+    l.declared = prog.decs.iter().map(|d| d.name).collect();
+    for name in predefined.iter().cloned() {
+        l.write(name);
+    }
+    for (_, e) in prog.prelude_vardecs.iter() {
+        l.expr(e);
+    }
+    for fundec in prog.decs.iter() {
+        l.locals = fundec.args.iter().map(|a| (*a, Usage::default())).collect();
+        l.cur_span = Some(fundec.span);
+        l.stmt(fundec.body);
+        let mut unused: Vec<_> = l
+            .locals
+            .drain()
+            .filter(|(_, u)| u.read.is_none() && u.written.is_none())
+            .map(|(name, _)| name)
+            .collect();
+        unused.sort_by_key(|name| fundec.args.iter().position(|a| a == name));
+        for name in unused {
+            l.warn(
+                Some(fundec.span),
+                format!(
+                    "parameter `{}` of function `{}` is never used",
+                    name, fundec.name
+                ),
+            );
+        }
+    }
+    l.cur_span = None;
+    for s in prog.begin.iter().chain(prog.prepare.iter()) {
+        l.stmt(s);
+    }
+    for (span, pat, body) in prog.pats.iter() {
+        l.cur_span = Some(*span);
+        // Rules that can never run are not compiled, so we do not lint their contents.
+        if pat.never_matches() {
+            l.warn(
+                Some(*span),
+                "this pattern can never match, so its rule is skipped".into(),
+            );
+            continue;
+        }
+        match pat {
+            Pattern::Null => {}
+            Pattern::Bool(e) => l.cond(e),
+            Pattern::Comma(e1, e2) => {
+                l.cond(e1);
+                l.cond(e2);
+            }
+            Pattern::Group(e) => l.expr(e),
+        }
+        if let Some(body) = body {
+            l.stmt(body);
+        }
+    }
+    l.cur_span = None;
+    for s in prog.end.iter().chain(prog.signal.iter()) {
+        l.stmt(s);
+    }
+
+    let called = prog.called_functions();
+    for fundec in prog.decs.iter() {
+        if !called.contains(fundec.name) {
+            l.warn(
+                Some(fundec.span),
+                format!("function `{}` is never called", fundec.name),
+            );
+        }
+    }
+    let mut globals: Vec<_> = l.globals.drain().collect();
+    globals.sort_by_key(|(name, _)| *name);
+    for (name, usage) in globals {
+        match usage {
+            Usage {
+                read: Some(span),
+                written: None,
+            } => l.warn(span, format!("`{}` is read but never assigned", name)),
+            Usage {
+                read: None,
+                written: Some(span),
+            } if !predefined.contains(&name) => {
+                l.warn(span, format!("`{}` is assigned but never used", name))
+            }
+            _ => {}
+        }
+    }
+    l.warnings
+        .sort_by_key(|w| w.span.map(|(start, _)| (start.line, start.col)));
+    l.warnings
+}
+
+impl<'b> Linter<'b> {
+    fn warn(&mut self, span: Option<Span>, msg: String) {
+        self.warnings.push(Warning { msg, span })
+    }
+
+    fn usage(&mut self, name: &'b str) -> Option<&mut Usage> {
+        if Variable::try_from(name).is_ok() {
+            return None;
+        }
+        Some(match self.locals.get_mut(name) {
+            Some(u) => u,
+            None => self.globals.entry(name).or_insert_with(Default::default),
+        })
+    }
+
+    fn read(&mut self, name: &'b str) {
+        let span = self.cur_span;
+        if let Some(u) = self.usage(name) {
+            u.read.get_or_insert(span);
+        }
+    }
+
+    fn write(&mut self, name: &'b str) {
+        let span = self.cur_span;
+        if let Some(u) = self.usage(name) {
+            u.written.get_or_insert(span);
+        }
+    }
+
+    fn stmt<'a>(&mut self, s: &Stmt<'a, 'b, &'b str>) {
+        use Stmt::*;
+        match s {
+            Located(span, s) => {
+                let prev = self.cur_span.replace(*span);
+                self.stmt(s);
+                self.cur_span = prev;
+            }
+            StartCond(_) | EndCond(_) | LastCond(_) | Break | Continue | Next | NextFile => {}
+            Expr(e) => self.expr(e),
+            Block(stmts) => {
+                for s in stmts.iter() {
+                    self.stmt(s)
+                }
+            }
+            Print(args, out) => {
+                for a in args.iter() {
+                    self.expr(a)
+                }
+                if let Some((out, _)) = out {
+                    self.expr(out)
+                }
+            }
+            Printf(fmt, args, out) => {
+                self.expr(fmt);
+                for a in args.iter() {
+                    self.expr(a)
+                }
+                if let Some((out, _)) = out {
+                    self.expr(out)
+                }
+            }
+            If(cond, t, f) => {
+                self.cond(cond);
+                self.stmt(t);
+                if let Some(f) = f {
+                    self.stmt(f)
+                }
+            }
+            For(init, cond, update, body) => {
+                if let Some(init) = init {
+                    self.stmt(init)
+                }
+                if let Some(cond) = cond {
+                    self.cond(cond)
+                }
+                if let Some(update) = update {
+                    self.stmt(update)
+                }
+                self.stmt(body)
+            }
+            DoWhile(cond, body) | While(_, cond, body) => {
+                self.cond(cond);
+                self.stmt(body)
+            }
+            ForEach(v, arr, body) => {
+                self.write(v);
+                self.expr(arr);
+                self.stmt(body)
+            }
+            Return(e) | Exit(e) => {
+                if let Some(e) = e {
+                    self.expr(e)
+                }
+            }
+            Phase(s) => self.stmt(s),
+        }
+    }
+
+    fn cond<'a>(&mut self, e: &Expr<'a, 'b, &'b str>) {
+        if let Expr::Assign(..) = e {
+            self.warn(
+                self.cur_span,
+                "assignment used as a condition; did you mean `==`?".into(),
+            );
+        }
+        self.expr(e)
+    }
+
+    /// Record an assignment to `e`.
+    fn lvalue<'a>(&mut self, e: &Expr<'a, 'b, &'b str>) {
+        match e {
+            Expr::Var(v) => self.write(v),
+            Expr::Index(arr, ix) => {
+                self.lvalue(arr);
+                self.expr(ix)
+            }
+            e => self.expr(e),
+        }
+    }
+
+    fn expr<'a>(&mut self, e: &Expr<'a, 'b, &'b str>) {
+        use Expr::*;
+        match e {
+            ILit(_) | FLit(_) | StrLit(_) | ReadStdin | Cond(_) => {}
+            PatLit(pat) => self.regex(pat),
+            Var(v) => self.read(v),
+            Unop(_, e) => self.expr(e),
+            Binop(_, l, r) => {
+                self.expr(l);
+                self.expr(r)
+            }
+            Index(arr, ix) => {
+                self.expr(arr);
+                self.expr(ix)
+            }
+            Assign(l, r) => {
+                self.lvalue(l);
+                self.expr(r)
+            }
+            // Reading a variable to update it does not count as a use, but the (common) idiom of
+            // incrementing an uninitialized counter should not be flagged either.
+            AssignOp(l, _, r) => {
+                self.lvalue(l);
+                self.expr(r)
+            }
+            Inc { x, .. } => self.lvalue(x),
+            And(l, r) | Or(l, r) => {
+                self.expr(l);
+                self.expr(r)
+            }
+            ITE(c, t, f) => {
+                self.expr(c);
+                self.expr(t);
+                self.expr(f)
+            }
+            Getline { into, from, .. } => {
+                if let Some(into) = into {
+                    self.lvalue(into)
+                }
+                if let Some(from) = from {
+                    self.expr(from)
+                }
+            }
+            Call(f, args) => {
+                let builtin = match f {
+                    Either::Left(name) if self.declared.contains(name) => None,
+                    Either::Left(name) => Function::try_from(*name).ok(),
+                    Either::Right(f) => Some(*f),
+                };
+                // The argument that the function assigns to, if any.
+                let out = match builtin {
+                    Some(Function::HllAdd)
+                    | Some(Function::BloomAdd)
+                    | Some(Function::Reservoir)
+                    | Some(Function::WinPush)
+                    | Some(Function::HistAdd)
+                    | Some(Function::VecAdd)
+                    | Some(Function::VecSub)
+                    | Some(Function::VecMul)
+                    | Some(Function::VecScale) => Some(0),
+                    Some(Function::Split)
+                    | Some(Function::Graphemes)
+                    | Some(Function::Stat)
+                    | Some(Function::Glob) => Some(1),
+                    Some(Function::Sub)
+                    | Some(Function::GSub)
+                    | Some(Function::IntDiv)
+                    | Some(Function::DivMod) => Some(2),
+                    Some(_) => None,
+                    None => None,
+                };
+                for (i, a) in args.iter().enumerate() {
+                    if Some(i) == out {
+                        self.lvalue(a);
+                    } else if let (None, Var(v)) = (builtin, a) {
+                        // Arrays are passed to functions by reference, so the callee may be the
+                        // one to initialize them.
+                        self.read(v);
+                        self.write(v);
+                    } else {
+                        self.expr(a)
+                    }
+                }
+            }
+        }
+    }
+
+    fn regex(&mut self, pat: &[u8]) {
+        let text = String::from_utf8_lossy(pat);
+        match Regex::new(&text) {
+            Ok(re) if re.is_match(b"") => self.warn(
+                self.cur_span,
+                format!("regex /{}/ matches every string", text),
+            ),
+            Ok(_) if never_matches(pat) => {
+                self.warn(self.cur_span, format!("regex /{}/ can never match", text))
+            }
+            // Invalid regexes are reported during compilation.
+            _ => {}
+        }
+    }
+}
+
+/// Flag the comparisons in `ctx` that type inference has resolved to string comparisons, but that
+/// look like they were meant to compare numbers.
+pub(crate) fn lint_comparisons<'a>(
+    ctx: &mut cfg::ProgramContext<'a, &'a str>,
+) -> Result<Vec<Warning>> {
+    let mut warnings = Vec::new();
+    // gawk compares numeric-looking strings as numbers.
+    if compat::gawk() {
+        return Ok(warnings);
+    }
+    for (span, op, l, r) in compile::string_comparisons(ctx)? {
+        let msg = match (&l, &r) {
+            (PrimVal::StrLit(_), PrimVal::StrLit(_)) => continue,
+            (PrimVal::StrLit(s), _) | (_, PrimVal::StrLit(s)) => {
+                if !compat::looks_numeric(s) {
+                    continue;
+                }
+                let text = String::from_utf8_lossy(s);
+                format!(
+                    "comparison with string constant \"{}\" is performed on strings, not numbers; did you mean {}?",
+                    text,
+                    text.trim()
+                )
+            }
+            _ if op == Binop::EQ => continue,
+            _ => format!(
+                "both sides of `{}` are strings, so they are compared as strings, not numbers; add 0 to one side to compare numerically",
+                op
+            ),
+        };
+        warnings.push(Warning { msg, span });
+    }
+    warnings.sort_by_key(|w| w.span.map(|(start, _)| (start.line, start.col)));
+    Ok(warnings)
+}
+
+/// A conservative check for regexes with anchors in positions that cannot match, such as `a^b` or
+/// `a$b`.
+fn never_matches(pat: &[u8]) -> bool {
+    let mut prev: Option<u8> = None;
+    let mut in_class = false;
+    let mut i = 0;
+    while i < pat.len() {
+        let c = pat[i];
+        match c {
+            b'\\' => {
+                prev = Some(b'a');
+                i += 2;
+                continue;
+            }
+            b'[' if !in_class => in_class = true,
+            b']' if in_class => in_class = false,
+            b'^' if !in_class => {
+                if prev.map_or(false, |p| p.is_ascii_alphanumeric()) {
+                    return true;
+                }
+            }
+            b'$' if !in_class => {
+                if pat.get(i + 1).map_or(false, |n| n.is_ascii_alphanumeric()) {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+        prev = Some(c);
+        i += 1;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena::Arena;
+    use crate::common::Stage;
+    use crate::lexer::Tokenizer;
+    use crate::parsing::syntax::ProgParser;
+
+    fn lint_messages(text: &str) -> Vec<(usize, String)> {
+        let a = Arena::default();
+        let text = a.alloc_str(text);
+        let mut buf = Vec::new();
+        let mut prog = Prog::from_stage(Stage::Main(()));
+        ProgParser::new()
+            .parse(&a, &mut buf, &mut prog, Tokenizer::new(text))
+            .expect("parse failure");
+        lint(&prog, &["preset"])
+            .into_iter()
+            .map(|w| (w.span.map_or(0, |(start, _)| start.line + 1), w.msg))
+            .collect()
+    }
+
+    #[test]
+    fn clean_program() {
+        let prog = r#"function add(m, k) { m[k]++; return length(m) }
+BEGIN { FS = "," }
+{ n = add(counts, $1); split($2, parts, ":"); total += parts[1] }
+END { for (k in counts) print k, counts[k], n, total, preset; }"#;
+        assert_eq!(lint_messages(prog), vec![]);
+    }
+
+    #[test]
+    fn flags_mistakes() {
+        let prog = r#"function unused(x, y) { return x }
+BEGIN { if (z = 1) print q; }
+$1 == "10" { w = 1 }
+/a*/ { print }
+/a$b/ { print }
+NR < 3 && NR > 5 { dead(v) }
+function dead(x) { return x }"#;
+        let warnings = lint_messages(prog);
+        let expected: Vec<(usize, String)> = vec![
+            (1, "parameter `y` of function `unused` is never used".into()),
+            (1, "function `unused` is never called".into()),
+            (
+                2,
+                "assignment used as a condition; did you mean `==`?".into(),
+            ),
+            (2, "`q` is read but never assigned".into()),
+            (2, "`z` is assigned but never used".into()),
+            (3, "`w` is assigned but never used".into()),
+            (4, "regex /a*/ matches every string".into()),
+            (5, "regex /a$b/ can never match".into()),
+            (
+                6,
+                "this pattern can never match, so its rule is skipped".into(),
+            ),
+            (7, "function `dead` is never called".into()),
+        ];
+        let mut warnings_sorted = warnings.clone();
+        warnings_sorted.sort();
+        let mut expected_sorted = expected;
+        expected_sorted.sort();
+        assert_eq!(warnings_sorted, expected_sorted);
+    }
+
+    #[test]
+    fn string_comparisons() {
+        let prog = r#"BEGIN { lim = "10"; x = 5 }
+$1 > "10" { print }
+x > "10" { print }
+$1 > lim { print }
+{ if ($2 > max) max = $2; }
+$3 == $4 && $3 != "abc" { print }
+function f(a) { return a < "2.5" }
+{ print f($1), f(1) }"#;
+        // `x` is an integer, so `x > "10"` compares numbers. `f` is inlined, so its comparison is
+        // reported on the line it is called from, and only for the call with a string argument.
+        let both = "both sides of `>` are strings, so they are compared as strings, not numbers; add 0 to one side to compare numerically";
+        let expected: Vec<(usize, String)> = vec![
+            (2, "comparison with string constant \"10\" is performed on strings, not numbers; did you mean 10?".into()),
+            (4, both.into()),
+            (5, both.into()),
+            (8, "comparison with string constant \"2.5\" is performed on strings, not numbers; did you mean 2.5?".into()),
+        ];
+        assert_eq!(crate::harness::lint_comparisons(prog).unwrap(), expected);
+    }
+}
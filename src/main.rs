@@ -13,22 +13,35 @@ pub mod cfg;
 #[macro_use]
 pub mod codegen;
 pub mod compile;
+mod const_fold;
 pub mod cross_stage;
+mod cse;
 pub mod dataflow;
 mod display;
 pub mod dom;
+mod escape;
 #[cfg(test)]
 pub mod harness;
 mod input_taint;
 pub mod interp;
+mod kernels;
 pub mod lexer;
+mod licm;
+mod lint;
+mod ownership;
 #[allow(unused_parens)] // Warnings appear in generated code
 pub mod parsing;
+mod pretty;
+mod profile;
 pub mod pushdown;
+mod repl;
 pub mod runtime;
+mod serialize;
+mod strict;
 mod string_constants;
 #[cfg(test)]
 mod test_string_constants;
+mod trace;
 pub mod types;
 extern crate cfg_if;
 extern crate clap;
@@ -37,7 +50,7 @@ extern crate crossbeam_channel;
 extern crate elsa;
 extern crate grep_cli;
 extern crate hashbrown;
-#[cfg(feature = "use_jemalloc")]
+#[cfg(all(feature = "use_jemalloc", not(target_env = "msvc")))]
 extern crate jemallocator;
 extern crate lalrpop_util;
 extern crate lazy_static;
@@ -59,54 +72,123 @@ use clap::{App, Arg};
 
 use arena::Arena;
 use cfg::Escaper;
-use codegen::intrinsics::IntoRuntime;
-use common::{ExecutionStrategy, Stage};
+use codegen::{cache::JitCache, intrinsics::IntoRuntime};
+use common::{CompileError, ExecutionStrategy, IntOverflow, Source, Stage};
 use runtime::{
     splitter::{
         batch::{ByteReader, CSVReader, InputFormat},
+        is_var_assignment,
         regex::RegexSplitter,
     },
-    ChainedReader, LineReader, CHUNK_SIZE,
+    ArgvReader, ChainedReader, LineReader, CHUNK_SIZE,
 };
-use std::fs::File;
 use std::io::{self, BufReader, Write};
 use std::iter::once;
 
-#[cfg(feature = "use_jemalloc")]
+#[cfg(all(feature = "use_jemalloc", not(target_env = "msvc")))]
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 macro_rules! fail {
     ($($t:tt)*) => {{
-        eprintln_ignore!($($t)*);
+        let msg = format!($($t)*);
+        eprintln_ignore!("{}", msg);
+        crate::runtime::stats::set_error(msg);
+        crate::runtime::stats::report();
         std::process::exit(1)
     }}
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Default)]
 struct PreludeScalars {
     arbitrary_shell: bool,
     fold_regexes: bool,
+    fold_constants: bool,
+    inline_functions: bool,
     parse_header: bool,
+    trace: bool,
+    strict: bool,
+    int_overflow: IntOverflow,
+    round_trip: bool,
+    match_status: bool,
     escaper: Escaper,
     stage: Stage<()>,
+    // Whether a program with a specialized implementation (see the `kernels` module) can run
+    // that instead of being compiled.
+    kernels: bool,
+    // Whether a program that refers to ARGV can take its input files from ARGV as it runs.
+    dynamic_argv: bool,
+    // Whether the program is staged for `--tiered`; see `run_tiered`.
+    tiered: bool,
+    // Process-wide settings, enabled in `runtime` before the program is compiled. They are kept
+    // here so that they are part of the JIT cache key.
+    #[allow(dead_code)]
+    gawk_compat: bool,
+    #[allow(dead_code)]
+    div_by_zero_errors: bool,
+    #[allow(dead_code)]
+    non_decimal_data: bool,
 }
 
+#[derive(Clone, Default)]
 struct RawPrelude {
+    // The file the program was read from, if it was passed with -f.
+    program_file: Option<String>,
     argv: Vec<String>,
     var_decs: Vec<String>,
     field_sep: Option<String>,
     output_sep: Option<&'static str>,
     output_record_sep: Option<&'static str>,
+    // The arrays named with --persist.
+    persist: Vec<String>,
+    // The global arrays whose sizes are reported by --stats.
+    stat_arrays: Vec<String>,
     scalars: PreludeScalars,
 }
 
+impl RawPrelude {
+    /// A description of everything about this invocation that affects the code generated for
+    /// `prog`. This is used as the key for the JIT cache.
+    ///
+    /// `config` and the scalar options are included whole, through their `Debug` impls, so that
+    /// options added to them later are part of the key as well.
+    fn cache_key(&self, prog: &str, config: &codegen::Config) -> String {
+        // ARGV is filled in when the program starts, so the operands only affect the generated
+        // code if some of them are var=value assignments.
+        let operands: &[String] = if self.argv.iter().skip(1).any(|arg| is_var_assignment(arg)) {
+            &self.argv[1..]
+        } else {
+            &[]
+        };
+        format!(
+            "{:?}",
+            (
+                prog,
+                config,
+                operands,
+                &self.var_decs,
+                &self.field_sep,
+                self.output_sep,
+                self.output_record_sep,
+                &self.persist,
+                &self.stat_arrays,
+                &self.scalars,
+            )
+        )
+    }
+}
+
 struct Prelude<'a> {
     var_decs: Vec<(&'a str, &'a ast::Expr<'a, 'a, &'a str>)>,
     field_sep: Option<&'a [u8]>,
     output_sep: Option<&'a [u8]>,
     output_record_sep: Option<&'a [u8]>,
+    operand_var_decs: Vec<(usize, &'a str, &'a ast::Expr<'a, 'a, &'a str>)>,
+    input_files: Vec<&'a str>,
     argv: Vec<&'a str>,
+    program_file: Option<&'a str>,
+    persist: Vec<&'a str>,
+    stat_arrays: Vec<&'a str>,
     scalars: PreludeScalars,
 }
 
@@ -138,10 +220,34 @@ fn open_file_read(f: &str) -> impl io::BufRead {
 
     let filename = String::from(f);
     BufReader::new(LazyReader::Uninit(move || {
-        Ok(File::open(filename.as_str())?)
+        if filename == "-" {
+            let stdin = runtime::command::line_endings(Box::new(io::stdin()));
+            return Ok(runtime::progress::counted(stdin));
+        }
+        runtime::command::open_input(filename.as_str()).map(runtime::progress::counted)
     }))
 }
 
+/// The total size of the main input, if it is made up of regular files (standard input, if `files`
+/// is empty) that are read as they are, rather than through a `--pre` command.
+fn input_size(files: &[String], preprocessed: bool) -> Option<u64> {
+    if preprocessed {
+        return None;
+    }
+    let size = |path: &str| {
+        std::fs::metadata(path)
+            .ok()
+            .filter(|m| m.is_file())
+            .map(|m| m.len())
+    };
+    if files.len() == 0 {
+        return size("/dev/stdin");
+    }
+    files.iter().try_fold(0u64, |total, f| {
+        Some(total + size(if f == "-" { "/dev/stdin" } else { f.as_str() })?)
+    })
+}
+
 fn chained<LR: LineReader>(lr: LR) -> ChainedReader<LR> {
     ChainedReader::new(std::iter::once(lr))
 }
@@ -171,6 +277,23 @@ fn get_vars<'a, 'b>(
     stmts
 }
 
+/// The program run by `-e` and `frawk eval`, which prints the value of each of `exprs` in turn,
+/// using `printf fmt` if a format is given.
+fn eval_program(exprs: &[&str], fmt: Option<&str>) -> String {
+    let mut prog = String::from("BEGIN {\n");
+    for e in exprs {
+        match fmt {
+            Some(fmt) => {
+                let fmt = fmt.replace('"', "\\\"");
+                prog.push_str(&format!("printf \"{}\\n\", ({})\n", fmt, e));
+            }
+            None => prog.push_str(&format!("print ({})\n", e)),
+        }
+    }
+    prog.push_str("}\n");
+    prog
+}
+
 fn get_prelude<'a>(a: &'a Arena, raw: &RawPrelude) -> Prelude<'a> {
     let mut buf = Vec::new();
     let output_sep = raw
@@ -183,47 +306,121 @@ fn get_prelude<'a>(a: &'a Arena, raw: &RawPrelude) -> Prelude<'a> {
         .field_sep
         .as_ref()
         .map(|s| lexer::parse_string_literal(s.as_str(), a, &mut buf));
+    // Unlike -v, the value in a var=value operand is a string, with the same escape sequences as
+    // a string literal.
+    let mut input_files = Vec::new();
+    let mut operand_var_decs = Vec::new();
+    for arg in raw.argv.iter().skip(1) {
+        if is_var_assignment(arg) {
+            let (name, value) = arg.split_at(arg.find('=').unwrap());
+            let value = lexer::parse_string_literal(&value[1..], a, &mut buf);
+            operand_var_decs.push((
+                input_files.len(),
+                a.alloc_str(name),
+                a.alloc_v(ast::Expr::StrLit(value)),
+            ));
+        } else {
+            input_files.push(a.alloc_str(arg.as_str()));
+        }
+    }
     Prelude {
         field_sep,
         var_decs: get_vars(raw.var_decs.iter().map(|s| s.as_str()), a, &mut buf),
+        operand_var_decs,
+        input_files,
         scalars: raw.scalars.clone(),
         output_sep,
         output_record_sep,
         argv: raw.argv.iter().map(|s| a.alloc_str(s.as_str())).collect(),
+        program_file: raw.program_file.as_ref().map(|s| a.alloc_str(s.as_str())),
+        persist: raw
+            .persist
+            .iter()
+            .map(|s| a.alloc_str(s.as_str()))
+            .collect(),
+        stat_arrays: raw
+            .stat_arrays
+            .iter()
+            .map(|s| a.alloc_str(s.as_str()))
+            .collect(),
     }
 }
 
 fn get_context<'a>(
     prog: &str,
     a: &'a Arena,
-    mut prelude: Prelude<'a>,
+    prelude: Prelude<'a>,
 ) -> cfg::ProgramContext<'a, &'a str> {
+    try_get_context(prog, a, prelude).unwrap_or_else(|e| fail!("{}", e))
+}
+
+/// Like `get_context`, but returns a description of any error rather than exiting.
+fn try_get_context<'a>(
+    prog: &str,
+    a: &'a Arena,
+    mut prelude: Prelude<'a>,
+) -> Result<cfg::ProgramContext<'a, &'a str>, String> {
     let prog = a.alloc_str(prog);
+    let source = Source {
+        text: prog,
+        file: prelude.program_file,
+    };
     let lexer = lexer::Tokenizer::new(prog);
     let mut buf = Vec::new();
     let parser = parsing::syntax::ProgParser::new();
     let mut prog = ast::Prog::from_stage(prelude.scalars.stage.clone());
-    prog.argv = std::mem::replace(&mut prelude.argv, Default::default());
-    let stmt = match parser.parse(a, &mut buf, &mut prog, lexer) {
+    let argv = std::mem::replace(&mut prelude.argv, Default::default());
+    match parser.parse(a, &mut buf, &mut prog, lexer) {
         Ok(()) => {
             prog.field_sep = prelude.field_sep;
             prog.prelude_vardecs = prelude.var_decs;
+            prog.operand_vardecs = prelude.operand_var_decs;
+            prog.input_files = prelude.input_files;
             prog.output_sep = prelude.output_sep;
             prog.output_record_sep = prelude.output_record_sep;
             prog.parse_header = prelude.scalars.parse_header;
-            a.alloc_v(prog)
-        }
-        Err(e) => {
-            fail!("{}", e);
+            prog.trace = prelude.scalars.trace;
+            prog.tiered = prelude.scalars.tiered;
+            prog.round_trip = prelude.scalars.round_trip;
+            prog.match_status = prelude.scalars.match_status;
+            prog.inline_functions = prelude.scalars.inline_functions;
+            if prelude.scalars.dynamic_argv && prog.uses_argv() {
+                prog.argv_vars = Some(prog.argv_vars());
+            }
+            prog.persist = std::mem::replace(&mut prelude.persist, Default::default());
+            prog.stat_arrays = std::mem::replace(&mut prelude.stat_arrays, Default::default());
         }
+        Err(e) => return Err(source.render(&parsing::parse_error(e))),
     };
+    if prog.signal.is_some() {
+        runtime::signal::install(prog.atexit_functions().len() > 0);
+    }
+    if prelude.scalars.strict {
+        if let Err(e) = strict::instrument(a, &mut prog) {
+            return Err(source.render(&e));
+        }
+    }
+    let stmt = a.alloc_v(prog);
     match cfg::ProgramContext::from_prog(a, stmt, prelude.scalars.escaper) {
         Ok(mut ctx) => {
+            if prelude.scalars.kernels {
+                ctx.kernel = kernels::recognize(stmt);
+            }
             ctx.allow_arbitrary_commands = prelude.scalars.arbitrary_shell;
             ctx.fold_regex_constants = prelude.scalars.fold_regexes;
-            ctx
+            if prelude.scalars.fold_constants {
+                ctx.fold_constants(a);
+                ctx.eliminate_common_subexprs();
+                ctx.hoist_loop_invariants();
+            }
+            ctx.source = source;
+            ctx.argv = argv;
+            Ok(ctx)
         }
-        Err(e) => fail!("failed to create program context: {}", e),
+        Err(e) => Err(format!(
+            "failed to create program context: {}",
+            source.render(&e)
+        )),
     }
 }
 
@@ -232,13 +429,382 @@ fn run_interp_with_context<'a>(
     stdin: impl LineReader,
     ff: impl runtime::writers::FileFactory,
     num_workers: usize,
+    profile: Option<&str>,
+    trace: Option<trace::Config>,
+    strict: bool,
+    strict_warn: bool,
+    int_overflow: IntOverflow,
 ) {
+    let source = ctx.source;
     let mut interp = match compile::bytecode(&mut ctx, stdin, ff, num_workers) {
         Ok(ctx) => ctx,
-        Err(e) => fail!("bytecode compilation failure: {}", e),
+        Err(e) => fail!("bytecode compilation failure: {}", source.render(&e)),
+    };
+    if profile.is_some() {
+        interp.enable_profile();
+    }
+    if let Some(config) = trace {
+        interp.enable_trace(source.text, config);
+    }
+    if strict {
+        interp.enable_strict(strict_warn);
+    }
+    interp.set_int_overflow(int_overflow);
+    let res = interp.run();
+    if let (Some(path), Some(p)) = (profile, interp.take_profile()) {
+        write_profile(source.text, &p, path);
+    }
+    // Flush output files before exiting, including any written to by `atexit` functions that ran
+    // after an error.
+    drop(interp);
+    if let Err(e) = res {
+        fail!("fatal error during execution: {}", source.render(&e));
+    }
+}
+
+/// Write a listing of `prog` annotated with the counts and timings in `profile` to `path`.
+fn write_profile(prog: &str, profile: &profile::Profile, path: &str) {
+    let a = Arena::default();
+    let text = a.alloc_str(prog);
+    let prog = match parse(&a, text) {
+        Ok(prog) => prog,
+        Err(e) => fail!("failed to parse program for profile: {}", e),
+    };
+    let listing = pretty::pretty_print_annotated(&prog, text, profile);
+    if let Err(e) = std::fs::write(path, format!("{}\n", listing)) {
+        fail!("failed to write profile to {}: {}", path, e);
+    }
+}
+
+/// Parse `text` without any of the additional processing done by `get_context`.
+fn parse<'a>(a: &'a Arena, text: &'a str) -> Result<ast::Prog<'a, 'a, &'a str>, CompileError> {
+    let mut buf = Vec::new();
+    let mut prog = ast::Prog::from_stage(Stage::Main(()));
+    parsing::syntax::ProgParser::new()
+        .parse(a, &mut buf, &mut prog, lexer::Tokenizer::new(text))
+        .map_err(parsing::parse_error)?;
+    Ok(prog)
+}
+
+/// Print a formatted version of `prog` to standard output.
+fn pretty_print(prog: &str, program_file: Option<&str>) {
+    let a = Arena::default();
+    let text = a.alloc_str(prog);
+    match parse(&a, text) {
+        Ok(prog) => {
+            let _ = writeln!(std::io::stdout(), "{}", pretty::pretty_print(&prog, text));
+        }
+        Err(e) => fail!(
+            "{}",
+            Source {
+                text,
+                file: program_file
+            }
+            .render(&e)
+        ),
+    }
+}
+
+/// Run the lint pass over `prog`, printing any warnings to standard error. If `fatal` is set, exit
+/// with an error if there were any warnings.
+fn lint(prog: &str, raw: &RawPrelude, fatal: bool) {
+    let a = Arena::default();
+    let prelude = get_prelude(&a, raw);
+    let text = a.alloc_str(prog);
+    let mut prog = match parse(&a, text) {
+        Ok(prog) => prog,
+        // Syntax errors are reported when the program is compiled.
+        Err(_) => return,
+    };
+    prog.prelude_vardecs = prelude.var_decs;
+    prog.operand_vardecs = prelude.operand_var_decs;
+    let predefined: Vec<&str> = prog
+        .prelude_vardecs
+        .iter()
+        .map(|(v, _)| *v)
+        .chain(prog.operand_vardecs.iter().map(|(_, v, _)| *v))
+        .collect();
+    let source = Source {
+        text,
+        file: prelude.program_file,
+    };
+    let mut warnings = lint::lint(&prog, &predefined[..]);
+    // Comparisons are checked once types are known. Errors are reported when the program is
+    // compiled.
+    if let Ok(mut ctx) = try_get_context(text, &a, get_prelude(&a, raw)) {
+        warnings.extend(lint::lint_comparisons(&mut ctx).unwrap_or_default());
+    }
+    for w in warnings.iter() {
+        let mut e = CompileError::new(format!("warning: {}", w.msg));
+        e.span = w.span;
+        eprintln_ignore!("{}", source.render(&e));
+    }
+    if fatal && warnings.len() > 0 {
+        fail!("exiting due to {} lint warning(s)", warnings.len());
+    }
+}
+
+/// Read up to `repl::SAMPLE_LINES` lines from `files`, or nothing if there are none. Standard
+/// input is where entries are read from, so it is never sampled.
+fn read_sample(files: &[String]) -> Vec<u8> {
+    use io::BufRead;
+    let mut sample = Vec::new();
+    let mut lines = 0;
+    for file in files {
+        let mut r = open_file_read(file.as_str());
+        while lines < repl::SAMPLE_LINES {
+            match r.read_until(b'\n', &mut sample) {
+                Ok(0) => break,
+                Ok(_) => lines += 1,
+                Err(e) => fail!("failed to read {}: {}", file, e),
+            }
+        }
+    }
+    sample
+}
+
+/// Run the REPL started by `frawk repl [file...]`, reading entries from standard input and
+/// running each one against a sample of the input files.
+fn repl(raw: &RawPrelude, input_files: &[String], ifmt: Option<InputFormat>, check_utf8: bool) {
+    use io::{BufRead, IsTerminal};
+    let sample = read_sample(input_files);
+    let sample_name = input_files.get(0).cloned().unwrap_or_else(|| "-".into());
+    let state_file = std::env::temp_dir().join(format!("frawk-repl-{}", std::process::id()));
+    let state_path = state_file.to_string_lossy().into_owned();
+    let interactive = io::stdin().is_terminal();
+    let mut defs = String::new();
+    let mut state = String::new();
+    let mut entry = String::new();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    if interactive {
+        eprintln_ignore!(
+            "frawk repl: {} lines of sample input. Type :help for help.",
+            sample.iter().filter(|b| **b == b'\n').count()
+        );
+    }
+    loop {
+        if interactive {
+            let prompt = if entry.len() == 0 {
+                "frawk> "
+            } else {
+                "   ... "
+            };
+            let mut err = io::stderr();
+            let _ = write!(&mut err, "{}", prompt);
+            let _ = err.flush();
+        }
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => fail!("failed to read standard input: {}", e),
+            None => break,
+        };
+        if entry.len() == 0 {
+            match line.trim() {
+                "" => continue,
+                ":q" | ":quit" => break,
+                ":help" => {
+                    eprintln_ignore!("{}", repl::HELP);
+                    continue;
+                }
+                ":state" => {
+                    let _ = write!(std::io::stdout(), "{}", state);
+                    continue;
+                }
+                ":reset" => {
+                    defs.clear();
+                    state.clear();
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        entry.push_str(line.as_str());
+        entry.push('\n');
+        let kind = repl::classify(entry.as_str());
+        if kind == repl::Entry::Incomplete {
+            continue;
+        }
+        let text = std::mem::replace(&mut entry, String::new());
+        let prog = repl::assemble(text.as_str(), state.as_str(), defs.as_str(), None);
+        let a = Arena::default();
+        let mut ctx = match try_get_context(prog.as_str(), &a, get_prelude(&a, raw)) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                eprintln_ignore!("{}", e);
+                continue;
+            }
+        };
+        if kind == repl::Entry::Functions {
+            defs.push_str(text.as_str());
+            continue;
+        }
+        let globals = match compile::global_types(&mut ctx) {
+            Ok(globals) => globals,
+            Err(e) => {
+                eprintln_ignore!("{}", ctx.source.render(&e));
+                continue;
+            }
+        };
+        let save = repl::save_state(&globals[..], state_path.as_str());
+        let prog = repl::assemble(
+            text.as_str(),
+            state.as_str(),
+            defs.as_str(),
+            Some(save.as_str()),
+        );
+        let a = Arena::default();
+        let mut ctx = match try_get_context(prog.as_str(), &a, get_prelude(&a, raw)) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                eprintln_ignore!("{}", e);
+                continue;
+            }
+        };
+        let source = ctx.source;
+        let input: Box<dyn io::Read + Send> = Box::new(io::Cursor::new(sample.clone()));
+        let ff = runtime::writers::default_factory();
+        let res = match ifmt {
+            Some(ifmt) => {
+                let inp = CSVReader::new(
+                    once((input, sample_name.clone())),
+                    ifmt,
+                    CHUNK_SIZE,
+                    check_utf8,
+                    ExecutionStrategy::Serial,
+                );
+                compile::bytecode(&mut ctx, inp, ff, 1).and_then(|mut interp| interp.run())
+            }
+            None => {
+                let inp = chained(RegexSplitter::new(
+                    input,
+                    CHUNK_SIZE,
+                    sample_name.clone(),
+                    check_utf8,
+                ));
+                compile::bytecode(&mut ctx, inp, ff, 1).and_then(|mut interp| interp.run())
+            }
+        };
+        match res {
+            Ok(()) => {
+                state = std::fs::read_to_string(&state_file).unwrap_or_default();
+                let _ = std::fs::remove_file(&state_file);
+            }
+            Err(e) => eprintln_ignore!("{}", source.render(&e)),
+        }
+    }
+    if entry.len() > 0 {
+        eprintln_ignore!("input ended in the middle of an entry; it was not run");
+    }
+    let _ = std::fs::remove_file(&state_file);
+}
+
+fn emit_bytecode(prog: &str, raw: &RawPrelude, path: &str) {
+    let a = Arena::default();
+    let mut ctx = get_context(prog, &a, get_prelude(&a, raw));
+    let analysis_result = ctx.analyze_sep_assignments();
+    let program = match compile::bytecode_program(&mut ctx) {
+        Ok(program) => program,
+        Err(e) => fail!("bytecode compilation failure: {}", ctx.source.render(&e)),
     };
+    if let Err(e) = std::fs::write(path, serialize::write_program(&analysis_result, &program)) {
+        fail!("failed to write bytecode to {}: {}", path, e);
+    }
+}
+
+fn run_interp_with_program<'a>(
+    program: bytecode::Program<'a>,
+    argv: &[&str],
+    stdin: impl LineReader,
+    ff: impl runtime::writers::FileFactory,
+    num_workers: usize,
+) {
+    let mut interp = program.into_interp(stdin, ff, num_workers);
+    interp.set_argv(argv);
+    let res = interp.run();
+    drop(interp);
+    if let Err(e) = res {
+        // The program text is not available, but the stack trace still has line numbers.
+        fail!(
+            "fatal error during execution: {}",
+            Source::default().render(&e)
+        );
+    }
+}
+
+/// Run `prog` in the interpreter while another thread compiles it with `backend`, handing it over
+/// to the compiled code once that is ready; see `runtime::tiered`. `raw` must have `tiered` set.
+fn run_tiered<LR>(
+    prog: &str,
+    raw: &RawPrelude,
+    backend: &str,
+    cfg: codegen::Config,
+    cache: Option<JitCache>,
+    stdin: LR,
+    ff: impl runtime::writers::FileFactory,
+) where
+    LR: LineReader,
+    codegen::intrinsics::InputData: From<codegen::intrinsics::InputTuple<LR>>,
+{
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    {
+        let prog = prog.to_string();
+        let raw = raw.clone();
+        let backend = backend.to_string();
+        std::thread::spawn(move || {
+            let a = Arena::default();
+            // The main thread has already parsed the program. If it cannot be compiled, the
+            // interpreter just runs all of it.
+            let mut ctx = match try_get_context(prog.as_str(), &a, get_prelude(&a, &raw)) {
+                Ok(ctx) => ctx,
+                Err(_) => return,
+            };
+            let ready = |main: Stage<*const u8>, atexit: Option<*const u8>| {
+                let addrs = (main.map(|p| p as usize), atexit.map(|p| p as usize));
+                if sender.send(addrs).is_ok() {
+                    runtime::tiered::set_ready();
+                }
+                // The code is freed when this returns; keep it until the process exits.
+                loop {
+                    std::thread::park();
+                }
+            };
+            let _ = match backend.as_str() {
+                #[cfg(feature = "llvm_backend")]
+                "llvm" => compile::with_llvm(&mut ctx, cfg, ready),
+                _ => compile::with_cranelift(&mut ctx, cfg, cache.as_ref(), ready),
+            };
+        });
+    }
+    let a = Arena::default();
+    let mut ctx = get_context(prog, &a, get_prelude(&a, raw));
+    let source = ctx.source;
+    let mut interp = match compile::bytecode(&mut ctx, stdin, ff, 1) {
+        Ok(interp) => interp,
+        Err(e) => fail!("bytecode compilation failure: {}", source.render(&e)),
+    };
+    interp.set_int_overflow(raw.scalars.int_overflow);
     if let Err(e) = interp.run() {
-        fail!("fatal error during execution: {}", e);
+        drop(interp);
+        fail!("fatal error during execution: {}", source.render(&e));
+    }
+    if !runtime::tiered::stopped() {
+        return;
+    }
+    let (main, atexit) = receiver
+        .recv()
+        .expect("compiled code must be sent before the interpreter stops");
+    let (core, input) = interp.into_parts();
+    let res = unsafe {
+        codegen::resume_main(
+            core,
+            input.into(),
+            main.map(|p| p as *const u8),
+            atexit.map(|p| p as *const u8),
+        )
+    };
+    if let Err(e) = res {
+        fail!("fatal error during execution: {}", source.render(&e));
     }
 }
 
@@ -247,9 +813,10 @@ fn run_cranelift_with_context<'a>(
     stdin: impl IntoRuntime,
     ff: impl runtime::writers::FileFactory,
     cfg: codegen::Config,
+    cache: Option<&JitCache>,
 ) {
-    if let Err(e) = compile::run_cranelift(&mut ctx, stdin, ff, cfg) {
-        fail!("error compiling cranelift: {}", e)
+    if let Err(e) = compile::run_cranelift(&mut ctx, stdin, ff, cfg, cache) {
+        fail!("error compiling cranelift: {}", ctx.source.render(&e))
     }
 }
 
@@ -262,7 +829,7 @@ cfg_if::cfg_if! {
             cfg: codegen::Config,
         ) {
             if let Err(e) = compile::run_llvm(&mut ctx, stdin, ff, cfg) {
-                fail!("error compiling llvm: {}", e)
+                fail!("error compiling llvm: {}", ctx.source.render(&e))
             }
         }
 
@@ -271,7 +838,7 @@ cfg_if::cfg_if! {
             let mut ctx = get_context(prog, &a, get_prelude(&a, raw));
             match compile::dump_llvm(&mut ctx, cfg) {
                 Ok(s) => s,
-                Err(e) => fail!("error compiling llvm: {}", e),
+                Err(e) => fail!("error compiling llvm: {}", ctx.source.render(&e)),
             }
         }
 
@@ -280,6 +847,56 @@ cfg_if::cfg_if! {
 
 const DEFAULT_OPT_LEVEL: i32 = 3;
 
+/// Pick a backend for `ctx` when none was given on the command line.
+///
+/// Compiling with cranelift costs a few milliseconds for a one-liner and more for larger scripts,
+/// which dominates the running time of simple programs over small inputs; those are interpreted.
+/// LLVM compiles slowest and produces the fastest code, so it is only chosen for large programs
+/// run over large inputs. Input sizes are only known when reading from files.
+fn choose_backend(ctx: &cfg::ProgramContext<&str>, input_files: &[String]) -> &'static str {
+    const SMALL_INPUT: u64 = 1 << 20;
+    const LARGE_INPUT: u64 = 1 << 30;
+    const LARGE_PROGRAM: usize = 2000;
+    // User-defined functions may recurse, so treat them like loops.
+    let has_udfs = ctx.funcs.len() > ctx.main_offsets().count();
+    let may_loop = ctx.num_loops() > 0 || has_udfs;
+    if !ctx.has_main_loop && !may_loop {
+        return "interp";
+    }
+    let input_size = if input_files.len() == 0 {
+        None
+    } else {
+        input_files
+            .iter()
+            .map(|f| std::fs::metadata(f).ok().map(|m| m.len()))
+            .try_fold(0u64, |acc, len| Some(acc.saturating_add(len?)))
+    };
+    match input_size {
+        Some(n) if n <= SMALL_INPUT && !may_loop => "interp",
+        Some(n)
+            if cfg!(feature = "llvm_backend")
+                && n >= LARGE_INPUT
+                && ctx.num_stmts() >= LARGE_PROGRAM =>
+        {
+            "llvm"
+        }
+        _ => default_jit(),
+    }
+}
+
+/// The JIT backend to use when none was given on the command line. Cranelift does not support
+/// every architecture frawk runs on; LLVM (if it was compiled in) or the interpreter are used
+/// there instead.
+fn default_jit() -> &'static str {
+    if codegen::clif::SUPPORTED {
+        "cranelift"
+    } else if cfg!(feature = "llvm_backend") {
+        "llvm"
+    } else {
+        "interp"
+    }
+}
+
 fn dump_bytecode(prog: &str, raw: &RawPrelude) -> String {
     use std::io::Cursor;
     let a = Arena::default();
@@ -298,7 +915,7 @@ fn dump_bytecode(prog: &str, raw: &RawPrelude) -> String {
         /*num_workers=*/ 1,
     ) {
         Ok(ctx) => ctx,
-        Err(e) => fail!("bytecode compilation failure: {}", e),
+        Err(e) => fail!("bytecode compilation failure: {}", ctx.source.render(&e)),
     };
     let mut v = Vec::<u8>::new();
     for (i, func) in interp.instrs().iter().enumerate() {
@@ -311,6 +928,46 @@ fn dump_bytecode(prog: &str, raw: &RawPrelude) -> String {
     String::from_utf8(v).unwrap()
 }
 
+fn dump_types(prog: &str, raw: &RawPrelude) -> String {
+    let a = Arena::default();
+    let mut ctx = get_context(prog, &a, get_prelude(&a, raw));
+    match compile::dump_types(&mut ctx) {
+        Ok(s) => s,
+        Err(e) => fail!("type inference failure: {}", ctx.source.render(&e)),
+    }
+}
+
+fn dump_ir(prog: &str, raw: &RawPrelude) -> String {
+    let a = Arena::default();
+    let mut ctx = get_context(prog, &a, get_prelude(&a, raw));
+    match compile::dump_ir(&mut ctx) {
+        Ok(s) => s,
+        Err(e) => fail!("compilation failure: {}", ctx.source.render(&e)),
+    }
+}
+
+fn dump_clif(prog: &str, cfg: codegen::Config, raw: &RawPrelude) -> String {
+    let a = Arena::default();
+    let mut ctx = get_context(prog, &a, get_prelude(&a, raw));
+    match compile::dump_clif(&mut ctx, cfg) {
+        Ok(s) => s,
+        Err(e) => fail!("error compiling cranelift: {}", ctx.source.render(&e)),
+    }
+}
+
+/// Parse a number of bytes, optionally followed by a K, M or G suffix.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, shift) = match s.as_bytes().last() {
+        Some(b'k') | Some(b'K') => (&s[..s.len() - 1], 10),
+        Some(b'm') | Some(b'M') => (&s[..s.len() - 1], 20),
+        Some(b'g') | Some(b'G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let n = digits.parse::<usize>().map_err(|e| e.to_string())?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| format!("{} is too large", s))
+}
+
 fn main() {
     #[allow(unused_mut)]
     let mut app = App::new("frawk")
@@ -318,15 +975,138 @@ fn main() {
         .author("Eli R.")
         .about("frawk is a pattern scanning and (semi-structured) text processing language")
         .arg("-f, --program-file=[FILE] 'a file containing frawk program'")
+        .arg("-n, --null-input 'do not read standard input unless it is named as `-` among the input files, so that a program with no input files runs BEGIN and END, with no records in between, instead of waiting for input'")
+        .arg(Arg::new("eval")
+             .long("eval")
+             .short('e')
+             .takes_value(true)
+             .value_name("EXPR")
+             .multiple(true)
+             .number_of_values(1)
+             .about("print the value of the expression EXPR and exit without reading any input; may be repeated. `frawk eval EXPR...` does the same. Variables set with -v are visible to EXPR"))
+        .arg("--eval-format=[FMT] 'with -e or `frawk eval`, print each value with printf FMT rather than print, e.g. %.2f, %.15g or %x'")
         .arg(Arg::new("opt-level")
              .long("opt-level")
              .short('O')
              .about("the optimization level for the program. Positive levels determine the optimization level for LLVM. Level -1 forces bytecode interpretation")
              .possible_values(&["0", "1", "2", "3"]))
         .arg("--out-file=[FILE] 'the output file used in place of standard input'")
+        .arg(Arg::new("buffering")
+             .long("buffering")
+             .takes_value(true)
+             .possible_values(&["line", "block", "immediate"])
+             .about("when to write buffered output: as each line is completed, once a full buffer has accumulated, or as soon as anything is printed. By default, standard output is line-buffered when it is a terminal, and output is block-buffered otherwise"))
+        .arg(Arg::new("on-broken-pipe")
+             .long("on-broken-pipe")
+             .takes_value(true)
+             .possible_values(&["exit", "end"])
+             .about("what to do when whatever is reading standard output goes away, as in `frawk ... | head`. Either way, frawk stops reading input and does not report an error. With 'exit' (the default) it exits immediately; with 'end' it runs the END block first, discarding anything it prints to standard output"))
+        .arg("--stdout-buffer-size=[BYTES] 'the size of the buffer used for standard output and commands, such as 64K (default 4K)'")
+        .arg("--file-buffer-size=[BYTES] 'the size of the buffer used for each output file, such as 1M (default 32K)'")
+        .arg("--max-open-files=[N] 'keep at most N output files open at once, closing the least recently written-to file when another is opened, and appending to it when it is written to again. Defaults to a little less than the limit on open file descriptors'")
+        .arg("--resume=[FILE] 'record how many records of each input file have been processed in FILE, and skip those records when FILE already exists, so that an interrupted job can pick up where it left off. Only supported when reading input serially'")
+        .arg("--skip-header=[N] 'drop the first N records of each input file before the program sees them; they do not count towards NR or FNR. Not supported with -pr'")
+        .arg("--match-status 'exit with status 0 if the action of any rule (other than BEGIN and END) ran, or the program printed anything, and 1 otherwise, as grep does; a status passed to `exit` takes precedence'")
+        .arg("-q, --quiet 'discard what the program prints to standard output, and exit as with --match-status, so that frawk can be used as a test in shell scripts like grep -q'")
+        .arg("--round-trip 'write records that `print` or `print $0` outputs unchanged exactly as they were read, including their original record separator'")
+        .arg("--skip-footer=[N] 'drop the last N records of each input file before the program sees them. Not supported with -pr'")
+        .arg("--record-timeout=[SECS] 'report any record that takes longer than SECS seconds to process, printing the record, and by default abort. Protects batch jobs from a single pathological record. Only supported in parallel mode'")
+        .arg(Arg::new("on-record-timeout")
+             .long("on-record-timeout")
+             .takes_value(true)
+             .requires("record-timeout")
+             .possible_values(&["abort", "warn"])
+             .about("what to do when a record exceeds --record-timeout: 'abort' (the default) exits with status 2, and 'warn' prints a warning and lets the record finish. frawk cannot stop a record part-way through"))
+        .arg("--pre=[CMD] 'pipe each input file through CMD before reading it, passing the file name as its last argument, as in --pre=\"zcat -f\"'")
+        .arg(Arg::new("crlf")
+             .long("crlf")
+             .takes_value(true)
+             .possible_values(&["strip", "keep"])
+             .about("what to do with CRLF line endings in the main input: 'strip' removes the carriage returns, so that records (and their last field) do not end in one, and 'keep' leaves them in place. Defaults to 'strip' on Windows and 'keep' elsewhere"))
+        .arg("--stats 'print statistics to standard error on exit: the records read from each input file, the bytes read and written, the size of each global array after END, how often the regex cache was hit, and the time spent compiling, reading input and executing'")
+        .arg("--progress 'periodically print how much of the input has been read to standard error, with the percentage read and an estimate of the time remaining when the input files are regular files'")
+        .arg(Arg::new("report")
+             .long("report")
+             .takes_value(true)
+             .possible_values(&["json"])
+             .about("write a summary of the run to standard error on exit, as a single JSON object, for programs that monitor frawk jobs: the exit status and any error, the records read from each input file, the bytes read and written, the number of warnings of each kind, the time spent compiling, reading input and executing, and peak memory use"))
+        .arg(Arg::new("report-file")
+             .long("report-file")
+             .takes_value(true)
+             .requires("report")
+             .value_name("FILE")
+             .about("write the --report summary to this file instead of standard error"))
+        .arg("--state=[FILE] 'the state file used by --persist, load_state and save_state. It is created if it does not exist'")
+        .arg("--persist=[ARRAYS] 'a comma-separated list of arrays to load from the --state file before BEGIN and save back to it after END, so that a job can pick up where its last run left off'")
+        .arg(Arg::new("regex-classes")
+             .long("regex-classes")
+             .takes_value(true)
+             .possible_values(&["unicode", "ascii"])
+             .about("whether classes like \\w, \\d and \\s, and case-insensitive matching with (?i), cover all of Unicode (the default) or only ASCII. With 'ascii', . and negated classes match any single byte. Patterns can override this with (?u) or (?-u)"))
+        .arg("--regex-cache-size=[N] 'keep at most N compiled dynamic regular expressions, evicting the least recently used one when another is compiled (default 1024)'")
+        .arg("--jit-cache=[DIR] 'cache compiled code in DIR, reusing it on later runs with the same program and options. Only used by the cranelift backend'")
+        .arg("--bytes 'count bytes rather than characters in length, substr, index, match (RSTART and RLENGTH), levenshtein and jaro_winkler'")
         .arg("--utf8 'validate all input as UTF-8, returning an error if it is invalid'")
+        .arg("--pretty-print 'print the program with consistent indentation and spacing instead of running it. Comments are preserved'")
+        .arg(Arg::new("lint")
+             .long("lint")
+             .takes_value(true)
+             .min_values(0)
+             .require_equals(true)
+             .possible_values(&["fatal"])
+             .about("print warnings for suspicious constructs in the program, such as variables that are never assigned. With --lint=fatal, exit with an error if there are any warnings"))
+        .arg(Arg::new("compat")
+             .long("compat")
+             .takes_value(true)
+             .possible_values(&["gawk"])
+             .about("match gawk where frawk's behavior differs: numbers that are not integers convert to strings using %.6g, and values that look numeric compare as numbers even when they are strings"))
+        .arg("--non-decimal-data 'recognize hexadecimal (0x1f), octal (0o17) and binary (0b101) numbers when converting strings, such as fields, to numbers. Numeric constants in the program can always use these prefixes'")
+        .arg("--wrap 'keep the results of +, - and * on integers as integers, wrapping around if they overflow. By default they are computed in floating point, as in gawk, so they never overflow but are only exact up to 2^53'")
+        .arg(Arg::new("checked")
+             .long("checked")
+             .conflicts_with("wrap")
+             .about("keep the results of +, - and * on integers as integers, and stop with a runtime error if they overflow. The interp backend also reports the location in the program"))
+        .arg(Arg::new("div-by-zero")
+             .long("div-by-zero")
+             .takes_value(true)
+             .possible_values(&["inf", "error"])
+             .about("what dividing a floating-point number by zero, with / or %, does: return an infinity or NaN ('inf', the default), or stop with a runtime error ('error'). Integer division by zero, with % or the intdiv and divmod functions, is always an error"))
+        .arg(Arg::new("strict")
+             .long("strict")
+             .takes_value(true)
+             .min_values(0)
+             .require_equals(true)
+             .possible_values(&["error", "warn"])
+             .about("treat likely mistakes as errors: using a variable as both an array and a scalar is a compile error, and converting a string that does not look like a number to a number, or reading a variable before it has been assigned, is a runtime error. With --strict=warn, those runtime errors are reported as warnings (see --warnings) and the program carries on. Implies --backend=interp"))
+        .arg(Arg::new("warnings")
+             .long("warnings")
+             .takes_value(true)
+             .min_values(0)
+             .require_equals(true)
+             .possible_values(&["plain", "json"])
+             .about("report recoverable problems found while running to standard error: invalid UTF-8 replaced by printf, a last input record without a record separator, and output files still open at exit. Warnings are plain text by default, or one JSON object per line with --warnings=json"))
+        .arg(Arg::new("profile")
+             .long("profile")
+             .takes_value(true)
+             .min_values(0)
+             .require_equals(true)
+             .value_name("FILE")
+             .about("count how many times each statement runs and how long each rule and function takes, and write the program annotated with those figures to FILE (awkprof.out by default) on exit. Implies --backend=interp; when running in parallel, only the main thread is profiled"))
+        .arg(Arg::new("trace")
+             .long("trace")
+             .takes_value(true)
+             .min_values(0)
+             .require_equals(true)
+             .value_name("LINES")
+             .about("log each statement to standard error as it runs, along with NR and the values of the global variables it mentions. LINES limits the log to statements on a line or range of lines, such as 10 or 10-20. Implies --backend=interp"))
+        .arg("--trace-limit=[N] 'stop logging statements for --trace after the first N'")
         .arg("--dump-cfg 'print untyped SSA form for input program'")
         .arg("--dump-bytecode 'print bytecode for input program'")
+        .arg("--dump-types 'print the types inferred for each variable and function in the input program'")
+        .arg("--dump-ir 'print typed IR for input program'")
+        .arg("--dump-clif 'print cranelift IR for input program'")
+        .arg("--emit-bytecode=[FILE] 'compile the program to bytecode and write it to FILE instead of running it'")
+        .arg("--load-bytecode=[FILE] 'run a program written by --emit-bytecode using the bytecode interpreter. Any program argument is treated as an input file'")
         .arg(Arg::new("parse-header")
              .long("parse-header")
              .short('H')
@@ -335,30 +1115,43 @@ fn main() {
         .arg(Arg::new("input-format")
              .long("input-format")
              .short('i')
-             .possible_values(&["csv", "tsv"])
-             .about("Input is split according to the rules of (csv|tsv). $0 contains the unescaped line. Assigning to columns does nothing."))
+             .possible_values(&["csv", "tsv", "inplace"])
+             .multiple_occurrences(true)
+             .about("Input is split according to the rules of (csv|tsv). $0 contains the unescaped line. Assigning to columns does nothing. `-i inplace` instead replaces each input file with what the program prints while reading it, and can be combined with either format"))
+        .arg("--inplace-suffix=[SUFFIX] 'with -i inplace, keep the original contents of each input file in a file whose name is the input file name followed by SUFFIX'")
+        .arg("--diff 'like -i inplace, but leave the input files as they are and print a unified diff of the changes that would be made to each one'")
         .arg(Arg::new("var")
              .long("var")
              .short('v')
              .multiple(true)
+             .number_of_values(1)
              .takes_value(true)
              .about("Has the form <identifier>=<expr>"))
         .arg("-F, --field-separator=[SEPARATOR] 'Field separator for frawk program.'")
+        .arg("--perf-map 'write /tmp/perf-<pid>.map so that perf can attribute samples in JIT-compiled code to AWK functions. Only supported by the cranelift backend'")
+        .arg(Arg::new("jit-opt")
+             .long("jit-opt")
+             .takes_value(true)
+             .possible_values(&["none", "speed", "speed_and_size"])
+             .about("How much the cranelift backend optimizes generated code. `none` compiles fastest; `speed` (the default) runs loop-invariant code motion and other optimizations"))
         .arg(Arg::new("backend")
              .long("backend")
              .short('b')
-             .about("The backend used to run the frawk program, ranging from fastest to compile and slowest to execute, and slowest to compile and fastest to execute. Cranelift is the default")
+             .about("The backend used to run the frawk program, ranging from fastest to compile and slowest to execute, and slowest to compile and fastest to execute. When unspecified, a backend is chosen based on the size of the program and its input")
              .possible_values(&["interp", "cranelift", "llvm"]))
+        .arg(Arg::new("tiered")
+             .long("tiered")
+             .about("Start running the program in the interpreter while it is compiled with the cranelift or LLVM backend in the background, and switch to the compiled code at the next record once it is ready. This only applies to serial programs that read their input record by record, and which run compiled code rather than the interpreter"))
         .arg(Arg::new("output-format")
              .long("output-format")
              .short('o')
              .possible_values(&["csv", "tsv"])
              .about("If set, records output via print are escaped according to the rules of the corresponding format"))
         .arg(Arg::new("program")
-             .about("The frawk program to execute")
+             .about("The frawk program to execute. `frawk repl [FILE...]` instead starts an interactive session that runs each pattern or action typed at the prompt against the first lines of the input files, and `frawk eval EXPR...` prints the value of each expression, like -e")
              .index(1))
         .arg(Arg::new("input-files")
-             .about("Input files to be read by frawk program")
+             .about("Input files to be read by frawk program. An operand of the form var=value assigns value to var before the next file is read")
              .index(2)
              .multiple(true))
         .arg(Arg::new("parallel-strategy")
@@ -380,13 +1173,46 @@ fn main() {
             app = app.arg("--dump-llvm 'print LLVM-IR for the input program'");
         }
     }
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "fancy_regex")] {
+            app = app.arg(Arg::new("regex-engine")
+                .long("regex-engine")
+                .takes_value(true)
+                .possible_values(&["default", "fancy"])
+                .about("with 'fancy', regexes using lookaround or backreferences are supported for matching (~, match, sub and gsub); they are slower than other regexes, and never match input that is not valid UTF-8"));
+        }
+    }
     let matches = app.get_matches();
-    let ifmt = match matches.value_of("input-format") {
-        Some("csv") => Some(InputFormat::CSV),
-        Some("tsv") => Some(InputFormat::TSV),
-        Some(x) => fail!("invalid input format: {}", x),
-        None => None,
+    // Print the statistics once everything else has been dropped, on any path out of main.
+    let _stats_report = if matches.is_present("stats") || matches.is_present("report") {
+        if matches.is_present("stats") {
+            runtime::stats::enable();
+        }
+        if matches.is_present("report") {
+            runtime::stats::enable_json(matches.value_of("report-file").map(String::from));
+        }
+        Some(runtime::stats::Report)
+    } else {
+        None
     };
+    // cmp_locale follows the user's locale; this has to happen before any threads are started.
+    runtime::collate::init_locale();
+    if matches.is_present("warnings") {
+        runtime::warnings::set_format(match matches.value_of("warnings") {
+            Some("json") => runtime::warnings::Format::Json,
+            _ => runtime::warnings::Format::Plain,
+        });
+    }
+    let mut ifmt = None;
+    let mut in_place = false;
+    for f in matches.values_of("input-format").into_iter().flatten() {
+        match f {
+            "csv" => ifmt = Some(InputFormat::CSV),
+            "tsv" => ifmt = Some(InputFormat::TSV),
+            "inplace" => in_place = true,
+            x => fail!("invalid input format: {}", x),
+        }
+    }
     let exec_strategy = match matches.value_of("parallel-strategy") {
         Some("r") | Some("record") => ExecutionStrategy::ShardPerRecord,
         Some("f") | Some("file") => ExecutionStrategy::ShardPerFile,
@@ -406,31 +1232,69 @@ fn main() {
         },
         None => exec_strategy.num_workers(),
     };
+    let load_bytecode = matches.value_of("load-bytecode");
+    // If the program comes from a file (either source code passed with -f, or a bytecode file),
+    // then the "program" is really the first input file.
+    let program_is_operand = load_bytecode.is_some() || matches.is_present("program-file");
+    // With -e, or `frawk eval`, the operands are expressions to print rather than input files.
+    let eval_exprs: Option<Vec<&str>> = if let Some(exprs) = matches.values_of("eval") {
+        if program_is_operand || matches.is_present("program") {
+            fail!("-e cannot be combined with a program or input files");
+        }
+        Some(exprs.collect())
+    } else if matches.value_of("program") == Some("eval") && !program_is_operand {
+        match matches.values_of("input-files") {
+            Some(exprs) => Some(exprs.collect()),
+            None => fail!("usage: frawk eval EXPR..."),
+        }
+    } else {
+        None
+    };
+    if matches.is_present("eval-format") && eval_exprs.is_none() {
+        fail!("--eval-format only applies to -e and `frawk eval`");
+    }
     let argv: Vec<String> = std::env::args()
         .next()
         .into_iter()
+        .chain(
+            matches
+                .value_of("program")
+                .filter(|_| program_is_operand)
+                .map(String::from),
+        )
         .chain(
             matches
                 .values_of("input-files")
                 .into_iter()
+                .filter(|_| eval_exprs.is_none())
                 .flat_map(|x| x.map(String::from)),
         )
         .collect();
-    let mut input_files: Vec<String> = matches
-        .values_of("input-files")
-        .map(|x| x.map(String::from).collect())
-        .unwrap_or_else(Vec::new);
+    if !matches!(exec_strategy, ExecutionStrategy::Serial)
+        && argv.iter().skip(1).any(|arg| is_var_assignment(arg))
+    {
+        fail!("var=value operands are not supported in parallel mode");
+    }
+    let input_files: Vec<String> = argv
+        .iter()
+        .skip(1)
+        .filter(|f| !is_var_assignment(f))
+        .cloned()
+        .collect();
+    // With -n, standard input is only read if it is named explicitly, as `-`.
+    let null_input = matches.is_present("null-input");
+    // getline can read standard input itself unless the main input does.
+    if (input_files.len() > 0 || null_input) && input_files.iter().all(|f| f != "-") {
+        runtime::set_main_input_from_files();
+    }
     let program_string = {
-        if let Some(pfile) = matches.value_of("program-file") {
+        if let Some(exprs) = &eval_exprs {
+            eval_program(exprs, matches.value_of("eval-format"))
+        } else if load_bytecode.is_some() {
+            String::new()
+        } else if let Some(pfile) = matches.value_of("program-file") {
             match std::fs::read_to_string(pfile) {
-                Ok(p) => {
-                    // We specified a file on the command line, so the "program" will be
-                    // interpreted as another input file.
-                    if let Some(p) = matches.value_of("program") {
-                        input_files.push(p.into());
-                    }
-                    p
-                }
+                Ok(p) => p,
                 Err(e) => fail!("failed to read program from {}: {}", pfile, e),
             }
         } else if let Some(p) = matches.value_of("program") {
@@ -450,6 +1314,210 @@ fn main() {
     };
     let arbitrary_shell = matches.is_present("arbitrary-shell");
     let parse_header = matches.is_present("parse-header");
+    let gawk_compat = matches.value_of("compat") == Some("gawk");
+    let strict = matches.is_present("strict");
+    let strict_warn = matches.value_of("strict") == Some("warn");
+    if strict_warn && runtime::warnings::format() == runtime::warnings::Format::Off {
+        runtime::warnings::set_format(runtime::warnings::Format::Plain);
+    }
+    let checked = matches.is_present("checked");
+    let int_overflow = if checked {
+        IntOverflow::Checked
+    } else if matches.is_present("wrap") {
+        IntOverflow::Wrap
+    } else {
+        IntOverflow::Promote
+    };
+    if int_overflow != IntOverflow::Promote {
+        runtime::arith::disable_int_promotion();
+    }
+    if gawk_compat {
+        runtime::compat::enable_gawk();
+    }
+    let non_decimal_data = matches.is_present("non-decimal-data");
+    if non_decimal_data {
+        runtime::enable_non_decimal_data();
+    }
+    match matches.value_of("buffering") {
+        Some("line") => runtime::writers::set_buffering(runtime::writers::Buffering::Line),
+        Some("block") => runtime::writers::set_buffering(runtime::writers::Buffering::Block),
+        Some("immediate") => {
+            runtime::writers::set_buffering(runtime::writers::Buffering::Immediate)
+        }
+        Some(x) => fail!("invalid buffering mode: {}", x),
+        None => {}
+    }
+    let div_by_zero_errors = match matches.value_of("div-by-zero") {
+        Some("inf") | None => false,
+        Some("error") => {
+            runtime::arith::enable_div_by_zero_errors();
+            true
+        }
+        Some(x) => fail!("invalid value for --div-by-zero: {}", x),
+    };
+    match matches.value_of("on-broken-pipe") {
+        Some("exit") | None => {}
+        Some("end") => runtime::writers::set_end_on_broken_pipe(true),
+        Some(x) => fail!("invalid value for --on-broken-pipe: {}", x),
+    }
+    #[cfg(feature = "fancy_regex")]
+    match matches.value_of("regex-engine") {
+        Some("default") | None => {}
+        Some("fancy") => runtime::matcher::set_fancy(true),
+        Some(x) => fail!("invalid value for --regex-engine: {}", x),
+    }
+    if let Some(size) = matches.value_of("stdout-buffer-size") {
+        match parse_size(size) {
+            Ok(n) => runtime::writers::set_stdout_buffer_size(n),
+            Err(e) => fail!("invalid value for --stdout-buffer-size: {}", e),
+        }
+    }
+    if let Some(size) = matches.value_of("file-buffer-size") {
+        match parse_size(size) {
+            Ok(n) => runtime::writers::set_file_buffer_size(n),
+            Err(e) => fail!("invalid value for --file-buffer-size: {}", e),
+        }
+    }
+    if matches.is_present("bytes") {
+        runtime::chars::set_bytes(true);
+    }
+    match matches.value_of("regex-classes") {
+        Some("unicode") | None => {}
+        Some("ascii") => runtime::matcher::set_unicode(false),
+        Some(x) => fail!("invalid value for --regex-classes: {}", x),
+    }
+    if let Some(n) = matches.value_of("regex-cache-size") {
+        match n.parse::<usize>() {
+            Ok(n) if n > 0 => runtime::set_regex_cache_size(n),
+            Ok(_) => fail!("value of --regex-cache-size must be positive"),
+            Err(e) => fail!("value of --regex-cache-size must be a number: {}", e),
+        }
+    }
+    if let Some(n) = matches.value_of("max-open-files") {
+        match n.parse::<usize>() {
+            Ok(n) if n > 0 => runtime::writers::set_max_open_files(n),
+            Ok(_) => fail!("value of --max-open-files must be positive"),
+            Err(e) => fail!("value of --max-open-files must be a number: {}", e),
+        }
+    }
+    if let Some(path) = matches.value_of("resume") {
+        if !matches!(exec_strategy, ExecutionStrategy::Serial) {
+            fail!("--resume is only supported when reading input serially");
+        }
+        if let Err(e) = runtime::checkpoint::set_resume_file(path) {
+            fail!("{}", e);
+        }
+    }
+    let match_status = matches.is_present("match-status") || matches.is_present("quiet");
+    if match_status {
+        runtime::exit::enable_match_status();
+    }
+    if matches.is_present("quiet") && matches.is_present("out-file") {
+        fail!("--quiet cannot be combined with --out-file");
+    }
+    let in_place = in_place || matches.is_present("diff");
+    if in_place {
+        if !matches!(exec_strategy, ExecutionStrategy::Serial) {
+            fail!("-i inplace is only supported when reading input serially");
+        }
+        if input_files.len() == 0 {
+            fail!("-i inplace requires input files to edit");
+        }
+        runtime::inplace::enable(
+            matches.value_of("inplace-suffix").map(String::from),
+            matches.is_present("diff"),
+        );
+    } else if matches.is_present("inplace-suffix") {
+        fail!("--inplace-suffix requires -i inplace");
+    }
+    let mut skipped = [0usize; 2];
+    for (i, flag) in ["skip-header", "skip-footer"].iter().enumerate() {
+        if let Some(n) = matches.value_of(flag) {
+            match n.parse::<usize>() {
+                Ok(n) => skipped[i] = n,
+                Err(e) => fail!("value of --{} must be a number: {}", flag, e),
+            }
+        }
+    }
+    if skipped != [0, 0] {
+        if matches!(exec_strategy, ExecutionStrategy::ShardPerRecord) {
+            fail!("--skip-header and --skip-footer are not supported with -pr; use -pf to read each file on a single worker");
+        }
+        if matches.is_present("resume") {
+            fail!("--skip-header and --skip-footer cannot be combined with --resume");
+        }
+    }
+    // With -H, each CSV or TSV file after the first starts with a copy of the header, which should
+    // not be read as data.
+    let repeated_header = matches.is_present("parse-header")
+        && ifmt.is_some()
+        && !matches.is_present("resume")
+        && !matches!(exec_strategy, ExecutionStrategy::ShardPerRecord);
+    if skipped != [0, 0] || repeated_header {
+        runtime::set_skipped_records(skipped[0], skipped[1], repeated_header);
+    }
+    if let Some(secs) = matches.value_of("record-timeout") {
+        if matches!(exec_strategy, ExecutionStrategy::Serial) {
+            fail!("--record-timeout is only supported in parallel mode");
+        }
+        match secs.parse::<f64>() {
+            Ok(secs) if secs > 0.0 && secs.is_finite() => runtime::watchdog::set_record_timeout(
+                std::time::Duration::from_secs_f64(secs),
+                matches.value_of("on-record-timeout") != Some("warn"),
+            ),
+            _ => fail!("value of --record-timeout must be a positive number of seconds"),
+        }
+    }
+    if let Some(cmd) = matches.value_of("pre") {
+        runtime::command::set_preprocessor(cmd);
+    }
+    if let Some(crlf) = matches.value_of("crlf") {
+        runtime::command::set_strip_cr(crlf == "strip");
+    }
+    // Clear the last progress report once everything else has been dropped.
+    let _progress = if matches.is_present("progress") {
+        runtime::progress::enable(input_size(&input_files, matches.is_present("pre")));
+        Some(runtime::progress::Finish)
+    } else {
+        None
+    };
+    if let Some(path) = matches.value_of("state") {
+        runtime::state::set_state_file(path);
+    }
+    let persist: Vec<String> = match matches.value_of("persist") {
+        Some(_) if !matches.is_present("state") => fail!("--persist requires --state"),
+        Some(names) => names
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let mut bs = name.bytes();
+                let valid = bs
+                    .next()
+                    .map_or(false, |b| b.is_ascii_alphabetic() || b == b'_')
+                    && bs.all(|b| b.is_ascii_alphanumeric() || b == b'_');
+                if !valid {
+                    fail!("--persist: {:?} is not a variable name", name);
+                }
+                String::from(name)
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let trace = if matches.is_present("trace") {
+        let mut config = trace::Config::default();
+        if let Err(e) = config.parse_lines(matches.value_of("trace").unwrap_or("")) {
+            fail!("--trace: {}", e);
+        }
+        if let Some(n) = matches.value_of("trace-limit") {
+            match n.parse::<u64>() {
+                Ok(n) => config.limit = Some(n),
+                Err(e) => fail!("value of --trace-limit must be a number: {}", e),
+            }
+        }
+        Some(config)
+    } else {
+        None
+    };
 
     let opt_level: i32 = match matches.value_of("opt-level") {
         Some("3") => 3,
@@ -460,7 +1528,37 @@ fn main() {
         None => DEFAULT_OPT_LEVEL,
         Some(x) => panic!("this case should be covered by clap argument validation: found unexpected opt-level value {}", x),
     };
-    let raw = RawPrelude {
+    let jit_opt = match matches.value_of("jit-opt") {
+        Some("none") => codegen::JitOpt::None,
+        None | Some("speed") => codegen::JitOpt::Speed,
+        Some("speed_and_size") => codegen::JitOpt::SpeedAndSize,
+        Some(x) => fail!("invalid --jit-opt value: {}", x),
+    };
+    let config = codegen::Config {
+        opt_level: if opt_level < 0 { 3 } else { opt_level as usize },
+        num_workers,
+        jit_opt,
+        perf_map: matches.is_present("perf-map"),
+        int_overflow,
+    };
+    // Programs with a specialized implementation skip compilation entirely, unless a backend was
+    // requested explicitly, or an option changes how the program runs or which records it sees.
+    let kernels = matches.value_of("backend").is_none()
+        && matches!(exec_strategy, ExecutionStrategy::Serial)
+        && opt_level != 0
+        && matches!(escaper, Escaper::Identity)
+        && !matches.is_present("profile")
+        && trace.is_none()
+        && !strict
+        && !checked
+        && !runtime::checkpoint::enabled()
+        && !runtime::stats::enabled()
+        && !runtime::inplace::enabled()
+        && !runtime::skips_records();
+    // Programs that read or modify ARGV get their input files from ARGV as they run, rather than
+    // from the list given on the command line. That is only supported for serial, non-CSV input.
+    let dynamic_argv = matches!(exec_strategy, ExecutionStrategy::Serial) && ifmt.is_none();
+    let mut raw = RawPrelude {
         field_sep: matches.value_of("field-separator").map(String::from),
         var_decs: matches
             .values_of("var")
@@ -471,22 +1569,73 @@ fn main() {
             escaper,
             arbitrary_shell,
             fold_regexes: opt_level >= 3,
+            fold_constants: opt_level != 0,
+            inline_functions: opt_level != 0 && !matches.is_present("profile"),
             stage: exec_strategy.stage(),
+            kernels,
+            dynamic_argv,
+            tiered: false,
             parse_header,
+            trace: trace.is_some(),
+            gawk_compat,
+            strict,
+            int_overflow,
+            div_by_zero_errors,
+            non_decimal_data,
+            round_trip: matches.is_present("round-trip"),
+            match_status,
         },
         output_record_sep,
         argv,
+        program_file: matches.value_of("program-file").map(String::from),
+        persist,
+        stat_arrays: Vec::new(),
     };
+    if matches.value_of("program") == Some("repl")
+        && matches.value_of("program-file").is_none()
+        && load_bytecode.is_none()
+    {
+        repl(&raw, &input_files[..], ifmt, matches.is_present("utf8"));
+        return;
+    }
+    if matches.is_present("pretty-print") {
+        pretty_print(program_string.as_str(), matches.value_of("program-file"));
+        return;
+    }
+    if matches.is_present("lint") {
+        lint(
+            program_string.as_str(),
+            &raw,
+            matches.value_of("lint") == Some("fatal"),
+        );
+    }
+    if runtime::stats::text_enabled() && load_bytecode.is_none() {
+        // Report the sizes of the program's global arrays, if they can be determined. Any errors
+        // are reported when the program is compiled for real.
+        let a = Arena::default();
+        let globals = match try_get_context(program_string.as_str(), &a, get_prelude(&a, &raw)) {
+            Ok(mut ctx) => compile::global_types(&mut ctx).unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        raw.stat_arrays = globals
+            .into_iter()
+            .filter(|(_, ty)| ty.is_array())
+            .map(|(name, _)| name.into())
+            .collect();
+    }
+    let jit_cache = matches
+        .value_of("jit-cache")
+        .map(|dir| JitCache::new(dir, raw.cache_key(program_string.as_str(), &config)));
     let opt_dump_bytecode = matches.is_present("dump-bytecode");
     let opt_dump_cfg = matches.is_present("dump-cfg");
+    let opt_dump_types = matches.is_present("dump-types");
+    let opt_dump_ir = matches.is_present("dump-ir");
+    let opt_dump_clif = matches.is_present("dump-clif");
+    let opt_dump = opt_dump_cfg || opt_dump_types || opt_dump_ir || opt_dump_clif;
     cfg_if::cfg_if! {
         if #[cfg(feature="llvm_backend")] {
             let opt_dump_llvm = matches.is_present("dump-llvm");
             if opt_dump_llvm {
-                let config = codegen::Config {
-                    opt_level: if opt_level < 0 { 3 } else { opt_level as usize },
-                    num_workers,
-                };
                 let _ = write!(
                     std::io::stdout(),
                     "{}",
@@ -497,7 +1646,13 @@ fn main() {
             let opt_dump_llvm = false;
         }
     }
-    let skip_output = opt_dump_llvm || opt_dump_bytecode || opt_dump_cfg;
+    let opt_emit_bytecode = matches.value_of("emit-bytecode");
+    if load_bytecode.is_some()
+        && (opt_emit_bytecode.is_some() || opt_dump_bytecode || opt_dump || opt_dump_llvm)
+    {
+        fail!("--load-bytecode cannot be combined with options that compile a program");
+    }
+    let skip_output = opt_dump_llvm || opt_dump_bytecode || opt_dump || opt_emit_bytecode.is_some();
     if opt_dump_bytecode {
         let _ = write!(
             std::io::stdout(),
@@ -511,6 +1666,30 @@ fn main() {
         let mut stdout = std::io::stdout();
         let _ = ctx.dbg_print(&mut stdout);
     }
+    if opt_dump_types {
+        let _ = write!(
+            std::io::stdout(),
+            "{}",
+            dump_types(program_string.as_str(), &raw)
+        );
+    }
+    if opt_dump_ir {
+        let _ = write!(
+            std::io::stdout(),
+            "{}",
+            dump_ir(program_string.as_str(), &raw)
+        );
+    }
+    if opt_dump_clif {
+        let _ = write!(
+            std::io::stdout(),
+            "{}",
+            dump_clif(program_string.as_str(), config, &raw)
+        );
+    }
+    if let Some(path) = opt_emit_bytecode {
+        emit_bytecode(program_string.as_str(), &raw, path);
+    }
     if skip_output {
         return;
     }
@@ -520,9 +1699,17 @@ fn main() {
     // types, making functions hard to write. Still, there must be something to be done to clean
     // this up here.
     macro_rules! with_inp {
-        ($analysis:expr, $inp:ident, $body:expr) => {
-            if input_files.len() == 0 {
-                let _reader: Box<dyn io::Read + Send> = Box::new(io::stdin());
+        ($analysis:expr, $argv_input:expr, $inp:ident, $body:expr) => {
+            if $argv_input {
+                let stdin = runtime::command::line_endings(runtime::progress::counted(Box::new(io::stdin())));
+                let $inp = ArgvReader::new(stdin, null_input, chunk_size, check_utf8);
+                $body
+            } else if input_files.len() == 0 {
+                let _reader: Box<dyn io::Read + Send> = if null_input {
+                    Box::new(io::empty())
+                } else {
+                    runtime::command::line_endings(runtime::progress::counted(Box::new(io::stdin())))
+                };
                 match (ifmt, $analysis) {
                     (Some(ifmt), _) => {
                         let $inp = CSVReader::new(
@@ -554,7 +1741,7 @@ fn main() {
                                 $body
                             } else {
                                 let $inp = ByteReader::new(
-                                    once((io::stdin(), String::from("-"))),
+                                    once((_reader, String::from("-"))),
                                     field_sep[0],
                                     record_sep[0],
                                     chunk_size,
@@ -647,58 +1834,294 @@ fn main() {
     }
 
     let a = Arena::default();
-    let ctx = get_context(program_string.as_str(), &a, get_prelude(&a, &raw));
-    let analysis_result = ctx.analyze_sep_assignments();
-    let out_file = matches.value_of("out-file");
+    let out_file = if matches.is_present("quiet") {
+        Some(if cfg!(windows) { "NUL" } else { "/dev/null" })
+    } else {
+        matches.value_of("out-file")
+    };
     macro_rules! with_io {
-        (|$inp:ident, $out:ident| $body:expr) => {
+        ($analysis:expr, $argv_input:expr, |$inp:ident, $out:ident| $body:expr) => {
             match out_file {
                 Some(oup) => {
                     let $out = runtime::writers::factory_from_file(oup)
                         .unwrap_or_else(|e| fail!("failed to open {}: {}", oup, e));
-                    with_inp!(analysis_result, $inp, $body);
+                    with_inp!($analysis, $argv_input, $inp, $body);
                 }
                 None => {
                     let $out = runtime::writers::default_factory();
-                    with_inp!(analysis_result, $inp, $body);
+                    with_inp!($analysis, $argv_input, $inp, $body);
                 }
             }
         };
     }
-    match matches.value_of("backend") {
-        Some("llvm") => {
+    if let Some(path) = load_bytecode {
+        match matches.value_of("backend") {
+            None | Some("interp") => {}
+            Some(b) => fail!(
+                "bytecode files can only be run with the interp backend, not {}",
+                b
+            ),
+        }
+        let contents = std::fs::read(path)
+            .unwrap_or_else(|e| fail!("failed to read bytecode from {}: {}", path, e));
+        let (analysis_result, program) = serialize::read_program(&a, &contents[..])
+            .unwrap_or_else(|e| fail!("failed to load bytecode from {}: {}", path, e));
+        match (&program.stage, exec_strategy) {
+            (Stage::Main(_), ExecutionStrategy::Serial)
+            | (Stage::Par { .. }, ExecutionStrategy::ShardPerRecord)
+            | (Stage::Par { .. }, ExecutionStrategy::ShardPerFile) => {}
+            (Stage::Main(_), _) => fail!(
+                "{} was compiled for serial execution, but -p was passed",
+                path
+            ),
+            (Stage::Par { .. }, _) => fail!(
+                "{} was compiled for parallel execution; pass the same -p flag used to compile it",
+                path
+            ),
+        }
+        let argv_input = dynamic_argv && program.uses_argv();
+        let argv: Vec<&str> = raw.argv.iter().map(String::as_str).collect();
+        with_io!(analysis_result, argv_input, |inp, oup| {
+            run_interp_with_program(program, &argv[..], inp, oup, num_workers)
+        });
+        return;
+    }
+    let ctx = get_context(program_string.as_str(), &a, get_prelude(&a, &raw));
+    let analysis_result = ctx.analyze_sep_assignments();
+    let argv_input = ctx.reads_argv;
+    let profile = if matches.is_present("profile") {
+        Some(matches.value_of("profile").unwrap_or("awkprof.out"))
+    } else {
+        None
+    };
+    let instrumented = match (profile, &trace, strict) {
+        (Some(_), _, _) => Some("--profile"),
+        (None, Some(_), _) => Some("--trace"),
+        (None, None, true) => Some("--strict"),
+        (None, None, false) => None,
+    };
+    let backend = match (matches.value_of("backend"), instrumented) {
+        (None, Some(_)) | (Some("interp"), Some(_)) => "interp",
+        (Some(b), Some(flag)) => fail!(
+            "{} is only supported by the interp backend, not {}",
+            flag,
+            b
+        ),
+        (Some(b), None) => b,
+        (None, None) => choose_backend(&ctx, &input_files[..]),
+    };
+    if let Some(kernel) = &ctx.kernel {
+        let (field_sep, record_sep) = match analysis_result {
+            cfg::SepAssign::Potential {
+                field_sep,
+                record_sep,
+            } => (field_sep.unwrap_or(b" "), record_sep.unwrap_or(b"\n")),
+            cfg::SepAssign::Unsure => (&b" "[..], &b"\n"[..]),
+        };
+        with_io!(analysis_result, argv_input, |inp, oup| {
+            if let Err(e) = kernel.run(inp, oup, field_sep, record_sep) {
+                fail!("fatal error during execution: {}", e);
+            }
+        });
+        return;
+    }
+    let tiered = matches.is_present("tiered")
+        && matches!(exec_strategy, ExecutionStrategy::Serial)
+        && ctx.has_main_loop
+        && backend != "interp";
+    if tiered {
+        let mut raw = raw.clone();
+        raw.scalars.tiered = true;
+        let jit_cache = matches
+            .value_of("jit-cache")
+            .map(|dir| JitCache::new(dir, raw.cache_key(program_string.as_str(), &config)));
+        with_io!(analysis_result, argv_input, |inp, oup| run_tiered(
+            program_string.as_str(),
+            &raw,
+            backend,
+            config,
+            jit_cache,
+            inp,
+            oup,
+        ));
+        exit_with_status();
+        return;
+    }
+    match backend {
+        "llvm" => {
             cfg_if::cfg_if! {
                 if #[cfg(feature = "llvm_backend")] {
-                    with_io!(|inp, oup| run_llvm_with_context(
+                    with_io!(analysis_result, argv_input, |inp, oup| run_llvm_with_context(
                             ctx,
                             inp,
                             oup,
-                            codegen::Config {
-                                opt_level: opt_level as usize,
-                                num_workers,
-                            },
+                            config,
                     ));
                 } else {
                     fail!("backend specified as LLVM, but compiled without LLVM support");
                 }
             }
         }
-        Some("interp") => {
-            with_io!(|inp, oup| run_interp_with_context(ctx, inp, oup, num_workers))
-        }
-        None | Some("cranelift") => {
-            with_io!(|inp, oup| run_cranelift_with_context(
-                ctx,
-                inp,
-                oup,
-                codegen::Config {
-                    opt_level: opt_level as usize,
+        "interp" => {
+            with_io!(analysis_result, argv_input, |inp, oup| {
+                run_interp_with_context(
+                    ctx,
+                    inp,
+                    oup,
                     num_workers,
-                },
-            ));
+                    profile,
+                    trace,
+                    strict,
+                    strict_warn,
+                    int_overflow,
+                )
+            })
         }
-        Some(b) => {
+        "cranelift" => {
+            with_io!(analysis_result, argv_input, |inp, oup| {
+                run_cranelift_with_context(ctx, inp, oup, config, jit_cache.as_ref())
+            });
+        }
+        b => {
             fail!("invalid backend: {:?}", b);
         }
     }
+    exit_with_status();
+}
+
+/// `exit n` only records its status, so that END still runs; exit with it once the program is done.
+/// Likewise, die of a second signal that stopped the program once its `atexit` functions have run.
+fn exit_with_status() {
+    runtime::signal::reraise();
+    let status = runtime::exit::status();
+    if status != 0 {
+        runtime::progress::finish();
+        runtime::stats::report();
+        std::process::exit(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: codegen::Config = codegen::Config {
+        opt_level: 3,
+        num_workers: 1,
+        jit_opt: codegen::JitOpt::Speed,
+        perf_map: false,
+        int_overflow: IntOverflow::Promote,
+    };
+
+    #[test]
+    fn cache_key_covers_options() {
+        // Every field is named here, so adding one to `Config` means adding a case below.
+        let codegen::Config {
+            opt_level,
+            num_workers,
+            jit_opt: _,
+            perf_map,
+            int_overflow: _,
+        } = CONFIG;
+        let configs = [
+            codegen::Config {
+                opt_level: opt_level - 1,
+                ..CONFIG
+            },
+            codegen::Config {
+                num_workers: num_workers + 1,
+                ..CONFIG
+            },
+            codegen::Config {
+                jit_opt: codegen::JitOpt::None,
+                ..CONFIG
+            },
+            codegen::Config {
+                perf_map: !perf_map,
+                ..CONFIG
+            },
+            codegen::Config {
+                int_overflow: IntOverflow::Wrap,
+                ..CONFIG
+            },
+        ];
+        let prog = "{ print $1 }";
+        let raw = RawPrelude::default();
+        let key = raw.cache_key(prog, &CONFIG);
+        for config in configs.iter() {
+            assert_ne!(key, raw.cache_key(prog, config), "{:?}", config);
+        }
+        let mut tiered = raw.clone();
+        tiered.scalars.tiered = true;
+        assert_ne!(key, tiered.cache_key(prog, &CONFIG));
+        let mut strict = raw.clone();
+        strict.scalars.strict = true;
+        assert_ne!(key, strict.cache_key(prog, &CONFIG));
+    }
+
+    fn backend_for(prog: &str, input_files: &[&std::path::Path]) -> &'static str {
+        let a = Arena::default();
+        let ctx = get_context(prog, &a, get_prelude(&a, &RawPrelude::default()));
+        let input_files: Vec<String> = input_files
+            .iter()
+            .map(|p| p.to_str().unwrap().into())
+            .collect();
+        choose_backend(&ctx, &input_files[..])
+    }
+
+    #[test]
+    fn choose_backend_by_program() {
+        // Programs that only run once, without loops, are interpreted.
+        assert_eq!(backend_for("BEGIN { print 1 + 2 }", &[]), "interp");
+        // Loops, or functions that may recurse, are worth compiling.
+        let looping = "BEGIN { for (i = 0; i < 10; i++) s += i; print s }";
+        assert_eq!(backend_for(looping, &[]), default_jit());
+        let recursive =
+            "function f(n) { return n < 2 ? n : f(n-1) + f(n-2) } BEGIN { print f(10) }";
+        assert_eq!(backend_for(recursive, &[]), default_jit());
+        // The size of standard input is not known up front.
+        assert_eq!(backend_for("{ print $1 }", &[]), default_jit());
+    }
+
+    #[test]
+    fn choose_backend_by_input() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let small = tmpdir.path().join("small");
+        std::fs::write(&small, "1 2\n3 4\n").unwrap();
+        let large = tmpdir.path().join("large");
+        std::fs::File::create(&large)
+            .unwrap()
+            .set_len(1 << 30)
+            .unwrap();
+        let missing = tmpdir.path().join("missing");
+
+        assert_eq!(backend_for("{ print $1 }", &[&small]), "interp");
+        assert_eq!(backend_for("{ print $1 }", &[&small, &small]), "interp");
+        let looping = "{ for (i = 1; i <= NF; i++) s += $i; } END { print s }";
+        assert_eq!(backend_for(looping, &[&small]), default_jit());
+        assert_eq!(backend_for("{ print $1 }", &[&large]), default_jit());
+        assert_eq!(
+            backend_for("{ print $1 }", &[&small, &large]),
+            default_jit()
+        );
+        // Files we cannot find the size of are assumed to be large.
+        assert_eq!(backend_for("{ print $1 }", &[&missing]), default_jit());
+
+        // Large programs over large inputs use LLVM, if it is available.
+        let mut big_prog = String::from("{\n");
+        for i in 0..2000 {
+            big_prog.push_str(&format!("s{} += $1\n", i % 10));
+        }
+        big_prog.push_str("}");
+        let a = Arena::default();
+        let ctx = get_context(&big_prog, &a, get_prelude(&a, &RawPrelude::default()));
+        assert!(ctx.num_stmts() >= 2000, "{}", ctx.num_stmts());
+        assert_eq!(backend_for(&big_prog, &[&small]), "interp");
+        let expected = if cfg!(feature = "llvm_backend") {
+            "llvm"
+        } else {
+            default_jit()
+        };
+        assert_eq!(backend_for(&big_prog, &[&large]), expected);
+    }
 }
@@ -0,0 +1,221 @@
+//! This module finds string moves in a (typed) frawk program that can transfer ownership of their
+//! source rather than copying it.
+//!
+//! Strings are reference-counted. Copying a string from one register to another increments its
+//! reference count, and the copy still held by the source register is released when that register
+//! is next assigned to, or when the function returns. Many copies read their source for the last
+//! time, however: SSA construction introduces copies for every assignment of one variable to
+//! another, and for every phi node, including those at the end of each iteration of a loop. For
+//! those, we can instead _take_ the source string, leaving an empty string behind, which saves
+//! both the increment and the matching decrement.
+//!
+//! We compute which string registers are live at the end of each basic block with a standard
+//! backwards dataflow analysis. To keep the analysis simple (and safe), only moves, calls, phi
+//! nodes and a handful of common string operations (see `str_dst`) are treated as assigning to a
+//! register; any other instruction is assumed to read every string register it mentions.
+//!
+//! A copy can take its source if the source register is not live after the copy, if it is a local
+//! register of the current function, and if it is not one of the function's parameters: the
+//! native backends do not own the strings passed to a function.
+//!
+//! Moves within a basic block are rewritten to `TakeStr` instructions. Copies for phi nodes are
+//! only generated when the typed CFG is lowered, so for those we record the predecessor block and
+//! phi node in `Frame::owned_phi_srcs` instead.
+//!
+//! Only strings are handled. An empty string needs no allocation, whereas leaving an empty map
+//! behind would replace a reference count update with an allocation.
+use crate::bytecode::{Accum, Instr as LL};
+use crate::common::{Either, NodeIx, NumTy};
+use crate::compile::{self, Frame, HighLevel, Ty};
+
+use hashbrown::HashSet;
+use smallvec::SmallVec;
+
+type Live = HashSet<NumTy>;
+
+/// Rewrite the string moves in `frame` that are the last use of their source register.
+/// `is_local` reports whether a string register is local to a function.
+pub(crate) fn elide_copies(frame: &mut Frame, is_local: impl Fn(NumTy) -> bool) {
+    let arg_regs = frame.arg_regs.clone();
+    let owned = |reg: NumTy| is_local(reg) && !arg_regs.contains(&reg);
+    let live_in = live_in(frame);
+    let nodes: Vec<NodeIx> = frame.cfg.node_indices().collect();
+    for n in nodes {
+        // The registers that are live once all copies for phi nodes in successor blocks are done.
+        let mut live = Live::default();
+        for succ in frame.cfg.neighbors(n) {
+            live.extend(live_in[succ.index()].iter().cloned());
+        }
+        let copies = phi_copies(frame, n);
+        for (dst, src) in copies.iter().cloned() {
+            if owned(src)
+                && src != dst
+                && !live.contains(&src)
+                && copies.iter().filter(|(_, s)| *s == src).count() == 1
+            {
+                frame.owned_phi_srcs.insert((n, dst));
+            }
+        }
+        live.extend(copies.iter().map(|(_, src)| *src));
+        let bb = frame.cfg.node_weight_mut(n).unwrap();
+        for inst in bb.insts.iter_mut().rev() {
+            if let Either::Left(LL::Mov(Ty::Str, dst, src)) = inst {
+                let (dst, src) = (*dst, *src);
+                if owned(src) && src != dst && !live.contains(&src) {
+                    *inst = Either::Left(LL::TakeStr(dst.into(), src.into()));
+                }
+            }
+            step(inst, &mut live);
+        }
+    }
+}
+
+// The string registers copied into phi nodes at the end of `n`, as (destination, source) pairs.
+fn phi_copies(frame: &Frame, n: NodeIx) -> Vec<(NumTy, NumTy)> {
+    let mut res = Vec::new();
+    for succ in frame.cfg.neighbors(n) {
+        for inst in frame.cfg.node_weight(succ).unwrap().insts.iter() {
+            match inst {
+                Either::Right(HighLevel::Phi(dst, ty, preds)) => {
+                    if *ty != Ty::Str {
+                        continue;
+                    }
+                    if let Some((_, src)) = preds.iter().find(|(pred, _)| *pred == n) {
+                        res.push((*dst, *src));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    res
+}
+
+// The string registers live at the start of each basic block, indexed by node.
+fn live_in(frame: &Frame) -> Vec<Live> {
+    let cfg = &frame.cfg;
+    let mut live_in = vec![Live::default(); cfg.node_count()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for n in cfg.node_indices().rev() {
+            let mut live = Live::default();
+            for succ in cfg.neighbors(n) {
+                live.extend(live_in[succ.index()].iter().cloned());
+            }
+            live.extend(phi_copies(frame, n).into_iter().map(|(_, src)| src));
+            for inst in cfg.node_weight(n).unwrap().insts.iter().rev() {
+                step(inst, &mut live);
+            }
+            if live != live_in[n.index()] {
+                live_in[n.index()] = live;
+                changed = true;
+            }
+        }
+    }
+    live_in
+}
+
+// Update `live`, the string registers live after `inst`, to those live before it.
+fn step(inst: &Either<LL, HighLevel>, live: &mut Live) {
+    match inst {
+        Either::Left(LL::Mov(Ty::Str, dst, src)) => {
+            live.remove(dst);
+            live.insert(*src);
+        }
+        Either::Left(LL::TakeStr(dst, src)) => {
+            live.remove(&dst.reflect().0);
+            live.insert(src.reflect().0);
+        }
+        // The sources of a phi node are read at the end of its predecessors.
+        Either::Right(HighLevel::Phi(dst, Ty::Str, _)) => {
+            live.remove(dst);
+        }
+        Either::Right(HighLevel::Phi(..)) => {}
+        Either::Right(HighLevel::Call {
+            dst_reg,
+            dst_ty,
+            args,
+            ..
+        }) => {
+            if *dst_ty == Ty::Str {
+                live.remove(dst_reg);
+            }
+            for (reg, ty) in args.iter() {
+                if *ty == Ty::Str {
+                    live.insert(*reg);
+                }
+            }
+        }
+        _ => {
+            let mut regs = SmallVec::<[NumTy; 4]>::new();
+            compile::accum(inst, |reg, ty| {
+                if ty == Ty::Str {
+                    regs.push(reg)
+                }
+            });
+            if let Some(dst) = str_dst(inst) {
+                // `dst` is only read if it is also passed as one of the operands.
+                if let Some(i) = regs.iter().position(|r| *r == dst) {
+                    regs.remove(i);
+                }
+                live.remove(&dst);
+            }
+            live.extend(regs);
+        }
+    }
+}
+
+// The string register written by `inst`, for common instructions that assign a string to their
+// first operand without reading it first.
+fn str_dst(inst: &Either<LL, HighLevel>) -> Option<NumTy> {
+    use LL::*;
+    match inst {
+        Either::Left(StoreConstStr(dst, _))
+        | Either::Left(IntToStr(dst, _))
+        | Either::Left(FloatToStr(dst, _))
+        | Either::Left(Concat(dst, _, _))
+        | Either::Left(EscapeCSV(dst, _))
+        | Either::Left(EscapeTSV(dst, _))
+        | Either::Left(Substr(dst, _, _, _))
+        | Either::Left(GraphemeSubstr(dst, _, _, _))
+        | Either::Left(Trim(dst, _, _))
+        | Either::Left(LTrim(dst, _, _))
+        | Either::Left(RTrim(dst, _, _))
+        | Either::Left(Squeeze(dst, _))
+        | Either::Left(Color(dst, _, _))
+        | Either::Left(Bold(dst, _))
+        | Either::Left(LPad(dst, _, _, _))
+        | Either::Left(RPad(dst, _, _, _))
+        | Either::Left(ZFill(dst, _, _))
+        | Either::Left(GetColumn(dst, _))
+        | Either::Left(LoadVarStr(dst, _)) => Some(dst.reflect().0),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::harness::string_moves;
+
+    #[test]
+    fn takes_last_uses() {
+        // Every copy of `s`, including the one carried into the next iteration of the main loop,
+        // is the last use of its source.
+        let (movs, takes) = string_moves(r#"{ s = s $1; } END { print s; }"#).unwrap();
+        assert_eq!(movs, 0);
+        assert!(takes > 0);
+    }
+
+    #[test]
+    fn copies_live_sources() {
+        let (movs, _) = string_moves(r#"{ x = $1; y = x; print x, y; }"#).unwrap();
+        assert!(movs > 0);
+        // Parameters and globals are never taken.
+        let (movs, _) = string_moves(
+            r#"function f(a,    b) { b = a; return b a; } { g = $1; h = g; print f(h); } END { print g; }"#,
+        )
+        .unwrap();
+        assert!(movs > 0);
+    }
+}
@@ -1,3 +1,35 @@
-use lalrpop_util::lalrpop_mod;
+use lalrpop_util::{lalrpop_mod, ParseError};
+
+use crate::common::CompileError;
+use crate::lexer::{self, Loc, Tok};
 
 lalrpop_mod!(pub syntax);
+
+/// Convert an error from the generated parser into a `CompileError` pointing at the offending
+/// token.
+pub(crate) fn parse_error(e: ParseError<Loc, Tok, lexer::Error>) -> CompileError {
+    fn expecting(msg: String, expected: Vec<String>) -> String {
+        match expected.len() {
+            0 => msg,
+            1 => format!("{}; expected {}", msg, expected[0]),
+            _ => format!("{}; expected one of {}", msg, expected.join(", ")),
+        }
+    }
+    let (msg, span) = match e {
+        ParseError::InvalidToken { location } => ("invalid token".into(), (location, location)),
+        ParseError::UnrecognizedEOF { location, expected } => (
+            expecting("unexpected end of program".into(), expected),
+            (location, location),
+        ),
+        ParseError::UnrecognizedToken {
+            token: (l, tok, r),
+            expected,
+        } => (
+            expecting(format!("unexpected token `{}`", tok), expected),
+            (l, r),
+        ),
+        ParseError::ExtraToken { token: (l, tok, r) } => (format!("extra token `{}`", tok), (l, r)),
+        ParseError::User { error } => (error.desc.into(), (error.location, error.location)),
+    };
+    CompileError::new(msg).with_span(span)
+}
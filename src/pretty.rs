@@ -0,0 +1,936 @@
+//! A pretty-printer for AWK programs, used to implement `--pretty-print` and the listings written
+//! by `--profile`.
+//!
+//! The printer works from the AST returned by the parser, along with the comments in the program
+//! text (which the parser discards). Statements are printed one per line with four spaces of
+//! indentation, bodies of control-flow statements are always braced, and parentheses are only
+//! added where precedence requires them. Comments are attached to the statement that follows
+//! them, or to the end of the line they trailed in the original program. Comments at the end of a
+//! block are emitted after the block's closing brace.
+use crate::ast::{self, Binop, Expr, FunDec, Pattern, Prog, Stmt};
+use crate::builtins::Function;
+use crate::common::{Either, FileSpec};
+use crate::lexer::{Loc, Span, Tokenizer};
+
+const INDENT: &str = "    ";
+
+// Expression precedence levels, from loosest to tightest binding. These follow the layers of the
+// grammar in parsing/syntax.lalrpop.
+const GETLINE: usize = 0;
+const ASSIGN: usize = 1;
+const TERNARY: usize = 2;
+const OR: usize = 3;
+const AND: usize = 4;
+const IN: usize = 5;
+const MATCH: usize = 6;
+const CMP: usize = 7;
+const ADD: usize = 8;
+const MUL: usize = 9;
+const POW: usize = 10;
+const UNOP: usize = 11;
+const INC: usize = 12;
+const CONCAT: usize = 13;
+const FIELD: usize = 14;
+const TERM: usize = 15;
+
+type E<'a, 'b> = Expr<'a, 'b, &'b str>;
+type S<'a, 'b> = Stmt<'a, 'b, &'b str>;
+
+/// Extra information to print alongside a program, such as execution counts in a profile.
+pub(crate) trait Annotate {
+    /// Text for the margin of the line beginning with the statement or top-level item at `span`.
+    fn margin(&self, span: &Span) -> Option<String>;
+    /// A comment to print above the top-level item covering the program text from offset `start`
+    /// up to offset `end`. `func` is the name of the item if it is a function.
+    fn header(&self, func: Option<&str>, start: usize, end: usize) -> Option<String>;
+    /// A comment to print after the program.
+    fn footer(&self) -> Option<String> {
+        None
+    }
+}
+
+// The width of the margin, when printing with annotations.
+const MARGIN: usize = 10;
+
+/// Format `prog`, which was parsed from `text`.
+pub(crate) fn pretty_print<'a, 'b>(prog: &Prog<'a, 'b, &'b str>, text: &'b str) -> String {
+    print_prog(prog, text, None)
+}
+
+/// Format `prog` with annotations from `ann` in a margin to the left of the program.
+pub(crate) fn pretty_print_annotated<'a, 'b>(
+    prog: &Prog<'a, 'b, &'b str>,
+    text: &'b str,
+    ann: &dyn Annotate,
+) -> String {
+    print_prog(prog, text, Some(ann))
+}
+
+fn print_prog<'a, 'b>(
+    prog: &Prog<'a, 'b, &'b str>,
+    text: &'b str,
+    ann: Option<&dyn Annotate>,
+) -> String {
+    enum Item<'x, 'a, 'b> {
+        Fun(&'x FunDec<'a, 'b, &'b str>),
+        Begin(&'a S<'a, 'b>),
+        Prepare(&'a S<'a, 'b>),
+        End(&'a S<'a, 'b>),
+        Signal(&'a S<'a, 'b>),
+        Pat(
+            &'x Span,
+            &'x Pattern<'a, 'b, &'b str>,
+            Option<&'a S<'a, 'b>>,
+        ),
+    }
+    let mut items = Vec::new();
+    items.extend(prog.decs.iter().map(|f| (Some(f.span.0), Item::Fun(f))));
+    items.extend(prog.begin.map(|s| (first_loc(s), Item::Begin(s))));
+    items.extend(prog.prepare.map(|s| (first_loc(s), Item::Prepare(s))));
+    items.extend(
+        prog.pats
+            .iter()
+            .map(|(span, pat, body)| (Some(span.0), Item::Pat(span, pat, *body))),
+    );
+    items.extend(prog.end.map(|s| (first_loc(s), Item::End(s))));
+    items.extend(prog.signal.map(|s| (first_loc(s), Item::Signal(s))));
+    // Print top-level items in the order they appeared in the program.
+    let mut last = 0;
+    let mut items: Vec<_> = items
+        .into_iter()
+        .map(|(loc, item)| {
+            if let Some(loc) = loc {
+                last = loc.offset;
+            }
+            (last, item)
+        })
+        .collect();
+    items.sort_by_key(|(offset, _)| *offset);
+
+    let ends: Vec<usize> = items
+        .iter()
+        .skip(1)
+        .map(|(offset, _)| *offset)
+        .chain(std::iter::once(text.len()))
+        .collect();
+
+    let mut p = Printer::new(text, ann);
+    for ((start, item), end) in items.into_iter().zip(ends) {
+        let func = match item {
+            Item::Fun(f) => Some(f.name),
+            _ => None,
+        };
+        if let Some(header) = ann.and_then(|ann| ann.header(func, start, end)) {
+            p.header(start, header);
+        }
+        match item {
+            Item::Fun(f) => {
+                p.start(Some(&f.span));
+                p.out.push_str("function ");
+                p.out.push_str(f.name);
+                p.out.push('(');
+                p.out.push_str(f.args.join(", ").as_str());
+                p.out.push_str(") ");
+                p.block(f.body);
+            }
+            Item::Begin(s) => {
+                let (span, body) = located(s);
+                p.start(span);
+                p.out.push_str("BEGIN ");
+                p.block(body);
+            }
+            Item::Prepare(s) => {
+                let (span, body) = located(s);
+                p.start(span);
+                p.out.push_str("PREPARE ");
+                p.block(body);
+            }
+            Item::End(s) => {
+                let (span, body) = located(s);
+                p.start(span);
+                p.out.push_str("END ");
+                p.block(body);
+            }
+            Item::Signal(s) => {
+                let (span, body) = located(s);
+                p.start(span);
+                p.out.push_str("SIGNAL ");
+                p.block(body);
+            }
+            Item::Pat(span, pat, body) => {
+                p.start(Some(span));
+                match pat {
+                    Pattern::Null => {}
+                    Pattern::Bool(e) => p.expr(e, GETLINE),
+                    Pattern::Comma(l, r) => {
+                        p.expr(l, TERM);
+                        p.out.push_str(", ");
+                        p.expr(r, TERM);
+                    }
+                    Pattern::Group(e) => {
+                        p.out.push_str("GROUP ");
+                        p.expr(e, GETLINE);
+                    }
+                }
+                match body {
+                    Some(body) => {
+                        if let Pattern::Null = pat {
+                        } else {
+                            p.out.push(' ');
+                        }
+                        p.block(body)
+                    }
+                    None => p.trailing_comment(span),
+                }
+            }
+        }
+    }
+    p.comments_before(usize::max_value());
+    if let Some(footer) = ann.and_then(|ann| ann.footer()) {
+        if p.out.len() > 0 {
+            p.out.push('\n');
+        }
+        p.line(None);
+        p.out.push_str("# ");
+        p.out.push_str(footer.as_str());
+    }
+    p.out
+}
+
+/// `s` as an AWK string literal.
+pub(crate) fn string_literal(s: &[u8]) -> String {
+    let mut p = Printer::new("", None);
+    p.str_lit(s);
+    p.out
+}
+
+fn located<'a, 'b>(s: &'a S<'a, 'b>) -> (Option<&'a Span>, &'a S<'a, 'b>) {
+    match s {
+        Stmt::Located(span, s) => (Some(span), s),
+        s => (None, s),
+    }
+}
+
+/// The location of the first statement in `s`, if there is one.
+fn first_loc<'a, 'b>(s: &S<'a, 'b>) -> Option<Loc> {
+    match s {
+        Stmt::Located((l, _), _) => Some(*l),
+        Stmt::Block(stmts) => stmts.iter().filter_map(|s| first_loc(s)).next(),
+        _ => None,
+    }
+}
+
+struct Printer<'b> {
+    text: &'b str,
+    out: String,
+    indent: usize,
+    comments: Vec<(Loc, &'b str)>,
+    next_comment: usize,
+    // The last line of the original program that we have printed something from.
+    last_line: Option<usize>,
+    ann: Option<&'b dyn Annotate>,
+}
+
+impl<'b> Printer<'b> {
+    fn new(text: &'b str, ann: Option<&'b dyn Annotate>) -> Printer<'b> {
+        Printer {
+            text,
+            out: String::new(),
+            indent: 0,
+            comments: Tokenizer::comments(text),
+            next_comment: 0,
+            last_line: None,
+            ann,
+        }
+    }
+
+    fn newline(&mut self) {
+        self.line(None)
+    }
+
+    /// Begin a new line of output, with the margin for `span` if we are printing annotations.
+    fn line(&mut self, span: Option<&Span>) {
+        while self.out.ends_with(' ') {
+            self.out.pop();
+        }
+        if self.out.len() > 0 {
+            self.out.push('\n');
+        }
+        if let Some(ann) = self.ann {
+            let margin = span.and_then(|span| ann.margin(span)).unwrap_or_default();
+            self.out
+                .push_str(format!("{:>w$}  ", margin, w = MARGIN - 2).as_str());
+        }
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    /// Print `header` as a comment above the top-level item starting at `offset`.
+    fn header(&mut self, offset: usize, header: String) {
+        self.comments_before(offset);
+        let line = match self.text.get(..offset) {
+            Some(prefix) => prefix.matches('\n').count(),
+            None => return,
+        };
+        self.blank_line(line);
+        self.line(None);
+        self.out.push_str("# ");
+        self.out.push_str(header.as_str());
+        self.last_line = Some(line);
+    }
+
+    /// Start a new line, first emitting any blank lines and comments from the original program
+    /// that came before `span`.
+    fn start(&mut self, span: Option<&Span>) {
+        if let Some((l, _)) = span {
+            self.comments_before(l.offset);
+            self.blank_line(l.line);
+            self.last_line = Some(l.line);
+        }
+        self.line(span);
+    }
+
+    /// Emit a blank line if there was at least one between the last thing we printed and `line`.
+    fn blank_line(&mut self, line: usize) {
+        let last = match self.last_line {
+            Some(last) if last + 1 < line => last,
+            _ => return,
+        };
+        let blank = self
+            .text
+            .lines()
+            .skip(last + 1)
+            .take(line - last - 1)
+            .any(|l| l.trim().len() == 0);
+        if blank && self.out.len() > 0 {
+            self.out.push('\n');
+        }
+    }
+
+    fn comments_before(&mut self, offset: usize) {
+        while let Some((loc, comment)) = self.comments.get(self.next_comment).cloned() {
+            if loc.offset >= offset {
+                break;
+            }
+            self.next_comment += 1;
+            self.blank_line(loc.line);
+            self.last_line = Some(loc.line);
+            self.newline();
+            self.out.push_str(comment.trim_end());
+        }
+    }
+
+    /// Append a comment that appeared on the last line of `span` to the current line.
+    fn trailing_comment(&mut self, span: &Span) {
+        let line = span.1.line;
+        if let Some((loc, comment)) = self.comments.get(self.next_comment).cloned() {
+            if loc.line == line {
+                self.next_comment += 1;
+                self.out.push(' ');
+                self.out.push_str(comment.trim_end());
+            }
+        }
+        self.last_line = Some(line);
+    }
+
+    /// Print `s` as a braced block.
+    fn block<'a>(&mut self, s: &S<'a, 'b>) {
+        self.out.push('{');
+        self.indent += 1;
+        self.stmt(s);
+        self.indent -= 1;
+        self.newline();
+        self.out.push('}');
+    }
+
+    /// Print `s` on a new line. Blocks are printed as their contents, one statement per line.
+    fn stmt<'a>(&mut self, s: &S<'a, 'b>) {
+        match s {
+            Stmt::Located(span, _) => {
+                let (inner_span, inner) = innermost(s);
+                if let Stmt::Block(_) = inner {
+                    self.comments_before(span.0.offset);
+                    self.stmt(inner);
+                    return;
+                }
+                self.start(Some(span));
+                self.stmt_inner(inner);
+                if !is_compound(inner) {
+                    self.trailing_comment(inner_span);
+                }
+            }
+            Stmt::Block(stmts) => {
+                for s in stmts.iter() {
+                    self.stmt(s)
+                }
+            }
+            s => {
+                self.start(None);
+                self.stmt_inner(s)
+            }
+        }
+    }
+
+    /// Print a single statement, starting on the current line.
+    fn stmt_inner<'a>(&mut self, s: &S<'a, 'b>) {
+        use Stmt::*;
+        match s {
+            Located(_, s) => self.stmt_inner(s),
+            Block(_) => self.block(s),
+            // These are only introduced when desugaring the program.
+            StartCond(_) | EndCond(_) | LastCond(_) => {}
+            Phase(s) => self.stmt_inner(s),
+            Expr(crate::ast::Expr::Call(Either::Right(Function::Delete), args))
+                if args.len() == 2 =>
+            {
+                self.out.push_str("delete ");
+                self.expr(args[0], TERM);
+                self.index(args[1]);
+            }
+            Expr(e) => self.expr(e, GETLINE),
+            Print(args, out) => {
+                self.out.push_str("print");
+                for (i, a) in args.iter().enumerate() {
+                    self.out.push_str(if i == 0 { " " } else { ", " });
+                    self.expr(a, ADD);
+                }
+                self.redirect(out);
+            }
+            Printf(fmt, args, out) => {
+                self.out.push_str("printf ");
+                self.expr(fmt, ADD);
+                for a in args.iter() {
+                    self.out.push_str(", ");
+                    self.expr(a, ADD);
+                }
+                self.redirect(out);
+            }
+            If(cond, t, f) => {
+                self.out.push_str("if (");
+                self.expr(cond, GETLINE);
+                self.out.push_str(") ");
+                self.block(t);
+                if let Some(f) = f {
+                    self.out.push_str(" else ");
+                    let (span, inner) = match f {
+                        Located(span, inner) => (Some(span), *inner),
+                        f => (None, *f),
+                    };
+                    if let If(..) = inner {
+                        if let Some((l, _)) = span {
+                            self.comments_before(l.offset);
+                        }
+                        self.stmt_inner(inner);
+                    } else {
+                        self.block(inner);
+                    }
+                }
+            }
+            For(init, cond, update, body) => {
+                self.out.push_str("for (");
+                if let Some(init) = init {
+                    self.simple_stmt(init, true);
+                }
+                self.out.push(';');
+                if let Some(cond) = cond {
+                    self.out.push(' ');
+                    self.expr(cond, GETLINE);
+                }
+                self.out.push(';');
+                if let Some(update) = update {
+                    self.out.push(' ');
+                    self.simple_stmt(update, false);
+                }
+                self.out.push_str(") ");
+                self.block(body);
+            }
+            DoWhile(cond, body) => {
+                self.out.push_str("do ");
+                self.block(body);
+                self.out.push_str(" while (");
+                self.expr(cond, GETLINE);
+                self.out.push(')');
+            }
+            While(_, cond, body) => {
+                self.out.push_str("while (");
+                self.expr(cond, GETLINE);
+                self.out.push_str(") ");
+                self.block(body);
+            }
+            ForEach(v, arr, body) => {
+                self.out.push_str("for (");
+                self.out.push_str(v);
+                self.out.push_str(" in ");
+                self.expr(arr, GETLINE);
+                self.out.push_str(") ");
+                self.block(body);
+            }
+            Break => self.out.push_str("break"),
+            Continue => self.out.push_str("continue"),
+            Next => self.out.push_str("next"),
+            NextFile => self.out.push_str("nextfile"),
+            Return(e) => {
+                self.out.push_str("return");
+                if let Some(e) = e {
+                    self.out.push(' ');
+                    self.expr(e, GETLINE);
+                }
+            }
+            Exit(e) => {
+                self.out.push_str("exit");
+                if let Some(e) = e {
+                    self.out.push(' ');
+                    self.expr(e, GETLINE);
+                }
+            }
+        }
+    }
+
+    /// Print the initializer or update clause of a `for` loop.
+    fn simple_stmt<'a>(&mut self, s: &S<'a, 'b>, no_in: bool) {
+        match s {
+            Stmt::Located(_, s) => self.simple_stmt(s, no_in),
+            Stmt::Expr(e) if no_in && contains_in(e) => {
+                // `in` is not allowed at the top level of a for loop's initializer.
+                self.out.push('(');
+                self.expr(e, GETLINE);
+                self.out.push(')');
+            }
+            Stmt::Expr(e) => self.expr(e, GETLINE),
+            s => self.stmt_inner(s),
+        }
+    }
+
+    fn redirect<'a>(&mut self, out: &Option<(&'a E<'a, 'b>, FileSpec)>) {
+        if let Some((e, spec)) = out {
+            self.out.push_str(match spec {
+                FileSpec::Trunc => " > ",
+                FileSpec::Append => " >> ",
+                FileSpec::Cmd => " | ",
+            });
+            self.expr(e, CONCAT);
+        }
+    }
+
+    fn index<'a>(&mut self, ix: &E<'a, 'b>) {
+        self.out.push('[');
+        self.subscript(ix);
+        self.out.push(']');
+    }
+
+    /// Print an array subscript, turning `a SUBSEP b` back into `a, b`.
+    fn subscript<'a>(&mut self, ix: &E<'a, 'b>) {
+        match split_subsep(ix) {
+            Some((l, r)) => {
+                self.subscript(l);
+                self.out.push_str(", ");
+                self.expr(r, MATCH);
+            }
+            None => self.expr(ix, GETLINE),
+        }
+    }
+
+    fn expr<'a>(&mut self, e: &E<'a, 'b>, min_prec: usize) {
+        let paren = prec(e) < min_prec;
+        if paren {
+            self.out.push('(');
+        }
+        self.expr_inner(e);
+        if paren {
+            self.out.push(')');
+        }
+    }
+
+    /// Print `e` such that a `/` following it will be lexed as division rather than the start of
+    /// a regular expression.
+    fn before_slash<'a>(&mut self, e: &E<'a, 'b>, min_prec: usize) {
+        let start = self.out.len();
+        self.expr(e, min_prec);
+        let ok = self.out[start..].chars().last().map_or(false, |c| {
+            c.is_alphanumeric() || c == '_' || c == '"' || c == ')' || c == '/' || c == '.'
+        });
+        if !ok {
+            self.out.insert(start, '(');
+            self.out.push(')');
+        }
+    }
+
+    fn expr_inner<'a>(&mut self, e: &E<'a, 'b>) {
+        use Expr::*;
+        match e {
+            ILit(n) => self.out.push_str(n.to_string().as_str()),
+            FLit(f) => self.out.push_str(format!("{:?}", f).as_str()),
+            StrLit(s) => self.str_lit(s),
+            PatLit(p) => {
+                self.out.push('/');
+                for c in String::from_utf8_lossy(p).chars() {
+                    if c == '/' {
+                        self.out.push('\\');
+                    }
+                    self.out.push(c);
+                }
+                self.out.push('/');
+            }
+            Var(v) => self.out.push_str(v),
+            Unop(ast::Unop::Not, Binop(ast::Binop::EQ, l, r)) => {
+                self.expr(l, ADD);
+                self.out.push_str(" != ");
+                self.expr(r, CMP);
+            }
+            Unop(ast::Unop::Not, Binop(ast::Binop::IsMatch, l, r)) => {
+                self.expr(l, MATCH);
+                self.out.push_str(" !~ ");
+                self.expr(r, CMP);
+            }
+            Unop(ast::Unop::Column, e) => {
+                self.out.push('$');
+                self.expr(e, TERM);
+            }
+            Unop(op, e) => {
+                self.out.push_str(match op {
+                    ast::Unop::Not => "!",
+                    ast::Unop::Neg => "-",
+                    ast::Unop::Pos => "+",
+                    ast::Unop::Column => unreachable!(),
+                });
+                let start = self.out.len();
+                self.expr(e, INC);
+                // Avoid printing "--" or "++".
+                if let Some('-') | Some('+') = self.out[start..].chars().next() {
+                    self.out.insert(start, ' ');
+                }
+            }
+            Binop(op, l, r) => {
+                use ast::Binop::*;
+                let (s, lp, rp) = match op {
+                    Plus => (" + ", ADD, MUL),
+                    Minus => (" - ", ADD, MUL),
+                    Mult => (" * ", MUL, POW),
+                    Div => (" / ", MUL, POW),
+                    Mod => (" % ", MUL, POW),
+                    Concat => (" ", CONCAT, FIELD),
+                    IsMatch => (" ~ ", MATCH, CMP),
+                    Pow => ("^", UNOP, POW),
+                    LT => (" < ", ADD, CMP),
+                    GT => (" > ", ADD, CMP),
+                    LTE => (" <= ", ADD, CMP),
+                    GTE => (" >= ", ADD, CMP),
+                    EQ => (" == ", ADD, CMP),
+                };
+                if let Div = op {
+                    self.before_slash(l, lp);
+                } else {
+                    self.expr(l, lp);
+                }
+                self.out.push_str(s);
+                self.expr(r, rp);
+            }
+            Call(Either::Right(Function::Contains), args) if args.len() == 2 => {
+                match split_subsep(args[1]) {
+                    Some(_) => {
+                        self.out.push('(');
+                        self.subscript(args[1]);
+                        self.out.push(')');
+                    }
+                    None => self.expr(args[1], MATCH),
+                }
+                self.out.push_str(" in ");
+                self.expr(args[0], MATCH);
+            }
+            Call(f, args) => {
+                match f {
+                    Either::Left(name) => self.out.push_str(name),
+                    Either::Right(f) => self.out.push_str(f.to_string().as_str()),
+                }
+                self.out.push('(');
+                for (i, a) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expr(a, GETLINE);
+                }
+                self.out.push(')');
+            }
+            Index(arr, ix) => {
+                self.expr(arr, TERM);
+                self.index(ix);
+            }
+            Assign(l, r) => {
+                self.expr(l, IN);
+                self.out.push_str(" = ");
+                self.expr(r, ASSIGN);
+            }
+            AssignOp(l, op, r) => {
+                use ast::Binop::*;
+                if let Div = op {
+                    self.before_slash(l, IN);
+                } else {
+                    self.expr(l, IN);
+                }
+                self.out.push_str(match op {
+                    Plus => " += ",
+                    Minus => " -= ",
+                    Mult => " *= ",
+                    Div => " /= ",
+                    Mod => " %= ",
+                    Pow => " ^= ",
+                    _ => " = ",
+                });
+                self.expr(r, ASSIGN);
+            }
+            And(l, r) => {
+                self.expr(l, IN);
+                self.out.push_str(" && ");
+                self.expr(r, AND);
+            }
+            Or(l, r) => {
+                self.expr(l, AND);
+                self.out.push_str(" || ");
+                self.expr(r, OR);
+            }
+            ITE(c, t, f) => {
+                self.expr(c, OR);
+                self.out.push_str(" ? ");
+                self.expr(t, TERNARY);
+                self.out.push_str(" : ");
+                self.expr(f, TERNARY);
+            }
+            Inc { is_inc, is_post, x } => {
+                let op = if *is_inc { "++" } else { "--" };
+                if !is_post {
+                    self.out.push_str(op);
+                }
+                self.expr(x, CONCAT);
+                if *is_post {
+                    self.out.push_str(op);
+                }
+            }
+            Getline {
+                into,
+                from,
+                is_file,
+            } => {
+                if let (false, Some(from)) = (is_file, from) {
+                    self.expr(from, FIELD);
+                    self.out.push_str(" | ");
+                }
+                self.out.push_str("getline");
+                if let Some(into) = into {
+                    self.out.push(' ');
+                    self.expr(into, TERM);
+                }
+                if let (true, Some(from)) = (is_file, from) {
+                    self.out.push_str(" < ");
+                    self.expr(from, TERM);
+                }
+            }
+            ReadStdin => self.out.push_str("getline"),
+            Cond(_) => {}
+        }
+    }
+
+    fn str_lit(&mut self, s: &[u8]) {
+        self.out.push('"');
+        let text = match std::str::from_utf8(s) {
+            Ok(text) => text,
+            Err(_) => {
+                for b in s.iter() {
+                    match *b {
+                        b' '..=b'~' => self.escaped_char(*b as char),
+                        b => self.out.push_str(format!("\\{:03o}", b).as_str()),
+                    }
+                }
+                self.out.push('"');
+                return;
+            }
+        };
+        for c in text.chars() {
+            self.escaped_char(c)
+        }
+        self.out.push('"');
+    }
+
+    fn escaped_char(&mut self, c: char) {
+        match c {
+            '"' => self.out.push_str("\\\""),
+            '\\' => self.out.push_str("\\\\"),
+            '\n' => self.out.push_str("\\n"),
+            '\t' => self.out.push_str("\\t"),
+            '\r' => self.out.push_str("\\r"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => {
+                self.out.push_str(format!("\\{:03o}", c as u32).as_str())
+            }
+            c => self.out.push(c),
+        }
+    }
+}
+
+/// Strip the `Located` wrappers from `s`, returning the innermost span along with the statement
+/// itself. Statements can have more than one wrapper because several grammar rules record spans.
+fn innermost<'x, 'a, 'b>(s: &'x S<'a, 'b>) -> (&'x Span, &'x S<'a, 'b>) {
+    let mut res = match s {
+        Stmt::Located(span, inner) => (span, *inner),
+        _ => unreachable!(),
+    };
+    while let Stmt::Located(span, inner) = res.1 {
+        res = (span, inner);
+    }
+    res
+}
+
+fn is_compound<'a, 'b>(s: &S<'a, 'b>) -> bool {
+    use Stmt::*;
+    match s {
+        Located(_, s) => is_compound(s),
+        Block(_) | If(..) | For(..) | DoWhile(..) | While(..) | ForEach(..) => true,
+        _ => false,
+    }
+}
+
+/// The parser desugars multidimensional subscripts `a[x, y]` into `a[x SUBSEP y]`. Recognize that
+/// form, returning `(x, y)`.
+fn split_subsep<'a, 'b>(e: &'a E<'a, 'b>) -> Option<(&'a E<'a, 'b>, &'a E<'a, 'b>)> {
+    match e {
+        Expr::Binop(Binop::Concat, l, r) => match l {
+            Expr::Binop(Binop::Concat, x, Expr::Var("SUBSEP")) => Some((x, r)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `e` has an `in` expression that is not enclosed in parentheses or brackets.
+fn contains_in<'a, 'b>(e: &E<'a, 'b>) -> bool {
+    use Expr::*;
+    match e {
+        Call(Either::Right(Function::Contains), _) => true,
+        Binop(_, l, r) | Assign(l, r) | AssignOp(l, _, r) | And(l, r) | Or(l, r) => {
+            (prec(l) >= prec(e) && contains_in(l)) || (prec(r) >= prec(e) && contains_in(r))
+        }
+        Unop(_, x) | Inc { x, .. } => prec(x) >= prec(e) && contains_in(x),
+        ITE(c, t, f) => [c, t, f]
+            .iter()
+            .any(|x| prec(x) >= TERNARY && contains_in(x)),
+        _ => false,
+    }
+}
+
+/// The precedence level of the grammar rule that produces `e`.
+fn prec<'a, 'b>(e: &E<'a, 'b>) -> usize {
+    use Expr::*;
+    match e {
+        ILit(n) if *n < 0 => UNOP,
+        FLit(f) if *f < 0.0 => UNOP,
+        ILit(_) | FLit(_) | StrLit(_) | PatLit(_) | Var(_) | Index(..) | Cond(_) => TERM,
+        Call(Either::Right(Function::Contains), _) => IN,
+        Call(..) => TERM,
+        Unop(ast::Unop::Column, _) => FIELD,
+        Unop(ast::Unop::Not, Binop(ast::Binop::EQ, _, _)) => CMP,
+        Unop(ast::Unop::Not, Binop(ast::Binop::IsMatch, _, _)) => MATCH,
+        Unop(..) => UNOP,
+        Binop(op, _, _) => {
+            use ast::Binop::*;
+            match op {
+                Plus | Minus => ADD,
+                Mult | Div | Mod => MUL,
+                Concat => CONCAT,
+                IsMatch => MATCH,
+                Pow => POW,
+                LT | GT | LTE | GTE | EQ => CMP,
+            }
+        }
+        Assign(..) | AssignOp(..) => ASSIGN,
+        And(..) => AND,
+        Or(..) => OR,
+        ITE(..) => TERNARY,
+        Inc { .. } => INC,
+        Getline { .. } | ReadStdin => GETLINE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena::Arena;
+    use crate::common::Stage;
+    use crate::parsing::syntax::ProgParser;
+
+    fn format(text: &str) -> String {
+        let a = Arena::default();
+        let text = a.alloc_str(text);
+        let mut buf = Vec::new();
+        let mut prog = Prog::from_stage(Stage::Main(()));
+        ProgParser::new()
+            .parse(&a, &mut buf, &mut prog, Tokenizer::new(text))
+            .expect("parse failure");
+        pretty_print(&prog, text)
+    }
+
+    #[test]
+    fn formats_and_keeps_comments() {
+        let prog = r#"# count words
+BEGIN{FS=","}   # comma-separated
+function inc(m,k){m[k]++;return m[k]}
+
+$1!="skip"&&!($2~/a\/b/){ for(i=1;i<=NF;i++) n+=inc(seen,$i)
+  if (x) y=1; else if (z) y=2; else { y = 3 }
+  # totals
+  while ((getline line < "f") > 0) print line, (a > b) > "out"
+}
+END { for (k in seen) if ((k, 1) in m) print k, -(-n); }"#;
+        let expected = r#"# count words
+BEGIN {
+    FS = "," # comma-separated
+}
+function inc(m, k) {
+    m[k]++
+    return m[k]
+}
+
+$1 != "skip" && $2 !~ /a\/b/ {
+    for (i = 1; i <= NF; i++) {
+        n += inc(seen, $i)
+    }
+    if (x) {
+        y = 1
+    } else if (z) {
+        y = 2
+    } else {
+        y = 3
+    }
+    # totals
+    while ((getline line < "f") > 0) {
+        print line, (a > b) > "out"
+    }
+}
+END {
+    for (k in seen) {
+        if ((k, 1) in m) {
+            print k, -(-n)
+        }
+    }
+}
+"#;
+        let formatted = format(prog);
+        assert_eq!(formatted, expected.trim_end());
+        // Formatting should be idempotent.
+        assert_eq!(format(formatted.as_str()), formatted);
+    }
+
+    #[test]
+    fn preserves_semantics() {
+        for prog in &[
+            r#"{ x = (a[1]) / 2; y = -x ^ 2; z = (-x) ^ 2; w = a b c; v = (a b) + 1; print "\t\"" }"#,
+            r#"{ x = 1 - (2 - 3); y = 2 ^ 3 ^ 4; z = (2 ^ 3) ^ 4; print x (-1), $(i + 1), $i++ }"#,
+            r#"{ while (("cmd" | getline x) > 0) n++; printf "%s\n", x > "/dev/stderr" }"#,
+        ] {
+            let once = format(prog);
+            assert_eq!(format(once.as_str()), once, "prog={}", prog);
+        }
+        assert_eq!(
+            format("{ x = (a[1]) / 2; y = -x ^ 2; z = (-x) ^ 2; w = 1 - (2 - 3) }"),
+            "{\n    x = (a[1]) / 2\n    y = -x^2\n    z = -x^2\n    w = 1 - (2 - 3)\n}"
+        );
+    }
+}
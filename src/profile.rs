@@ -0,0 +1,233 @@
+//! Execution profiles for `--profile`.
+//!
+//! The bytecode interpreter calls into a `Profiler` before executing each instruction. Using the
+//! debug information attached to the program, the profiler maps instructions back to the
+//! statements they were generated from, counting each time execution reaches the start of a statement and charging
+//! the time spent executing it. Calls to user-defined functions are counted and timed separately,
+//! including the time spent in anything they call.
+//!
+//! The resulting `Profile` annotates a listing of the program produced by the pretty-printer, in
+//! the style of gawk's `--profile` output.
+use hashbrown::{HashMap, HashSet};
+
+use crate::bytecode::DebugInfo;
+use crate::lexer::Span;
+use crate::pretty::Annotate;
+use crate::runtime::RegexStats;
+
+use std::time::{Duration, Instant};
+
+const NONE: u32 = u32::max_value();
+
+#[derive(Default)]
+struct Stmt {
+    count: u64,
+    time: Duration,
+}
+
+#[derive(Default)]
+struct Func {
+    calls: u64,
+    time: Duration,
+}
+
+pub(crate) struct Profiler {
+    // For each function, for each instruction, the index in `stmts` of the statement it was
+    // generated from, or NONE.
+    regions: Vec<Vec<u32>>,
+    // For each function, whether each instruction is the first one executed for a statement.
+    starts: Vec<Vec<bool>>,
+    stmts: Vec<Stmt>,
+    // The offset into the program text of each statement.
+    offsets: Vec<usize>,
+    funcs: Vec<Func>,
+    names: Vec<String>,
+    // The statement currently executing, and when we started executing it.
+    cur: u32,
+    last: Instant,
+    // The statement making each active call, and when the call started.
+    calls: Vec<(u32, Instant)>,
+}
+
+impl Profiler {
+    pub(crate) fn new(debug: &[DebugInfo], instrs: impl Fn(usize) -> usize) -> Profiler {
+        let mut ids = HashMap::<usize, u32>::new();
+        let mut offsets = Vec::new();
+        let mut regions = Vec::with_capacity(debug.len());
+        let mut starts = Vec::with_capacity(debug.len());
+        for (func, info) in debug.iter().enumerate() {
+            let len = instrs(func);
+            let mut region = vec![NONE; len];
+            let mut start = vec![false; len];
+            let mut seen = HashSet::new();
+            for (instr, span) in info.lines.iter() {
+                let (l, _) = match span {
+                    Some(span) => span,
+                    None => continue,
+                };
+                let id = *ids.entry(l.offset).or_insert_with(|| {
+                    offsets.push(l.offset);
+                    offsets.len() as u32 - 1
+                });
+                // Statements like loops have several entries in `lines`. Only the first one marks
+                // the start of the statement.
+                if !seen.insert(id) {
+                    continue;
+                }
+                if *instr < len {
+                    start[*instr] = true;
+                }
+            }
+            for (i, (instr, span)) in info.lines.iter().enumerate() {
+                let end = info.lines.get(i + 1).map(|(next, _)| *next).unwrap_or(len);
+                let id = match span {
+                    Some((l, _)) => ids[&l.offset],
+                    None => NONE,
+                };
+                for r in &mut region[(*instr).min(len)..end.min(len)] {
+                    *r = id;
+                }
+            }
+            regions.push(region);
+            starts.push(start);
+        }
+        let mut stmts = Vec::new();
+        stmts.resize_with(offsets.len(), Default::default);
+        let mut funcs = Vec::new();
+        funcs.resize_with(debug.len(), Default::default);
+        Profiler {
+            regions,
+            starts,
+            stmts,
+            offsets,
+            funcs,
+            names: debug.iter().map(|info| info.name.clone()).collect(),
+            cur: NONE,
+            last: Instant::now(),
+            calls: Vec::new(),
+        }
+    }
+
+    // Charge the time since the last switch to the current statement and start running `next`.
+    fn switch(&mut self, next: u32) {
+        let now = Instant::now();
+        if self.cur != NONE {
+            self.stmts[self.cur as usize].time += now - self.last;
+        }
+        self.cur = next;
+        self.last = now;
+    }
+
+    /// Record that instruction `instr` of function `func` is about to execute.
+    #[inline]
+    pub(crate) fn step(&mut self, func: usize, instr: usize) {
+        let region = self.regions[func][instr];
+        if self.starts[func][instr] {
+            self.stmts[region as usize].count += 1;
+        } else if region == self.cur {
+            return;
+        }
+        // Jumping into the middle of a statement (e.g. to the end of a loop or an if statement)
+        // does not count as executing it again.
+        self.switch(region);
+    }
+
+    /// Record a call to function `func`.
+    pub(crate) fn call(&mut self, func: usize) {
+        self.funcs[func].calls += 1;
+        self.calls.push((self.cur, Instant::now()));
+    }
+
+    /// Record a return from function `func`.
+    pub(crate) fn ret(&mut self, func: usize) {
+        if let Some((caller, start)) = self.calls.pop() {
+            self.funcs[func].time += start.elapsed();
+            // Continuing the caller's statement does not count as executing it again.
+            self.switch(caller);
+        }
+    }
+
+    /// Stop timing the current statement, e.g. at the end of a main function.
+    pub(crate) fn pause(&mut self) {
+        self.switch(NONE);
+    }
+
+    pub(crate) fn finish(mut self, regex_stats: RegexStats) -> Profile {
+        self.pause();
+        let mut stmts = HashMap::new();
+        for (offset, stmt) in self.offsets.into_iter().zip(self.stmts.into_iter()) {
+            stmts.insert(offset, stmt);
+        }
+        // Each instantiation of a function gets its own frame; combine them here.
+        let mut funcs = HashMap::<String, Func>::new();
+        for (name, func) in self.names.into_iter().zip(self.funcs.into_iter()) {
+            let total = funcs.entry(name).or_default();
+            total.calls += func.calls;
+            total.time += func.time;
+        }
+        Profile {
+            stmts,
+            funcs,
+            regex_stats,
+        }
+    }
+}
+
+/// The counts and timings gathered while running a program, keyed by position in the program
+/// text.
+pub(crate) struct Profile {
+    stmts: HashMap<usize, Stmt>,
+    funcs: HashMap<String, Func>,
+    regex_stats: RegexStats,
+}
+
+fn fmt_duration(d: Duration) -> String {
+    let secs = d.as_secs_f64();
+    if secs >= 1.0 {
+        format!("{:.3}s", secs)
+    } else if secs >= 1e-3 {
+        format!("{:.3}ms", secs * 1e3)
+    } else {
+        format!("{:.3}us", secs * 1e6)
+    }
+}
+
+impl Annotate for Profile {
+    fn margin(&self, span: &Span) -> Option<String> {
+        match self.stmts.get(&span.0.offset) {
+            Some(stmt) if stmt.count > 0 => Some(stmt.count.to_string()),
+            _ => None,
+        }
+    }
+
+    fn header(&self, func: Option<&str>, start: usize, end: usize) -> Option<String> {
+        if let Some(name) = func {
+            let func = self.funcs.get(name)?;
+            return Some(format!(
+                "{} call{}, {}",
+                func.calls,
+                if func.calls == 1 { "" } else { "s" },
+                fmt_duration(func.time)
+            ));
+        }
+        let time: Duration = self
+            .stmts
+            .iter()
+            .filter(|(offset, _)| (start..end).contains(*offset))
+            .map(|(_, stmt)| stmt.time)
+            .sum();
+        Some(fmt_duration(time))
+    }
+
+    fn footer(&self) -> Option<String> {
+        let RegexStats {
+            lookups,
+            misses,
+            evictions,
+        } = self.regex_stats;
+        Some(format!(
+            "regex cache: {} lookups, {} misses, {} evictions",
+            lookups, misses, evictions
+        ))
+    }
+}
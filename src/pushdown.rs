@@ -103,6 +103,12 @@ impl FieldSet {
     pub fn empty() -> FieldSet {
         FieldSet(0)
     }
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+    pub fn from_bits(bits: u64) -> FieldSet {
+        FieldSet(bits)
+    }
     pub fn union(&mut self, other: &FieldSet) {
         self.0 = self.0 | other.0;
     }
@@ -225,6 +231,8 @@ pub struct UsedFieldAnalysis {
     // the variables in question.  We can always add it in the future, but since join nodes are
     // always "leaves" we will just add the missing columns as a postprocessing step.
     joins: Vec<(Key /*lhs*/, Key /*rhs*/)>,
+    // record_text() returns the whole record as it was read, even if $0 itself is never used.
+    record_text: bool,
 }
 
 impl Default for UsedFieldAnalysis {
@@ -232,6 +240,7 @@ impl Default for UsedFieldAnalysis {
         let mut res = UsedFieldAnalysis {
             dfa: Default::default(),
             joins: Default::default(),
+            record_text: false,
         };
         res.dfa.add_src(Key::Rng, FieldSet::all());
         res.dfa.add_src(Key::VarVal(Variable::FI), FieldSet::fi());
@@ -259,7 +268,8 @@ impl UsedFieldAnalysis {
             | Store { .. }
             | IterBegin { .. }
             | IterGetNext { .. }
-            | Mov(..) => dataflow::boilerplate::visit_ll(inst, |dst, src| {
+            | Mov(..)
+            | TakeStr(..) => dataflow::boilerplate::visit_ll(inst, |dst, src| {
                 if let Some(src) = src {
                     self.dfa.add_dep(dst, src, ())
                 } else {
@@ -278,6 +288,16 @@ impl UsedFieldAnalysis {
                 self.dfa.add_src(dst, FieldSet::all());
                 self.joins.push((start.into(), end.into()));
             }
+            SumColumns(dst, start, end) | MeanColumns(dst, start, end) => {
+                self.dfa.add_query(start);
+                self.dfa.add_query(end);
+                self.dfa.add_src(dst, FieldSet::all());
+                self.joins.push((start.into(), end.into()));
+            }
+            RecordText(dst) => {
+                self.record_text = true;
+                self.dfa.add_src(dst, FieldSet::all());
+            }
             _ => dataflow::boilerplate::visit_ll(inst, |dst, _| {
                 self.dfa.add_src(dst, FieldSet::all())
             }),
@@ -293,6 +313,9 @@ impl UsedFieldAnalysis {
             l_flds.fill(r_flds);
             res.union(&l_flds);
         }
+        if self.record_text {
+            res.set(0);
+        }
         res
     }
 }
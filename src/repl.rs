@@ -0,0 +1,269 @@
+//! Support for `frawk repl`, which runs snippets of AWK typed at a prompt against a sample of
+//! input.
+//!
+//! Each entry is compiled and run as a complete program over the whole sample. Variables carry
+//! over from one entry to the next by appending an END block to each program that writes the
+//! final value of every global variable out as a sequence of AWK assignments; the next entry runs
+//! those assignments in a BEGIN block before any of its own code. Entries that only define
+//! functions are saved and included in every later program.
+use crate::arena::Arena;
+use crate::ast::Prog;
+use crate::common::Stage;
+use crate::compile::Ty;
+use crate::lexer::Tokenizer;
+use crate::parsing::syntax::ProgParser;
+use crate::pretty;
+
+use lalrpop_util::ParseError;
+
+use std::fmt::Write;
+
+/// The number of lines of input that the REPL reads to run entries against.
+pub(crate) const SAMPLE_LINES: usize = 1000;
+
+pub(crate) const HELP: &str = "\
+Enter AWK patterns and actions to run them against the sample input. Variables keep their values
+from one entry to the next, and function definitions are available in later entries.
+Commands:
+    :state   print the saved variables
+    :reset   forget all saved variables and functions
+    :quit    exit (as does end of input)";
+
+/// What to do with a chunk of text entered at the prompt.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Entry {
+    /// The program is not finished: read another line.
+    Incomplete,
+    /// The text only defines functions.
+    Functions,
+    /// The text is a program to run (or one with a syntax error to report).
+    Program,
+}
+
+/// Decide how to handle `text`.
+pub(crate) fn classify(text: &str) -> Entry {
+    let a = Arena::default();
+    let mut buf = Vec::new();
+    let mut prog = Prog::from_stage(Stage::Main(()));
+    match ProgParser::new().parse(&a, &mut buf, &mut prog, Tokenizer::new(text)) {
+        Err(ParseError::UnrecognizedEOF { .. }) => Entry::Incomplete,
+        // So does an unterminated string or regex.
+        Err(ParseError::User { error }) if error.desc.starts_with("incomplete") => {
+            Entry::Incomplete
+        }
+        Err(_) => Entry::Program,
+        Ok(()) => {
+            if prog.decs.len() > 0
+                && prog.begin.is_none()
+                && prog.prepare.is_none()
+                && prog.end.is_none()
+                && prog.signal.is_none()
+                && prog.pats.len() == 0
+            {
+                Entry::Functions
+            } else {
+                Entry::Program
+            }
+        }
+    }
+}
+
+const QUOTE: &str = "__frawk_repl_quote";
+const SAVE: &str = "__frawk_repl_save";
+
+/// AWK source that, once the program finishes, writes assignments to `path` that restore the
+/// values of `globals`.
+pub(crate) fn save_state(globals: &[(&str, Ty)], path: &str) -> String {
+    let path = pretty::string_literal(path.as_bytes());
+    let mut res = String::new();
+    let _ = writeln!(
+        &mut res,
+        r#"function {}(s) {{ gsub(/\\/, "\\\\", s); gsub(/"/, "\\\"", s); gsub(/\n/, "\\n", s); return "\"" s "\"" }}"#,
+        QUOTE
+    );
+    let _ = writeln!(&mut res, "function {}(  k) {{", SAVE);
+    let scalar = |ty: Ty, e: &str| match ty {
+        Ty::Int => e.to_string(),
+        // Floats convert to the shortest string that parses back to the same value.
+        Ty::Float => format!("({} \"\")", e),
+        _ => format!("{}({})", QUOTE, e),
+    };
+    for (name, ty) in globals.iter().cloned() {
+        // SUBSEP is treated as a variable, but it is reset at the start of every program.
+        if name.starts_with("__frawk_repl") || name == "SUBSEP" {
+            continue;
+        }
+        match ty {
+            Ty::Int | Ty::Float | Ty::Str => {
+                let _ = writeln!(
+                    &mut res,
+                    "    print \"{} = \" {} > {}",
+                    name,
+                    scalar(ty, name),
+                    path
+                );
+            }
+            Ty::MapIntInt
+            | Ty::MapIntFloat
+            | Ty::MapIntStr
+            | Ty::MapStrInt
+            | Ty::MapStrFloat
+            | Ty::MapStrStr => {
+                let (key, val) = (ty.key().unwrap(), ty.val().unwrap());
+                let _ = writeln!(
+                    &mut res,
+                    "    for (k in {0}) print \"{0}[\" {1} \"] = \" {2} > {3}",
+                    name,
+                    scalar(key, "k"),
+                    scalar(val, format!("{}[k]", name).as_str()),
+                    path
+                );
+            }
+            Ty::IterInt | Ty::IterStr | Ty::Null => {}
+        }
+    }
+    let _ = writeln!(&mut res, "    close({})\n}}", path);
+    res
+}
+
+/// Assemble the program to run for the entry `text`. The assignments in `state` go at the start
+/// of its BEGIN block and, if `save` (the output of `save_state`) is given, the program saves its
+/// final state at the end of its END block. `defs` and `save` come after the entry, so that line
+/// numbers in error messages match the entry.
+pub(crate) fn assemble(text: &str, state: &str, defs: &str, save: Option<&str>) -> String {
+    use crate::lexer::Tok;
+    // The offsets just past the `{` starting the BEGIN block and of the `}` ending the END block.
+    let mut begin = None;
+    let mut end = None;
+    let mut toks = Tokenizer::new(text).filter_map(|t| t.ok());
+    while let Some((_, tok, _)) = toks.next() {
+        match tok {
+            Tok::Begin => {
+                if let Some((_, Tok::LBrace, r)) = toks.next() {
+                    begin = Some(r.offset);
+                }
+            }
+            Tok::End => {
+                if let Some((_, Tok::LBrace, _)) = toks.next() {
+                    let mut depth = 1;
+                    for (l, tok, _) in &mut toks {
+                        match tok {
+                            Tok::LBrace => depth += 1,
+                            Tok::RBrace if depth == 1 => {
+                                end = Some(l.offset);
+                                break;
+                            }
+                            Tok::RBrace => depth -= 1,
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // Keep the restored state on one line.
+    let state = state.lines().collect::<Vec<_>>().join("; ");
+    let mut res = String::new();
+    let mut prev = 0;
+    let mut inserts = Vec::new();
+    match begin {
+        Some(begin) if state.len() > 0 => inserts.push((begin, format!(" {};", state))),
+        _ => {}
+    }
+    match end {
+        Some(end) if save.is_some() => inserts.push((end, format!("\n{}()\n", SAVE))),
+        _ => {}
+    }
+    inserts.sort_by_key(|(offset, _)| *offset);
+    for (offset, insert) in inserts {
+        res.push_str(&text[prev..offset]);
+        res.push_str(insert.as_str());
+        prev = offset;
+    }
+    res.push_str(&text[prev..]);
+    res.push('\n');
+    if begin.is_none() && state.len() > 0 {
+        let _ = writeln!(&mut res, "BEGIN {{ {} }}", state);
+    }
+    if let Some(save) = save {
+        if end.is_none() {
+            let _ = writeln!(&mut res, "END {{ {}() }}", SAVE);
+        }
+        res.push_str(save);
+    }
+    res.push_str(defs);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_entries() {
+        assert_eq!(classify("{ print $1"), Entry::Incomplete);
+        assert_eq!(classify("{ print \"abc"), Entry::Incomplete);
+        assert_eq!(classify("{ print $1 }"), Entry::Program);
+        assert_eq!(classify("{ print $1 } }"), Entry::Program);
+        assert_eq!(classify("function f(x) { return x*2 }"), Entry::Functions);
+        assert_eq!(
+            classify("function f(x) { return x*2 }\n{ print f($1) }"),
+            Entry::Program
+        );
+    }
+
+    #[test]
+    fn assemble_programs() {
+        let state = "n = 3\nm[\"a\"] = 1\n";
+        assert_eq!(
+            assemble("BEGIN { x = n }\nEND { print x }", state, "", Some("")),
+            "BEGIN { n = 3; m[\"a\"] = 1; x = n }\nEND { print x \n__frawk_repl_save()\n}\n"
+        );
+        assert_eq!(
+            assemble("{ print $1 }", state, "function f() {}\n", None),
+            "{ print $1 }\nBEGIN { n = 3; m[\"a\"] = 1 }\nfunction f() {}\n"
+        );
+    }
+
+    #[test]
+    fn save_and_restore() {
+        use crate::cfg::{Escaper, ProgramContext};
+        use crate::common::ExecutionStrategy;
+        use crate::harness::{parse_program, run_program};
+        use crate::runtime::{splitter::regex::RegexSplitter, writers::testing::FakeFs};
+        fn context<'a>(a: &'a Arena, prog: &str) -> ProgramContext<'a, &'a str> {
+            let stmt = parse_program(prog, a, Escaper::Identity, ExecutionStrategy::Serial);
+            ProgramContext::from_prog(a, stmt.unwrap(), Escaper::Identity).unwrap()
+        }
+
+        let text =
+            r#"BEGIN { n = 3; f = 0.5; s = "a\"b\\c\nd"; m["x"] = 1; m["y"] = 2; a[1] = "z" }"#;
+        let a = Arena::default();
+        let globals =
+            crate::compile::global_types(&mut context(&a, assemble(text, "", "", None).as_str()))
+                .unwrap();
+        let save = save_state(&globals[..], "state");
+        let mut ctx = context(&a, assemble(text, "", "", Some(save.as_str())).as_str());
+        let fs = FakeFs::default();
+        let input = crate::runtime::ChainedReader::new(std::iter::empty::<
+            RegexSplitter<Box<dyn std::io::Read + Send>>,
+        >());
+        let mut interp = crate::compile::bytecode(&mut ctx, input, fs.clone(), 1).unwrap();
+        interp.run().unwrap();
+        std::mem::drop(interp);
+        let state = String::from_utf8(fs.get_handle("state").unwrap().read_data()).unwrap();
+
+        let prog = "BEGIN { print n + 1, f * 2, s; for (k in m) t += m[k]; print t, a[1] }";
+        let (out, _, _) = run_program(
+            &a,
+            assemble(prog, state.as_str(), "", None).as_str(),
+            "",
+            Escaper::Identity,
+            None,
+            ExecutionStrategy::Serial,
+        )
+        .unwrap();
+        assert_eq!(out, "4 1 a\"b\\c\nd\n3 z\n");
+    }
+}
@@ -0,0 +1,158 @@
+//! Division and remainders, for the `/` and `%` operators and the `intdiv` and `divmod` builtins,
+//! and the handling of integer overflow in the other arithmetic operators.
+//!
+//! Dividing a floating-point number by zero produces an infinity or a NaN, unless division by zero
+//! has been made an error with `--div-by-zero=error`. Integers have no way to represent those
+//! results, so integer division by zero is always an error.
+//!
+//! Integer `+`, `-`, `*` and unary `-` are computed in floating point by default, as in gawk, so
+//! they cannot overflow. With `--wrap` or `--checked` they stay integers, which wrap around or
+//! raise an error (respectively) on overflow.
+use crate::common::Result;
+use crate::runtime::{Float, Int, IntMap, Str, StrMap};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DIV_BY_ZERO_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Make floating-point division by zero an error. This must be called before compiling a program.
+pub(crate) fn enable_div_by_zero_errors() {
+    DIV_BY_ZERO_ERRORS.store(true, Ordering::Relaxed);
+}
+
+/// Whether floating-point division by zero is an error.
+pub(crate) fn div_by_zero_errors() -> bool {
+    DIV_BY_ZERO_ERRORS.load(Ordering::Relaxed)
+}
+
+static INT_PROMOTION: AtomicBool = AtomicBool::new(true);
+
+/// Keep the results of `+`, `-`, `*` and unary `-` on integers as integers, rather than computing
+/// them in floating point (see `IntOverflow`). This must be called before compiling a program.
+pub(crate) fn disable_int_promotion() {
+    INT_PROMOTION.store(false, Ordering::Relaxed);
+}
+
+/// Whether `+`, `-`, `*` and unary `-` on integers produce floating-point numbers.
+pub(crate) fn int_promotion() -> bool {
+    INT_PROMOTION.load(Ordering::Relaxed)
+}
+
+/// `x + y`, failing if the result overflows (`--checked`).
+pub(crate) fn checked_add(x: Int, y: Int) -> Result<Int> {
+    match x.checked_add(y) {
+        Some(i) => Ok(i),
+        None => err!("integer overflow in {} + {}", x, y),
+    }
+}
+
+/// `x - y`, failing if the result overflows (`--checked`).
+pub(crate) fn checked_sub(x: Int, y: Int) -> Result<Int> {
+    match x.checked_sub(y) {
+        Some(i) => Ok(i),
+        None => err!("integer overflow in {} - {}", x, y),
+    }
+}
+
+/// `x * y`, failing if the result overflows (`--checked`).
+pub(crate) fn checked_mul(x: Int, y: Int) -> Result<Int> {
+    match x.checked_mul(y) {
+        Some(i) => Ok(i),
+        None => err!("integer overflow in {} * {}", x, y),
+    }
+}
+
+/// `-x`, failing if the result overflows (`--checked`).
+pub(crate) fn checked_neg(x: Int) -> Result<Int> {
+    match x.checked_neg() {
+        Some(i) => Ok(i),
+        None => err!("integer overflow in -({})", x),
+    }
+}
+
+/// `x / y`.
+pub(crate) fn div(x: Float, y: Float) -> Result<Float> {
+    if y == 0.0 && div_by_zero_errors() {
+        return err!("division by zero in {} / {}", x, y);
+    }
+    Ok(x / y)
+}
+
+/// `x % y`, for floating-point operands.
+pub(crate) fn fmod(x: Float, y: Float) -> Result<Float> {
+    if y == 0.0 && div_by_zero_errors() {
+        return err!("division by zero in {} % {}", x, y);
+    }
+    Ok(x % y)
+}
+
+/// `x % y`, for integer operands. The result has the sign of `x`.
+pub(crate) fn imod(x: Int, y: Int) -> Result<Int> {
+    if y == 0 {
+        return err!("division by zero in {} % {}", x, y);
+    }
+    // i64::MIN % -1 overflows, though the remainder is 0.
+    Ok(x.wrapping_rem(y))
+}
+
+/// `intdiv(num, den, res)`: store the quotient of `num` and `den`, rounded towards zero, in
+/// `res["quotient"]` and the remainder in `res["remainder"]`, as gawk does. `res` is cleared
+/// first. Returns 0.
+pub(crate) fn intdiv<'a>(num: Int, den: Int, res: &StrMap<'a, Int>) -> Result<Int> {
+    if den == 0 {
+        return err!("division by zero in intdiv({}, {})", num, den);
+    }
+    res.clear();
+    res.insert(Str::from("quotient"), num.wrapping_div(den));
+    res.insert(Str::from("remainder"), num.wrapping_rem(den));
+    Ok(0)
+}
+
+/// `divmod(num, den, res)`: store the quotient of `num` and `den`, rounded down, in `res[1]` and
+/// the remainder in `res[2]`. Unlike `intdiv` and `%`, the remainder has the sign of `den`, so
+/// that `res[1] * den + res[2] == num` with `0 <= res[2] < den` for positive `den`. `res` is
+/// cleared first. Returns the quotient.
+pub(crate) fn divmod(num: Int, den: Int, res: &IntMap<Int>) -> Result<Int> {
+    if den == 0 {
+        return err!("division by zero in divmod({}, {})", num, den);
+    }
+    let (mut q, mut r) = (num.wrapping_div(den), num.wrapping_rem(den));
+    if r != 0 && ((r < 0) != (den < 0)) {
+        q -= 1;
+        r += den;
+    }
+    res.clear();
+    res.insert(1, q);
+    res.insert(2, r);
+    Ok(q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_division() {
+        let m = StrMap::default();
+        let get = |k: &'static str| m.get(&Str::from(k)).unwrap();
+        intdiv(-7, 2, &m).unwrap();
+        assert_eq!((get("quotient"), get("remainder")), (-3, -1));
+        intdiv(Int::MIN, -1, &m).unwrap();
+        assert_eq!((get("quotient"), get("remainder")), (Int::MIN, 0));
+        assert!(intdiv(1, 0, &m).is_err());
+
+        let m = IntMap::default();
+        for (num, den, q, r) in &[
+            (7, 2, 3, 1),
+            (-7, 2, -4, 1),
+            (7, -2, -4, -1),
+            (-7, -2, 3, -1),
+        ] {
+            assert_eq!(divmod(*num, *den, &m).unwrap(), *q);
+            assert_eq!((m.get(&1), m.get(&2)), (Some(*q), Some(*r)));
+        }
+        assert!(divmod(1, 0, &m).is_err());
+        assert!(imod(1, 0).is_err());
+        assert_eq!(imod(Int::MIN, -1).unwrap(), 0);
+    }
+}
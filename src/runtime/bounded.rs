@@ -0,0 +1,265 @@
+//! Support for bounded arrays, which evict old entries once they hold too many.
+//!
+//! `bound(m, n[, policy])` limits the array `m` to `n` entries. Once `m` is full, adding a new key
+//! evicts either the least recently used key (the default, `"lru"`) or the key that was added
+//! first (`"fifo"`). Reads and writes of a key both count as uses; `delete` and `split` work as
+//! usual. This lets long-running jobs keep per-key state in an array without it growing forever.
+//!
+//! Unbounded arrays (the common case) only pay for a check that the bound is missing.
+//!
+//! Entries are ordered using a queue of (timestamp, key) pairs along with the latest timestamp of
+//! each key. Using a key pushes a new pair onto the queue rather than moving the old one, so stale
+//! pairs are skipped during eviction and pruned once they make up most of the queue.
+use super::{
+    table::{MapKey, Table},
+    Int,
+};
+use crate::common::Result;
+
+use hashbrown::HashMap;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::ops::Deref;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Eviction {
+    Lru,
+    Fifo,
+}
+
+impl Eviction {
+    pub(crate) fn parse(s: &[u8]) -> Result<Eviction> {
+        match s {
+            b"lru" => Ok(Eviction::Lru),
+            b"fifo" => Ok(Eviction::Fifo),
+            _ => err!(
+                "unknown eviction policy {:?}, expected \"lru\" or \"fifo\"",
+                String::from_utf8_lossy(s)
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bound<K> {
+    size: usize,
+    policy: Eviction,
+    clock: u64,
+    stamps: HashMap<K, u64>,
+    order: VecDeque<(u64, K)>,
+}
+
+impl<K: Hash + Eq + Clone> Bound<K> {
+    fn touch(&mut self, k: &K) {
+        self.clock += 1;
+        self.stamps.insert(k.clone(), self.clock);
+        self.order.push_back((self.clock, k.clone()));
+        if self.order.len() > 2 * self.stamps.len() + 16 {
+            let stamps = &self.stamps;
+            self.order.retain(|(t, k)| stamps.get(k) == Some(t));
+        }
+    }
+
+    /// The next key to evict, if any.
+    fn pop(&mut self) -> Option<K> {
+        while let Some((t, k)) = self.order.pop_front() {
+            if self.stamps.get(&k) == Some(&t) {
+                self.stamps.remove(&k);
+                return Some(k);
+            }
+        }
+        None
+    }
+}
+
+/// The contents of an array: a table, along with an optional bound on its size.
+///
+/// Reads go through `Deref`; all modifications must go through the methods here so that the bound
+/// is kept up to date.
+#[derive(Debug)]
+pub(crate) struct MapRep<K, V> {
+    map: Table<K, V>,
+    bound: Option<Box<Bound<K>>>,
+}
+
+impl<K, V> Default for MapRep<K, V> {
+    fn default() -> MapRep<K, V> {
+        MapRep {
+            map: Default::default(),
+            bound: None,
+        }
+    }
+}
+
+impl<K: MapKey, V> From<HashMap<K, V>> for MapRep<K, V> {
+    fn from(map: HashMap<K, V>) -> MapRep<K, V> {
+        MapRep {
+            map: map.into(),
+            bound: None,
+        }
+    }
+}
+
+impl<K, V> Deref for MapRep<K, V> {
+    type Target = Table<K, V>;
+    fn deref(&self) -> &Table<K, V> {
+        &self.map
+    }
+}
+
+impl<K: MapKey, V> MapRep<K, V> {
+    pub(crate) fn is_bounded(&self) -> bool {
+        self.bound.is_some()
+    }
+
+    pub(crate) fn insert(&mut self, k: K, v: V) {
+        if let Some(bound) = &mut self.bound {
+            if bound.policy == Eviction::Lru || !self.map.contains_key(&k) {
+                bound.touch(&k);
+            }
+            self.map.insert(k, v);
+            self.evict();
+        } else {
+            self.map.insert(k, v);
+        }
+    }
+
+    /// Look up `k`, counting it as a use of `k`.
+    pub(crate) fn get_touch(&mut self, k: &K) -> Option<&V> {
+        if let Some(bound) = &mut self.bound {
+            if bound.policy == Eviction::Lru && self.map.contains_key(k) {
+                bound.touch(k);
+            }
+        }
+        self.map.get(k)
+    }
+
+    pub(crate) fn remove(&mut self, k: &K) {
+        if self.map.remove(k).is_some() {
+            if let Some(bound) = &mut self.bound {
+                bound.stamps.remove(k);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.map.clear();
+        if let Some(bound) = &mut self.bound {
+            bound.stamps.clear();
+            bound.order.clear();
+        }
+    }
+
+    /// Limit the map to `size` entries, or remove any limit if `size` is not positive. Returns the
+    /// number of entries evicted to get under the new limit.
+    pub(crate) fn set_bound(&mut self, size: Int, policy: Eviction) -> Int {
+        if size <= 0 {
+            self.bound = None;
+            return 0;
+        }
+        let mut bound = match self.bound.take() {
+            Some(b) if b.policy == policy => b,
+            _ => {
+                // Existing entries have no order to speak of; evict them in an arbitrary one.
+                let mut b = Box::new(Bound {
+                    size: 0,
+                    policy,
+                    clock: 0,
+                    stamps: Default::default(),
+                    order: Default::default(),
+                });
+                for k in self.map.keys() {
+                    b.touch(&k);
+                }
+                b
+            }
+        };
+        bound.size = size as usize;
+        self.bound = Some(bound);
+        self.evict()
+    }
+
+    fn evict(&mut self) -> Int {
+        let mut evicted = 0;
+        if let Some(bound) = &mut self.bound {
+            while self.map.len() > bound.size {
+                match bound.pop() {
+                    Some(k) => {
+                        self.map.remove(&k);
+                        evicted += 1;
+                    }
+                    None => break,
+                }
+            }
+        }
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(m: &MapRep<Int, Int>) -> Vec<Int> {
+        let mut ks: Vec<_> = m.keys().collect();
+        ks.sort();
+        ks
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let mut m = MapRep::<Int, Int>::default();
+        assert_eq!(m.set_bound(3, Eviction::Lru), 0);
+        for i in 1..=3 {
+            m.insert(i, i);
+        }
+        assert_eq!(m.get_touch(&1), Some(&1));
+        m.insert(4, 4);
+        assert_eq!(keys(&m), vec![1, 3, 4]);
+        m.insert(3, 30);
+        m.insert(5, 5);
+        assert_eq!(keys(&m), vec![3, 4, 5]);
+        m.remove(&4);
+        m.insert(6, 6);
+        assert_eq!(keys(&m), vec![3, 5, 6]);
+        // Lots of reads of the same keys shouldn't grow the queue without bound.
+        for _ in 0..1000 {
+            m.get_touch(&5);
+        }
+        assert!(m.bound.as_ref().unwrap().order.len() < 100);
+        assert_eq!(m.set_bound(1, Eviction::Lru), 2);
+        assert_eq!(keys(&m), vec![5]);
+    }
+
+    #[test]
+    fn fifo_eviction() {
+        let mut m = MapRep::<Int, Int>::default();
+        for i in 1..=5 {
+            m.insert(i, i);
+        }
+        assert_eq!(m.set_bound(2, Eviction::Fifo), 3);
+        assert_eq!(m.len(), 2);
+        m.clear();
+        for i in 1..=3 {
+            m.insert(i, i);
+            m.get_touch(&1);
+        }
+        assert_eq!(keys(&m), vec![2, 3]);
+        // Overwriting a key does not move it to the back of the queue.
+        m.insert(2, 20);
+        m.insert(4, 4);
+        assert_eq!(keys(&m), vec![3, 4]);
+        m.set_bound(0, Eviction::Fifo);
+        for i in 10..20 {
+            m.insert(i, i);
+        }
+        assert_eq!(m.len(), 12);
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(Eviction::parse(b"lru").unwrap(), Eviction::Lru);
+        assert_eq!(Eviction::parse(b"fifo").unwrap(), Eviction::Fifo);
+        assert!(Eviction::parse(b"random").is_err());
+    }
+}
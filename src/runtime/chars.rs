@@ -0,0 +1,367 @@
+//! Character-based indexing for `length`, `substr`, `index` and `match`, along with
+//! grapheme-based variants of `length` and `substr`.
+//!
+//! Like gawk in a UTF-8 locale, these builtins count characters (Unicode scalar values) rather
+//! than bytes by default; `--bytes` switches them back to counting bytes. Strings need not be
+//! valid UTF-8: a character is any byte that is not a UTF-8 continuation byte, along with the
+//! continuation bytes that follow it. That means indexes never point into the middle of a
+//! well-formed character, and every byte of a string belongs to exactly one character.
+//!
+//! Mapping between character and byte offsets takes a scan of the string, which would make loops
+//! like `for (i = 1; i <= length(s); i++) c = substr(s, i, 1)` quadratic. ASCII strings skip the
+//! scan entirely, and for longer strings we keep the byte offset of every `STRIDE`th character of
+//! the most recently indexed string, so that repeated lookups only scan a short stretch of it.
+//!
+//! Several characters can make up what a reader sees as one: a letter followed by combining
+//! accents, or an emoji with skin-tone modifiers. `grapheme_length`, `grapheme_substr` and
+//! `graphemes` count these extended grapheme clusters instead, regardless of `--bytes`.
+use super::{utf8::is_char_boundary, Int, IntMap, Str};
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static BYTES: AtomicBool = AtomicBool::new(false);
+
+/// Index strings by bytes rather than characters.
+pub fn set_bytes(bytes: bool) {
+    BYTES.store(bytes, Ordering::Relaxed)
+}
+
+pub(crate) fn bytes_mode() -> bool {
+    BYTES.load(Ordering::Relaxed)
+}
+
+/// Strings shorter than this are scanned directly.
+const MIN_INDEXED_LEN: usize = 256;
+const STRIDE: usize = 64;
+
+struct CharIndex {
+    // Holding onto the string keeps its buffer alive (and unchanged), so the pointer and length
+    // identify it.
+    _s: Str<'static>,
+    ptr: *const u8,
+    len: usize,
+    // marks[i] is the byte offset of character i*STRIDE. Empty for ASCII strings.
+    marks: Vec<usize>,
+    chars: usize,
+}
+
+thread_local! {
+    static LAST_INDEX: RefCell<Option<CharIndex>> = RefCell::new(None);
+}
+
+impl CharIndex {
+    fn new(s: &Str, bs: &[u8]) -> CharIndex {
+        let mut marks = Vec::new();
+        let mut chars = 0;
+        if !bs.is_ascii() {
+            for (i, b) in bs.iter().enumerate() {
+                if i == 0 || is_char_boundary(*b) {
+                    if chars % STRIDE == 0 {
+                        marks.push(i);
+                    }
+                    chars += 1;
+                }
+            }
+        } else {
+            chars = bs.len();
+        }
+        CharIndex {
+            _s: s.clone().unmoor(),
+            ptr: bs.as_ptr(),
+            len: bs.len(),
+            marks,
+            chars,
+        }
+    }
+    fn is_ascii(&self) -> bool {
+        self.marks.len() == 0
+    }
+    fn offset(&self, bs: &[u8], ch: usize) -> usize {
+        if self.is_ascii() {
+            return std::cmp::min(ch, bs.len());
+        }
+        if ch >= self.chars {
+            return bs.len();
+        }
+        let mark = ch / STRIDE;
+        let start = self.marks[mark];
+        start + offset(&bs[start..], ch - mark * STRIDE)
+    }
+    fn chars_before(&self, bs: &[u8], off: usize) -> usize {
+        if self.is_ascii() {
+            return off;
+        }
+        let mark = match self.marks.binary_search(&off) {
+            Ok(m) => return m * STRIDE,
+            Err(m) => m - 1,
+        };
+        mark * STRIDE + count(&bs[self.marks[mark]..off])
+    }
+}
+
+/// Run `f` against an index of `s` if it is worth building one, and against `None` otherwise.
+fn with_index<R>(s: &Str, f: impl FnOnce(&[u8], Option<&CharIndex>) -> R) -> R {
+    s.with_bytes(|bs| {
+        if bs.len() < MIN_INDEXED_LEN || !s.is_heap() {
+            // Literals are not worth caching: we cannot tell if a literal at the same address
+            // later has different contents.
+            return f(bs, None);
+        }
+        LAST_INDEX.with(|last| {
+            let mut last = last.borrow_mut();
+            match &*last {
+                Some(ix) if ix.ptr == bs.as_ptr() && ix.len == bs.len() => {}
+                _ => *last = Some(CharIndex::new(s, bs)),
+            }
+            f(bs, last.as_ref())
+        })
+    })
+}
+
+/// The number of characters in `bs`.
+fn count(bs: &[u8]) -> usize {
+    if bs.is_empty() {
+        return 0;
+    }
+    1 + bs[1..].iter().filter(|b| is_char_boundary(**b)).count()
+}
+
+/// The byte offset of character `ch` (0-indexed) in `bs`, or `bs.len()` if there are not that many
+/// characters.
+fn offset(bs: &[u8], ch: usize) -> usize {
+    if ch == 0 {
+        return 0;
+    }
+    let mut seen = 0;
+    for (i, b) in bs.iter().enumerate().skip(1) {
+        if is_char_boundary(*b) {
+            seen += 1;
+            if seen == ch {
+                return i;
+            }
+        }
+    }
+    bs.len()
+}
+
+/// `length(s)`
+pub(crate) fn len(s: &Str) -> Int {
+    if bytes_mode() {
+        return s.len() as Int;
+    }
+    with_index(s, |bs, ix| match ix {
+        Some(ix) => ix.chars,
+        None => count(bs),
+    }) as Int
+}
+
+/// `substr(s, l, n)`: the (at most) `n` characters of `s` starting at the 1-indexed position `l`.
+pub(crate) fn substr<'a>(s: &Str<'a>, l: Int, n: Int) -> Str<'a> {
+    use std::cmp::{max, min};
+    let clamp = |len: usize| {
+        let l = min(max(0, l - 1), len as Int);
+        let r = max(l, min(len as Int, l.saturating_add(n)));
+        (l as usize, r as usize)
+    };
+    if bytes_mode() {
+        let (l, r) = clamp(s.len());
+        return s.slice(l, r);
+    }
+    let (from, to) = with_index(s, |bs, ix| match ix {
+        Some(ix) => {
+            let (l, r) = clamp(ix.chars);
+            (ix.offset(bs, l), ix.offset(bs, r))
+        }
+        None if bs.is_ascii() => clamp(bs.len()),
+        None => {
+            let (l, r) = clamp(count(bs));
+            let from = offset(bs, l);
+            (from, from + offset(&bs[from..], r - l))
+        }
+    });
+    s.slice(from, to)
+}
+
+/// Convert a byte offset into `s` to a character offset.
+fn chars_before(s: &Str, off: usize) -> usize {
+    if bytes_mode() {
+        return off;
+    }
+    with_index(s, |bs, ix| match ix {
+        Some(ix) => ix.chars_before(bs, off),
+        None => count(&bs[..off]),
+    })
+}
+
+/// `index(haystack, needle)`: the 1-indexed position of `needle` in `haystack`, or 0.
+pub(crate) fn index<'a>(haystack: &Str<'a>, needle: &Str<'a>) -> Int {
+    match super::string_search::index_substr(needle, haystack) {
+        0 => 0,
+        i => chars_before(haystack, i as usize - 1) as Int + 1,
+    }
+}
+
+/// The values of `RSTART` and `RLENGTH` for a match of the bytes `start..end` in `s`.
+pub(crate) fn match_loc(s: &Str, start: usize, end: usize) -> (Int, Int) {
+    let rstart = chars_before(s, start);
+    let rend = chars_before(s, end);
+    (rstart as Int + 1, (rend - rstart) as Int)
+}
+
+/// Call `f` with the start and end of each extended grapheme cluster in `bs`, until it returns
+/// false. Bytes that are not part of valid UTF-8 are clusters of their own.
+fn each_grapheme(bs: &[u8], mut f: impl FnMut(usize, usize) -> bool) {
+    use unicode_segmentation::UnicodeSegmentation;
+    let mut base = 0;
+    while base < bs.len() {
+        let rest = &bs[base..];
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(s) => (s, 0),
+            Err(e) => {
+                let valid = e.valid_up_to();
+                let s = unsafe { std::str::from_utf8_unchecked(&rest[..valid]) };
+                (s, e.error_len().unwrap_or(rest.len() - valid))
+            }
+        };
+        for (off, g) in valid.grapheme_indices(/*extended=*/ true) {
+            if !f(base + off, base + off + g.len()) {
+                return;
+            }
+        }
+        base += valid.len();
+        for _ in 0..invalid {
+            if !f(base, base + 1) {
+                return;
+            }
+            base += 1;
+        }
+    }
+}
+
+/// `grapheme_length(s)`
+pub(crate) fn grapheme_len(s: &Str) -> Int {
+    let mut n = 0;
+    s.with_bytes(|bs| {
+        each_grapheme(bs, |_, _| {
+            n += 1;
+            true
+        })
+    });
+    n
+}
+
+/// `grapheme_substr(s, l, n)`: like `substr`, but counting grapheme clusters.
+pub(crate) fn grapheme_substr<'a>(s: &Str<'a>, l: Int, n: Int) -> Str<'a> {
+    use std::cmp::max;
+    let l = max(0, l - 1);
+    let r = max(l, l.saturating_add(n));
+    let (from, to) = s.with_bytes(|bs| {
+        let (mut from, mut to) = (bs.len(), bs.len());
+        let mut i = 0;
+        each_grapheme(bs, |start, _| {
+            if i == l {
+                from = start;
+            }
+            if i == r {
+                to = start;
+                return false;
+            }
+            i += 1;
+            true
+        });
+        (from, to)
+    });
+    s.slice(from, to)
+}
+
+/// `graphemes(s, arr)`: store each grapheme cluster of `s` in `arr`, starting at index 1, and
+/// return how many there are.
+pub(crate) fn graphemes<'a>(s: &Str<'a>, arr: &IntMap<Str<'a>>) -> Int {
+    let mut arr = arr.0.borrow_mut();
+    arr.clear();
+    let mut i = 0;
+    s.with_bytes(|bs| {
+        each_grapheme(bs, |start, end| {
+            i += 1;
+            arr.insert(i, s.slice(start, end));
+            true
+        })
+    });
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heap(s: &str) -> Str<'static> {
+        Str::from(s.to_string())
+    }
+
+    #[test]
+    fn characters() {
+        for s in &[
+            Str::from("héllo wörld"),
+            heap(&"héllo wörld ".repeat(100)),
+            heap(&"hello world ".repeat(100)),
+        ] {
+            let text = s.with_bytes(|bs| String::from_utf8(bs.to_vec()).unwrap());
+            let chars: Vec<char> = text.chars().collect();
+            assert_eq!(len(s), chars.len() as Int);
+            for &(l, n) in &[
+                (1, 3),
+                (2, 5),
+                (0, 2),
+                (-3, 10),
+                (60, 200),
+                (700, 3),
+                (5, -1),
+            ] {
+                let from = std::cmp::min(std::cmp::max(0, l - 1), chars.len() as Int);
+                let to = std::cmp::max(
+                    from,
+                    std::cmp::min(chars.len() as Int, from.saturating_add(n)),
+                );
+                let expected: String = chars[from as usize..to as usize].iter().collect();
+                assert_eq!(substr(s, l, n), Str::from(expected), "{} {}", l, n);
+            }
+            for needle in &["h", "wö", "ld", "zz"] {
+                let expected = match text.find(needle) {
+                    Some(b) => text[..b].chars().count() as Int + 1,
+                    None => 0,
+                };
+                assert_eq!(index(s, &Str::from(*needle)), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let s = Str::from(&b"\xffa\xc3\xa9\x80b"[..]);
+        // \xff, a, \xc3\xa9\x80, b
+        assert_eq!(len(&s), 4);
+        assert_eq!(substr(&s, 3, 1), Str::from(&b"\xc3\xa9\x80"[..]));
+        assert_eq!(index(&s, &Str::from("b")), 4);
+        assert_eq!(match_loc(&s, 2, 6), (3, 2));
+    }
+
+    #[test]
+    fn grapheme_clusters() {
+        // e + combining acute, t, precomposed é, space, thumbs up + skin tone, invalid byte.
+        let s = Str::from(&b"e\xcc\x81t\xc3\xa9 \xf0\x9f\x91\x8d\xf0\x9f\x8f\xbd\xff"[..]);
+        assert_eq!(len(&s), 8);
+        assert_eq!(grapheme_len(&s), 6);
+        assert_eq!(grapheme_substr(&s, 2, 2), Str::from("té"));
+        assert_eq!(grapheme_substr(&s, 5, 1), Str::from("👍🏽"));
+        assert_eq!(grapheme_substr(&s, 6, 10), Str::from(&b"\xff"[..]));
+        assert_eq!(grapheme_substr(&s, 0, 2), Str::from("e\u{301}t"));
+        assert_eq!(grapheme_substr(&s, 7, 1), Str::default());
+        let arr: IntMap<Str> = Default::default();
+        assert_eq!(graphemes(&s, &arr), 6);
+        assert_eq!(arr.get(&1), Some(Str::from("e\u{301}")));
+        assert_eq!(arr.get(&6), Some(Str::from(&b"\xff"[..])));
+        assert_eq!(graphemes(&Str::default(), &arr), 0);
+        assert_eq!(arr.len(), 0);
+    }
+}
@@ -0,0 +1,489 @@
+//! Checkpoints of how much of each input file has been processed, for `--resume`.
+//!
+//! With `--resume=FILE`, frawk counts the records read from each input file and periodically
+//! writes those counts to `FILE`. If `FILE` already exists when frawk starts, the records it
+//! records as processed are skipped: files that were read to the end and have not changed size
+//! since are not read at all, and the first `records` records of any other file are read but not
+//! passed to the program. A long job that fails part-way through can therefore be restarted with
+//! the same arguments, and a job that runs periodically over files that grow (such as logs) only
+//! sees what was appended since its last run.
+//!
+//! A record counts as processed once the program asks for the next one, and the checkpoint is
+//! written at most about once a second, at the end of each file and at the end of the input. This
+//! means that a record may be processed again after a failure, but never skipped without having
+//! been processed. Standard input is never skipped, and skipped records do not count towards `NR`
+//! or `FNR`.
+//!
+//! The file is a JSON object:
+//!
+//! ```text
+//! {"files": [{"name": "a.log", "records": 1200, "size": 81234, "complete": true}, ...]}
+//! ```
+//!
+//! where `size` is the size of the file in bytes when the entry was written; a file that has since
+//! shrunk is assumed to have been replaced, and is read from the start.
+use super::Str;
+use crate::common::Result;
+
+use lazy_static::lazy_static;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often to write the checkpoint while reading a file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many records to read between checks of how long it has been since the last checkpoint.
+const CHECK_EVERY: u64 = 1024;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Progress {
+    name: String,
+    records: u64,
+    size: u64,
+    complete: bool,
+}
+
+/// The file currently being read.
+struct Current {
+    name: String,
+    // The records read from the file, including the ones skipped.
+    records: u64,
+    // The records that remain to be skipped.
+    skip: u64,
+    // Whether to track this file at all.
+    tracked: bool,
+}
+
+struct Checkpoint {
+    path: PathBuf,
+    files: Vec<Progress>,
+    cur: Option<Current>,
+    since_check: u64,
+    last_save: Instant,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref CHECKPOINT: Mutex<Option<Checkpoint>> = Mutex::new(None);
+}
+
+/// Whether `--resume` was passed.
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Read and write checkpoints to `path`, loading any progress it records from a previous run.
+pub fn set_resume_file(path: impl Into<PathBuf>) -> Result<()> {
+    let path = path.into();
+    let files = match std::fs::read(&path) {
+        Ok(contents) => match decode(&contents) {
+            Some(files) => files,
+            None => return err!("{} is not a valid checkpoint file", path.display()),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return err!("failed to read checkpoint file {}: {}", path.display(), e),
+    };
+    *CHECKPOINT.lock().unwrap() = Some(Checkpoint {
+        path,
+        files,
+        cur: None,
+        since_check: 0,
+        last_save: Instant::now(),
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// What to do with the record that was just read.
+pub(crate) enum Next {
+    Keep,
+    SkipRecord,
+    SkipFile,
+}
+
+/// Called after each read from the main input: `filename` is the file the record came from, and
+/// `got_record` is false once the input is exhausted.
+pub(crate) fn after_read(filename: &Str, got_record: bool) -> Result<Next> {
+    let mut guard = CHECKPOINT.lock().unwrap();
+    let cp = guard.as_mut().unwrap();
+    if !got_record {
+        if cp.cur.is_some() {
+            cp.finish_file();
+            cp.save()?;
+        }
+        return Ok(Next::Keep);
+    }
+    let same_file = match &cp.cur {
+        Some(cur) => filename.with_bytes(|bs| bs == cur.name.as_bytes()),
+        None => false,
+    };
+    if !same_file {
+        if cp.cur.is_some() {
+            cp.finish_file();
+            cp.save()?;
+        }
+        let name = filename.with_bytes(|bs| String::from_utf8_lossy(bs).into_owned());
+        if cp.start_file(name) {
+            return Ok(Next::SkipFile);
+        }
+    }
+    let cur = cp.cur.as_mut().unwrap();
+    cur.records += 1;
+    if cur.skip > 0 {
+        cur.skip -= 1;
+        return Ok(Next::SkipRecord);
+    }
+    if cur.tracked {
+        cp.since_check += 1;
+        if cp.since_check >= CHECK_EVERY {
+            cp.since_check = 0;
+            if cp.last_save.elapsed() >= SAVE_INTERVAL {
+                cp.save()?;
+            }
+        }
+    }
+    Ok(Next::Keep)
+}
+
+fn file_size(name: &str) -> Option<u64> {
+    std::fs::metadata(name)
+        .ok()
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+}
+
+impl Checkpoint {
+    fn entry(&mut self, name: &str) -> &mut Progress {
+        match self.files.iter().position(|p| p.name == name) {
+            Some(i) => &mut self.files[i],
+            None => {
+                self.files.push(Progress {
+                    name: name.into(),
+                    ..Default::default()
+                });
+                self.files.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Start reading `name`, returning whether to skip it entirely.
+    fn start_file(&mut self, name: String) -> bool {
+        let size = if name == "-" { None } else { file_size(&name) };
+        let mut cur = Current {
+            name,
+            records: 0,
+            skip: 0,
+            tracked: size.is_some(),
+        };
+        let mut skip_file = false;
+        if let Some(size) = size {
+            if let Some(prev) = self.files.iter().find(|p| p.name == cur.name) {
+                if prev.complete && prev.size == size {
+                    // Keep the previous count when the file is finished.
+                    cur.records = prev.records;
+                    skip_file = true;
+                } else if prev.size <= size {
+                    cur.skip = prev.records;
+                }
+            }
+        }
+        self.cur = Some(cur);
+        skip_file
+    }
+
+    /// Record that the current file has been read to the end (or skipped with `nextfile`).
+    fn finish_file(&mut self) {
+        let cur = self.cur.take().unwrap();
+        if !cur.tracked || cur.skip > 0 {
+            // Either we cannot resume this file, or it has fewer records than it used to: it
+            // will have to be read from the start next time.
+            if cur.skip > 0 {
+                self.files.retain(|p| p.name != cur.name);
+            }
+            return;
+        }
+        let size = file_size(&cur.name).unwrap_or(0);
+        let entry = self.entry(&cur.name);
+        entry.records = cur.records;
+        entry.size = size;
+        entry.complete = true;
+    }
+
+    fn save(&mut self) -> Result<()> {
+        if let Some(cur) = &self.cur {
+            if cur.tracked && cur.skip == 0 {
+                // The last record read is still being processed.
+                let (name, records) = (cur.name.clone(), cur.records.saturating_sub(1));
+                let size = file_size(&name).unwrap_or(0);
+                let entry = self.entry(&name);
+                entry.records = records;
+                entry.size = size;
+                entry.complete = false;
+            }
+        }
+        self.last_save = Instant::now();
+        let contents = encode(&self.files);
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".tmp-{}", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+        let res = std::fs::File::create(&tmp)
+            .and_then(|mut f| f.write_all(contents.as_bytes()))
+            .and_then(|_| std::fs::rename(&tmp, &self.path));
+        if let Err(e) = res {
+            let _ = std::fs::remove_file(&tmp);
+            return err!(
+                "failed to write checkpoint file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+        Ok(())
+    }
+}
+
+fn encode(files: &[Progress]) -> String {
+    fn quote(s: &str, out: &mut String) {
+        out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\t' => out.push_str("\\t"),
+                '\r' => out.push_str("\\r"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+    let mut out = String::from("{\"files\": [");
+    for (i, p) in files.iter().enumerate() {
+        out.push_str(if i == 0 {
+            "\n  {\"name\": "
+        } else {
+            ",\n  {\"name\": "
+        });
+        quote(&p.name, &mut out);
+        out.push_str(&format!(
+            ", \"records\": {}, \"size\": {}, \"complete\": {}}}",
+            p.records, p.size, p.complete
+        ));
+    }
+    out.push_str("\n]}\n");
+    out
+}
+
+/// A JSON value, as far as we need to read one.
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Obj(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn ws(&mut self) {
+        while self.i < self.s.len() && self.s[self.i].is_ascii_whitespace() {
+            self.i += 1;
+        }
+    }
+    fn eat(&mut self, b: u8) -> Option<()> {
+        self.ws();
+        if self.s.get(self.i) == Some(&b) {
+            self.i += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+    fn lit(&mut self, lit: &[u8], v: Json) -> Option<Json> {
+        if self.s[self.i..].starts_with(lit) {
+            self.i += lit.len();
+            Some(v)
+        } else {
+            None
+        }
+    }
+    fn value(&mut self) -> Option<Json> {
+        self.ws();
+        match *self.s.get(self.i)? {
+            b'n' => self.lit(b"null", Json::Null),
+            b't' => self.lit(b"true", Json::Bool(true)),
+            b'f' => self.lit(b"false", Json::Bool(false)),
+            b'"' => self.string().map(Json::Str),
+            b'[' => {
+                self.i += 1;
+                let mut elts = Vec::new();
+                if self.eat(b']').is_some() {
+                    return Some(Json::Arr(elts));
+                }
+                loop {
+                    elts.push(self.value()?);
+                    if self.eat(b']').is_some() {
+                        return Some(Json::Arr(elts));
+                    }
+                    self.eat(b',')?;
+                }
+            }
+            b'{' => {
+                self.i += 1;
+                let mut fields = Vec::new();
+                if self.eat(b'}').is_some() {
+                    return Some(Json::Obj(fields));
+                }
+                loop {
+                    self.ws();
+                    let k = self.string()?;
+                    self.eat(b':')?;
+                    fields.push((k, self.value()?));
+                    if self.eat(b'}').is_some() {
+                        return Some(Json::Obj(fields));
+                    }
+                    self.eat(b',')?;
+                }
+            }
+            _ => {
+                let start = self.i;
+                while self.i < self.s.len()
+                    && matches!(
+                        self.s[self.i],
+                        b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'
+                    )
+                {
+                    self.i += 1;
+                }
+                let num = std::str::from_utf8(&self.s[start..self.i]).ok()?;
+                num.parse().ok().map(Json::Num)
+            }
+        }
+    }
+    fn string(&mut self) -> Option<String> {
+        if self.s.get(self.i) != Some(&b'"') {
+            return None;
+        }
+        self.i += 1;
+        let mut out = Vec::new();
+        loop {
+            let b = *self.s.get(self.i)?;
+            self.i += 1;
+            match b {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => {
+                    let e = *self.s.get(self.i)?;
+                    self.i += 1;
+                    let c = match e {
+                        b'"' | b'\\' | b'/' => e as char,
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\x08',
+                        b'f' => '\x0c',
+                        b'u' => {
+                            let hex = std::str::from_utf8(self.s.get(self.i..self.i + 4)?).ok()?;
+                            self.i += 4;
+                            std::char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+                        }
+                        _ => return None,
+                    };
+                    let mut buf = [0u8; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                b => out.push(b),
+            }
+        }
+    }
+}
+
+fn decode(contents: &[u8]) -> Option<Vec<Progress>> {
+    let mut p = Parser { s: contents, i: 0 };
+    let v = p.value()?;
+    p.ws();
+    if p.i != contents.len() {
+        return None;
+    }
+    let files = match v.get("files")? {
+        Json::Arr(files) => files,
+        _ => return None,
+    };
+    let mut res = Vec::with_capacity(files.len());
+    for f in files {
+        let num = |k| match f.get(k) {
+            Some(Json::Num(n)) if *n >= 0.0 => Some(*n as u64),
+            _ => None,
+        };
+        res.push(Progress {
+            name: match f.get("name")? {
+                Json::Str(s) => s.clone(),
+                _ => return None,
+            },
+            records: num("records")?,
+            size: num("size")?,
+            complete: match f.get("complete") {
+                Some(Json::Bool(b)) => *b,
+                None | Some(Json::Null) => false,
+                _ => return None,
+            },
+        });
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let files = vec![
+            Progress {
+                name: "a.log".into(),
+                records: 1200,
+                size: 81234,
+                complete: true,
+            },
+            Progress {
+                name: "dir/\"odd\" name\t\u{e9}.csv".into(),
+                records: 0,
+                size: 0,
+                complete: false,
+            },
+        ];
+        let encoded = encode(&files);
+        assert_eq!(decode(encoded.as_bytes()), Some(files));
+        assert_eq!(decode(encode(&[]).as_bytes()), Some(vec![]));
+        assert_eq!(
+            decode(br#"{"version": 1, "files": [{"name": "xA", "records": 3, "size": 10}]}"#),
+            Some(vec![Progress {
+                name: "xA".into(),
+                records: 3,
+                size: 10,
+                complete: false,
+            }])
+        );
+        assert_eq!(decode(b"{\"files\": [}"), None);
+        assert_eq!(decode(b"{\"files\": []} trailing"), None);
+        assert_eq!(decode(b"{\"other\": []}"), None);
+    }
+}
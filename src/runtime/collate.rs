@@ -0,0 +1,129 @@
+//! String orderings for human-facing output: `cmp_natural` and `cmp_locale`.
+//!
+//! Both return -1, 0 or 1, like the comparison function passed to a sort. They only return 0 for
+//! strings with the same bytes: strings that the ordering considers equivalent (e.g. "007" and "7"
+//! for `cmp_natural`) are ordered by their bytes, so that sorting with them is deterministic.
+use super::{Int, Str};
+
+use std::cmp::Ordering;
+
+fn to_int(ord: Ordering) -> Int {
+    match ord {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }
+}
+
+/// `cmp_natural(a, b)`: compare `a` and `b` byte by byte, except that runs of digits are compared
+/// by their numeric value, so that "file9" sorts before "file10".
+pub(crate) fn cmp_natural(a: &Str, b: &Str) -> Int {
+    a.with_bytes(|a| b.with_bytes(|b| to_int(natural(a, b).then_with(|| a.cmp(b)))))
+}
+
+fn natural(mut a: &[u8], mut b: &[u8]) -> Ordering {
+    fn digits(bs: &[u8]) -> usize {
+        bs.iter().take_while(|b| b.is_ascii_digit()).count()
+    }
+    fn strip_zeros(bs: &[u8]) -> &[u8] {
+        let zeros = bs.iter().take_while(|b| **b == b'0').count();
+        &bs[zeros..]
+    }
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (da, db) = (digits(a), digits(b));
+                let (na, nb) = (strip_zeros(&a[..da]), strip_zeros(&b[..db]));
+                // Without leading zeros, a longer run of digits is a larger number.
+                let ord = na.len().cmp(&nb.len()).then_with(|| na.cmp(nb));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = &a[da..];
+                b = &b[db..];
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+/// Use the collation order of the user's locale (`LC_ALL`, `LC_COLLATE` or `LANG`) for
+/// `cmp_locale`. This must be called before any other threads are started.
+#[cfg(unix)]
+pub fn init_locale() {
+    unsafe { libc::setlocale(libc::LC_COLLATE, b"\0".as_ptr() as *const libc::c_char) };
+}
+
+#[cfg(not(unix))]
+pub fn init_locale() {}
+
+/// `cmp_locale(a, b)`: compare `a` and `b` in the collation order of the user's locale.
+pub(crate) fn cmp_locale(a: &Str, b: &Str) -> Int {
+    a.with_bytes(|a| b.with_bytes(|b| to_int(collate(a, b).then_with(|| a.cmp(b)))))
+}
+
+#[cfg(unix)]
+fn collate(a: &[u8], b: &[u8]) -> Ordering {
+    use std::ffi::CString;
+    // strcoll stops at the first NUL; compare only what it would see.
+    let c_str = |bs: &[u8]| {
+        let len = memchr::memchr(0, bs).unwrap_or(bs.len());
+        CString::new(&bs[..len]).unwrap()
+    };
+    let (a, b) = (c_str(a), c_str(b));
+    unsafe { libc::strcoll(a.as_ptr(), b.as_ptr()) }.cmp(&0)
+}
+
+#[cfg(not(unix))]
+fn collate(a: &[u8], b: &[u8]) -> Ordering {
+    a.cmp(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natural_order() {
+        let mut names = vec![
+            "file10", "file9", "file1", "File2", "file", "file09", "a2b10", "a2b9", "a10b1", "",
+        ];
+        names.sort_by(|a, b| cmp_natural(&Str::from(*a), &Str::from(*b)).cmp(&0));
+        assert_eq!(
+            names,
+            vec![
+                "", "File2", "a2b9", "a2b10", "a10b1", "file", "file1", "file09", "file9", "file10"
+            ]
+        );
+        assert_eq!(cmp_natural(&Str::from("x7"), &Str::from("x7")), 0);
+        assert_eq!(cmp_natural(&Str::from("x07"), &Str::from("x7")), -1);
+    }
+
+    #[test]
+    fn locale_order_is_total() {
+        // The tests run in the "C" locale, which orders strings by their bytes.
+        for (a, b, ord) in &[
+            ("a", "b", -1),
+            ("b", "a", 1),
+            ("a", "a", 0),
+            ("a\0b", "a\0c", -1),
+        ] {
+            assert_eq!(
+                cmp_locale(&Str::from(*a), &Str::from(*b)),
+                *ord,
+                "{:?} {:?}",
+                a,
+                b
+            );
+        }
+    }
+}
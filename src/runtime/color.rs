@@ -0,0 +1,147 @@
+//! `color` and `bold`.
+//!
+//! Both wrap a string in the ANSI escape codes that turn a style on and back off. Those codes make
+//! sense on a terminal but are noise in a file or a pipe, and whether a string ends up on one is
+//! only known when it is written. So once a program has called either function, every SGR
+//! sequence (`ESC [ ... m`) is removed from output going to a destination that is not a terminal.
+//! Standard output and the names of terminal devices (`/dev/tty`, `/dev/stderr`, and so on) are
+//! checked individually; commands are never terminals. Setting `NO_COLOR` in the environment
+//! removes the codes everywhere.
+use super::{str_impl::DynamicBuf, Str};
+use crate::common::Result;
+
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static USED: AtomicBool = AtomicBool::new(false);
+
+/// The names accepted by `color`, with their foreground SGR codes.
+const COLORS: &[(&str, u8)] = &[
+    ("black", 30),
+    ("red", 31),
+    ("green", 32),
+    ("yellow", 33),
+    ("blue", 34),
+    ("magenta", 35),
+    ("cyan", 36),
+    ("white", 37),
+    ("gray", 90),
+    ("grey", 90),
+];
+
+const BOLD: u8 = 1;
+const RESET: &[u8] = b"\x1b[0m";
+
+/// Whether the program has called `color` or `bold`, so that output needs checking for escape
+/// codes.
+pub(crate) fn used() -> bool {
+    USED.load(Ordering::Relaxed)
+}
+
+fn wrap<'a>(s: &Str<'a>, code: u8) -> Str<'a> {
+    USED.store(true, Ordering::Relaxed);
+    s.with_bytes(|bs| {
+        let mut buf = DynamicBuf::new(bs.len() + 9);
+        write!(buf, "\x1b[{}m", code).unwrap();
+        buf.write(bs).unwrap();
+        buf.write(RESET).unwrap();
+        unsafe { buf.into_str() }
+    })
+}
+
+/// `color(s, name)`: `s` in the foreground color `name`.
+pub(crate) fn color<'a>(s: &Str<'a>, name: &Str) -> Result<Str<'a>> {
+    let code = name.with_bytes(|name| {
+        COLORS
+            .iter()
+            .find(|(c, _)| c.as_bytes().eq_ignore_ascii_case(name))
+            .map(|(_, code)| *code)
+    });
+    match code {
+        Some(code) => Ok(wrap(s, code)),
+        None => err!(
+            "unknown color {:?}; expected one of black, red, green, yellow, blue, magenta, cyan, white or gray",
+            name.to_string()
+        ),
+    }
+}
+
+/// `bold(s)`: `s` in bold.
+pub(crate) fn bold<'a>(s: &Str<'a>) -> Str<'a> {
+    wrap(s, BOLD)
+}
+
+/// Whether escape codes written to `path` (standard output if it is None) will reach a terminal.
+pub(crate) fn is_terminal(path: Option<&str>) -> bool {
+    if std::env::var_os("NO_COLOR").is_some() {
+        return false;
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return io::stdout().is_terminal(),
+    };
+    match path {
+        "/dev/stdout" => io::stdout().is_terminal(),
+        "/dev/stderr" => io::stderr().is_terminal(),
+        "/dev/tty" => true,
+        _ => path.starts_with("/dev/pts/"),
+    }
+}
+
+/// The length of the SGR sequence at the start of `bs`, if there is one.
+fn sgr_len(bs: &[u8]) -> Option<usize> {
+    if !bs.starts_with(b"\x1b[") {
+        return None;
+    }
+    let params = bs[2..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit() || **b == b';')
+        .count();
+    if bs.get(2 + params) == Some(&b'm') {
+        Some(3 + params)
+    } else {
+        None
+    }
+}
+
+/// `s` without any SGR sequences.
+pub(crate) fn strip<'a>(s: &Str<'a>) -> Str<'a> {
+    s.with_bytes(|bs| {
+        if memchr::memchr(0x1b, bs).is_none() {
+            return s.clone();
+        }
+        let mut buf = DynamicBuf::new(bs.len());
+        let mut start = 0;
+        let mut i = 0;
+        while let Some(ix) = memchr::memchr(0x1b, &bs[i..]) {
+            i += ix;
+            match sgr_len(&bs[i..]) {
+                Some(len) => {
+                    buf.write(&bs[start..i]).unwrap();
+                    i += len;
+                    start = i;
+                }
+                None => i += 1,
+            }
+        }
+        buf.write(&bs[start..]).unwrap();
+        unsafe { buf.into_str() }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_and_strip() {
+        let s = Str::from("hi");
+        let red = color(&s, &Str::from("Red")).unwrap();
+        assert_eq!(red, Str::from("\x1b[31mhi\x1b[0m"));
+        assert_eq!(bold(&red), Str::from("\x1b[1m\x1b[31mhi\x1b[0m\x1b[0m"));
+        assert!(color(&s, &Str::from("mauve")).is_err());
+        assert_eq!(strip(&bold(&red)), s);
+        let other = Str::from("a\x1b[2Jb\x1b[0;1mc\x1b");
+        assert_eq!(strip(&other), Str::from("a\x1b[2Jbc\x1b"));
+    }
+}
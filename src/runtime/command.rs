@@ -1,9 +1,41 @@
-use std::io;
-use std::process::{ChildStdin, ChildStdout, Command, Stdio};
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
 
 use crate::runtime::Int;
 
-fn prepare_command(bs: &[u8]) -> io::Result<Command> {
+lazy_static! {
+    static ref PREPROCESSOR: Mutex<Option<String>> = Mutex::new(None);
+}
+
+static STRIP_CR: AtomicBool = AtomicBool::new(cfg!(windows));
+
+/// Pipe each input file through `cmd` before reading it, as set with `--pre`.
+pub fn set_preprocessor(cmd: &str) {
+    *PREPROCESSOR.lock().unwrap() = Some(cmd.into());
+}
+
+/// Whether to remove the carriage return from CRLF line endings in the main input (`--crlf`). This
+/// is the default on Windows.
+pub fn set_strip_cr(strip: bool) {
+    STRIP_CR.store(strip, Ordering::Relaxed);
+}
+
+/// `r`, with CRLF line endings turned into LF if `set_strip_cr` asks for it.
+pub fn line_endings(r: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+    if STRIP_CR.load(Ordering::Relaxed) {
+        Box::new(StripCr::new(r))
+    } else {
+        r
+    }
+}
+
+pub(crate) fn prepare_command(bs: &[u8]) -> io::Result<Command> {
     let prog = match std::str::from_utf8(bs) {
         Ok(s) => s,
         Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
@@ -33,6 +65,25 @@ pub fn run_command(bs: &[u8]) -> Int {
     }
 }
 
+/// Run `bs` to completion and return its standard output with any trailing newlines removed, as
+/// `$(...)` does in the shell. Returns nothing if the command could not be started.
+pub fn read_command(bs: &[u8]) -> Vec<u8> {
+    let output = match prepare_command(bs).and_then(|mut cmd| {
+        cmd.stdin(Stdio::inherit())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .output()
+    }) {
+        Ok(output) => output,
+        Err(_) => return Vec::new(),
+    };
+    let mut out = output.stdout;
+    while out.last() == Some(&b'\n') {
+        out.pop();
+    }
+    out
+}
+
 pub fn command_for_write(bs: &[u8]) -> io::Result<ChildStdin> {
     let mut cmd = prepare_command(bs)?;
     let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::inherit()).spawn()?;
@@ -44,3 +95,163 @@ pub fn command_for_read(bs: &[u8]) -> io::Result<ChildStdout> {
     let mut child = cmd.stdin(Stdio::inherit()).stdout(Stdio::piped()).spawn()?;
     Ok(child.stdout.take().unwrap())
 }
+
+/// Open the input file `path` for reading, through the `--pre` command if there is one.
+pub fn open_input(path: &str) -> io::Result<Box<dyn Read + Send>> {
+    let pre = PREPROCESSOR.lock().unwrap().clone();
+    Ok(line_endings(match pre {
+        Some(pre) => Box::new(Preprocessed::spawn(&pre, path)?),
+        None => Box::new(File::open(path)?),
+    }))
+}
+
+/// A reader that drops the carriage return from each CRLF in the underlying reader.
+struct StripCr<R> {
+    inner: R,
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+    // Whether the last read ended in a carriage return, which is only passed on if the next byte
+    // is not a newline.
+    held_cr: bool,
+}
+
+impl<R> StripCr<R> {
+    fn new(inner: R) -> StripCr<R> {
+        StripCr {
+            inner,
+            buf: vec![0; 64 << 10].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            held_cr: false,
+        }
+    }
+}
+
+impl<R: Read> Read for StripCr<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.start == self.end {
+            // Read past the first byte, leaving room to put back a held carriage return.
+            let n = self.inner.read(&mut self.buf[1..])?;
+            let first = if mem::replace(&mut self.held_cr, false) {
+                self.buf[0] = b'\r';
+                0
+            } else {
+                1
+            };
+            let end = n + 1;
+            if n == 0 {
+                self.start = first;
+                self.end = end;
+                break;
+            }
+            let mut w = 0;
+            for r in first..end {
+                let b = self.buf[r];
+                if b == b'\r' {
+                    if r + 1 == end {
+                        self.held_cr = true;
+                        continue;
+                    }
+                    if self.buf[r + 1] == b'\n' {
+                        continue;
+                    }
+                }
+                self.buf[w] = b;
+                w += 1;
+            }
+            self.start = 0;
+            self.end = w;
+        }
+        let n = out.len().min(self.end - self.start);
+        out[..n].copy_from_slice(&self.buf[self.start..self.start + n]);
+        self.start += n;
+        Ok(n)
+    }
+}
+
+/// The output of a `--pre` command for a single input file.
+///
+/// The command is waited for once its output has been read, and an error is returned if it
+/// failed. A command whose output is abandoned early, because the program exited before reading
+/// all of it, is killed when the reader is dropped. Either way, no zombie processes are left
+/// behind.
+struct Preprocessed {
+    child: Child,
+    stdout: ChildStdout,
+    path: String,
+    done: bool,
+}
+
+impl Preprocessed {
+    fn spawn(pre: &str, path: &str) -> io::Result<Preprocessed> {
+        // The file is passed as an argument to the shell, rather than pasted into the command, so
+        // that it needs no quoting.
+        let mut cmd = if cfg!(target_os = "windows") {
+            prepare_command(format!("{} \"{}\"", pre, path).as_bytes())?
+        } else {
+            let mut cmd = prepare_command(format!("{} \"$1\"", pre).as_bytes())?;
+            cmd.args(&["sh", path]);
+            cmd
+        };
+        let mut child = cmd.stdin(Stdio::null()).stdout(Stdio::piped()).spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(Preprocessed {
+            child,
+            stdout,
+            path: path.into(),
+            done: false,
+        })
+    }
+}
+
+impl Read for Preprocessed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && buf.len() > 0 && !self.done {
+            self.done = true;
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("preprocessing '{}' failed: {}", self.path, status),
+                ));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Preprocessed {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_cr() {
+        // Each piece is returned by a separate read, so CRLFs are split across reads.
+        struct Pieces(Vec<&'static [u8]>);
+        impl Read for Pieces {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Ok(0);
+                }
+                let piece = self.0.remove(0);
+                buf[..piece.len()].copy_from_slice(piece);
+                Ok(piece.len())
+            }
+        }
+        let pieces = Pieces(vec![b"a\r\nb\r", b"\nc\rd\r", b"\r", b"\r\n", b"e\r"]);
+        let mut out = Vec::new();
+        StripCr::new(pieces).read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"a\nb\nc\rd\r\r\ne\r");
+    }
+}
@@ -0,0 +1,157 @@
+//! Behaviors that differ between frawk and gawk, enabled with `--compat=gawk`.
+//!
+//! The setting is process-wide: it is fixed before the program is compiled and never changes
+//! afterwards. It affects two things:
+//!
+//! * Converting floating-point numbers to strings. Both print integral values as integers. For
+//!   everything else, frawk prints the shortest string that parses back to the same number (so
+//!   `0.1 + 0.2` is printed as `0.30000000000000004`); gawk uses `%.6g` (the default `CONVFMT` and
+//!   `OFMT`).
+//! * Comparisons involving strings. frawk converts a string compared with a number to a number,
+//!   and always compares two strings as strings. gawk compares two values numerically if they
+//!   both "look numeric", and as strings otherwise. frawk does not track whether a string came
+//!   from input or from a string constant, so constants that look numeric are compared as
+//!   numbers as well.
+use super::{Float, Str};
+
+use std::cmp::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering as MemOrdering};
+
+static GAWK: AtomicBool = AtomicBool::new(false);
+
+/// Turn on gawk-compatible behavior. This must be called before compiling a program.
+pub(crate) fn enable_gawk() {
+    GAWK.store(true, MemOrdering::Relaxed);
+}
+
+/// Whether gawk-compatible behavior is enabled.
+#[inline(always)]
+pub(crate) fn gawk() -> bool {
+    GAWK.load(MemOrdering::Relaxed)
+}
+
+/// Format `f` the way gawk does when converting a number to a string.
+pub(crate) fn float_to_str<'a>(f: Float) -> Str<'a> {
+    let s = if f.is_nan() {
+        if f.is_sign_negative() { "-nan" } else { "+nan" }.to_string()
+    } else if f.is_infinite() {
+        if f < 0.0 { "-inf" } else { "+inf" }.to_string()
+    } else if f.fract() == 0.0 {
+        format!("{:.0}", f)
+    } else {
+        format_g(f, 6)
+    };
+    Str::from(s)
+}
+
+/// Format `f` like C's `%.{prec}g`: use scientific notation if the exponent is less than -4 or
+/// at least `prec`, fixed notation otherwise, and drop trailing zeros in either case.
+pub(crate) fn format_g(f: Float, prec: usize) -> String {
+    let prec = prec.max(1);
+    // Get the exponent after rounding to `prec` significant digits.
+    let sci = format!("{:.*e}", prec - 1, f);
+    let epos = sci.find('e').unwrap();
+    let exp: i32 = sci[epos + 1..].parse().unwrap();
+    if exp < -4 || exp >= prec as i32 {
+        let mantissa = strip_zeros(&sci[..epos]);
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    } else {
+        strip_zeros(&format!("{:.*}", (prec as i32 - 1 - exp) as usize, f)).to_string()
+    }
+}
+
+fn strip_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Whether `bs` looks like a number: optional blanks, an optional sign, a decimal number with an
+/// optional exponent, and optional blanks.
+pub(crate) fn looks_numeric(bs: &[u8]) -> bool {
+    let is_blank = |b: &u8| *b == b' ' || *b == b'\t' || *b == b'\n';
+    let start = bs.iter().position(|b| !is_blank(b)).unwrap_or(bs.len());
+    let end = bs
+        .iter()
+        .rposition(|b| !is_blank(b))
+        .map_or(start, |i| i + 1);
+    let bs = &bs[start..end];
+    let mut i = 0;
+    let digits = |i: &mut usize| {
+        let start = *i;
+        while *i < bs.len() && bs[*i].is_ascii_digit() {
+            *i += 1;
+        }
+        *i - start
+    };
+    if i < bs.len() && (bs[i] == b'+' || bs[i] == b'-') {
+        i += 1;
+    }
+    let mut mantissa = digits(&mut i);
+    if i < bs.len() && bs[i] == b'.' {
+        i += 1;
+        mantissa += digits(&mut i);
+    }
+    if mantissa == 0 {
+        return false;
+    }
+    if i < bs.len() && (bs[i] == b'e' || bs[i] == b'E') {
+        i += 1;
+        if i < bs.len() && (bs[i] == b'+' || bs[i] == b'-') {
+            i += 1;
+        }
+        if digits(&mut i) == 0 {
+            return false;
+        }
+    }
+    i == bs.len()
+}
+
+/// Compare two strings. If gawk compatibility is enabled and both strings look numeric, they are
+/// compared as numbers.
+pub(crate) fn compare_strs(l: &Str, r: &Str) -> Ordering {
+    l.with_bytes(|l| {
+        r.with_bytes(|r| {
+            if gawk() && looks_numeric(l) && looks_numeric(r) {
+                if let Some(ord) = super::strtod(l).partial_cmp(&super::strtod(r)) {
+                    return ord;
+                }
+            }
+            l.cmp(r)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number_formatting() {
+        let fmt = |f: Float| {
+            let s: Str = float_to_str(f);
+            s.to_string()
+        };
+        assert_eq!(fmt(7.0), "7");
+        assert_eq!(fmt(-0.0), "-0");
+        assert_eq!(fmt(0.1 + 0.2), "0.3");
+        assert_eq!(fmt(3.14159265), "3.14159");
+        assert_eq!(fmt(1234567.5), "1.23457e+06");
+        assert_eq!(fmt(0.0000123), "1.23e-05");
+        assert_eq!(fmt(1e30), "1000000000000000019884624838656");
+        assert_eq!(fmt(1.0 / 0.0), "+inf");
+    }
+
+    #[test]
+    fn numeric_strings() {
+        for s in &["1", " 1 ", "-1.5", "+.5", "1e5", "1E-5", "2.", "\t10\n"] {
+            assert!(looks_numeric(s.as_bytes()), "{:?}", s);
+        }
+        for s in &["", " ", "abc", "1a", "1e", ".", "-", "0x10", "1 2"] {
+            assert!(!looks_numeric(s.as_bytes()), "{:?}", s);
+        }
+    }
+}
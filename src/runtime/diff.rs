@@ -0,0 +1,235 @@
+//! Unified diffs of the changes `-i inplace` would make to a file, for `--diff`.
+//!
+//! The output follows `diff -u`: a `---`/`+++` header naming the file, followed by hunks of
+//! changed lines with three lines of context on either side. Lines are compared as bytes, so
+//! files need not be valid UTF-8.
+
+/// How many unchanged lines to show around each change.
+const CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    // A line of the old text (by index), kept in the new one.
+    Equal(usize),
+    // A line of the old text that was removed.
+    Delete(usize),
+    // A line of the new text that was added.
+    Insert(usize),
+}
+
+/// Split `text` into lines, keeping the newline at the end of each.
+fn lines(text: &[u8]) -> Vec<&[u8]> {
+    let mut res = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let end = match memchr::memchr(b'\n', &text[start..]) {
+            Some(ix) => start + ix + 1,
+            None => text.len(),
+        };
+        res.push(&text[start..end]);
+        start = end;
+    }
+    res
+}
+
+/// The shortest edit script turning `a` into `b`, using Myers' algorithm. Indexes are relative to
+/// the start of each slice.
+fn shortest_edit(a: &[&[u8]], b: &[&[u8]]) -> Vec<Op> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m) as usize;
+    let off = max as isize;
+    // v[k + off] is the furthest x reached on diagonal k = x - y so far.
+    let mut v = vec![0isize; 2 * max + 2];
+    let mut trace = Vec::new();
+    'search: for d in 0..=max as isize {
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let ix = (k + off) as usize;
+            let mut x = if k == -d || (k != d && v[ix - 1] < v[ix + 1]) {
+                v[ix + 1]
+            } else {
+                v[ix - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[ix] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+            k += 2;
+        }
+    }
+    let mut ops = Vec::with_capacity(max);
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let ix = (k + off) as usize;
+        let prev_k = if k == -d || (k != d && v[ix - 1] < v[ix + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(prev_k + off) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            ops.push(Op::Equal(x as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                ops.push(Op::Insert(prev_y as usize));
+            } else {
+                ops.push(Op::Delete(prev_x as usize));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+/// The edit script turning `a` into `b`. Lines common to the start or end of both are matched up
+/// before searching for the shortest script for what remains, which is usually much smaller.
+fn edit_script(a: &[&[u8]], b: &[&[u8]]) -> Vec<Op> {
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let mut ops: Vec<Op> = (0..prefix).map(Op::Equal).collect();
+    let middle = shortest_edit(&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    ops.extend(middle.into_iter().map(|op| match op {
+        Op::Equal(i) => Op::Equal(i + prefix),
+        Op::Delete(i) => Op::Delete(i + prefix),
+        Op::Insert(j) => Op::Insert(j + prefix),
+    }));
+    ops.extend((a.len() - suffix..a.len()).map(Op::Equal));
+    ops
+}
+
+/// A hunk header range: the first line (counting from 1) and the number of lines. An empty range
+/// starts at the line before it.
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+fn push_line(out: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    out.push(prefix);
+    out.extend_from_slice(line);
+    if line.last() != Some(&b'\n') {
+        out.extend_from_slice(b"\n\\ No newline at end of file\n");
+    }
+}
+
+/// A unified diff from `old` to `new`, the old and new contents of the file `name`. This is empty
+/// if they are the same.
+pub(crate) fn unified(name: &str, old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if old == new {
+        return out;
+    }
+    let (a, b) = (lines(old), lines(new));
+    let ops = edit_script(&a, &b);
+    out.extend_from_slice(format!("--- {}\n+++ {}\n", name, name).as_bytes());
+    // The position of each op in the old and new text.
+    let mut pos = Vec::with_capacity(ops.len());
+    let (mut i, mut j) = (0, 0);
+    for op in ops.iter() {
+        pos.push((i, j));
+        match op {
+            Op::Equal(_) => {
+                i += 1;
+                j += 1;
+            }
+            Op::Delete(_) => i += 1,
+            Op::Insert(_) => j += 1,
+        }
+    }
+    let changes: Vec<usize> = (0..ops.len())
+        .filter(|ix| !matches!(ops[*ix], Op::Equal(_)))
+        .collect();
+    let mut c = 0;
+    while c < changes.len() {
+        // Extend the hunk for as long as the next change is close enough for the context around
+        // them to meet.
+        let mut last = c;
+        while last + 1 < changes.len() && changes[last + 1] - changes[last] <= 2 * CONTEXT + 1 {
+            last += 1;
+        }
+        let start = changes[c].saturating_sub(CONTEXT);
+        let end = (changes[last] + CONTEXT + 1).min(ops.len());
+        let (old_start, new_start) = pos[start];
+        let old_len = ops[start..end]
+            .iter()
+            .filter(|op| !matches!(op, Op::Insert(_)))
+            .count();
+        let new_len = ops[start..end]
+            .iter()
+            .filter(|op| !matches!(op, Op::Delete(_)))
+            .count();
+        out.extend_from_slice(
+            format!(
+                "@@ -{} +{} @@\n",
+                range(old_start, old_len),
+                range(new_start, new_len)
+            )
+            .as_bytes(),
+        );
+        for op in ops[start..end].iter() {
+            match *op {
+                Op::Equal(i) => push_line(&mut out, b' ', a[i]),
+                Op::Delete(i) => push_line(&mut out, b'-', a[i]),
+                Op::Insert(j) => push_line(&mut out, b'+', b[j]),
+            }
+        }
+        c = last + 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(old: &str, new: &str) -> String {
+        String::from_utf8(unified("f", old.as_bytes(), new.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn single_change() {
+        assert_eq!(diff("a\nb\nc\n", "a\nb\nc\n"), "");
+        assert_eq!(
+            diff("a\nb\nc\n", "a\nB\nc\n"),
+            "--- f\n+++ f\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n"
+        );
+        assert_eq!(
+            diff("", "x"),
+            "--- f\n+++ f\n@@ -0,0 +1 @@\n+x\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn separate_hunks() {
+        let old: String = (1..=20).map(|i| format!("{}\n", i)).collect();
+        let new = old.replacen("2\n", "two\n", 1).replace("19\n", "X\n");
+        assert_eq!(
+            diff(&old, &new),
+            "--- f\n+++ f\n\
+             @@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+             @@ -16,5 +16,5 @@\n 16\n 17\n 18\n-19\n+X\n 20\n"
+        );
+    }
+}
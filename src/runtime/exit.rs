@@ -0,0 +1,74 @@
+//! The exit status set by `exit n`.
+//!
+//! `exit` does not stop frawk right away: END, the functions registered with `atexit`, `--persist`
+//! and `--stats` all run first. The status is recorded here and returned by `main` once the
+//! program has finished.
+//!
+//! Outside of functions, `exit` jumps straight to the end of the BEGIN block and main loop, or of
+//! END. Anywhere else it cannot jump there directly: inside a function, or in a parallel main loop
+//! whose other workers have to stop as well. There, `exit` sets the flag read by `exiting`, and the
+//! code it returns to checks the flag after each call and unwinds until it reaches the end of the
+//! phase. END clears the flag again, unless a second signal arrived; see `runtime::signal`.
+//!
+//! With `--match-status`, a program that does not call `exit n` exits with status 1 unless one of
+//! its rules' actions ran or it printed something, as `grep` does when nothing matches.
+use crate::runtime::Int;
+
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+static STATUS: AtomicI32 = AtomicI32::new(0);
+// Whether `exit n` has been called.
+static SET: AtomicBool = AtomicBool::new(false);
+static MATCH_STATUS: AtomicBool = AtomicBool::new(false);
+static MATCHED: AtomicBool = AtomicBool::new(false);
+static EXITING: AtomicBool = AtomicBool::new(false);
+
+/// Record `status` as the exit status of the process. Like other Awks, only the low 8 bits are
+/// kept. Returns the status that was recorded.
+pub(crate) fn set_status(status: Int) -> Int {
+    let status = (status & 0xff) as i32;
+    STATUS.store(status, Ordering::Relaxed);
+    SET.store(true, Ordering::Relaxed);
+    status as Int
+}
+
+/// Exit with status 1 if nothing matched; see `set_matched`.
+pub fn enable_match_status() {
+    MATCH_STATUS.store(true, Ordering::Relaxed);
+}
+
+/// Whether `--match-status` was passed.
+pub(crate) fn match_status() -> bool {
+    MATCH_STATUS.load(Ordering::Relaxed)
+}
+
+/// Record that a rule's action ran, or that the program wrote some output.
+pub(crate) fn set_matched() {
+    MATCHED.store(true, Ordering::Relaxed);
+}
+
+/// The exit status recorded by the last `exit n`, or 0 (1 with `--match-status`, if nothing
+/// matched).
+pub(crate) fn status() -> i32 {
+    if match_status() && !SET.load(Ordering::Relaxed) && !MATCHED.load(Ordering::Relaxed) {
+        return 1;
+    }
+    STATUS.load(Ordering::Relaxed)
+}
+
+/// Whether an `exit` is unwinding to the end of the current phase.
+pub(crate) fn exiting() -> Int {
+    EXITING.load(Ordering::Relaxed) as Int
+}
+
+/// Start unwinding (`n` = 1) or stop (`n` = 0). Stopping has no effect once a second signal has
+/// asked frawk to stop, so that END does not run; `n` = -1 stops all the same, for running the
+/// functions registered with `atexit`. Returns `exiting()`.
+pub(crate) fn set_exiting(n: Int) -> Int {
+    match n {
+        1 => EXITING.store(true, Ordering::Relaxed),
+        0 if super::signal::stopping() => {}
+        _ => EXITING.store(false, Ordering::Relaxed),
+    }
+    exiting()
+}
@@ -11,8 +11,23 @@ fn unlikely(b: bool) -> bool {
 }
 
 use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
 mod slow_path;
 
+static NON_DECIMAL_DATA: AtomicBool = AtomicBool::new(false);
+
+/// Recognize numbers written with a `0x`, `0o` or `0b` prefix when converting strings to numbers
+/// (see `radixtoi`). This must be called before compiling a program.
+pub(crate) fn enable_non_decimal_data() {
+    NON_DECIMAL_DATA.store(true, Ordering::Relaxed);
+}
+
+/// Whether conversions from strings to numbers recognize `0x`, `0o` and `0b` prefixes.
+#[inline(always)]
+pub(crate) fn non_decimal_data() -> bool {
+    NON_DECIMAL_DATA.load(Ordering::Relaxed)
+}
+
 /// The simdjson repo has more optimizations to add for int parsing, but this is a big win over libc
 /// for the time being, if only because we do not have to copy `s` into a NUL-terminated
 /// representation.
@@ -74,6 +89,45 @@ pub fn hextoi(mut bs: &[u8]) -> i64 {
     }
 }
 
+/// Parse an integer written in hexadecimal (`0x1f`), octal (`0o17`) or binary (`0b101`), with an
+/// optional sign. Returns `None` if `bs` does not start with one of these prefixes followed by at
+/// least one digit. As with `strtoi`, parsing stops at the first byte that is not a digit.
+///
+/// The digits are read as the bits of an unsigned 64-bit integer, so that `0xffffffffffffffff` is
+/// -1; values that do not fit in 64 bits are 0.
+pub fn radixtoi(bs: &[u8]) -> Option<i64> {
+    let (neg, bs) = match bs {
+        [b'-', rest @ ..] => (true, rest),
+        [b'+', rest @ ..] => (false, rest),
+        _ => (false, bs),
+    };
+    let (radix, bits) = match bs {
+        [b'0', b'x', ..] | [b'0', b'X', ..] => (16, 4),
+        [b'0', b'o', ..] | [b'0', b'O', ..] => (8, 3),
+        [b'0', b'b', ..] | [b'0', b'B', ..] => (2, 1),
+        _ => return None,
+    };
+    let mut i = 0u64;
+    let mut digits = 0;
+    for b in bs[2..].iter().cloned() {
+        let digit = match (b as char).to_digit(radix) {
+            Some(d) => d as u64,
+            None => break,
+        };
+        if i.leading_zeros() < bits {
+            // overflow
+            return Some(0);
+        }
+        i = (i << bits) | digit;
+        digits += 1;
+    }
+    if digits == 0 {
+        return None;
+    }
+    let i = i as i64;
+    Some(if neg { i.wrapping_neg() } else { i })
+}
+
 // And now, for floats
 
 // This algorithm can be thought of as having two parts, "parsing" and "projection". The parsing
@@ -376,6 +430,20 @@ mod tests {
         assert_eq!(strtod(imax.as_bytes()), i64::max_value() as f64);
         assert_eq!(strtod(imin.as_bytes()), i64::min_value() as f64);
     }
+
+    #[test]
+    fn prefixed_integers() {
+        assert_eq!(radixtoi(b"0x1F"), Some(31));
+        assert_eq!(radixtoi(b"-0o17"), Some(-15));
+        assert_eq!(radixtoi(b"+0b101x"), Some(5));
+        assert_eq!(radixtoi(b"0b102"), Some(2));
+        assert_eq!(radixtoi(b"0xffffffffffffffff"), Some(-1));
+        assert_eq!(radixtoi(b"0x10000000000000000"), Some(0));
+        assert_eq!(radixtoi(b"0x"), None);
+        assert_eq!(radixtoi(b"0o8"), None);
+        assert_eq!(radixtoi(b"17"), None);
+        assert_eq!(radixtoi(b""), None);
+    }
 }
 
 // What follows is a bunch of precomputed values for powers of 10; it's unlikely to be very
@@ -0,0 +1,236 @@
+//! Histograms, for the `hist_*` builtins.
+//!
+//! A histogram is an ordinary frawk array mapping the index of each bucket to the number of values
+//! that fell into it. With linear buckets of width `w`, bucket `i` counts the values in
+//! `[i*w, (i+1)*w)`; with logarithmic buckets of base `w`, it counts the values in
+//! `[w^i, w^(i+1))`, and values that are zero or negative are counted in a bucket of their own.
+//! The first call to `hist_add` chooses the buckets, and stores them under keys that are too small
+//! to be the index of a bucket.
+use crate::common::Result;
+use crate::runtime::{compat, Float, Int, IntMap, Str};
+
+use hashbrown::HashMap;
+
+/// The bit pattern of the width (or base) of the buckets.
+const WIDTH: Int = Int::min_value() + 1;
+/// Whether the buckets are logarithmic.
+const LOG: Int = Int::min_value() + 2;
+/// The bucket for values that are zero or negative, in a logarithmic histogram.
+const NONPOSITIVE: Int = Int::min_value() + 3;
+const MIN_BUCKET: Int = Int::min_value() + 4;
+
+/// The width of the bars printed for the fullest bucket.
+const BAR_WIDTH: Int = 40;
+
+/// The width and kind of the buckets of `h`, if any value has been added to it.
+fn buckets(h: &IntMap<Int>) -> Option<(Float, bool)> {
+    let width = Float::from_bits(h.get(&WIDTH)? as u64);
+    Some((width, h.get(&LOG).unwrap_or(0) != 0))
+}
+
+/// `q.floor()`, except that values within rounding error of an integer are rounded to it: 0.3/0.1
+/// and log_10(1000) come out just below 3, but belong in bucket 3.
+fn floor(q: Float) -> Int {
+    let r = q.round();
+    if (q - r).abs() <= 1e-9 * r.abs().max(1.0) {
+        r as Int
+    } else {
+        q.floor() as Int
+    }
+}
+
+/// The index of the bucket that `x` falls into.
+fn bucket(x: Float, width: Float, log: bool) -> Int {
+    let i = if !log {
+        floor(x / width)
+    } else if x <= 0.0 {
+        return NONPOSITIVE;
+    } else {
+        floor(x.ln() / width.ln())
+    };
+    std::cmp::max(i, MIN_BUCKET)
+}
+
+/// `hist_add(h, x, w, log)`: count `x` in the histogram `h`, whose buckets are `w` wide, or are
+/// the powers of `w` if `log` is nonzero. Returns the number of values in `x`'s bucket.
+pub(crate) fn add(h: &IntMap<Int>, x: Float, w: Float, log: Int) -> Result<Int> {
+    let (width, log) = match buckets(h) {
+        Some(b) => b,
+        None => {
+            let log = log != 0;
+            if !(w > 0.0 && w.is_finite()) || (log && w <= 1.0) {
+                let kind = if log { "base" } else { "width" };
+                return err!("hist_add: invalid bucket {} {}", kind, w);
+            }
+            h.insert(WIDTH, w.to_bits() as Int);
+            h.insert(LOG, log as Int);
+            (w, log)
+        }
+    };
+    if x.is_nan() {
+        return err!("hist_add: cannot add NaN to a histogram");
+    }
+    let i = bucket(x, width, log);
+    let count = h.get(&i).unwrap_or(0) + 1;
+    h.insert(i, count);
+    Ok(count)
+}
+
+fn fmt_bound(f: Float) -> String {
+    if f.fract() == 0.0 && f.abs() < 1e16 {
+        format!("{:.0}", f)
+    } else {
+        compat::format_g(f, 6)
+    }
+}
+
+/// `hist_format(h)`: one line for each nonempty bucket of `h`, in order, with its range, its count
+/// and a bar of `#`s proportional to the count. Ranges, counts and bars are aligned.
+pub(crate) fn format<'a>(h: &IntMap<Int>) -> Str<'a> {
+    let (width, log) = match buckets(h) {
+        Some(b) => b,
+        None => return Str::default(),
+    };
+    let mut keys: Vec<Int> = h
+        .to_vec()
+        .into_iter()
+        .filter(|k| *k >= MIN_BUCKET || (log && *k == NONPOSITIVE))
+        .collect();
+    keys.sort_unstable();
+    let rows: Vec<(String, Int)> = keys
+        .into_iter()
+        .map(|i| {
+            let range = if i == NONPOSITIVE {
+                "(-inf, 0]".to_string()
+            } else if log {
+                let lo = width.powf(i as Float);
+                format!("[{}, {})", fmt_bound(lo), fmt_bound(lo * width))
+            } else {
+                let lo = i as Float * width;
+                format!("[{}, {})", fmt_bound(lo), fmt_bound(lo + width))
+            };
+            (range, h.get(&i).unwrap_or(0))
+        })
+        .collect();
+    let range_width = rows.iter().map(|(r, _)| r.len()).max().unwrap_or(0);
+    let count_width = rows
+        .iter()
+        .map(|(_, c)| c.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let most = rows.iter().map(|(_, c)| *c).max().unwrap_or(0);
+    let mut res = String::new();
+    for (range, count) in rows {
+        // Every nonempty bucket gets at least one `#`.
+        let bar = if count <= 0 {
+            0
+        } else {
+            std::cmp::max(count.saturating_mul(BAR_WIDTH) / most, 1)
+        };
+        res.push_str(&format!(
+            "{:<rw$} {:>cw$} {}\n",
+            range,
+            count,
+            "#".repeat(bar as usize),
+            rw = range_width,
+            cw = count_width
+        ));
+    }
+    Str::from(res)
+}
+
+/// Merge the histogram `other` into `h`, if they have the same buckets; otherwise return `other`
+/// to be merged like any other array.
+pub(crate) fn merge(
+    h: &mut HashMap<Int, Int>,
+    other: HashMap<Int, Int>,
+) -> Option<HashMap<Int, Int>> {
+    match (h.get(&WIDTH), other.get(&WIDTH)) {
+        (Some(w1), Some(w2)) if w1 == w2 && h.get(&LOG) == other.get(&LOG) => {}
+        _ => return Some(other),
+    }
+    for (k, count) in other {
+        if k != WIDTH && k != LOG {
+            *h.entry(k).or_insert(0) += count;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hist(xs: &[Float], w: Float, log: Int) -> IntMap<Int> {
+        let h = IntMap::default();
+        for x in xs {
+            add(&h, *x, w, log).unwrap();
+        }
+        h
+    }
+
+    fn to_map(h: &IntMap<Int>) -> HashMap<Int, Int> {
+        h.to_vec()
+            .into_iter()
+            .map(|k| (k, h.get(&k).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn linear_buckets() {
+        let h = hist(&[1.0, 12.5, 15.0, 19.9, -3.0, 20.0, 31.0], 10.0, 0);
+        // The buckets are fixed by the first call.
+        assert_eq!(add(&h, 11.0, 1.0, 1).unwrap(), 4);
+        assert_eq!(
+            format(&h).to_string(),
+            "[-10, 0) 1 ##########\n\
+             [0, 10)  1 ##########\n\
+             [10, 20) 4 ########################################\n\
+             [20, 30) 1 ##########\n\
+             [30, 40) 1 ##########\n"
+        );
+        let h = hist(&[0.25, 0.3, 0.45], 0.1, 0);
+        assert_eq!(
+            format(&h).to_string(),
+            "[0.2, 0.3) 1 ########################################\n\
+             [0.3, 0.4) 1 ########################################\n\
+             [0.4, 0.5) 1 ########################################\n"
+        );
+    }
+
+    #[test]
+    fn log_buckets() {
+        let h = hist(&[1000.0, 999.0, 5.0, 0.5, 0.0, -1.0, 1.0], 10.0, 1);
+        assert_eq!(
+            format(&h).to_string(),
+            "(-inf, 0]     2 ########################################\n\
+             [0.1, 1)      1 ####################\n\
+             [1, 10)       2 ########################################\n\
+             [100, 1000)   1 ####################\n\
+             [1000, 10000) 1 ####################\n"
+        );
+    }
+
+    #[test]
+    fn invalid_buckets() {
+        let h = IntMap::default();
+        assert!(add(&h, 1.0, 0.0, 0).is_err());
+        assert!(add(&h, 1.0, 1.0, 1).is_err());
+        assert!(add(&h, Float::NAN, 1.0, 0).is_err());
+        assert_eq!(format(&IntMap::default()), Str::default());
+    }
+
+    #[test]
+    fn merge_histograms() {
+        let h1 = hist(&[1.0, 2.0, 15.0], 10.0, 0);
+        let h2 = hist(&[3.0, 25.0], 10.0, 0);
+        let mut m1 = to_map(&h1);
+        assert!(merge(&mut m1, to_map(&h2)).is_none());
+        assert_eq!((m1[&0], m1[&1], m1[&2]), (3, 1, 1));
+        assert_eq!(m1[&WIDTH], (10.0 as Float).to_bits() as Int);
+
+        // Histograms with different buckets are left alone.
+        let h3 = hist(&[3.0], 1.0, 0);
+        assert!(merge(&mut m1, to_map(&h3)).is_some());
+    }
+}
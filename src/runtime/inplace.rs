@@ -0,0 +1,137 @@
+//! In-place editing of the input files, for `-i inplace`.
+//!
+//! While the main input reads a file named on the command line, everything the program prints to
+//! standard output is written to a temporary file in the same directory instead. Once the input
+//! moves on to the next file, or ends, the temporary file is renamed over the file that was read,
+//! so that other readers see either the old contents or the new ones. With a backup suffix, the
+//! old contents are also kept in a file whose name is the input's name followed by the suffix.
+//!
+//! Output written in `BEGIN` and `END`, or while reading standard input, still goes to standard
+//! output. A file that the program stops reading part-way through, because it called `exit` or
+//! hit an error, is left as it was.
+//!
+//! With `--diff`, the files are left as they are, and a unified diff of the changes that would
+//! have been made to each one is printed to standard output instead.
+use super::{diff, Str};
+use crate::common::Result;
+
+use lazy_static::lazy_static;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DIFF: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref BACKUP_SUFFIX: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Whether `-i inplace` was passed.
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Whether `--diff` was passed.
+pub(crate) fn diff_only() -> bool {
+    DIFF.load(Ordering::Relaxed)
+}
+
+/// Replace each input file with what the program prints while reading it, keeping the original
+/// contents in a file with `backup_suffix` appended to its name, if there is one. With `diff`,
+/// print the changes instead of making them. This must be called before running a program.
+pub fn enable(backup_suffix: Option<String>, diff: bool) {
+    *BACKUP_SUFFIX.lock().unwrap() = backup_suffix;
+    DIFF.store(diff, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// An input file being edited in place.
+#[derive(Clone)]
+pub(crate) struct Edit {
+    target: String,
+    temp: Str<'static>,
+}
+
+impl Edit {
+    /// Start editing `input`, or return None if it does not name a file that can be replaced
+    /// (because it is standard input, or the input has ended).
+    pub(crate) fn start(input: &Str) -> Option<Edit> {
+        let target = input.with_bytes(|bs| String::from_utf8_lossy(bs).into_owned());
+        if target.is_empty() || target == "-" || target == "/dev/stdin" {
+            return None;
+        }
+        let path = Path::new(&target);
+        let name = path.file_name()?.to_string_lossy();
+        let temp = path.with_file_name(format!(".{}.frawk-{}", name, std::process::id()));
+        let temp = temp.to_string_lossy().into_owned();
+        // Left behind by an earlier run that was killed; output is appended to this file.
+        let _ = fs::remove_file(&temp);
+        Some(Edit {
+            target,
+            temp: Str::from(temp),
+        })
+    }
+
+    /// The file standard output is written to instead while the target is read.
+    pub(crate) fn temp(&self) -> &Str<'static> {
+        &self.temp
+    }
+
+    /// Replace the target with the temporary file, which must have been closed.
+    pub(crate) fn finish(self) -> Result<()> {
+        let temp = self.temp.to_string();
+        let res = self.replace(&temp);
+        if res.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        res
+    }
+
+    fn replace(&self, temp: &str) -> Result<()> {
+        let target = self.target.as_str();
+        // The temporary file is only created once something is written to it.
+        if let Err(e) = fs::OpenOptions::new().append(true).create(true).open(temp) {
+            return err!("failed to create a replacement for {}: {}", target, e);
+        }
+        if let Ok(meta) = fs::metadata(target) {
+            let _ = fs::set_permissions(temp, meta.permissions());
+        }
+        if let Some(suffix) = BACKUP_SUFFIX.lock().unwrap().as_ref() {
+            let backup = format!("{}{}", target, suffix);
+            let _ = fs::remove_file(&backup);
+            if fs::hard_link(target, &backup).is_err() {
+                if let Err(e) = fs::copy(target, &backup) {
+                    return err!("failed to back up {} to {}: {}", target, backup, e);
+                }
+            }
+        }
+        if let Err(e) = fs::rename(temp, target) {
+            return err!("failed to replace {}: {}", target, e);
+        }
+        Ok(())
+    }
+
+    /// A unified diff of the changes that replacing the target would make, leaving it as it was.
+    /// The temporary file must have been closed.
+    pub(crate) fn diff(self) -> Result<Vec<u8>> {
+        let temp = self.temp.to_string();
+        // As in `replace`, nothing may have been written to the temporary file.
+        let new = fs::read(&temp).unwrap_or_default();
+        let _ = fs::remove_file(&temp);
+        match fs::read(&self.target) {
+            Ok(old) => Ok(diff::unified(&self.target, &old, &new)),
+            Err(e) => err!("failed to read {}: {}", self.target, e),
+        }
+    }
+
+    /// Give up on editing the target, leaving it as it was.
+    pub(crate) fn abandon(self) {
+        eprintln_ignore!(
+            "frawk: not replacing {}: the program stopped before reading all of it",
+            self.target
+        );
+        let _ = fs::remove_file(self.temp.to_string());
+    }
+}
@@ -0,0 +1,110 @@
+//! Interning for array keys.
+//!
+//! Fields are slices of the buffer that the input was read into. Using one as a key in an array
+//! directly would keep that whole buffer alive for as long as the key is in the array, so new keys
+//! are copied into a buffer of their own first. A script like `{ N[$1]++; SUM[$1] += $2 }` over
+//! a low-cardinality column would still allocate a copy of the same key for each array it is added
+//! to (and again after it is deleted), so the copies are interned: every array holding a given key
+//! shares one allocation.
+//!
+//! The interner holds a reference to each string it hands out. Strings that only the interner
+//! refers to (because the arrays holding them were cleared or dropped) are reclaimed whenever the
+//! interner doubles in size.
+use super::Str;
+
+use hashbrown::HashSet;
+use std::cell::RefCell;
+use std::mem;
+
+/// The smallest size at which the interner is scanned for unused strings.
+const MIN_PRUNE: usize = 1024;
+
+struct Interner {
+    // Only strings with a heap buffer of their own are stored here, so the 'static lifetime is
+    // accurate.
+    strs: HashSet<Str<'static>>,
+    prune_at: usize,
+}
+
+impl Default for Interner {
+    fn default() -> Interner {
+        Interner {
+            strs: Default::default(),
+            prune_at: MIN_PRUNE,
+        }
+    }
+}
+
+impl Interner {
+    fn intern<'a>(&mut self, s: Str<'a>) -> Str<'a> {
+        if !s.is_heap() {
+            // Inline strings and literals do not allocate.
+            return s;
+        }
+        // Safety: we only store strings that own their buffers, and only hand out clones of those.
+        let s = unsafe { mem::transmute::<Str<'a>, Str<'static>>(s) };
+        let res = if let Some(interned) = self.strs.get(&s) {
+            interned.clone()
+        } else {
+            let compact = s.compact();
+            self.strs.insert(compact.clone());
+            if self.strs.len() >= self.prune_at {
+                self.prune();
+            }
+            compact
+        };
+        unsafe { mem::transmute::<Str<'static>, Str<'a>>(res) }
+    }
+
+    fn prune(&mut self) {
+        self.strs.retain(|s| !s.is_sole_reference());
+        self.prune_at = MIN_PRUNE.max(self.strs.len() * 2);
+    }
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Default::default());
+}
+
+/// Return a string equal to `s` that is suitable for storing as an array key.
+pub(crate) fn intern<'a>(s: Str<'a>) -> Str<'a> {
+    INTERNER.with(|i| i.borrow_mut().intern(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::str_impl::DynamicBuf;
+    use std::io::Write;
+
+    fn boxed(s: &str) -> Str<'static> {
+        let mut buf = DynamicBuf::new(s.len());
+        buf.write_all(s.as_bytes()).unwrap();
+        unsafe { buf.into_str() }
+    }
+
+    #[test]
+    fn shares_and_reclaims() {
+        let mut i = Interner::default();
+        let line = boxed("a line of input that is too long to be inlined\tand a second field");
+        let field = line.slice(0, 46);
+        let k1 = i.intern(field.clone());
+        let k2 = i.intern(field.clone());
+        assert_eq!(k1, field);
+        assert_eq!(i.strs.len(), 1);
+        // The keys share one buffer, separate from the line's.
+        drop(field);
+        assert!(line.is_sole_reference());
+        drop(k2);
+        assert!(!k1.is_sole_reference());
+        i.prune();
+        assert_eq!(i.strs.len(), 1);
+        drop(k1);
+        i.prune();
+        assert_eq!(i.strs.len(), 0);
+
+        // Short strings are not stored.
+        assert_eq!(i.intern(Str::from("short")), Str::from("short"));
+        assert_eq!(i.strs.len(), 0);
+    }
+}
@@ -0,0 +1,619 @@
+//! Regexes with fast paths for patterns that are (mostly) plain strings.
+//!
+//! A good portion of the regexes in Awk scripts are really string literals: `/ERROR/`, `$1 ~
+//! "^GET"`, `$NF ~ /\.json$/`. The regex crate already searches for literals efficiently, but it
+//! still has to go through the machinery of a full regex engine to do so. We recognize these
+//! patterns when they are compiled and answer `is_match` queries for them with a substring search
+//! (or a comparison against the start or end of the input). Patterns that begin with a literal
+//! string, like `/user[0-9]+/`, first check that the literal appears at all, which rules out most
+//! inputs for grep-like programs.
+//!
+//! Everything other than `is_match` (splitting, `match`, `sub`, ...) goes through the regex itself.
+//!
+//! The regex crate does not support lookaround or backreferences. When frawk is built with the
+//! `fancy_regex` feature and run with `--regex-engine=fancy`, patterns using them are compiled with
+//! the fancy-regex crate instead. Those patterns can be used for matching (`~`, `match`, `sub`,
+//! `gsub`) but not for splitting; inputs that are not valid UTF-8 never match them.
+//!
+//! Patterns may set flags inline, as in `(?i)error` or `(?x) \d+ - \d+`; a flag set at the start of
+//! a pattern applies to the whole pattern, which keeps it off the literal fast paths. By default,
+//! character classes (`\w`, `\d`, `\s`, `.`) and case-insensitive matching cover all of
+//! Unicode. `--regex-classes=ascii` restricts them to ASCII, as if every pattern began with
+//! `(?-u)`; `.` and negated classes then match any single byte.
+//!
+//! A PatternSet tests several patterns against the same input at once. Programs with many `/re/`
+//! rules use one to decide which rules apply to a record in a single pass over it.
+use aho_corasick::AhoCorasick;
+use memchr::memmem;
+use regex::bytes::{Regex, RegexBuilder, RegexSetBuilder};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static FANCY: AtomicBool = AtomicBool::new(false);
+
+/// Compile patterns the default engine does not support with fancy-regex.
+#[cfg(feature = "fancy_regex")]
+pub fn set_fancy(fancy: bool) {
+    FANCY.store(fancy, Ordering::Relaxed)
+}
+
+static UNICODE: AtomicBool = AtomicBool::new(true);
+
+/// Whether character classes and case-insensitive matching cover all of Unicode (the default) or
+/// only ASCII. Patterns can override this with `(?u)` or `(?-u)`.
+pub fn set_unicode(unicode: bool) {
+    UNICODE.store(unicode, Ordering::Relaxed)
+}
+
+/// An error compiling a pattern.
+#[derive(Debug)]
+pub struct Error(String);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Searching for successive non-overlapping matches of a pattern.
+pub trait Find {
+    /// Call `f` with the start and end offsets of each match in `bs`, until it returns false.
+    fn find_each(&self, bs: &[u8], f: impl FnMut(usize, usize) -> bool);
+
+    fn find_first(&self, bs: &[u8]) -> Option<(usize, usize)> {
+        let mut res = None;
+        self.find_each(bs, |start, end| {
+            res = Some((start, end));
+            false
+        });
+        res
+    }
+}
+
+impl Find for Regex {
+    fn find_each(&self, bs: &[u8], mut f: impl FnMut(usize, usize) -> bool) {
+        for m in self.find_iter(bs) {
+            if !f(m.start(), m.end()) {
+                break;
+            }
+        }
+    }
+    fn find_first(&self, bs: &[u8]) -> Option<(usize, usize)> {
+        self.find(bs).map(|m| (m.start(), m.end()))
+    }
+}
+
+pub struct Matcher {
+    re: Engine,
+    fast: Fast,
+}
+
+enum Engine {
+    Default(Regex),
+    #[cfg(feature = "fancy_regex")]
+    Fancy(fancy_regex::Regex),
+}
+
+enum Fast {
+    /// The pattern matches exactly the inputs containing this string.
+    Contains(memmem::Finder<'static>),
+    /// `^lit`
+    StartsWith(Box<[u8]>),
+    /// `lit$`
+    EndsWith(Box<[u8]>),
+    /// `^lit$`
+    Equals(Box<[u8]>),
+    /// Every match of the pattern starts with this string.
+    Requires(memmem::Finder<'static>),
+    None,
+}
+
+impl Matcher {
+    pub fn new(pat: &str) -> Result<Matcher, Error> {
+        Self::compile(
+            pat,
+            FANCY.load(Ordering::Relaxed),
+            UNICODE.load(Ordering::Relaxed),
+        )
+    }
+
+    fn compile(pat: &str, fancy: bool, unicode: bool) -> Result<Matcher, Error> {
+        let err = match RegexBuilder::new(pat).unicode(unicode).build() {
+            Ok(re) => {
+                return Ok(Matcher {
+                    re: Engine::Default(re),
+                    fast: Fast::new(pat),
+                })
+            }
+            Err(e) => e,
+        };
+        let constructs = unsupported_constructs(pat);
+        if constructs.len() == 0 {
+            return Err(Error(err.to_string()));
+        }
+        if fancy {
+            #[cfg(feature = "fancy_regex")]
+            return match fancy_regex::Regex::new(pat) {
+                Ok(re) => Ok(Matcher {
+                    re: Engine::Fancy(re),
+                    fast: Fast::None,
+                }),
+                Err(e) => Err(Error(format!("regex /{}/: {}", pat, e))),
+            };
+        }
+        let hint = if cfg!(feature = "fancy_regex") {
+            "pass --regex-engine=fancy to enable them"
+        } else {
+            "they are only available in builds of frawk with the fancy_regex feature"
+        };
+        Err(Error(format!(
+            "regex /{}/ uses {}, which the default regex engine does not support; {}",
+            pat,
+            constructs.join(", "),
+            hint,
+        )))
+    }
+
+    pub fn as_str(&self) -> &str {
+        match &self.re {
+            Engine::Default(re) => re.as_str(),
+            #[cfg(feature = "fancy_regex")]
+            Engine::Fancy(re) => re.as_str(),
+        }
+    }
+
+    /// The underlying regex, if this pattern is handled by the default engine.
+    pub fn regex(&self) -> Option<&Regex> {
+        match &self.re {
+            Engine::Default(re) => Some(re),
+            #[cfg(feature = "fancy_regex")]
+            Engine::Fancy(_) => None,
+        }
+    }
+
+    pub fn is_match(&self, bs: &[u8]) -> bool {
+        match &self.fast {
+            Fast::Contains(f) => f.find(bs).is_some(),
+            Fast::StartsWith(lit) => bs.starts_with(lit),
+            Fast::EndsWith(lit) => bs.ends_with(lit),
+            Fast::Equals(lit) => bs == &**lit,
+            Fast::Requires(f) => f.find(bs).is_some() && self.is_match_slow(bs),
+            Fast::None => self.is_match_slow(bs),
+        }
+    }
+
+    fn is_match_slow(&self, bs: &[u8]) -> bool {
+        match &self.re {
+            Engine::Default(re) => re.is_match(bs),
+            #[cfg(feature = "fancy_regex")]
+            Engine::Fancy(re) => std::str::from_utf8(bs).map_or(false, |s| {
+                // Patterns that backtrack too much fail to match, rather than aborting the
+                // program.
+                re.is_match(s).unwrap_or(false)
+            }),
+        }
+    }
+}
+
+impl Find for Matcher {
+    fn find_each(&self, bs: &[u8], f: impl FnMut(usize, usize) -> bool) {
+        match &self.re {
+            Engine::Default(re) => re.find_each(bs, f),
+            #[cfg(feature = "fancy_regex")]
+            Engine::Fancy(re) => {
+                let mut f = f;
+                if let Ok(s) = std::str::from_utf8(bs) {
+                    for m in re.find_iter(s) {
+                        match m {
+                            Ok(m) if f(m.start(), m.end()) => {}
+                            Ok(_) | Err(_) => break,
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Whether `pat` may be supported by the default regex engine.
+pub fn default_engine_supports(pat: &[u8]) -> bool {
+    unsupported_constructs(&String::from_utf8_lossy(pat)).len() == 0
+}
+
+/// Describe the constructs in `pat` that fancy-regex supports but the regex crate does not.
+fn unsupported_constructs(pat: &str) -> Vec<&'static str> {
+    let mut res = Vec::new();
+    let mut add = |c| {
+        if !res.contains(&c) {
+            res.push(c)
+        }
+    };
+    let bs = pat.as_bytes();
+    let mut in_class = false;
+    let mut i = 0;
+    while i < bs.len() {
+        match bs[i] {
+            b'\\' => {
+                if !in_class && matches!(bs.get(i + 1), Some(b'1'..=b'9')) {
+                    add("backreferences (\\N)");
+                }
+                i += 2;
+                continue;
+            }
+            b'[' if !in_class => in_class = true,
+            b']' if in_class => in_class = false,
+            b'(' if !in_class => {
+                let rest = &bs[i + 1..];
+                if rest.starts_with(b"?=") {
+                    add("lookahead (?=...)");
+                } else if rest.starts_with(b"?!") {
+                    add("negative lookahead (?!...)");
+                } else if rest.starts_with(b"?<=") {
+                    add("lookbehind (?<=...)");
+                } else if rest.starts_with(b"?<!") {
+                    add("negative lookbehind (?<!...)");
+                } else if rest.starts_with(b"?>") {
+                    add("atomic groups (?>...)");
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    res
+}
+
+/// A set of patterns matched against an input together. Sets of plain strings are searched for
+/// with Aho-Corasick; anything else goes through a RegexSet.
+pub struct PatternSet {
+    pats: Vec<String>,
+    imp: SetImpl,
+}
+
+enum SetImpl {
+    Literals(AhoCorasick),
+    Regexes(regex::bytes::RegexSet),
+}
+
+impl PatternSet {
+    /// Matches are reported as a bitmask, so sets hold at most this many patterns.
+    pub const MAX_PATTERNS: usize = 64;
+
+    pub fn new(pats: Vec<String>) -> Result<PatternSet, Error> {
+        if pats.len() > Self::MAX_PATTERNS {
+            return Err(Error(format!(
+                "pattern sets hold at most {} patterns, got {}",
+                Self::MAX_PATTERNS,
+                pats.len()
+            )));
+        }
+        let mut lits = Vec::with_capacity(pats.len());
+        for p in pats.iter() {
+            match literal_prefix(p) {
+                (lit, "") if lit.len() > 0 => lits.push(lit),
+                _ => break,
+            }
+        }
+        let imp = match AhoCorasick::new(&lits) {
+            Ok(ac) if lits.len() == pats.len() => SetImpl::Literals(ac),
+            _ => SetImpl::Regexes(
+                RegexSetBuilder::new(pats.iter())
+                    .unicode(UNICODE.load(Ordering::Relaxed))
+                    .build()
+                    .map_err(|e| Error(e.to_string()))?,
+            ),
+        };
+        Ok(PatternSet { pats, imp })
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.pats[..]
+    }
+
+    /// Bit `i` of the result is set if pattern `i` matches `bs`.
+    pub fn matches(&self, bs: &[u8]) -> i64 {
+        let mut res = 0u64;
+        match &self.imp {
+            SetImpl::Literals(ac) => {
+                let all = if self.pats.len() == 64 {
+                    !0
+                } else {
+                    (1u64 << self.pats.len()) - 1
+                };
+                for m in ac.find_overlapping_iter(bs) {
+                    res |= 1 << m.pattern().as_usize();
+                    if res == all {
+                        break;
+                    }
+                }
+            }
+            SetImpl::Regexes(set) => {
+                for i in set.matches(bs).into_iter() {
+                    res |= 1 << i;
+                }
+            }
+        }
+        res as i64
+    }
+}
+
+impl std::fmt::Debug for PatternSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.pats.iter()).finish()
+    }
+}
+
+impl Fast {
+    fn new(pat: &str) -> Fast {
+        let (anchored, rest) = match pat.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, pat),
+        };
+        let (lit, rest) = literal_prefix(rest);
+        let finder = |lit: Vec<u8>| memmem::Finder::new(&lit).into_owned();
+        match (anchored, rest) {
+            (false, "") if lit.len() > 0 => Fast::Contains(finder(lit)),
+            (true, "") => Fast::StartsWith(lit.into()),
+            (false, "$") => Fast::EndsWith(lit.into()),
+            (true, "$") => Fast::Equals(lit.into()),
+            _ if pat.contains('|') => Fast::None,
+            _ => {
+                // A quantifier applies to the last character of the literal, which then need not
+                // appear.
+                let mut lit = lit;
+                if rest.starts_with(|c| matches!(c, '*' | '?' | '{')) {
+                    let last = std::str::from_utf8(&lit)
+                        .ok()
+                        .and_then(|s| s.chars().last())
+                        .map_or(0, char::len_utf8);
+                    lit.truncate(lit.len() - last);
+                }
+                if lit.len() == 0 {
+                    Fast::None
+                } else {
+                    Fast::Requires(finder(lit))
+                }
+            }
+        }
+    }
+}
+
+/// Split `pat` into the longest prefix of it that only matches a literal string (that string is
+/// returned) and the rest of the pattern.
+fn literal_prefix(pat: &str) -> (Vec<u8>, &str) {
+    let mut lit = Vec::new();
+    let mut chars = pat.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                // Escaped punctuation stands for itself; other escapes are character classes
+                // (\d, \w), assertions (\b) or control characters, which we leave to the regex.
+                Some((_, e)) if e.is_ascii_punctuation() => {
+                    lit.push(*e as u8);
+                    chars.next();
+                }
+                _ => return (lit, &pat[i..]),
+            },
+            '.' | '+' | '*' | '?' | '(' | ')' | '|' | '[' | ']' | '{' | '}' | '^' | '$' => {
+                return (lit, &pat[i..])
+            }
+            c => {
+                let mut buf = [0u8; 4];
+                lit.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    (lit, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pat: &str, inputs: &[&str]) {
+        let m = Matcher::new(pat).unwrap();
+        for i in inputs {
+            assert_eq!(
+                m.is_match(i.as_bytes()),
+                m.regex().unwrap().is_match(i.as_bytes()),
+                "pattern={:?} input={:?}",
+                pat,
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn classify() {
+        let kind = |pat| match Matcher::new(pat).unwrap().fast {
+            Fast::Contains(_) => "contains",
+            Fast::StartsWith(_) => "starts_with",
+            Fast::EndsWith(_) => "ends_with",
+            Fast::Equals(_) => "equals",
+            Fast::Requires(f) => {
+                assert!(f.needle().len() > 0);
+                "requires"
+            }
+            Fast::None => "none",
+        };
+        assert_eq!(kind("ERROR"), "contains");
+        assert_eq!(kind(r"a\.b"), "contains");
+        assert_eq!(kind("^GET "), "starts_with");
+        assert_eq!(kind(r"\.json$"), "ends_with");
+        assert_eq!(kind("^abc$"), "equals");
+        assert_eq!(kind("^$"), "equals");
+        assert_eq!(kind("user[0-9]+"), "requires");
+        assert_eq!(kind("ab+c"), "requires");
+        assert_eq!(kind("ab*c"), "requires");
+        assert_eq!(kind("a*"), "none");
+        assert_eq!(kind("a|b"), "none");
+        assert_eq!(kind("foo(bar|baz)"), "none");
+        assert_eq!(kind(r"\d+"), "none");
+        assert_eq!(kind("(?i)abc"), "none");
+        assert_eq!(kind(""), "none");
+    }
+
+    #[test]
+    fn agrees_with_regex() {
+        let inputs = &[
+            "",
+            "a",
+            "abc",
+            "xabcx",
+            "ab",
+            "abbbc",
+            "ac",
+            "a.b",
+            "axb",
+            "GET /index.html",
+            " GET /",
+            "data.json",
+            "data.json\n",
+            "user",
+            "user12",
+            "héllo wörld",
+            "wörld",
+        ];
+        for pat in &[
+            "abc",
+            "^abc",
+            "abc$",
+            "^abc$",
+            "^$",
+            "",
+            r"a\.b",
+            "a.b",
+            "^GET ",
+            r"\.json$",
+            "user[0-9]+",
+            "ab+c",
+            "ab*c",
+            "ab?c",
+            "ab{2,}c",
+            "wörld",
+            "ö+",
+            "^héllo",
+            "a|user",
+        ] {
+            check(pat, inputs);
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        assert_eq!(
+            unsupported_constructs(r"(?=a)b\1[(?!]\\2(?=c)"),
+            vec!["lookahead (?=...)", "backreferences (\\N)"]
+        );
+        assert_eq!(
+            unsupported_constructs(r"(?:a)(?i)b[\1]"),
+            Vec::<&str>::new()
+        );
+        let err = Matcher::compile("a(?<!b)c", false, true)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("negative lookbehind (?<!...)"), "{}", err);
+        let err = Matcher::compile("a(b", false, true)
+            .unwrap_err()
+            .to_string();
+        assert!(!err.contains("default regex engine"), "{}", err);
+    }
+
+    #[test]
+    fn flags() {
+        let inputs = &[
+            "", "ERROR", "error", "Error: x", "a\nb", "aXb", "3 - 4", "3-4", "Éa",
+        ];
+        for pat in &[
+            "(?i)error",
+            "^(?i)error$",
+            "er(?i)ror",
+            "(?s)a.b",
+            "(?x) \\d+ - \\d+",
+            "(?i)é",
+        ] {
+            check(pat, inputs);
+        }
+        let m = Matcher::new("(?i)ERROR").unwrap();
+        assert!(m.is_match(b"an error"));
+        let m = Matcher::new("(?x) a b # comment").unwrap();
+        assert!(m.is_match(b"ab"));
+        assert!(!m.is_match(b"a b"));
+
+        let unicode = Matcher::compile(r"^\w+$", false, true).unwrap();
+        let ascii = Matcher::compile(r"^\w+$", false, false).unwrap();
+        assert!(unicode.is_match("wörld".as_bytes()));
+        assert!(!ascii.is_match("wörld".as_bytes()));
+        assert!(ascii.is_match(b"world"));
+        let ascii = Matcher::compile("(?i)é", false, false).unwrap();
+        assert!(!ascii.is_match("É".as_bytes()));
+        let ascii = Matcher::compile(r"(?u)^\w+$", false, false).unwrap();
+        assert!(ascii.is_match("wörld".as_bytes()));
+        // Without Unicode, `.` matches bytes that are not valid UTF-8.
+        let ascii = Matcher::compile("^a.b$", false, false).unwrap();
+        assert!(ascii.is_match(b"a\xffb"));
+    }
+
+    #[cfg(feature = "fancy_regex")]
+    #[test]
+    fn fancy() {
+        let m = Matcher::compile(r"foo(?!bar)", true, true).unwrap();
+        assert!(m.regex().is_none());
+        assert!(m.is_match(b"foobaz"));
+        assert!(!m.is_match(b"foobar"));
+        assert!(!m.is_match(b"foo\xff"));
+        let m = Matcher::compile(r"(a)\1", true, true).unwrap();
+        let mut found = Vec::new();
+        m.find_each(b"xaaaab", |start, end| {
+            found.push((start, end));
+            true
+        });
+        assert_eq!(found, vec![(1, 3), (3, 5)]);
+        // Patterns the default engine supports still use it.
+        assert!(Matcher::compile("a+b", true, true)
+            .unwrap()
+            .regex()
+            .is_some());
+    }
+
+    #[test]
+    fn pattern_sets() {
+        let inputs = &["", "abc", "user12 GET", "xyz.json", "wörld", "ab"];
+        for pats in &[
+            &["abc", "GET", "json", "ö"][..],
+            &["^abc", "user[0-9]+", r"\.json$", "b"][..],
+            &["a", "ab", "abc"][..],
+        ] {
+            let set = PatternSet::new(pats.iter().map(|p| p.to_string()).collect()).unwrap();
+            for i in inputs {
+                let mut expected = 0;
+                for (j, p) in pats.iter().enumerate() {
+                    if Regex::new(p).unwrap().is_match(i.as_bytes()) {
+                        expected |= 1 << j;
+                    }
+                }
+                assert_eq!(
+                    set.matches(i.as_bytes()),
+                    expected,
+                    "pats={:?} input={:?}",
+                    pats,
+                    i
+                );
+            }
+        }
+        assert!(matches!(
+            PatternSet::new(vec!["a".into(), "b".into()]).unwrap().imp,
+            SetImpl::Literals(_)
+        ));
+        assert!(matches!(
+            PatternSet::new(vec!["a".into(), "b+".into()]).unwrap().imp,
+            SetImpl::Regexes(_)
+        ));
+    }
+}
@@ -1,41 +1,84 @@
 use crate::common::{Either, FileSpec, Result};
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use regex::bytes::Regex;
 use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::fs::File;
-use std::hash::Hash;
 use std::io;
 use std::iter::FromIterator;
 use std::mem;
 use std::process::ChildStdout;
 use std::rc::Rc;
 use std::str;
-
-mod command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub(crate) mod arith;
+pub(crate) mod bounded;
+pub mod chars;
+pub mod checkpoint;
+pub(crate) mod collate;
+pub(crate) mod color;
+pub mod command;
+pub(crate) mod compat;
+pub(crate) mod diff;
+pub(crate) mod exit;
 pub mod float_parse;
+pub(crate) mod hist;
+pub mod inplace;
+pub(crate) mod intern;
+pub mod matcher;
+pub(crate) mod os;
+pub(crate) mod pad;
+mod poll;
 pub mod printf;
+pub(crate) mod process;
+pub mod progress;
+pub(crate) mod record_arena;
+pub(crate) mod signal;
+pub(crate) mod similarity;
+pub(crate) mod sketch;
 pub mod splitter;
+pub mod state;
+pub mod stats;
 pub mod str_impl;
 pub mod string_search;
+pub(crate) mod table;
+pub(crate) mod tiered;
+pub(crate) mod trim;
 pub mod utf8;
+pub(crate) mod vector;
+pub mod warnings;
+pub(crate) mod watchdog;
+pub(crate) mod window;
 pub mod writers;
 
 use crate::pushdown::FieldSet;
+use matcher::{Find, Matcher};
 use splitter::regex::RegexSplitter;
+use splitter::{ReaderState, RecordBatch, RecordCursor};
+use table::MapKey;
+
+/// The sum of `strs`, converted to numbers.
+pub(crate) fn sum_strs<'a, 'b: 'a>(strs: impl Iterator<Item = &'a Str<'b>>) -> Float {
+    strs.map(|s| convert::<_, Float>(s)).fold(0.0, |a, b| a + b)
+}
 
 // TODO: remove the pub use for Variables here.
 pub(crate) use crate::builtins::Variables;
 pub use command::run_command;
-pub(crate) use float_parse::{hextoi, strtod, strtoi};
+pub(crate) use float_parse::{
+    enable_non_decimal_data, hextoi, non_decimal_data, radixtoi, strtod, strtoi,
+};
 pub(crate) use printf::FormatArg;
 pub use splitter::{
     batch::{escape_csv, escape_tsv},
-    ChainedReader, Line, LineReader,
+    ArgvReader, ChainedReader, Line, LineReader,
 };
 pub use str_impl::{Str, UniqueStr};
 
-// TODO(ezr): this IntMap can probably be unboxed, but wait until we decide whether or not to
-// specialize the IntMap implementation.
+// TODO(ezr): this IntMap can probably be unboxed. IntMaps with small dense keys are now stored in
+// a Vec (see the `table` module), so the Left case may no longer be pulling its weight.
 pub(crate) type LazyVec<T> = Either<Vec<T>, IntMap<T>>;
 
 impl<T> LazyVec<T> {
@@ -91,7 +134,7 @@ impl LazyVec<Str<'static>> {
             Either::Left(v) => sep.join(v[start..end].iter().cloned().map(by)),
             Either::Right(m) => {
                 let r = m.0.borrow();
-                let mut v: Vec<_> = r.keys().cloned().collect();
+                let mut v: Vec<_> = r.keys().collect();
                 v.sort();
                 sep.join(
                     v.into_iter()
@@ -102,13 +145,25 @@ impl LazyVec<Str<'static>> {
         }
     }
 }
+impl LazyVec<Str<'static>> {
+    pub(crate) fn sum_range(&self, start: usize, end: usize) -> Float {
+        // Like join_by, assumes zero-indexing and doesn't do len-checks.
+        match self {
+            Either::Left(v) => sum_strs(v[start..end].iter()),
+            Either::Right(m) => {
+                let r = m.0.borrow();
+                sum_strs((start..end).filter_map(|i| r.get(&(i as Int))))
+            }
+        }
+    }
+}
 impl<'a> LazyVec<Str<'a>> {
     pub(crate) fn join_all(&self, sep: &Str<'a>) -> Str<'a> {
         match self {
             Either::Left(v) => sep.join(v.iter().cloned()),
             Either::Right(m) => {
                 let r = m.0.borrow();
-                let mut v: Vec<_> = r.keys().cloned().collect();
+                let mut v: Vec<_> = r.keys().collect();
                 v.sort();
                 sep.join(v.into_iter().map(|i| r[&i].clone()))
             }
@@ -171,14 +226,59 @@ impl<T: Default> LazyVec<T> {
     }
 }
 
-#[derive(Default)]
-pub struct RegexCache(Registry<Regex>);
+/// The default number of compiled regexes kept by a RegexCache.
+pub const DEFAULT_REGEX_CACHE_SIZE: usize = 1024;
+
+static REGEX_CACHE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_REGEX_CACHE_SIZE);
+
+/// Set the maximum number of compiled regexes kept by each RegexCache created afterwards.
+pub fn set_regex_cache_size(n: usize) {
+    REGEX_CACHE_SIZE.store(std::cmp::max(n, 1), Ordering::Relaxed)
+}
+
+/// Counts of how a RegexCache has been used, reported by `--profile`.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub struct RegexStats {
+    pub lookups: u64,
+    /// Lookups for patterns that had to be compiled.
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// Compiled regexes, keyed by their source. Programs can build patterns out of their input, so
+/// the cache is bounded: once it is full, compiling a new pattern discards the least recently used
+/// one.
+pub struct RegexCache(Registry<Matcher>);
+
+impl Default for RegexCache {
+    fn default() -> RegexCache {
+        RegexCache(Registry::bounded(REGEX_CACHE_SIZE.load(Ordering::Relaxed)))
+    }
+}
 
 impl RegexCache {
-    pub(crate) fn with_regex<T>(&mut self, pat: &Str, mut f: impl FnMut(&Regex) -> T) -> Result<T> {
+    /// Compile each of `pats` ahead of time, so that the first records to use them do not pay for
+    /// compiling them. Invalid patterns are skipped; they are reported if they are ever used.
+    pub(crate) fn precompile<'a>(&mut self, pats: impl IntoIterator<Item = &'a str>) {
+        for pat in pats {
+            let _ = self.with_matcher(&Str::from(pat), |_| ());
+        }
+        // Those don't count as misses.
+        self.0.stats = Default::default();
+    }
+
+    pub(crate) fn stats(&self) -> RegexStats {
+        self.0.stats
+    }
+
+    pub(crate) fn with_matcher<T>(
+        &mut self,
+        pat: &Str,
+        mut f: impl FnMut(&Matcher) -> T,
+    ) -> Result<T> {
         self.0.get(
             pat,
-            |s| match Regex::new(s) {
+            |s| match Matcher::new(s) {
                 Ok(r) => Ok(r),
                 Err(e) => err!("{}", e),
             },
@@ -186,14 +286,14 @@ impl RegexCache {
             |x| f(x),
         )
     }
-    pub(crate) fn with_regex_fallible<T>(
+    fn with_matcher_fallible<T>(
         &mut self,
         pat: &Str,
-        mut f: impl FnMut(&Regex) -> Result<T>,
+        mut f: impl FnMut(&Matcher) -> Result<T>,
     ) -> Result<T> {
         self.0.get_fallible(
             pat,
-            |s| match Regex::new(s) {
+            |s| match Matcher::new(s) {
                 Ok(r) => Ok(r),
                 Err(e) => err!("{}", e),
             },
@@ -201,6 +301,17 @@ impl RegexCache {
             |x| f(x),
         )
     }
+    /// Run `f` on the regex for `pat`. Patterns that are only supported by the fancy-regex engine
+    /// cannot be used to split strings, so this fails for them.
+    pub(crate) fn with_regex<T>(&mut self, pat: &Str, mut f: impl FnMut(&Regex) -> T) -> Result<T> {
+        self.with_matcher_fallible(pat, |m| match m.regex() {
+            Some(re) => Ok(f(re)),
+            None => err!(
+                "regex /{}/ can only be used for matching (~, match, sub and gsub), not for splitting",
+                m.as_str()
+            ),
+        })
+    }
 
     pub(crate) fn get_line<'a, LR: LineReader>(
         &mut self,
@@ -209,10 +320,25 @@ impl RegexCache {
         reg: &mut FileRead<LR>,
         is_file: bool,
     ) -> Result<Str<'a>> {
+        if is_file && reg.reads_main_input(file) {
+            let (_, mut line) = reg.read_main_line(pat, self)?;
+            return Ok(line.get_col(0, pat, pat, self)?.clone().upcast());
+        }
+        if let Some(timeout) = reg.inputs.timeouts.get(&file.clone().unmoor()).cloned() {
+            if let (false, Some(fd)) = self.input_ready(file, !is_file, pat, reg)? {
+                if !poll::readable(&[fd], timeout)?[0] {
+                    reg.inputs.timed_out.insert(file.clone().unmoor());
+                    return Ok(Str::default());
+                }
+            }
+        }
         Ok(if is_file {
-            reg.with_file(file, |reader| {
+            match reg.with_file(file, |reader| {
                 self.with_regex(pat, |re| reader.read_line_regex(re))
-            })?
+            })? {
+                Some(line) => line,
+                None => return Ok(Str::default()),
+            }
         } else {
             reg.with_cmd(file, |reader| {
                 self.with_regex(pat, |re| reader.read_line_regex(re))
@@ -222,24 +348,134 @@ impl RegexCache {
         .upcast())
     }
 
+    /// Whether getline can read a record from the file or command `name` without waiting, and if
+    /// not, the file descriptor to wait on. `name` names a command if `is_cmd` is set or it is
+    /// already being read as one; it is opened if necessary. Files that cannot be opened are ready,
+    /// as getline reports the failure straight away.
+    fn input_ready<'a, LR: LineReader>(
+        &mut self,
+        name: &Str<'a>,
+        is_cmd: bool,
+        pat: &Str<'a>,
+        reg: &mut FileRead<LR>,
+    ) -> Result<(bool, Option<poll::Fd>)> {
+        let (has_record, fd) = if is_cmd || reg.inputs.commands.contains(name) {
+            reg.with_cmd(name, |r| {
+                self.with_regex(pat, |re| (r.has_record(re), poll::fd(r.inner())))
+            })?
+        } else {
+            match reg.with_file(name, |r| {
+                self.with_regex(pat, |re| (r.has_record(re), poll::fd(r.inner())))
+            })? {
+                Some(res) => res,
+                None => (true, None),
+            }
+        };
+        Ok((has_record || fd.is_none(), fd))
+    }
+
+    /// `readable(inputs, timeout)`: wait until getline can read a record from at least one of the
+    /// inputs named by the keys of `inputs` without blocking, or until `timeout` milliseconds have
+    /// passed. A negative timeout waits indefinitely. Each key names a command that is already
+    /// being read with `cmd | getline`, a command to start if its value is negative, or otherwise
+    /// a file, which is opened if necessary. Inputs that have reached their end, or that cannot be
+    /// opened, count as ready: getline returns immediately for them. Sets `inputs[name]` to 1 for
+    /// each ready input and 0 for the rest, and returns the number that are ready.
+    pub(crate) fn readable<'a, LR: LineReader>(
+        &mut self,
+        inputs: &StrMap<'a, Int>,
+        timeout: Int,
+        pat: &Str<'a>,
+        reg: &mut FileRead<LR>,
+    ) -> Result<Int> {
+        let names = inputs.to_vec();
+        let mut ready = vec![false; names.len()];
+        // The inputs that need to be polled, as (index into `names`, file descriptor) pairs.
+        let mut pending = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let is_cmd = inputs.get(name).map_or(false, |v| v < 0);
+            match self.input_ready(name, is_cmd, pat, reg)? {
+                (false, Some(fd)) => pending.push((i, fd)),
+                _ => ready[i] = true,
+            }
+        }
+        if pending.len() > 0 {
+            // Do not wait if some input is ready already.
+            let timeout = if ready.iter().any(|r| *r) { 0 } else { timeout };
+            let fds: Vec<_> = pending.iter().map(|(_, fd)| *fd).collect();
+            for ((i, _), is_ready) in pending.iter().zip(poll::readable(&fds[..], timeout)?) {
+                ready[*i] = is_ready;
+            }
+        }
+        for (name, is_ready) in names.into_iter().zip(ready.iter()) {
+            inputs.insert(name, *is_ready as Int);
+        }
+        Ok(ready.iter().filter(|r| **r).count() as Int)
+    }
+
     // This only gets used if getline is invoked explicitly without an input file argument.
     pub(crate) fn get_line_stdin<'a, LR: LineReader>(
         &mut self,
-        pat: &Str<'a>,
+        vars: &Variables<'a>,
         reg: &mut FileRead<LR>,
     ) -> Result<(/* file changed */ bool, Str<'a>)> {
-        let (changed, mut line) = reg.stdin.read_line(pat, self)?;
+        let pat = &vars.rs;
+        let mut changed = false;
+        let mut line = loop {
+            let (mut started, mut line) = reg.read_main_line(pat, self)?;
+            started |= reg.next_argv_file(vars, self, &mut line)?;
+            if checkpoint::enabled() {
+                started |= reg.resume(vars, self, &mut line)?;
+            }
+            changed |= started;
+            if skips_records() {
+                changed |= reg.skip_records(vars, self, &mut line, started)?;
+            }
+            if !reg.filtered(&mut line, pat, self)? {
+                break line;
+            }
+        };
         // NB both of these `pat`s are "wrong" but we are fine because they are only used
         // when the column is nonzero, or someone has overwritten a nonzero column.
-        Ok((changed, line.get_col(0, pat, pat, self)?.clone().upcast()))
+        let line = line.get_col(0, pat, pat, self)?;
+        if reg.track_records {
+            reg.track_record(&line);
+        }
+        if watchdog::enabled() {
+            time_record(&reg.stdin, &line);
+        }
+        Ok((changed, line.clone().upcast()))
     }
     pub(crate) fn get_line_stdin_reuse<'a, LR: LineReader>(
         &mut self,
-        pat: &Str<'a>,
+        vars: &Variables<'a>,
         reg: &mut FileRead<LR>,
         old_line: &mut LR::Line,
     ) -> Result</*file changed */ bool> {
-        reg.stdin.read_line_reuse(pat, self, old_line)
+        let mut changed = false;
+        loop {
+            let mut started = reg.read_main_line_reuse(&vars.rs, self, old_line)?;
+            started |= reg.next_argv_file(vars, self, old_line)?;
+            if checkpoint::enabled() {
+                started |= reg.resume(vars, self, old_line)?;
+            }
+            changed |= started;
+            if skips_records() {
+                changed |= reg.skip_records(vars, self, old_line, started)?;
+            }
+            if !reg.filtered(old_line, &vars.rs, self)? {
+                break;
+            }
+        }
+        if reg.track_records {
+            let line = old_line.get_col(0, &vars.rs, &vars.rs, self)?;
+            reg.track_record(&line);
+        }
+        if watchdog::enabled() {
+            let line = old_line.get_col(0, &vars.rs, &vars.rs, self)?;
+            time_record(&reg.stdin, &line);
+        }
+        Ok(changed)
     }
     fn split_internal<'a>(
         &mut self,
@@ -316,16 +552,16 @@ impl RegexCache {
         })
     }
 
-    pub(crate) fn regex_const_match_loc(vars: &mut Variables, re: &Regex, s: &Str) -> Result<Int> {
+    pub(crate) fn regex_const_match_loc(
+        vars: &mut Variables,
+        re: &Matcher,
+        s: &Str,
+    ) -> Result<Int> {
         use crate::builtins::Variable;
-        let (start, len) = s.with_bytes(|bs| match re.find(bs) {
-            Some(m) => {
-                let start = m.start() as Int;
-                let end = m.end() as Int;
-                (start + 1, end - start)
-            }
+        let (start, len) = match s.with_bytes(|bs| re.find_first(bs)) {
+            Some((start, end)) => chars::match_loc(s, start, end),
             None => (0, -1),
-        });
+        };
         vars.store_int(Variable::RSTART, start)?;
         vars.store_int(Variable::RLENGTH, len)?;
         Ok(start)
@@ -336,20 +572,32 @@ impl RegexCache {
         pat: &Str,
         s: &Str,
     ) -> Result<Int> {
-        self.with_regex_fallible(pat, |re| Self::regex_const_match_loc(vars, re, s))
+        self.with_matcher_fallible(pat, |re| Self::regex_const_match_loc(vars, re, s))
     }
 
-    pub(crate) fn regex_const_match(pat: &Regex, s: &Str) -> bool {
+    pub(crate) fn regex_const_match(pat: &Matcher, s: &Str) -> bool {
         s.with_bytes(|bs| pat.is_match(bs))
     }
 
     pub(crate) fn is_regex_match(&mut self, pat: &Str, s: &Str) -> Result<bool> {
-        self.with_regex(pat, |re| Self::regex_const_match(re, s))
+        self.with_matcher(pat, |m| Self::regex_const_match(m, s))
     }
 }
 
+/// The output files and commands written to by a program. `print > file` truncates `file` only the
+/// first time it is opened in a run; writing to it again after closing it appends.
 #[derive(Clone)]
-pub(crate) struct FileWrite(writers::Registry);
+pub(crate) struct FileWrite {
+    files: writers::Registry,
+    // The main input file that the program is reading, with `-i inplace`; standard output is
+    // written to the replacement for it, if it is being edited.
+    input: Str<'static>,
+    editing: Option<inplace::Edit>,
+    // Whether each output file (by name) is a terminal, once the program has used `color` or
+    // `bold`. Output to anything else has escape codes removed; see `color`.
+    terminals: HashMap<Str<'static>, bool>,
+    stdout_terminal: Option<bool>,
+}
 
 impl Default for FileWrite {
     fn default() -> FileWrite {
@@ -357,19 +605,98 @@ impl Default for FileWrite {
     }
 }
 
+/// Once the reader of stdout has gone away, writes to it fail, halting the program. If the user
+/// asked for the END block to run regardless, we drop those writes instead.
+fn stdout_result(res: Result<()>) -> Result<()> {
+    if res.is_err() && writers::stdout_closed() && writers::end_on_broken_pipe() {
+        Ok(())
+    } else {
+        res
+    }
+}
+
 impl FileWrite {
     pub(crate) fn flush_stdout(&mut self) -> Result<()> {
-        self.0.get_file(None)?.flush()
+        stdout_result(self.stdout()?.flush())
     }
     pub(crate) fn close(&mut self, path: &Str) -> Result<()> {
-        self.0.close(path)
+        self.files.close(path)
     }
     pub(crate) fn new(ff: impl writers::FileFactory) -> FileWrite {
-        FileWrite(writers::Registry::from_factory(ff))
+        FileWrite {
+            files: writers::Registry::from_factory(ff),
+            input: Str::default(),
+            editing: None,
+            terminals: Default::default(),
+            stdout_terminal: None,
+        }
     }
 
     pub(crate) fn shutdown(&mut self) -> Result<()> {
-        self.0.destroy_and_flush_all_files()
+        let res = self.files.destroy_and_flush_all_files();
+        if let Some(edit) = self.editing.take() {
+            edit.abandon();
+        }
+        res
+    }
+
+    fn stdout(&mut self) -> Result<&mut writers::FileHandle> {
+        match &self.editing {
+            Some(edit) => {
+                let temp = edit.temp().clone();
+                self.files.get_file(Some(&temp))
+            }
+            None => self.files.get_file(None),
+        }
+    }
+
+    /// With `-i inplace`, called after each read from the main input: `input` is the file the
+    /// record came from, or empty once the input has ended. When the program moves on from a file
+    /// that is being edited, the file is replaced with what was printed while reading it (or, with
+    /// `--diff`, the changes that would be made are printed instead).
+    pub(crate) fn edit_in_place(&mut self, input: Str<'static>) -> Result<()> {
+        if input == self.input {
+            return Ok(());
+        }
+        if let Some(edit) = self.editing.take() {
+            self.files.close(edit.temp())?;
+            if inplace::diff_only() {
+                let diff = edit.diff()?;
+                if diff.len() > 0 {
+                    let diff = Str::from(&diff[..]);
+                    stdout_result(self.files.get_file(None)?.write(&diff, FileSpec::Append))?;
+                }
+            } else {
+                edit.finish()?;
+            }
+        }
+        self.editing = inplace::Edit::start(&input);
+        self.input = input;
+        Ok(())
+    }
+
+    /// Whether escape codes written to `out` (standard output if it is None) reach a terminal.
+    fn is_terminal(&mut self, out: Option<(&Str, FileSpec)>) -> bool {
+        match out {
+            Some((_, FileSpec::Cmd)) => false,
+            Some((path, _)) => {
+                if let Some(res) = self.terminals.get(&path.clone().unmoor()) {
+                    return *res;
+                }
+                let res = color::is_terminal(Some(path.to_string().as_str()));
+                self.terminals.insert(path.clone().unmoor(), res);
+                res
+            }
+            None => {
+                // With -i inplace, "standard output" may be a replacement for an input file.
+                if self.editing.is_some() {
+                    return false;
+                }
+                *self
+                    .stdout_terminal
+                    .get_or_insert_with(|| color::is_terminal(None))
+            }
+        }
     }
 
     pub(crate) fn printf(
@@ -378,40 +705,154 @@ impl FileWrite {
         spec: &Str,
         pa: &[printf::FormatArg],
     ) -> Result<()> {
-        let (handle, fspec) = if let Some((out_file, fspec)) = path {
-            (self.0.get_handle(Some(out_file), fspec)?, fspec)
-        } else {
-            (
-                self.0.get_handle(None, FileSpec::default())?,
-                FileSpec::default(),
-            )
-        };
+        if exit::match_status() {
+            exit::set_matched();
+        }
+        let fspec = path.map_or(FileSpec::default(), |(_, fspec)| fspec);
         let mut text = str_impl::DynamicBuf::default();
         spec.with_bytes(|spec| printf::printf(&mut text, spec, pa))?;
-        let s = unsafe { text.into_str() };
-        handle.write(&s, fspec)
+        let mut s = unsafe { text.into_str() };
+        if color::used() && !self.is_terminal(path) {
+            s = color::strip(&s);
+        }
+        let handle = if let Some((out_file, fspec)) = path {
+            self.files.get_handle(Some(out_file), fspec)?
+        } else {
+            self.stdout()?
+        };
+        let res = handle.write(&s, fspec);
+        if path.is_some() {
+            res
+        } else {
+            stdout_result(res)
+        }
     }
     pub(crate) fn write_all(
         &mut self,
         ss: &[&Str],
         out_spec: Option<(&Str, FileSpec)>,
     ) -> Result<()> {
+        if exit::match_status() {
+            exit::set_matched();
+        }
+        if color::used() && !self.is_terminal(out_spec) {
+            let stripped: Vec<Str> = ss.iter().map(|s| color::strip(s)).collect();
+            let refs: Vec<&Str> = stripped.iter().collect();
+            return self.write_all_inner(&refs[..], out_spec);
+        }
+        self.write_all_inner(ss, out_spec)
+    }
+    fn write_all_inner(&mut self, ss: &[&Str], out_spec: Option<(&Str, FileSpec)>) -> Result<()> {
         if let Some((path, spec)) = out_spec {
-            self.0.get_handle(Some(path), spec)?.write_all(ss, spec)
+            self.files.get_handle(Some(path), spec)?.write_all(ss, spec)
         } else {
-            self.0
-                .get_handle(None, FileSpec::default())?
-                .write_all(ss, FileSpec::Append)
+            stdout_result(self.stdout()?.write_all(ss, FileSpec::Append))
         }
     }
 }
 
+/// Start timing the record just read from the main input for `--record-timeout`, or stop if the
+/// input has ended.
+fn time_record(reader: &impl LineReader, line: &Str) {
+    if reader.read_state() == ReaderState::OK as i64 {
+        line.with_bytes(watchdog::start_record)
+    } else {
+        watchdog::end_record()
+    }
+}
+
 pub const CHUNK_SIZE: usize = 8 << 10;
 
+static MAIN_INPUT_IS_STDIN: AtomicBool = AtomicBool::new(true);
+
+/// Record that the main input comes from files named on the command line, rather than from
+/// standard input. This must be called before running a program.
+pub(crate) fn set_main_input_from_files() {
+    MAIN_INPUT_IS_STDIN.store(false, Ordering::Relaxed);
+}
+
+static SKIP_HEADER: AtomicUsize = AtomicUsize::new(0);
+static SKIP_FOOTER: AtomicUsize = AtomicUsize::new(0);
+static REPEATED_HEADER: AtomicBool = AtomicBool::new(false);
+
+/// Drop the first `header` and the last `footer` records of each file of the main input before
+/// the program sees them. With `repeated_header`, each file after the first also starts with a
+/// header line (the one `-H` parses from the first file), which is dropped as well. This must be
+/// called before running a program.
+pub fn set_skipped_records(header: usize, footer: usize, repeated_header: bool) {
+    SKIP_HEADER.store(header, Ordering::Relaxed);
+    SKIP_FOOTER.store(footer, Ordering::Relaxed);
+    REPEATED_HEADER.store(repeated_header, Ordering::Relaxed);
+}
+
+/// Whether any records of the main input are dropped before the program sees them.
+pub(crate) fn skips_records() -> bool {
+    SKIP_HEADER.load(Ordering::Relaxed) > 0
+        || SKIP_FOOTER.load(Ordering::Relaxed) > 0
+        || REPEATED_HEADER.load(Ordering::Relaxed)
+}
+
+/// Whether getline reading from `path` reads standard input. `getline < "-"` and
+/// `getline < "/dev/stdin"` let programs reading files prompt for input. If the main input is
+/// standard input, they read its next record instead, so that two readers do not compete for
+/// (and buffer) the same data.
+fn is_stdin_path(path: &Str) -> bool {
+    path.with_bytes(|bs| bs == b"-" || bs == b"/dev/stdin")
+}
+
+/// Whether getline reading from `path` may be reading a response to a prompt. Standard output is
+/// flushed before such reads, so that prompts printed without a trailing newline are visible.
+pub(crate) fn may_prompt(path: &Str) -> bool {
+    is_stdin_path(path) || path.with_bytes(|bs| bs == b"/dev/tty")
+}
+
+/// Open `path` for getline. "-" and /dev/stdin name standard input, even on Windows, which has no
+/// such file.
+fn open_for_getline(path: &str) -> io::Result<File> {
+    #[cfg(windows)]
+    {
+        if path == "-" || path == "/dev/stdin" {
+            use std::os::windows::io::{AsRawHandle, FromRawHandle};
+            let handle = io::stdin().as_raw_handle();
+            // The handle belongs to the standard library; only the duplicate is ever closed.
+            let stdin = std::mem::ManuallyDrop::new(unsafe { File::from_raw_handle(handle) });
+            return stdin.try_clone();
+        }
+    }
+    File::open(if path == "-" { "/dev/stdin" } else { path })
+}
+
 #[derive(Default)]
 pub(crate) struct Inputs {
     files: Registry<RegexSplitter<File>>,
     commands: Registry<RegexSplitter<ChildStdout>>,
+    // Files that could not be opened by the last getline that tried to read from them. The
+    // following call to read_err reports the failure, after which we try to open them again.
+    failed: HashSet<Str<'static>>,
+    // The number of milliseconds that getline waits for input from each file or command, set by
+    // `settimeout`. Inputs without an entry wait indefinitely.
+    timeouts: HashMap<Str<'static>, Int>,
+    // Inputs whose last getline gave up waiting; like `failed`, the following call to read_err
+    // reports it.
+    timed_out: HashSet<Str<'static>>,
+}
+
+/// What getline returns when it gives up waiting for input.
+pub(crate) const GETLINE_TIMED_OUT: Int = -2;
+
+impl Inputs {
+    /// `settimeout(name, ms)`: make getline from the file or command `name` give up if no input
+    /// arrives within `ms` milliseconds; a negative `ms` waits indefinitely. Returns the previous
+    /// timeout, or -1 if there was none.
+    pub(crate) fn set_timeout(&mut self, name: &Str, ms: Int) -> Int {
+        let name = name.clone().unmoor();
+        let prev = if ms < 0 {
+            self.timeouts.remove(&name)
+        } else {
+            self.timeouts.insert(name, ms)
+        };
+        prev.unwrap_or(-1)
+    }
 }
 
 // TODO: save used_fields
@@ -419,12 +860,71 @@ pub(crate) struct Inputs {
 // TODO: supply an "update used fields" that takes FI
 //
 
-pub(crate) struct FileRead<LR = RegexSplitter<Box<dyn io::Read + Send>>> {
+pub(crate) struct FileRead<LR: LineReader = RegexSplitter<Box<dyn io::Read + Send>>> {
     pub(crate) inputs: Inputs,
     stdin: LR,
     named_columns: Option<Vec<Str<'static>>>,
     used_fields: FieldSet,
     backup_used_fields: FieldSet,
+    // Records the reader has already split out of its buffer; see `read_main_line_reuse`.
+    batch: RecordBatch<LR::Line>,
+    // Boxed so that compiled code can hold on to its address; see `RecordCursor`.
+    cursor: Box<RecordCursor>,
+    // Whether the last record of the main input came from `batch`.
+    from_batch: bool,
+    // Records of the main input matching none of these patterns are skipped; see `set_prefilter`.
+    prefilter: Option<Arc<[Matcher]>>,
+    // The state of `skip_records`: the number of files of the main input started so far, the
+    // number of records left to drop from the start of the current one, and the most recent
+    // records of the current file, which are dropped if the file ends before enough others follow.
+    files_started: usize,
+    header_left: usize,
+    footer: VecDeque<(LR::Line, RecordPos)>,
+    // The position of a record passed on from `footer`, which the reader has since moved past.
+    footer_pos: Option<RecordPos>,
+    // Where the last record of the main input came from; only kept up to date if `track_records`
+    // is set. See `record_file`, `record_offset` and `record_text`.
+    track_records: bool,
+    record: RecordSource,
+    // Whether `record` includes the separator that ended the record; see `round_trip_sep`.
+    round_trip: bool,
+}
+
+/// Options for reading the main input that depend on the program being run.
+#[derive(Clone, Debug, Default)]
+pub struct InputConfig {
+    /// Records matching none of these patterns are skipped; see `ast::Prog::prefilter`.
+    pub prefilter: Vec<String>,
+    /// Whether the program calls `record_file`, `record_offset` or `record_text`.
+    pub track_records: bool,
+    /// Whether `print` writes records that were not modified exactly as they were read; see
+    /// `FileRead::round_trip_sep`.
+    pub round_trip: bool,
+}
+
+/// Where a record sits in its file: the byte offset at which it starts, and (in round-trip mode)
+/// the separator that ended it.
+#[derive(Clone)]
+struct RecordPos {
+    offset: Int,
+    sep: Option<Str<'static>>,
+}
+
+impl Default for RecordPos {
+    fn default() -> RecordPos {
+        RecordPos {
+            offset: -1,
+            sep: None,
+        }
+    }
+}
+
+/// The file a record was read from, where it sits in that file, and its text as it was read.
+#[derive(Default)]
+struct RecordSource {
+    file: Str<'static>,
+    pos: RecordPos,
+    text: Str<'static>,
 }
 
 impl<LR: LineReader> FileRead<LR> {
@@ -434,12 +934,27 @@ impl<LR: LineReader> FileRead<LR> {
             .into_iter()
             .map(|x| {
                 let fields = self.used_fields.clone();
+                let prefilter = self.prefilter.clone();
+                // -H has already read the header of the first file.
+                let files_started = self.files_started;
+                let (track_records, round_trip) = (self.track_records, self.round_trip);
                 move || FileRead {
                     inputs: Default::default(),
                     stdin: x(),
                     named_columns: None,
                     used_fields: fields.clone(),
                     backup_used_fields: fields.clone(),
+                    batch: Default::default(),
+                    cursor: Default::default(),
+                    from_batch: false,
+                    prefilter,
+                    files_started,
+                    header_left: 0,
+                    footer: VecDeque::new(),
+                    footer_pos: None,
+                    track_records,
+                    record: Default::default(),
+                    round_trip,
                 }
             })
             .collect()
@@ -447,6 +962,7 @@ impl<LR: LineReader> FileRead<LR> {
 
     pub(crate) fn close(&mut self, path: &Str) {
         self.inputs.files.remove(path);
+        self.inputs.failed.remove(&path.clone().unmoor());
     }
 
     pub(crate) fn new(
@@ -470,11 +986,127 @@ impl<LR: LineReader> FileRead<LR> {
             backup_used_fields,
             named_columns: named_columns
                 .map(|cs| cs.into_iter().map(|s| Str::from(s).unmoor()).collect()),
+            batch: Default::default(),
+            cursor: Default::default(),
+            from_batch: false,
+            prefilter: None,
+            files_started: 0,
+            header_left: 0,
+            footer: VecDeque::new(),
+            footer_pos: None,
+            track_records: false,
+            record: Default::default(),
+            round_trip: false,
         };
         res.stdin.set_used_fields(&res.used_fields);
         res
     }
 
+    /// Whether records of the main input can be read ahead in batches. That is the case unless
+    /// something looks at the reader after each record it returns: prefiltering, skipping records,
+    /// resuming from a checkpoint, tracking where records came from, timing records, or opening
+    /// the files named in ARGV as the program runs.
+    fn batches(&self) -> bool {
+        self.prefilter.is_none()
+            && !self.track_records
+            && !LR::READS_ARGV
+            && !skips_records()
+            && !checkpoint::enabled()
+            && !watchdog::enabled()
+    }
+
+    /// The cursor compiled code uses to take records from the current batch.
+    pub(crate) fn record_cursor(&mut self) -> *mut RecordCursor {
+        &mut *self.cursor
+    }
+
+    /// Catch up with the records compiled code has taken from the batch since the runtime last
+    /// looked at the input, moving the last of them into `line`.
+    pub(crate) fn take_records(&mut self, line: &mut LR::Line) {
+        if self.cursor.taken == 0 {
+            return;
+        }
+        for _ in 0..self.cursor.taken {
+            let popped = self.batch.pop_into(line);
+            debug_assert!(popped);
+        }
+        self.cursor.taken = 0;
+        self.from_batch = true;
+    }
+
+    /// Read the next record of the main input into `line`, taking it from the current batch if
+    /// there is one. Returns whether a new file was started.
+    fn read_main_line_reuse(
+        &mut self,
+        pat: &Str,
+        rc: &mut RegexCache,
+        line: &mut LR::Line,
+    ) -> Result<bool> {
+        // Compiled code only takes records from the batch between calls into the runtime.
+        debug_assert_eq!(self.cursor.taken, 0);
+        let changed = if self.batch.pop_into(line) {
+            self.from_batch = true;
+            false
+        } else {
+            self.from_batch = false;
+            let changed = self.stdin.read_line_reuse(pat, rc, line)?;
+            // The first record of each file is read on its own, so that a header parsed from it
+            // (see `update_named_columns`) can change the fields the reader splits out before it
+            // splits any more records.
+            if !changed && self.batches() && self.stdin.read_state() == ReaderState::OK as Int {
+                self.stdin.read_batch(&mut self.batch)?;
+            }
+            changed
+        };
+        self.cursor.avail = self.batch.len() as Int;
+        Ok(changed)
+    }
+
+    fn read_main_line(&mut self, pat: &Str, rc: &mut RegexCache) -> Result<(bool, LR::Line)> {
+        let mut line = LR::Line::default();
+        let changed = self.read_main_line_reuse(pat, rc, &mut line)?;
+        Ok((changed, line))
+    }
+
+    /// The read state of the main input as of the last record the program was given.
+    fn main_read_state(&self) -> Int {
+        if self.from_batch || self.cursor.taken > 0 {
+            ReaderState::OK as Int
+        } else {
+            self.stdin.read_state()
+        }
+    }
+
+    /// Set up the main input for the program about to run.
+    ///
+    /// Any record of the main input that matches none of the `prefilter` patterns is skipped
+    /// without passing it to the program. An empty list (or one with a pattern that does not
+    /// compile, which the program itself will report) reads every record.
+    pub(crate) fn configure(&mut self, cfg: &InputConfig) {
+        self.track_records = cfg.track_records || cfg.round_trip;
+        self.round_trip = cfg.round_trip;
+        self.prefilter = cfg
+            .prefilter
+            .iter()
+            .map(|p| Matcher::new(p).ok())
+            .collect::<Option<Vec<_>>>()
+            .filter(|ms| ms.len() > 0)
+            .map(Arc::from);
+    }
+
+    /// Whether `line`, which was just read from the main input, should be skipped.
+    fn filtered(&self, line: &mut LR::Line, rs: &Str, rc: &mut RegexCache) -> Result<bool> {
+        let pats = match &self.prefilter {
+            Some(pats) => pats,
+            None => return Ok(false),
+        };
+        if self.stdin.read_state() != ReaderState::OK as Int {
+            return Ok(false);
+        }
+        let record = line.get_col(0, rs, rs, rc)?;
+        Ok(!record.with_bytes(|bs| pats.iter().any(|m| m.is_match(bs))))
+    }
+
     pub(crate) fn update_named_columns<'a>(&mut self, fi: &StrMap<'a, Int>) {
         let referenced_fi = self.backup_used_fields.has_fi();
         let have_columns = self.named_columns.is_some();
@@ -507,22 +1139,226 @@ impl<LR: LineReader> FileRead<LR> {
         self.stdin.set_used_fields(&self.used_fields)
     }
 
+    /// If the main input reads its files from ARGV and the last read reached the end of a file,
+    /// move on to the next file in ARGV and read `line` from it. Returns whether a new file was
+    /// opened.
+    fn next_argv_file<'a>(
+        &mut self,
+        vars: &Variables<'a>,
+        rc: &mut RegexCache,
+        line: &mut LR::Line,
+    ) -> Result<bool> {
+        let mut changed = false;
+        while LR::READS_ARGV
+            && self.stdin.read_state() == ReaderState::EOF as Int
+            && self
+                .stdin
+                .next_argv_file(vars.argc, |i| vars.argv.get(&i).map(|s| s.to_string()))?
+        {
+            self.stdin.read_line_reuse(&vars.rs, rc, line)?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    /// Skip over any records that an earlier run already processed, according to the checkpoint
+    /// file passed to `--resume`. `line` is the record that was just read.
+    fn resume<'a>(
+        &mut self,
+        vars: &Variables<'a>,
+        rc: &mut RegexCache,
+        line: &mut LR::Line,
+    ) -> Result<bool> {
+        let mut changed = false;
+        loop {
+            let got_record = self.stdin.read_state() != ReaderState::EOF as Int;
+            match checkpoint::after_read(&self.stdin.filename(), got_record)? {
+                checkpoint::Next::Keep => return Ok(changed),
+                checkpoint::Next::SkipRecord => {}
+                checkpoint::Next::SkipFile => {
+                    self.stdin.next_file()?;
+                }
+            }
+            changed |= self.stdin.read_line_reuse(&vars.rs, rc, line)?;
+            changed |= self.next_argv_file(vars, rc, line)?;
+        }
+    }
+
+    /// Drop records from the start and end of each file, according to `set_skipped_records`.
+    /// `line` is the record that was just read, and `started` is whether it is the first record of
+    /// a file. Returns whether a later file was started while looking for a record to keep.
+    fn skip_records<'a>(
+        &mut self,
+        vars: &Variables<'a>,
+        rc: &mut RegexCache,
+        line: &mut LR::Line,
+        mut started: bool,
+    ) -> Result<bool> {
+        let footer = SKIP_FOOTER.load(Ordering::Relaxed);
+        let mut changed = false;
+        loop {
+            if started {
+                self.header_left = SKIP_HEADER.load(Ordering::Relaxed);
+                if self.files_started > 0 && REPEATED_HEADER.load(Ordering::Relaxed) {
+                    self.header_left += 1;
+                }
+                self.files_started += 1;
+                self.footer.clear();
+            }
+            if self.stdin.read_state() != ReaderState::OK as Int {
+                // Whatever we held back is the footer of the last file.
+                self.footer.clear();
+                return Ok(changed);
+            }
+            if self.header_left > 0 {
+                self.header_left -= 1;
+            } else if footer == 0 {
+                return Ok(changed);
+            } else {
+                // Hold this record back, and pass on the one read `footer` records ago.
+                let pos = self.record_pos();
+                self.footer.push_back((mem::take(line), pos));
+                if self.footer.len() > footer {
+                    let (held, pos) = self.footer.pop_front().unwrap();
+                    *line = held;
+                    self.footer_pos = Some(pos);
+                    return Ok(changed);
+                }
+            }
+            started = self.stdin.read_line_reuse(&vars.rs, rc, line)?;
+            started |= self.next_argv_file(vars, rc, line)?;
+            changed |= started;
+        }
+    }
+
+    /// Note where `line`, the record of the main input that was just read, came from.
+    fn track_record(&mut self, line: &Str) {
+        if self.stdin.read_state() != ReaderState::OK as Int {
+            // Keep describing the last record, as $0 does not.
+            return;
+        }
+        let pos = match self.footer_pos.take() {
+            Some(pos) => pos,
+            None => self.record_pos(),
+        };
+        self.record = RecordSource {
+            file: self.stdin.filename(),
+            pos,
+            text: line.clone().unmoor(),
+        };
+    }
+
+    /// Where the record the reader just returned sits in its file.
+    fn record_pos(&self) -> RecordPos {
+        RecordPos {
+            offset: self.stdin.record_offset(),
+            sep: if self.round_trip {
+                self.stdin.record_sep()
+            } else {
+                None
+            },
+        }
+    }
+
+    /// The separator `print` writes after `record` in round-trip mode: the one that ended the last
+    /// record of the main input if `record` is still that record as it was read, so that the
+    /// record is written out exactly as it appeared in the input, and `ors` otherwise.
+    pub(crate) fn round_trip_sep<'a>(&self, record: &Str<'a>, ors: &Str<'a>) -> Str<'a> {
+        match &self.record.pos.sep {
+            Some(sep) if self.record.text.upcast_ref() == record => sep.clone().upcast(),
+            _ => ors.clone(),
+        }
+    }
+
+    /// `record_file()`: the file the last record of the main input was read from.
+    pub(crate) fn record_file(&self) -> Str<'static> {
+        self.record.file.clone()
+    }
+
+    /// `record_offset()`: the byte offset at which the last record of the main input starts in its
+    /// file, or -1 if no record has been read.
+    pub(crate) fn record_offset(&self) -> Int {
+        self.record.pos.offset
+    }
+
+    /// `record_text()`: the last record of the main input as it was read, whatever has since been
+    /// assigned to $0 or its fields.
+    pub(crate) fn record_text(&self) -> Str<'static> {
+        self.record.text.clone()
+    }
+
+    /// The next `var=value` entry in ARGV that the main input has passed over, with the escape
+    /// sequences in its value processed as they are for `var=value` operands, or an empty string
+    /// if there are none left. See `Prog::desugar_operand_vardecs`.
+    pub(crate) fn argv_assign(&mut self) -> Str<'static> {
+        let arg = match self.stdin.next_assignment() {
+            Some(arg) => arg,
+            None => return Str::default(),
+        };
+        let (name, value) = arg.split_at(arg.find('=').unwrap());
+        let arena = crate::arena::Arena::default();
+        let mut buf = Vec::new();
+        let value = crate::lexer::parse_string_literal(&value[1..], &arena, &mut buf);
+        let mut res = name.as_bytes().to_vec();
+        res.push(b'=');
+        res.extend_from_slice(value);
+        Str::from(&res[..]).unmoor()
+    }
+
     pub(crate) fn stdin_filename(&self) -> Str<'static> {
         self.stdin.filename()
     }
 
+    /// The file the last record of the main input was read from, or an empty string if the main
+    /// input has ended.
+    pub(crate) fn main_input_file(&self) -> Str<'static> {
+        if self.stdin.read_state() == ReaderState::EOF as Int {
+            Str::default()
+        } else {
+            self.stdin.filename()
+        }
+    }
+
     pub(crate) fn read_err_stdin<'a>(&mut self) -> Int {
-        self.stdin.read_state()
+        if writers::stdout_closed() {
+            // Nothing we print will be seen: stop reading input.
+            return ReaderState::EOF as Int;
+        }
+        self.main_read_state()
+    }
+
+    /// Whether getline reading from `path` reads the next record of the main input; see
+    /// `is_stdin_path`.
+    fn reads_main_input(&self, path: &Str) -> bool {
+        MAIN_INPUT_IS_STDIN.load(Ordering::Relaxed) && is_stdin_path(path)
     }
 
     pub(crate) fn read_err<'a>(&mut self, path: &Str<'a>) -> Result<Int> {
-        self.with_file(path, |reader| Ok(reader.read_state()))
+        if self.reads_main_input(path) {
+            return Ok(self.read_err_stdin());
+        }
+        if self.inputs.failed.remove(&path.clone().unmoor()) {
+            return Ok(ReaderState::ERROR as Int);
+        }
+        if self.inputs.timed_out.remove(&path.clone().unmoor()) {
+            return Ok(GETLINE_TIMED_OUT);
+        }
+        Ok(self
+            .with_file(path, |reader| Ok(reader.read_state()))?
+            .unwrap_or(ReaderState::ERROR as Int))
     }
     pub(crate) fn read_err_cmd<'a>(&mut self, cmd: &Str<'a>) -> Result<Int> {
+        if self.inputs.timed_out.remove(&cmd.clone().unmoor()) {
+            return Ok(GETLINE_TIMED_OUT);
+        }
         self.with_cmd(cmd, |reader| Ok(reader.read_state()))
     }
 
     pub(crate) fn next_file(&mut self) -> Result<()> {
+        self.batch.clear();
+        self.cursor.avail = 0;
+        self.cursor.taken = 0;
+        self.from_batch = false;
         let _ = self.stdin.next_file()?;
         Ok(())
     }
@@ -536,37 +1372,46 @@ impl<LR: LineReader> FileRead<LR> {
         self.inputs.commands.get_fallible(
             cmd,
             |s| match command::command_for_read(s.as_bytes()) {
-                Ok(r) => Ok(RegexSplitter::new(
-                    r,
-                    CHUNK_SIZE,
-                    cmd.clone().unmoor(),
-                    check_utf8,
-                )),
+                Ok(r) => Ok(
+                    RegexSplitter::new(r, CHUNK_SIZE, cmd.clone().unmoor(), check_utf8).streaming(),
+                ),
                 Err(e) => err!("failed to crate command for reading: {}", e),
             },
             f,
         )
     }
 
+    /// Call `f` with the reader for `path`, opening it if necessary. Returns `None` if the file
+    /// could not be opened; getline reports this as an error (-1) rather than halting the program.
     fn with_file<'a, R>(
         &mut self,
         path: &Str<'a>,
         f: impl FnMut(&mut RegexSplitter<File>) -> Result<R>,
-    ) -> Result<R> {
+    ) -> Result<Option<R>> {
         let check_utf8 = self.stdin.check_utf8();
-        self.inputs.files.get_fallible(
+        let mut open_failed = false;
+        let res = self.inputs.files.get_fallible(
             path,
-            |s| match File::open(s) {
-                Ok(f) => Ok(RegexSplitter::new(
-                    f,
-                    CHUNK_SIZE,
-                    path.clone().unmoor(),
-                    check_utf8,
-                )),
-                Err(e) => err!("failed to open file '{}': {}", s, e),
+            |s| match open_for_getline(s) {
+                Ok(f) => Ok(
+                    RegexSplitter::new(f, CHUNK_SIZE, path.clone().unmoor(), check_utf8)
+                        .streaming(),
+                ),
+                Err(e) => {
+                    open_failed = true;
+                    err!("failed to open file '{}': {}", s, e)
+                }
             },
             f,
-        )
+        );
+        match res {
+            Ok(r) => Ok(Some(r)),
+            Err(_) if open_failed => {
+                self.inputs.failed.insert(path.clone().unmoor());
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
     }
 }
 
@@ -577,20 +1422,37 @@ pub(crate) struct Registry<T> {
     // We could be fine having duplicates for Regex. We could also also intern strings
     // as we go by swapping out one Rc for another as we encounter them. That would keep the
     // fast path fast, but we would have to make sure we weren't keeping any Refs alive.
-    cached: HashMap<Str<'static>, T>,
+    //
+    // Each value is stored alongside the last time (according to `clock`) it was used.
+    cached: HashMap<Str<'static>, (T, u64)>,
+    clock: u64,
+    // The maximum number of values to keep. Evicting the least recently used value is linear in
+    // the size of the registry, but bounded registries hold values (like regexes) that are far
+    // more expensive to create.
+    limit: usize,
+    stats: RegexStats,
 }
 impl<T> Default for Registry<T> {
     fn default() -> Self {
-        Registry {
-            cached: Default::default(),
-        }
+        Registry::bounded(usize::max_value())
     }
 }
 
 impl<T> Registry<T> {
+    fn bounded(limit: usize) -> Self {
+        Registry {
+            cached: Default::default(),
+            clock: 0,
+            limit,
+            stats: Default::default(),
+        }
+    }
     fn remove(&mut self, s: &Str) {
         self.cached.remove(&s.clone().unmoor());
     }
+    fn contains(&self, s: &Str) -> bool {
+        self.cached.contains_key(&s.clone().unmoor())
+    }
     fn get<R>(
         &mut self,
         s: &Str,
@@ -606,10 +1468,28 @@ impl<T> Registry<T> {
         getter: impl FnOnce(&mut T) -> Result<R>,
     ) -> Result<R> {
         use hashbrown::hash_map::Entry;
+        self.clock += 1;
+        self.stats.lookups += 1;
         let k_str = s.clone().unmoor();
+        if self.cached.len() >= self.limit && !self.cached.contains_key(&k_str) {
+            let lru = self
+                .cached
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(k, _)| k.clone());
+            if let Some(k) = lru {
+                self.cached.remove(&k);
+                self.stats.evictions += 1;
+            }
+        }
         match self.cached.entry(k_str) {
-            Entry::Occupied(mut o) => getter(o.get_mut()),
+            Entry::Occupied(mut o) => {
+                let (val, last_used) = o.get_mut();
+                *last_used = self.clock;
+                getter(val)
+            }
             Entry::Vacant(v) => {
+                self.stats.misses += 1;
                 let (val, res) = v.key().with_bytes(|raw_str| {
                     let s = match str::from_utf8(raw_str) {
                         Ok(s) => s,
@@ -619,7 +1499,7 @@ impl<T> Registry<T> {
                     let res = getter(&mut val);
                     Ok((val, res))
                 })?;
-                v.insert(val);
+                v.insert((val, self.clock));
                 res
             }
         }
@@ -651,28 +1531,50 @@ impl<'a> Convert<Int, Str<'a>> for _Carrier {
 }
 impl<'a> Convert<Float, Str<'a>> for _Carrier {
     fn convert(f: Float) -> Str<'a> {
-        f.into()
+        if compat::gawk() {
+            compat::float_to_str(f)
+        } else {
+            f.into()
+        }
     }
 }
 impl<'a> Convert<Str<'a>, Float> for _Carrier {
     fn convert(s: Str<'a>) -> Float {
-        s.with_bytes(strtod)
+        s.with_bytes(str_to_float)
     }
 }
 impl<'a> Convert<Str<'a>, Int> for _Carrier {
     fn convert(s: Str<'a>) -> Int {
-        s.with_bytes(strtoi)
+        s.with_bytes(str_to_int)
     }
 }
 impl<'b, 'a> Convert<&'b Str<'a>, Float> for _Carrier {
     fn convert(s: &'b Str<'a>) -> Float {
-        s.with_bytes(strtod)
+        s.with_bytes(str_to_float)
     }
 }
 impl<'b, 'a> Convert<&'b Str<'a>, Int> for _Carrier {
     fn convert(s: &'b Str<'a>) -> Int {
-        s.with_bytes(strtoi)
+        s.with_bytes(str_to_int)
+    }
+}
+
+fn str_to_float(bs: &[u8]) -> Float {
+    if non_decimal_data() {
+        if let Some(i) = radixtoi(bs) {
+            return i as Float;
+        }
+    }
+    strtod(bs)
+}
+
+fn str_to_int(bs: &[u8]) -> Int {
+    if non_decimal_data() {
+        if let Some(i) = radixtoi(bs) {
+            return i;
+        }
     }
+    strtoi(bs)
 }
 
 pub(crate) fn convert<S, T>(s: S) -> T
@@ -682,11 +1584,31 @@ where
     _Carrier::convert(s)
 }
 
+/// Whether `s` is missing as a number ("NA"), for `isna` and `numor`: it is empty, blank, or does
+/// not look like a number.
+pub(crate) fn is_na(s: &Str) -> bool {
+    s.with_bytes(|bs| {
+        !(compat::looks_numeric(bs) || (non_decimal_data() && radixtoi(bs).is_some()))
+    })
+}
+
+/// `numor(s, default)`: the numeric value of `s`, or `default` if it is NA.
+pub(crate) fn num_or(s: &Str, default: Float) -> Float {
+    if is_na(s) {
+        return default;
+    }
+    s.with_bytes(|bs| {
+        // `is_na` allows leading blanks, but the conversion does not skip them.
+        let start = bs.iter().position(|b| !b.is_ascii_whitespace());
+        str_to_float(&bs[start.unwrap_or(bs.len())..])
+    })
+}
+
 // AWK arrays are inherently shared and mutable, so we have to do this, even if it is a code smell.
 // NB These are repr(transparent) because we pass them around as void* when compiling with LLVM.
 #[repr(transparent)]
 #[derive(Debug)]
-pub(crate) struct SharedMap<K, V>(Rc<RefCell<HashMap<K, V>>>);
+pub(crate) struct SharedMap<K, V>(Rc<RefCell<bounded::MapRep<K, V>>>);
 
 impl<K, V> Default for SharedMap<K, V> {
     fn default() -> SharedMap<K, V> {
@@ -700,7 +1622,7 @@ impl<K, V> Clone for SharedMap<K, V> {
     }
 }
 
-impl<K: Hash + Eq, V> SharedMap<K, V> {
+impl<K: MapKey, V> SharedMap<K, V> {
     pub(crate) fn len(&self) -> usize {
         self.0.borrow().len()
     }
@@ -710,9 +1632,17 @@ impl<K: Hash + Eq, V> SharedMap<K, V> {
     pub(crate) fn delete(&self, k: &K) {
         self.0.borrow_mut().remove(k);
     }
+    pub(crate) fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+    /// Implements `bound(m, size, policy)`, returning the number of entries evicted.
+    pub(crate) fn set_bound(&self, size: Int, policy: &Str) -> Result<Int> {
+        let policy = policy.with_bytes(bounded::Eviction::parse)?;
+        Ok(self.0.borrow_mut().set_bound(size, policy))
+    }
     pub(crate) fn iter<'a, F, R>(&'a self, f: F) -> R
     where
-        F: FnOnce(hashbrown::hash_map::Iter<K, V>) -> R,
+        F: FnOnce(table::Iter<K, V>) -> R,
     {
         f(self.0.borrow().iter())
     }
@@ -723,23 +1653,33 @@ impl<K: Hash + Eq, V> SharedMap<K, V> {
 pub(crate) struct Shuttle<T>(T);
 impl<'a> From<Shuttle<HashMap<Int, UniqueStr<'a>>>> for IntMap<Str<'a>> {
     fn from(sh: Shuttle<HashMap<Int, UniqueStr<'a>>>) -> Self {
-        SharedMap(Rc::new(RefCell::new(
-            sh.0.into_iter().map(|(x, y)| (x, y.into_str())).collect(),
-        )))
+        SharedMap::from(
+            sh.0.into_iter()
+                .map(|(x, y)| (x, y.into_str()))
+                .collect::<HashMap<_, _>>(),
+        )
     }
 }
 
 impl<'a> From<Shuttle<HashMap<UniqueStr<'a>, Int>>> for StrMap<'a, Int> {
     fn from(sh: Shuttle<HashMap<UniqueStr<'a>, Int>>) -> Self {
-        SharedMap(Rc::new(RefCell::new(
-            sh.0.into_iter().map(|(x, y)| (x.into_str(), y)).collect(),
-        )))
+        SharedMap::from(
+            sh.0.into_iter()
+                .map(|(x, y)| (x.into_str(), y))
+                .collect::<HashMap<_, _>>(),
+        )
     }
 }
 
-impl<K: Hash + Eq, V: Clone> SharedMap<K, V> {
+impl<K: MapKey, V: Clone> SharedMap<K, V> {
     pub(crate) fn get(&self, k: &K) -> Option<V> {
-        self.0.borrow().get(k).cloned()
+        {
+            let m = self.0.borrow();
+            if !m.is_bounded() {
+                return m.get(k).cloned();
+            }
+        }
+        self.0.borrow_mut().get_touch(k).cloned()
     }
 }
 
@@ -749,7 +1689,7 @@ impl<'a> IntMap<Str<'a>> {
             self.0
                 .borrow()
                 .iter()
-                .map(|(x, y)| (*x, UniqueStr::from(y.clone())))
+                .map(|(x, y)| (x, UniqueStr::from(y.clone())))
                 .collect(),
         )
     }
@@ -761,35 +1701,33 @@ impl<'a> StrMap<'a, Int> {
             self.0
                 .borrow()
                 .iter()
-                .map(|(x, y)| (UniqueStr::from(x.clone()), *y))
+                .map(|(x, y)| (UniqueStr::from(x), *y))
                 .collect(),
         )
     }
 }
 
-impl<K: Hash + Eq + Clone, V> SharedMap<K, V> {
+impl<K: MapKey, V> SharedMap<K, V> {
     pub(crate) fn to_iter(&self) -> Iter<K> {
-        self.0.borrow().keys().cloned().collect()
+        self.0.borrow().keys().collect()
     }
     pub(crate) fn to_vec(&self) -> Vec<K> {
-        self.0.borrow().keys().cloned().collect()
+        self.0.borrow().keys().collect()
     }
 }
 
-impl<K: Hash + Eq, V> From<HashMap<K, V>> for SharedMap<K, V> {
+impl<K: MapKey, V> From<HashMap<K, V>> for SharedMap<K, V> {
     fn from(m: HashMap<K, V>) -> SharedMap<K, V> {
-        SharedMap(Rc::new(RefCell::new(m)))
+        SharedMap(Rc::new(RefCell::new(m.into())))
     }
 }
 
-impl<K: Hash + Eq, V> FromIterator<(K, V)> for SharedMap<K, V> {
+impl<K: MapKey, V> FromIterator<(K, V)> for SharedMap<K, V> {
     fn from_iter<T>(iter: T) -> Self
     where
         T: IntoIterator<Item = (K, V)>,
     {
-        SharedMap(Rc::new(RefCell::new(
-            iter.into_iter().collect::<HashMap<K, V>>(),
-        )))
+        SharedMap::from(iter.into_iter().collect::<HashMap<K, V>>())
     }
 }
 
@@ -833,3 +1771,34 @@ impl<S> Iter<S> {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regex_cache_evicts_least_recently_used() {
+        let mut cache = RegexCache(Registry::bounded(2));
+        let is_match = |cache: &mut RegexCache, pat: &str| {
+            cache
+                .with_matcher(&Str::from(pat), |m| m.is_match(b"abc"))
+                .unwrap()
+        };
+        assert!(is_match(&mut cache, "a"));
+        assert!(is_match(&mut cache, "b"));
+        assert!(is_match(&mut cache, "a"));
+        assert!(!is_match(&mut cache, "d"));
+        // "b" was the least recently used, so adding "d" evicted it.
+        assert_eq!(cache.0.cached.len(), 2);
+        assert!(cache.0.cached.contains_key(&Str::from("a")));
+        assert!(!cache.0.cached.contains_key(&Str::from("b")));
+        assert_eq!(
+            cache.stats(),
+            RegexStats {
+                lookups: 4,
+                misses: 3,
+                evictions: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,377 @@
+//! The environment, the filesystem and the clock, for `getenv`, `setenv`, `stat`, `glob`,
+//! `mkdirp`, `rename`, `rmfile`, `sleep` and `monotime`.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+
+use crate::common::Result;
+use crate::runtime::{Float, Int, IntMap, Str, StrMap};
+
+fn to_string(s: &Str) -> String {
+    s.with_bytes(|bs| String::from_utf8_lossy(bs).into_owned())
+}
+
+/// `getenv(name[, default])`: the value of the environment variable `name`, or `default` if it is
+/// not set.
+pub(crate) fn getenv<'a>(name: &Str<'a>, default: &Str<'a>) -> Str<'a> {
+    match env::var_os(to_string(name)) {
+        Some(val) => Str::from(val.to_string_lossy().into_owned()),
+        None => default.clone(),
+    }
+}
+
+/// `setenv(name, val)`: set the environment variable `name` to `val`, for this program and for
+/// every command that it starts afterwards. Returns 1.
+pub(crate) fn setenv(name: &Str, val: &Str) -> Result<Int> {
+    let (name, val) = (to_string(name), to_string(val));
+    if name.is_empty() || name.contains('=') || name.contains('\0') || val.contains('\0') {
+        return err!("setenv: invalid environment variable {:?}", name);
+    }
+    env::set_var(name, val);
+    Ok(1)
+}
+
+#[cfg(unix)]
+fn mode(md: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    md.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(md: &fs::Metadata) -> u32 {
+    if md.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+/// `stat(path, a)`: replace the contents of `a` with information about the file at `path`,
+/// following symbolic links: its `size` in bytes, its modification time `mtime` in seconds since
+/// the epoch, its permission bits `mode` in octal, and its `type` (`file`, `directory` or
+/// `other`). Returns 1, or -1 (leaving `a` empty) if `path` does not exist or cannot be read.
+pub(crate) fn stat<'a>(path: &Str<'a>, a: &StrMap<'a, Str<'a>>) -> Int {
+    a.clear();
+    let md = match fs::metadata(to_string(path)) {
+        Ok(md) => md,
+        Err(_) => return -1,
+    };
+    let mtime = md
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    let ty = if md.is_file() {
+        "file"
+    } else if md.is_dir() {
+        "directory"
+    } else {
+        "other"
+    };
+    a.insert(Str::from("size"), Str::from(md.len() as Int));
+    a.insert(Str::from("mtime"), Str::from(mtime as Int));
+    a.insert(Str::from("mode"), Str::from(format!("{:o}", mode(&md))));
+    a.insert(Str::from("type"), Str::from(ty));
+    1
+}
+
+// Whether the file name `name` matches the wildcard pattern `pat`: `*` matches any sequence of
+// characters, `?` matches one character, and `[...]` matches one character from a set such as
+// `[a-z0-9_]`, or from outside of it if the set starts with `!` or `^`. `\` escapes the next
+// character.
+fn wildcard_match(pat: &[char], name: &[char]) -> bool {
+    // Where to resume after the most recent `*`: the pattern after it, and the next character of
+    // the name that it could absorb.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        let step = match pat.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pat[p..], name[n]),
+            Some('\\') if p + 1 < pat.len() => {
+                if pat[p + 1] == name[n] {
+                    Some(2)
+                } else {
+                    None
+                }
+            }
+            Some(c) if *c == name[n] => Some(1),
+            _ => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            }
+            (None, Some((after, absorbed))) => {
+                star = Some((after, absorbed + 1));
+                p = after;
+                n = absorbed + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    pat[p..].iter().all(|c| *c == '*')
+}
+
+// If `pat` starts with a character class that matches `c`, the length of the class. A `[` with
+// no closing `]` only matches itself.
+fn match_class(pat: &[char], c: char) -> Option<usize> {
+    let mut i = 1;
+    let negate = matches!(pat.get(i), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
+    let mut found = false;
+    let start = i;
+    loop {
+        match pat.get(i) {
+            None => return if c == '[' { Some(1) } else { None },
+            Some(']') if i > start => break,
+            Some(lo) => {
+                if pat.get(i + 1) == Some(&'-') && i + 2 < pat.len() && pat[i + 2] != ']' {
+                    found |= *lo <= c && c <= pat[i + 2];
+                    i += 3;
+                } else {
+                    found |= *lo == c;
+                    i += 1;
+                }
+            }
+        }
+    }
+    if found != negate {
+        Some(i + 1)
+    } else {
+        None
+    }
+}
+
+fn has_wildcards(component: &str) -> bool {
+    component.contains(|c| c == '*' || c == '?' || c == '[')
+}
+
+/// `glob(pattern, a)`: replace the contents of `a` with the paths matching the shell wildcard
+/// pattern `pattern`, in sorted order and numbered from 1, returning how many there are. As in the
+/// shell, wildcards do not match the `/` between directories, and only match a leading `.` if the
+/// pattern spells it out.
+pub(crate) fn glob<'a>(pattern: &Str<'a>, a: &IntMap<Str<'a>>) -> Int {
+    a.clear();
+    let pattern = to_string(pattern);
+    let mut paths = vec![PathBuf::new()];
+    let mut components = pattern.split('/').peekable();
+    if pattern.starts_with('/') {
+        paths = vec![PathBuf::from("/")];
+        components.next();
+    }
+    while let Some(component) = components.next() {
+        let last = components.peek().is_none();
+        if component.is_empty() {
+            continue;
+        }
+        let mut next = Vec::new();
+        if !has_wildcards(component) {
+            let component = component.replace('\\', "");
+            for path in paths {
+                let path = path.join(&component);
+                if !last || fs::symlink_metadata(&path).is_ok() {
+                    next.push(path);
+                }
+            }
+        } else {
+            let pat: Vec<char> = component.chars().collect();
+            for path in paths {
+                let dir = if path.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    path.as_path()
+                };
+                let entries = match fs::read_dir(dir) {
+                    Ok(entries) => entries,
+                    Err(_) => continue,
+                };
+                for entry in entries.filter_map(|e| e.ok()) {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if name.starts_with('.') && !component.starts_with('.') {
+                        continue;
+                    }
+                    let chars: Vec<char> = name.chars().collect();
+                    if wildcard_match(&pat, &chars) && (last || entry.path().is_dir()) {
+                        next.push(path.join(name));
+                    }
+                }
+            }
+        }
+        paths = next;
+    }
+    let mut matches: Vec<String> = paths
+        .into_iter()
+        .filter(|p| !p.as_os_str().is_empty() && p.exists())
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    matches.sort();
+    for (i, m) in matches.iter().enumerate() {
+        a.insert(i as Int + 1, Str::from(m.clone()));
+    }
+    matches.len() as Int
+}
+
+fn status(res: std::io::Result<()>) -> Int {
+    if res.is_ok() {
+        1
+    } else {
+        -1
+    }
+}
+
+/// `mkdirp(path)`: create the directory `path` along with any missing parent directories, like
+/// `mkdir -p`. Returns 1, or -1 if the directory could not be created.
+pub(crate) fn mkdirp(path: &Str) -> Int {
+    status(fs::create_dir_all(to_string(path)))
+}
+
+/// `rename(from, to)`: move the file `from` to `to`, replacing `to` if it exists. Within a
+/// filesystem this happens atomically. Returns 1, or -1 if the file could not be moved.
+pub(crate) fn rename(from: &Str, to: &Str) -> Int {
+    status(fs::rename(to_string(from), to_string(to)))
+}
+
+/// `rmfile(path)`: remove the file `path`. Returns 1, or -1 if it could not be removed; directories
+/// are never removed.
+pub(crate) fn rmfile(path: &Str) -> Int {
+    status(fs::remove_file(to_string(path)))
+}
+
+/// `sleep(secs)`: pause for `secs` seconds, which may be fractional. Negative and NaN durations do
+/// not pause at all. Returns 0.
+pub(crate) fn sleep(secs: Float) -> Int {
+    if secs > 0.0 {
+        std::thread::sleep(Duration::from_secs_f64(secs.min(u32::MAX as Float)));
+    }
+    0
+}
+
+lazy_static! {
+    static ref CLOCK_START: Instant = Instant::now();
+}
+
+/// `monotime()`: seconds elapsed on a monotonic clock since the first time it was read, with
+/// sub-microsecond resolution. The result never goes backwards, even if the system clock is
+/// adjusted, so the difference between two calls times the code in between.
+pub(crate) fn monotime() -> Float {
+    CLOCK_START.elapsed().as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wildcard(pat: &str, name: &str) -> bool {
+        let pat: Vec<char> = pat.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        wildcard_match(&pat, &name)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard("*.log", "a.log"));
+        assert!(wildcard("*.log", ".log"));
+        assert!(!wildcard("*.log", "a.log.gz"));
+        assert!(wildcard("a*b*c", "aXbYbZc"));
+        assert!(!wildcard("a*b*c", "aXbYbZ"));
+        assert!(wildcard("file?.txt", "file1.txt"));
+        assert!(!wildcard("file?.txt", "file.txt"));
+        assert!(wildcard("[a-c]x", "bx"));
+        assert!(!wildcard("[a-c]x", "dx"));
+        assert!(wildcard("[!a-c]x", "dx"));
+        assert!(wildcard("[]]", "]"));
+        assert!(wildcard("[-a]", "-"));
+        assert!(wildcard("[ab", "[ab"));
+        assert!(wildcard("\\*", "*"));
+        assert!(!wildcard("\\*", "x"));
+        assert!(wildcard("**", ""));
+    }
+
+    #[test]
+    fn glob_and_stat() {
+        let dir = std::env::temp_dir().join(format!("frawk_glob_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for f in &["a.log", "b.log", "c.txt", ".hidden.log", "sub/d.log"] {
+            fs::write(dir.join(f), "12345").unwrap();
+        }
+        let base = dir.to_string_lossy().into_owned();
+        let found = |pat: &str| {
+            let a = IntMap::default();
+            let n = glob(&Str::from(format!("{}/{}", base, pat)), &a);
+            assert_eq!(n as usize, a.len());
+            (1..=n)
+                .map(|i| {
+                    let s = a.get(&i).unwrap();
+                    let s = to_string(&s);
+                    s[base.len() + 1..].to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(found("*.log"), vec!["a.log", "b.log"]);
+        assert_eq!(found(".*.log"), vec![".hidden.log"]);
+        assert_eq!(found("*/*.log"), vec!["sub/d.log"]);
+        assert_eq!(found("[bc].*"), vec!["b.log", "c.txt"]);
+        assert_eq!(found("c.txt"), vec!["c.txt"]);
+        assert_eq!(found("*.csv"), Vec::<String>::new());
+
+        let a = StrMap::default();
+        assert_eq!(stat(&Str::from(format!("{}/a.log", base)), &a), 1);
+        assert_eq!(a.get(&Str::from("size")), Some(Str::from("5")));
+        assert_eq!(a.get(&Str::from("type")), Some(Str::from("file")));
+        assert_eq!(stat(&Str::from(format!("{}/sub", base)), &a), 1);
+        assert_eq!(a.get(&Str::from("type")), Some(Str::from("directory")));
+        assert_eq!(stat(&Str::from(format!("{}/missing", base)), &a), -1);
+        assert_eq!(a.len(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_management() {
+        let dir = std::env::temp_dir().join(format!("frawk_files_{}", std::process::id()));
+        let path = |p: &str| Str::from(dir.join(p).to_string_lossy().into_owned());
+        assert_eq!(mkdirp(&path("a/b/c")), 1);
+        assert_eq!(mkdirp(&path("a/b/c")), 1);
+        assert!(dir.join("a/b/c").is_dir());
+        fs::write(dir.join("a/x.tmp"), "x").unwrap();
+        assert_eq!(rename(&path("a/x.tmp"), &path("a/b/x")), 1);
+        assert_eq!(fs::read_to_string(dir.join("a/b/x")).unwrap(), "x");
+        assert_eq!(rename(&path("a/x.tmp"), &path("a/b/x")), -1);
+        assert_eq!(rmfile(&path("a/b/c")), -1);
+        assert_eq!(rmfile(&path("a/b/x")), 1);
+        assert_eq!(rmfile(&path("a/b/x")), -1);
+        fs::write(dir.join("a/f"), "").unwrap();
+        assert_eq!(mkdirp(&path("a/f/g")), -1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn environment() {
+        let name = Str::from("FRAWK_OS_TEST_VAR");
+        assert_eq!(getenv(&name, &Str::from("dflt")), Str::from("dflt"));
+        assert_eq!(setenv(&name, &Str::from("val")).unwrap(), 1);
+        assert_eq!(getenv(&name, &Str::from("dflt")), Str::from("val"));
+        assert!(setenv(&Str::from("A=B"), &Str::from("val")).is_err());
+    }
+
+    #[test]
+    fn clock() {
+        let start = monotime();
+        assert_eq!(sleep(0.02), 0);
+        let elapsed = monotime() - start;
+        assert!(elapsed >= 0.02, "elapsed={}", elapsed);
+        assert_eq!(sleep(-1.0), 0);
+        assert_eq!(sleep(Float::NAN), 0);
+    }
+}
@@ -0,0 +1,142 @@
+//! `lpad`, `rpad` and `zfill`.
+//!
+//! These cover the most common uses of `sprintf("%10s", s)`, `sprintf("%-10s", s)` and
+//! `sprintf("%05d", n)` in report-generating scripts without going through the general-purpose
+//! formatting code: the result is written directly into a buffer of its final size, and strings
+//! that are already wide enough are returned without copying them. Widths count characters, or
+//! bytes with `--bytes`.
+use super::{chars, str_impl::DynamicBuf, utf8::is_char_boundary, Int, Str};
+
+use std::io::Write;
+
+/// The padding used by `lpad(s, n)` and `rpad(s, n)`.
+pub(crate) const DEFAULT_PAD: &[u8] = b" ";
+
+/// The number of characters that must be added to `s` to make it `width` characters wide.
+fn missing(s: &Str, width: Int) -> usize {
+    if width <= 0 || (width as usize) <= s.len() / 4 {
+        // Every character takes at most 4 bytes, so `s` is already wide enough.
+        return 0;
+    }
+    let len = chars::len(s);
+    if len >= width {
+        0
+    } else {
+        (width - len) as usize
+    }
+}
+
+/// Write `n` characters of `pad`, repeating it as many times as necessary.
+fn write_padding(buf: &mut DynamicBuf, pad: &[u8], n: usize) {
+    if pad.len() == 1 {
+        for _ in 0..n {
+            buf.write(pad).unwrap();
+        }
+        return;
+    }
+    let by_bytes = chars::bytes_mode() || pad.is_ascii();
+    let mut written = 0;
+    let mut start = 0;
+    while written < n {
+        let mut end = start + 1;
+        if !by_bytes {
+            while end < pad.len() && !is_char_boundary(pad[end]) {
+                end += 1;
+            }
+        }
+        buf.write(&pad[start..end]).unwrap();
+        written += 1;
+        start = if end == pad.len() { 0 } else { end };
+    }
+}
+
+fn pad<'a>(s: &Str<'a>, width: Int, with: &Str, left: bool) -> Str<'a> {
+    let n = missing(s, width);
+    if n == 0 {
+        return s.clone();
+    }
+    with.with_bytes(|pad| {
+        if pad.is_empty() {
+            return s.clone();
+        }
+        s.with_bytes(|bs| {
+            let mut buf = DynamicBuf::new(bs.len() + n * pad.len());
+            if left {
+                write_padding(&mut buf, pad, n);
+                buf.write(bs).unwrap();
+            } else {
+                buf.write(bs).unwrap();
+                write_padding(&mut buf, pad, n);
+            }
+            unsafe { buf.into_str() }
+        })
+    })
+}
+
+/// `lpad(s, n, c)`: `s`, preceded by enough copies of `c` to make it `n` characters wide.
+pub(crate) fn lpad<'a>(s: &Str<'a>, width: Int, with: &Str) -> Str<'a> {
+    pad(s, width, with, /*left=*/ true)
+}
+
+/// `rpad(s, n, c)`: `s`, followed by enough copies of `c` to make it `n` characters wide.
+pub(crate) fn rpad<'a>(s: &Str<'a>, width: Int, with: &Str) -> Str<'a> {
+    pad(s, width, with, /*left=*/ false)
+}
+
+/// `zfill(s, n)`: `s` padded with zeros to `n` characters. The zeros go after a leading sign, so
+/// that `zfill(-42, 5)` is `-0042`.
+pub(crate) fn zfill<'a>(s: &Str<'a>, width: Int) -> Str<'a> {
+    let n = missing(s, width);
+    if n == 0 {
+        return s.clone();
+    }
+    s.with_bytes(|bs| {
+        let sign = match bs.first() {
+            Some(b'-') | Some(b'+') => 1,
+            _ => 0,
+        };
+        let mut buf = DynamicBuf::new(bs.len() + n);
+        buf.write(&bs[..sign]).unwrap();
+        for _ in 0..n {
+            buf.write(b"0").unwrap();
+        }
+        buf.write(&bs[sign..]).unwrap();
+        unsafe { buf.into_str() }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding() {
+        for (s, width, with, left, right) in &[
+            ("abc", 6, " ", "   abc", "abc   "),
+            ("abc", 3, " ", "abc", "abc"),
+            ("abc", -1, " ", "abc", "abc"),
+            ("abc", 8, "xy", "xyxyxabc", "abcxyxyx"),
+            ("abc", 5, "", "abc", "abc"),
+            ("héllo", 7, ".", "..héllo", "héllo.."),
+            ("x", 4, "äö", "äöäx", "xäöä"),
+            ("", 2, "-", "--", "--"),
+        ] {
+            let (s, with) = (Str::from(*s), Str::from(*with));
+            assert_eq!(lpad(&s, *width, &with), Str::from(*left));
+            assert_eq!(rpad(&s, *width, &with), Str::from(*right));
+        }
+    }
+
+    #[test]
+    fn zero_filling() {
+        for (s, width, expected) in &[
+            ("42", 5, "00042"),
+            ("-42", 5, "-0042"),
+            ("+4.5", 6, "+004.5"),
+            ("12345", 3, "12345"),
+            ("", 2, "00"),
+        ] {
+            assert_eq!(zfill(&Str::from(*s), *width), Str::from(*expected));
+        }
+    }
+}
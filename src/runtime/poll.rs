@@ -0,0 +1,73 @@
+//! Waiting for input, for the `readable` builtin.
+//!
+//! Only Unix platforms are supported; elsewhere, `fd` finds nothing to wait on, so every input
+//! counts as ready.
+use crate::common::Result;
+use crate::runtime::Int;
+
+#[cfg(unix)]
+pub(crate) use unix::*;
+
+#[cfg(not(unix))]
+pub(crate) use fallback::*;
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::io;
+    use std::os::unix::io::{AsRawFd, RawFd};
+
+    pub(crate) type Fd = RawFd;
+
+    /// The file descriptor to wait on to read from `r`.
+    pub(crate) fn fd(r: &impl AsRawFd) -> Option<Fd> {
+        Some(r.as_raw_fd())
+    }
+
+    /// Wait for at least one of `fds` to have data available (or to reach the end of its input),
+    /// for at most `timeout` milliseconds. A negative timeout waits indefinitely. Returns whether
+    /// each of `fds` is ready.
+    pub(crate) fn readable(fds: &[Fd], timeout: Int) -> Result<Vec<bool>> {
+        let mut pfds: Vec<_> = fds
+            .iter()
+            .map(|fd| libc::pollfd {
+                fd: *fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = if timeout < 0 {
+            -1
+        } else {
+            timeout.min(libc::c_int::max_value() as Int) as libc::c_int
+        };
+        loop {
+            let rc = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout) };
+            if rc >= 0 {
+                break;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return err!("failed to wait for input: {}", e);
+            }
+        }
+        // POLLHUP and POLLERR are reported for inputs that have ended or failed. getline returns
+        // immediately for those, too.
+        Ok(pfds.iter().map(|p| p.revents != 0).collect())
+    }
+}
+
+#[cfg(not(unix))]
+mod fallback {
+    use super::*;
+
+    pub(crate) type Fd = ();
+
+    pub(crate) fn fd<R>(_: &R) -> Option<Fd> {
+        None
+    }
+
+    pub(crate) fn readable(fds: &[Fd], _timeout: Int) -> Result<Vec<bool>> {
+        Ok(vec![true; fds.len()])
+    }
+}
@@ -10,7 +10,7 @@
 //! appends a newline) may find some bytes replaced inadvertently. We could solve this by adding a
 //! new print function that does not append a newline.
 use crate::common::Result;
-use crate::runtime::{convert, strtoi, Float, Int, Str};
+use crate::runtime::{convert, strtoi, warnings, Float, Int, Str};
 
 use std::convert::TryFrom;
 use std::fmt;
@@ -41,7 +41,15 @@ impl Write for StackWriter {
 struct DisplayBytes<'a>(&'a [u8]);
 impl<'a> fmt::Display for DisplayBytes<'a> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Display::fmt(&*std::string::String::from_utf8_lossy(self.0), fmt)
+        let s = std::string::String::from_utf8_lossy(self.0);
+        if let std::borrow::Cow::Owned(_) = s {
+            warnings::warn(
+                warnings::Kind::InvalidUtf8,
+                None,
+                "replaced invalid UTF-8 in printf argument",
+            );
+        }
+        fmt::Display::fmt(&*s, fmt)
     }
 }
 
@@ -138,7 +146,7 @@ impl Default for FormatSpec {
 
 fn is_spec(c: u8) -> bool {
     match c {
-        b'f' | b'c' | b'd' | b'e' | b'g' | b'o' | b's' | b'x' => true,
+        b'b' | b'f' | b'c' | b'd' | b'e' | b'g' | b'o' | b's' | b'x' => true,
         _ => false,
     }
 }
@@ -220,6 +228,7 @@ fn process_spec(mut w: impl Write, fspec: &mut FormatSpec, arg: &FormatArg) -> R
         b'd' => match_for_spec!("", arg.to_int()),
         b'o' => match_for_spec!("o", arg.to_int()),
         b'x' => match_for_spec!("x", arg.to_int()),
+        b'b' => match_for_spec!("b", arg.to_int()),
         b'c' => {
             // First, see if we have something ascii/UTF8 here
             match char::try_from(arg.to_int() as u32) {
@@ -438,6 +447,8 @@ mod tests {
         assert_eq!(s1.as_str(), "000142 |Feb       |");
         let s2 = sprintf!(b"|%-10.");
         assert_eq!(s2.as_str(), "|%-10.");
+        let s3 = sprintf!(b"%b %08b|%-6b|", 5, 10, 3);
+        assert_eq!(s3.as_str(), "101 00001010|11    |");
     }
 
     #[test]
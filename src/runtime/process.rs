@@ -0,0 +1,178 @@
+//! Subprocesses started with `spawn`, for the `readline`, `writeline` and `wait` builtins.
+//!
+//! A process is named by a small integer handle rather than by its command string, so the same
+//! command can be running more than once and closing one process cannot affect another. Its
+//! standard output and standard error are each drained by a thread that passes complete lines
+//! back over a channel: a process that fills one pipe while the program waits on the other cannot
+//! deadlock, and a process whose output is never read still runs to completion.
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use crate::common::Result;
+use crate::runtime::{command, Int, Str};
+
+/// The stream that `readline` reads from by default.
+pub(crate) const STDOUT: Int = 1;
+/// The stream holding a process's standard error.
+pub(crate) const STDERR: Int = 2;
+
+struct Process {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: Receiver<Vec<u8>>,
+    stderr: Receiver<Vec<u8>>,
+    status: Option<Int>,
+}
+
+#[derive(Default)]
+pub(crate) struct Processes {
+    procs: Vec<Process>,
+}
+
+fn lines(r: impl Read + Send + 'static) -> Receiver<Vec<u8>> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut r = BufReader::new(r);
+        loop {
+            let mut line = Vec::new();
+            match r.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
+
+fn exit_code(status: io::Result<std::process::ExitStatus>) -> Int {
+    match status {
+        Ok(status) => status.code().map(Int::from).unwrap_or(1),
+        Err(e) => e.raw_os_error().map(Int::from).unwrap_or(1),
+    }
+}
+
+impl Processes {
+    fn get(&mut self, func: &str, h: Int) -> Result<&mut Process> {
+        if h >= 1 && h as usize <= self.procs.len() {
+            Ok(&mut self.procs[h as usize - 1])
+        } else {
+            err!("{}: invalid process handle {}", func, h)
+        }
+    }
+
+    /// `spawn(cmd)`: start the shell command `cmd` with its standard input, output and error
+    /// connected to the program, returning a handle for it, or -1 if it could not be started.
+    pub(crate) fn spawn(&mut self, cmd: &Str) -> Int {
+        let child = cmd.with_bytes(|bs| {
+            command::prepare_command(bs)?
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+        });
+        let mut child = match child {
+            Ok(child) => child,
+            Err(_) => return -1,
+        };
+        let stdin = child.stdin.take();
+        let stdout = lines(child.stdout.take().unwrap());
+        let stderr = lines(child.stderr.take().unwrap());
+        self.procs.push(Process {
+            child,
+            stdin,
+            stdout,
+            stderr,
+            status: None,
+        });
+        self.procs.len() as Int
+    }
+
+    /// `readline(h[, stream])`: the next line that the process `h` wrote to its standard output,
+    /// or to its standard error if `stream` is 2, including the trailing newline. Returns the
+    /// empty string once the stream has ended, so that it can be told apart from an empty line.
+    pub(crate) fn read_line(&mut self, h: Int, stream: Int) -> Result<Str<'static>> {
+        let proc = self.get("readline", h)?;
+        let rx = match stream {
+            STDOUT => &proc.stdout,
+            STDERR => &proc.stderr,
+            _ => return err!("readline: invalid stream {} (expected 1 or 2)", stream),
+        };
+        Ok(match rx.recv() {
+            Ok(line) => Str::from(&line[..]).unmoor(),
+            Err(_) => Str::default(),
+        })
+    }
+
+    /// `writeline(h, s)`: write `s` followed by a newline to the standard input of the process
+    /// `h`. Returns 1 on success and -1 if the process is no longer reading its input.
+    pub(crate) fn write_line(&mut self, h: Int, s: &Str) -> Result<Int> {
+        let proc = self.get("writeline", h)?;
+        let stdin = match &mut proc.stdin {
+            Some(stdin) => stdin,
+            None => return Ok(-1),
+        };
+        let res = s.with_bytes(|bs| {
+            stdin.write_all(bs)?;
+            stdin.write_all(b"\n")?;
+            stdin.flush()
+        });
+        Ok(if res.is_ok() { 1 } else { -1 })
+    }
+
+    /// `wait(h)`: close the standard input of the process `h` and wait for it to exit, returning
+    /// its exit status. Any output that has not been read yet can still be read afterwards.
+    pub(crate) fn wait(&mut self, h: Int) -> Result<Int> {
+        let proc = self.get("wait", h)?;
+        if let Some(status) = proc.status {
+            return Ok(status);
+        }
+        proc.stdin = None;
+        let status = exit_code(proc.child.wait());
+        proc.status = Some(status);
+        Ok(status)
+    }
+}
+
+impl Drop for Processes {
+    // Processes that were never waited for are waited for at exit, after closing their input, so
+    // that none are left behind.
+    fn drop(&mut self) {
+        for proc in self.procs.iter_mut() {
+            if proc.status.is_none() {
+                proc.stdin = None;
+                let _ = proc.child.wait();
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawn_read_write_wait() {
+        let mut procs = Processes::default();
+        let h = procs.spawn(&Str::from("tr a-z A-Z; echo oops >&2; exit 3"));
+        assert_eq!(h, 1);
+        assert_eq!(procs.write_line(h, &Str::from("hello")).unwrap(), 1);
+        assert_eq!(procs.write_line(h, &Str::from("")).unwrap(), 1);
+        assert_eq!(procs.wait(h).unwrap(), 3);
+        assert_eq!(procs.wait(h).unwrap(), 3);
+        assert_eq!(procs.write_line(h, &Str::from("late")).unwrap(), -1);
+        assert_eq!(procs.read_line(h, STDOUT).unwrap(), Str::from("HELLO\n"));
+        assert_eq!(procs.read_line(h, STDOUT).unwrap(), Str::from("\n"));
+        assert_eq!(procs.read_line(h, STDOUT).unwrap(), Str::default());
+        assert_eq!(procs.read_line(h, STDERR).unwrap(), Str::from("oops\n"));
+        assert_eq!(procs.read_line(h, STDERR).unwrap(), Str::default());
+        assert!(procs.read_line(h, 3).is_err());
+        assert!(procs.read_line(2, STDOUT).is_err());
+        assert!(procs.wait(0).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! Progress reports for `--progress`.
+//!
+//! The readers for the main input count the bytes they read, and a background thread prints how
+//! far through the input the program is to standard error every so often. When the size of the
+//! input is known up front, because every input file (or standard input) is a regular file, the
+//! report includes the percentage read and an estimate of the time remaining; otherwise it only
+//! says how much has been read, and how quickly.
+//!
+//! On a terminal, each report overwrites the last one, and the line is cleared once the program
+//! finishes; if standard output goes to the same terminal, reports are printed on lines of their
+//! own instead, so that output does not end up in the middle of one. When standard error is
+//! redirected to a log, reports are printed less often. Nothing is printed for programs that
+//! finish within the first interval.
+use lazy_static::lazy_static;
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often to report progress when standard error is a terminal.
+const TERMINAL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to report progress otherwise.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default)]
+struct Reporter {
+    // Whether the last thing written to standard error was a report, which needs clearing.
+    pending: bool,
+    done: bool,
+}
+
+lazy_static! {
+    static ref REPORTER: Mutex<Reporter> = Mutex::new(Reporter::default());
+}
+
+/// Whether `--progress` was passed.
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Start reporting progress through `total` bytes of input, or through an input of unknown size
+/// if it is None. This starts the thread that prints the reports, and must be called before the
+/// main input is opened.
+pub fn enable(total: Option<u64>) {
+    ENABLED.store(true, Ordering::Relaxed);
+    let start = Instant::now();
+    let terminal = io::stderr().is_terminal();
+    let overwrite = terminal && !io::stdout().is_terminal();
+    let interval = if terminal {
+        TERMINAL_INTERVAL
+    } else {
+        LOG_INTERVAL
+    };
+    std::thread::Builder::new()
+        .name("frawk-progress".into())
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            let mut reporter = REPORTER.lock().unwrap();
+            if reporter.done {
+                return;
+            }
+            let line = report(BYTES.load(Ordering::Relaxed), total, start.elapsed());
+            if overwrite {
+                to_terminal(&format!("\rfrawk: {}\x1b[K", line));
+                reporter.pending = true;
+            } else {
+                eprintln_ignore!("frawk: {}", line);
+            }
+        })
+        .expect("failed to start --progress thread");
+}
+
+/// Stop reporting progress, clearing the last report from the terminal. This is called as the
+/// program exits.
+pub fn finish() {
+    if !enabled() {
+        return;
+    }
+    let mut reporter = REPORTER.lock().unwrap();
+    reporter.done = true;
+    if reporter.pending {
+        to_terminal("\r\x1b[K");
+        reporter.pending = false;
+    }
+}
+
+/// Write `s`, which does not end in a newline, to standard error.
+fn to_terminal(s: &str) {
+    let mut err = io::stderr();
+    let _ = err.write_all(s.as_bytes());
+    let _ = err.flush();
+}
+
+/// Calls `finish` when dropped.
+pub struct Finish;
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        finish()
+    }
+}
+
+/// A reader of the main input, which counts the bytes read from it.
+struct Counted<R>(R);
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        BYTES.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Count the bytes read from `r`, part of the main input, towards the progress reports, if they
+/// are enabled.
+pub fn counted(r: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+    if enabled() {
+        Box::new(Counted(r))
+    } else {
+        r
+    }
+}
+
+fn size(bytes: f64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", size as u64)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn clock(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{}:{:02}:{:02}", h, m, s)
+    } else {
+        format!("{}:{:02}", m, s)
+    }
+}
+
+/// A report of `bytes` bytes of input read out of `total`, `elapsed` after starting.
+fn report(bytes: u64, total: Option<u64>, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { bytes as f64 / secs } else { 0.0 };
+    let read = match total {
+        Some(total) if total > 0 => {
+            // Files can grow while they are read.
+            let frac = (bytes as f64 / total as f64).min(1.0);
+            format!("{:.1}% of {} read", 100.0 * frac, size(total as f64))
+        }
+        _ => format!("{} read", size(bytes as f64)),
+    };
+    let mut res = format!("{}, {}/s", read, size(rate));
+    if let Some(total) = total {
+        if rate > 0.0 {
+            let left = total.saturating_sub(bytes) as f64 / rate;
+            res.push_str(&format!(", {} left", clock(left.ceil() as u64)));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_lines() {
+        let total = Some(4 << 30);
+        assert_eq!(
+            report(1 << 30, total, Duration::from_secs(4)),
+            "25.0% of 4.0 GiB read, 256.0 MiB/s, 0:12 left"
+        );
+        assert_eq!(
+            report(1000, None, Duration::from_secs(2)),
+            "1000 B read, 500 B/s"
+        );
+        assert_eq!(
+            report(1 << 20, Some(10 << 30), Duration::from_secs(1)),
+            "0.0% of 10.0 GiB read, 1.0 MiB/s, 2:50:39 left"
+        );
+        assert_eq!(
+            report(0, total, Duration::from_secs(1)),
+            "0.0% of 4.0 GiB read, 0 B/s"
+        );
+    }
+}
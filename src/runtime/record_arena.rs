@@ -0,0 +1,237 @@
+//! A bump allocator for strings that only live for the duration of a record.
+//!
+//! Most of the strings a script builds while processing a record (concatenations, `sprintf`
+//! output, numbers converted to strings) are printed or compared and then overwritten when the
+//! next record is processed. Allocating each of these from the system allocator (and an `Rc` node
+//! for each lazy concatenation) is a large part of the cost of a script like `{ print $1 "," $2 }`.
+//!
+//! The interpreter instead allocates these strings from a thread-local arena of fixed-size
+//! chunks, but only for instructions whose results the compiler has determined will not escape
+//! the current record (see the `escape` module). Each chunk keeps a count of the live buffers
+//! allocated from it. At the end of a record, the current chunk is reused from the start if none
+//! of its buffers are live; otherwise allocation continues where it left off. A full chunk is
+//! retired, and freed once its last buffer is dropped.
+//!
+//! The escape analysis is only a heuristic: a string from the arena that does outlive its record
+//! is still valid, it just keeps its chunk alive. Buffers from the arena are never shared with
+//! other threads; `UniqueStr` and array-key interning copy them onto the heap.
+use std::alloc::{alloc, dealloc, Layout};
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::ptr;
+
+/// The size (and alignment) of a chunk.
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// The largest allocation served from the arena. Larger strings go to the heap.
+pub(crate) const MAX_ALLOC: usize = CHUNK_SIZE / 16;
+
+const ALIGN: usize = 16;
+
+struct ChunkHeader {
+    live: Cell<usize>,
+    retired: Cell<bool>,
+}
+
+const START: usize = (mem::size_of::<ChunkHeader>() + ALIGN - 1) & !(ALIGN - 1);
+
+fn chunk_layout() -> Layout {
+    Layout::from_size_align(CHUNK_SIZE, CHUNK_SIZE).unwrap()
+}
+
+unsafe fn new_chunk() -> *mut ChunkHeader {
+    let chunk = alloc(chunk_layout()) as *mut ChunkHeader;
+    assert!(!chunk.is_null());
+    ptr::write(
+        chunk,
+        ChunkHeader {
+            live: Cell::new(0),
+            retired: Cell::new(false),
+        },
+    );
+    chunk
+}
+
+struct Arena {
+    cur: *mut ChunkHeader,
+    head: usize,
+    // A retired chunk kept around to avoid going back to the allocator for each new chunk.
+    spare: *mut ChunkHeader,
+}
+
+impl Arena {
+    unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+        let size = (size + ALIGN - 1) & !(ALIGN - 1);
+        if self.cur.is_null() || self.head + size > CHUNK_SIZE {
+            self.next_chunk();
+        }
+        let res = (self.cur as *mut u8).add(self.head);
+        self.head += size;
+        let live = &(*self.cur).live;
+        live.set(live.get() + 1);
+        res
+    }
+
+    unsafe fn next_chunk(&mut self) {
+        if !self.cur.is_null() {
+            if (*self.cur).live.get() == 0 {
+                self.head = START;
+                return;
+            }
+            (*self.cur).retired.set(true);
+        }
+        self.cur = if self.spare.is_null() {
+            new_chunk()
+        } else {
+            mem::replace(&mut self.spare, ptr::null_mut())
+        };
+        (*self.cur).retired.set(false);
+        self.head = START;
+    }
+
+    fn end_record(&mut self) {
+        if !self.cur.is_null() && unsafe { (*self.cur).live.get() } == 0 {
+            self.head = START;
+        }
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.cur.is_null() {
+                if (*self.cur).live.get() == 0 {
+                    dealloc(self.cur as *mut u8, chunk_layout());
+                } else {
+                    // The remaining buffers free the chunk when they are dropped.
+                    (*self.cur).retired.set(true);
+                }
+            }
+            if !self.spare.is_null() {
+                dealloc(self.spare as *mut u8, chunk_layout());
+            }
+        }
+    }
+}
+
+thread_local! {
+    static ACTIVE: Cell<bool> = Cell::new(false);
+    static ARENA: RefCell<Arena> = RefCell::new(Arena {
+        cur: ptr::null_mut(),
+        head: 0,
+        spare: ptr::null_mut(),
+    });
+}
+
+/// Allocates from the arena while it is alive; see `enter`.
+pub(crate) struct Active(bool);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        if self.0 {
+            ACTIVE.with(|a| a.set(false));
+        }
+    }
+}
+
+/// Serve allocations made by `try_alloc` from the arena until the returned guard is dropped. This
+/// is a no-op if `enabled` is false.
+pub(crate) fn enter(enabled: bool) -> Active {
+    if enabled {
+        ACTIVE.with(|a| a.set(true));
+    }
+    Active(enabled)
+}
+
+/// Whether allocations are currently served from the arena.
+pub(crate) fn active() -> bool {
+    ACTIVE.with(|a| a.get())
+}
+
+/// Allocate `layout.size()` bytes from the arena, if it is active and the allocation is small
+/// enough. The result is aligned to 16 bytes, and must be passed to `free`.
+pub(crate) fn try_alloc(layout: Layout) -> Option<*mut u8> {
+    if layout.size() > MAX_ALLOC || layout.align() > ALIGN || !active() {
+        return None;
+    }
+    ARENA.with(|a| unsafe { Some(a.borrow_mut().alloc(layout.size())) })
+}
+
+/// Free a buffer returned by `try_alloc`.
+///
+/// # Safety
+/// `p` must have been returned by `try_alloc` on the current thread, and not freed since.
+pub(crate) unsafe fn free(p: *mut u8) {
+    let chunk = ((p as usize) & !(CHUNK_SIZE - 1)) as *mut ChunkHeader;
+    let live = (*chunk).live.get() - 1;
+    (*chunk).live.set(live);
+    if live != 0 || !(*chunk).retired.get() {
+        return;
+    }
+    let recycled = ARENA
+        .try_with(|a| match a.try_borrow_mut() {
+            Ok(mut a) if a.spare.is_null() => {
+                a.spare = chunk;
+                true
+            }
+            _ => false,
+        })
+        .unwrap_or(false);
+    if !recycled {
+        dealloc(chunk as *mut u8, chunk_layout());
+    }
+}
+
+/// Mark the end of a record. If none of the strings allocated from the current chunk are still
+/// alive, the next record starts allocating from the beginning of the chunk.
+pub(crate) fn end_record() {
+    ARENA.with(|a| a.borrow_mut().end_record())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_of(p: *mut u8) -> usize {
+        p as usize & !(CHUNK_SIZE - 1)
+    }
+
+    #[test]
+    fn reuse_and_retire() {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        assert!(try_alloc(layout).is_none());
+        let _active = enter(true);
+        let p1 = try_alloc(layout).unwrap();
+        let p2 = try_alloc(layout).unwrap();
+        assert_eq!(p2 as usize - p1 as usize, 112);
+        assert!(try_alloc(Layout::from_size_align(MAX_ALLOC + 1, 8).unwrap()).is_none());
+
+        // The chunk is still in use, so the next record keeps bumping.
+        end_record();
+        let p3 = try_alloc(layout).unwrap();
+        assert!(p3 > p2);
+        unsafe {
+            free(p1);
+            free(p2);
+            free(p3);
+        }
+        end_record();
+        assert_eq!(try_alloc(layout).unwrap(), p1);
+        unsafe { free(p1) };
+
+        // Fill the chunk, holding on to one buffer: the next allocation comes from a new chunk,
+        // and the old one is freed along with the buffer.
+        let held = try_alloc(layout).unwrap();
+        let mut p = held;
+        while chunk_of(p) == chunk_of(held) {
+            if p != held {
+                unsafe { free(p) };
+            }
+            p = try_alloc(layout).unwrap();
+        }
+        unsafe {
+            free(held);
+            free(p);
+        }
+    }
+}
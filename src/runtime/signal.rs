@@ -0,0 +1,80 @@
+//! Deferred signal handling for programs with a `SIGNAL` block.
+//!
+//! When a program has a `SIGNAL` block, SIGINT, SIGTERM and SIGHUP no longer kill frawk outright.
+//! The handler only records which signal arrived; the main loop checks for it before reading each
+//! record, so no rule ever sees a half-processed record. Once a signal has been caught the main
+//! loop stops reading input, and the `SIGNAL` block runs just before END. The handler is installed
+//! without `SA_RESTART`, so a signal also interrupts a read that is waiting for more input (from
+//! `tail -f`, say), and the reader treats that as the end of the input. A second signal restores
+//! the default disposition and re-raises itself, so a job stuck in END can still be interrupted.
+//!
+//! If the program registers functions with `atexit`, the second signal instead stops the program
+//! the way `exit` inside a function does: the running code unwinds (it also checks at the top of
+//! each loop), END is skipped, and the `atexit` functions run before frawk dies of the signal. A
+//! third signal kills frawk outright.
+use crate::runtime::Int;
+
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+static CAUGHT: AtomicI32 = AtomicI32::new(0);
+// Whether the second signal should stop the program rather than kill frawk, and the signal that
+// did so.
+static DEFER: AtomicBool = AtomicBool::new(false);
+static STOPPED: AtomicI32 = AtomicI32::new(0);
+
+/// The number of the signal that was caught, or 0 if no signal has arrived.
+pub(crate) fn caught() -> Int {
+    CAUGHT.load(Ordering::Relaxed) as Int
+}
+
+/// Whether a second signal has asked the program to stop; see the module documentation.
+pub(crate) fn stopping() -> bool {
+    STOPPED.load(Ordering::Relaxed) != 0
+}
+
+/// If a second signal stopped the program, die of that signal now that the `atexit` functions
+/// have run.
+#[cfg(unix)]
+pub(crate) fn reraise() {
+    let sig = STOPPED.load(Ordering::Relaxed);
+    if sig != 0 {
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn reraise() {}
+
+/// Route SIGINT, SIGTERM and SIGHUP through `caught` rather than terminating the process. If
+/// `defer` is set, the second signal stops the program rather than killing frawk.
+#[cfg(unix)]
+pub(crate) fn install(defer: bool) {
+    extern "C" fn handle(sig: libc::c_int) {
+        // Only async-signal-safe operations are allowed here.
+        if CAUGHT.swap(sig, Ordering::Relaxed) != 0 {
+            if DEFER.load(Ordering::Relaxed) && STOPPED.swap(sig, Ordering::Relaxed) == 0 {
+                super::exit::set_exiting(1);
+                return;
+            }
+            unsafe {
+                libc::signal(sig, libc::SIG_DFL);
+                libc::raise(sig);
+            }
+        }
+    }
+    DEFER.store(defer, Ordering::Relaxed);
+    for sig in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as *const () as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(*sig, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn install(_defer: bool) {}
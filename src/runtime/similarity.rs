@@ -0,0 +1,241 @@
+//! String similarity measures for fuzzy matching: `levenshtein` and `jaro_winkler`.
+//!
+//! Both compare strings character by character (byte by byte with `--bytes`); bytes that are not
+//! valid UTF-8 count as characters of their own. The strings are decoded into scratch buffers kept
+//! per thread, so that after the first few calls neither measure allocates.
+use super::{chars, Float, Int, Str};
+
+use std::cell::RefCell;
+use std::cmp::{max, min};
+
+#[derive(Default)]
+struct Scratch {
+    a: Vec<u32>,
+    b: Vec<u32>,
+    row: Vec<usize>,
+    matched_a: Vec<bool>,
+    matched_b: Vec<bool>,
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::new(Default::default());
+}
+
+/// Decode `bs` into `out`, one element per character. Bytes that are not part of valid UTF-8 are
+/// mapped past the end of the Unicode range, so they never compare equal to a real character.
+fn decode(bs: &[u8], out: &mut Vec<u32>) {
+    out.clear();
+    if chars::bytes_mode() || bs.is_ascii() {
+        out.extend(bs.iter().map(|b| *b as u32));
+        return;
+    }
+    let mut rest = bs;
+    while !rest.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(s) => (s, 0),
+            Err(e) => {
+                let valid = e.valid_up_to();
+                let s = unsafe { std::str::from_utf8_unchecked(&rest[..valid]) };
+                (s, e.error_len().unwrap_or(rest.len() - valid))
+            }
+        };
+        out.extend(valid.chars().map(|c| c as u32));
+        let bad = &rest[valid.len()..valid.len() + invalid];
+        out.extend(bad.iter().map(|b| 0x110000 + *b as u32));
+        rest = &rest[valid.len() + invalid..];
+    }
+}
+
+fn with_decoded<R>(a: &Str, b: &Str, f: impl FnOnce(&[u32], &[u32], &mut Scratch) -> R) -> R {
+    SCRATCH.with(|scratch| {
+        let mut scratch = scratch.borrow_mut();
+        let mut da = std::mem::take(&mut scratch.a);
+        let mut db = std::mem::take(&mut scratch.b);
+        a.with_bytes(|bs| decode(bs, &mut da));
+        b.with_bytes(|bs| decode(bs, &mut db));
+        let res = f(&da[..], &db[..], &mut scratch);
+        scratch.a = da;
+        scratch.b = db;
+        res
+    })
+}
+
+/// `levenshtein(a, b)`: the minimum number of single-character insertions, deletions and
+/// substitutions needed to turn `a` into `b`.
+pub(crate) fn levenshtein(a: &Str, b: &Str) -> Int {
+    with_decoded(a, b, |a, b, scratch| {
+        levenshtein_units(a, b, &mut scratch.row)
+    }) as Int
+}
+
+fn levenshtein_units(a: &[u32], b: &[u32], row: &mut Vec<usize>) -> usize {
+    // Common prefixes and suffixes do not affect the distance.
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+    // Keep the row as short as possible.
+    let (a, b) = if a.len() < b.len() { (b, a) } else { (a, b) };
+    if b.is_empty() {
+        return a.len();
+    }
+    row.clear();
+    row.extend(0..=b.len());
+    for (i, x) in a.iter().enumerate() {
+        // row[j] holds the distance between a[..i] and b[..j]; we overwrite it in place with the
+        // distance between a[..=i] and b[..j], keeping the old row[j-1] in `diag`.
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let sub = diag + (x != y) as usize;
+            diag = row[j + 1];
+            row[j + 1] = min(sub, min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// `jaro_winkler(a, b)`: the Jaro-Winkler similarity of `a` and `b`, between 0 (nothing in
+/// common) and 1 (equal).
+pub(crate) fn jaro_winkler(a: &Str, b: &Str) -> Float {
+    with_decoded(a, b, |a, b, scratch| jaro_winkler_units(a, b, scratch))
+}
+
+fn jaro_winkler_units(a: &[u32], b: &[u32], scratch: &mut Scratch) -> Float {
+    let jaro = jaro_units(a, b, &mut scratch.matched_a, &mut scratch.matched_b);
+    let prefix = a
+        .iter()
+        .zip(b.iter())
+        .take(4)
+        .take_while(|(x, y)| x == y)
+        .count();
+    jaro + prefix as Float * 0.1 * (1.0 - jaro)
+}
+
+fn jaro_units(a: &[u32], b: &[u32], matched_a: &mut Vec<bool>, matched_b: &mut Vec<bool>) -> Float {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    // Characters only match if they are no further than this apart.
+    let window = (max(a.len(), b.len()) / 2).saturating_sub(1);
+    matched_a.clear();
+    matched_a.resize(a.len(), false);
+    matched_b.clear();
+    matched_b.resize(b.len(), false);
+    let mut matches = 0;
+    for (i, x) in a.iter().enumerate() {
+        let lo = i.saturating_sub(window);
+        let hi = min(b.len(), i + window + 1);
+        for j in lo..hi {
+            if !matched_b[j] && b[j] == *x {
+                matched_a[i] = true;
+                matched_b[j] = true;
+                matches += 1;
+                break;
+            }
+        }
+    }
+    if matches == 0 {
+        return 0.0;
+    }
+    // Count the matched characters that appear in a different order in the two strings.
+    let mut transpositions = 0;
+    let mut j = 0;
+    for (i, x) in a.iter().enumerate() {
+        if !matched_a[i] {
+            continue;
+        }
+        while !matched_b[j] {
+            j += 1;
+        }
+        if b[j] != *x {
+            transpositions += 1;
+        }
+        j += 1;
+    }
+    let m = matches as Float;
+    let t = (transpositions / 2) as Float;
+    (m / a.len() as Float + m / b.len() as Float + (m - t) / m) / 3.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levenshtein_distances() {
+        for (a, b, d) in &[
+            ("", "", 0),
+            ("", "abc", 3),
+            ("abc", "", 3),
+            ("kitten", "sitting", 3),
+            ("flaw", "lawn", 2),
+            ("saturday", "sunday", 3),
+            ("same", "same", 0),
+            ("héllo", "hello", 1),
+            ("日本語", "日本", 1),
+            ("abcdef", "azcdxf", 2),
+        ] {
+            assert_eq!(
+                levenshtein(&Str::from(*a), &Str::from(*b)),
+                *d,
+                "{} {}",
+                a,
+                b
+            );
+            assert_eq!(
+                levenshtein(&Str::from(*b), &Str::from(*a)),
+                *d,
+                "{} {}",
+                b,
+                a
+            );
+        }
+        let invalid = Str::from(&b"ab\xff"[..]);
+        assert_eq!(levenshtein(&invalid, &Str::from("ab")), 1);
+        assert_eq!(levenshtein(&invalid, &Str::from("ab\u{ff}")), 1);
+    }
+
+    #[test]
+    fn jaro_winkler_similarities() {
+        let close = |x: Float, y: Float| (x - y).abs() < 1e-3;
+        for (a, b, sim) in &[
+            ("", "", 1.0),
+            ("abc", "", 0.0),
+            ("abc", "abc", 1.0),
+            ("abc", "xyz", 0.0),
+            ("martha", "marhta", 0.961),
+            ("dwayne", "duane", 0.84),
+            ("dixon", "dicksonx", 0.813),
+            ("crate", "trace", 0.733),
+            ("jöhn", "jon", 0.75),
+        ] {
+            let got = jaro_winkler(&Str::from(*a), &Str::from(*b));
+            assert!(
+                close(got, *sim),
+                "{} {}: got {}, expected {}",
+                a,
+                b,
+                got,
+                sim
+            );
+            let got = jaro_winkler(&Str::from(*b), &Str::from(*a));
+            assert!(
+                close(got, *sim),
+                "{} {}: got {}, expected {}",
+                b,
+                a,
+                got,
+                sim
+            );
+        }
+    }
+}
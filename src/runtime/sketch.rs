@@ -0,0 +1,444 @@
+//! Probabilistic summaries of large sets, for the `hll_*`, `bloom_*` and `reservoir` builtins.
+//!
+//! Sketches are stored in ordinary frawk arrays, so that they can be passed around, persisted
+//! and, most importantly, aggregated across workers in parallel mode like any other array. The
+//! parallel runtime merges arrays by summing the values stored under each key, so HyperLogLog
+//! sketches only record the *presence* of keys: a key is set (to 1) the first time it is needed,
+//! and merging two sketches yields a sketch with the union of their keys.
+//!
+//! A HyperLogLog sketch with 2^`HLL_BITS` registers stores the fact that register `i` has seen a
+//! value with rank `r` as the key `i * 64 + r`. The value of a register is then the largest `r`
+//! stored for it. With the default size, estimates have a standard error of about 1.6% and a
+//! sketch holds at most 256K keys, regardless of the number of distinct values added.
+//!
+//! Storing one key per bit would make a Bloom filter larger than the exact set it stands in for,
+//! so Bloom filters pack 64 bits into each value instead, and record their size under the key
+//! `BLOOM_HEADER`. Summing packed bits does not merge two filters, so the parallel runtime checks
+//! for that header and merges filters with `merge_bloom` instead.
+//!
+//! A reservoir sample of `k` records keeps them under the keys 1 through `k`, with the number of
+//! records offered so far under the key 0 and `k` itself under the key -1. Merging two samples
+//! means drawing from each in proportion to the number of records it stands for, which
+//! `merge_reservoir` does in place of the usual merge.
+use crate::common::Result;
+use crate::runtime::{self, Float, Int, IntMap, Str, UniqueStr};
+
+use hashbrown::HashMap;
+use rand::{seq::SliceRandom, Rng};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+const HLL_BITS: u32 = 12;
+const HLL_REGISTERS: usize = 1 << HLL_BITS;
+
+/// A 64-bit hash of `s` that is the same in every worker and every run, so that sketches built
+/// separately can be merged.
+fn hash(s: &Str) -> u64 {
+    s.with_bytes(|bs| {
+        let mut h = DefaultHasher::new();
+        h.write(bs);
+        h.finish()
+    })
+}
+
+/// `hll_add(h, x)`: add `x` to the HyperLogLog sketch `h`. Returns 1 if this changed the sketch,
+/// and 0 otherwise.
+pub(crate) fn hll_add(h: &IntMap<Int>, x: &Str) -> Int {
+    let hash = hash(x);
+    let register = (hash >> (64 - HLL_BITS)) as Int;
+    // The rank is the position of the first set bit among the remaining bits; the extra bit caps
+    // it for hashes whose remaining bits are all zero.
+    let rest = (hash << HLL_BITS) | (1 << (HLL_BITS - 1));
+    let rank = rest.leading_zeros() as Int + 1;
+    let key = register * 64 + rank;
+    if h.get(&key).is_some() {
+        return 0;
+    }
+    h.insert(key, 1);
+    1
+}
+
+/// `hll_count(h)`: estimate the number of distinct values added to the HyperLogLog sketch `h`.
+pub(crate) fn hll_count(h: &IntMap<Int>) -> Int {
+    let mut registers = [0u8; HLL_REGISTERS];
+    for key in h.to_vec() {
+        // Ignore keys that a sketch does not contain; `h` is an ordinary array, after all.
+        if key < 0 || key >= (HLL_REGISTERS * 64) as Int {
+            continue;
+        }
+        let (i, rank) = ((key / 64) as usize, (key % 64) as u8);
+        registers[i] = std::cmp::max(registers[i], rank);
+    }
+    let m = HLL_REGISTERS as f64;
+    let alpha = 0.7213 / (1.0 + 1.079 / m);
+    let (mut sum, mut zeros) = (0.0, 0);
+    for r in registers.iter() {
+        sum += (-(*r as f64)).exp2();
+        if *r == 0 {
+            zeros += 1;
+        }
+    }
+    let estimate = alpha * m * m / sum;
+    // Linear counting is more accurate for small sets.
+    let estimate = if estimate <= 2.5 * m && zeros > 0 {
+        m * (m / zeros as f64).ln()
+    } else {
+        estimate
+    };
+    estimate.round() as Int
+}
+
+/// The default number of values that a Bloom filter is sized for.
+pub(crate) const BLOOM_VALUES: Int = 1_000_000;
+/// The default false-positive rate of a Bloom filter.
+pub(crate) const BLOOM_ERROR: Float = 0.01;
+
+/// The key under which a Bloom filter stores its size: the number of 64-bit words in the filter,
+/// shifted left by 8, plus the number of hash functions.
+const BLOOM_HEADER: Int = Int::min_value();
+
+/// The number of words and hash functions of a Bloom filter that holds `n` values with a
+/// false-positive rate of `p`.
+fn bloom_size(n: Int, p: Float) -> Result<(Int, Int)> {
+    if n <= 0 || !(p > 0.0 && p < 1.0) {
+        return err!(
+            "invalid Bloom filter size: {} values with false-positive rate {}",
+            n,
+            p
+        );
+    }
+    let ln2 = std::f64::consts::LN_2;
+    let bits = (-(n as f64) * p.ln() / (ln2 * ln2)).ceil();
+    let words = std::cmp::max((bits / 64.0).ceil() as Int, 1);
+    let hashes = ((words * 64) as f64 / n as f64 * ln2).round() as Int;
+    Ok((words, hashes.max(1).min(32)))
+}
+
+/// The words and hash functions of the Bloom filter `b`, or `None` if `b` is empty.
+fn bloom_header(b: &IntMap<Int>, func: &str) -> Result<Option<(Int, Int)>> {
+    match b.get(&BLOOM_HEADER) {
+        Some(h) if h >> 8 > 0 => Ok(Some((h >> 8, h & 0xff))),
+        None if b.len() == 0 => Ok(None),
+        _ => err!("{}: array is not a Bloom filter", func),
+    }
+}
+
+/// The bits that `x` sets in a filter with `words` words, as (word, mask) pairs.
+fn bloom_bits(x: &Str, words: Int, hashes: Int) -> impl Iterator<Item = (Int, Int)> {
+    // Derive all of the hash functions from two hashes of `x` (Kirsch and Mitzenmacher).
+    let h1 = hash(x);
+    let h2 = h1.rotate_left(32).wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    let bits = (words as u64) * 64;
+    (0..hashes as u64).map(move |i| {
+        let bit = h1.wrapping_add(i.wrapping_mul(h2)) % bits;
+        ((bit / 64) as Int, 1 << (bit % 64))
+    })
+}
+
+/// `bloom_add(b, x, n, p)`: add `x` to the Bloom filter `b`, returning 1 if `x` was not already
+/// (probably) present and 0 otherwise. If `b` is empty, it is sized to hold `n` values with a
+/// false-positive rate of `p`; otherwise, `n` and `p` are ignored.
+pub(crate) fn bloom_add(b: &IntMap<Int>, x: &Str, n: Int, p: Float) -> Result<Int> {
+    let (words, hashes) = match bloom_header(b, "bloom_add")? {
+        Some(size) => size,
+        None => {
+            let (words, hashes) = bloom_size(n, p)?;
+            b.insert(BLOOM_HEADER, words << 8 | hashes);
+            (words, hashes)
+        }
+    };
+    let mut added = 0;
+    for (word, mask) in bloom_bits(x, words, hashes) {
+        let cur = b.get(&word).unwrap_or(0);
+        if cur & mask == 0 {
+            b.insert(word, cur | mask);
+            added = 1;
+        }
+    }
+    Ok(added)
+}
+
+/// `bloom_has(b, x)`: 1 if `x` may have been added to the Bloom filter `b`, and 0 if it certainly
+/// was not.
+pub(crate) fn bloom_has(b: &IntMap<Int>, x: &Str) -> Result<Int> {
+    let (words, hashes) = match bloom_header(b, "bloom_has")? {
+        Some(size) => size,
+        None => return Ok(0),
+    };
+    for (word, mask) in bloom_bits(x, words, hashes) {
+        if b.get(&word).unwrap_or(0) & mask == 0 {
+            return Ok(0);
+        }
+    }
+    Ok(1)
+}
+
+/// Merge the Bloom filter `other` into `b`, returning `other` if either of them is not a Bloom
+/// filter or if their sizes differ.
+pub(crate) fn merge_bloom(
+    b: &mut HashMap<Int, Int>,
+    other: HashMap<Int, Int>,
+) -> Option<HashMap<Int, Int>> {
+    match (b.get(&BLOOM_HEADER), other.get(&BLOOM_HEADER)) {
+        (Some(h1), Some(h2)) if h1 == h2 && h1 >> 8 > 0 => {}
+        _ => return Some(other),
+    }
+    for (word, bits) in other {
+        *b.entry(word).or_insert(0) |= bits;
+    }
+    None
+}
+
+/// The keys of a reservoir sample holding the number of records offered, and the size of the
+/// sample.
+const RESERVOIR_SEEN: Int = 0;
+const RESERVOIR_SIZE: Int = -1;
+
+/// `reservoir(arr, k, x)`: offer `x` to the sample of `k` records kept in `arr` (Algorithm R), so
+/// that every record offered so far is equally likely to be in the sample. Returns the key that
+/// `x` was stored under, or 0 if it was not kept.
+pub(crate) fn reservoir<'a>(
+    arr: &IntMap<Str<'a>>,
+    k: Int,
+    x: &Str<'a>,
+    rng: &mut impl Rng,
+) -> Result<Int> {
+    if k <= 0 {
+        return err!("reservoir: invalid sample size {}", k);
+    }
+    let seen = arr
+        .get(&RESERVOIR_SEEN)
+        .map(|s| runtime::convert::<_, Int>(&s))
+        .unwrap_or(0)
+        + 1;
+    arr.insert(RESERVOIR_SEEN, runtime::convert::<_, Str>(seen));
+    arr.insert(RESERVOIR_SIZE, runtime::convert::<_, Str>(k));
+    let slot = if seen <= k {
+        seen
+    } else {
+        rng.gen_range(1, seen + 1)
+    };
+    if slot > k {
+        return Ok(0);
+    }
+    arr.insert(slot, x.clone());
+    Ok(slot)
+}
+
+/// Merge the reservoir sample `other` into `arr`, returning `other` if either of them is not a
+/// sample or if their sizes differ.
+pub(crate) fn merge_reservoir(
+    arr: &mut HashMap<Int, UniqueStr<'static>>,
+    mut other: HashMap<Int, UniqueStr<'static>>,
+) -> Option<HashMap<Int, UniqueStr<'static>>> {
+    let get = |m: &HashMap<Int, UniqueStr>, k| -> Option<Int> {
+        m.get(&k)
+            .map(|s| runtime::convert::<_, Int>(&s.clone_str()))
+    };
+    let (k, seen_a, seen_b) = match (
+        get(arr, RESERVOIR_SIZE),
+        get(&other, RESERVOIR_SIZE),
+        get(arr, RESERVOIR_SEEN),
+        get(&other, RESERVOIR_SEEN),
+    ) {
+        (Some(k1), Some(k2), Some(a), Some(b)) if k1 == k2 && k1 > 0 && a > 0 && b > 0 => {
+            (k1, a, b)
+        }
+        _ => return Some(other),
+    };
+    let mut rng = rand::thread_rng();
+    let mut take = |m: &mut HashMap<Int, UniqueStr<'static>>, seen: Int| {
+        let mut sample: Vec<_> = (1..=std::cmp::min(seen, k))
+            .filter_map(|i| m.remove(&i))
+            .collect();
+        sample.shuffle(&mut rng);
+        sample
+    };
+    let (mut sample_a, mut sample_b) = (take(arr, seen_a), take(&mut other, seen_b));
+    // Each record of the merged sample comes from one side or the other in proportion to the
+    // number of records on that side that have not been drawn yet, and is then a uniformly random
+    // record from that side's sample.
+    let (mut left_a, mut left_b) = (seen_a, seen_b);
+    for slot in 1..=std::cmp::min(k, seen_a + seen_b) {
+        let from_a = rng.gen_range(0, left_a + left_b) < left_a;
+        let next = if from_a {
+            left_a -= 1;
+            sample_a.pop()
+        } else {
+            left_b -= 1;
+            sample_b.pop()
+        };
+        if let Some(x) = next {
+            arr.insert(slot, x);
+        }
+    }
+    let seen = runtime::convert::<_, Str>(seen_a + seen_b);
+    arr.insert(RESERVOIR_SEEN, UniqueStr::from(seen));
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_range(h: &IntMap<Int>, lo: usize, hi: usize) {
+        for i in lo..hi {
+            hll_add(h, &Str::from(format!("user-{}", i)));
+        }
+    }
+
+    fn assert_close(estimate: Int, n: usize) {
+        let err = (estimate as f64 - n as f64).abs() / n as f64;
+        assert!(
+            err < 0.05,
+            "estimated {} for {} distinct values",
+            estimate,
+            n
+        );
+    }
+
+    #[test]
+    fn hll_estimates() {
+        let h = IntMap::default();
+        assert_eq!(hll_count(&h), 0);
+        assert_eq!(hll_add(&h, &Str::from("a")), 1);
+        assert_eq!(hll_add(&h, &Str::from("a")), 0);
+        assert_eq!(hll_count(&h), 1);
+        for &n in &[100, 10_000, 200_000] {
+            let h = IntMap::default();
+            add_range(&h, 0, n);
+            add_range(&h, 0, n / 2);
+            assert_close(hll_count(&h), n);
+        }
+    }
+
+    #[test]
+    fn hll_merge() {
+        // Summing the values of two sketches, as the parallel runtime does, yields a sketch of the
+        // union of their sets.
+        let (h1, h2) = (IntMap::default(), IntMap::default());
+        add_range(&h1, 0, 60_000);
+        add_range(&h2, 40_000, 100_000);
+        for k in h2.to_vec() {
+            h1.insert(k, h1.get(&k).unwrap_or(0) + h2.get(&k).unwrap());
+        }
+        assert_close(hll_count(&h1), 100_000);
+    }
+
+    #[test]
+    fn bloom_membership() {
+        let b = IntMap::default();
+        let x = Str::from("x");
+        assert_eq!(bloom_has(&b, &x).unwrap(), 0);
+        assert_eq!(bloom_add(&b, &x, 1000, 0.01).unwrap(), 1);
+        assert_eq!(bloom_add(&b, &x, 1000, 0.01).unwrap(), 0);
+        assert_eq!(bloom_has(&b, &x).unwrap(), 1);
+        for i in 0..1000 {
+            bloom_add(&b, &Str::from(format!("in-{}", i)), 1000, 0.01).unwrap();
+        }
+        let false_positives = (0..10_000)
+            .filter(|i| bloom_has(&b, &Str::from(format!("out-{}", i))).unwrap() == 1)
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+        assert!((0..1000).all(|i| bloom_has(&b, &Str::from(format!("in-{}", i))).unwrap() == 1));
+
+        assert!(bloom_add(&IntMap::default(), &x, 0, 0.01).is_err());
+        assert!(bloom_add(&IntMap::default(), &x, 10, 1.0).is_err());
+        let not_bloom = IntMap::default();
+        not_bloom.insert(1, 1);
+        assert!(bloom_has(&not_bloom, &x).is_err());
+    }
+
+    #[test]
+    fn reservoir_sampling() {
+        let mut rng = rand::thread_rng();
+        let arr = IntMap::default();
+        assert!(reservoir(&arr, 0, &Str::from("x"), &mut rng).is_err());
+        for i in 1..=3 {
+            let x = Str::from(format!("r{}", i));
+            assert_eq!(reservoir(&arr, 5, &x, &mut rng).unwrap(), i);
+        }
+        assert_eq!(arr.len(), 5);
+        // Offer 1..=100 to samples of 10 many times; each value should be kept about 10% of the
+        // time.
+        let mut kept = vec![0; 101];
+        for _ in 0..2000 {
+            let arr = IntMap::default();
+            for i in 1..=100 {
+                reservoir(&arr, 10, &Str::from(format!("{}", i)), &mut rng).unwrap();
+            }
+            assert_eq!(arr.get(&RESERVOIR_SEEN), Some(Str::from("100")));
+            for slot in 1..=10 {
+                let v = runtime::convert::<_, Int>(&arr.get(&slot).unwrap());
+                kept[v as usize] += 1;
+            }
+        }
+        assert!(kept[1..].iter().all(|n| *n > 100 && *n < 300), "{:?}", kept);
+    }
+
+    #[test]
+    fn reservoir_merge() {
+        let mut rng = rand::thread_rng();
+        let to_map = |arr: IntMap<Str<'static>>| -> HashMap<Int, UniqueStr<'static>> {
+            arr.to_vec()
+                .into_iter()
+                .map(|k| (k, UniqueStr::from(arr.get(&k).unwrap())))
+                .collect()
+        };
+        // 10 records on one side and 90 on the other: about 10% of the merged sample should come
+        // from the first side.
+        let mut from_small = 0;
+        for _ in 0..500 {
+            let (small, large) = (IntMap::default(), IntMap::default());
+            for i in 0..10 {
+                reservoir(&small, 20, &Str::from(format!("s{}", i)), &mut rng).unwrap();
+            }
+            for i in 0..90 {
+                reservoir(&large, 20, &Str::from(format!("l{}", i)), &mut rng).unwrap();
+            }
+            let mut merged = to_map(small);
+            assert!(merge_reservoir(&mut merged, to_map(large)).is_none());
+            assert_eq!(merged.len(), 22);
+            assert_eq!(
+                merged.get(&RESERVOIR_SEEN).unwrap().clone_str(),
+                Str::from("100")
+            );
+            from_small += (1..=20)
+                .filter(|i| {
+                    merged
+                        .get(i)
+                        .unwrap()
+                        .clone_str()
+                        .with_bytes(|b| b[0] == b's')
+                })
+                .count();
+        }
+        let share = from_small as f64 / (500 * 20) as f64;
+        assert!(share > 0.08 && share < 0.12, "{}", share);
+
+        let mut plain: HashMap<Int, UniqueStr> = HashMap::new();
+        plain.insert(1, UniqueStr::from(Str::from("x")));
+        assert!(merge_reservoir(&mut plain, HashMap::new()).is_some());
+    }
+
+    #[test]
+    fn bloom_merge() {
+        let to_map = |b: IntMap<Int>| -> HashMap<Int, Int> {
+            b.to_vec()
+                .into_iter()
+                .map(|k| (k, b.get(&k).unwrap()))
+                .collect()
+        };
+        let (b1, b2) = (IntMap::default(), IntMap::default());
+        bloom_add(&b1, &Str::from("a"), 100, 0.01).unwrap();
+        bloom_add(&b2, &Str::from("b"), 100, 0.01).unwrap();
+        let mut merged = to_map(b1);
+        assert!(merge_bloom(&mut merged, to_map(b2)).is_none());
+        let merged: IntMap<Int> = merged.into_iter().collect();
+        assert_eq!(bloom_has(&merged, &Str::from("a")).unwrap(), 1);
+        assert_eq!(bloom_has(&merged, &Str::from("b")).unwrap(), 1);
+
+        let mut plain: HashMap<Int, Int> = vec![(1, 1)].into_iter().collect();
+        assert!(merge_bloom(&mut plain, HashMap::new()).is_some());
+    }
+}
@@ -31,12 +31,13 @@ use crate::common::{ExecutionStrategy, Result};
 use crate::pushdown::FieldSet;
 use crate::runtime::{
     str_impl::{Buf, Str, UniqueBuf},
-    Int, LazyVec, RegexCache,
+    sum_strs, Float, Int, LazyVec, RegexCache,
 };
 
 use super::{
     chunk::{self, Chunk, ChunkProducer, OffsetChunk, ParallelChunkProducer, ShardedChunkProducer},
-    normalize_join_indexes, DefaultLine, LineReader, ReaderState,
+    normalize_join_indexes, normalize_sum_indexes, DefaultLine, LineReader, ReaderState,
+    RecordBatch,
 };
 
 pub struct CSVReader<P> {
@@ -46,6 +47,8 @@ pub struct CSVReader<P> {
     buf_len: usize,
     prev_ix: usize,
     last_len: usize,
+    // The offset in the input of the last record read.
+    offset: usize,
     // Used to trigger updating FILENAME on the first read.
     ifmt: InputFormat,
     field_set: FieldSet,
@@ -80,6 +83,7 @@ impl LineReader for CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
                     buf_len: 0,
                     prev_ix: 0,
                     last_len: 0,
+                    offset: 0,
                     ifmt,
                     field_set,
                     check_utf8,
@@ -101,6 +105,21 @@ impl LineReader for CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
     ) -> Result<bool> {
         Ok(self.read_line_inner(old)?)
     }
+    fn read_batch(&mut self, batch: &mut RecordBatch<Line>) -> Result<()> {
+        // Keep $0 even if the program does not use it: `getline var` can take the next record from
+        // the batch.
+        let used_fields = self.field_set.clone();
+        self.field_set.union(&FieldSet::singleton(0));
+        // read_line_inner only refreshes the buffer once it has run out of offsets.
+        while !batch.is_full() && self.cur_chunk.off.start != self.cur_chunk.off.fields.len() {
+            let mut line = batch.take_spare();
+            let changed = self.read_line_inner(&mut line)?;
+            debug_assert!(!changed);
+            batch.push(line);
+        }
+        self.field_set = used_fields;
+        Ok(())
+    }
     fn read_state(&self) -> i64 {
         if self.cur_chunk.version != 0 && self.last_len == 0 {
             ReaderState::EOF as i64
@@ -118,6 +137,12 @@ impl LineReader for CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
     fn set_used_fields(&mut self, field_set: &FieldSet) {
         self.field_set = field_set.clone();
     }
+    fn record_offset(&self) -> Int {
+        self.offset as Int
+    }
+    fn record_sep(&self) -> Option<Str<'static>> {
+        Some(sep_before(&self.cur_buf, self.buf_len, self.prev_ix, b'\n'))
+    }
 }
 
 impl CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
@@ -171,6 +196,7 @@ impl CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
             cur_chunk: OffsetChunk::default(),
             prev_ix: 0,
             last_len: 0,
+            offset: 0,
             field_set: FieldSet::all(),
             ifmt,
             empty_buf,
@@ -179,6 +205,16 @@ impl CSVReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
     }
 }
 
+/// The separator ending a record that finishes just before `end` in the first `len` bytes of
+/// `buf`: `sep` itself, or nothing if the input ended first.
+fn sep_before(buf: &Buf, len: usize, end: usize, sep: u8) -> Str<'static> {
+    if end > 0 && end <= len && buf.as_bytes()[end - 1] == sep {
+        buf.slice_to_str(end - 1, end)
+    } else {
+        Str::default()
+    }
+}
+
 // TODO rename as it handles CSV and TSV
 impl<P: ChunkProducer<Chunk = OffsetChunk>> CSVReader<P> {
     fn refresh_buf(&mut self) -> Result<(/*is eof*/ bool, /* file changed */ bool)> {
@@ -232,6 +268,7 @@ impl<P: ChunkProducer<Chunk = OffsetChunk>> CSVReader<P> {
             }
         }
 
+        self.offset = self.cur_chunk.offset + self.prev_ix;
         let (prev_ix, st) = {
             let mut stepper = self.stepper(State::Init, line);
             (unsafe { stepper.step() }, stepper.st)
@@ -313,6 +350,11 @@ impl<'a> super::Line<'a> for Line {
             .join(self.fields[start..end].iter().cloned().map(trans))
             .upcast())
     }
+    fn sum_cols(&mut self, start: Int, end: Int, nf: usize) -> Result<Float> {
+        debug_assert_eq!(self.fields.len(), nf);
+        let (start, end) = normalize_sum_indexes(start, end, nf)?;
+        Ok(sum_strs(self.fields[start..end].iter()))
+    }
     fn nf(&mut self, _pat: &Str, _rc: &mut super::RegexCache) -> Result<usize> {
         Ok(self.fields.len())
     }
@@ -604,61 +646,71 @@ impl InputFormat {
 // feature-set is supported. To avoid querying this on every function call, the calling library
 // will instead store a function pointer that is computed at startup based on the dynamically
 // available CPU features.
+//
+// On other architectures (e.g. aarch64) we always use the portable implementation.
+
+#[cfg(all(target_arch = "x86_64", feature = "allow_avx2"))]
+const ALLOW_AVX2: bool = true;
+#[cfg(all(target_arch = "x86_64", not(feature = "allow_avx2")))]
+const ALLOW_AVX2: bool = false;
 
 pub fn get_find_indexes(
     ifmt: InputFormat,
 ) -> unsafe fn(&[u8], &mut Offsets, u64, u64) -> (u64, u64) {
-    #[cfg(feature = "allow_avx2")]
-    const ALLOW_AVX2: bool = true;
-    #[cfg(not(feature = "allow_avx2"))]
-    const ALLOW_AVX2: bool = false;
-
-    if ALLOW_AVX2 && is_x86_feature_detected!("avx2") && is_x86_feature_detected!("pclmulqdq") {
-        match ifmt {
-            InputFormat::CSV => generic::find_indexes_csv::<avx2::Impl>,
-            InputFormat::TSV => generic::find_indexes_tsv::<avx2::Impl>,
-        }
-    } else if is_x86_feature_detected!("sse2") && is_x86_feature_detected!("pclmulqdq") {
-        match ifmt {
-            InputFormat::CSV => generic::find_indexes_csv::<sse2::Impl>,
-            InputFormat::TSV => generic::find_indexes_tsv::<sse2::Impl>,
-        }
-    } else {
-        match ifmt {
-            InputFormat::CSV => generic::find_indexes_csv::<generic::Impl>,
-            InputFormat::TSV => generic::find_indexes_tsv::<generic::Impl>,
+    #[cfg(target_arch = "x86_64")]
+    {
+        if ALLOW_AVX2 && is_x86_feature_detected!("avx2") && is_x86_feature_detected!("pclmulqdq") {
+            return match ifmt {
+                InputFormat::CSV => generic::find_indexes_csv::<avx2::Impl>,
+                InputFormat::TSV => generic::find_indexes_tsv::<avx2::Impl>,
+            };
+        } else if is_x86_feature_detected!("sse2") && is_x86_feature_detected!("pclmulqdq") {
+            return match ifmt {
+                InputFormat::CSV => generic::find_indexes_csv::<sse2::Impl>,
+                InputFormat::TSV => generic::find_indexes_tsv::<sse2::Impl>,
+            };
         }
     }
+    match ifmt {
+        InputFormat::CSV => generic::find_indexes_csv::<generic::Impl>,
+        InputFormat::TSV => generic::find_indexes_tsv::<generic::Impl>,
+    }
 }
 
-pub fn get_find_indexes_bytes() -> unsafe fn(&[u8], &mut Offsets, u8, u8) {
-    #[cfg(feature = "allow_avx2")]
-    const ALLOW_AVX2: bool = true;
-    #[cfg(not(feature = "allow_avx2"))]
-    const ALLOW_AVX2: bool = false;
-
-    if ALLOW_AVX2 && is_x86_feature_detected!("avx2") {
-        generic::find_indexes_byte::<avx2::Impl>
-    } else if is_x86_feature_detected!("sse2") {
-        generic::find_indexes_byte::<sse2::Impl>
-    } else {
-        generic::find_indexes_byte::<generic::Impl>
+pub fn get_find_indexes_bytes(
+    field_sep: u8,
+    record_sep: u8,
+) -> unsafe fn(&[u8], &mut Offsets, u8, u8) {
+    macro_rules! specialize {
+        ($impl:ty) => {
+            match (field_sep, record_sep) {
+                (b'\t', b'\n') => generic::find_indexes_tab::<$impl>,
+                (b',', b'\n') => generic::find_indexes_comma::<$impl>,
+                _ => generic::find_indexes_byte::<$impl>,
+            }
+        };
     }
+    #[cfg(target_arch = "x86_64")]
+    {
+        if ALLOW_AVX2 && is_x86_feature_detected!("avx2") {
+            return specialize!(avx2::Impl);
+        } else if is_x86_feature_detected!("sse2") {
+            return specialize!(sse2::Impl);
+        }
+    }
+    specialize!(generic::Impl)
 }
 
 pub fn get_find_indexes_ascii_whitespace() -> unsafe fn(&[u8], &mut WhitespaceOffsets, u64) -> u64 {
-    #[cfg(feature = "allow_avx2")]
-    const ALLOW_AVX2: bool = true;
-    #[cfg(not(feature = "allow_avx2"))]
-    const ALLOW_AVX2: bool = false;
-
-    if ALLOW_AVX2 && is_x86_feature_detected!("avx2") {
-        generic::find_indexes_ascii_whitespace::<avx2::Impl>
-    } else if is_x86_feature_detected!("sse2") {
-        generic::find_indexes_ascii_whitespace::<sse2::Impl>
-    } else {
-        generic::find_indexes_ascii_whitespace::<generic::Impl>
+    #[cfg(target_arch = "x86_64")]
+    {
+        if ALLOW_AVX2 && is_x86_feature_detected!("avx2") {
+            return generic::find_indexes_ascii_whitespace::<avx2::Impl>;
+        } else if is_x86_feature_detected!("sse2") {
+            return generic::find_indexes_ascii_whitespace::<sse2::Impl>;
+        }
     }
+    generic::find_indexes_ascii_whitespace::<generic::Impl>
 }
 
 // TODO: consider putting these into the runtime struct to avoid the extra indirection.
@@ -751,6 +803,7 @@ mod generic {
         // Precondition: bptr points to at least INPUT_SIZE bytes.
         unsafe fn fill_input(btr: *const u8) -> Self;
         unsafe fn or(self, rhs: Self) -> Self;
+        #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
         unsafe fn and(self, rhs: Self) -> Self;
         unsafe fn mask(self) -> u64;
         // Compute a mask of which bits in input match (bytewise) `m`.
@@ -817,8 +870,11 @@ mod generic {
         }
     }
 
+    // The generic implementation covers 64 bytes at a time, like the SIMD ones, so that its masks
+    // fill a u64: the state carried between iterations (e.g. `prev_iter_inside_quote`) is read off
+    // of bit 63.
     #[derive(Copy, Clone)]
-    pub struct Impl([u8; 32]);
+    pub struct Impl([u8; 64]);
 
     macro_rules! foreach_impl_inner {
         ($ix: ident, $body:expr, [$($ixv:expr),*] ) => {{
@@ -837,7 +893,9 @@ mod generic {
                 $body,
                 [
                     0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21,
-                    22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+                    22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41,
+                    42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61,
+                    62, 63
                 ]
             )
         };
@@ -846,12 +904,12 @@ mod generic {
     // A generic implementation of the `Vector` trait. No explicit simd, and relatively few unsafe
     // constructs aside from the explicitly unsafe `fill_input`.
     impl Vector for Impl {
-        const VEC_BYTES: usize = 32;
+        const VEC_BYTES: usize = 64;
         const INPUT_SIZE: usize = Self::VEC_BYTES;
 
         unsafe fn fill_input(btr: *const u8) -> Self {
             use std::ptr::copy;
-            let mut i = Impl([0; 32]);
+            let mut i = Impl([0; 64]);
             copy(btr, i.0.as_mut_ptr(), Self::VEC_BYTES);
             i
         }
@@ -879,7 +937,7 @@ mod generic {
         unsafe fn find_quote_mask(self, prev_iter_inside_quote: &mut u64) -> (u64, u64) {
             let quote_mask = self.cmp_against_input(b'"');
             let mut in_quote = false;
-            let mut in_quotes = Impl([0; 32]);
+            let mut in_quotes = Impl([0; 64]);
             for ix in 0..Self::VEC_BYTES {
                 let cmp = quote_mask.0[ix];
                 in_quote = (cmp == 1 && !in_quote) || (in_quote && cmp == 0);
@@ -1219,6 +1277,7 @@ mod generic {
         (0, 0)
     }
 
+    #[inline(always)]
     pub unsafe fn find_indexes_byte<V: Vector>(
         buf: &[u8],
         offsets: &mut Offsets,
@@ -1232,6 +1291,19 @@ mod generic {
             fs.or(rs).mask()
         });
     }
+
+    // Variants of find_indexes_byte with the separators baked in, for the most common
+    // newline-delimited formats. The comparison vectors become constants rather than being
+    // broadcast from arguments on every block. The separator arguments are ignored; they are only
+    // there so these share a signature with find_indexes_byte.
+
+    pub unsafe fn find_indexes_tab<V: Vector>(buf: &[u8], offsets: &mut Offsets, _: u8, _: u8) {
+        find_indexes_byte::<V>(buf, offsets, b'\t', b'\n')
+    }
+
+    pub unsafe fn find_indexes_comma<V: Vector>(buf: &[u8], offsets: &mut Offsets, _: u8, _: u8) {
+        find_indexes_byte::<V>(buf, offsets, b',', b'\n')
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -1364,6 +1436,8 @@ pub struct ByteReader<P: ChunkProducer> {
     used_fields: FieldSet,
     // Progress in the current buffer.
     progress: usize,
+    // The offset in the input of the last record read.
+    offset: usize,
     record_sep: u8,
 
     last_len: usize,
@@ -1420,6 +1494,7 @@ impl ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk>>> {
             cur_buf: UniqueBuf::new(0).into_buf(),
             buf_len: 0,
             progress: 0,
+            offset: 0,
             record_sep,
             used_fields: FieldSet::all(),
             last_len: usize::max_value(),
@@ -1477,7 +1552,8 @@ impl ByteReader<Box<dyn ChunkProducer<Chunk = OffsetChunk<WhitespaceOffsets>>>>
             cur_buf: UniqueBuf::new(0).into_buf(),
             buf_len: 0,
             progress: 0,
-            record_sep: 0, // unused
+            offset: 0,
+            record_sep: b'\n', // only used by `record_sep`
             used_fields: FieldSet::all(),
             last_len: usize::max_value(),
             check_utf8,
@@ -1509,6 +1585,7 @@ where
                 cur_buf: UniqueBuf::new(0).into_buf(),
                 buf_len: 0,
                 progress: 0,
+                offset: 0,
                 record_sep,
                 last_len: usize::max_value(),
                 used_fields,
@@ -1541,6 +1618,24 @@ where
         old.fields = LazyVec::from_vec(old_fields);
         Ok(changed)
     }
+    fn read_batch(&mut self, batch: &mut RecordBatch<DefaultLine>) -> Result<()> {
+        // See the comment in CSVReader::read_batch.
+        let used_fields = self.used_fields.clone();
+        self.used_fields.union(&FieldSet::singleton(0));
+        // read_line_inner only refreshes the buffer when maybe_done holds.
+        while !batch.is_full() && !self.maybe_done() {
+            let mut line = batch.take_spare();
+            line.diverged = false;
+            line.used_fields = used_fields.clone();
+            let mut fields = line.fields.get_cleared_vec();
+            let changed = self.read_line_inner(&mut line.line, &mut fields)?;
+            debug_assert!(!changed);
+            line.fields = LazyVec::from_vec(fields);
+            batch.push(line);
+        }
+        self.used_fields = used_fields;
+        Ok(())
+    }
     fn read_state(&self) -> i64 {
         if self.cur_chunk_version() != 0 && self.last_len == 0 {
             ReaderState::EOF as i64
@@ -1560,6 +1655,17 @@ where
     fn set_used_fields(&mut self, field_set: &FieldSet) {
         self.used_fields = field_set.clone();
     }
+    fn record_offset(&self) -> Int {
+        self.offset as Int
+    }
+    fn record_sep(&self) -> Option<Str<'static>> {
+        Some(sep_before(
+            &self.cur_buf,
+            self.buf_len,
+            self.progress,
+            self.record_sep,
+        ))
+    }
 }
 
 // Most of the implementation for splitting by whitespace and splitting by a single byte is
@@ -1626,6 +1732,7 @@ where
             return Ok(false);
         }
     }
+    br.offset = br.cur_chunk.offset + br.progress;
     let (next_line, consumed) = unsafe { br.consume_line(fields) };
     *line = next_line;
     br.last_len = consumed;
@@ -1824,6 +1931,12 @@ unquoted,commas,"as well, including some long ones", and there we have it."#;
         );
     }
 
+    #[test]
+    fn generic_smoke_test() {
+        smoke_test::<generic::Impl>();
+    }
+
+    #[cfg(target_arch = "x86_64")]
     #[test]
     fn avx2_smoke_test() {
         if is_x86_feature_detected!("avx2") {
@@ -1831,6 +1944,7 @@ unquoted,commas,"as well, including some long ones", and there we have it."#;
         }
     }
 
+    #[cfg(target_arch = "x86_64")]
     #[test]
     fn sse2_smoke_test() {
         smoke_test::<sse2::Impl>();
@@ -2018,6 +2132,17 @@ unquoted,commas,"as well, including some long ones", and there we have it."#;
         bytes_split(b' ', b'\n', "   leading whitespace   \n and some    more\n");
     }
 
+    #[test]
+    fn bytes_splitter_specialized() {
+        // Tab and comma with a newline record separator use dedicated kernels.
+        bytes_split(
+            b',',
+            b'\n',
+            crate::test_string_constants::PRIDE_PREJUDICE_CH2,
+        );
+        bytes_split(b'\t', b'\n', "a\tb\t\tc\n\td\n\n\te\tf\t\ng\th");
+    }
+
     fn multithreaded_count<LR: LineReader + 'static>(
         corpus: &'static str,
         n_threads: usize,
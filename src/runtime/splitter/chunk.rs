@@ -15,6 +15,7 @@ use crate::runtime::{
         Reader,
     },
     str_impl::UniqueBuf,
+    warnings,
 };
 
 // TODO: We probably want a better story here about ChunkProducers propagating error values.
@@ -95,7 +96,7 @@ pub fn new_offset_chunk_producer_bytes<R: Read>(
     start_version: u32,
     check_utf8: bool,
 ) -> OffsetChunkProducer<R, impl FnMut(&[u8], &mut Offsets)> {
-    let find_indexes = get_find_indexes_bytes();
+    let find_indexes = get_find_indexes_bytes(field_sep, record_sep);
     OffsetChunkProducer {
         name: name.into(),
         inner: Reader::new(r, chunk_size, /*padding=*/ 128, check_utf8),
@@ -234,6 +235,8 @@ pub struct OffsetChunk<Off = Offsets> {
     pub buf: Option<UniqueBuf>,
     pub len: usize,
     pub off: Off,
+    // The offset in the file of the first byte of buf.
+    pub offset: usize,
 }
 
 impl<Off: Default> Default for OffsetChunk<Off> {
@@ -244,6 +247,7 @@ impl<Off: Default> Default for OffsetChunk<Off> {
             buf: None,
             len: 0,
             off: Default::default(),
+            offset: 0,
         }
     }
 }
@@ -274,6 +278,7 @@ impl<R: Read, F: FnMut(&[u8], &mut Offsets)> ChunkProducer for OffsetChunkProduc
                 ChunkState::Main => {
                     chunk.version = self.cur_file_version;
                     chunk.name = self.name.clone();
+                    chunk.offset = self.inner.base;
                     let buf = self.inner.buf.clone();
                     let bs = buf.as_bytes();
                     (self.find_indexes)(bs, &mut chunk.off);
@@ -318,6 +323,8 @@ impl<R: Read, F: FnMut(&[u8], &mut Offsets)> ChunkProducer for OffsetChunkProduc
                     // Lastly, if it is not the last buffer and we do not have a record separator,
                     // we simply repeat this entire loop.
                     chunk.len = self.inner.end;
+                    let unterminated = self.inner.end > self.inner.start
+                        && bs[self.inner.end - 1] != self.record_sep;
                     let is_eof = self.inner.reset()?;
                     return match (is_partial, is_eof) {
                         (false, false) => {
@@ -329,6 +336,9 @@ impl<R: Read, F: FnMut(&[u8], &mut Offsets)> ChunkProducer for OffsetChunkProduc
                         }
                         (false, true) | (true, true) => {
                             // Yield the entire buffer, this was the last piece of data.
+                            if unterminated {
+                                warn_unterminated(&self.name);
+                            }
                             self.inner.clear_buf();
                             chunk.buf = Some(buf.try_unique().unwrap());
                             chunk.off.fields.truncate(always_truncate);
@@ -347,6 +357,11 @@ impl<R: Read, F: FnMut(&[u8], &mut Offsets)> ChunkProducer for OffsetChunkProduc
     }
 }
 
+fn warn_unterminated(name: &str) {
+    let kind = warnings::Kind::TruncatedRecord;
+    warnings::warn(kind, Some(name), "last record has no record separator");
+}
+
 pub struct WhitespaceChunkProducer<R, F>(OffsetChunkProducer<R, F>, u64);
 
 impl<R: Read, F: FnMut(&[u8], &mut WhitespaceOffsets, u64) -> u64> ChunkProducer
@@ -371,6 +386,7 @@ impl<R: Read, F: FnMut(&[u8], &mut WhitespaceOffsets, u64) -> u64> ChunkProducer
                 ChunkState::Main => {
                     chunk.version = self.0.cur_file_version;
                     chunk.name = self.0.name.clone();
+                    chunk.offset = self.0.inner.base;
                     let buf = self.0.inner.buf.clone();
                     let bs = buf.as_bytes();
                     self.1 = (self.0.find_indexes)(bs, &mut chunk.off, self.1);
@@ -397,6 +413,8 @@ impl<R: Read, F: FnMut(&[u8], &mut WhitespaceOffsets, u64) -> u64> ChunkProducer
                         };
                     // See comments in get_chunk for OffsetChunkProducer<R, F>
                     chunk.len = self.0.inner.end;
+                    let unterminated =
+                        self.0.inner.end > self.0.inner.start && bs[self.0.inner.end - 1] != b'\n';
                     let is_eof = self.0.inner.reset()?;
                     return match (is_partial, is_eof) {
                         (false, false) => {
@@ -408,6 +426,9 @@ impl<R: Read, F: FnMut(&[u8], &mut WhitespaceOffsets, u64) -> u64> ChunkProducer
                         }
                         (false, true) | (true, true) => {
                             // Yield the entire buffer, this was the last piece of data.
+                            if unterminated {
+                                warn_unterminated(&self.0.name);
+                            }
                             self.0.inner.clear_buf();
                             chunk.buf = Some(buf.try_unique().unwrap());
                             self.0.state = ChunkState::Done;
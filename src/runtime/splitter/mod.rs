@@ -9,13 +9,15 @@ pub mod batch;
 pub mod chunk;
 pub mod regex;
 
+use self::regex::RegexSplitter;
 use super::str_impl::{Buf, Str, UniqueBuf};
 use super::utf8::{is_utf8, validate_utf8_clipped};
-use super::{Int, LazyVec, RegexCache};
+use super::{Float, Int, LazyVec, RegexCache};
 use crate::common::Result;
 use crate::pushdown::FieldSet;
 
-use std::io::{ErrorKind, Read};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read};
 
 // We have several implementations of "read and split a line"; they are governed by the LineReader
 // and Line traits.
@@ -31,6 +33,9 @@ pub trait Line<'a>: Default {
     ) -> Result<Str<'a>>
     where
         F: FnMut(Str<'static>) -> Str<'static>;
+    // The sum of columns start through end, converted to numbers. Columns past the end of the line
+    // are empty, and count as 0.
+    fn sum_cols(&mut self, start: Int, end: Int, nf: usize) -> Result<Float>;
     fn nf(&mut self, pat: &Str, rc: &mut RegexCache) -> Result<usize>;
     fn get_col(&mut self, col: Int, pat: &Str, ofs: &Str, rc: &mut RegexCache) -> Result<Str<'a>>;
     fn set_col(&mut self, col: Int, s: &Str<'a>, pat: &Str, rc: &mut RegexCache) -> Result<()>;
@@ -58,12 +63,113 @@ pub trait LineReader: Sized {
         std::mem::swap(old, &mut new);
         Ok(changed)
     }
+    // Read more records into `batch`, until it is full, taking only those that are already in this
+    // reader's buffer. Reading a batch never reads more input, starts a new file or reaches the end
+    // of the input, so each record in it is one `read_line_reuse` would have returned next, with an
+    // OK read state. Readers that do not support batches leave `batch` as it is.
+    fn read_batch(&mut self, _batch: &mut RecordBatch<Self::Line>) -> Result<()> {
+        Ok(())
+    }
     fn read_state(&self) -> i64;
     fn next_file(&mut self) -> Result<bool>;
     fn set_used_fields(&mut self, used_fields: &FieldSet);
     // Whether or not this LineReader is configured to check for valid UTF-8. This is used to
     // propagate consistent options across multiple LineReader instances.
     fn check_utf8(&self) -> bool;
+
+    // Readers that take their input files from ARGV while the program runs (see ArgvReader) stop
+    // at the end of each file. When they do, next_argv_file opens the next file named in ARGV,
+    // returning whether there was one. `arg(i)` returns ARGV[i], if it is set.
+    const READS_ARGV: bool = false;
+    fn next_argv_file(&mut self, _argc: Int, _arg: impl Fn(Int) -> Option<String>) -> Result<bool> {
+        Ok(false)
+    }
+    // The `var=value` entries in ARGV that next_argv_file has passed over, in order. Each is
+    // returned once.
+    fn next_assignment(&mut self) -> Option<String> {
+        None
+    }
+
+    // The byte offset within its file at which the last record read starts, or -1 if this reader
+    // does not keep track of offsets.
+    fn record_offset(&self) -> Int {
+        -1
+    }
+
+    // The record separator that ended the last record read, as it appeared in the input: empty if
+    // the input ended without one. None if this reader does not keep track of it.
+    fn record_sep(&self) -> Option<Str<'static>> {
+        None
+    }
+}
+
+/// Records of the main input that a [`LineReader`] has already split out of its buffer, waiting
+/// to be handed to the program one at a time.
+pub struct RecordBatch<L> {
+    records: VecDeque<L>,
+    // Lines the program has finished with, kept so that their field vectors can be reused.
+    spare: Vec<L>,
+}
+
+impl<L> Default for RecordBatch<L> {
+    fn default() -> RecordBatch<L> {
+        RecordBatch {
+            records: VecDeque::new(),
+            spare: Vec::new(),
+        }
+    }
+}
+
+impl<L: Default> RecordBatch<L> {
+    /// The number of records a batch holds when it is full.
+    pub const CAPACITY: usize = 64;
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.records.len() >= Self::CAPACITY
+    }
+
+    /// A line to read the next record of the batch into, before passing it to `push`.
+    pub fn take_spare(&mut self) -> L {
+        self.spare.pop().unwrap_or_default()
+    }
+
+    pub fn push(&mut self, line: L) {
+        self.records.push_back(line)
+    }
+
+    /// Replace `line` with the next record in the batch, returning whether there was one.
+    pub fn pop_into(&mut self, line: &mut L) -> bool {
+        match self.records.pop_front() {
+            Some(mut next) => {
+                std::mem::swap(line, &mut next);
+                self.spare.push(next);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop the records in the batch.
+    pub fn clear(&mut self) {
+        self.spare.extend(self.records.drain(..));
+    }
+}
+
+/// The part of a [`RecordBatch`] that compiled code reads and writes directly.
+///
+/// The main loop of a compiled program takes the next record of a batch by moving it from `avail`
+/// to `taken`, without calling into the runtime. The runtime catches up, moving the last record
+/// taken into the current line, whenever it next looks at the input (see
+/// `FileRead::take_records`). So `avail + taken` is always the length of the batch.
+#[repr(C)]
+#[derive(Default)]
+pub struct RecordCursor {
+    pub avail: Int,
+    pub taken: Int,
 }
 
 fn normalize_join_indexes(start: Int, end: Int, nf: usize) -> Result<(usize, usize)> {
@@ -81,6 +187,24 @@ fn normalize_join_indexes(start: Int, end: Int, nf: usize) -> Result<(usize, usi
     Ok((start, end))
 }
 
+fn normalize_sum_indexes(start: Int, end: Int, nf: usize) -> Result<(usize, usize)> {
+    if start <= 0 {
+        return err!("smallest summable column is 1, got {}", start);
+    }
+    let start = std::cmp::min(start as usize - 1, nf);
+    let end = std::cmp::max(std::cmp::min(end, nf as Int), start as Int) as usize;
+    Ok((start, end))
+}
+
+/// fmean(start, end): the mean of columns start through end, given their sum.
+pub(crate) fn mean_cols(sum: Float, start: Int, end: Int) -> Float {
+    if end < start {
+        0.0
+    } else {
+        sum / (end - start + 1) as Float
+    }
+}
+
 // Default implementation of Line; it supports assignment into fields as well as lazy splitting.
 pub struct DefaultLine {
     line: Str<'static>,
@@ -137,6 +261,11 @@ impl<'a> Line<'a> for DefaultLine {
             .join_by(&sep.clone().unmoor(), start, end, trans)
             .upcast())
     }
+    fn sum_cols(&mut self, start: Int, end: Int, nf: usize) -> Result<Float> {
+        // Should have split before calling this function.
+        let (start, end) = normalize_sum_indexes(start, end, nf)?;
+        Ok(self.fields.sum_range(start, end))
+    }
     fn nf(&mut self, pat: &Str, rc: &mut RegexCache) -> Result<usize> {
         self.split_if_needed(pat, rc)?;
         Ok(self.fields.len())
@@ -271,6 +400,173 @@ where
             i.set_used_fields(used_fields);
         }
     }
+    fn record_offset(&self) -> Int {
+        self.0.last().map(LineReader::record_offset).unwrap_or(-1)
+    }
+    fn record_sep(&self) -> Option<Str<'static>> {
+        self.0.last().and_then(LineReader::record_sep)
+    }
+}
+
+/// Whether the command-line operand `arg` is a `var=value` assignment rather than the name of an
+/// input file.
+pub fn is_var_assignment(arg: &str) -> bool {
+    let name = match arg.find('=') {
+        Some(i) => &arg[..i],
+        None => return false,
+    };
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// ArgvReader reads the main input from the files named in ARGV. Rather than fixing the list of
+/// files when it is created, it consults ARGV and ARGC each time it reaches the end of a file, so
+/// that programs can add, remove or reorder their input files in BEGIN or while reading earlier
+/// files. Empty entries are skipped, `var=value` assignments are queued for the program to
+/// perform (see `next_assignment`), `-` names standard input, and standard input is read if ARGV
+/// does not name any files (unless `null_input` is set, in which case the input is empty).
+pub struct ArgvReader {
+    cur: Option<RegexSplitter<Box<dyn Read + Send>>>,
+    stdin: Option<Box<dyn Read + Send>>,
+    null_input: bool,
+    // The index of the next ARGV entry to consider.
+    next: Int,
+    // The assignments passed over that the program has not performed yet.
+    assignments: VecDeque<String>,
+    // Whether we have opened a file (or standard input) yet.
+    opened: bool,
+    used_fields: FieldSet,
+    chunk_size: usize,
+    check_utf8: bool,
+}
+
+impl ArgvReader {
+    pub fn new(
+        stdin: Box<dyn Read + Send>,
+        null_input: bool,
+        chunk_size: usize,
+        check_utf8: bool,
+    ) -> ArgvReader {
+        ArgvReader {
+            cur: None,
+            stdin: Some(stdin),
+            null_input,
+            next: 1,
+            assignments: VecDeque::new(),
+            opened: false,
+            used_fields: FieldSet::all(),
+            chunk_size,
+            check_utf8,
+        }
+    }
+
+    fn open(&mut self, reader: Box<dyn Read + Send>, name: String) {
+        let mut cur = RegexSplitter::new(reader, self.chunk_size, name, self.check_utf8);
+        cur.set_used_fields(&self.used_fields);
+        self.cur = Some(cur);
+        self.opened = true;
+    }
+}
+
+impl LineReader for ArgvReader {
+    type Line = DefaultLine;
+    const READS_ARGV: bool = true;
+    fn filename(&self) -> Str<'static> {
+        self.cur
+            .as_ref()
+            .map(LineReader::filename)
+            .unwrap_or_else(Str::default)
+    }
+    fn check_utf8(&self) -> bool {
+        self.check_utf8
+    }
+    fn read_line(&mut self, pat: &Str, rc: &mut RegexCache) -> Result<(bool, DefaultLine)> {
+        let mut line = DefaultLine::default();
+        let changed = self.read_line_reuse(pat, rc, &mut line)?;
+        Ok((changed, line))
+    }
+    fn read_line_reuse<'a, 'b: 'a>(
+        &'b mut self,
+        pat: &Str,
+        rc: &mut RegexCache,
+        old: &'a mut DefaultLine,
+    ) -> Result<bool> {
+        match &mut self.cur {
+            Some(cur) => cur.read_line_reuse(pat, rc, old),
+            None => {
+                *old = Default::default();
+                Ok(false)
+            }
+        }
+    }
+    fn read_state(&self) -> i64 {
+        match &self.cur {
+            Some(cur) => cur.read_state(),
+            None => ReaderState::EOF as i64,
+        }
+    }
+    fn next_file(&mut self) -> Result<bool> {
+        // Finish the current file; the next read will move on to the next file in ARGV.
+        if let Some(cur) = &mut self.cur {
+            cur.next_file()?;
+        }
+        Ok(false)
+    }
+    fn set_used_fields(&mut self, used_fields: &FieldSet) {
+        self.used_fields = used_fields.clone();
+        if let Some(cur) = &mut self.cur {
+            cur.set_used_fields(used_fields);
+        }
+    }
+    fn next_argv_file(&mut self, argc: Int, arg: impl Fn(Int) -> Option<String>) -> Result<bool> {
+        while self.next < argc {
+            let name = arg(self.next).unwrap_or_default();
+            self.next += 1;
+            if is_var_assignment(name.as_str()) {
+                self.assignments.push_back(name);
+                continue;
+            }
+            if name.is_empty() {
+                continue;
+            }
+            let reader: Box<dyn Read + Send> = if name == "-" {
+                match self.stdin.take() {
+                    Some(stdin) => stdin,
+                    None => Box::new(io::empty()),
+                }
+            } else {
+                match super::command::open_input(name.as_str()) {
+                    Ok(f) => super::progress::counted(f),
+                    Err(e) => return err!("failed to open file '{}': {}", name, e),
+                }
+            };
+            self.open(reader, name);
+            return Ok(true);
+        }
+        if self.opened {
+            return Ok(false);
+        }
+        let stdin = if self.null_input {
+            Box::new(io::empty())
+        } else {
+            self.stdin.take().unwrap()
+        };
+        self.open(stdin, String::from("-"));
+        Ok(true)
+    }
+    fn next_assignment(&mut self) -> Option<String> {
+        self.assignments.pop_front()
+    }
+    fn record_offset(&self) -> Int {
+        self.cur
+            .as_ref()
+            .map(LineReader::record_offset)
+            .unwrap_or(-1)
+    }
+    fn record_sep(&self) -> Option<Str<'static>> {
+        self.cur.as_ref().and_then(LineReader::record_sep)
+    }
 }
 
 // Buffer management and io
@@ -294,6 +590,8 @@ struct Reader<R> {
     buf: Buf,
     // The current "read head" into buf.
     start: usize,
+    // The offset in the input of the first byte of buf.
+    base: usize,
     // Upper bound on readable bytes into buf (not including padding and clipped UTF8 bytes).
     end: usize,
     // Upper bound on all bytes read from input, not including padding.
@@ -309,9 +607,16 @@ struct Reader<R> {
 
     // Validate input as UTF-8
     check_utf8: bool,
+
+    // Readers of pipes, terminals and other live inputs return whatever data is available rather
+    // than waiting to fill a whole chunk; see `RegexSplitter::streaming`. For these, only a read
+    // of zero bytes marks the end of the input, and `drained` records that the last read returned
+    // everything that was available at the time.
+    streaming: bool,
+    drained: bool,
 }
 
-fn read_to_slice(r: &mut impl Read, mut buf: &mut [u8]) -> Result<usize> {
+fn read_to_slice(r: &mut impl Read, mut buf: &mut [u8], streaming: bool) -> Result<usize> {
     let mut read = 0;
     while buf.len() > 0 {
         match r.read(buf) {
@@ -321,8 +626,13 @@ fn read_to_slice(r: &mut impl Read, mut buf: &mut [u8]) -> Result<usize> {
                 }
                 buf = &mut buf[n..];
                 read += n;
+                if streaming {
+                    break;
+                }
             }
             Err(e) => match e.kind() {
+                // A signal caught for a SIGNAL block stops the main loop, so stop waiting for input.
+                ErrorKind::Interrupted if crate::runtime::signal::caught() != 0 => break,
                 ErrorKind::Interrupted => continue,
                 ErrorKind::UnexpectedEof => {
                     break;
@@ -340,6 +650,7 @@ impl<R: Read> Reader<R> {
             inner: r,
             buf: UniqueBuf::new(0).into_buf(),
             start: 0,
+            base: 0,
             end: 0,
             input_end: 0,
             chunk_size,
@@ -347,6 +658,8 @@ impl<R: Read> Reader<R> {
             state: ReaderState::OK,
             last_len: 0,
             check_utf8,
+            streaming: false,
+            drained: false,
         };
         res
     }
@@ -393,6 +706,7 @@ impl<R: Read> Reader<R> {
             return Ok(true);
         }
         let (next_buf, next_len, input_len) = self.get_next_buf(self.start)?;
+        self.base += self.start;
         self.buf = next_buf.into_buf();
         self.end = next_len;
         self.input_end = input_len;
@@ -423,11 +737,15 @@ impl<R: Read> Reader<R> {
             );
         }
         let mut bytes = &mut data.as_mut_bytes()[..self.chunk_size];
-        let bytes_read = plen + read_to_slice(&mut self.inner, &mut bytes[plen..])?;
+        let read_start = super::stats::read_start();
+        let new_bytes = read_to_slice(&mut self.inner, &mut bytes[plen..], self.streaming)?;
+        super::stats::read_done(read_start, new_bytes);
+        let bytes_read = plen + new_bytes;
         if bytes_read != self.chunk_size {
-            done = true;
+            done = !self.streaming || new_bytes == 0;
             bytes = &mut bytes[..bytes_read];
         }
+        self.drained = self.streaming && bytes_read != self.chunk_size;
         let mut ulen = bytes.len();
         if self.check_utf8 {
             ulen = {
@@ -3,7 +3,7 @@ use std::io::Read;
 
 use crate::common::Result;
 use crate::pushdown::FieldSet;
-use crate::runtime::{LazyVec, Str};
+use crate::runtime::{Int, LazyVec, Str};
 use regex::bytes::Regex;
 
 use super::{DefaultLine, LineReader, Reader, ReaderState};
@@ -15,6 +15,10 @@ pub struct RegexSplitter<R> {
     used_fields: FieldSet,
     // Used to trigger updating FILENAME on the first read.
     start: bool,
+    // The offset in the input of the last record read.
+    offset: usize,
+    // The bounds in the current buffer of the separator that ended the last record read.
+    sep: (usize, usize),
 }
 
 impl<R: Read> LineReader for RegexSplitter<R> {
@@ -70,6 +74,13 @@ impl<R: Read> LineReader for RegexSplitter<R> {
     fn set_used_fields(&mut self, used_fields: &FieldSet) {
         self.used_fields = used_fields.clone();
     }
+    fn record_offset(&self) -> Int {
+        self.offset as Int
+    }
+    fn record_sep(&self) -> Option<Str<'static>> {
+        let (start, end) = self.sep;
+        Some(self.reader.buf.slice_to_str(start, end))
+    }
 }
 
 impl<R: Read> RegexSplitter<R> {
@@ -79,17 +90,41 @@ impl<R: Read> RegexSplitter<R> {
             name: name.into(),
             used_fields: FieldSet::all(),
             start: true,
+            offset: 0,
+            sep: (0, 0),
         }
     }
 
+    /// Return records as soon as they are available, rather than waiting to fill a whole chunk of
+    /// input. This is what interactive use of getline needs, for pipes and terminals that produce
+    /// input over time.
+    pub fn streaming(mut self) -> Self {
+        self.reader.streaming = true;
+        self
+    }
+
+    /// Whether the next call to `read_line_regex` can return without reading more input: either a
+    /// complete record is buffered, or the input has ended.
+    pub(crate) fn has_record(&self, pat: &Regex) -> bool {
+        let r = &self.reader;
+        r.state != ReaderState::OK || pat.is_match(&r.buf.as_bytes()[r.start..r.end])
+    }
+
+    /// The input being read.
+    pub(crate) fn inner(&self) -> &R {
+        &self.reader.inner
+    }
+
     pub fn read_line_regex(&mut self, pat: &Regex) -> Str<'static> {
         // We keep this as a separate method because it helps in writing tests.
+        self.offset = self.reader.base + self.reader.start;
         let (res, consumed) = self.read_line_inner(pat);
         self.reader.last_len = consumed;
         res
     }
 
     fn read_line_inner(&mut self, pat: &Regex) -> (Str<'static>, usize) {
+        self.sep = (0, 0);
         if self.reader.is_eof() {
             return (Str::default(), 0);
         }
@@ -99,13 +134,18 @@ impl<R: Read> RegexSplitter<R> {
             // makes it harder for us to call mutable methods like advance in the body, so just get
             // the start and end pointers.
             match pat.find(s).map(|m| (m.start(), m.end())) {
-                // We need this check in case the regex matches across a chunk boundary.
-                Some((start, end)) if end + self.reader.start < self.reader.end => {
+                // We need this check in case the regex matches across a chunk boundary. Streaming
+                // readers that have read everything available take the match as it is rather than
+                // waiting for more input.
+                Some((start, end))
+                    if end + self.reader.start < self.reader.end || self.reader.drained =>
+                {
                     // Valid offsets guaranteed by correctness of regex `find`.
                     let res = self
                         .reader
                         .buf
                         .slice_to_str(self.reader.start, self.reader.start + start);
+                    self.sep = (self.reader.start + start, self.reader.start + end);
                     self.reader.start += end;
                     return (res, end);
                 }
@@ -155,6 +195,7 @@ impl<R: Read> RegexSplitter<R> {
                                 .reader
                                 .buf
                                 .slice_to_str(self.reader.start, self.reader.start + start);
+                            self.sep = (self.reader.start + start, self.reader.start + end);
                             self.reader.start += end;
                             (res, end)
                         }
@@ -226,6 +267,33 @@ mod tests {
         }
     }
 
+    // A reader that returns one piece of its input per call to `read`, like a pipe.
+    struct Pieces(Vec<&'static [u8]>);
+
+    impl Read for Pieces {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let piece = self.0.remove(0);
+            buf[..piece.len()].copy_from_slice(piece);
+            Ok(piece.len())
+        }
+    }
+
+    #[test]
+    fn test_streaming_split() {
+        let pieces = Pieces(vec![b"ab\nc", b"d\n", b"e"]);
+        let mut rdr = RegexSplitter::new(pieces, /*chunk_size=*/ 512, "", true).streaming();
+        assert_eq!(rdr.read_line_regex(&*LINE), Str::from("ab"));
+        // Only "c" is buffered: reading the next record would block on a live input.
+        assert!(!rdr.has_record(&*LINE));
+        assert_eq!(rdr.read_line_regex(&*LINE), Str::from("cd"));
+        assert_eq!(rdr.read_line_regex(&*LINE), Str::from("e"));
+        assert!(rdr.has_record(&*LINE));
+        assert!(rdr.reader.is_eof());
+    }
+
     #[test]
     fn test_line_split() {
         let chunk_size = 1 << 9;
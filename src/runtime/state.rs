@@ -0,0 +1,354 @@
+//! Persistent arrays, saved to a state file at the end of one run and loaded at the start of the
+//! next.
+//!
+//! `--state=FILE --persist=a,b` loads the arrays `a` and `b` from `FILE` before `BEGIN` runs, and
+//! saves them back once `END` finishes; the same is available from within a program as
+//! `load_state(m, name)` and `save_state(m, name)`. A state file that does not exist yet holds no
+//! arrays, so the first run of an incremental job starts from scratch.
+//!
+//! The file holds any number of named arrays. Saving one array rewrites the whole file, keeping
+//! the arrays it does not touch, by writing a temporary file next to it and renaming it into
+//! place; a run that is interrupted part-way through leaves the previous state behind rather than
+//! a truncated file.
+//!
+//! The format is compact and binary:
+//!
+//! ```text
+//! file  := MAGIC (varint(len(name)) name varint(len(array)) array)*
+//! array := key_tag val_tag varint(count) (key val)*
+//! value := zigzag varint (ints) | 8 little-endian bytes (floats) | varint(len) bytes (strings)
+//! ```
+//!
+//! Keys and values are converted to the array's types when they are loaded, so changing the types
+//! of a persisted array between runs does not invalidate the state file.
+use super::{convert, str_impl::DynamicBuf, table::MapKey, Float, Int, SharedMap, Str};
+use crate::common::Result;
+
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const MAGIC: &[u8] = b"frawk-state\x01";
+
+const TAG_INT: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+const TAG_STR: u8 = 2;
+
+struct State {
+    path: Option<PathBuf>,
+    // The encoded contents of each array in the file, or None if we have not read it yet.
+    arrays: Option<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State {
+        path: None,
+        arrays: None,
+    });
+}
+
+/// Set the file used by `load_state` and `save_state`.
+pub fn set_state_file(path: impl Into<PathBuf>) {
+    let mut state = STATE.lock().unwrap();
+    state.path = Some(path.into());
+    state.arrays = None;
+}
+
+impl State {
+    fn arrays(&mut self) -> Result<&mut BTreeMap<Vec<u8>, Vec<u8>>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return err!("no state file given: pass one with --state"),
+        };
+        if self.arrays.is_none() {
+            let contents = match std::fs::read(path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => MAGIC.to_vec(),
+                Err(e) => return err!("failed to read state file {}: {}", path.display(), e),
+            };
+            match decode_file(&contents) {
+                Some(arrays) => self.arrays = Some(arrays),
+                None => return err!("{} is not a valid state file", path.display()),
+            }
+        }
+        Ok(self.arrays.as_mut().unwrap())
+    }
+
+    fn write(&mut self) -> Result<()> {
+        let mut contents = MAGIC.to_vec();
+        for (name, array) in self.arrays()?.iter() {
+            write_bytes(&mut contents, name);
+            write_bytes(&mut contents, array);
+        }
+        let path = self.path.as_ref().unwrap();
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(format!(".tmp-{}", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+        let res = std::fs::File::create(&tmp)
+            .and_then(|mut f| {
+                f.write_all(&contents)?;
+                f.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp, path));
+        if let Err(e) = res {
+            let _ = std::fs::remove_file(&tmp);
+            return err!("failed to write state file {}: {}", path.display(), e);
+        }
+        Ok(())
+    }
+}
+
+fn decode_file(mut bs: &[u8]) -> Option<BTreeMap<Vec<u8>, Vec<u8>>> {
+    if !bs.starts_with(MAGIC) {
+        return None;
+    }
+    bs = &bs[MAGIC.len()..];
+    let mut arrays = BTreeMap::new();
+    while !bs.is_empty() {
+        let name = read_bytes(&mut bs)?;
+        let array = read_bytes(&mut bs)?;
+        arrays.insert(name.to_vec(), array.to_vec());
+    }
+    Some(arrays)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+fn read_varint(bs: &mut &[u8]) -> Option<u64> {
+    let mut res = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (b, rest) = bs.split_first()?;
+        *bs = rest;
+        res |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(res);
+        }
+    }
+    None
+}
+
+fn write_bytes(out: &mut Vec<u8>, bs: &[u8]) {
+    write_varint(out, bs.len() as u64);
+    out.extend_from_slice(bs);
+}
+
+fn read_bytes<'b>(bs: &mut &'b [u8]) -> Option<&'b [u8]> {
+    let len = read_varint(bs)? as usize;
+    if len > bs.len() {
+        return None;
+    }
+    let (res, rest) = bs.split_at(len);
+    *bs = rest;
+    Some(res)
+}
+
+/// A key or value as it is stored in the state file.
+pub(crate) enum Stored<'b> {
+    Int(Int),
+    Float(Float),
+    Str(&'b [u8]),
+}
+
+fn read_stored<'b>(tag: u8, bs: &mut &'b [u8]) -> Option<Stored<'b>> {
+    match tag {
+        TAG_INT => {
+            let z = read_varint(bs)?;
+            Some(Stored::Int(((z >> 1) as i64) ^ -((z & 1) as i64)))
+        }
+        TAG_FLOAT => {
+            if bs.len() < 8 {
+                return None;
+            }
+            let (f, rest) = bs.split_at(8);
+            *bs = rest;
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(f);
+            Some(Stored::Float(Float::from_le_bytes(buf)))
+        }
+        TAG_STR => read_bytes(bs).map(Stored::Str),
+        _ => None,
+    }
+}
+
+/// The types that can be stored in an array.
+pub(crate) trait Persist: Sized {
+    const TAG: u8;
+    fn store(&self, out: &mut Vec<u8>);
+    fn load(s: Stored) -> Self;
+}
+
+fn owned_str<'a>(bs: &[u8]) -> Str<'a> {
+    let mut buf = DynamicBuf::new(bs.len());
+    buf.write_all(bs).unwrap();
+    unsafe { buf.into_str() }
+}
+
+impl Persist for Int {
+    const TAG: u8 = TAG_INT;
+    fn store(&self, out: &mut Vec<u8>) {
+        write_varint(out, ((*self << 1) ^ (*self >> 63)) as u64)
+    }
+    fn load(s: Stored) -> Int {
+        match s {
+            Stored::Int(i) => i,
+            Stored::Float(f) => convert::<Float, Int>(f),
+            Stored::Str(bs) => convert::<&Str, Int>(&Str::from(bs)),
+        }
+    }
+}
+
+impl Persist for Float {
+    const TAG: u8 = TAG_FLOAT;
+    fn store(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes())
+    }
+    fn load(s: Stored) -> Float {
+        match s {
+            Stored::Int(i) => convert::<Int, Float>(i),
+            Stored::Float(f) => f,
+            Stored::Str(bs) => convert::<&Str, Float>(&Str::from(bs)),
+        }
+    }
+}
+
+impl<'a> Persist for Str<'a> {
+    const TAG: u8 = TAG_STR;
+    fn store(&self, out: &mut Vec<u8>) {
+        self.with_bytes(|bs| write_bytes(out, bs))
+    }
+    fn load(s: Stored) -> Str<'a> {
+        match s {
+            Stored::Int(i) => convert::<Int, Str>(i),
+            Stored::Float(f) => convert::<Float, Str>(f),
+            Stored::Str(bs) => owned_str(bs),
+        }
+    }
+}
+
+/// `load_state(m, name)`: replace the contents of `m` with the array saved as `name`, returning
+/// the number of entries loaded.
+pub(crate) fn load<K, V>(m: &SharedMap<K, V>, name: &Str) -> Result<Int>
+where
+    K: Persist + MapKey,
+    V: Persist,
+{
+    let mut state = STATE.lock().unwrap();
+    let arrays = state.arrays()?;
+    let array = match name.with_bytes(|name| arrays.get(name)) {
+        Some(array) => array,
+        None => return Ok(0),
+    };
+    let mut entries = HashMap::new();
+    let mut bs = &array[..];
+    let ok = (|| {
+        let (key_tag, val_tag) = (*bs.get(0)?, *bs.get(1)?);
+        bs = &bs[2..];
+        let count = read_varint(&mut bs)?;
+        for _ in 0..count {
+            let k = K::load(read_stored(key_tag, &mut bs)?);
+            let v = V::load(read_stored(val_tag, &mut bs)?);
+            entries.insert(k, v);
+        }
+        Some(())
+    })();
+    if ok.is_none() {
+        let name = name.with_bytes(|bs| String::from_utf8_lossy(bs).into_owned());
+        return err!("array {} in the state file is corrupt", name);
+    }
+    let n = entries.len() as Int;
+    m.clear();
+    for (k, v) in entries {
+        m.insert(k, v);
+    }
+    Ok(n)
+}
+
+/// `save_state(m, name)`: save the contents of `m` as `name`, returning the number of entries
+/// saved.
+pub(crate) fn save<K, V>(m: &SharedMap<K, V>, name: &Str) -> Result<Int>
+where
+    K: Persist + MapKey,
+    V: Persist,
+{
+    let mut array = vec![K::TAG, V::TAG];
+    let n = m.len();
+    write_varint(&mut array, n as u64);
+    m.iter(|iter| {
+        for (k, v) in iter {
+            k.store(&mut array);
+            v.store(&mut array);
+        }
+    });
+    let mut state = STATE.lock().unwrap();
+    state
+        .arrays()?
+        .insert(name.with_bytes(|bs| bs.to_vec()), array);
+    state.write()?;
+    Ok(n as Int)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints() {
+        for x in &[0u64, 1, 127, 128, 300, u64::max_value()] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, *x);
+            let mut bs = &buf[..];
+            assert_eq!(read_varint(&mut bs), Some(*x));
+            assert!(bs.is_empty());
+        }
+        for i in &[0, -1, 1, Int::min_value(), Int::max_value()] {
+            let mut buf = Vec::new();
+            i.store(&mut buf);
+            match read_stored(TAG_INT, &mut &buf[..]) {
+                Some(Stored::Int(j)) => assert_eq!(*i, j),
+                _ => panic!("failed to read back {}", i),
+            }
+        }
+        assert!(read_varint(&mut &[0x80u8][..]).is_none());
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        set_state_file(dir.path().join("state"));
+
+        let name = Str::from("counts");
+        let counts: SharedMap<Str, Int> = Default::default();
+        assert_eq!(load(&counts, &name).unwrap(), 0);
+        counts.insert(Str::from("a"), 3);
+        counts.insert(Str::from("b"), -7);
+        let other_name = Str::from("other");
+        let other: SharedMap<Int, Float> = Default::default();
+        other.insert(1, 2.5);
+        assert_eq!(save(&counts, &name).unwrap(), 2);
+        assert_eq!(save(&other, &other_name).unwrap(), 1);
+
+        // Re-read from disk, converting to other types along the way.
+        set_state_file(dir.path().join("state"));
+        let loaded: SharedMap<Str, Str> = Default::default();
+        loaded.insert(Str::from("stale"), Str::from("x"));
+        assert_eq!(load(&loaded, &name).unwrap(), 2);
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(&Str::from("b")), Some(Str::from("-7")));
+        let loaded: SharedMap<Str, Float> = Default::default();
+        assert_eq!(load(&loaded, &other_name).unwrap(), 1);
+        assert_eq!(loaded.get(&Str::from("1")), Some(2.5));
+
+        std::fs::write(dir.path().join("state"), b"garbage").unwrap();
+        set_state_file(dir.path().join("state"));
+        assert!(load(&loaded, &name).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//! Counters for `--stats`, which prints a summary of the I/O a program performed, and of where its
+//! time went, when it exits, and for `--report=json`, which writes the same summary as a JSON
+//! object for programs that monitor frawk jobs.
+//!
+//! Counters are only updated once `enable` or `enable_json` has been called, so that programs run
+//! without either pay for no more than a relaxed load of `ENABLED` in the places that update them.
+//! Counts are shared by all threads; when running in parallel, records read from each file are
+//! summed across workers.
+use crate::runtime::{warnings, Int, RegexStats, Str};
+
+use lazy_static::lazy_static;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REPORTED: AtomicBool = AtomicBool::new(false);
+static BYTES_IN: AtomicU64 = AtomicU64::new(0);
+static BYTES_OUT: AtomicU64 = AtomicU64::new(0);
+static READ_NANOS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default)]
+struct Stats {
+    start: Option<Instant>,
+    // When the program started running, once it was compiled.
+    running: Option<Instant>,
+    // CPU time used (user, system) at `running`.
+    running_cpu: Option<(Duration, Duration)>,
+    // The number of records read from each input file, in the order they were first read.
+    files: Vec<(String, u64)>,
+    regexes: RegexStats,
+    // Whether to print the summary for --stats.
+    text: bool,
+    // Where to write the summary for --report=json: a file, or standard error if it is None.
+    json: Option<Option<String>>,
+    // The error that ended the program, if it failed.
+    error: Option<String>,
+}
+
+lazy_static! {
+    static ref STATS: Mutex<Stats> = Mutex::new(Stats::default());
+}
+
+fn start() -> std::sync::MutexGuard<'static, Stats> {
+    let mut stats = STATS.lock().unwrap();
+    stats.start.get_or_insert_with(Instant::now);
+    ENABLED.store(true, Ordering::Relaxed);
+    stats
+}
+
+/// Start collecting statistics, and print them on exit. This should be called as early as
+/// possible, as the time until the program starts running is reported as compilation time.
+pub fn enable() {
+    start().text = true;
+}
+
+/// Start collecting statistics, and write them as JSON to `file` (or standard error, if it is
+/// None) on exit. As with `enable`, this should be called as early as possible.
+pub fn enable_json(file: Option<String>) {
+    start().json = Some(file);
+    warnings::enable_counting();
+}
+
+/// Whether statistics are being collected.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Whether statistics are printed for `--stats`, rather than only written as JSON.
+pub fn text_enabled() -> bool {
+    enabled() && STATS.lock().unwrap().text
+}
+
+/// Record that the program failed with the error `msg`, before it exits.
+pub fn set_error(msg: impl Into<String>) {
+    if enabled() {
+        let error = &mut STATS.lock().unwrap().error;
+        if error.is_none() {
+            *error = Some(msg.into().trim().to_string());
+        }
+    }
+}
+
+/// Note that the program has been compiled, and is about to start running.
+pub(crate) fn start_running() {
+    if !enabled() {
+        return;
+    }
+    let mut stats = STATS.lock().unwrap();
+    if stats.running.is_none() {
+        stats.running = Some(Instant::now());
+        stats.running_cpu = cpu_time();
+    }
+}
+
+/// The time at which a read of input started, if its duration should be recorded with
+/// `read_done`.
+pub(crate) fn read_start() -> Option<Instant> {
+    if enabled() {
+        Some(Instant::now())
+    } else {
+        None
+    }
+}
+
+/// Record a read of `bytes` bytes of input, started at `start`.
+pub(crate) fn read_done(start: Option<Instant>, bytes: usize) {
+    if let Some(start) = start {
+        BYTES_IN.fetch_add(bytes as u64, Ordering::Relaxed);
+        let nanos = start.elapsed().as_nanos() as u64;
+        READ_NANOS.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// Record a write of `bytes` bytes of output.
+pub(crate) fn wrote(bytes: usize) {
+    if enabled() {
+        BYTES_OUT.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Record that `records` records were read from the input file `name`, given the values of
+/// `FILENAME` and `FNR` as the program moves on to the next file, or finishes.
+pub(crate) fn file_done(name: &Str, records: Int) {
+    if !enabled() || records <= 0 {
+        return;
+    }
+    let name = name.to_string();
+    let files = &mut STATS.lock().unwrap().files;
+    match files.iter_mut().find(|(f, _)| *f == name) {
+        Some((_, n)) => *n += records as u64,
+        None => files.push((name, records as u64)),
+    }
+}
+
+/// Record how a regex cache was used, once it is no longer needed.
+pub(crate) fn regexes_done(regexes: RegexStats) {
+    if !enabled() {
+        return;
+    }
+    let total = &mut STATS.lock().unwrap().regexes;
+    total.lookups += regexes.lookups;
+    total.misses += regexes.misses;
+    total.evictions += regexes.evictions;
+}
+
+/// Print the statistics collected so far to standard error, or write them as JSON, if they are
+/// enabled. Only the first call prints anything.
+pub fn report() {
+    if !enabled() || REPORTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let stats = STATS.lock().unwrap();
+    let end = Instant::now();
+    if stats.text {
+        print_text(&stats, end);
+    }
+    if let Some(file) = &stats.json {
+        let mut json = to_json(&stats, end);
+        json.push('\n');
+        match file {
+            Some(file) => {
+                if let Err(e) = std::fs::write(file, json) {
+                    eprintln_ignore!("frawk: failed to write report to {}: {}", file, e);
+                }
+            }
+            None => eprintln_ignore!("{}", json.trim_end()),
+        }
+    }
+}
+
+fn print_text(stats: &Stats, end: Instant) {
+    let start = stats.start.unwrap_or(end);
+    let running = stats.running.unwrap_or(end);
+    let reading = Duration::from_nanos(READ_NANOS.load(Ordering::Relaxed));
+    let records: u64 = stats.files.iter().map(|(_, n)| *n).sum();
+    eprintln_ignore!(
+        "frawk: input: {} records, {} bytes",
+        records,
+        BYTES_IN.load(Ordering::Relaxed)
+    );
+    for (file, n) in stats.files.iter() {
+        eprintln_ignore!("frawk:   {}: {} records", file, n);
+    }
+    eprintln_ignore!("frawk: output: {} bytes", BYTES_OUT.load(Ordering::Relaxed));
+    let RegexStats {
+        lookups, misses, ..
+    } = stats.regexes;
+    if lookups > 0 {
+        eprintln_ignore!(
+            "frawk: regex cache: {} lookups, {} misses, {:.1}% hit rate",
+            lookups,
+            misses,
+            100.0 * (lookups - misses) as f64 / lookups as f64
+        );
+    }
+    let run = end.saturating_duration_since(running);
+    eprintln_ignore!(
+        "frawk: wall time: {} compiling, {} reading input, {} executing",
+        secs(running.saturating_duration_since(start)),
+        secs(reading),
+        secs(run.checked_sub(reading).unwrap_or_default())
+    );
+    if let Some((user, sys)) = cpu_time() {
+        let (run_user, run_sys) = stats.running_cpu.unwrap_or((user, sys));
+        eprintln_ignore!(
+            "frawk: cpu time: {} user, {} system ({} user, {} system compiling)",
+            secs(user),
+            secs(sys),
+            secs(run_user),
+            secs(run_sys)
+        );
+    }
+}
+
+fn to_json(stats: &Stats, end: Instant) -> String {
+    let start = stats.start.unwrap_or(end);
+    let running = stats.running.unwrap_or(end);
+    let reading = Duration::from_nanos(READ_NANOS.load(Ordering::Relaxed));
+    let run = end.saturating_duration_since(running);
+    let mut res = String::new();
+    let status = if stats.error.is_some() {
+        1
+    } else {
+        super::exit::status()
+    };
+    let _ = write!(&mut res, "{{\"status\":{},\"error\":", status);
+    match &stats.error {
+        Some(e) => warnings::push_json_str(&mut res, e),
+        None => res.push_str("null"),
+    }
+    res.push_str(",\"files\":[");
+    for (i, (file, n)) in stats.files.iter().enumerate() {
+        if i > 0 {
+            res.push(',');
+        }
+        res.push_str("{\"name\":");
+        warnings::push_json_str(&mut res, file);
+        let _ = write!(&mut res, ",\"records\":{}}}", n);
+    }
+    let records: u64 = stats.files.iter().map(|(_, n)| *n).sum();
+    let _ = write!(
+        &mut res,
+        "],\"records\":{},\"bytes_read\":{},\"bytes_written\":{},\"warnings\":{{",
+        records,
+        BYTES_IN.load(Ordering::Relaxed),
+        BYTES_OUT.load(Ordering::Relaxed)
+    );
+    for (i, (kind, n)) in warnings::counts().into_iter().enumerate() {
+        if i > 0 {
+            res.push(',');
+        }
+        warnings::push_json_str(&mut res, kind);
+        let _ = write!(&mut res, ":{}", n);
+    }
+    let _ = write!(
+        &mut res,
+        "}},\"seconds\":{{\"wall\":{:.6},\"compiling\":{:.6},\"reading\":{:.6},\"executing\":{:.6}",
+        end.saturating_duration_since(start).as_secs_f64(),
+        running.saturating_duration_since(start).as_secs_f64(),
+        reading.as_secs_f64(),
+        run.checked_sub(reading).unwrap_or_default().as_secs_f64()
+    );
+    if let Some((user, sys)) = cpu_time() {
+        let _ = write!(
+            &mut res,
+            ",\"user\":{:.6},\"system\":{:.6}",
+            user.as_secs_f64(),
+            sys.as_secs_f64()
+        );
+    }
+    res.push('}');
+    if let Some(bytes) = peak_memory() {
+        let _ = write!(&mut res, ",\"peak_memory_bytes\":{}", bytes);
+    }
+    res.push('}');
+    res
+}
+
+/// Calls `report` when dropped.
+pub struct Report;
+
+impl Drop for Report {
+    fn drop(&mut self) {
+        report()
+    }
+}
+
+fn secs(d: Duration) -> String {
+    format!("{:.3}s", d.as_secs_f64())
+}
+
+/// The user and system CPU time used by the process so far.
+#[cfg(unix)]
+fn cpu_time() -> Option<(Duration, Duration)> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let dur = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    Some((dur(usage.ru_utime), dur(usage.ru_stime)))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<(Duration, Duration)> {
+    None
+}
+
+/// The largest amount of memory the process has had resident at once, in bytes.
+#[cfg(unix)]
+fn peak_memory() -> Option<u64> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let max_rss = usage.ru_maxrss as u64;
+    // macOS reports bytes; everywhere else reports kilobytes.
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+#[cfg(not(unix))]
+fn peak_memory() -> Option<u64> {
+    None
+}
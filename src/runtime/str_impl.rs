@@ -5,9 +5,21 @@
 /// this representation because it significanly improved some benchmarks in terms of time and
 /// space, and it also makes for more ergonomic interop with LLVM.
 ///
-/// TODO explain more about what is going on here.
+/// A `Str` is 16 bytes wide, and native code passes it around as a 128-bit integer. The low three
+/// bits of the first word are a tag (see `StrTag`) describing how to interpret the rest:
+///
+/// * Inline strings store their length in the rest of the first byte and up to
+///   `MAX_INLINE_SIZE` bytes of content directly after it. The empty string is the all-zero
+///   Inline value.
+/// * Literals point to bytes that outlive the string, such as string constants.
+/// * Boxed strings own a reference-counted buffer, and Shared strings are a slice of one.
+/// * Concat strings are lazy concatenations, flattened into a Boxed string on first read.
+///
+/// Most fields and intermediate values are short, so we take care to produce Inline strings
+/// whenever the result fits: doing so avoids a heap allocation as well as any reference count
+/// traffic when the string is copied or dropped.
 use crate::pushdown::FieldSet;
-use crate::runtime::{Float, Int};
+use crate::runtime::{matcher::Find, record_arena, Float, Int};
 
 use regex::bytes::Regex;
 use smallvec::SmallVec;
@@ -61,6 +73,9 @@ impl StrTag {
 struct Inline(u128);
 const MAX_INLINE_SIZE: usize = 15;
 
+/// Concatenations with a left operand at least this long are copied into a builder.
+const MIN_BUILDER_SIZE: usize = 1 << 10;
+
 impl Default for Inline {
     fn default() -> Inline {
         Inline(StrTag::Inline as u128)
@@ -332,7 +347,7 @@ impl<'a> UniqueStr<'a> {
             StrTag::Inline | StrTag::Literal => self.0.clone(),
             StrTag::Boxed => unsafe {
                 rep.view_as(|b: &Boxed| {
-                    let bs = b.buf.as_bytes();
+                    let bs = &b.buf.as_bytes()[..b.len as usize];
                     Str::from_rep(
                         Boxed {
                             buf: Buf::read_from_raw(bs.as_ptr(), bs.len()),
@@ -359,12 +374,12 @@ impl<'a> From<Str<'a>> for UniqueStr<'a> {
             debug_assert_eq!(StrTag::Boxed, rep.get_tag());
             // We have a box in place, check its refcount
             if let Some(boxed) = rep.view_as(|b: &Boxed| {
-                if b.buf.refcount() == 1 {
+                // Buffers from the record arena cannot be freed from another thread.
+                if b.buf.refcount() == 1 && !b.buf.in_arena() {
                     None
                 } else {
                     // Copy a new buffer.
-                    let bs = b.buf.as_bytes();
-                    debug_assert_eq!(bs.len() as u64, b.len);
+                    let bs = &b.buf.as_bytes()[..b.len as usize];
                     Some(Boxed {
                         buf: Buf::read_from_raw(bs.as_ptr(), bs.len()),
                         len: bs.len() as u64,
@@ -388,7 +403,9 @@ pub struct Str<'a>(UnsafeCell<StrRep<'a>>);
 
 impl<'a> Str<'a> {
     pub fn is_empty(&self) -> bool {
-        unsafe { mem::transmute::<&Str, &Inline>(self) == &Inline::default() }
+        // Compare the representation rather than viewing `self` as an `Inline`: a `u128` is more
+        // strictly aligned than a `Str`.
+        unsafe { self.rep() == &StrRep::default() }
     }
     unsafe fn rep(&self) -> &StrRep<'a> {
         &*self.0.get()
@@ -459,14 +476,14 @@ impl<'a> Str<'a> {
         res
     }
 
-    pub fn subst_first(&self, pat: &Regex, subst: &Str<'a>) -> (Str<'a>, bool) {
+    pub fn subst_first(&self, pat: &impl Find, subst: &Str<'a>) -> (Str<'a>, bool) {
         self.with_bytes(|s| {
             subst.with_bytes(|subst| {
-                if let Some(m) = pat.find(s) {
+                if let Some((start, end)) = pat.find_first(s) {
                     let mut buf = DynamicBuf::new(s.len());
-                    buf.write(&s[0..m.start()]).unwrap();
+                    buf.write(&s[0..start]).unwrap();
                     buf.write(subst).unwrap();
-                    buf.write(&s[m.end()..s.len()]).unwrap();
+                    buf.write(&s[end..s.len()]).unwrap();
                     (unsafe { buf.into_str() }, true)
                 } else {
                     (self.clone(), false)
@@ -475,18 +492,19 @@ impl<'a> Str<'a> {
         })
     }
 
-    pub fn subst_all(&self, pat: &Regex, subst: &Str<'a>) -> (Str<'a>, Int) {
+    pub fn subst_all(&self, pat: &impl Find, subst: &Str<'a>) -> (Str<'a>, Int) {
         self.with_bytes(|s| {
             subst.with_bytes(|subst| {
                 let mut buf = DynamicBuf::new(0);
                 let mut prev = 0;
                 let mut count = 0;
-                for m in pat.find_iter(s) {
-                    buf.write(&s[prev..m.start()]).unwrap();
+                pat.find_each(s, |start, end| {
+                    buf.write(&s[prev..start]).unwrap();
                     buf.write(subst).unwrap();
-                    prev = m.end();
+                    prev = end;
                     count += 1;
-                }
+                    true
+                });
                 if count == 0 {
                     (self.clone(), count)
                 } else {
@@ -513,8 +531,38 @@ impl<'a> Str<'a> {
         let llen = left.len();
         let rlen = right.len();
         let new_len = llen + rlen;
-        if new_len <= MAX_INLINE_SIZE {
-            let mut b = DynamicBuf::new(0);
+        if let Some(res) = left.try_append(&right) {
+            return res;
+        }
+        if llen >= MIN_BUILDER_SIZE {
+            // Appending to a long string is often one of a series of appends (e.g. `s = s x` in a
+            // loop). Copy it into a builder with room to spare, so the next append can write its
+            // bytes in place rather than creating an ever-deeper tree of concatenations.
+            let mut buf = UniqueBuf::builder(new_len * 2);
+            unsafe {
+                let p = buf.as_mut_ptr();
+                left.with_bytes(|bs| ptr::copy_nonoverlapping(bs.as_ptr(), p, llen));
+                right.with_bytes(|bs| ptr::copy_nonoverlapping(bs.as_ptr(), p.add(llen), rlen));
+            }
+            let buf = buf.into_buf();
+            buf.written().unwrap().set(new_len);
+            return Str::from_rep(
+                Boxed {
+                    buf,
+                    len: new_len as u64,
+                }
+                .into(),
+            );
+        }
+        let copy = new_len <= MAX_INLINE_SIZE
+            || (new_len <= record_arena::MAX_ALLOC
+                && record_arena::active()
+                && !left.is_concat()
+                && !right.is_concat());
+        if copy {
+            // Copying into the record arena is cheaper than allocating a node for the lazy
+            // concatenation and then flattening it later.
+            let mut b = DynamicBuf::new(new_len);
             unsafe {
                 b.write(&*left.get_bytes()).unwrap();
                 b.write(&*right.get_bytes()).unwrap();
@@ -531,6 +579,33 @@ impl<'a> Str<'a> {
         }
     }
 
+    /// Append `right` to this string in place, if it is the last string written to a builder with
+    /// enough room left for `right`.
+    fn try_append(&self, right: &Str<'a>) -> Option<Str<'a>> {
+        let rep = unsafe { self.rep_mut() };
+        if rep.get_tag() != StrTag::Boxed {
+            return None;
+        }
+        let (buf, len) = unsafe { rep.view_as(|b: &Boxed| (b.buf.clone(), b.len as usize)) };
+        let written = buf.written()?;
+        let rlen = right.len();
+        if written.get() != len || buf.len() - len < rlen {
+            return None;
+        }
+        // Other strings referencing `buf` only read the first `written` bytes.
+        right.with_bytes(|bs| unsafe {
+            ptr::copy_nonoverlapping(bs.as_ptr(), (buf.as_ptr() as *mut u8).add(len), rlen)
+        });
+        written.set(len + rlen);
+        Some(Str::from_rep(
+            Boxed {
+                buf,
+                len: (len + rlen) as u64,
+            }
+            .into(),
+        ))
+    }
+
     fn from_rep(rep: StrRep<'a>) -> Str<'a> {
         Str(UnsafeCell::new(rep))
     }
@@ -740,7 +815,9 @@ impl<'a> Str<'a> {
                 StrTag::Shared => rep.view_as(|s: &Shared| {
                     &s.buf.as_bytes()[s.start as usize..s.end as usize] as *const _
                 }),
-                StrTag::Boxed => rep.view_as(|b: &Boxed| b.buf.as_bytes() as *const _),
+                StrTag::Boxed => {
+                    rep.view_as(|b: &Boxed| &b.buf.as_bytes()[..b.len as usize] as *const _)
+                }
                 StrTag::Concat => {
                     self.force();
                     self.get_bytes()
@@ -754,6 +831,43 @@ impl<'a> Str<'a> {
         unsafe { f(&*raw) }
     }
 
+    fn is_concat(&self) -> bool {
+        unsafe { self.rep_mut() }.get_tag() == StrTag::Concat
+    }
+
+    /// Whether the bytes of this string are in a heap buffer that it holds a reference to. The
+    /// bytes of such a string stay put, unchanged, for as long as it (or a clone of it) is alive.
+    pub fn is_heap(&self) -> bool {
+        let tag = unsafe { self.rep_mut() }.get_tag();
+        matches!(tag, StrTag::Shared | StrTag::Boxed | StrTag::Concat)
+    }
+
+    /// A copy of this string that does not keep any other string's buffer alive. Slices (such as
+    /// fields) and concatenations are copied into a buffer of their own; other strings are cloned.
+    pub fn compact(&self) -> Str<'a> {
+        let res = self.clone();
+        // Safety: `res` is not borrowed, so forcing it cannot invalidate any references to its
+        // bytes.
+        unsafe { res.force() };
+        let rep = unsafe { res.rep_mut() };
+        let copy = rep.get_tag() == StrTag::Boxed
+            && unsafe { rep.view_as(|b: &Boxed| b.buf.in_arena() || b.buf.written().is_some()) };
+        if copy {
+            // Don't keep a chunk of the record arena, or a builder's spare capacity, alive.
+            return res.with_bytes(|bs| Buf::read_from_bytes(bs).into_str());
+        }
+        res
+    }
+
+    /// Whether this string has a heap buffer of its own that no other string refers to.
+    pub fn is_sole_reference(&self) -> bool {
+        let rep = unsafe { self.rep_mut() };
+        match rep.get_tag() {
+            StrTag::Boxed => unsafe { rep.view_as(|b: &Boxed| b.buf.refcount() == 1) },
+            _ => false,
+        }
+    }
+
     pub fn unmoor(self) -> Str<'static> {
         let rep = unsafe { self.rep_mut() };
         let tag = rep.get_tag();
@@ -841,6 +955,9 @@ impl<'a> From<String> for Str<'a> {
         if s.len() == 0 {
             return Default::default();
         }
+        if s.len() <= MAX_INLINE_SIZE {
+            return Str::from_rep(unsafe { Inline::from_unchecked(s.as_bytes()).into() });
+        }
         let buf = Buf::read_from_bytes(s.as_bytes());
         let boxed = Boxed {
             len: s.len() as u64,
@@ -871,6 +988,15 @@ impl<'a> From<Int> for Str<'a> {
 
 impl<'a> From<Float> for Str<'a> {
     fn from(f: Float) -> Str<'a> {
+        // Integer arithmetic produces floats by default (see runtime::arith), so integral values
+        // print as integers, as they do in gawk. Anything in this range converts to an Int
+        // exactly; -0.0 does not, and prints as "-0".
+        if f.fract() == 0.0 {
+            if f.abs() < 9223372036854775808.0 && !(f == 0.0 && f.is_sign_negative()) {
+                return Str::from(f as Int);
+            }
+            return Str::from(format!("{:.0}", f));
+        }
         // Per ryu's documentation, we will only ever use 24 bytes when printing an f64.
         let mut ryubuf = ryu::Buffer::new();
         let s = ryubuf.format(f);
@@ -893,11 +1019,28 @@ impl Str<'static> {
 
 #[repr(C)]
 struct BufHeader {
+    // The high bit is set for buffers allocated from the record arena, the next highest for
+    // builders (see `UniqueBuf::builder`).
     size: usize,
     // We only have "strong counts"
     count: Cell<usize>,
 }
 
+const ARENA_BIT: usize = 1 << (mem::size_of::<usize>() * 8 - 1);
+const BUILDER_BIT: usize = ARENA_BIT >> 1;
+
+impl BufHeader {
+    fn len(&self) -> usize {
+        self.size & !(ARENA_BIT | BUILDER_BIT)
+    }
+    fn in_arena(&self) -> bool {
+        self.size & ARENA_BIT != 0
+    }
+    fn is_builder(&self) -> bool {
+        self.size & BUILDER_BIT != 0
+    }
+}
+
 #[repr(transparent)]
 pub struct UniqueBuf(*mut BufHeader);
 unsafe impl Send for UniqueBuf {}
@@ -910,12 +1053,12 @@ pub struct DynamicBufHeap {
 impl DynamicBufHeap {
     pub fn new(size: usize) -> DynamicBufHeap {
         DynamicBufHeap {
-            data: UniqueBuf::new(size),
+            data: UniqueBuf::new_transient(size),
             write_head: 0,
         }
     }
     fn size(&self) -> usize {
-        unsafe { (*self.data.0).size }
+        unsafe { (*self.data.0).len() }
     }
     pub fn as_mut_bytes(&mut self) -> &mut [u8] {
         self.data.as_mut_bytes()
@@ -927,12 +1070,31 @@ impl DynamicBufHeap {
         self.data.into_buf()
     }
     pub unsafe fn into_str<'a>(mut self) -> Str<'a> {
+        if self.write_head <= MAX_INLINE_SIZE {
+            // We guessed too high when allocating the buffer; copy the contents inline instead.
+            let len = self.write_head;
+            return Str::from_rep(Inline::from_raw(self.data.as_mut_ptr(), len).into());
+        }
         // Shrink the buffer to fit.
         self.realloc(self.write_head);
         self.data.into_buf().into_str()
     }
     unsafe fn realloc(&mut self, new_cap: usize) {
         let cap = self.size();
+        if (*self.data.0).in_arena() {
+            if new_cap <= cap {
+                (*self.data.0).size = new_cap | ARENA_BIT;
+            } else {
+                let mut new_buf = UniqueBuf::new_transient(new_cap);
+                ptr::copy_nonoverlapping(
+                    self.data.as_mut_ptr(),
+                    new_buf.as_mut_ptr(),
+                    self.write_head,
+                );
+                self.data = new_buf;
+            }
+            return;
+        }
         let new_buf = realloc(
             self.data.0 as *mut u8,
             UniqueBuf::layout(cap),
@@ -1048,7 +1210,16 @@ impl Drop for UniqueBuf {
     fn drop(&mut self) {
         let header: &mut BufHeader = unsafe { &mut (*self.0) };
         debug_assert_eq!(header.count.get(), 1);
-        unsafe { dealloc(self.0 as *mut u8, UniqueBuf::layout(header.size)) }
+        if header.in_arena() {
+            unsafe { record_arena::free(self.0 as *mut u8) }
+        } else if header.is_builder() {
+            unsafe {
+                let base = (self.0 as *mut usize).offset(-1) as *mut u8;
+                dealloc(base, UniqueBuf::builder_layout(header.len()))
+            }
+        } else {
+            unsafe { dealloc(self.0 as *mut u8, UniqueBuf::layout(header.size)) }
+        }
     }
 }
 
@@ -1073,6 +1244,32 @@ impl UniqueBuf {
         )
         .unwrap()
     }
+    fn builder_layout(cap: usize) -> Layout {
+        Layout::from_size_align(
+            cap + mem::size_of::<BufHeader>() + mem::size_of::<usize>(),
+            mem::align_of::<BufHeader>(),
+        )
+        .unwrap()
+    }
+    /// A buffer with room for `cap` bytes that strings can be appended to in place. Builders are
+    /// preceded by the number of bytes that have been written to them so far: a string whose
+    /// bytes end at that point can have more bytes written after it without affecting any other
+    /// string referencing the buffer.
+    fn builder(cap: usize) -> UniqueBuf {
+        unsafe {
+            let base = alloc_zeroed(UniqueBuf::builder_layout(cap)) as *mut usize;
+            assert!(!base.is_null());
+            let alloced = base.offset(1) as *mut BufHeader;
+            ptr::write(
+                alloced,
+                BufHeader {
+                    size: cap | BUILDER_BIT,
+                    count: Cell::new(1),
+                },
+            );
+            UniqueBuf(alloced)
+        }
+    }
     pub fn new(size: usize) -> UniqueBuf {
         let layout = UniqueBuf::layout(size);
         unsafe {
@@ -1088,10 +1285,28 @@ impl UniqueBuf {
             UniqueBuf(alloced)
         }
     }
+    /// Like `new`, but allocates from the record arena if it is active, and does not zero the
+    /// buffer.
+    fn new_transient(size: usize) -> UniqueBuf {
+        match record_arena::try_alloc(UniqueBuf::layout(size)) {
+            Some(p) => unsafe {
+                let alloced = p as *mut BufHeader;
+                ptr::write(
+                    alloced,
+                    BufHeader {
+                        size: size | ARENA_BIT,
+                        count: Cell::new(1),
+                    },
+                );
+                UniqueBuf(alloced)
+            },
+            None => UniqueBuf::new(size),
+        }
+    }
     pub fn as_mut_bytes(&mut self) -> &mut [u8] {
         let header: &BufHeader = unsafe { &(*self.0) };
         debug_assert_eq!(header.count.get(), 1);
-        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), header.size) }
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), header.len()) }
     }
     pub fn as_mut_ptr(&mut self) -> *mut u8 {
         let header: &BufHeader = unsafe { &(*self.0) };
@@ -1117,7 +1332,20 @@ impl Buf {
     }
 
     pub fn len(&self) -> usize {
-        unsafe { &(*self.0) }.size
+        unsafe { &(*self.0) }.len()
+    }
+
+    fn in_arena(&self) -> bool {
+        unsafe { &(*self.0) }.in_arena()
+    }
+
+    /// The number of bytes written to a builder.
+    fn written(&self) -> Option<&Cell<usize>> {
+        if unsafe { &(*self.0) }.is_builder() {
+            Some(unsafe { &*((self.0 as *const Cell<usize>).offset(-1)) })
+        } else {
+            None
+        }
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -1298,6 +1526,62 @@ And this is the second part"#
         });
     }
 
+    #[test]
+    fn short_strings_are_inline() {
+        let is_inline = |s: &Str| unsafe { s.rep().get_tag() } == StrTag::Inline;
+        assert_eq!(mem::size_of::<Str>(), 16);
+        let s: Str = String::from("short string").into();
+        assert!(is_inline(&s));
+        s.with_bytes(|bs| assert_eq!(bs, b"short string"));
+        let s: Str = String::from("a string that needs the heap").into();
+        assert!(!is_inline(&s));
+
+        // Buffers that end up holding fewer bytes than expected are copied inline.
+        let mut d = DynamicBuf::new(64);
+        write!(&mut d, "{}-{}", 1, 2).unwrap();
+        let s = unsafe { d.into_str() };
+        assert!(is_inline(&s));
+        s.with_bytes(|bs| assert_eq!(bs, b"1-2"));
+        assert!(is_inline(&Str::from(1.5)));
+    }
+
+    fn is_builder(s: &Str) -> bool {
+        let rep = unsafe { s.rep_mut() };
+        rep.get_tag() == StrTag::Boxed
+            && unsafe { rep.view_as(|b: &Boxed| b.buf.written().is_some()) }
+    }
+
+    #[test]
+    fn builder_appends() {
+        let chunk = Str::from("0123456789abcdef");
+        let mut s = Str::default();
+        for _ in 0..(MIN_BUILDER_SIZE / 16) {
+            s = Str::concat(s, chunk.clone());
+        }
+        let prefix = Str::concat(s.clone(), chunk.clone());
+        assert!(is_builder(&prefix));
+
+        // Appends write in place, leaving the strings sharing the builder unchanged.
+        let longer = Str::concat(prefix.clone(), Str::from("!"));
+        assert_eq!(longer.len(), MIN_BUILDER_SIZE + 17);
+        assert_eq!(prefix.len(), MIN_BUILDER_SIZE + 16);
+        prefix.with_bytes(|bs| assert!(bs.ends_with(b"cdef")));
+        longer.with_bytes(|bs| assert!(bs.ends_with(b"cdef!")));
+
+        // A second append to the same prefix cannot write in place.
+        let other = Str::concat(prefix.clone(), Str::from("?"));
+        other.with_bytes(|bs| assert!(bs.ends_with(b"cdef?")));
+        longer.with_bytes(|bs| assert!(bs.ends_with(b"cdef!")));
+        assert_eq!(other.slice(0, 16), chunk);
+
+        // Long chains of appends stay flat, without overflowing the stack when dropped.
+        let mut s = prefix;
+        for _ in 0..1_000_000 {
+            s = Str::concat(s, Str::from("x"));
+        }
+        assert_eq!(s.len(), MIN_BUILDER_SIZE + 16 + 1_000_000);
+    }
+
     #[test]
     fn subst() {
         let s1: Str = "String number one".into();
@@ -1441,7 +1725,7 @@ mod formatting {
             write!(
                 f,
                 "Buf {{ size: {}, count: {}, contents: {:?} }}",
-                header.size,
+                header.len(),
                 header.count.get(),
                 self.as_bytes(),
             )
@@ -0,0 +1,438 @@
+//! The hash table underlying each array, with a special case for arrays indexed by small integers.
+//!
+//! Arrays whose keys are small non-negative integers, such as those filled in by `split` or used as
+//! lists or per-column counters, are stored in a `Vec` indexed by key. This is both smaller and
+//! faster than hashing each key. Once a key is added that is negative or would leave the `Vec`
+//! mostly empty, the array switches over to a hash table for good.
+//!
+//! Iterating over an array stored in a `Vec` visits its keys in increasing order.
+//!
+//! Hashed arrays are built directly on hashbrown's `RawTable` so that they can remember where the
+//! last key they looked up lives. Updates like `a[$1]++` look up a key and then store to it
+//! straight away; the store only has to check that it has the same key (usually just a comparison
+//! of the two `Str`s' bits) and can then skip hashing the key and probing the table a second time.
+use super::{Int, Str};
+
+use hashbrown::{
+    hash_map::DefaultHashBuilder,
+    raw::{Bucket, RawIter, RawTable},
+    HashMap,
+};
+use std::cell::Cell;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::Index;
+
+/// A type that can be used as an array key.
+pub(crate) trait MapKey: Hash + Eq + Clone {
+    /// The index of this key in the `Vec` representation, if it can have one.
+    fn dense_index(&self) -> Option<usize> {
+        None
+    }
+    /// The inverse of `dense_index`.
+    fn from_dense_index(_ix: usize) -> Self {
+        unreachable!()
+    }
+    /// The copy of this key to store when it is added to a hashed array.
+    fn interned(self) -> Self {
+        self
+    }
+}
+
+impl MapKey for Int {
+    fn dense_index(&self) -> Option<usize> {
+        if *self >= 0 {
+            Some(*self as usize)
+        } else {
+            None
+        }
+    }
+    fn from_dense_index(ix: usize) -> Int {
+        ix as Int
+    }
+}
+
+impl<'a> MapKey for Str<'a> {
+    fn interned(self) -> Str<'a> {
+        super::intern::intern(self)
+    }
+}
+
+/// How far past the end of the `Vec` (relative to the number of entries) a new key can be before
+/// we switch to a hash table. Keeping this proportional to the number of entries keeps the `Vec`
+/// at least about half full.
+const SLACK: usize = 16;
+
+#[derive(Debug)]
+pub(crate) enum Table<K, V> {
+    Dense { slots: Vec<Option<V>>, len: usize },
+    Hashed(Hashed<K, V>),
+}
+
+/// A hash table along with the location of the most recently looked-up key.
+pub(crate) struct Hashed<K, V> {
+    table: RawTable<(K, V)>,
+    builder: DefaultHashBuilder,
+    last: Cell<Option<Last<K, V>>>,
+}
+
+/// The last key looked up in a `Hashed` table, its hash, and its bucket if it is present.
+///
+/// The key is cloned into `last`, so a table can keep one extra string (and the input buffer that
+/// it points into, for a field) alive. `bucket` points into
+/// `table`, so `last` has to be reset whenever an entry is added or removed.
+struct Last<K, V> {
+    key: K,
+    hash: u64,
+    bucket: Option<Bucket<(K, V)>>,
+}
+
+fn make_hash<K: Hash>(builder: &DefaultHashBuilder, k: &K) -> u64 {
+    let mut state = builder.build_hasher();
+    k.hash(&mut state);
+    state.finish()
+}
+
+impl<K: MapKey, V> Hashed<K, V> {
+    fn with_capacity(cap: usize) -> Hashed<K, V> {
+        Hashed {
+            table: RawTable::with_capacity(cap),
+            builder: Default::default(),
+            last: Cell::new(None),
+        }
+    }
+
+    /// Hash `k` and find its bucket, reusing the previous answer if `k` was the last key we saw.
+    fn lookup(&self, k: &K) -> (u64, Option<Bucket<(K, V)>>) {
+        if let Some(last) = self.last.take() {
+            if &last.key == k {
+                let res = (last.hash, last.bucket.clone());
+                self.last.set(Some(last));
+                return res;
+            }
+        }
+        let hash = make_hash(&self.builder, k);
+        let bucket = self.table.find(hash, |(x, _)| x == k);
+        self.last.set(Some(Last {
+            key: k.clone(),
+            hash,
+            bucket: bucket.clone(),
+        }));
+        (hash, bucket)
+    }
+
+    fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    fn get(&self, k: &K) -> Option<&V> {
+        // Safety: buckets from `lookup` are valid until the next insertion or removal, both of
+        // which require `&mut self`.
+        self.lookup(k).1.map(|bucket| unsafe { &bucket.as_ref().1 })
+    }
+
+    fn insert(&mut self, k: K, v: V) {
+        match self.lookup(&k) {
+            (_, Some(bucket)) => unsafe { bucket.as_mut().1 = v },
+            (hash, None) => {
+                let builder = &self.builder;
+                self.last.set(None);
+                self.table
+                    .insert(hash, (k.interned(), v), |(k, _)| make_hash(builder, k));
+            }
+        }
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        let bucket = self.lookup(k).1?;
+        self.last.set(None);
+        Some(unsafe { self.table.remove(bucket) }.1)
+    }
+
+    fn iter(&self) -> HashedIter<'_, K, V> {
+        HashedIter(unsafe { self.table.iter() }, PhantomData)
+    }
+}
+
+impl<K, V> fmt::Debug for Hashed<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hashed {{ len: {} }}", self.table.len())
+    }
+}
+
+impl<K, V> Default for Table<K, V> {
+    fn default() -> Table<K, V> {
+        Table::Dense {
+            slots: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<K: MapKey, V> From<HashMap<K, V>> for Table<K, V> {
+    fn from(m: HashMap<K, V>) -> Table<K, V> {
+        let mut h = Hashed::with_capacity(m.len());
+        for (k, v) in m {
+            h.insert(k, v);
+        }
+        Table::Hashed(h)
+    }
+}
+
+impl<K: MapKey, V> Table<K, V> {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Table::Dense { len, .. } => *len,
+            Table::Hashed(h) => h.len(),
+        }
+    }
+
+    pub(crate) fn get(&self, k: &K) -> Option<&V> {
+        match self {
+            Table::Dense { slots, .. } => slots.get(k.dense_index()?)?.as_ref(),
+            Table::Hashed(h) => h.get(k),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, k: &K) -> bool {
+        self.get(k).is_some()
+    }
+
+    pub(crate) fn insert(&mut self, k: K, v: V) {
+        if let Table::Dense { slots, len } = self {
+            match k.dense_index() {
+                Some(ix) if ix < slots.len() => {
+                    if slots[ix].is_none() {
+                        *len += 1;
+                    }
+                    slots[ix] = Some(v);
+                    return;
+                }
+                Some(ix) if ix - slots.len() <= *len + SLACK => {
+                    slots.resize_with(ix, || None);
+                    slots.push(Some(v));
+                    *len += 1;
+                    return;
+                }
+                _ => self.promote(),
+            }
+        }
+        if let Table::Hashed(h) = self {
+            h.insert(k, v);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, k: &K) -> Option<V> {
+        match self {
+            Table::Dense { slots, len } => {
+                let ix = k.dense_index()?;
+                let res = slots.get_mut(ix)?.take();
+                if res.is_some() {
+                    *len -= 1;
+                    while let Some(None) = slots.last() {
+                        slots.pop();
+                    }
+                }
+                res
+            }
+            Table::Hashed(h) => h.remove(k),
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        match self {
+            Table::Dense { slots, len } => {
+                slots.clear();
+                *len = 0;
+            }
+            // Start over with a `Vec`: `split` clears its array before filling it in.
+            Table::Hashed(..) => *self = Default::default(),
+        }
+    }
+
+    fn promote(&mut self) {
+        if let Table::Dense { slots, len } = self {
+            let mut h = Hashed::with_capacity(*len + 1);
+            for (ix, v) in std::mem::take(slots).into_iter().enumerate() {
+                if let Some(v) = v {
+                    h.insert(K::from_dense_index(ix), v);
+                }
+            }
+            *self = Table::Hashed(h);
+        }
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_, K, V> {
+        match self {
+            Table::Dense { slots, .. } => Iter::Dense(slots.iter().enumerate()),
+            Table::Hashed(h) => Iter::Hashed(h.iter()),
+        }
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.iter().map(|(k, _)| k)
+    }
+}
+
+impl<'k, K: MapKey, V> Index<&'k K> for Table<K, V> {
+    type Output = V;
+    fn index(&self, k: &'k K) -> &V {
+        self.get(k).expect("key not present in array")
+    }
+}
+
+/// An iterator over the entries of a `Table`. Keys are returned by value, as the `Vec`
+/// representation does not store them.
+pub(crate) enum Iter<'a, K, V> {
+    Dense(std::iter::Enumerate<std::slice::Iter<'a, Option<V>>>),
+    Hashed(HashedIter<'a, K, V>),
+}
+
+pub(crate) struct HashedIter<'a, K, V>(RawIter<(K, V)>, PhantomData<&'a Hashed<K, V>>);
+
+impl<'a, K, V> Iterator for HashedIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        // Safety: the iterator borrows the table, so it cannot be modified underneath us.
+        self.0.next().map(|bucket| {
+            let (k, v) = unsafe { bucket.as_ref() };
+            (k, v)
+        })
+    }
+}
+
+impl<'a, K: MapKey, V> Iterator for Iter<'a, K, V> {
+    type Item = (K, &'a V);
+    fn next(&mut self) -> Option<(K, &'a V)> {
+        match self {
+            Iter::Dense(it) => loop {
+                let (ix, slot) = it.next()?;
+                if let Some(v) = slot {
+                    return Some((K::from_dense_index(ix), v));
+                }
+            },
+            Iter::Hashed(it) => it.next().map(|(k, v)| (k.clone(), v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_dense<K, V>(t: &Table<K, V>) -> bool {
+        matches!(t, Table::Dense { .. })
+    }
+
+    #[test]
+    fn dense_until_sparse() {
+        let mut t = Table::<Int, Int>::default();
+        for i in 1..=100 {
+            t.insert(i, i * 2);
+        }
+        assert!(is_dense(&t));
+        assert_eq!(t.len(), 100);
+        assert_eq!(t.get(&50), Some(&100));
+        assert_eq!(t.get(&0), None);
+        assert_eq!(t.get(&101), None);
+        assert_eq!(t.get(&-1), None);
+        t.insert(50, 7);
+        assert_eq!(t.len(), 100);
+        assert_eq!(t[&50], 7);
+        // Trailing deletions shrink the Vec.
+        assert_eq!(t.remove(&100), Some(200));
+        assert_eq!(t.remove(&100), None);
+        assert_eq!(t.remove(&-5), None);
+        assert_eq!(t.len(), 99);
+        let keys: Vec<_> = t.keys().collect();
+        assert_eq!(keys, (1..=99).collect::<Vec<_>>());
+
+        // A key far past the end switches to hashing, keeping everything that was there.
+        t.insert(1 << 40, 1);
+        assert!(!is_dense(&t));
+        assert_eq!(t.len(), 100);
+        assert_eq!(t.get(&50), Some(&7));
+        assert_eq!(t.get(&(1 << 40)), Some(&1));
+        t.clear();
+        assert!(is_dense(&t));
+        assert_eq!(t.len(), 0);
+
+        t.insert(-1, 1);
+        assert!(!is_dense(&t));
+        assert_eq!(t.get(&-1), Some(&1));
+    }
+
+    #[test]
+    fn string_keys() {
+        let mut t = Table::<Str, Int>::default();
+        t.insert(Str::from("a"), 1);
+        t.insert(Str::from("b"), 2);
+        assert!(!is_dense(&t));
+        assert_eq!(t.get(&Str::from("b")), Some(&2));
+        let mut keys: Vec<_> = t.keys().map(|k| k.to_string()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn memoized_hash() {
+        let mut t = Table::<Str, Int>::default();
+        for round in 0..3 {
+            for k in &["x", "y", "a longer key that is not stored inline"] {
+                let k = Str::from(*k);
+                let v = t.get(&k).cloned().unwrap_or(0);
+                t.insert(k, v + 1);
+            }
+            assert_eq!(t.len(), 3);
+            assert_eq!(t.get(&Str::from("y")), Some(&(round + 1)));
+        }
+        // Switching keys between a lookup and a store must not reuse the stale hash.
+        assert_eq!(t.get(&Str::from("x")), Some(&3));
+        t.insert(Str::from("z"), 10);
+        assert_eq!(t.remove(&Str::from("x")), Some(3));
+        assert_eq!(t.get(&Str::from("x")), None);
+        assert_eq!(t.get(&Str::from("z")), Some(&10));
+        assert_eq!(t.len(), 3);
+    }
+}
+
+#[cfg(all(feature = "unstable", test))]
+mod bench {
+    extern crate test;
+    use super::*;
+    use test::{black_box, Bencher};
+
+    // `N[$1]++` over 1000 lines with 100 distinct keys, where each key is a slice of the input
+    // like a field would be.
+    fn bench_group_by(b: &mut Bencher, prefix: &str) {
+        let mut text = String::new();
+        let mut fields = Vec::new();
+        for i in 0..1000 {
+            let start = text.len();
+            text.push_str(&format!("{}{}", prefix, i * 7919 % 100));
+            fields.push((start, text.len()));
+            text.push_str(&format!("\t{}\n", i));
+        }
+        let input = Str::from(text);
+        let mut t = Table::<Str, Int>::default();
+        b.iter(|| {
+            for (start, end) in fields.iter() {
+                let k = input.slice(*start, *end);
+                let v = t.get(&k).cloned().unwrap_or(0);
+                t.insert(k, v + 1);
+            }
+            black_box(t.len());
+        });
+    }
+
+    #[bench]
+    fn bench_group_by_short_keys(b: &mut Bencher) {
+        bench_group_by(b, "k");
+    }
+
+    #[bench]
+    fn bench_group_by_long_keys(b: &mut Bencher) {
+        bench_group_by(b, "https://www.example.com/a/reasonably/long/path/");
+    }
+}
@@ -0,0 +1,32 @@
+//! Handing a running program from the interpreter to compiled code, for `--tiered`.
+//!
+//! With `--tiered`, frawk starts running a program in the bytecode interpreter while another
+//! thread compiles it. The program is staged as it would be for a parallel run, so that every
+//! global the main loop needs passes through a slot; see `ast::Prog::tiered`. The main loop calls
+//! `tier-up` before reading each record. Once the compiled code is ready, that call returns 1 and
+//! the interpreter stops at the end of the main loop, and the compiled main loop and END carry on
+//! from the slots and input it left behind.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static READY: AtomicBool = AtomicBool::new(false);
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Record that the compiled version of the program is ready to take over.
+pub(crate) fn set_ready() {
+    READY.store(true, Ordering::Release);
+}
+
+/// Whether the main loop should stop so that the compiled code can take over. Once this has
+/// returned true, `stopped` does as well.
+pub(crate) fn tier_up() -> bool {
+    if !READY.load(Ordering::Acquire) {
+        return false;
+    }
+    STOPPED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Whether the interpreter has stopped to hand the program off; it skips END if so.
+pub(crate) fn stopped() -> bool {
+    STOPPED.load(Ordering::Relaxed)
+}
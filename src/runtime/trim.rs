@@ -0,0 +1,183 @@
+//! `trim`, `ltrim`, `rtrim` and `squeeze`.
+//!
+//! All of these return the input unchanged (without copying it) when there is nothing to remove,
+//! and a slice of it when trimming, so that cleaning up strings that are already clean is cheap.
+//! Only `squeeze` ever has to build a new string.
+use super::{chars, str_impl::DynamicBuf, utf8::is_char_boundary, Str};
+
+use std::io::Write;
+
+/// The characters removed by `trim(s)` and friends when no set is given.
+pub(crate) const DEFAULT_SET: &[u8] = b" \t\n\r\x0b\x0c";
+
+fn is_space(b: u8) -> bool {
+    DEFAULT_SET.contains(&b)
+}
+
+/// The length of the character starting at `bs[0]`, following the conventions in `chars`.
+fn char_len(bs: &[u8]) -> usize {
+    1 + bs[1..]
+        .iter()
+        .take_while(|b| !is_char_boundary(**b))
+        .count()
+}
+
+/// The length of the character ending at `bs[bs.len()-1]`.
+fn last_char_len(bs: &[u8]) -> usize {
+    match bs.iter().rposition(|b| is_char_boundary(*b)) {
+        Some(i) => bs.len() - i,
+        None => bs.len(),
+    }
+}
+
+/// The bytes `start..end` of `s` that remain after removing any characters in `set` from the
+/// front (if `left`) and back (if `right`).
+fn trimmed(s: &[u8], set: &[u8], left: bool, right: bool) -> (usize, usize) {
+    let (mut start, mut end) = (0, s.len());
+    if chars::bytes_mode() || set.is_ascii() {
+        let mut table = [false; 256];
+        for b in set {
+            table[*b as usize] = true;
+        }
+        if left {
+            while start < end && table[s[start] as usize] {
+                start += 1;
+            }
+        }
+        if right {
+            while end > start && table[s[end - 1] as usize] {
+                end -= 1;
+            }
+        }
+        return (start, end);
+    }
+    // Collect the characters of the set, which we then compare against whole characters of `s`.
+    let mut members = Vec::new();
+    let mut rest = set;
+    while !rest.is_empty() {
+        let n = char_len(rest);
+        members.push(&rest[..n]);
+        rest = &rest[n..];
+    }
+    if left {
+        while start < end {
+            let n = char_len(&s[start..end]);
+            if !members.contains(&&s[start..start + n]) {
+                break;
+            }
+            start += n;
+        }
+    }
+    if right {
+        while end > start {
+            let n = last_char_len(&s[start..end]);
+            if !members.contains(&&s[end - n..end]) {
+                break;
+            }
+            end -= n;
+        }
+    }
+    (start, end)
+}
+
+/// `trim(s, set)`, `ltrim(s, set)` and `rtrim(s, set)`: remove the characters in `set` from the
+/// start and/or end of `s`.
+pub(crate) fn trim<'a>(s: &Str<'a>, set: &Str, left: bool, right: bool) -> Str<'a> {
+    let (start, end) = s.with_bytes(|bs| set.with_bytes(|set| trimmed(bs, set, left, right)));
+    if start == 0 && end == s.len() {
+        s.clone()
+    } else {
+        s.slice(start, end)
+    }
+}
+
+/// `squeeze(s)`: replace each run of whitespace in `s` with a single space.
+pub(crate) fn squeeze<'a>(s: &Str<'a>) -> Str<'a> {
+    s.with_bytes(|bs| {
+        // Find the first run that is anything other than a lone space.
+        let mut i = 0;
+        let first = loop {
+            if i == bs.len() {
+                return s.clone();
+            }
+            if is_space(bs[i]) {
+                let run = bs[i..].iter().take_while(|b| is_space(**b)).count();
+                if run > 1 || bs[i] != b' ' {
+                    break i;
+                }
+                i += run;
+            } else {
+                i += 1;
+            }
+        };
+        let mut buf = DynamicBuf::new(bs.len());
+        buf.write(&bs[..first]).unwrap();
+        let mut in_run = false;
+        for b in &bs[first..] {
+            if is_space(*b) {
+                if !in_run {
+                    buf.write(b" ").unwrap();
+                    in_run = true;
+                }
+            } else {
+                buf.write(std::slice::from_ref(b)).unwrap();
+                in_run = false;
+            }
+        }
+        unsafe { buf.into_str() }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trim_all(s: &str, set: &str) -> (Str<'static>, Str<'static>, Str<'static>) {
+        let (s, set) = (Str::from(s.to_string()), Str::from(set.to_string()));
+        (
+            trim(&s, &set, true, true),
+            trim(&s, &set, true, false),
+            trim(&s, &set, false, true),
+        )
+    }
+
+    #[test]
+    fn trimming() {
+        let ws = std::str::from_utf8(DEFAULT_SET).unwrap();
+        for (s, set, both, left, right) in &[
+            ("  a b  ", ws, "a b", "a b  ", "  a b"),
+            ("\t\na\r\n", ws, "a", "a\r\n", "\t\na"),
+            ("abc", ws, "abc", "abc", "abc"),
+            ("    ", ws, "", "", ""),
+            ("", ws, "", "", ""),
+            ("xxaxyx", "xy", "a", "axyx", "xxa"),
+            ("«quoted»", "«»", "quoted", "quoted»", "«quoted"),
+            ("éaé", "é", "a", "aé", "éa"),
+            ("éaé", "e", "éaé", "éaé", "éaé"),
+        ] {
+            let (b, l, r) = trim_all(s, set);
+            assert_eq!(b, Str::from(*both), "trim({:?}, {:?})", s, set);
+            assert_eq!(l, Str::from(*left), "ltrim({:?}, {:?})", s, set);
+            assert_eq!(r, Str::from(*right), "rtrim({:?}, {:?})", s, set);
+        }
+    }
+
+    #[test]
+    fn squeezing() {
+        for (s, expected) in &[
+            ("a b c", "a b c"),
+            ("a  b\t\tc", "a b c"),
+            ("  a \n b  ", " a b "),
+            ("a\tb", "a b"),
+            ("", ""),
+            ("\n", " "),
+        ] {
+            assert_eq!(
+                squeeze(&Str::from(s.to_string())),
+                Str::from(*expected),
+                "{:?}",
+                s
+            );
+        }
+    }
+}
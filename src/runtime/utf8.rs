@@ -29,6 +29,10 @@ fn parse_utf8_clipped(bs: &[u8]) -> Option<&str> {
     validate_utf8_clipped(bs).map(|off| unsafe { str::from_utf8_unchecked(&bs[..off]) })
 }
 
+#[cfg_attr(
+    not(any(target_arch = "x86", target_arch = "x86_64")),
+    allow(unused_mut)
+)]
 pub(crate) fn validate_utf8_clipped(mut bs: &[u8]) -> Option<usize> {
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     {
@@ -100,6 +104,10 @@ fn validate_utf8_fallback(bs: &[u8]) -> Option<usize> {
     }
 }
 
+#[cfg_attr(
+    not(any(target_arch = "x86", target_arch = "x86_64")),
+    allow(unused_mut)
+)]
 pub(crate) fn is_utf8(mut bs: &[u8]) -> bool {
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     {
@@ -0,0 +1,129 @@
+//! Vector arithmetic over arrays, for the `vec_*` builtins.
+//!
+//! The vector held in an array `a` is `a[1], ..., a[n]` where `n` is `length(a)`, just like the
+//! arrays that `split` fills in: missing elements are 0, and elements whose keys are outside of
+//! `1..=n` are ignored. Arrays like these are stored densely in a `Vec` (see `table`), and the
+//! builtins copy them into plain `Vec<Float>`s in a single pass before doing any arithmetic; the
+//! arrays they fill in are dense as well.
+use crate::runtime::{Float, Int, IntMap};
+
+fn dense(a: &IntMap<Float>) -> Vec<Float> {
+    let mut res = vec![0.0; a.len()];
+    a.iter(|iter| {
+        for (k, v) in iter {
+            if k >= 1 && k as usize <= res.len() {
+                res[k as usize - 1] = *v;
+            }
+        }
+    });
+    res
+}
+
+/// Replace the contents of `dst` with `v`, and return the length of the vector.
+fn store(dst: &IntMap<Float>, v: Vec<Float>) -> Int {
+    dst.clear();
+    for (i, x) in v.iter().enumerate() {
+        dst.insert(i as Int + 1, *x);
+    }
+    v.len() as Int
+}
+
+/// `vec_dot(a, b)`: the dot product of `a` and `b`. The shorter vector is padded with zeros.
+pub(crate) fn dot(a: &IntMap<Float>, b: &IntMap<Float>) -> Float {
+    let (a, b) = (dense(a), dense(b));
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| x * y)
+        .fold(0.0, |a, b| a + b)
+}
+
+/// `vec_norm(a)`: the Euclidean length of `a`.
+pub(crate) fn norm(a: &IntMap<Float>) -> Float {
+    dense(a)
+        .iter()
+        .map(|x| x * x)
+        .fold(0.0, |a, b| a + b)
+        .sqrt()
+}
+
+/// Set `dst` to `f` applied to the elements of `a` and `b`, padding the shorter vector with
+/// zeros. `dst` may be `a` or `b`.
+fn zip_with(
+    dst: &IntMap<Float>,
+    a: &IntMap<Float>,
+    b: &IntMap<Float>,
+    f: impl Fn(Float, Float) -> Float,
+) -> Int {
+    let (mut a, mut b) = (dense(a), dense(b));
+    let n = std::cmp::max(a.len(), b.len());
+    a.resize(n, 0.0);
+    b.resize(n, 0.0);
+    store(dst, a.into_iter().zip(b).map(|(x, y)| f(x, y)).collect())
+}
+
+/// `vec_add(dst, a, b)`: set `dst` to the elementwise sum of `a` and `b`.
+pub(crate) fn add(dst: &IntMap<Float>, a: &IntMap<Float>, b: &IntMap<Float>) -> Int {
+    zip_with(dst, a, b, |x, y| x + y)
+}
+
+/// `vec_sub(dst, a, b)`: set `dst` to the elementwise difference of `a` and `b`.
+pub(crate) fn sub(dst: &IntMap<Float>, a: &IntMap<Float>, b: &IntMap<Float>) -> Int {
+    zip_with(dst, a, b, |x, y| x - y)
+}
+
+/// `vec_mul(dst, a, b)`: set `dst` to the elementwise product of `a` and `b`.
+pub(crate) fn mul(dst: &IntMap<Float>, a: &IntMap<Float>, b: &IntMap<Float>) -> Int {
+    zip_with(dst, a, b, |x, y| x * y)
+}
+
+/// `vec_scale(dst, a, k)`: set `dst` to `a` multiplied by `k`.
+pub(crate) fn scale(dst: &IntMap<Float>, a: &IntMap<Float>, k: Float) -> Int {
+    store(dst, dense(a).into_iter().map(|x| x * k).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(xs: &[Float]) -> IntMap<Float> {
+        let v = IntMap::default();
+        for (i, x) in xs.iter().enumerate() {
+            v.insert(i as Int + 1, *x);
+        }
+        v
+    }
+
+    #[test]
+    fn products_and_norms() {
+        let (a, b) = (vector(&[1.0, 2.0, 3.0]), vector(&[4.0, -5.0]));
+        assert_eq!(dot(&a, &b), -6.0);
+        assert_eq!(norm(&vector(&[3.0, 4.0])), 5.0);
+        assert_eq!(norm(&IntMap::default()), 0.0);
+
+        // Keys outside of 1..=length(a) are ignored, and missing elements are 0.
+        let c = IntMap::default();
+        c.insert(0, 10.0);
+        c.insert(2, 2.0);
+        assert_eq!(dense(&c), vec![0.0, 2.0]);
+        assert_eq!(dot(&a, &c), 4.0);
+    }
+
+    #[test]
+    fn elementwise() {
+        let (a, b) = (vector(&[1.0, 2.0, 3.0]), vector(&[4.0, -5.0]));
+        let c = vector(&[9.0, 9.0, 9.0, 9.0]);
+        assert_eq!(add(&c, &a, &b), 3);
+        assert_eq!(dense(&c), vec![5.0, -3.0, 3.0]);
+        assert_eq!(sub(&c, &a, &b), 3);
+        assert_eq!(dense(&c), vec![-3.0, 7.0, 3.0]);
+        assert_eq!(mul(&c, &b, &a), 3);
+        assert_eq!(dense(&c), vec![4.0, -10.0, 0.0]);
+        assert_eq!(scale(&c, &b, 0.5), 2);
+        assert_eq!(c.len(), 2);
+        assert_eq!(dense(&c), vec![2.0, -2.5]);
+
+        // The destination may also be an argument.
+        assert_eq!(add(&a, &a, &a), 3);
+        assert_eq!(dense(&a), vec![2.0, 4.0, 6.0]);
+    }
+}
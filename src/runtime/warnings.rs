@@ -0,0 +1,171 @@
+//! Warnings about recoverable problems found while a program runs, enabled with `--warnings`.
+//!
+//! Warnings are written to standard error, either as plain text or as one JSON object per line.
+//! Each kind of warning is reported at most `MAX_PER_KIND` times, followed by a note that the
+//! rest were suppressed, so that a bad input file does not drown out the program's own output.
+//! `--report` counts warnings of each kind, whether or not they are written.
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+/// How warnings are written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Warnings are not reported.
+    Off = 0,
+    /// `frawk: warning: <file>: <message>`
+    Plain = 1,
+    /// `{"level":"warning","kind":...,"file":...,"message":...}`
+    Json = 2,
+}
+
+/// The kinds of problem warned about.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// Output contained bytes that are not valid UTF-8, and they were replaced.
+    InvalidUtf8 = 0,
+    /// A string that does not look like a number was converted to one, under `--strict=warn`.
+    NumericConversion = 1,
+    /// A variable was read before it was assigned, under `--strict=warn`.
+    Uninitialized = 2,
+    /// The last record of an input file was not followed by a record separator.
+    TruncatedRecord = 3,
+    /// An output file or command was written to, but never closed.
+    UnclosedFile = 4,
+    /// A record took longer than `--record-timeout` to process, under `--on-record-timeout=warn`.
+    SlowRecord = 5,
+}
+
+const NUM_KINDS: usize = 6;
+const MAX_PER_KIND: usize = 100;
+
+const KINDS: [Kind; NUM_KINDS] = [
+    Kind::InvalidUtf8,
+    Kind::NumericConversion,
+    Kind::Uninitialized,
+    Kind::TruncatedRecord,
+    Kind::UnclosedFile,
+    Kind::SlowRecord,
+];
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::InvalidUtf8 => "invalid-utf8",
+            Kind::NumericConversion => "numeric-conversion",
+            Kind::Uninitialized => "uninitialized",
+            Kind::TruncatedRecord => "truncated-record",
+            Kind::UnclosedFile => "unclosed-file",
+            Kind::SlowRecord => "slow-record",
+        }
+    }
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(Format::Off as u8);
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static COUNTS: [AtomicUsize; NUM_KINDS] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+pub fn set_format(format: Format) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn format() -> Format {
+    match FORMAT.load(Ordering::Relaxed) {
+        1 => Format::Plain,
+        2 => Format::Json,
+        _ => Format::Off,
+    }
+}
+
+/// Count warnings even if they are not reported, so that `counts` includes them.
+pub fn enable_counting() {
+    COUNTING.store(true, Ordering::Relaxed);
+}
+
+/// Whether warnings are reported or counted. Callers should check this before doing any work to
+/// detect a problem.
+pub(crate) fn enabled() -> bool {
+    FORMAT.load(Ordering::Relaxed) != Format::Off as u8 || COUNTING.load(Ordering::Relaxed)
+}
+
+/// The number of warnings of each kind so far, leaving out kinds with none.
+pub(crate) fn counts() -> Vec<(&'static str, usize)> {
+    KINDS
+        .iter()
+        .map(|kind| (kind.name(), COUNTS[*kind as usize].load(Ordering::Relaxed)))
+        .filter(|(_, n)| *n > 0)
+        .collect()
+}
+
+/// Report a warning of kind `kind`, about the file `file` if there is one.
+pub(crate) fn warn(kind: Kind, file: Option<&str>, msg: impl fmt::Display) {
+    let format = format();
+    if format == Format::Off && !COUNTING.load(Ordering::Relaxed) {
+        return;
+    }
+    let n = COUNTS[kind as usize].fetch_add(1, Ordering::Relaxed);
+    if format == Format::Off || n > MAX_PER_KIND {
+        return;
+    }
+    let msg = if n == MAX_PER_KIND {
+        format!("further {} warnings suppressed", kind.name())
+    } else {
+        msg.to_string()
+    };
+    let line = match format {
+        Format::Json => {
+            let mut line = String::new();
+            line.push_str("{\"level\":\"warning\",\"kind\":");
+            push_json_str(&mut line, kind.name());
+            if let Some(file) = file {
+                line.push_str(",\"file\":");
+                push_json_str(&mut line, file);
+            }
+            line.push_str(",\"message\":");
+            push_json_str(&mut line, &msg);
+            line.push('}');
+            line
+        }
+        _ => match file {
+            Some(file) => format!("frawk: warning: {}: {}", file, msg),
+            None => format!("frawk: warning: {}", msg),
+        },
+    };
+    eprintln_ignore!("{}", line);
+}
+
+pub(crate) fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_escaping() {
+        let mut s = String::new();
+        push_json_str(&mut s, "a \"b\"\\\n\u{1}");
+        assert_eq!(s, r#""a \"b\"\\\n\u0001""#);
+    }
+}
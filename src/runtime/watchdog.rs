@@ -0,0 +1,151 @@
+//! Per-record time limits for `--record-timeout`.
+//!
+//! Each thread running the main loop keeps a copy of the record it is processing and the time at
+//! which it read it. A background thread checks these every so often, and reports any record that
+//! has been processed for longer than the limit. frawk cannot interrupt a record part-way through,
+//! so the report either ends the process (`--on-record-timeout=abort`, the default) or is a
+//! warning after which the record carries on (`--on-record-timeout=warn`). Either way the
+//! offending record is printed, so that a batch job stuck on one malformed line says which one.
+//!
+//! Time is only counted while the main loop runs: a record stops counting when the next one is
+//! read, when the input ends, or when the main loop returns. This is why the limit is only
+//! supported in parallel mode, where the main loop is a function of its own.
+use super::warnings::{self, Kind};
+
+use lazy_static::lazy_static;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The longest record printed in a report; longer records are truncated.
+const MAX_REPORTED_BYTES: usize = 1024;
+
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+static ABORT: AtomicBool = AtomicBool::new(true);
+
+#[derive(Default)]
+struct Record {
+    bytes: Vec<u8>,
+    start: Option<Instant>,
+    warned: bool,
+}
+
+lazy_static! {
+    static ref RECORDS: Mutex<Vec<Arc<Mutex<Record>>>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Mutex<Record>>>> = RefCell::new(None);
+}
+
+/// Whether `--record-timeout` was passed.
+pub(crate) fn enabled() -> bool {
+    TIMEOUT_MS.load(Ordering::Relaxed) != 0
+}
+
+/// Report records that take longer than `timeout` to process, aborting if `abort` is set. This
+/// starts the thread that checks on them.
+pub fn set_record_timeout(timeout: Duration, abort: bool) {
+    let ms = std::cmp::max(timeout.as_millis(), 1) as u64;
+    ABORT.store(abort, Ordering::Relaxed);
+    TIMEOUT_MS.store(ms, Ordering::Relaxed);
+    let interval = Duration::from_millis(std::cmp::min(std::cmp::max(ms / 4, 1), 100));
+    std::thread::Builder::new()
+        .name("frawk-watchdog".into())
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            check(Duration::from_millis(ms));
+        })
+        .expect("failed to start --record-timeout thread");
+}
+
+fn with_current(f: impl FnOnce(&mut Record)) {
+    CURRENT.with(|cur| {
+        let mut cur = cur.borrow_mut();
+        let rec = cur.get_or_insert_with(|| {
+            let rec = Arc::new(Mutex::new(Record::default()));
+            RECORDS.lock().unwrap().push(rec.clone());
+            rec
+        });
+        f(&mut rec.lock().unwrap());
+    })
+}
+
+/// Start timing `record`, which the current thread has just read from the main input.
+pub(crate) fn start_record(record: &[u8]) {
+    with_current(|rec| {
+        rec.bytes.clear();
+        rec.bytes.extend_from_slice(record);
+        rec.start = Some(Instant::now());
+        rec.warned = false;
+    })
+}
+
+/// Stop timing the record the current thread is processing, if any.
+pub(crate) fn end_record() {
+    if enabled() {
+        with_current(|rec| rec.start = None)
+    }
+}
+
+fn check(timeout: Duration) {
+    for rec in RECORDS.lock().unwrap().iter() {
+        let mut rec = rec.lock().unwrap();
+        let elapsed = match rec.start {
+            Some(start) if !rec.warned => start.elapsed(),
+            _ => continue,
+        };
+        if elapsed < timeout {
+            continue;
+        }
+        let shown = &rec.bytes[..std::cmp::min(rec.bytes.len(), MAX_REPORTED_BYTES)];
+        let msg = format!(
+            "record took longer than {:?} to process{}: {}{}",
+            timeout,
+            if ABORT.load(Ordering::Relaxed) {
+                ""
+            } else {
+                " (continuing)"
+            },
+            String::from_utf8_lossy(shown),
+            if shown.len() < rec.bytes.len() {
+                "..."
+            } else {
+                ""
+            },
+        );
+        if ABORT.load(Ordering::Relaxed) {
+            eprintln_ignore!("frawk: {}", msg);
+            std::process::exit(2);
+        }
+        // These are always reported; `--warnings` only picks the format.
+        if warnings::enabled() {
+            warnings::warn(Kind::SlowRecord, None, msg);
+        } else {
+            eprintln_ignore!("frawk: warning: {}", msg);
+        }
+        rec.warned = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_records_are_reported_once() {
+        TIMEOUT_MS.store(1, Ordering::Relaxed);
+        ABORT.store(false, Ordering::Relaxed);
+        start_record(b"slow");
+        std::thread::sleep(Duration::from_millis(5));
+        check(Duration::from_millis(1));
+        with_current(|rec| assert!(rec.warned && rec.bytes == b"slow"));
+        // A record that has ended is not reported.
+        start_record(b"fast");
+        end_record();
+        check(Duration::from_millis(1));
+        with_current(|rec| assert!(!rec.warned && rec.start.is_none()));
+        TIMEOUT_MS.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,136 @@
+//! Sliding windows over a stream of numbers, for the `win_*` builtins.
+//!
+//! A window is an ordinary frawk array. The `i`th value pushed onto it is stored under the key `i`
+//! until it falls out of the window, and the negative keys hold the running totals that let each
+//! operation run in constant (amortized) time:
+//!
+//! * The number of values pushed so far, and the number still in the window.
+//! * The sum of the values in the window. Adding and subtracting values as they come and go
+//!   accumulates rounding error, so the sum is recomputed from scratch every time the window has
+//!   been replaced entirely.
+//! * A queue of the keys of the values that are larger than every value pushed after them, whose
+//!   first element is the key of the largest value in the window. The queue is stored under keys
+//!   starting at `QUEUE`, between the positions stored under `HEAD` and `TAIL`.
+use crate::common::Result;
+use crate::runtime::{Float, Int, IntMap};
+
+const PUSHED: Int = -1;
+const LEN: Int = -2;
+const SUM: Int = -3;
+const HEAD: Int = -4;
+const TAIL: Int = -5;
+const QUEUE: Int = Int::min_value() / 2;
+
+fn get(w: &IntMap<Float>, k: Int) -> Float {
+    w.get(&k).unwrap_or(0.0)
+}
+
+fn get_int(w: &IntMap<Float>, k: Int) -> Int {
+    get(w, k) as Int
+}
+
+/// `win_push(w, x, n)`: push `x` onto the window `w`, dropping its oldest values until it holds
+/// at most `n` of them. Returns the number of values in the window.
+pub(crate) fn push(w: &IntMap<Float>, x: Float, n: Int) -> Result<Int> {
+    if n <= 0 {
+        return err!("win_push: invalid window size {}", n);
+    }
+    let pushed = get_int(w, PUSHED) + 1;
+    let mut len = get_int(w, LEN) + 1;
+    let mut sum = get(w, SUM) + x;
+    w.insert(pushed, x);
+
+    // Drop the values in the queue that `x` is at least as large as, then add `x`.
+    let head = get_int(w, HEAD);
+    let mut tail = get_int(w, TAIL);
+    while tail > head && get(w, get_int(w, QUEUE + tail - 1)) <= x {
+        tail -= 1;
+        w.delete(&(QUEUE + tail));
+    }
+    w.insert(QUEUE + tail, pushed as Float);
+    tail += 1;
+    let mut head = head;
+
+    while len > n {
+        let oldest = pushed - len + 1;
+        sum -= get(w, oldest);
+        w.delete(&oldest);
+        if get_int(w, QUEUE + head) == oldest {
+            w.delete(&(QUEUE + head));
+            head += 1;
+        }
+        len -= 1;
+    }
+    if pushed % n == 0 {
+        sum = (pushed - len + 1..=pushed).map(|i| get(w, i)).sum();
+    }
+
+    w.insert(PUSHED, pushed as Float);
+    w.insert(LEN, len as Float);
+    w.insert(SUM, sum);
+    w.insert(HEAD, head as Float);
+    w.insert(TAIL, tail as Float);
+    Ok(len)
+}
+
+/// `win_sum(w)`: the sum of the values in the window `w`.
+pub(crate) fn sum(w: &IntMap<Float>) -> Float {
+    get(w, SUM)
+}
+
+/// `win_avg(w)`: the mean of the values in the window `w`, or 0 if it is empty.
+pub(crate) fn avg(w: &IntMap<Float>) -> Float {
+    let len = get(w, LEN);
+    if len == 0.0 {
+        0.0
+    } else {
+        get(w, SUM) / len
+    }
+}
+
+/// `win_max(w)`: the largest value in the window `w`, or 0 if it is empty.
+pub(crate) fn max(w: &IntMap<Float>) -> Float {
+    if get_int(w, TAIL) == get_int(w, HEAD) {
+        return 0.0;
+    }
+    get(w, get_int(w, QUEUE + get_int(w, HEAD)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window() {
+        let w = IntMap::default();
+        assert_eq!((sum(&w), avg(&w), max(&w)), (0.0, 0.0, 0.0));
+        assert!(push(&w, 1.0, 0).is_err());
+        let xs = [
+            3.0, 1.0, 4.0, 1.0, 5.0, 9.0, 2.0, 6.0, 5.0, 3.0, -5.0, -8.0, -9.0, -7.0,
+        ];
+        for (i, x) in xs.iter().enumerate() {
+            let len = push(&w, *x, 3).unwrap();
+            let window = &xs[i.saturating_sub(2)..=i];
+            assert_eq!(len as usize, window.len());
+            assert_eq!(sum(&w), window.iter().sum::<f64>());
+            assert_eq!(avg(&w), window.iter().sum::<f64>() / window.len() as f64);
+            assert_eq!(max(&w), window.iter().cloned().fold(f64::MIN, f64::max));
+        }
+        // Old values and queue entries are dropped as the window slides.
+        assert!(w.len() <= 3 + 3 + 5);
+
+        // Shrinking the window drops the oldest values.
+        assert_eq!(push(&w, 0.0, 1).unwrap(), 1);
+        assert_eq!((sum(&w), max(&w)), (0.0, 0.0));
+    }
+
+    #[test]
+    fn sum_does_not_drift() {
+        let w = IntMap::default();
+        push(&w, 1e16, 2).unwrap();
+        for _ in 0..10 {
+            push(&w, 1.0, 2).unwrap();
+        }
+        assert_eq!(sum(&w), 2.0);
+    }
+}
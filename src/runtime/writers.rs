@@ -20,6 +20,12 @@
 //! of open output files. In that case, we could replace each of these background threads with a
 //! "task" a la futures/async.)
 //!
+//! Programs that write to more files than the process can have open at once (e.g. one file per key
+//! in their input) would otherwise fail with "too many open files". The threads writing to named
+//! files therefore share an `OpenFiles` set bounding the number of files they keep open. Once the
+//! limit is reached, opening another file closes the one that was least recently written to; that
+//! file is reopened in append mode the next time it is written to.
+//!
 //! Within a client, we batch writes similar to how a BufWriter would: copy incoming writes to a
 //! local vector until we have buffered up to a given threshold. Once that threshold is reached, we
 //! then send a reference to buffer on the channel to the receiving thread. While this write is
@@ -36,7 +42,7 @@
 //! writes all data in memory.
 
 use std::collections::VecDeque;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
 use std::process::ChildStdin;
 use std::sync::{
     atomic::{AtomicBool, AtomicUsize, Ordering},
@@ -57,14 +63,163 @@ use crossbeam_channel::{bounded, Receiver, Sender};
 use hashbrown::HashMap;
 
 use crate::common::{CompileError, FileSpec, Notification, Result};
-use crate::runtime::{command::command_for_write, Str};
+use crate::runtime::{command::command_for_write, warnings, Str};
 
 /// The maximum number of pending requests in the per-file channels.
 const IO_CHAN_SIZE: usize = 16;
 
-/// The size of client-side batches.
+/// The default size of client-side batches.
 const BUFFER_SIZE: usize = 4 << 10;
 
+/// The default size of client-side batches for named files. These are larger than those for
+/// stdout and commands (where output is often consumed incrementally) to cut down on the number of
+/// writes, and reopens, issued for each file.
+const FILE_BUFFER_SIZE: usize = 32 << 10;
+
+static STDOUT_BUFFER_SIZE: AtomicUsize = AtomicUsize::new(BUFFER_SIZE);
+static FILE_BUFFER: AtomicUsize = AtomicUsize::new(FILE_BUFFER_SIZE);
+
+/// Set the size of client-side batches for stdout and commands. This must be called before any
+/// output is written.
+pub fn set_stdout_buffer_size(n: usize) {
+    STDOUT_BUFFER_SIZE.store(n, Ordering::Relaxed);
+}
+
+/// Set the size of client-side batches for named files. This must be called before any output is
+/// written.
+pub fn set_file_buffer_size(n: usize) {
+    FILE_BUFFER.store(n, Ordering::Relaxed);
+}
+
+/// When buffered output is handed off to be written.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Buffering {
+    /// Write each line as soon as it is complete.
+    Line = 1,
+    /// Write output once a full buffer of it has accumulated.
+    Block = 2,
+    /// Write output as soon as it is printed.
+    Immediate = 3,
+}
+
+/// The buffering policy for all output; zero means stdout (and commands) are line-buffered if
+/// stdout is a terminal, and everything else is block-buffered.
+static BUFFERING: AtomicUsize = AtomicUsize::new(0);
+
+/// Use `b` for all output, rather than picking a policy based on whether stdout is a terminal.
+/// This must be called before any output is written.
+pub fn set_buffering(b: Buffering) {
+    BUFFERING.store(b as usize, Ordering::Relaxed);
+}
+
+fn buffering(is_stdout: bool) -> Buffering {
+    match BUFFERING.load(Ordering::Relaxed) {
+        1 => Buffering::Line,
+        2 => Buffering::Block,
+        3 => Buffering::Immediate,
+        _ if is_stdout && io::stdout().is_terminal() => Buffering::Line,
+        _ => Buffering::Block,
+    }
+}
+
+/// Set once a write to stdout fails because whatever was reading it has gone away, as in
+/// `frawk '{ print $1 }' big.csv | head`.
+static STDOUT_CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// Whether to run the END block once stdout has been closed, rather than exiting right away.
+static END_ON_BROKEN_PIPE: AtomicBool = AtomicBool::new(false);
+
+/// Run the END block, discarding anything it prints to stdout, when the reader of stdout goes
+/// away. By default frawk exits (successfully) as soon as it notices. This must be called before
+/// any output is written.
+pub fn set_end_on_broken_pipe(end: bool) {
+    END_ON_BROKEN_PIPE.store(end, Ordering::Relaxed);
+}
+
+pub(crate) fn end_on_broken_pipe() -> bool {
+    END_ON_BROKEN_PIPE.load(Ordering::Relaxed)
+}
+
+/// Whether the reader of stdout has gone away. Once it has, there is no point in reading any more
+/// input.
+pub(crate) fn stdout_closed() -> bool {
+    STDOUT_CLOSED.load(Ordering::Relaxed)
+}
+
+/// Whether to skip the END block because stdout has been closed.
+pub(crate) fn skip_end() -> bool {
+    stdout_closed() && !end_on_broken_pipe()
+}
+
+/// Stdout wraps the writer for standard output, recording when writes to it fail with EPIPE.
+struct Stdout<W>(W);
+
+impl<W> Stdout<W> {
+    fn note_error(e: io::Error) -> io::Error {
+        if e.kind() == io::ErrorKind::BrokenPipe {
+            STDOUT_CLOSED.store(true, Ordering::Relaxed);
+        }
+        e
+    }
+}
+
+impl<W: io::Write> io::Write for Stdout<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf).map_err(Self::note_error)
+    }
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        self.0.write_vectored(bufs).map_err(Self::note_error)
+    }
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf).map_err(Self::note_error)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush().map_err(Self::note_error)
+    }
+}
+
+/// The stack size for writer threads, which do very little besides issuing writes. Keeping this
+/// small matters for programs writing to thousands of files.
+const WRITER_STACK_SIZE: usize = 256 << 10;
+
+/// The maximum number of output files to keep open at once. Zero means "pick a limit based on the
+/// process's limit on open file descriptors".
+static MAX_OPEN_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the maximum number of output files to keep open at once. This must be called before any
+/// output is written.
+pub fn set_max_open_files(n: usize) {
+    MAX_OPEN_FILES.store(n, Ordering::Relaxed);
+}
+
+fn max_open_files() -> usize {
+    match MAX_OPEN_FILES.load(Ordering::Relaxed) {
+        0 => default_max_open_files(),
+        n => n,
+    }
+}
+
+#[cfg(unix)]
+fn default_max_open_files() -> usize {
+    // Leave some descriptors free for input files, commands, and the like.
+    const RESERVED: u64 = 64;
+    const MAX: u64 = 1 << 16;
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return 1 << 10;
+    }
+    let cur = (limit.rlim_cur as u64).min(MAX);
+    cur.saturating_sub(RESERVED).max(16) as usize
+}
+
+#[cfg(not(unix))]
+fn default_max_open_files() -> usize {
+    1 << 10
+}
+
 /// FileFactory abstracts over the portions of the file system used for the output of a frawk
 /// program. It includes "file objects" as well as "stdout", which both implement the io::Write
 /// trait.
@@ -98,11 +253,70 @@ impl<W: io::Write, T: Fn(&str, FileSpec) -> io::Result<W> + Clone + 'static + Se
 
 type FileWriter = std::fs::File;
 
+/// The file descriptor named by `path`, if it is one of /dev/stdout, /dev/stderr, or /dev/fd/N.
+#[cfg(unix)]
+fn special_fd(path: &str) -> Option<i32> {
+    match path {
+        "/dev/stdout" => Some(1),
+        "/dev/stderr" => Some(2),
+        _ => path.strip_prefix("/dev/fd/")?.parse().ok(),
+    }
+}
+
+/// A copy of the standard output or error handle named by `path`, or the null device, if `path`
+/// is one of /dev/stdout, /dev/stderr or /dev/null. Windows has no such files, but programs
+/// written for Unix use these names.
+#[cfg(windows)]
+fn special_file(path: &str) -> Option<io::Result<FileWriter>> {
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+    let handle = match path {
+        "/dev/stdout" => io::stdout().as_raw_handle(),
+        "/dev/stderr" => io::stderr().as_raw_handle(),
+        "/dev/null" => return Some(std::fs::OpenOptions::new().write(true).open("NUL")),
+        _ => return None,
+    };
+    // The handle belongs to the standard library; only the duplicate is ever closed.
+    let file = std::mem::ManuallyDrop::new(unsafe { FileWriter::from_raw_handle(handle) });
+    Some(file.try_clone())
+}
+
+/// Whether `path` names one of the streams the process inherited, rather than a file.
+fn is_inherited(path: &str) -> bool {
+    #[cfg(unix)]
+    {
+        special_fd(path).is_some()
+    }
+    #[cfg(not(unix))]
+    {
+        path == "/dev/stdout" || path == "/dev/stderr"
+    }
+}
+
 fn open_file(path: &str, spec: FileSpec) -> io::Result<FileWriter> {
+    // Writes to /dev/stderr and friends go to the descriptor we inherited, as in other Awks.
+    // Opening the device afresh would truncate (or write over) a file it was redirected to.
+    #[cfg(unix)]
+    {
+        if let Some(fd) = special_fd(path) {
+            use std::os::unix::io::FromRawFd;
+            let new_fd = unsafe { libc::dup(fd) };
+            if new_fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(unsafe { FileWriter::from_raw_fd(new_fd) });
+        }
+    }
+    #[cfg(windows)]
+    {
+        if let Some(file) = special_file(path) {
+            return file;
+        }
+    }
     let file = std::fs::OpenOptions::new()
         .write(true)
         .create(true)
         .append(matches!(spec, FileSpec::Append))
+        .truncate(!matches!(spec, FileSpec::Append))
         .open(path)?;
     Ok(file)
 }
@@ -133,15 +347,96 @@ pub fn factory_from_file(fname: &str) -> io::Result<impl FileFactory> {
 fn build_handle<W: io::Write, F: Fn(FileSpec) -> io::Result<W> + Send + 'static>(
     f: F,
     is_stdout: bool,
+    open_files: Option<&Arc<OpenFiles>>,
 ) -> RawHandle {
     let (sender, receiver) = bounded(IO_CHAN_SIZE);
     let error = Arc::new(Mutex::new(None));
     let receiver_error = error.clone();
-    std::thread::spawn(move || receive_thread(receiver, receiver_error, f));
+    let tracker = open_files.map(|files| OpenFile {
+        id: files.next_id.fetch_add(1, Ordering::Relaxed),
+        files: files.clone(),
+        sender: sender.clone(),
+    });
+    std::thread::Builder::new()
+        .stack_size(WRITER_STACK_SIZE)
+        .spawn(move || receive_thread(receiver, receiver_error, f, tracker))
+        .expect("failed to spawn writer thread");
     RawHandle {
         error,
         sender,
-        line_buffer: is_stdout && grep_cli::is_tty_stdout(),
+        buffering: buffering(is_stdout),
+        buffer_size: if open_files.is_some() {
+            FILE_BUFFER.load(Ordering::Relaxed)
+        } else {
+            STDOUT_BUFFER_SIZE.load(Ordering::Relaxed)
+        },
+    }
+}
+
+/// OpenFiles bounds the number of named output files that are open at once, closing the least
+/// recently written-to file when opening a new one would exceed the limit.
+struct OpenFiles {
+    max_open: usize,
+    next_id: AtomicUsize,
+    state: Mutex<OpenFilesState>,
+}
+
+#[derive(Default)]
+struct OpenFilesState {
+    clock: u64,
+    // The time of the last write to each open file, along with a channel to its writer thread.
+    open: HashMap<usize, (u64, Sender<Request>)>,
+}
+
+impl OpenFiles {
+    fn new(max_open: usize) -> OpenFiles {
+        OpenFiles {
+            max_open: max_open.max(1),
+            next_id: AtomicUsize::new(0),
+            state: Default::default(),
+        }
+    }
+}
+
+/// The state a writer thread uses to register its file with an `OpenFiles` set.
+struct OpenFile {
+    id: usize,
+    files: Arc<OpenFiles>,
+    sender: Sender<Request>,
+}
+
+impl OpenFile {
+    /// Record that this file is open and has just been written to. If that puts us over the limit
+    /// of open files, ask the thread for the least recently used file to close it.
+    fn touch(&self) {
+        let victim = {
+            let mut state = self.files.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some(entry) = state.open.get_mut(&self.id) {
+                entry.0 = clock;
+                return;
+            }
+            state.open.insert(self.id, (clock, self.sender.clone()));
+            if state.open.len() <= self.files.max_open {
+                return;
+            }
+            let victim = *state
+                .open
+                .iter()
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .unwrap()
+                .0;
+            state.open.remove(&victim).unwrap().1
+        };
+        // Send the request without holding the lock: the victim's thread may need it to make
+        // progress.
+        let _ = victim.send(Request::Evict);
+    }
+
+    /// Record that this file has been closed.
+    fn release(&self) {
+        self.files.state.lock().unwrap().open.remove(&self.id);
     }
 }
 
@@ -158,7 +453,11 @@ pub struct Registry {
 
 impl Registry {
     pub fn from_factory(f: impl FileFactory) -> Registry {
-        let root_impl = RootImpl::from_factory(f);
+        Registry::with_max_open_files(f, max_open_files())
+    }
+
+    fn with_max_open_files(f: impl FileFactory, max_open: usize) -> Registry {
+        let root_impl = RootImpl::from_factory(f, max_open);
         let stdout = root_impl.get_stdout().into_handle();
         Registry {
             global: Arc::new(root_impl),
@@ -233,6 +532,9 @@ impl Registry {
     }
 
     pub fn destroy_and_flush_all_files(&mut self) -> Result<()> {
+        if warnings::enabled() {
+            self.warn_unclosed();
+        }
         let mut last_error = Ok(());
         for (_, mut fh) in self.files.drain().chain(self.cmds.drain()) {
             let res = fh.flush();
@@ -242,6 +544,22 @@ impl Registry {
         }
         last_error
     }
+
+    fn warn_unclosed(&self) {
+        let kind = warnings::Kind::UnclosedFile;
+        for (name, fh) in self.files.iter() {
+            let name = name.to_string();
+            if fh.written && !is_inherited(&name) {
+                warnings::warn(kind, Some(&name), "output file was not closed");
+            }
+        }
+        for (cmd, fh) in self.cmds.iter() {
+            if fh.written {
+                let cmd = cmd.to_string();
+                warnings::warn(kind, Some(&cmd), "output command was not closed");
+            }
+        }
+    }
 }
 
 impl Clone for Registry {
@@ -267,20 +585,23 @@ trait Root: 'static + Send + Sync {
 
 struct RootImpl<F> {
     handles: Mutex<HashMap<String, RawHandle>>,
+    open_files: Arc<OpenFiles>,
     commands: Mutex<HashMap<Box<[u8]>, RawHandle>>,
     stdout_raw: RawHandle,
     file_factory: F,
 }
 
 impl<F: FileFactory> RootImpl<F> {
-    fn from_factory(file_factory: F) -> RootImpl<F> {
+    fn from_factory(file_factory: F, max_open: usize) -> RootImpl<F> {
         let local_factory = file_factory.clone();
         let stdout_raw = build_handle(
-            move |_append| Ok(local_factory.stdout()),
+            move |_append| Ok(Stdout(local_factory.stdout())),
             /*is_stdout*/ true,
+            /*open_files*/ None,
         );
         RootImpl {
             handles: Default::default(),
+            open_files: Arc::new(OpenFiles::new(max_open)),
             commands: Default::default(),
             stdout_raw,
             file_factory,
@@ -332,6 +653,7 @@ impl<F: FileFactory> Root for RootImpl<F> {
         let handle = build_handle(
             move |_| local_factory.cmd(&*local_name),
             /*is_stdout=*/ true,
+            /*open_files=*/ None,
         );
         let _old = cmds.insert(global_name, handle.clone());
         debug_assert!(
@@ -352,6 +674,7 @@ impl<F: FileFactory> Root for RootImpl<F> {
         let handle = build_handle(
             move |append| local_factory.build(local_name.as_str(), append),
             /*is_stdout=*/ false,
+            Some(&self.open_files),
         );
         handles.insert(global_name, handle.clone());
         handle
@@ -374,6 +697,8 @@ pub struct FileHandle {
     old_guards: Vec<Box<WriteGuard>>,
     guards: VecDeque<Box<WriteGuard>>,
     cur_batch: Box<WriteGuard>,
+    // Whether anything has been written since the file was opened or last closed.
+    written: bool,
 }
 
 impl FileHandle {
@@ -419,10 +744,10 @@ impl FileHandle {
         if let Ok(lock) = self.raw.error.lock() {
             match &*lock {
                 Some(err) => err.clone(),
-                None => CompileError(BAD_SHUTDOWN_MSG.into()),
+                None => CompileError::new(BAD_SHUTDOWN_MSG),
             }
         } else {
-            CompileError(BAD_SHUTDOWN_MSG.into())
+            CompileError::new(BAD_SHUTDOWN_MSG)
         }
     }
 
@@ -449,18 +774,25 @@ impl FileHandle {
         let cur_len = self.cur_batch.data.len();
         let mut added_bytes = 0;
         let mut last_line = None;
+        let line_buffer = self.raw.buffering == Buffering::Line;
         for s in ss.iter() {
             let bs = unsafe { &*s.get_bytes() };
             self.cur_batch.extend(&*bs, spec);
-            if self.raw.line_buffer {
-                if let Some(ix) = memchr::memchr(b'\n', bs) {
+            if line_buffer {
+                if let Some(ix) = memchr::memrchr(b'\n', bs) {
                     // +1 to include the newline
                     last_line = Some(cur_len + added_bytes + ix + 1);
                 }
             }
             added_bytes += bs.len();
         }
-        if (self.raw.line_buffer && last_line.is_some()) || (added_bytes + cur_len > BUFFER_SIZE) {
+        super::stats::wrote(added_bytes);
+        self.written = true;
+        if self.raw.buffering == Buffering::Immediate {
+            self.clear_batch(Some(cur_len + added_bytes))?;
+        } else if (line_buffer && last_line.is_some())
+            || (added_bytes + cur_len > self.raw.buffer_size)
+        {
             self.clear_batch(last_line)?;
         }
         Ok(())
@@ -470,11 +802,14 @@ impl FileHandle {
     }
 
     pub fn flush(&mut self) -> Result<()> {
-        self.clear_batch(None)?;
+        // Wait for the flush even if an earlier write failed: requests still in flight point into
+        // `guards`, so those cannot be freed until the writer thread is done with them.
+        let res = self.clear_batch(None);
         let (n, req) = Request::flush();
         self.raw.sender.send(req).unwrap();
         n.1.wait();
         self.guards.clear();
+        res?;
         if let RequestStatus::ERROR = n.0.read() {
             Err(self.read_error())
         } else {
@@ -483,9 +818,11 @@ impl FileHandle {
     }
 
     pub fn close(&mut self) -> Result<()> {
+        self.written = false;
         self.clear_batch(None)?;
         self.raw.sender.send(Request::Close).unwrap();
-        Ok(())
+        // Wait for the close to go through, so that the program can read back what it wrote.
+        self.flush()
     }
 }
 
@@ -538,7 +875,7 @@ impl ErrorCode {
 enum Request {
     // Because frawk has no separate call for opening an output file, we pass `append` along with
     // write requests. The append value does nothing unless this is the first write received (after
-    // a close).
+    // a close), and only the first open of a file in a given run can truncate it.
     Write {
         data: *const [u8],
         status: *const ErrorCode,
@@ -547,6 +884,9 @@ enum Request {
     },
     Flush(Arc<(ErrorCode, Notification)>),
     Close,
+    // Close the file to make room for another one. Unlike Close, this is not visible to the
+    // program.
+    Evict,
 }
 
 // This isn't implemented automatically because of the raw pointers in Write. Those pointers are
@@ -566,7 +906,7 @@ impl Request {
             // length here should _always_ be safe. This is tracked by the {const_}slice_ptr_len
             // feature.
             Request::Write { data, .. } => unsafe { &**data }.len(),
-            Request::Flush(_) | Request::Close => 0,
+            Request::Flush(_) | Request::Close | Request::Evict => 0,
         }
     }
     fn set_code(&self, mut f: impl FnMut(&ErrorCode)) {
@@ -576,7 +916,7 @@ impl Request {
                 f(&n.0);
                 n.1.notify();
             }
-            Request::Close => {}
+            Request::Close | Request::Evict => {}
         }
     }
 }
@@ -592,7 +932,7 @@ impl Drop for Request {
             Request::Flush(n) => {
                 assert!(n.1.has_been_notified());
             }
-            Request::Close => {}
+            Request::Close | Request::Evict => {}
         }
     }
 }
@@ -607,12 +947,17 @@ struct WriteGuard {
 
 impl WriteGuard {
     fn extend(&mut self, bs: &[u8], spec: FileSpec) {
+        // If the file is closed, the first write in the batch is the one that reopens it, so its
+        // spec decides whether the file is truncated.
+        if self.data.is_empty() {
+            self.spec = spec;
+        }
         self.data.extend(bs);
-        self.spec = spec;
     }
 
     fn peel(&mut self, bytes: usize, next: &mut WriteGuard) {
         if bytes < self.data.len() {
+            next.spec = self.spec;
             next.data.extend(self.data[bytes..].iter().cloned());
             self.data.truncate(bytes);
         }
@@ -649,7 +994,8 @@ impl Drop for WriteGuard {
 struct RawHandle {
     error: Arc<Mutex<Option<CompileError>>>,
     sender: Sender<Request>,
-    line_buffer: bool,
+    buffering: Buffering,
+    buffer_size: usize,
 }
 
 impl RawHandle {
@@ -659,6 +1005,7 @@ impl RawHandle {
             raw: self,
             guards: Default::default(),
             old_guards: Default::default(),
+            written: false,
         }
     }
 }
@@ -729,7 +1076,7 @@ impl WriteBatch {
                 self.flush |= *flush;
             }
             Request::Flush(_) => self.flush = true,
-            Request::Close => self.close = true,
+            Request::Close | Request::Evict => self.close = true,
         };
         self.requests.push(req);
         self.flush || self.close
@@ -756,13 +1103,18 @@ fn receive_thread<W: io::Write>(
     receiver: Receiver<Request>,
     error: Arc<Mutex<Option<CompileError>>>,
     f: impl Fn(FileSpec) -> io::Result<W>,
+    open_file: Option<OpenFile>,
 ) {
     let mut batch = WriteBatch::default();
-    if let Err(e) = receive_loop(&receiver, &mut batch, f) {
+    let res = receive_loop(&receiver, &mut batch, f, open_file.as_ref());
+    if let Some(open_file) = &open_file {
+        open_file.release();
+    }
+    if let Err(e) = res {
         // We got an error! install it in the `error` mutex.
         {
             let mut err = error.lock().unwrap();
-            *err = Some(CompileError(format!("{}", e)));
+            *err = Some(CompileError::new(e.to_string()));
         }
         // Now signal an error on any pending requests.
         batch.clear_error();
@@ -777,12 +1129,16 @@ fn receive_loop<W: io::Write>(
     receiver: &Receiver<Request>,
     batch: &mut WriteBatch,
     f: impl Fn(FileSpec) -> io::Result<W>,
+    open_file: Option<&OpenFile>,
 ) -> io::Result<()> {
     const MAX_BATCH_BYTES: usize = 1 << 20;
     const MAX_BATCH_SIZE: usize = 1 << 10;
 
     // Writer starts off closed. We use `f` to open it if a write appears.
     let mut writer = None;
+    // Whether we have opened the file before. As in Awk, `print > file` only truncates `file` the
+    // first time it is opened; if it is closed and written to again, we append to it.
+    let mut opened = false;
 
     while let Ok(req) = receiver.recv() {
         // We build up a reasonably-sized batch of writes in the channel if it contains pending
@@ -815,10 +1171,22 @@ fn receive_loop<W: io::Write>(
             }
             // We need to (re)open the file, the first write request will tell us whether or not
             // this is an append request.
-            writer = Some(f(batch.get_spec())?);
+            let spec = if opened {
+                FileSpec::Append
+            } else {
+                batch.get_spec()
+            };
+            writer = Some(f(spec)?);
+            opened = true;
+        }
+        if let Some(open_file) = open_file {
+            open_file.touch();
         }
         if batch.issue(writer.as_mut().unwrap())? {
             writer = None;
+            if let Some(open_file) = open_file {
+                open_file.release();
+            }
         }
     }
     Ok(())
@@ -965,6 +1333,7 @@ mod tests {
             handle.write(&s2, FileSpec::Append).unwrap();
         }
         {
+            // Reopening a file after closing it appends to it, even for a truncating write.
             let handle = reg.get_handle(Some(&fname), FileSpec::default()).unwrap();
             handle.close().unwrap();
             handle.write(&s1, FileSpec::Trunc).unwrap();
@@ -972,7 +1341,32 @@ mod tests {
             handle.flush().unwrap();
         }
         let data = fs.get_handle(fname_str).unwrap().read_data();
-        assert_eq!(&data[..], "hello there".as_bytes());
+        assert_eq!(&data[..], "hello therehello therehello there".as_bytes());
+    }
+
+    #[test]
+    fn many_files() {
+        const N_FILES: usize = 20;
+        let fs = FakeFs::default();
+        let names: Vec<_> = (0..N_FILES).map(|i| format!("/fake/{}", i)).collect();
+        {
+            // Keep at most 4 of the files open at once; the rest get closed and reopened as we go.
+            let mut reg = Registry::with_max_open_files(fs.clone(), 4);
+            for round in 0..3 {
+                for name in names.iter() {
+                    let handle = reg.get_handle(Some(&Str::from(name.as_str())), FileSpec::Trunc);
+                    let handle = handle.unwrap();
+                    handle
+                        .write(&Str::from(format!("{} ", round)), FileSpec::Trunc)
+                        .unwrap();
+                    handle.flush().unwrap();
+                }
+            }
+        }
+        for name in names.iter() {
+            let data = fs.get_handle(name.as_str()).unwrap().read_data();
+            assert_eq!(&data[..], "0 1 2 ".as_bytes(), "{}", name);
+        }
     }
 
     #[test]
@@ -0,0 +1,790 @@
+//! Binary encodings for compiled programs.
+//!
+//! This module contains a small little-endian [`Writer`] and [`Reader`] pair, shared by the JIT
+//! cache, along with the format used for precompiled bytecode files (see `--emit-bytecode` and
+//! `--load-bytecode`). A bytecode file holds a program after it has been typed and lowered to
+//! [`Instr`]s, along with the metadata required to pick an input reader and construct an
+//! interpreter for it. Loading one skips parsing, type inference and lowering entirely. Anything
+//! compiled into the program, such as `-v` and `var=value` assignments, is fixed when the file is
+//! written; `ARGV` is taken from the command line the file is run with.
+//!
+//! Bytecode files are only readable by the version of frawk that wrote them: the instruction set
+//! is an implementation detail and changes freely between releases.
+use crate::arena::Arena;
+use crate::builtins::{Bitwise, FloatFunc, Variable};
+use crate::bytecode::{DebugInfo, Instr, Label, Program, Reg};
+use crate::cfg::SepAssign;
+use crate::common::{FileSpec, Result, Stage};
+use crate::compile::{self, Ty, NUM_TYPES};
+use crate::lexer::Loc;
+use crate::pushdown::FieldSet;
+use crate::runtime::{Str, UniqueStr};
+
+use crate::runtime::matcher::{Matcher, PatternSet};
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"FRAWKBC1";
+
+#[derive(Default)]
+pub(crate) struct Writer(pub(crate) Vec<u8>);
+
+impl Writer {
+    pub(crate) fn u8(&mut self, x: u8) {
+        self.0.push(x)
+    }
+    pub(crate) fn u32(&mut self, x: u32) {
+        self.0.extend_from_slice(&x.to_le_bytes())
+    }
+    pub(crate) fn u64(&mut self, x: u64) {
+        self.0.extend_from_slice(&x.to_le_bytes())
+    }
+    pub(crate) fn blob(&mut self, bs: &[u8]) {
+        self.u64(bs.len() as u64);
+        self.0.extend_from_slice(bs)
+    }
+}
+
+/// A cursor over an encoded buffer. Any malformed input yields `None`.
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub(crate) fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (res, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(res)
+    }
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.bytes(4)?);
+        Some(u32::from_le_bytes(buf))
+    }
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Some(u64::from_le_bytes(buf))
+    }
+    /// Read a length prefix. Lengths are at most the number of bytes remaining, which guards
+    /// against huge allocations when reading corrupt input.
+    pub(crate) fn len(&mut self) -> Option<usize> {
+        let n = self.u64()? as usize;
+        if n > self.0.len() {
+            return None;
+        }
+        Some(n)
+    }
+    pub(crate) fn blob(&mut self) -> Option<&'a [u8]> {
+        let n = self.len()?;
+        self.bytes(n)
+    }
+    pub(crate) fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.blob()?.to_vec()).ok()
+    }
+}
+
+/// Serialize `prog` to a bytecode file, along with the result of the separator analysis used to
+/// select an input reader for it.
+pub(crate) fn write_program(sep: &SepAssign, prog: &Program) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.0.extend_from_slice(&MAGIC[..]);
+    w.blob(env!("CARGO_PKG_VERSION").as_bytes());
+    match sep {
+        SepAssign::Unsure => w.u8(0),
+        SepAssign::Potential {
+            field_sep,
+            record_sep,
+        } => {
+            w.u8(1);
+            field_sep.put(&mut w);
+            record_sep.put(&mut w);
+        }
+    }
+    match &prog.stage {
+        Stage::Main(m) => {
+            w.u8(0);
+            m.put(&mut w);
+        }
+        Stage::Par {
+            begin,
+            main_loop,
+            end,
+        } => {
+            w.u8(1);
+            begin.put(&mut w);
+            main_loop.put(&mut w);
+            end.put(&mut w);
+        }
+    }
+    prog.atexit.put(&mut w);
+    for count in prog.regs.iter() {
+        count.put(&mut w);
+    }
+    w.u64(prog.used_fields.bits());
+    prog.named_columns.put(&mut w);
+    prog.instrs.put(&mut w);
+    prog.debug.put(&mut w);
+    w.0
+}
+
+/// Deserialize a bytecode file written by [`write_program`]. Any constants in the program are
+/// allocated in `arena`.
+pub(crate) fn read_program<'a>(
+    arena: &'a Arena<'a>,
+    bytes: &[u8],
+) -> Result<(SepAssign<'a>, Program<'a>)> {
+    let mut d = Decoder {
+        r: Reader(bytes),
+        arena,
+    };
+    if d.r.bytes(MAGIC.len()) != Some(&MAGIC[..]) {
+        return err!("not a frawk bytecode file");
+    }
+    match d.r.string() {
+        Some(v) if v == env!("CARGO_PKG_VERSION") => {}
+        Some(v) => {
+            return err!(
+                "bytecode file was written by frawk {}, but this is frawk {}",
+                v,
+                env!("CARGO_PKG_VERSION")
+            )
+        }
+        None => return err!("malformed bytecode file"),
+    }
+    match d.program() {
+        Some(res) if d.r.is_empty() => Ok(res),
+        _ => err!("malformed bytecode file"),
+    }
+}
+
+struct Decoder<'a, 'b> {
+    r: Reader<'b>,
+    arena: &'a Arena<'a>,
+}
+
+impl<'a, 'b> Decoder<'a, 'b> {
+    fn get<T: Field<'a>>(&mut self) -> Option<T> {
+        T::get(self)
+    }
+    fn program(&mut self) -> Option<(SepAssign<'a>, Program<'a>)> {
+        let sep = match self.r.u8()? {
+            0 => SepAssign::Unsure,
+            1 => SepAssign::Potential {
+                field_sep: self.get()?,
+                record_sep: self.get()?,
+            },
+            _ => return None,
+        };
+        let stage = match self.r.u8()? {
+            0 => Stage::Main(self.get()?),
+            1 => Stage::Par {
+                begin: self.get()?,
+                main_loop: self.get()?,
+                end: self.get()?,
+            },
+            _ => return None,
+        };
+        let atexit: Option<usize> = self.get()?;
+        let mut regs = [0usize; NUM_TYPES];
+        for count in regs.iter_mut() {
+            *count = self.get()?;
+        }
+        let used_fields = FieldSet::from_bits(self.r.u64()?);
+        let named_columns = self.get()?;
+        let instrs: Vec<Vec<Instr<'a>>> = self.get()?;
+        let debug: Vec<DebugInfo> = self.get()?;
+        // The interpreter indexes these without further checks.
+        let nfuncs = instrs.len();
+        if stage.iter().chain(atexit.iter()).any(|f| *f >= nfuncs)
+            || (debug.len() > 0 && debug.len() != nfuncs)
+        {
+            return None;
+        }
+        Some((
+            sep,
+            Program {
+                instrs,
+                stage,
+                atexit,
+                regs,
+                used_fields,
+                named_columns,
+                debug,
+                globals: Vec::new(),
+                record_local_strs: Vec::new(),
+            },
+        ))
+    }
+}
+
+/// A component of a program that can be written to and read from a bytecode file.
+trait Field<'a>: Sized {
+    fn put(&self, w: &mut Writer);
+    fn get(d: &mut Decoder<'a, '_>) -> Option<Self>;
+}
+
+impl<'a> Field<'a> for u32 {
+    fn put(&self, w: &mut Writer) {
+        w.u32(*self)
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<u32> {
+        d.r.u32()
+    }
+}
+
+impl<'a> Field<'a> for usize {
+    fn put(&self, w: &mut Writer) {
+        w.u64(*self as u64)
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<usize> {
+        Some(d.r.u64()? as usize)
+    }
+}
+
+impl<'a> Field<'a> for i64 {
+    fn put(&self, w: &mut Writer) {
+        w.u64(*self as u64)
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<i64> {
+        Some(d.r.u64()? as i64)
+    }
+}
+
+impl<'a> Field<'a> for f64 {
+    fn put(&self, w: &mut Writer) {
+        w.u64(self.to_bits())
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<f64> {
+        Some(f64::from_bits(d.r.u64()?))
+    }
+}
+
+impl<'a> Field<'a> for bool {
+    fn put(&self, w: &mut Writer) {
+        w.u8(*self as u8)
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<bool> {
+        match d.r.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Field<'a> for String {
+    fn put(&self, w: &mut Writer) {
+        w.blob(self.as_bytes())
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<String> {
+        d.r.string()
+    }
+}
+
+impl<'a> Field<'a> for &'a [u8] {
+    fn put(&self, w: &mut Writer) {
+        w.blob(self)
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<&'a [u8]> {
+        let bs = d.r.blob()?;
+        Some(d.arena.alloc_bytes(bs))
+    }
+}
+
+impl<'a> Field<'a> for UniqueStr<'a> {
+    fn put(&self, w: &mut Writer) {
+        self.clone_str().with_bytes(|bs| w.blob(bs))
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<UniqueStr<'a>> {
+        let bs: &'a [u8] = d.get()?;
+        Some(Str::from(bs).into())
+    }
+}
+
+impl<'a> Field<'a> for Arc<PatternSet> {
+    fn put(&self, w: &mut Writer) {
+        w.u64(self.patterns().len() as u64);
+        for p in self.patterns() {
+            w.blob(p.as_bytes())
+        }
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<Arc<PatternSet>> {
+        let mut pats = Vec::new();
+        for _ in 0..d.r.len()? {
+            pats.push(d.r.string()?);
+        }
+        Some(Arc::new(PatternSet::new(pats).ok()?))
+    }
+}
+
+impl<'a> Field<'a> for Arc<Matcher> {
+    fn put(&self, w: &mut Writer) {
+        w.blob(self.as_str().as_bytes())
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<Arc<Matcher>> {
+        Some(Arc::new(Matcher::new(d.r.string()?.as_str()).ok()?))
+    }
+}
+
+impl<'a, T> Field<'a> for Reg<T> {
+    fn put(&self, w: &mut Writer) {
+        w.u32(self.index() as u32)
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<Reg<T>> {
+        match d.r.u32()? {
+            compile::UNUSED | compile::NULL_REG => None,
+            r => Some(r.into()),
+        }
+    }
+}
+
+impl<'a> Field<'a> for Loc {
+    fn put(&self, w: &mut Writer) {
+        self.line.put(w);
+        self.col.put(w);
+        self.offset.put(w);
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<Loc> {
+        Some(Loc {
+            line: d.get()?,
+            col: d.get()?,
+            offset: d.get()?,
+        })
+    }
+}
+
+impl<'a> Field<'a> for DebugInfo {
+    fn put(&self, w: &mut Writer) {
+        self.name.put(w);
+        self.lines.put(w);
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<DebugInfo> {
+        Some(DebugInfo {
+            name: d.get()?,
+            lines: d.get()?,
+        })
+    }
+}
+
+impl<'a> Field<'a> for Label {
+    fn put(&self, w: &mut Writer) {
+        self.0.put(w)
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<Label> {
+        Some(Label(d.get()?))
+    }
+}
+
+impl<'a> Field<'a> for Ty {
+    fn put(&self, w: &mut Writer) {
+        w.u32(*self as u32)
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<Ty> {
+        Ty::try_from(d.r.u32()?).ok()
+    }
+}
+
+impl<'a> Field<'a> for Variable {
+    fn put(&self, w: &mut Writer) {
+        w.u32(*self as u32)
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<Variable> {
+        Variable::try_from(d.r.u32()? as usize).ok()
+    }
+}
+
+impl<'a> Field<'a> for FileSpec {
+    fn put(&self, w: &mut Writer) {
+        w.u32(*self as u32)
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<FileSpec> {
+        FileSpec::try_from(d.r.u32()? as i64).ok()
+    }
+}
+
+/// Implement `Field` for a fieldless enum by its index in a table of all of its variants.
+macro_rules! enum_field {
+    ($ty:ident, [$($variant:ident),*]) => {
+        impl<'a> Field<'a> for $ty {
+            fn put(&self, w: &mut Writer) {
+                const ALL: &[$ty] = &[$($ty::$variant),*];
+                w.u8(ALL.iter().position(|x| x == self).unwrap() as u8)
+            }
+            fn get(d: &mut Decoder<'a, '_>) -> Option<$ty> {
+                const ALL: &[$ty] = &[$($ty::$variant),*];
+                ALL.get(d.r.u8()? as usize).cloned()
+            }
+        }
+    };
+}
+
+enum_field!(
+    FloatFunc,
+    [Cos, Sin, Atan, Atan2, Log, Log2, Log10, Sqrt, Exp]
+);
+enum_field!(
+    Bitwise,
+    [
+        Complement,
+        And,
+        Or,
+        LogicalRightShift,
+        ArithmeticRightShift,
+        LeftShift,
+        Xor,
+        RotateLeft,
+        RotateRight,
+        PopCount,
+        LeadingZeros
+    ]
+);
+
+impl<'a, T: Field<'a>> Field<'a> for Option<T> {
+    fn put(&self, w: &mut Writer) {
+        match self {
+            None => w.u8(0),
+            Some(t) => {
+                w.u8(1);
+                t.put(w)
+            }
+        }
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<Option<T>> {
+        match d.r.u8()? {
+            0 => Some(None),
+            1 => Some(Some(d.get()?)),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, T: Field<'a>> Field<'a> for Vec<T> {
+    fn put(&self, w: &mut Writer) {
+        w.u64(self.len() as u64);
+        for t in self.iter() {
+            t.put(w)
+        }
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<Vec<T>> {
+        let n = d.r.len()?;
+        let mut res = Vec::with_capacity(n);
+        for _ in 0..n {
+            res.push(d.get()?);
+        }
+        Some(res)
+    }
+}
+
+impl<'a, A: Field<'a>, B: Field<'a>> Field<'a> for (A, B) {
+    fn put(&self, w: &mut Writer) {
+        self.0.put(w);
+        self.1.put(w);
+    }
+    fn get(d: &mut Decoder<'a, '_>) -> Option<(A, B)> {
+        let a = d.get()?;
+        let b = d.get()?;
+        Some((a, b))
+    }
+}
+
+// Expands to a single `d.get()?`, once per operand of the instruction being decoded.
+macro_rules! get_operand {
+    ($d:ident, $_op:ident) => {
+        $d.get()?
+    };
+}
+
+/// Instructions are encoded as a one-byte tag followed by their operands, in declaration order.
+/// Struct-like variants must list their fields in declaration order as well.
+macro_rules! instr_field {
+    ($($name:ident $(($($op:ident),*))? $({$($field:ident),*})? = $tag:literal,)*) => {
+        impl<'a> Field<'a> for Instr<'a> {
+            fn put(&self, w: &mut Writer) {
+                match self {
+                    $(
+                        Instr::$name $(($($op),*))? $({$($field),*})? => {
+                            w.u8($tag);
+                            $($($op.put(w);)*)?
+                            $($($field.put(w);)*)?
+                        }
+                    )*
+                }
+            }
+            fn get(d: &mut Decoder<'a, '_>) -> Option<Instr<'a>> {
+                Some(match d.r.u8()? {
+                    $(
+                        $tag => Instr::$name $(($(get_operand!(d, $op)),*))?
+                            $({$($field: d.get()?),*})?,
+                    )*
+                    _ => return None,
+                })
+            }
+        }
+    };
+}
+
+instr_field! {
+    StoreConstStr(dst, s) = 0,
+    StoreConstInt(dst, i) = 1,
+    StoreConstFloat(dst, f) = 2,
+    IntToStr(dst, src) = 3,
+    FloatToStr(dst, src) = 4,
+    StrToInt(dst, src) = 5,
+    HexStrToInt(dst, src) = 6,
+    FloatToInt(dst, src) = 7,
+    IntToFloat(dst, src) = 8,
+    StrToFloat(dst, src) = 9,
+    Mov(ty, dst, src) = 10,
+    AllocMap(ty, dst) = 11,
+    AddInt(dst, l, r) = 12,
+    AddFloat(dst, l, r) = 13,
+    MulFloat(dst, l, r) = 14,
+    MulInt(dst, l, r) = 15,
+    Div(dst, l, r) = 16,
+    Pow(dst, l, r) = 17,
+    MinusFloat(dst, l, r) = 18,
+    MinusInt(dst, l, r) = 19,
+    ModFloat(dst, l, r) = 20,
+    ModInt(dst, l, r) = 21,
+    Not(dst, src) = 22,
+    NotStr(dst, src) = 23,
+    NegInt(dst, src) = 24,
+    NegFloat(dst, src) = 25,
+    Float1(ff, dst, src) = 26,
+    Float2(ff, dst, l, r) = 27,
+    Int1(bw, dst, src) = 28,
+    Int2(bw, dst, l, r) = 29,
+    Rand(dst) = 30,
+    Srand(old, new) = 31,
+    ReseedRng(old) = 32,
+    Concat(dst, l, r) = 33,
+    IsMatch(dst, s, pat) = 34,
+    IsMatchConst(dst, s, pat) = 35,
+    Match(dst, s, pat) = 36,
+    MatchConst(dst, s, pat) = 37,
+    SubstrIndex(dst, s, t) = 38,
+    LenStr(dst, s) = 39,
+    Sub(dst, pat, s, in_s) = 40,
+    GSub(dst, pat, s, in_s) = 41,
+    EscapeCSV(dst, s) = 42,
+    EscapeTSV(dst, s) = 43,
+    Substr(dst, s, l, r) = 44,
+    LTFloat(dst, l, r) = 45,
+    LTInt(dst, l, r) = 46,
+    LTStr(dst, l, r) = 47,
+    GTFloat(dst, l, r) = 48,
+    GTInt(dst, l, r) = 49,
+    GTStr(dst, l, r) = 50,
+    LTEFloat(dst, l, r) = 51,
+    LTEInt(dst, l, r) = 52,
+    LTEStr(dst, l, r) = 53,
+    GTEFloat(dst, l, r) = 54,
+    GTEInt(dst, l, r) = 55,
+    GTEStr(dst, l, r) = 56,
+    EQFloat(dst, l, r) = 57,
+    EQInt(dst, l, r) = 58,
+    EQStr(dst, l, r) = 59,
+    SetColumn(dst, src) = 60,
+    GetColumn(dst, src) = 61,
+    JoinCSV(dst, start, end) = 62,
+    JoinTSV(dst, start, end) = 63,
+    JoinColumns(dst, start, end, sep) = 64,
+    ReadErr(dst, file, is_file) = 65,
+    NextLine(dst, file, is_file) = 66,
+    ReadErrStdin(dst) = 67,
+    NextLineStdin(dst) = 68,
+    NextLineStdinFused(dst) = 69,
+    NextFile() = 70,
+    UpdateUsedFields() = 71,
+    SetFI(key, val) = 72,
+    SplitInt(dst, s, map, sep) = 73,
+    SplitStr(dst, s, map, sep) = 74,
+    Sprintf { dst, fmt, args } = 75,
+    Printf { output, fmt, args } = 76,
+    PrintAll { output, args } = 77,
+    Close(file) = 78,
+    RunCmd(dst, cmd) = 79,
+    Lookup { map_ty, dst, map, key } = 80,
+    Contains { map_ty, dst, map, key } = 81,
+    Delete { map_ty, map, key } = 82,
+    Len { map_ty, dst, map } = 83,
+    Store { map_ty, map, key, val } = 84,
+    IterBegin { map_ty, dst, map } = 85,
+    IterHasNext { iter_ty, dst, iter } = 86,
+    IterGetNext { iter_ty, dst, iter } = 87,
+    LoadVarStr(dst, var) = 88,
+    StoreVarStr(var, src) = 89,
+    LoadVarInt(dst, var) = 90,
+    StoreVarInt(var, src) = 91,
+    LoadVarIntMap(dst, var) = 92,
+    StoreVarIntMap(var, src) = 93,
+    LoadVarStrMap(dst, var) = 94,
+    StoreVarStrMap(var, src) = 95,
+    LoadSlot { ty, slot, dst } = 96,
+    StoreSlot { ty, slot, src } = 97,
+    JmpIf(cond, lbl) = 98,
+    Jmp(lbl) = 99,
+    Halt = 100,
+    Push(ty, reg) = 101,
+    Pop(ty, reg) = 102,
+    Call(func) = 103,
+    Ret = 104,
+    MatchSet(dst, s, set) = 105,
+    GraphemeLen(dst, s) = 106,
+    GraphemeSubstr(dst, s, l, r) = 107,
+    Graphemes(dst, s, map) = 108,
+    Levenshtein(dst, a, b) = 109,
+    JaroWinkler(dst, a, b) = 110,
+    Trim(dst, s, set) = 111,
+    LTrim(dst, s, set) = 112,
+    RTrim(dst, s, set) = 113,
+    Squeeze(dst, s) = 114,
+    LPad(dst, s, width, with) = 115,
+    RPad(dst, s, width, with) = 116,
+    ZFill(dst, s, width) = 117,
+    Bound { map_ty, dst, map, size, policy } = 118,
+    LoadState { map_ty, dst, map, name } = 119,
+    SaveState { map_ty, dst, map, name } = 120,
+    TakeStr(dst, src) = 121,
+    IntDiv(dst, num, den, map) = 122,
+    DivMod(dst, num, den, map) = 123,
+    MarkInit(dst, var) = 124,
+    CheckInit(dst, var, name) = 125,
+    Readable(dst, inputs, timeout) = 126,
+    IsNA(dst, s) = 127,
+    NumOr(dst, s, default) = 128,
+    CmpNatural(dst, a, b) = 129,
+    CmpLocale(dst, a, b) = 130,
+    GetColumnInt(dst, s, col) = 131,
+    GetColumnFloat(dst, s, col) = 132,
+    HllAdd(dst, map, s) = 133,
+    HllCount(dst, map) = 134,
+    BloomAdd(dst, map, s, n, p) = 135,
+    BloomHas(dst, map, s) = 136,
+    Reservoir(dst, map, k, s) = 137,
+    WinPush(dst, map, x, n) = 138,
+    WinSum(dst, map) = 139,
+    WinAvg(dst, map) = 140,
+    WinMax(dst, map) = 141,
+    HistAdd(dst, map, x, w, log) = 142,
+    HistFormat(dst, map) = 143,
+    VecDot(dst, a, b) = 144,
+    VecNorm(dst, a) = 145,
+    VecAdd(res, dst, a, b) = 146,
+    VecSub(res, dst, a, b) = 147,
+    VecMul(res, dst, a, b) = 148,
+    VecScale(res, dst, a, k) = 149,
+    SumColumns(dst, start, end) = 150,
+    MeanColumns(dst, start, end) = 151,
+    SetTimeout(dst, input, ms) = 152,
+    Spawn(dst, cmd) = 153,
+    ReadLine(dst, h, stream) = 154,
+    WriteLine(dst, h, s) = 155,
+    Wait(dst, h) = 156,
+    ReadCmd(dst, cmd) = 157,
+    Getenv(dst, name, default) = 158,
+    Setenv(dst, name, val) = 159,
+    Stat(dst, path, arr) = 160,
+    Glob(dst, pattern, arr) = 161,
+    Mkdirp(dst, path) = 162,
+    Rename(dst, from, to) = 163,
+    RmFile(dst, path) = 164,
+    Sleep(dst, secs) = 165,
+    Monotime(dst) = 166,
+    Signal(dst) = 167,
+    SetExit(dst, status) = 168,
+    RecordFile(dst) = 169,
+    RecordOffset(dst) = 170,
+    RecordText(dst) = 171,
+    RoundTripSep(dst, record, ors) = 172,
+    Color(dst, s, name) = 173,
+    Bold(dst, s) = 174,
+    SetMatched(dst) = 175,
+    ArgvAssign(dst) = 176,
+    Exiting(dst) = 177,
+    SetExiting(dst, n) = 178,
+    TierUp(dst) = 179,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg;
+    use crate::common::ExecutionStrategy;
+    use crate::parsing::syntax::ProgParser;
+    use crate::{ast, lexer};
+
+    fn compile_program<'a>(
+        a: &'a Arena,
+        prog: &str,
+        strategy: ExecutionStrategy,
+    ) -> (SepAssign<'a>, Program<'a>) {
+        let prog = a.alloc_str(prog);
+        let mut buf = Vec::new();
+        let mut ast_prog = ast::Prog::from_stage(strategy.stage());
+        ProgParser::new()
+            .parse(a, &mut buf, &mut ast_prog, lexer::Tokenizer::new(prog))
+            .expect("parse failure");
+        let stmt = a.alloc_v(ast_prog);
+        let mut ctx = cfg::ProgramContext::from_prog(a, stmt, cfg::Escaper::Identity).unwrap();
+        let sep = ctx.analyze_sep_assignments();
+        (sep, compile::bytecode_program(&mut ctx).unwrap())
+    }
+
+    fn dump(sep: &SepAssign, prog: &Program) -> String {
+        format!(
+            "{:?} {:?} {:?} {:?} {:?} {:?}",
+            sep,
+            prog.instrs,
+            prog.stage,
+            prog.regs,
+            prog.used_fields.bits(),
+            prog.named_columns
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let progs = [
+            r#"BEGIN { FS=","; x = "hello"; y = 2.5; z = 0x1f; m[x] = y; srand(3); }
+               $2 ~ /a+b/ { n[$1]++; s = s substr($0, 2, 3); sub(/x/, "y", s); }
+               NR % 2 == 0 { printf "%s %d\n", $1, NF > "/dev/null"; next }
+               function f(a, b) { return a < b ? compl(a) : atan2(a, b); }
+               END { for (k in n) print k, n[k], f(1, 2) >> "/dev/null"; getline line < "/dev/null"; }"#,
+            r#"{ split($0, parts, ":"); sum += length(parts) } END { print sum, sprintf("%5.2f", sum) }"#,
+        ];
+        for (prog, strategy) in progs
+            .iter()
+            .zip(&[ExecutionStrategy::Serial, ExecutionStrategy::ShardPerRecord])
+        {
+            let a = Arena::default();
+            let (sep, program) = compile_program(&a, prog, *strategy);
+            let bytes = write_program(&sep, &program);
+            let b = Arena::default();
+            let (sep2, program2) = read_program(&b, &bytes[..]).expect("valid bytecode file");
+            assert_eq!(dump(&sep, &program), dump(&sep2, &program2));
+            assert_eq!(bytes, write_program(&sep2, &program2));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let a = Arena::default();
+        let (sep, program) = compile_program(&a, "{ print $1 }", ExecutionStrategy::Serial);
+        let mut bytes = write_program(&sep, &program);
+        assert!(read_program(&a, b"#!/usr/bin/frawk -f\n").is_err());
+        bytes.push(0);
+        assert!(read_program(&a, &bytes[..]).is_err());
+        bytes.truncate(bytes.len() - 4);
+        assert!(read_program(&a, &bytes[..]).is_err());
+    }
+}
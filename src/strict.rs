@@ -0,0 +1,540 @@
+//! Checks for `--strict`.
+//!
+//! frawk accepts some programs that use a variable as an array in one place and as a scalar in
+//! another, resolving the conflict silently. `--strict` rejects them before the program is
+//! compiled. (Calls to undefined functions are always an error.) The rest of `--strict` happens at
+//! runtime in the bytecode interpreter: converting a non-numeric string to a number is an error,
+//! and so is reading a global variable before anything has been assigned to it, which is usually
+//! a misspelled variable name. The checks for the latter are added to the program by
+//! `instrument`.
+use crate::arena::Arena;
+use crate::ast::{Expr, Pattern, Prog, Stmt};
+use crate::builtins::{Function, Variable};
+use crate::common::{CompileError, Either, Result};
+use crate::lexer::Span;
+
+use hashbrown::{HashMap, HashSet};
+
+use std::convert::TryFrom;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Array,
+    Scalar,
+}
+
+impl Kind {
+    fn describe(self) -> &'static str {
+        match self {
+            Kind::Array => "an array",
+            Kind::Scalar => "a scalar",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Checker<'b> {
+    cur_span: Option<Span>,
+    // The first use of each variable, and the kind of use it was.
+    globals: HashMap<&'b str, (Kind, Option<Span>)>,
+    // The same, for the parameters of the function currently being checked.
+    locals: HashMap<&'b str, Option<(Kind, Option<Span>)>>,
+    // The functions declared in the program, which take precedence over builtins of the same name.
+    declared: HashSet<&'b str>,
+}
+
+// Check that no variable in `prog` is used both as an array and as a scalar.
+fn check<'a, 'b>(prog: &Prog<'a, 'b, &'b str>) -> Result<Checker<'b>> {
+    let mut c = Checker::default();
+    c.declared = prog.decs.iter().map(|d| d.name).collect();
+    for (_, e) in prog.prelude_vardecs.iter() {
+        c.expr(e)?;
+    }
+    for fundec in prog.decs.iter() {
+        c.locals = fundec.args.iter().map(|a| (*a, None)).collect();
+        c.cur_span = Some(fundec.span);
+        c.stmt(fundec.body)?;
+    }
+    c.locals.clear();
+    c.cur_span = None;
+    for s in prog.begin.iter().chain(prog.prepare.iter()) {
+        c.stmt(s)?;
+    }
+    for (span, pat, body) in prog.pats.iter() {
+        c.cur_span = Some(*span);
+        match pat {
+            Pattern::Null => {}
+            Pattern::Bool(e) | Pattern::Group(e) => c.expr(e)?,
+            Pattern::Comma(e1, e2) => {
+                c.expr(e1)?;
+                c.expr(e2)?;
+            }
+        }
+        if let Some(body) = body {
+            c.stmt(body)?;
+        }
+    }
+    c.cur_span = None;
+    for s in prog.end.iter().chain(prog.signal.iter()) {
+        c.stmt(s)?;
+    }
+    Ok(c)
+}
+
+/// Check that no variable in `prog` is used both as an array and as a scalar, then rewrite `prog`
+/// so that reading a global scalar before anything has been assigned to it fails at runtime.
+///
+/// Each read of a checked variable `x` becomes `check_init(i, "x") ? x : x`, and each assignment
+/// to it becomes `mark_init(i) ? <assignment> : <assignment>`, where `i` identifies `x`. Only one
+/// branch of the conditional runs, after the interpreter has checked or updated its record of
+/// which variables have been assigned. Assignments include `++` and `--`, the assignment
+/// operators, `getline x`, `sub` and `gsub`, and `for (x in a)` loops. The variable is marked
+/// before the assigned value is computed, so that accumulating a value with `n++`, `sum += $1`
+/// or `s = s $1` is not an error.
+///
+/// Variables assigned with `-v` or as operands, special variables like `NR`, arrays and function
+/// parameters are not checked.
+pub(crate) fn instrument<'a, 'b>(
+    arena: &'a Arena<'a>,
+    prog: &mut Prog<'a, 'b, &'b str>,
+) -> Result<()> {
+    let c = check(prog)?;
+    let predefined: HashSet<&str> = prog
+        .prelude_vardecs
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(prog.operand_vardecs.iter().map(|(_, name, _)| *name))
+        .collect();
+    let mut names: Vec<&'b str> = c
+        .globals
+        .iter()
+        .filter(|(name, (kind, _))| *kind == Kind::Scalar && !predefined.contains(*name))
+        .map(|(name, _)| *name)
+        .collect();
+    names.sort();
+    let mut ins = Instrumenter {
+        arena,
+        vars: names
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (name, i as i64))
+            .collect(),
+        locals: Default::default(),
+    };
+    for fundec in prog.decs.iter_mut() {
+        ins.locals = fundec.args.clone();
+        fundec.body = ins.stmt(fundec.body);
+    }
+    ins.locals.clear();
+    prog.begin = prog.begin.map(|s| ins.stmt(s));
+    prog.prepare = prog.prepare.map(|s| ins.stmt(s));
+    prog.end = prog.end.map(|s| ins.stmt(s));
+    prog.signal = prog.signal.map(|s| ins.stmt(s));
+    for (_, pat, body) in prog.pats.iter_mut() {
+        *pat = match pat {
+            Pattern::Null => Pattern::Null,
+            Pattern::Bool(e) => Pattern::Bool(ins.expr(e)),
+            Pattern::Comma(e1, e2) => Pattern::Comma(ins.expr(e1), ins.expr(e2)),
+            Pattern::Group(e) => Pattern::Group(ins.expr(e)),
+        };
+        *body = body.map(|s| ins.stmt(s));
+    }
+    Ok(())
+}
+
+struct Instrumenter<'a, 'b> {
+    arena: &'a Arena<'a>,
+    // The checked variables, and the index identifying each of them at runtime.
+    vars: HashMap<&'b str, i64>,
+    // The parameters of the function currently being rewritten.
+    locals: Vec<&'b str>,
+}
+
+impl<'a, 'b> Instrumenter<'a, 'b> {
+    fn alloc(&self, e: Expr<'a, 'b, &'b str>) -> &'a Expr<'a, 'b, &'b str> {
+        self.arena.alloc_v(e)
+    }
+
+    // The index of `e`, if it is a checked variable.
+    fn index(&self, e: &Expr<'a, 'b, &'b str>) -> Option<i64> {
+        match e {
+            Expr::Var(v) if !self.locals.contains(v) => self.vars.get(v).cloned(),
+            _ => None,
+        }
+    }
+
+    fn mark_init(&self, ix: i64) -> &'a Expr<'a, 'b, &'b str> {
+        let args = vec![self.alloc(Expr::ILit(ix))];
+        self.alloc(Expr::Call(Either::Right(Function::MarkInit), args))
+    }
+
+    // Mark the variable with index `ix` as assigned, then evaluate `e`.
+    fn assign(&self, ix: i64, e: &'a Expr<'a, 'b, &'b str>) -> &'a Expr<'a, 'b, &'b str> {
+        self.alloc(Expr::ITE(self.mark_init(ix), e, e))
+    }
+
+    fn stmt(&self, s: &'a Stmt<'a, 'b, &'b str>) -> &'a Stmt<'a, 'b, &'b str> {
+        let exprs = |es: &Vec<&'a Expr<'a, 'b, &'b str>>| es.iter().map(|e| self.expr(e)).collect();
+        let res = match s {
+            Stmt::Located(span, s) => Stmt::Located(*span, self.stmt(s)),
+            Stmt::StartCond(_)
+            | Stmt::EndCond(_)
+            | Stmt::LastCond(_)
+            | Stmt::Break
+            | Stmt::Continue
+            | Stmt::Next
+            | Stmt::NextFile => return s,
+            Stmt::Expr(e) => Stmt::Expr(self.expr(e)),
+            Stmt::Block(stmts) => Stmt::Block(stmts.iter().map(|s| self.stmt(s)).collect()),
+            Stmt::Print(args, out) => Stmt::Print(
+                exprs(args),
+                out.as_ref().map(|(out, spec)| (self.expr(out), *spec)),
+            ),
+            Stmt::Printf(fmt, args, out) => Stmt::Printf(
+                self.expr(fmt),
+                exprs(args),
+                out.as_ref().map(|(out, spec)| (self.expr(out), *spec)),
+            ),
+            Stmt::If(cond, t, f) => {
+                Stmt::If(self.expr(cond), self.stmt(t), f.map(|f| self.stmt(f)))
+            }
+            Stmt::For(init, cond, update, body) => Stmt::For(
+                init.map(|s| self.stmt(s)),
+                cond.map(|e| self.expr(e)),
+                update.map(|s| self.stmt(s)),
+                self.stmt(body),
+            ),
+            Stmt::DoWhile(cond, body) => Stmt::DoWhile(self.expr(cond), self.stmt(body)),
+            Stmt::While(is_toplevel, cond, body) => {
+                Stmt::While(*is_toplevel, self.expr(cond), self.stmt(body))
+            }
+            Stmt::ForEach(v, arr, body) => {
+                let mut body = self.stmt(body);
+                if let Some(ix) = self.index(&Expr::Var(*v)) {
+                    let mark = self.arena.alloc_v(Stmt::Expr(self.mark_init(ix)));
+                    body = self.arena.alloc_v(Stmt::Block(vec![mark, body]));
+                }
+                Stmt::ForEach(*v, self.expr(arr), body)
+            }
+            Stmt::Return(e) => Stmt::Return(e.map(|e| self.expr(e))),
+            Stmt::Exit(e) => Stmt::Exit(e.map(|e| self.expr(e))),
+            Stmt::Phase(s) => Stmt::Phase(self.stmt(s)),
+        };
+        self.arena.alloc_v(res)
+    }
+
+    fn expr(&self, e: &'a Expr<'a, 'b, &'b str>) -> &'a Expr<'a, 'b, &'b str> {
+        use Expr::*;
+        let res = match e {
+            ILit(_) | FLit(_) | StrLit(_) | PatLit(_) | ReadStdin | Cond(_) => return e,
+            Var(v) => match self.index(e) {
+                Some(ix) => {
+                    let args = vec![self.alloc(ILit(ix)), self.alloc(StrLit(v.as_bytes()))];
+                    let cond = self.alloc(Call(Either::Right(Function::CheckInit), args));
+                    ITE(cond, e, e)
+                }
+                None => return e,
+            },
+            Unop(op, x) => Unop(*op, self.expr(x)),
+            Binop(op, l, r) => Binop(*op, self.expr(l), self.expr(r)),
+            And(l, r) => And(self.expr(l), self.expr(r)),
+            Or(l, r) => Or(self.expr(l), self.expr(r)),
+            ITE(c, t, f) => ITE(self.expr(c), self.expr(t), self.expr(f)),
+            Index(arr, ix) => Index(self.expr(arr), self.expr(ix)),
+            Assign(l, r) => match self.index(l) {
+                Some(ix) => return self.assign(ix, self.alloc(Assign(l, self.expr(r)))),
+                None => Assign(self.expr(l), self.expr(r)),
+            },
+            AssignOp(l, op, r) => match self.index(l) {
+                Some(ix) => return self.assign(ix, self.alloc(AssignOp(l, *op, self.expr(r)))),
+                None => AssignOp(self.expr(l), *op, self.expr(r)),
+            },
+            Inc { is_inc, is_post, x } => match self.index(x) {
+                Some(ix) => return self.assign(ix, e),
+                None => Inc {
+                    is_inc: *is_inc,
+                    is_post: *is_post,
+                    x: self.expr(x),
+                },
+            },
+            Getline {
+                into,
+                from,
+                is_file,
+            } => {
+                let from = from.map(|from| self.expr(from));
+                match into.and_then(|into| self.index(into)) {
+                    Some(ix) => {
+                        return self.assign(
+                            ix,
+                            self.alloc(Getline {
+                                into: *into,
+                                from,
+                                is_file: *is_file,
+                            }),
+                        )
+                    }
+                    None => Getline {
+                        into: into.map(|into| self.expr(into)),
+                        from,
+                        is_file: *is_file,
+                    },
+                }
+            }
+            Call(f, args) => {
+                let builtin = match f {
+                    Either::Left(name) => Function::try_from(*name).ok(),
+                    Either::Right(f) => Some(*f),
+                };
+                // The string that sub and gsub modify is assigned, not read.
+                let out = match (builtin, args.get(2)) {
+                    (Some(Function::Sub), Some(out)) | (Some(Function::GSub), Some(out)) => {
+                        self.index(out)
+                    }
+                    _ => None,
+                };
+                let args = args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, a)| {
+                        if out.is_some() && i == 2 {
+                            *a
+                        } else {
+                            self.expr(a)
+                        }
+                    })
+                    .collect();
+                match out {
+                    Some(ix) => return self.assign(ix, self.alloc(Call(f.clone(), args))),
+                    None => Call(f.clone(), args),
+                }
+            }
+        };
+        self.alloc(res)
+    }
+}
+
+impl<'b> Checker<'b> {
+    fn use_as(&mut self, name: &'b str, kind: Kind) -> Result<()> {
+        if Variable::try_from(name).is_ok() {
+            return Ok(());
+        }
+        let span = self.cur_span;
+        let first = match self.locals.get_mut(name) {
+            Some(first) => first.get_or_insert((kind, span)),
+            None => self.globals.entry(name).or_insert((kind, span)),
+        };
+        if first.0 == kind {
+            return Ok(());
+        }
+        let mut msg = format!(
+            "`{}` is used as {} here, but as {}",
+            name,
+            kind.describe(),
+            first.0.describe()
+        );
+        if let Some((start, _)) = first.1 {
+            msg.push_str(format!(" on {}", start).as_str());
+        }
+        let mut e = CompileError::new(msg);
+        e.span = span;
+        Err(e)
+    }
+
+    fn stmt<'a>(&mut self, s: &Stmt<'a, 'b, &'b str>) -> Result<()> {
+        use Stmt::*;
+        match s {
+            Located(span, s) => {
+                let prev = self.cur_span.replace(*span);
+                self.stmt(s)?;
+                self.cur_span = prev;
+            }
+            StartCond(_) | EndCond(_) | LastCond(_) | Break | Continue | Next | NextFile => {}
+            Expr(e) => self.expr(e)?,
+            Block(stmts) => {
+                for s in stmts.iter() {
+                    self.stmt(s)?;
+                }
+            }
+            Print(args, out) => {
+                for a in args.iter() {
+                    self.expr(a)?;
+                }
+                if let Some((out, _)) = out {
+                    self.expr(out)?;
+                }
+            }
+            Printf(fmt, args, out) => {
+                self.expr(fmt)?;
+                for a in args.iter() {
+                    self.expr(a)?;
+                }
+                if let Some((out, _)) = out {
+                    self.expr(out)?;
+                }
+            }
+            If(cond, t, f) => {
+                self.expr(cond)?;
+                self.stmt(t)?;
+                if let Some(f) = f {
+                    self.stmt(f)?;
+                }
+            }
+            For(init, cond, update, body) => {
+                if let Some(init) = init {
+                    self.stmt(init)?;
+                }
+                if let Some(cond) = cond {
+                    self.expr(cond)?;
+                }
+                if let Some(update) = update {
+                    self.stmt(update)?;
+                }
+                self.stmt(body)?;
+            }
+            DoWhile(cond, body) | While(_, cond, body) => {
+                self.expr(cond)?;
+                self.stmt(body)?;
+            }
+            ForEach(v, arr, body) => {
+                self.use_as(v, Kind::Scalar)?;
+                self.array(arr)?;
+                self.stmt(body)?;
+            }
+            Return(e) | Exit(e) => {
+                if let Some(e) = e {
+                    self.expr(e)?;
+                }
+            }
+            Phase(s) => self.stmt(s)?,
+        }
+        Ok(())
+    }
+
+    fn array<'a>(&mut self, e: &Expr<'a, 'b, &'b str>) -> Result<()> {
+        match e {
+            Expr::Var(v) => self.use_as(v, Kind::Array),
+            e => self.expr(e),
+        }
+    }
+
+    fn expr<'a>(&mut self, e: &Expr<'a, 'b, &'b str>) -> Result<()> {
+        use Expr::*;
+        match e {
+            ILit(_) | FLit(_) | StrLit(_) | PatLit(_) | ReadStdin | Cond(_) => {}
+            Var(v) => self.use_as(v, Kind::Scalar)?,
+            Unop(_, e) => self.expr(e)?,
+            Binop(_, l, r) | And(l, r) | Or(l, r) | Assign(l, r) | AssignOp(l, _, r) => {
+                self.expr(l)?;
+                self.expr(r)?;
+            }
+            Index(arr, ix) => {
+                self.array(arr)?;
+                self.expr(ix)?;
+            }
+            Inc { x, .. } => self.expr(x)?,
+            ITE(c, t, f) => {
+                self.expr(c)?;
+                self.expr(t)?;
+                self.expr(f)?;
+            }
+            Getline { into, from, .. } => {
+                if let Some(into) = into {
+                    self.expr(into)?;
+                }
+                if let Some(from) = from {
+                    self.expr(from)?;
+                }
+            }
+            Call(f, args) => {
+                let builtin = match f {
+                    Either::Left(name) if self.declared.contains(name) => None,
+                    Either::Left(name) => Function::try_from(*name).ok(),
+                    Either::Right(f) => Some(*f),
+                };
+                // The arguments that must be arrays, if any.
+                let arrs = match builtin {
+                    Some(Function::Contains)
+                    | Some(Function::Delete)
+                    | Some(Function::Bound)
+                    | Some(Function::LoadState)
+                    | Some(Function::SaveState)
+                    | Some(Function::Readable)
+                    | Some(Function::HllAdd)
+                    | Some(Function::HllCount)
+                    | Some(Function::BloomAdd)
+                    | Some(Function::BloomHas)
+                    | Some(Function::Reservoir)
+                    | Some(Function::WinPush)
+                    | Some(Function::WinSum)
+                    | Some(Function::WinAvg)
+                    | Some(Function::WinMax)
+                    | Some(Function::HistAdd)
+                    | Some(Function::HistFormat)
+                    | Some(Function::VecNorm) => 0..1,
+                    Some(Function::Split)
+                    | Some(Function::Graphemes)
+                    | Some(Function::Stat)
+                    | Some(Function::Glob) => 1..2,
+                    Some(Function::IntDiv) | Some(Function::DivMod) => 2..3,
+                    Some(Function::VecDot) | Some(Function::VecScale) => 0..2,
+                    Some(Function::VecAdd) | Some(Function::VecSub) | Some(Function::VecMul) => {
+                        0..3
+                    }
+                    _ => 0..0,
+                };
+                for (i, a) in args.iter().enumerate() {
+                    match (builtin, a) {
+                        _ if arrs.contains(&i) => self.array(a)?,
+                        // Variables passed to user-defined functions, or to `length`, can be
+                        // either arrays or scalars.
+                        (None, Var(_)) | (Some(Function::Length), Var(_)) => {}
+                        _ => self.expr(a)?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena::Arena;
+    use crate::common::Stage;
+    use crate::lexer::Tokenizer;
+    use crate::parsing::syntax::ProgParser;
+
+    fn check_program(text: &str) -> std::result::Result<(), String> {
+        let a = Arena::default();
+        let text = a.alloc_str(text);
+        let mut buf = Vec::new();
+        let mut prog = Prog::from_stage(Stage::Main(()));
+        ProgParser::new()
+            .parse(&a, &mut buf, &mut prog, Tokenizer::new(text))
+            .expect("parse failure");
+        check(&prog).map(|_| ()).map_err(|e| e.msg)
+    }
+
+    #[test]
+    fn arrays_and_scalars() {
+        assert_eq!(
+            check_program(
+                r#"function add(m, k) { m[k]++; return length(m) }
+{ n = add(counts, $1); split($2, parts, ":"); if ($3 in seen) delete seen[$3]; }
+END { for (k in counts) print k, counts[k], n, length(k); }"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check_program("BEGIN { x[1] = 1\n x = 2 }"),
+            Err("`x` is used as a scalar here, but as an array on line 1, column 9".into())
+        );
+        assert_eq!(
+            check_program("function f(a) { a = 1; return a[1] }"),
+            Err("`a` is used as an array here, but as a scalar on line 1, column 17".into())
+        );
+        assert!(check_program("BEGIN { split($0, n); print n + 1 }").is_err());
+    }
+}
@@ -107,7 +107,8 @@ impl<'a> StringConstantAnalysis<'a> {
             | Store { .. }
             | IterBegin { .. }
             | IterGetNext { .. }
-            | Mov(..) => dataflow::boilerplate::visit_ll(inst, |dst, src| {
+            | Mov(..)
+            | TakeStr(..) => dataflow::boilerplate::visit_ll(inst, |dst, src| {
                 if let Some(src) = src {
                     self.dfa.add_dep(dst, src, ())
                 } else {
@@ -0,0 +1,137 @@
+//! Statement tracing for `--trace`.
+//!
+//! When tracing is enabled, the bytecode interpreter asks the `Tracer` about every instruction
+//! that begins a statement (or re-evaluates a loop condition). The tracer decides whether the
+//! statement should be logged, given the line range and the limit on the number of statements to
+//! log, and which global variables mentioned in the statement to print the values of.
+use hashbrown::HashMap;
+
+use crate::common::NumTy;
+use crate::compile::Ty;
+use crate::lexer::{Span, Tok, Tokenizer};
+
+// Statements longer than this are truncated in the log.
+const MAX_STMT_LEN: usize = 60;
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Config {
+    /// Only log statements starting on these lines (1-indexed and inclusive).
+    pub lines: Option<(usize, usize)>,
+    /// Stop logging after this many statements.
+    pub limit: Option<u64>,
+}
+
+impl Config {
+    /// Parse the argument to `--trace`: either empty, a line number `N`, or a range `N-M`. Either
+    /// end of the range may be omitted.
+    pub(crate) fn parse_lines(&mut self, s: &str) -> Result<(), String> {
+        if s.len() == 0 {
+            return Ok(());
+        }
+        let bad = || format!("invalid line range {:?}; expected N, N-M, N- or -M", s);
+        let line = |s: &str, default: usize| {
+            if s.len() == 0 {
+                Ok(default)
+            } else {
+                s.trim().parse::<usize>().map_err(|_| bad())
+            }
+        };
+        self.lines = Some(match s.find('-') {
+            Some(ix) => (line(&s[..ix], 1)?, line(&s[ix + 1..], usize::max_value())?),
+            None => {
+                let l = line(s, 0)?;
+                (l, l)
+            }
+        });
+        Ok(())
+    }
+}
+
+pub(crate) struct Tracer {
+    text: String,
+    config: Config,
+    logged: u64,
+    // The name, register and type of each global variable.
+    globals: Vec<(String, NumTy, Ty)>,
+    // For each statement (keyed by its offset in `text`), the text to print and the indexes in
+    // `globals` of the variables it mentions.
+    stmts: HashMap<usize, (String, Vec<usize>)>,
+}
+
+impl Tracer {
+    pub(crate) fn new(text: &str, config: Config, globals: Vec<(String, NumTy, Ty)>) -> Tracer {
+        Tracer {
+            text: text.into(),
+            config,
+            logged: 0,
+            globals,
+            stmts: Default::default(),
+        }
+    }
+
+    /// If the statement at `span` should be logged, return its text and the name, register and
+    /// type of the global variables whose values should be printed alongside it.
+    pub(crate) fn statement<'a>(
+        &'a mut self,
+        span: &Span,
+    ) -> Option<(&'a str, impl Iterator<Item = &'a (String, NumTy, Ty)> + 'a)> {
+        let line = span.0.line + 1;
+        match self.config.lines {
+            Some((lo, hi)) if line < lo || line > hi => return None,
+            _ => {}
+        }
+        match self.config.limit {
+            Some(limit) if self.logged >= limit => return None,
+            _ => {}
+        }
+        self.logged += 1;
+        let (text, globals) = (&self.text, &self.globals);
+        let (stmt, vars) = self.stmts.entry(span.0.offset).or_insert_with(|| {
+            let stmt = text
+                .get(span.0.offset..span.1.offset)
+                .unwrap_or("")
+                .lines()
+                .next()
+                .unwrap_or("")
+                .trim();
+            let mut vars = Vec::new();
+            for tok in Tokenizer::new(stmt) {
+                let name = match tok {
+                    Ok((_, Tok::Ident(name), _)) => name,
+                    Ok(_) => continue,
+                    Err(_) => break,
+                };
+                if let Ok(i) = globals.binary_search_by(|(g, _, _)| g.as_str().cmp(name)) {
+                    if !vars.contains(&i) {
+                        vars.push(i);
+                    }
+                }
+            }
+            let stmt = match stmt.char_indices().nth(MAX_STMT_LEN) {
+                Some((ix, _)) => format!("{}...", &stmt[..ix]),
+                None => stmt.to_string(),
+            };
+            (stmt, vars)
+        });
+        Some((stmt.as_str(), vars.iter().map(move |i| &globals[*i])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        let parse = |s: &str| {
+            let mut c = Config::default();
+            c.parse_lines(s).map(|()| c.lines)
+        };
+        assert_eq!(parse(""), Ok(None));
+        assert_eq!(parse("3"), Ok(Some((3, 3))));
+        assert_eq!(parse("3-7"), Ok(Some((3, 7))));
+        assert_eq!(parse("-7"), Ok(Some((1, 7))));
+        assert_eq!(parse("3-"), Ok(Some((3, usize::max_value()))));
+        assert!(parse("x").is_err());
+    }
+}
@@ -277,7 +277,7 @@
 //! [`State`]: [crate::types::State]
 use crate::builtins;
 use crate::cfg::{self, Function, Ident, ProgramContext};
-use crate::common::{self, NodeIx, NumTy, Result};
+use crate::common::{self, CompileError, NodeIx, NumTy, Result};
 use crate::compile;
 use hashbrown::{HashMap, HashSet};
 
@@ -372,8 +372,10 @@ impl Constraint<State> {
                 key: None,
                 val: None,
             })),
+            // Maps are keyed by integers or strings; floating-point keys are converted to
+            // strings, as they are when keys of different types are joined.
             Constraint::KeyIn(Some(TVar::Scalar(k))) => Ok(Some(TVar::Map {
-                key: k.clone(),
+                key: k.map(|k| if k == BaseTy::Float { BaseTy::Str } else { k }),
                 val: None,
             })),
             Constraint::KeyIn(op) => err!("Non-scalar KeyIn constraint: {:?}", op),
@@ -462,6 +464,14 @@ impl Rule {
                 (Int, Int) => Int,
             }
         }
+        // Map keys (and hence iterators over them) are integers or strings.
+        fn join_key(b1: BaseTy, b2: BaseTy) -> BaseTy {
+            use BaseTy::*;
+            match (b1, b2) {
+                (Float, _) | (_, Float) | (Str, _) | (_, Str) | (Null, _) | (_, Null) => Str,
+                (Int, _) => Int,
+            }
+        }
         if let Rule::Const(tv) = self {
             return Ok((tv != prev, tv.clone()));
         }
@@ -475,28 +485,12 @@ impl Rule {
                 (None, x) | (x, None) => x,
                 (Some(x), Some(y)) => match (x, y) {
                     (Iter(x), Iter(None)) | (Iter(None), Iter(x)) => Some(Iter(x)),
-                    (Iter(Some(x)), Iter(Some(y))) => {
-                        if x == y {
-                            cur
-                        } else {
-                            return err!("Incompatible iterator types: {:?} vs. {:?}", x, y);
-                        }
-                    }
+                    // The key type of the map being iterated over can still change from Int to
+                    // Str as types are inferred.
+                    (Iter(Some(x)), Iter(Some(y))) => Some(Iter(Some(join_key(x, y)))),
                     (Scalar(x), Scalar(None)) | (Scalar(None), Scalar(x)) => Some(Scalar(x)),
                     (Scalar(Some(x)), Scalar(Some(y))) => Some(Scalar(Some(value_rule(x, y)))),
                     (Map { key: k1, val: v1 }, Map { key: k2, val: v2 }) => {
-                        fn join_key(b1: BaseTy, b2: BaseTy) -> BaseTy {
-                            use BaseTy::*;
-                            match (b1, b2) {
-                                (Float, _)
-                                | (_, Float)
-                                | (Str, _)
-                                | (_, Str)
-                                | (Null, _)
-                                | (_, Null) => Str,
-                                (Int, _) => Int,
-                            }
-                        }
                         fn lift(
                             f: impl Fn(BaseTy, BaseTy) -> BaseTy,
                             o1: Option<BaseTy>,
@@ -643,9 +637,12 @@ pub(crate) struct TypeContext<'a, 'b> {
     env: HashMap<Args<Ident>, NodeIx>,
     funcs: HashMap<Args<NumTy>, NodeIx>,
     maps: HashSet<NodeIx>,
+    // Maps filled in by `load_state`, see `default_loaded_maps`.
+    loaded_maps: Vec<NodeIx>,
     func_table: &'a [Function<'b, &'b str>],
     local_globals: &'a HashSet<NumTy>,
     udf_nodes: Vec<NodeIx>,
+    pc: &'a ProgramContext<'b, &'b str>,
 }
 
 struct View<'a, 'b, 'c> {
@@ -693,9 +690,11 @@ impl<'b, 'c> TypeContext<'b, 'c> {
             env: Default::default(),
             funcs: Default::default(),
             maps: Default::default(),
+            loaded_maps: Default::default(),
             func_table: &pc.funcs[..],
             local_globals: pc.local_globals_ref(),
             udf_nodes: Default::default(),
+            pc,
         };
         tc.udf_nodes = (0..pc.funcs.len())
             .map(|_| tc.nw.add_rule(Rule::AlwaysNotify))
@@ -712,6 +711,9 @@ impl<'b, 'c> TypeContext<'b, 'c> {
             tc.get_function(main, /*arg_nodes=*/ Default::default(), main_base);
         }
         tc.solve()?;
+        while tc.default_loaded_maps() {
+            tc.solve()?;
+        }
         let mut var_tys = HashMap::new();
         let mut func_tys = HashMap::new();
         for (Args { id, args, .. }, ix) in tc.funcs.iter() {
@@ -728,7 +730,7 @@ impl<'b, 'c> TypeContext<'b, 'c> {
             for a in args.iter().cloned() {
                 flat_args.push(flatten(concrete(a))?);
             }
-            let v = flatten(concrete(*tc.nw.read(*ix)))?;
+            let v = flatten(concrete(*tc.nw.read(*ix))).map_err(|e| tc.locate_ident(id, e))?;
 
             // We won't use the function id if id.global, so setting it to 0 should be fine.
             // TODO clean up some of this to make it less misleading
@@ -793,13 +795,16 @@ impl<'b, 'c> TypeContext<'b, 'c> {
             while let Some((e_ix, node_ix)) = walker.next(&self.nw.graph) {
                 let edge = self.nw.graph.edge_weight(e_ix).unwrap().clone();
                 let node_val = self.nw.graph.node_weight(node_ix).unwrap().cur_val.clone();
-                deps.push(edge.constraint.sub(node_val).eval(self)?);
+                let dep = edge.constraint.sub(node_val).eval(self);
+                deps.push(dep.map_err(|e| self.locate_node(ix, e))?);
                 if edge.constraint.is_flow() {
                     dep_indices.push(node_ix);
                 }
             }
             // Compute an update value based on the newly-evaluated constraints.
-            let (changed, next) = rule.step(&cur_val, &deps[..])?;
+            let (changed, next) = rule
+                .step(&cur_val, &deps[..])
+                .map_err(|e| self.locate_node(ix, e))?;
             if !changed {
                 continue;
             }
@@ -835,6 +840,39 @@ impl<'b, 'c> TypeContext<'b, 'c> {
         Ok(())
     }
 
+    /// Attach the location of the statement that introduced `id` to `e`.
+    fn locate_ident(&self, id: &Ident, e: CompileError) -> CompileError {
+        match self.pc.ident_span(id.low) {
+            Some(span) => e.with_span(span),
+            None => e,
+        }
+    }
+
+    /// Attach a location to an error encountered while solving for the type of node `ix`. This
+    /// only runs on the error path, so we just scan the environment for the variable that `ix`
+    /// corresponds to. Nodes without a location of their own (e.g. function parameters) borrow
+    /// one from a variable they are connected to.
+    fn locate_node(&self, ix: NodeIx, e: CompileError) -> CompileError {
+        let span_of = |ix: NodeIx| {
+            self.env
+                .iter()
+                .filter(|(_, node)| **node == ix)
+                .flat_map(|(Args { id, .. }, _)| self.pc.ident_span(id.low))
+                .next()
+        };
+        let span = span_of(ix).or_else(|| {
+            self.nw
+                .graph
+                .neighbors_undirected(ix)
+                .flat_map(span_of)
+                .next()
+        });
+        match span {
+            Some(span) => e.with_span(span),
+            None => e,
+        }
+    }
+
     pub(crate) fn constant(&mut self, tv: State) -> NodeIx {
         use hashbrown::hash_map::Entry::*;
         match self.base.entry(tv) {
@@ -857,6 +895,33 @@ impl<'b, 'c> TypeContext<'b, 'c> {
             self.nw.add_dep(is_map, ix, Constraint::Flows(()))
         }
     }
+    /// Record that `ix` is a map whose contents are loaded from a state file.
+    pub(crate) fn constrain_as_loaded(&mut self, ix: NodeIx) {
+        self.constrain_as_map(ix);
+        self.loaded_maps.push(ix);
+    }
+
+    /// A map that is only ever read from gets keys and values of type null, which would throw
+    /// away anything loaded into it. Once everything else is solved, give any keys and values of
+    /// such maps that are still unknown the type that can hold anything: strings. Returns whether
+    /// there were any, in which case we must solve again.
+    fn default_loaded_maps(&mut self) -> bool {
+        let mut changed = false;
+        for ix in self.loaded_maps.clone() {
+            if let Some(TVar::Map { key, val }) = *self.nw.read(ix) {
+                if key.is_some() && val.is_some() {
+                    continue;
+                }
+                let str_map = self.constant(Some(TVar::Map {
+                    key: Some(key.unwrap_or(BaseTy::Str)),
+                    val: Some(val.unwrap_or(BaseTy::Str)),
+                }));
+                self.nw.add_dep(str_map, ix, Constraint::Flows(()));
+                changed = true;
+            }
+        }
+        changed
+    }
     pub(crate) fn get_node(&mut self, key: Args<Ident>) -> NodeIx {
         self.env
             .entry(key)
@@ -0,0 +1,107 @@
+//! Differential tests for `--compat=gawk`.
+//!
+//! Each case runs a program under frawk with `--compat=gawk` and checks the output against what
+//! gawk prints for the same program. The expected output is recorded here so that the tests run
+//! without gawk; if a `gawk` binary is on the PATH, the program is also run under gawk to check
+//! that the recorded output is still accurate.
+use assert_cmd::Command;
+use std::fs::File;
+use std::io::Write;
+use tempfile::tempdir;
+
+const BACKEND_ARGS: &'static [&'static str] = &[
+    "-binterp",
+    #[cfg(feature = "llvm_backend")]
+    "-bllvm",
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    "-bcranelift",
+];
+
+const COUNTRIES: &'static str = "Russia 8650 262\nCanada 3852 24\nChina 3692 866.0\nUSA 3615 219\n";
+
+struct Case {
+    prog: &'static str,
+    input: &'static str,
+    expected: &'static str,
+}
+
+const CASES: &'static [Case] = &[
+    // Number to string conversion.
+    Case {
+        prog: r#"BEGIN { print 7.0, 0.1 + 0.2, 3.14159265, 10 / 4, 2^53, -0.5, 1 / 3 * 3 }"#,
+        input: "",
+        expected: "7 0.3 3.14159 2.5 9007199254740992 -0.5 1\n",
+    },
+    Case {
+        prog: r#"BEGIN { x = 1234567.5 ""; y = 0.0000123 ""; print x, y, 1 / 8 "" }"#,
+        input: "",
+        expected: "1.23457e+06 1.23e-05 0.125\n",
+    },
+    Case {
+        prog: r#"{ s += $3 } END { print s, s / NR }"#,
+        input: COUNTRIES,
+        expected: "1371 342.75\n",
+    },
+    // Comparisons between strings and numbers.
+    Case {
+        prog: r#"{ print ($1 < 1), ($3 == 866), ($2 < 3700), ($2 > $3) }"#,
+        input: COUNTRIES,
+        expected: "0 0 0 1\n0 0 0 1\n0 1 1 1\n0 0 1 1\n",
+    },
+    Case {
+        prog: r#"$3 > 200 { print $1 }"#,
+        input: COUNTRIES,
+        expected: "Russia\nChina\nUSA\n",
+    },
+    Case {
+        prog: r#"BEGIN { x = "abc"; print (x < 1), (x > 1), ("" < 0) }"#,
+        input: "",
+        expected: "0 1 1\n",
+    },
+    // Side effects of getline.
+    Case {
+        prog: r#"NR == 1 { getline; print NR, FNR, $1; getline x; print NR, FNR, x }"#,
+        input: COUNTRIES,
+        expected: "2 2 Canada\n3 3 China 3692 866.0\n",
+    },
+    Case {
+        prog: r#"END { "echo a b c" | getline; print NR, FNR, NF; "echo d" | getline y; print NR, y }"#,
+        input: COUNTRIES,
+        expected: "5 4 3\n6 d\n",
+    },
+];
+
+fn gawk_output(prog: &str, data: &str) -> Option<String> {
+    let output = std::process::Command::new("gawk")
+        .arg(prog)
+        .arg(data)
+        .output()
+        .ok()?;
+    Some(String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn matches_gawk() {
+    let tmpdir = tempdir().unwrap();
+    for (i, case) in CASES.iter().enumerate() {
+        let data = tmpdir.path().join(format!("case_{}", i));
+        let data = data.to_str().unwrap();
+        File::create(data)
+            .unwrap()
+            .write_all(case.input.as_bytes())
+            .unwrap();
+        if let Some(out) = gawk_output(case.prog, data) {
+            assert_eq!(out, case.expected, "gawk output for {:?}", case.prog);
+        }
+        for backend_arg in BACKEND_ARGS {
+            Command::cargo_bin("frawk")
+                .unwrap()
+                .arg(String::from(*backend_arg))
+                .arg("--compat=gawk")
+                .arg(case.prog)
+                .arg(data)
+                .assert()
+                .stdout(String::from(case.expected));
+        }
+    }
+}
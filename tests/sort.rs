@@ -19,10 +19,13 @@ fn numbers_str(n: usize) -> (String, String) {
 
 const N: usize = 10_000;
 
-#[cfg(feature = "llvm_backend")]
-const BACKEND_ARGS: &'static [&'static str] = &["-binterp", "-bllvm", "-bcranelift"];
-#[cfg(not(feature = "llvm_backend"))]
-const BACKEND_ARGS: &'static [&'static str] = &["-binterp", "-bcranelift"];
+const BACKEND_ARGS: &'static [&'static str] = &[
+    "-binterp",
+    #[cfg(feature = "llvm_backend")]
+    "-bllvm",
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    "-bcranelift",
+];
 
 #[cfg(not(target_os = "windows"))]
 #[test]